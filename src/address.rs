@@ -0,0 +1,499 @@
+/// The `address` module parses email addresses i.e. the mailboxes of RFC 5322 (section 3.4) whose
+/// address part is restricted to what RFC 5321 allows on the wire.
+///
+/// Comments and group syntax are not supported. Display names may contain non-ASCII characters,
+/// so may domains (IDN); the local part must be ASCII.
+use crate::encoding;
+use std::fmt;
+use std::net::IpAddr;
+
+/// The characters that may appear in an atom besides letters and digits (RFC 5322, section 3.2.3).
+const ATEXT: &str = "!#$%&'*+-/=?^_`{|}~";
+
+/// The maximum length of the local part of an address (RFC 5321, section 4.5.3.1.1).
+const MAX_LOCAL_LEN: usize = 64;
+
+/// The maximum length of an address (RFC 5321, section 4.5.3.1.3 minus the angle brackets).
+const MAX_ADDRESS_LEN: usize = 254;
+
+#[derive(Debug, Clone)]
+/// The `Address` struct holds a parsed email address.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Address {
+   /// The (unquoted) display name, if any e.g. `Frodo Baggins`
+   pub display_name: Option<String>,
+   /// The local part, quoted strings keep their quotes e.g. `frodo` or `"frodo baggins"`
+   pub local: String,
+   /// The domain as written e.g. `example.com`, `bücher.example` or an address literal like
+   /// `[192.0.2.1]`
+   pub domain: String,
+}
+
+impl PartialEq for Address {
+   /// Makes it possible to compare instances of `Address`
+   fn eq(&self, other: &Self) -> bool {
+      self.display_name == other.display_name
+         && self.local == other.local
+         && self.domain == other.domain
+   }
+}
+
+impl fmt::Display for Address {
+   /// Formats the address as it appears in an email header, the display name is quoted if needed
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      let name = self.display_name.as_deref().unwrap_or_default();
+      write!(f, "{}", encoding::mailbox(name, &self.email()))
+   }
+}
+
+impl Address {
+   /// Parses an address in short (`fb@example.com`) or long form (`Frodo Baggins
+   /// <fb@example.com>`).
+   ///
+   /// The error says what is wrong with the address, it does not repeat the address.
+   pub fn parse(text: &str) -> Result<Address, String> {
+      let text = text.trim();
+      if text.is_empty() {
+         return Err(String::from("empty address"));
+      }
+      let (display_name, spec) = match find_unquoted(text, '<')? {
+         Some(start) => {
+            let end = match find_unquoted(text, '>')? {
+               Some(end) if end > start => end,
+               _ => return Err(String::from("no closing > after <")),
+            };
+            if !text[end + 1..].trim().is_empty() {
+               return Err(String::from("text after the closing >"));
+            }
+            let name = parse_display_name(&text[..start])?;
+            (name, text[start + 1..end].trim())
+         }
+         None => {
+            if find_unquoted(text, '>')?.is_some() {
+               return Err(String::from("> without an opening <"));
+            }
+            if text.chars().any(char::is_whitespace) && !text.starts_with('"') {
+               return Err(String::from(
+                  "spaces are only allowed in the display name e.g. John Doe <jd@example.com>",
+               ));
+            }
+            (None, text)
+         }
+      };
+      let (local, domain) = parse_addr_spec(spec)?;
+      Ok(Address {
+         display_name,
+         local,
+         domain,
+      })
+   }
+
+   /// Returns the address without the display name e.g. `fb@example.com`.
+   pub fn email(&self) -> String {
+      format!("{}@{}", self.local, self.domain)
+   }
+
+   /// Returns the address with an internationalized domain converted to its ASCII (punycode)
+   /// form e.g. `info@xn--bcher-kva.example`, other addresses are returned as they are.
+   pub fn to_ascii(&self) -> Address {
+      Address {
+         display_name: self.display_name.clone(),
+         local: self.local.clone(),
+         domain: ascii_domain(&self.domain),
+      }
+   }
+}
+
+/// Converts an internationalized domain to its ASCII (punycode) form, ASCII domains are returned
+/// as they are.
+pub fn ascii_domain(domain: &str) -> String {
+   if domain.is_ascii() {
+      return domain.to_string();
+   }
+   idna::domain_to_ascii(domain).unwrap_or_else(|_| domain.to_string())
+}
+
+/// Converts the domain of an address without display name (e.g. a recipient's) to its ASCII
+/// form, see `ascii_domain`.
+pub fn ascii_email(email: &str) -> String {
+   match email.rfind('@') {
+      Some(at) => format!("{}@{}", &email[..at], ascii_domain(&email[at + 1..])),
+      None => email.to_string(),
+   }
+}
+
+/// Splits a comma-separated list of addresses, commas in quoted display names or within angle
+/// brackets do not separate addresses. Empty items are dropped.
+pub fn split_list(text: &str) -> Vec<&str> {
+   let mut result = vec![];
+   let mut quoted = false;
+   let mut escaped = false;
+   let mut angle = false;
+   let mut start = 0;
+   for (i, c) in text.char_indices() {
+      match c {
+         _ if escaped => escaped = false,
+         '\\' if quoted => escaped = true,
+         '"' => quoted = !quoted,
+         '<' if !quoted => angle = true,
+         '>' if !quoted => angle = false,
+         ',' if !quoted && !angle => {
+            result.push(text[start..i].trim());
+            start = i + 1;
+         }
+         _ => (),
+      }
+   }
+   result.push(text[start..].trim());
+   result.retain(|a| !a.is_empty());
+   result
+}
+
+/// Returns the position of the first occurrence of `wanted` outside of quoted strings.
+fn find_unquoted(text: &str, wanted: char) -> Result<Option<usize>, String> {
+   let mut quoted = false;
+   let mut escaped = false;
+   for (i, c) in text.char_indices() {
+      match c {
+         _ if escaped => escaped = false,
+         '\\' if quoted => escaped = true,
+         '"' => quoted = !quoted,
+         _ if c == wanted && !quoted => return Ok(Some(i)),
+         _ => (),
+      }
+   }
+   if quoted {
+      Err(String::from("unterminated quoted string"))
+   } else {
+      Ok(None)
+   }
+}
+
+/// Parses a display name (a phrase of atoms and quoted strings), an empty name yields `None`.
+///
+/// Unquoted periods are accepted since they are common in names (`John Doe Jr.`).
+fn parse_display_name(text: &str) -> Result<Option<String>, String> {
+   let mut name = String::new();
+   let mut chars = text.trim().chars();
+   while let Some(c) = chars.next() {
+      match c {
+         '"' => loop {
+            match chars.next() {
+               Some('"') => break,
+               Some('\\') => match chars.next() {
+                  Some(c) => name.push(c),
+                  None => return Err(String::from("unterminated quoted string")),
+               },
+               Some(c) => name.push(c),
+               None => return Err(String::from("unterminated quoted string")),
+            }
+         },
+         _ if c.is_whitespace() => {
+            if !name.ends_with(' ') {
+               name.push(' ')
+            }
+         }
+         _ if c.is_alphanumeric() || c == '.' || ATEXT.contains(c) => name.push(c),
+         _ => {
+            return Err(format!(
+               "invalid character '{}' in the display name, put the name in double quotes",
+               c
+            ))
+         }
+      }
+   }
+   let name = name.trim();
+   Ok(if name.is_empty() {
+      None
+   } else {
+      Some(name.to_string())
+   })
+}
+
+/// Parses an address without display name into its local part and domain.
+fn parse_addr_spec(spec: &str) -> Result<(String, String), String> {
+   if spec.len() > MAX_ADDRESS_LEN {
+      return Err(format!(
+         "the address is longer than {} characters",
+         MAX_ADDRESS_LEN
+      ));
+   }
+   // the local part may contain a (quoted) @ but the domain never does
+   let at = match spec.rfind('@') {
+      Some(at) => at,
+      None => return Err(String::from("no @ in the address")),
+   };
+   let (local, domain) = (&spec[..at], &spec[at + 1..]);
+   check_local(local)?;
+   check_domain(domain)?;
+   Ok((local.to_string(), domain.to_string()))
+}
+
+/// Checks the local part of an address, a dot-atom or a quoted string.
+fn check_local(local: &str) -> Result<(), String> {
+   if local.is_empty() {
+      return Err(String::from("empty local part"));
+   }
+   if local.len() > MAX_LOCAL_LEN {
+      return Err(format!(
+         "the local part is longer than {} characters",
+         MAX_LOCAL_LEN
+      ));
+   }
+   if local.len() > 1 && local.starts_with('"') && local.ends_with('"') {
+      let mut escaped = false;
+      for c in local[1..local.len() - 1].chars() {
+         match c {
+            _ if !c.is_ascii() => return Err(non_ascii_local(c)),
+            _ if c.is_ascii_control() => {
+               return Err(format!("invalid character '{}' in the local part", c))
+            }
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Err(String::from("unescaped \" in the quoted local part")),
+            _ => (),
+         }
+      }
+      if escaped {
+         return Err(String::from("unterminated quoted string"));
+      }
+      return Ok(());
+   }
+   if let Some(c) = local
+      .chars()
+      .find(|c| !(c.is_ascii_alphanumeric() || *c == '.' || ATEXT.contains(*c)))
+   {
+      if !c.is_ascii() {
+         return Err(non_ascii_local(c));
+      }
+      return Err(format!("invalid character '{}' in the local part", c));
+   }
+   if local.starts_with('.') || local.ends_with('.') {
+      return Err(String::from("the local part starts or ends with a period"));
+   }
+   if local.contains("..") {
+      return Err(String::from("consecutive periods in the local part"));
+   }
+   Ok(())
+}
+
+fn non_ascii_local(c: char) -> String {
+   format!(
+      "non-ASCII character '{}' in the local part, only the domain may be internationalized",
+      c
+   )
+}
+
+/// Checks the domain of an address, a host name with a top-level domain or an address literal.
+///
+/// Internationalized domains are checked in their ASCII form.
+fn check_domain(domain: &str) -> Result<(), String> {
+   if domain.is_empty() {
+      return Err(String::from("empty domain"));
+   }
+   if !domain.is_ascii() {
+      return match idna::domain_to_ascii(domain) {
+         Ok(ascii) if ascii.is_ascii() => check_domain(&ascii),
+         _ => Err(String::from("invalid internationalized domain")),
+      };
+   }
+   if domain.starts_with('[') {
+      let literal = match domain.strip_suffix(']') {
+         Some(literal) => &literal[1..],
+         None => return Err(String::from("no closing ] in the address literal")),
+      };
+      let ip = literal.strip_prefix("IPv6:").unwrap_or(literal);
+      return match ip.parse::<IpAddr>() {
+         Ok(IpAddr::V4(_)) if ip == literal => Ok(()),
+         Ok(IpAddr::V6(_)) if ip != literal => Ok(()),
+         _ => Err(format!("invalid address literal: [{}]", literal)),
+      };
+   }
+   let labels: Vec<&str> = domain.split('.').collect();
+   for label in labels.iter() {
+      if label.is_empty() {
+         return Err(String::from("empty label in the domain"));
+      }
+      if label.len() > 63 {
+         return Err(format!(
+            "the domain label {} is longer than 63 characters",
+            label
+         ));
+      }
+      if let Some(c) = label
+         .chars()
+         .find(|c| !(c.is_ascii_alphanumeric() || *c == '-'))
+      {
+         return Err(format!("invalid character '{}' in the domain", c));
+      }
+      if label.starts_with('-') || label.ends_with('-') {
+         return Err(format!(
+            "the domain label {} starts or ends with a hyphen",
+            label
+         ));
+      }
+   }
+   if labels.len() < 2 {
+      return Err(String::from("the domain has no top-level domain"));
+   }
+   if labels[labels.len() - 1].chars().all(|c| c.is_ascii_digit()) {
+      return Err(String::from("the top-level domain is numeric"));
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn address(name: Option<&str>, local: &str, domain: &str) -> Address {
+      Address {
+         display_name: name.map(|n| n.to_string()),
+         local: local.to_string(),
+         domain: domain.to_string(),
+      }
+   }
+
+   #[test]
+   fn parse_short_and_long_forms() {
+      assert_eq!(
+         Ok(address(None, "fb+tag", "mail.example.com")),
+         Address::parse("  fb+tag@mail.example.com ")
+      );
+      assert_eq!(
+         Ok(address(Some("Frodo Baggins"), "fb", "example.com")),
+         Address::parse("Frodo   Baggins <fb@example.com>")
+      );
+      assert_eq!(
+         Ok(address(
+            Some("Baggins, Frodo <the 1st>"),
+            "fb",
+            "example.com"
+         )),
+         Address::parse(r#""Baggins, Frodo <the 1st>" <fb@example.com>"#)
+      );
+      assert_eq!(
+         Ok(address(
+            Some(r#"Frodo "the Ring" Baggins"#),
+            "fb",
+            "example.com"
+         )),
+         Address::parse(r#""Frodo \"the Ring\" Baggins" <fb@example.com>"#)
+      );
+      assert_eq!(
+         Ok(address(Some("John Doe Jr."), "jd", "example.com")),
+         Address::parse("John Doe Jr. <jd@example.com>")
+      );
+      assert_eq!(
+         Ok(address(Some("Jürgen Müller"), "jm", "example.com")),
+         Address::parse("Jürgen Müller <jm@example.com>")
+      );
+      assert_eq!(
+         Ok(address(None, r#""john doe""#, "[192.0.2.1]")),
+         Address::parse(r#"<"john doe"@[192.0.2.1]>"#)
+      );
+   }
+
+   #[test]
+   fn parse_errors() {
+      let cases = [
+         ("", "empty address"),
+         ("oh!no!", "no @ in the address"),
+         ("@example.com", "empty local part"),
+         ("jd@", "empty domain"),
+         ("abc@defcom", "the domain has no top-level domain"),
+         ("j..d@example.com", "consecutive periods in the local part"),
+         (
+            ".jd@example.com",
+            "the local part starts or ends with a period",
+         ),
+         (
+            "j(d)@example.com",
+            "invalid character '(' in the local part",
+         ),
+         ("jd@exa_mple.com", "invalid character '_' in the domain"),
+         (
+            "jd@-example.com",
+            "the domain label -example starts or ends with a hyphen",
+         ),
+         ("jd@example..com", "empty label in the domain"),
+         ("jd@192.0.2.1", "the top-level domain is numeric"),
+         ("jd@[192.0.2]", "invalid address literal: [192.0.2]"),
+         ("John <jd@example.com", "no closing > after <"),
+         ("John <jd@example.com> Doe", "text after the closing >"),
+         ("jd@example.com>", "> without an opening <"),
+         (
+            "John Doe jd@example.com",
+            "spaces are only allowed in the display name e.g. John Doe <jd@example.com>",
+         ),
+         (
+            "Doe, John <jd@example.com>",
+            "invalid character ',' in the display name, put the name in double quotes",
+         ),
+         ("\"John Doe <jd@example.com>", "unterminated quoted string"),
+      ];
+      for (text, error) in cases.iter() {
+         assert_eq!(Err(error.to_string()), Address::parse(text), "{}", text);
+      }
+      let long = format!("{}@example.com", "a".repeat(65));
+      assert_eq!(
+         Err(String::from("the local part is longer than 64 characters")),
+         Address::parse(&long)
+      );
+   }
+
+   #[test]
+   fn internationalized_domains() {
+      let address = Address::parse("Jürgen <jm@bücher.example>").expect("invalid address");
+      assert_eq!("bücher.example", address.domain);
+      assert_eq!(
+         "Jürgen <jm@xn--bcher-kva.example>",
+         address.to_ascii().to_string()
+      );
+      assert_eq!("jm@xn--bcher-kva.example", ascii_email("jm@Bücher.example"));
+      assert_eq!("jm@example.com", ascii_email("jm@example.com"));
+      assert_eq!(
+         Err(String::from(
+            "non-ASCII character 'ü' in the local part, only the domain may be internationalized"
+         )),
+         Address::parse("jürgen@example.com")
+      );
+      assert_eq!(
+         Err(String::from("invalid character '_' in the domain")),
+         Address::parse("jm@bü_cher.example")
+      );
+   }
+
+   #[test]
+   fn display_quotes_the_name_if_needed() {
+      let cases = [
+         ("fb@example.com", "fb@example.com"),
+         (
+            r#""Frodo Baggins" <fb@example.com>"#,
+            "Frodo Baggins <fb@example.com>",
+         ),
+         (
+            r#""Baggins, Frodo" <fb@example.com>"#,
+            r#""Baggins, Frodo" <fb@example.com>"#,
+         ),
+      ];
+      for (text, expected) in cases.iter() {
+         assert_eq!(
+            *expected,
+            Address::parse(text).expect("invalid address").to_string()
+         );
+      }
+   }
+
+   #[test]
+   fn split_list_respects_quotes_and_brackets() {
+      assert_eq!(
+         vec![
+            r#""Doe, John" <jd@example.com>"#,
+            "mm@example.com",
+            r#"<"a,b"@example.com>"#
+         ],
+         split_list(r#" "Doe, John" <jd@example.com>, mm@example.com,,<"a,b"@example.com>"#)
+      );
+   }
+}
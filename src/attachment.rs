@@ -0,0 +1,392 @@
+/// The `attachment` module loads the files attached to the emails and determines their content
+/// types.
+use crate::encoding;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+#[derive(Debug)]
+/// The `Attachment` struct holds a file attached to the emails.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attachment {
+   /// The file name (without the directory) the recipients see
+   pub name: String,
+   /// The MIME content type e.g. `application/pdf`
+   pub content_type: String,
+   /// The file content
+   pub data: Vec<u8>,
+   /// The content identifier of an inline image (without the angle brackets), the HTML body
+   /// refers to it with `cid:`
+   pub content_id: Option<String>,
+}
+
+impl PartialEq for Attachment {
+   /// Makes it possible to compare instances of `Attachment`
+   fn eq(&self, other: &Self) -> bool {
+      self.name == other.name
+         && self.content_type == other.content_type
+         && self.data == other.data
+         && self.content_id == other.content_id
+   }
+}
+
+impl Attachment {
+   /// Returns the headers of the MIME part holding the attachment, the content is always base64
+   /// encoded. Inline images are marked as such and carry their `Content-ID`.
+   pub fn headers(&self) -> Vec<(String, String)> {
+      let disposition = if self.content_id.is_some() {
+         "inline"
+      } else {
+         "attachment"
+      };
+      let mut result = vec![
+         (
+            String::from("Content-Type"),
+            format!("{}; {}", self.content_type, parameter("name", &self.name)),
+         ),
+         (
+            String::from("Content-Disposition"),
+            format!("{}; {}", disposition, parameter("filename", &self.name)),
+         ),
+      ];
+      if let Some(ref cid) = self.content_id {
+         result.push((String::from("Content-ID"), format!("<{}>", cid)));
+      }
+      result.push((
+         String::from("Content-Transfer-Encoding"),
+         String::from("base64"),
+      ));
+      result
+   }
+}
+
+/// Formats a MIME header parameter, non-ASCII values are encoded as per RFC 2231.
+fn parameter(name: &str, value: &str) -> String {
+   if value.is_ascii() {
+      format!(
+         "{}=\"{}\"",
+         name,
+         value.replace('\\', "\\\\").replace('"', "\\\"")
+      )
+   } else {
+      format!("{}*=utf-8''{}", name, encoding::percent_encode(value))
+   }
+}
+
+/// Reads the file at the given path.
+pub fn load(path: &str) -> Result<Attachment, String> {
+   let data = fs::read(path).map_err(|e| format!("cannot read attachment {} ({})", path, e))?;
+   let name = Path::new(path)
+      .file_name()
+      .map(|n| n.to_string_lossy().into_owned())
+      .ok_or(format!("invalid attachment path: {}", path))?;
+   Ok(Attachment {
+      content_type: content_type(&name, &data),
+      name,
+      data,
+      content_id: None,
+   })
+}
+
+/// Runs the command that generates a personalized attachment and reads the file it generated.
+///
+/// The command is split into words which may use the template keys, it is not run by a shell.
+/// The template values are passed as environment variables as well (prefixed with `RMT_`, e.g.
+/// `RMT_EA` for `_EA`). The command prints the path of the generated file on the last line of
+/// its output.
+pub fn generate(command: &str, values: &HashMap<String, String>) -> Result<Attachment, String> {
+   let words: Vec<String> = command
+      .split_whitespace()
+      .map(|w| crate::template::expand(w, values))
+      .collect();
+   let (program, args) = words.split_first().ok_or("empty *attachment_cmd*")?;
+   // the automatic keys win over data keys with the same name
+   let mut keys: Vec<&String> = values.keys().collect();
+   keys.sort_by_key(|k| (k.starts_with('_'), k.to_string()));
+   let output = Command::new(program)
+      .args(args)
+      .envs(
+         keys
+            .into_iter()
+            .map(|k| (format!("RMT_{}", k.trim_start_matches('_')), &values[k])),
+      )
+      .output()
+      .map_err(|e| format!("cannot run {} ({})", program, e))?;
+   if !output.status.success() {
+      return Err(format!(
+         "{} failed ({}): {}",
+         program,
+         output.status,
+         String::from_utf8_lossy(&output.stderr).trim()
+      ));
+   }
+   let stdout = String::from_utf8_lossy(&output.stdout);
+   let path = stdout
+      .lines()
+      .map(|l| l.trim())
+      .rfind(|l| !l.is_empty())
+      .ok_or(format!(
+         "{} did not print the path of the attachment",
+         program
+      ))?;
+   load(path)
+}
+
+/// Reads the image at the given path for embedding it in HTML emails.
+///
+/// The content identifier is the file name (restricted to the characters allowed in a
+/// `Content-ID`) at the given domain e.g. `logo.png@example.com`.
+pub fn load_inline(path: &str, domain: &str) -> Result<Attachment, String> {
+   let mut result = load(path)?;
+   if !result.content_type.starts_with("image/") {
+      return Err(format!(
+         "inline image {} is not an image ({})",
+         path, result.content_type
+      ));
+   }
+   let local: String = result
+      .name
+      .chars()
+      .map(|c| {
+         if c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.".contains(c) {
+            c
+         } else {
+            '_'
+         }
+      })
+      .collect();
+   result.content_id = Some(format!("{}@{}", local, domain));
+   Ok(result)
+}
+
+/// The content types of well-known file extensions.
+const EXTENSIONS: &[(&str, &str)] = &[
+   ("csv", "text/csv"),
+   ("doc", "application/msword"),
+   (
+      "docx",
+      "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+   ),
+   ("eml", "message/rfc822"),
+   ("gif", "image/gif"),
+   ("gz", "application/gzip"),
+   ("htm", "text/html"),
+   ("html", "text/html"),
+   ("ics", "text/calendar"),
+   ("jpeg", "image/jpeg"),
+   ("jpg", "image/jpeg"),
+   ("json", "application/json"),
+   ("md", "text/markdown"),
+   ("mp3", "audio/mpeg"),
+   ("mp4", "video/mp4"),
+   ("odp", "application/vnd.oasis.opendocument.presentation"),
+   ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+   ("odt", "application/vnd.oasis.opendocument.text"),
+   ("pdf", "application/pdf"),
+   ("png", "image/png"),
+   (
+      "pptx",
+      "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+   ),
+   ("svg", "image/svg+xml"),
+   ("tar", "application/x-tar"),
+   ("txt", "text/plain"),
+   ("vcf", "text/vcard"),
+   ("webp", "image/webp"),
+   ("xls", "application/vnd.ms-excel"),
+   (
+      "xlsx",
+      "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+   ),
+   ("xml", "application/xml"),
+   ("zip", "application/zip"),
+];
+
+/// Determines the content type of a file from its magic bytes and its extension.
+///
+/// Formats with unambiguous magic bytes are recognized by them, regardless of the extension. ZIP
+/// based formats (e.g. `.docx`) are told apart by the extension. Unknown files are
+/// `text/plain` if they look like text and `application/octet-stream` otherwise.
+pub fn content_type(name: &str, data: &[u8]) -> String {
+   let magic: &[(&[u8], &str)] = &[
+      (b"%PDF-", "application/pdf"),
+      (b"\x89PNG\r\n\x1a\n", "image/png"),
+      (b"\xff\xd8\xff", "image/jpeg"),
+      (b"GIF87a", "image/gif"),
+      (b"GIF89a", "image/gif"),
+      (b"\x1f\x8b", "application/gzip"),
+   ];
+   if let Some((_, ctype)) = magic.iter().find(|(m, _)| data.starts_with(m)) {
+      return ctype.to_string();
+   }
+   if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+      return String::from("image/webp");
+   }
+   let extension = Path::new(name)
+      .extension()
+      .map(|e| e.to_string_lossy().to_lowercase())
+      .unwrap_or_default();
+   let by_extension = EXTENSIONS
+      .iter()
+      .find(|(e, _)| *e == extension)
+      .map(|(_, ctype)| *ctype);
+   if data.starts_with(b"PK\x03\x04") {
+      return by_extension.unwrap_or("application/zip").to_string();
+   }
+   let text = std::str::from_utf8(data).is_ok() && !data.contains(&0);
+   let ctype = match by_extension {
+      Some(ctype) => ctype,
+      None if text => "text/plain",
+      None => "application/octet-stream",
+   };
+   if text && ctype.starts_with("text/") {
+      format!("{}; charset=utf-8", ctype)
+   } else {
+      ctype.to_string()
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::io::Write;
+
+   #[test]
+   fn content_type_by_magic_bytes() {
+      // the magic bytes win over a misleading extension
+      assert_eq!(
+         "application/pdf",
+         content_type("report.txt", b"%PDF-1.7\n...")
+      );
+      assert_eq!(
+         "image/png",
+         content_type("logo", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")
+      );
+      assert_eq!(
+         "image/webp",
+         content_type("pic.jpg", b"RIFF\0\0\0\0WEBPVP8 ")
+      );
+      assert_eq!(
+         "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+         content_type("letter.DOCX", b"PK\x03\x04\x14\0")
+      );
+      assert_eq!(
+         "application/zip",
+         content_type("archive", b"PK\x03\x04\x14\0")
+      );
+   }
+
+   #[test]
+   fn content_type_by_extension_and_content() {
+      assert_eq!(
+         "text/calendar; charset=utf-8",
+         content_type("invite.ics", b"BEGIN:VCALENDAR\r\n")
+      );
+      assert_eq!(
+         "text/plain; charset=utf-8",
+         content_type("README", b"hello")
+      );
+      assert_eq!(
+         "application/octet-stream",
+         content_type("blob", b"\0\x01\x02")
+      );
+   }
+
+   #[test]
+   fn headers_encode_file_names() {
+      let attachment = Attachment {
+         name: String::from("Übersicht \"2024\".pdf"),
+         content_type: String::from("application/pdf"),
+         data: vec![],
+         content_id: None,
+      };
+      assert_eq!(
+         (
+            String::from("Content-Disposition"),
+            String::from("attachment; filename*=utf-8''%C3%9Cbersicht%20%222024%22.pdf")
+         ),
+         attachment.headers()[1]
+      );
+      let attachment = Attachment {
+         name: String::from("say \"hi\".txt"),
+         ..attachment
+      };
+      assert_eq!(
+         "application/pdf; name=\"say \\\"hi\\\".txt\"",
+         attachment.headers()[0].1
+      );
+   }
+
+   #[test]
+   fn load_attachment() {
+      let mut file = tempfile::Builder::new()
+         .suffix(".csv")
+         .tempfile()
+         .expect("cannot create file");
+      file.write_all(b"a,b\n1,2\n").unwrap();
+      let attachment = load(file.path().to_str().unwrap()).expect("cannot load attachment");
+      assert!(attachment.name.ends_with(".csv"));
+      assert_eq!("text/csv; charset=utf-8", attachment.content_type);
+      assert_eq!(b"a,b\n1,2\n".to_vec(), attachment.data);
+      assert!(load("/no/such/file.pdf").is_err());
+   }
+
+   #[cfg(unix)]
+   #[test]
+   fn generate_attachment() {
+      use std::os::unix::fs::PermissionsExt;
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let script = dir.path().join("make.sh");
+      fs::write(
+         &script,
+         "#!/bin/sh\nout=\"$(dirname \"$0\")/$1.txt\"\necho \"Dear $RMT_FN ($RMT_ORG)\" > \"$out\"\necho generated\necho \"$out\"\n",
+      )
+      .unwrap();
+      fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+      let values: HashMap<String, String> = [("_FN", "John"), ("ORG", "EFF"), ("ID", "jd")]
+         .iter()
+         .map(|(k, v)| (k.to_string(), v.to_string()))
+         .collect();
+      let command = format!("{} %ID%", script.display());
+      let attachment = generate(&command, &values).expect("cannot generate attachment");
+      assert_eq!("jd.txt", attachment.name);
+      assert_eq!(b"Dear John (EFF)\n".to_vec(), attachment.data);
+      assert!(generate("false", &values)
+         .unwrap_err()
+         .starts_with("false failed"));
+      assert_eq!(
+         Err(String::from(
+            "true did not print the path of the attachment"
+         )),
+         generate("true", &values)
+      );
+   }
+
+   #[test]
+   fn load_inline_image() {
+      let mut file = tempfile::Builder::new()
+         .prefix("company logo")
+         .suffix(".png")
+         .tempfile()
+         .expect("cannot create file");
+      file.write_all(b"\x89PNG\r\n\x1a\n").unwrap();
+      let image =
+         load_inline(file.path().to_str().unwrap(), "example.com").expect("cannot load image");
+      let cid = image.content_id.clone().unwrap();
+      assert!(cid.starts_with("company_logo") && cid.ends_with(".png@example.com"));
+      assert!(image.headers().contains(&(
+         String::from("Content-Disposition"),
+         format!("inline; filename=\"{}\"", image.name)
+      )));
+      assert!(image
+         .headers()
+         .contains(&(String::from("Content-ID"), format!("<{}>", cid))));
+      let mut file = tempfile::Builder::new()
+         .suffix(".txt")
+         .tempfile()
+         .expect("cannot create file");
+      file.write_all(b"hello").unwrap();
+      assert!(load_inline(file.path().to_str().unwrap(), "example.com").is_err());
+   }
+}
@@ -0,0 +1,174 @@
+/// The `audit` module records every attempted delivery in an append-only audit log.
+///
+/// The audit log is a [JSON Lines](https://jsonlines.org/) file, every line is a JSON object
+/// describing a single delivery attempt:
+///
+/// * `timestamp`: when the delivery was attempted (seconds since the epoch)
+/// * `campaign_id`: the campaign identifier
+/// * `recipient`: the email address of the recipient
+/// * `message_id`: the `Message-ID` of the email
+/// * `subject_hash`: the 64-bit FNV-1a hash of the *Subject* header (16 hex digits)
+/// * `transport`: how the email was sent e.g. `smtp://smtp.example.com:465`
+/// * `variant`: the template variant of an A/B test the recipient was assigned, `null` if the
+///   campaign is not an A/B test
+/// * `result`: `sent` or `failed`
+/// * `error`: the error message for failed deliveries, `null` otherwise
+///
+/// Lines are only ever appended, existing content is never modified.
+use crate::events::{self, Event, Observer};
+use crate::message;
+use log::error;
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+/// The `Audit` observer appends a line to the audit log for every delivery attempt.
+pub struct Audit {
+   path: String,
+   file: File,
+   transport: String,
+}
+
+impl Audit {
+   /// Opens the audit log at the given path (it is created if needed), `transport` describes the
+   /// transport used for the deliveries.
+   pub fn open(path: &str, transport: &str) -> Result<Audit, String> {
+      let file = OpenOptions::new()
+         .create(true)
+         .append(true)
+         .open(path)
+         .map_err(|e| format!("cannot open audit log {} ({})", path, e))?;
+      Ok(Audit {
+         path: path.to_string(),
+         file,
+         transport: transport.to_string(),
+      })
+   }
+
+   /// Returns the audit log line for the event if it is a delivery attempt.
+   pub fn line(&self, event: &Event, timestamp: u64) -> Option<String> {
+      let (campaign_id, recipient, message_id, subject, variant, error) = match event {
+         Event::MessageSent {
+            campaign_id,
+            recipient,
+            message_id,
+            subject,
+            variant,
+         } => (campaign_id, recipient, message_id, subject, variant, None),
+         Event::MessageFailed {
+            campaign_id,
+            recipient,
+            message_id,
+            subject,
+            variant,
+            error,
+         } => (
+            campaign_id,
+            recipient,
+            message_id,
+            subject,
+            variant,
+            Some(error),
+         ),
+         _ => return None,
+      };
+      let record = json!({
+         "timestamp": timestamp,
+         "campaign_id": campaign_id,
+         "recipient": recipient,
+         "message_id": message_id,
+         "subject_hash": format!("{:016x}", message::fnv1a(subject.as_bytes())),
+         "transport": self.transport,
+         "variant": variant,
+         "result": if error.is_none() { "sent" } else { "failed" },
+         "error": error,
+      });
+      Some(record.to_string())
+   }
+}
+
+impl Observer for Audit {
+   fn notify(&mut self, event: &Event) {
+      if let Some(line) = self.line(event, events::now()) {
+         // the line is written at once so that concurrent writers do not interleave
+         if let Err(err) = self.file.write_all(format!("{}\n", line).as_bytes()) {
+            error!("cannot write to audit log {} ({})", self.path, err);
+         }
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::fs;
+   use tempfile::NamedTempFile;
+
+   #[test]
+   fn audit_log_is_appended_to() {
+      let file = NamedTempFile::new().expect("cannot create temp file");
+      let path = file.path().to_str().unwrap();
+      fs::write(path, "previous line\n").unwrap();
+      let mut audit = Audit::open(path, "smtp://smtp.example.com:465").expect("open failed");
+      audit.notify(&Event::CampaignStarted {
+         campaign_id: String::from("spring-sale"),
+         recipients: 2,
+      });
+      audit.notify(&Event::MessageSent {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("jd@example.com"),
+         message_id: String::from("<spring-sale.1@example.com>"),
+         subject: String::from("Hello John!"),
+         variant: None,
+      });
+      audit.notify(&Event::MessageFailed {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("mm@gmail.com"),
+         message_id: String::from("<spring-sale.2@example.com>"),
+         subject: String::from("Hello Mickey!"),
+         variant: None,
+         error: String::from("mailbox full"),
+      });
+      let content = fs::read_to_string(path).unwrap();
+      let lines: Vec<&str> = content.lines().collect();
+      assert_eq!(3, lines.len());
+      assert_eq!("previous line", lines[0]);
+      let sent: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+      assert_eq!("jd@example.com", sent["recipient"]);
+      assert_eq!("sent", sent["result"]);
+      assert_eq!(serde_json::Value::Null, sent["error"]);
+      let failed: serde_json::Value = serde_json::from_str(lines[2]).unwrap();
+      assert_eq!("failed", failed["result"]);
+      assert_eq!("mailbox full", failed["error"]);
+      assert_eq!("smtp://smtp.example.com:465", failed["transport"]);
+   }
+
+   #[test]
+   fn line_format() {
+      let file = NamedTempFile::new().expect("cannot create temp file");
+      let audit = Audit::open(file.path().to_str().unwrap(), "mock").expect("open failed");
+      let event = Event::MessageSent {
+         campaign_id: String::from("c1"),
+         recipient: String::from("jd@example.com"),
+         message_id: String::from("<c1.1@example.com>"),
+         subject: String::from("foobar"),
+         variant: Some(String::from("b")),
+      };
+      assert_eq!(
+         Some(String::from(
+            r#"{"campaign_id":"c1","error":null,"message_id":"<c1.1@example.com>","recipient":"jd@example.com","result":"sent","subject_hash":"85944171f73967e8","timestamp":1600000000,"transport":"mock","variant":"b"}"#
+         )),
+         audit.line(&event, 1_600_000_000)
+      );
+      assert_eq!(
+         None,
+         audit.line(
+            &Event::CampaignStarted {
+               campaign_id: String::from("c1"),
+               recipients: 1
+            },
+            1_600_000_000
+         )
+      );
+   }
+}
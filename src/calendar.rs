@@ -0,0 +1,161 @@
+/// The `calendar` module generates the personalized iCalendar (RFC 5545) invitations to the event
+/// of a campaign.
+use crate::address;
+use crate::attachment::Attachment;
+use crate::config::{Config, Event, Recipient};
+use crate::template;
+use chrono::{DateTime, Utc};
+
+/// The longest line (in octets, without the line break) allowed in iCalendar data.
+const MAX_LINE_LEN: usize = 75;
+
+/// Formats a point in time as an iCalendar UTC date-time e.g. `20240501T130000Z`.
+fn date_time(t: &DateTime<Utc>) -> String {
+   t.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes the characters that have a special meaning in iCalendar text values.
+fn escape(text: &str) -> String {
+   text
+      .replace('\\', "\\\\")
+      .replace(';', "\\;")
+      .replace(',', "\\,")
+      .replace("\r\n", "\\n")
+      .replace('\n', "\\n")
+}
+
+/// Formats a name for a `CN` parameter, double quotes are not allowed in parameter values.
+fn common_name(name: &str) -> String {
+   format!("\"{}\"", name.replace('"', ""))
+}
+
+/// Folds a content line so that no line is longer than 75 octets, the continuation lines start
+/// with a space.
+fn fold(line: &str) -> String {
+   let mut result = String::with_capacity(line.len() + line.len() / MAX_LINE_LEN * 3);
+   let mut len = 0;
+   for c in line.chars() {
+      if len + c.len_utf8() > MAX_LINE_LEN {
+         result.push_str("\r\n ");
+         len = 1;
+      }
+      result.push(c);
+      len += c.len_utf8();
+   }
+   result
+}
+
+/// Generates the `invite.ics` attachment that invites the recipient to the event.
+///
+/// The summary and the description may use the template keys. All recipients get the same `UID`
+/// so that calendar clients treat the invitations as the same event.
+pub fn invite(cfg: &Config, event: &Event, rcp: &Recipient, stamp: DateTime<Utc>) -> Attachment {
+   let values = template::values(cfg, rcp);
+   let organizer = event.organizer.to_ascii();
+   let mut lines = vec![
+      String::from("BEGIN:VCALENDAR"),
+      format!("PRODID:-//{}//{}//EN", cfg.tool_name, cfg.tool_version),
+      String::from("VERSION:2.0"),
+      String::from("CALSCALE:GREGORIAN"),
+      String::from("METHOD:REQUEST"),
+      String::from("BEGIN:VEVENT"),
+      format!("UID:{}@{}", cfg.campaign_id, cfg.msgid_domain),
+      format!("DTSTAMP:{}", date_time(&stamp)),
+      format!("DTSTART:{}", date_time(&event.start)),
+      format!("DTEND:{}", date_time(&event.end)),
+      format!(
+         "SUMMARY:{}",
+         escape(&template::expand(&event.summary, &values))
+      ),
+   ];
+   if let Some(ref location) = event.location {
+      lines.push(format!("LOCATION:{}", escape(location)));
+   }
+   if let Some(ref description) = event.description {
+      lines.push(format!(
+         "DESCRIPTION:{}",
+         escape(&template::expand(description, &values))
+      ));
+   }
+   lines.push(match organizer.display_name {
+      Some(ref name) => format!(
+         "ORGANIZER;CN={}:mailto:{}",
+         common_name(name),
+         organizer.email()
+      ),
+      None => format!("ORGANIZER:mailto:{}", organizer.email()),
+   });
+   lines.push(format!(
+      "ATTENDEE;CN={};ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE:mailto:{}",
+      common_name(&rcp.names.join(" ")),
+      address::ascii_email(&rcp.email)
+   ));
+   lines.extend(
+      [
+         "SEQUENCE:0",
+         "STATUS:CONFIRMED",
+         "END:VEVENT",
+         "END:VCALENDAR",
+      ]
+      .iter()
+      .map(|l| l.to_string()),
+   );
+   let mut data = String::new();
+   for line in lines {
+      data.push_str(&fold(&line));
+      data.push_str("\r\n");
+   }
+   Attachment {
+      name: String::from("invite.ics"),
+      content_type: String::from("text/calendar; charset=utf-8; method=REQUEST"),
+      data: data.into_bytes(),
+      content_id: None,
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::address::Address;
+   use crate::testing;
+   use chrono::TimeZone;
+
+   #[test]
+   fn fold_long_lines() {
+      assert_eq!("short", fold("short"));
+      let line = format!("DESCRIPTION:{}", "ä".repeat(40));
+      let folded = fold(&line);
+      assert!(folded.split("\r\n").all(|l| l.len() <= MAX_LINE_LEN));
+      assert_eq!(line, folded.replace("\r\n ", ""));
+   }
+
+   #[test]
+   fn escape_text() {
+      assert_eq!("a\\, b\\; c\\\\d\\ne", escape("a, b; c\\d\ne"));
+   }
+
+   #[test]
+   fn invite_happy_case() {
+      let mut cfg = testing::config("rts@example.com", "Invitation");
+      cfg.campaign_id = String::from("webinar");
+      cfg.msgid_domain = String::from("example.com");
+      let event = Event {
+         summary: String::from("Webinar for %_FN%"),
+         start: Utc.with_ymd_and_hms(2024, 5, 1, 13, 0, 0).unwrap(),
+         end: Utc.with_ymd_and_hms(2024, 5, 1, 14, 30, 0).unwrap(),
+         location: Some(String::from("Room 1, 2nd floor")),
+         description: None,
+         organizer: Address::parse("Frodo Baggins <rts@example.com>").unwrap(),
+      };
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[]);
+      let stamp = Utc.with_ymd_and_hms(2024, 4, 1, 8, 0, 0).unwrap();
+      let attachment = invite(&cfg, &event, &rcp, stamp);
+      assert_eq!("invite.ics", attachment.name);
+      let text = String::from_utf8(attachment.data).unwrap();
+      assert!(text.contains("METHOD:REQUEST\r\n"));
+      assert!(text.contains("UID:webinar@example.com\r\nDTSTAMP:20240401T080000Z\r\nDTSTART:20240501T130000Z\r\nDTEND:20240501T143000Z\r\nSUMMARY:Webinar for John\r\nLOCATION:Room 1\\, 2nd floor\r\n"));
+      assert!(text.contains("ORGANIZER;CN=\"Frodo Baggins\":mailto:rts@example.com\r\n"));
+      assert!(text.contains("ATTENDEE;CN=\"John Doe\";ROLE=REQ-PARTICIPANT;PARTSTAT=NEEDS-ACTION;RSVP=TRUE\r\n :mailto:jd@example.com\r\n"));
+      assert!(text.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+   }
+}
@@ -0,0 +1,409 @@
+/// The `campaign` module bundles a config, its templates and a transport so that programs
+/// embedding rmt can check, render and send a campaign the way `rmt validate` and `rmt run` do.
+///
+/// The preparation of a campaign for sending (loading the embedded images, checking the
+/// recipients, the HTML and the email sizes, generating the attachments) is done by `prepare`,
+/// for `rmt run`, `rmt validate` and the REST API alike.
+use crate::config::{self, Config, OnOversize};
+use crate::diagnostic::Diagnostic;
+use crate::dns;
+use crate::events::{Event, Observer};
+use crate::ledger::Ledger;
+use crate::lint;
+use crate::message::{self, Message};
+use crate::sanitizer;
+use crate::template::Template;
+use crate::transport::{self, Delivery, Transport};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// The `Campaign` struct holds everything needed to send a campaign.
+pub struct Campaign<T: Transport> {
+   /// The parsed config with the settings and the recipients
+   pub config: Config,
+   /// The plain text template
+   pub template: Arc<Template>,
+   /// The HTML template, if any
+   pub html: Option<Arc<Template>>,
+   /// The transport the emails are sent with e.g. an `SmtpTransport`
+   pub transport: T,
+   /// The record of the emails delivered so far, if the campaign is to skip the recipients sent
+   /// to before
+   pub ledger: Option<Ledger>,
+}
+
+impl<T: Transport> Campaign<T> {
+   /// Sets up a campaign with a plain text template only.
+   pub fn new(config: Config, template: Template, transport: T) -> Campaign<T> {
+      Campaign {
+         config,
+         template: Arc::new(template),
+         html: None,
+         transport,
+         ledger: None,
+      }
+   }
+
+   /// Adds the HTML template.
+   pub fn with_html(mut self, html: Template) -> Campaign<T> {
+      self.html = Some(Arc::new(html));
+      self
+   }
+
+   /// Skips the recipients the ledger has on record for the campaign and records the emails
+   /// sent in it.
+   pub fn with_ledger(mut self, ledger: Ledger) -> Campaign<T> {
+      self.ledger = Some(ledger);
+      self
+   }
+
+   /// Checks the campaign (see `prepare`), the images the HTML embeds are loaded.
+   pub fn check(&mut self) -> Result<Checks, String> {
+      prepare(
+         &mut self.config,
+         &self.template,
+         self.html.as_ref(),
+         &Preparation::default(),
+      )
+   }
+
+   /// Checks the campaign and returns all problems found: the quarantined recipients (of a
+   /// leniently parsed config), recipients the templates lack data for, HTML that mail clients
+   /// would mangle (unless it is sanitized) and emails over the size limit (unless the campaign
+   /// sends them anyway).
+   pub fn validate(&mut self) -> Result<(), Vec<String>> {
+      let checks = self.check().map_err(|e| vec![e])?;
+      checks.result()
+   }
+
+   /// Composes the emails for all recipients, in the order of the recipients.
+   pub fn render_all(&self) -> Vec<Message> {
+      self
+         .config
+         .recipients
+         .iter()
+         .map(|rcp| message::compose(&self.config, &self.template, self.html.as_deref(), rcp))
+         .collect()
+   }
+
+   /// Prepares the campaign and sends it if there are no problems, the outcome of every
+   /// delivery attempt is returned (see `transport::deliver`).
+   pub fn send(&mut self) -> Result<Vec<Delivery>, Vec<String>> {
+      let mut events: Vec<Event> = vec![];
+      self.send_observed(&mut events)
+   }
+
+   /// Works like `send` but notifies the observer of the progress of the campaign.
+   pub fn send_observed(
+      &mut self,
+      observer: &mut dyn Observer,
+   ) -> Result<Vec<Delivery>, Vec<String>> {
+      if let Some(ref ledger) = self.ledger {
+         skip_delivered(&mut self.config, ledger);
+      }
+      let preparation = Preparation {
+         attachments: true,
+         ..Default::default()
+      };
+      prepare(
+         &mut self.config,
+         &self.template,
+         self.html.as_ref(),
+         &preparation,
+      )
+      .map_err(|e| vec![e])?
+      .result()?;
+      let mut observer = Recorded {
+         observer,
+         ledger: self.ledger.as_mut(),
+      };
+      Ok(transport::deliver(
+         &self.config,
+         &self.template,
+         self.html.as_deref(),
+         &mut self.transport,
+         &mut observer,
+      ))
+   }
+}
+
+/// The `Recorded` observer passes the events on and records the emails sent in the ledger, if
+/// any.
+struct Recorded<'a> {
+   observer: &'a mut dyn Observer,
+   ledger: Option<&'a mut Ledger>,
+}
+
+impl Observer for Recorded<'_> {
+   fn notify(&mut self, event: &Event) {
+      if let Some(ref mut ledger) = self.ledger {
+         ledger.notify(event);
+      }
+      self.observer.notify(event);
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `Checks` struct holds what preparing a campaign found: the problems that keep it from
+/// being sent and the warnings, located in the config files where possible.
+pub struct Checks {
+   pub problems: Vec<Diagnostic>,
+   pub warnings: Vec<Diagnostic>,
+}
+
+impl Checks {
+   /// Returns the problems as messages, if there are any.
+   pub fn result(&self) -> Result<(), Vec<String>> {
+      if self.problems.is_empty() {
+         Ok(())
+      } else {
+         Err(self.problems.iter().map(|p| p.to_string()).collect())
+      }
+   }
+}
+
+#[derive(Debug, Default, Clone)]
+/// The `Preparation` struct holds the options of `prepare`.
+pub struct Preparation {
+   /// The nameservers the domains of the recipients are verified with, they are not verified if
+   /// not given (see `dns::verify_recipients`)
+   pub nameservers: Option<Vec<SocketAddr>>,
+   /// Whether the recipients that cannot be sent to are quarantined rather than reported as
+   /// problems
+   pub skip_invalid: bool,
+   /// Whether the personalized attachments (*attachment_cmd*) are generated
+   pub attachments: bool,
+}
+
+/// Returns the plain text and the HTML templates the recipients may be sent: those of the
+/// *variants* if the campaign has any, the given ones otherwise.
+pub fn templates(
+   cfg: &Config,
+   template: &Arc<Template>,
+   html: Option<&Arc<Template>>,
+) -> (Vec<Arc<Template>>, Vec<Arc<Template>>) {
+   if cfg.variants.is_empty() {
+      (vec![template.clone()], html.cloned().into_iter().collect())
+   } else {
+      (
+         cfg.variants.iter().map(|v| v.template.clone()).collect(),
+         cfg.variants.iter().filter_map(|v| v.html.clone()).collect(),
+      )
+   }
+}
+
+/// Loads the images the HTML templates embed (`%_CID:path%`), see `Config::embed_images`.
+pub fn embed_images<'a>(
+   cfg: &mut Config,
+   htmls: impl IntoIterator<Item = &'a Template>,
+) -> Result<(), String> {
+   htmls
+      .into_iter()
+      .try_for_each(|html| cfg.embed_images(html))
+}
+
+/// Removes the recipients the ledger has on record for the campaign, returns how many there
+/// were.
+pub fn skip_delivered(cfg: &mut Config, ledger: &Ledger) -> usize {
+   let before = cfg.recipients.len();
+   let campaign_id = &cfg.campaign_id;
+   cfg.recipients
+      .retain(|r| !ledger.contains(campaign_id, &r.email));
+   before - cfg.recipients.len()
+}
+
+/// Prepares a campaign for sending with the given templates (or those of its *variants*) and
+/// checks it:
+///
+/// - the images the HTML templates embed are loaded
+/// - the HTML must not have what mail clients mangle, after sanitizing if *sanitize_html* is set
+/// - the domains of the recipients must accept email, if `nameservers` are given
+/// - the recipients must have the data all templates need
+/// - the emails must not exceed *max_message_size* if *on_oversize* is `abort`
+///
+/// The recipients quarantined while parsing the config are problems too, unless `skip_invalid`
+/// is set: then they and the recipients that fail the checks are quarantined and reported as
+/// warnings. The personalized attachments are generated once the checks pass, if asked to.
+///
+/// Returns an error if the domains cannot be looked up or the attachments cannot be generated.
+pub fn prepare(
+   cfg: &mut Config,
+   template: &Arc<Template>,
+   html: Option<&Arc<Template>>,
+   preparation: &Preparation,
+) -> Result<Checks, String> {
+   let mut checks = Checks::default();
+   if !preparation.skip_invalid {
+      checks.problems.extend(
+         cfg.quarantine
+            .iter()
+            .enumerate()
+            .map(|(i, reason)| Diagnostic::error(reason).at(cfg.sources.quarantined(i))),
+      );
+   }
+   let (texts, htmls) = templates(cfg, template, html);
+   for html in htmls.iter() {
+      if let Err(error) = cfg.embed_images(html) {
+         checks.problems.push(Diagnostic::error(&error));
+      }
+      // the problems sanitizing fixes are only reported if the HTML is not sanitized
+      let text = if cfg.sanitize_html {
+         sanitizer::sanitize(html.text())
+      } else {
+         html.text().to_string()
+      };
+      checks.problems.extend(
+         sanitizer::check(&text)
+            .iter()
+            .map(|p| Diagnostic::error(&format!("HTML template: {}", p))),
+      );
+   }
+   if htmls.is_empty() && !cfg.inline_images.is_empty() {
+      checks.warnings.push(
+         Diagnostic::warning("no HTML template, the inline images are not used")
+            .at(cfg.sources.origin("general", Some("inline_images"))),
+      );
+   }
+   if let Some(ref servers) = preparation.nameservers {
+      let undeliverable = dns::verify_recipients(cfg, servers)?;
+      if preparation.skip_invalid {
+         cfg.recipients
+            .retain(|r| !undeliverable.iter().any(|(email, _)| email == &r.email));
+         cfg.quarantine.extend(
+            undeliverable
+               .into_iter()
+               .map(|(email, reason)| format!("{}: {}", email, reason)),
+         );
+      } else {
+         checks
+            .problems
+            .extend(undeliverable.iter().map(|(email, reason)| {
+               Diagnostic::error(&format!("{}: {}", email, reason)).at(cfg.sources.recipient(email))
+            }));
+      }
+   }
+   let all: Vec<Arc<Template>> = texts.into_iter().chain(htmls).collect();
+   if preparation.skip_invalid {
+      for t in all.iter() {
+         t.quarantine(cfg);
+      }
+      checks.warnings.extend(
+         cfg.quarantine
+            .iter()
+            .enumerate()
+            .map(|(i, reason)| Diagnostic::warning(reason).at(cfg.sources.quarantined(i))),
+      );
+   }
+   for t in all.iter() {
+      for rcp in cfg.recipients.iter() {
+         if let Err(error) = t.check_recipient(rcp, &cfg.defaults) {
+            checks
+               .problems
+               .push(Diagnostic::error(&error).at(cfg.sources.recipient(&rcp.email)));
+         }
+      }
+   }
+   let used: Vec<&Template> = all.iter().map(|t| t.as_ref()).collect();
+   for (key, count) in lint::unused_keys(cfg, &used) {
+      checks.warnings.push(Diagnostic::warning(&format!(
+         "the recipient data key {} ({} recipient(s)) is not used by the template",
+         key, count
+      )));
+   }
+   // the attachments count towards the size of the emails
+   if preparation.attachments && checks.problems.is_empty() && cfg.attachment_cmd.is_some() {
+      cfg.generate_attachments()?;
+   }
+   let abort = cfg.on_oversize == OnOversize::Abort;
+   for (email, size) in message::oversized(cfg, template, html.map(|h| h.as_ref())) {
+      let message = format!(
+         "{}: the email is {}, the limit is {}",
+         email,
+         config::format_size(size as u64),
+         config::format_size(cfg.max_message_size)
+      );
+      let origin = cfg.sources.recipient(&email);
+      if abort {
+         checks.problems.push(Diagnostic::error(&message).at(origin));
+      } else {
+         checks
+            .warnings
+            .push(Diagnostic::warning(&message).at(origin));
+      }
+   }
+   Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::template;
+   use crate::testing::{self, MockTransport};
+   use tempfile::TempDir;
+
+   fn campaign(transport: MockTransport) -> Campaign<MockTransport> {
+      let mut cfg = testing::config("fb@example.com", "Hello %_FN%");
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[("ORG", "EFF")]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[("ORG", "Disney")]),
+      ];
+      Campaign::new(cfg, template::new("Hi %_FN% at %ORG%"), transport)
+   }
+
+   #[test]
+   fn render_and_send() {
+      let mut campaign = campaign(MockTransport::new().fail_for("mm@gmail.com"))
+         .with_html(template::new("<p>Hi %_FN%</p>"));
+      assert_eq!(Ok(()), campaign.validate());
+      let messages = campaign.render_all();
+      assert_eq!(2, messages.len());
+      assert_eq!("Hi John at EFF", messages[0].body);
+      assert_eq!(Some("<p>Hi Mickey</p>".to_string()), messages[1].html);
+      let deliveries = campaign.send().expect("invalid campaign");
+      assert_eq!(2, deliveries.len());
+      assert_eq!(1, deliveries.iter().filter(|d| d.result.is_err()).count());
+      assert!(campaign.transport.sent_to("jd@example.com").is_some());
+   }
+
+   #[test]
+   fn invalid_campaign() {
+      let mut campaign = campaign(MockTransport::new())
+         .with_html(template::new("<p>%TITLE%<script>x()</script></p>"));
+      let problems = campaign.send().expect_err("valid campaign");
+      assert_eq!(3, problems.len(), "{:?}", problems);
+      assert!(problems[0].starts_with("HTML template: "));
+      assert!(campaign.transport.messages.is_empty());
+   }
+
+   #[test]
+   fn prepare_for_sending() {
+      let dir = TempDir::new().expect("no temporary directory");
+      let ledger_path = dir.path().join("ledger.tsv");
+      let mut ledger = Ledger::open(&ledger_path).expect("no ledger");
+      let mut cfg = campaign(MockTransport::new()).config;
+      ledger
+         .record(&cfg.campaign_id, "jd@example.com", "<1@example.com>", 0)
+         .expect("not recorded");
+      let mut campaign = campaign(MockTransport::new())
+         .with_html(template::new("<p>Hi %_FN% %_CID:logo.png%</p>"))
+         .with_ledger(Ledger::open(&ledger_path).expect("no ledger"));
+      let problems = campaign.send().expect_err("valid campaign");
+      assert_eq!(1, problems.len(), "{:?}", problems);
+      assert!(problems[0].contains("logo.png"), "{}", problems[0]);
+      // the recipient the ledger has on record is skipped
+      assert_eq!(1, campaign.config.recipients.len());
+
+      cfg.recipients
+         .push(testing::recipient("oh@example.com", "Old Hat", &[]));
+      let tmpl = Arc::new(template::new("Hi %_FN% at %ORG%"));
+      let preparation = Preparation {
+         skip_invalid: true,
+         ..Default::default()
+      };
+      let checks = prepare(&mut cfg, &tmpl, None, &preparation).expect("not prepared");
+      assert_eq!(Ok(()), checks.result());
+      assert_eq!(2, cfg.recipients.len());
+      assert_eq!(1, checks.warnings.len(), "{:?}", checks.warnings);
+      assert!(checks.warnings[0].message.contains("ORG"));
+   }
+}
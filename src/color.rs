@@ -0,0 +1,70 @@
+/// The `color` module colors the terminal output: errors red, warnings yellow and success
+/// summaries green.
+use console::style;
+use std::env;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `ColorChoice` enum lists the values of the `--color` flag.
+pub enum ColorChoice {
+   /// Colors if stdout is a terminal and `NO_COLOR` is not set (the default)
+   #[default]
+   Auto,
+   /// Colors even if the output is piped e.g. into `less -R`
+   Always,
+   /// No colors
+   Never,
+}
+
+impl ColorChoice {
+   /// Parses the `--color` flag: `auto`, `always` or `never`.
+   pub fn parse(text: &str) -> Result<ColorChoice, String> {
+      match text.trim().to_lowercase().as_str() {
+         "auto" => Ok(ColorChoice::Auto),
+         "always" => Ok(ColorChoice::Always),
+         "never" => Ok(ColorChoice::Never),
+         _ => Err(format!("invalid color choice: {}", text)),
+      }
+   }
+}
+
+/// Turns the colors on or off for the rest of the process, this also applies to the progress bar.
+pub fn init(choice: ColorChoice) {
+   let enabled = match choice {
+      ColorChoice::Always => true,
+      ColorChoice::Never => false,
+      ColorChoice::Auto => {
+         env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && console::colors_enabled()
+      }
+   };
+   console::set_colors_enabled(enabled);
+}
+
+/// Returns the text in red, if colors are enabled.
+pub fn error(text: &str) -> String {
+   style(text).red().to_string()
+}
+
+/// Returns the text in yellow, if colors are enabled.
+pub fn warning(text: &str) -> String {
+   style(text).yellow().to_string()
+}
+
+/// Returns the text in green, if colors are enabled.
+pub fn success(text: &str) -> String {
+   style(text).green().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn parse_color_choice() {
+      assert_eq!(Ok(ColorChoice::Always), ColorChoice::parse("Always "));
+      assert_eq!(Ok(ColorChoice::Never), ColorChoice::parse("never"));
+      assert_eq!(
+         Err(String::from("invalid color choice: rainbow")),
+         ColorChoice::parse("rainbow")
+      );
+   }
+}
@@ -1,20 +1,40 @@
 /// The `config` module implements the logic for parsing config files.
+use crate::address::{self, Address};
+use crate::attachment::{self, Attachment};
+use crate::credentials;
+use crate::encoding::TransferEncoding;
+use crate::greeting::Formality;
+use crate::listing;
+use crate::message;
+use crate::schema::{DataType, Locale};
+use crate::secrets;
+use crate::segment::{self, Segment};
+use crate::template::{self, Engine, Template};
+use crate::tz;
+use crate::user_config::{self, Defaults, UserConfig};
+use chrono::{DateTime, FixedOffset, Utc};
 use ini::Ini;
 use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use yaml_rust::{Yaml, YamlLoader};
 
 #[derive(Debug)]
 /// The `GData` struct holds the contents of the [general] section that may be overridden for
 /// particular recipients.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GData {
-   /// The 'From' email header value (a valid email address)
-   pub from: String,
+   /// The 'From' email header value
+   pub from: Address,
    /// The email subject
    pub subject: String,
    /// A list of 'Cc' email addresses
-   pub cc: Vec<String>,
+   pub cc: Vec<Address>,
    /// A list of 'Reply-To' email addresses
-   pub replyto: Vec<String>,
+   pub replyto: Vec<Address>,
 }
 
 impl PartialEq for GData {
@@ -27,31 +47,635 @@ impl PartialEq for GData {
    }
 }
 
-impl ToString for GData {
+impl fmt::Display for GData {
    /// Makes it possible to print instances of `GData`
-   fn to_string(&self) -> String {
-      let mut result = format!("from: {}, subject: {}", self.from, self.subject);
-      if self.cc.len() > 0 {
-         result.push_str(format!(", cc: {}", self.cc.join(", ")).as_ref());
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "from: {}, subject: {}", self.from, self.subject)?;
+      if !self.cc.is_empty() {
+         write!(f, ", cc: {}", join(&self.cc))?;
       }
-      if self.replyto.len() > 0 {
-         result.push_str(format!(", replyto: {}", self.replyto.join(", ")).as_ref());
+      if !self.replyto.is_empty() {
+         write!(f, ", replyto: {}", join(&self.replyto))?;
+      }
+      Ok(())
+   }
+}
+
+/// Joins the addresses with commas, the way they appear in an email header.
+pub fn join(addresses: &[Address]) -> String {
+   addresses
+      .iter()
+      .map(|a| a.to_string())
+      .collect::<Vec<String>>()
+      .join(", ")
+}
+
+#[derive(Debug)]
+/// The `Signature` struct holds a named signature from the [signatures] section.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Signature {
+   /// The plain text variant of the signature
+   pub text: String,
+   /// The optional HTML variant of the signature
+   pub html: Option<String>,
+}
+
+impl PartialEq for Signature {
+   /// Makes it possible to compare instances of `Signature`
+   fn eq(&self, other: &Self) -> bool {
+      self.text == other.text && self.html == other.html
+   }
+}
+
+#[derive(Debug)]
+/// The `Variant` struct holds a template variant of an A/B test.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Variant {
+   /// The name of the variant e.g. `a`
+   pub name: String,
+   /// The share of the recipients that get the variant, relative to the other variants' weights
+   pub weight: u32,
+   /// The plain text template
+   pub template: Arc<Template>,
+   /// The optional HTML template
+   pub html: Option<Arc<Template>>,
+}
+
+impl PartialEq for Variant {
+   /// Makes it possible to compare instances of `Variant`
+   fn eq(&self, other: &Self) -> bool {
+      self.name == other.name
+         && self.weight == other.weight
+         && self.template.text() == other.template.text()
+         && self.html.as_ref().map(|h| h.text()) == other.html.as_ref().map(|h| h.text())
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `Threading` struct holds the settings that make the emails of a follow-up campaign thread
+/// under the messages of a previous one.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Threading {
+   /// The `Message-ID` of the message all emails are a reply to
+   pub in_reply_to: Option<String>,
+   /// The `Message-ID`s of the messages in the thread
+   pub references: Vec<String>,
+   /// The identifier of a previous campaign; the emails are a reply to the message the respective
+   /// recipient received in that campaign
+   pub follow_up_of: Option<String>,
+   /// The `Message-ID`s of the emails sent in the *follow_up_of* campaign by the (lowercase)
+   /// address of their recipient, see `history::thread_under`
+   #[cfg_attr(feature = "serde", serde(skip, default))]
+   pub message_ids: HashMap<String, String>,
+}
+
+impl PartialEq for Threading {
+   /// Makes it possible to compare instances of `Threading`
+   fn eq(&self, other: &Self) -> bool {
+      self.in_reply_to == other.in_reply_to
+         && self.references == other.references
+         && self.follow_up_of == other.follow_up_of
+         && self.message_ids == other.message_ids
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `Tracking` struct holds the contents of the [tracking] section i.e. how the links in HTML
+/// emails are rewritten for click tracking.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tracking {
+   /// The UTM parameters (name and value) appended to all links
+   pub utm: Vec<(String, String)>,
+   /// The base URL of a redirect service the links are routed through
+   pub redirect_url: Option<String>,
+   /// The key of the recipients' tracking tokens (see `message::tracking_token`), never
+   /// serialized
+   #[cfg_attr(feature = "serde", serde(skip_serializing, default))]
+   pub secret: Option<String>,
+}
+
+impl PartialEq for Tracking {
+   /// Makes it possible to compare instances of `Tracking`
+   fn eq(&self, other: &Self) -> bool {
+      self.utm == other.utm
+         && self.redirect_url == other.redirect_url
+         && self.secret == other.secret
+   }
+}
+
+impl Tracking {
+   /// Returns whether the links in HTML emails are to be rewritten at all.
+   pub fn is_active(&self) -> bool {
+      !self.utm.is_empty() || self.redirect_url.is_some()
+   }
+}
+
+#[derive(Debug)]
+/// The `Event` struct holds the contents of the [event] section i.e. the event the recipients are
+/// invited to with a personalized calendar invitation.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Event {
+   /// What the event is about, may use the template keys
+   pub summary: String,
+   /// When the event starts
+   pub start: DateTime<Utc>,
+   /// When the event ends
+   pub end: DateTime<Utc>,
+   /// Where the event takes place e.g. a room or a URL
+   pub location: Option<String>,
+   /// The details of the event, may use the template keys
+   pub description: Option<String>,
+   /// Who organizes the event, the *From* address by default
+   pub organizer: Address,
+}
+
+impl PartialEq for Event {
+   /// Makes it possible to compare instances of `Event`
+   fn eq(&self, other: &Self) -> bool {
+      self.summary == other.summary
+         && self.start == other.start
+         && self.end == other.end
+         && self.location == other.location
+         && self.description == other.description
+         && self.organizer == other.organizer
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The `Security` enum defines how the connection to the SMTP server is secured.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Security {
+   /// The connection is TLS wrapped from the start (the default, port 465)
+   Tls,
+   /// The connection is upgraded with `STARTTLS` which is required (port 587)
+   StartTls,
+   /// The connection is not encrypted at all (for testing purposes, port 25)
+   None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The `Rate` struct holds a rate limit i.e. the maximum number of emails sent per period.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rate {
+   /// The maximum number of emails
+   pub messages: u32,
+   /// The period
+   pub per: Duration,
+}
+
+impl Rate {
+   /// Returns the minimum time between two emails.
+   pub fn interval(&self) -> Duration {
+      self.per / self.messages
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The `Jitter` struct holds the range the time between two emails is picked from at random, so
+/// that the emails are not sent at perfectly uniform intervals.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Jitter {
+   /// The shortest time between two emails
+   pub min: Duration,
+   /// The longest time between two emails
+   pub max: Duration,
+}
+
+impl Jitter {
+   /// Picks a time between two emails, `random` is a random number.
+   pub fn pick(&self, random: u64) -> Duration {
+      let span = (self.max - self.min).as_millis() as u64;
+      self.min + Duration::from_millis(random % (span + 1))
+   }
+
+   /// Returns the average time between two emails.
+   pub fn mean(&self) -> Duration {
+      (self.min + self.max) / 2
+   }
+}
+
+/// Returns the next number of the splitmix64 pseudo-random sequence and advances its `state`.
+pub fn splitmix64(state: &mut u64) -> u64 {
+   *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+   let mut z = *state;
+   z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+   z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+   z ^ (z >> 31)
+}
+
+/// Parses a jitter range like `5-30s`, the units are `ms`, `s` (the default) and `min`.
+pub fn parse_jitter(jitter: &str) -> Result<Jitter, String> {
+   let re = Regex::new(r"^(\d+)\s*-\s*(\d+)\s*(ms|s|min)?$").unwrap();
+   let caps = re
+      .captures(jitter.trim())
+      .ok_or_else(|| format!("invalid jitter: {}", jitter))?;
+   let unit = match caps.get(3).map(|m| m.as_str()) {
+      Some("ms") => Duration::from_millis(1),
+      Some("min") => Duration::from_secs(60),
+      _ => Duration::from_secs(1),
+   };
+   let bound = |i: usize| caps[i].parse::<u32>().map(|n| unit * n);
+   match (bound(1), bound(2)) {
+      (Ok(min), Ok(max)) if min <= max => Ok(Jitter { min, max }),
+      _ => Err(format!("invalid jitter: {}", jitter)),
+   }
+}
+
+/// Parses a rate limit like `30/min`, the periods are `s`, `min` and `h`.
+pub fn parse_rate(rate: &str) -> Result<Rate, String> {
+   let error = || format!("invalid rate limit: {}", rate);
+   let mut parts = rate.trim().splitn(2, '/');
+   let messages = match parts.next().map(|n| n.trim().parse::<u32>()) {
+      Some(Ok(n)) if n > 0 => n,
+      _ => return Err(error()),
+   };
+   let per = match parts.next().map(|p| p.trim()) {
+      Some("s") | Some("sec") => Duration::from_secs(1),
+      Some("min") => Duration::from_secs(60),
+      Some("h") | Some("hour") => Duration::from_secs(3600),
+      _ => return Err(error()),
+   };
+   Ok(Rate { messages, per })
+}
+
+/// The maximum number of connections to the SMTP server, most servers limit the connections
+/// per client anyway.
+pub const MAX_CONNECTIONS: usize = 32;
+
+/// The default maximum size of a message (10 MB), many providers reject larger ones.
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Formats a size in bytes for humans e.g. `1.5 MB`.
+pub fn format_size(bytes: u64) -> String {
+   match bytes {
+      b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+      b if b >= 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+      b => format!("{} bytes", b),
+   }
+}
+
+/// Parses a size in bytes like `10M`, the suffixes are `K`, `M` and `G` (optionally followed by
+/// `B`), powers of 1024.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+   let error = || format!("invalid size: {}", size);
+   let upper = size.trim().to_uppercase();
+   let number = upper.strip_suffix('B').unwrap_or(&upper);
+   let (number, factor) = match number.chars().last() {
+      Some('K') => (&number[..number.len() - 1], 1024),
+      Some('M') => (&number[..number.len() - 1], 1024 * 1024),
+      Some('G') => (&number[..number.len() - 1], 1024 * 1024 * 1024),
+      _ => (number, 1),
+   };
+   number
+      .trim()
+      .parse::<u64>()
+      .ok()
+      .and_then(|n| n.checked_mul(factor))
+      .ok_or_else(error)
+}
+
+#[derive(Debug, Clone)]
+/// The `Smtp` struct holds the contents of the [smtp] section i.e. the settings needed to send the
+/// emails via an SMTP server.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Smtp {
+   /// The SMTP server host name
+   pub host: String,
+   /// The SMTP server port
+   pub port: u16,
+   /// How the connection to the SMTP server is secured
+   pub security: Security,
+   /// The user name to authenticate with (if any)
+   pub username: Option<String>,
+   /// The password to authenticate with (if any), never serialized
+   #[cfg_attr(feature = "serde", serde(skip_serializing, default))]
+   pub password: Option<String>,
+   /// The name of the system keyring entry holding the password, instead of the password
+   pub keyring: Option<String>,
+   /// The maximum rate at which emails are sent (if any)
+   pub rate: Option<Rate>,
+   /// The maximum rates at which emails are sent to the recipients of a domain, keyed by the
+   /// (lower case) domain, the `default` entry applies to the domains not listed
+   pub domain_rates: HashMap<String, Rate>,
+   /// The range the time between two emails is picked from at random (if any)
+   pub jitter: Option<Jitter>,
+   /// Whether the emails are sent grouped by the recipient domains (in alphabetical order)
+   /// instead of in the order of the config file
+   pub group_by_domain: bool,
+   /// The maximum number of emails sent to a domain in a row when grouping by domain, the
+   /// domains take turns if they have more recipients
+   pub domain_burst: Option<usize>,
+   /// The number of connections to the SMTP server the emails are sent over at the same time
+   pub connections: usize,
+   /// When to ask for delivery status notifications (RFC 3461) e.g. `SUCCESS,FAILURE`, the
+   /// `NOTIFY` parameter of the recipients
+   pub dsn_notify: Option<String>,
+   /// What a delivery status notification returns of the message, `HDRS` or `FULL`
+   pub dsn_ret: Option<String>,
+   /// Whether the server supports SMTPUTF8 (RFC 6531) i.e. internationalized domains may appear
+   /// in the headers in their Unicode form, they are converted to punycode otherwise
+   pub smtputf8: bool,
+}
+
+impl Smtp {
+   /// Returns the average time between two emails as per the rate limit and the jitter, `None`
+   /// if neither is set.
+   pub fn interval(&self) -> Option<Duration> {
+      let rate = self.rate.map(|r| r.interval());
+      let jitter = self.jitter.map(|j| j.mean());
+      rate.max(jitter)
+   }
+
+   /// Returns the maximum rate at which emails are sent to the recipients of the given domain
+   /// (if any).
+   pub fn domain_rate(&self, domain: &str) -> Option<Rate> {
+      let domain = domain.to_lowercase();
+      self
+         .domain_rates
+         .get(&domain)
+         .or_else(|| self.domain_rates.get("default"))
+         .copied()
+   }
+
+   /// Returns the estimated time needed to send emails to the given recipients, `None` if they
+   /// are not rate limited at all.
+   ///
+   /// The domains are sent to in turns, so the estimate is the longer of the time the overall
+   /// limit needs for all recipients and the time the slowest domain needs for its recipients.
+   pub fn estimate(&self, recipients: &[Recipient]) -> Option<Duration> {
+      let mut counts: HashMap<String, u32> = HashMap::new();
+      for rcp in recipients.iter() {
+         *counts
+            .entry(email_domain(&rcp.email).unwrap_or_default())
+            .or_insert(0) += 1;
+      }
+      let total = self.interval().map(|i| i * recipients.len() as u32);
+      let slowest = counts
+         .iter()
+         .filter_map(|(domain, n)| self.domain_rate(domain).map(|r| r.interval() * *n))
+         .max();
+      total.max(slowest)
+   }
+
+   /// Fills in the credentials that are not given in the *smtp* section.
+   ///
+   /// The password is read from the system keyring if the section names a keyring entry. A
+   /// section without any credentials takes them from the `RMT_SMTP_USERNAME` and
+   /// `RMT_SMTP_PASSWORD` variables or else from the `~/.netrc` entry for the host.
+   pub fn resolve_credentials(&mut self) -> Result<(), String> {
+      if let (None, Some(name)) = (&self.password, &self.keyring) {
+         self.password = Some(secrets::get(name)?);
+      }
+      if self.username.is_some() || self.password.is_some() {
+         return Ok(());
+      }
+      let found = match credentials::from_env() {
+         Some(found) => Some(found),
+         None => match credentials::netrc_path() {
+            Some(path) if path.exists() => {
+               let text = std::fs::read_to_string(&path)
+                  .map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+               credentials::netrc_lookup(&text, &self.host)
+            }
+            _ => None,
+         },
+      };
+      if let Some((username, password)) = found {
+         self.username = Some(username);
+         self.password = Some(password);
+      }
+      Ok(())
+   }
+}
+
+impl PartialEq for Smtp {
+   /// Makes it possible to compare instances of `Smtp`
+   fn eq(&self, other: &Self) -> bool {
+      self.host == other.host
+         && self.port == other.port
+         && self.security == other.security
+         && self.username == other.username
+         && self.password == other.password
+         && self.keyring == other.keyring
+         && self.rate == other.rate
+         && self.domain_rates == other.domain_rates
+         && self.jitter == other.jitter
+         && self.group_by_domain == other.group_by_domain
+         && self.domain_burst == other.domain_burst
+         && self.connections == other.connections
+         && self.dsn_notify == other.dsn_notify
+         && self.dsn_ret == other.dsn_ret
+         && self.smtputf8 == other.smtputf8
+   }
+}
+
+#[derive(Debug, Clone)]
+/// The `Imap` struct holds the contents of the [imap] section i.e. the mailbox copies of the
+/// emails sent are stored in.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Imap {
+   /// The IMAP server host name
+   pub host: String,
+   /// The IMAP server port
+   pub port: u16,
+   /// How the connection to the IMAP server is secured
+   pub security: Security,
+   /// The user name to log in with
+   pub username: String,
+   /// The password to log in with, never serialized
+   #[cfg_attr(feature = "serde", serde(skip_serializing, default))]
+   pub password: Option<String>,
+   /// The name of the system keyring entry holding the password, instead of the password
+   pub keyring: Option<String>,
+   /// The folder the copies are stored in e.g. `Sent`
+   pub folder: String,
+}
+
+impl Imap {
+   /// Fills in the password if it is not given in the *imap* section, it is read from the
+   /// system keyring if the section names a keyring entry or else from the `~/.netrc` entry for
+   /// the host.
+   pub fn resolve_credentials(&mut self) -> Result<(), String> {
+      if self.password.is_some() {
+         return Ok(());
+      }
+      if let Some(ref name) = self.keyring {
+         self.password = Some(secrets::get(name)?);
+         return Ok(());
+      }
+      if let Some(path) = credentials::netrc_path().filter(|p| p.exists()) {
+         let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+         self.password = credentials::netrc_lookup(&text, &self.host)
+            .filter(|(login, _)| login == &self.username)
+            .map(|(_, password)| password);
+      }
+      match self.password {
+         Some(_) => Ok(()),
+         None => Err(format!(
+            "no password for {} on the imap server {}",
+            self.username, self.host
+         )),
+      }
+   }
+}
+
+impl PartialEq for Imap {
+   /// Makes it possible to compare instances of `Imap`
+   fn eq(&self, other: &Self) -> bool {
+      self.host == other.host
+         && self.port == other.port
+         && self.security == other.security
+         && self.username == other.username
+         && self.password == other.password
+         && self.keyring == other.keyring
+         && self.folder == other.folder
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `OnDuplicate` enum defines what happens if an email address appears more than once among
+/// the recipients (the comparison is case-insensitive).
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OnDuplicate {
+   /// The config is rejected (the default)
+   #[default]
+   Error,
+   /// The first entry is used, later ones are ignored
+   KeepFirst,
+   /// The last entry is used
+   KeepLast,
+   /// The data of all entries is merged, later values win; the names are the first entry's
+   Merge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `OnOversize` enum defines what happens if a message exceeds the maximum message size.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OnOversize {
+   /// Nothing is sent (the default)
+   #[default]
+   Abort,
+   /// The oversized messages are reported but sent anyway
+   Warn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `ListMerge` enum defines how the lists (recipients, attachments, *Cc* addresses etc.) of
+/// two configs are combined by `Config::merge`.
+pub enum ListMerge {
+   /// The entries of the later config are added to the ones of the earlier config (the default)
+   #[default]
+   Append,
+   /// The entries of the later config replace the ones of the earlier config, unless there are
+   /// none
+   Replace,
+}
+
+impl ListMerge {
+   /// Combines the lists as per the policy.
+   fn apply<T>(self, list: &mut Vec<T>, other: Vec<T>) {
+      match self {
+         ListMerge::Append => list.extend(other),
+         ListMerge::Replace if !other.is_empty() => *list = other,
+         ListMerge::Replace => (),
       }
-      result
    }
 }
 
 #[derive(Debug)]
 /// The `Config` struct holds the contents of the config file after the latter was parsed
 /// successfully.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Config {
    /// The data in the [general] section that may be overidden for recipients
    pub gdata: GData,
+   /// The campaign identifier, used as the first part of the generated `Message-ID` headers
+   pub campaign_id: String,
+   /// The domain used for the generated `Message-ID` headers
+   pub msgid_domain: String,
+   /// The threading headers settings
+   pub threading: Threading,
+   /// Whether non-ASCII display names are transliterated to ASCII instead of being encoded
+   pub transliterate: bool,
+   /// The URL of the image that tracks opened (HTML) emails; tracking is off unless this is set
+   pub tracking_pixel_url: Option<String>,
+   /// The click tracking settings
+   pub tracking: Tracking,
+   /// The URL the campaign events are POSTed to (if any)
+   pub webhook_url: Option<String>,
+   /// The path of the audit log every delivery attempt is recorded in (if any)
+   pub audit_log: Option<String>,
+   /// The address read receipts are requested to with a `Disposition-Notification-To` header
+   pub disposition_notification_to: Option<Address>,
+   /// The operator address a summary of every campaign run is sent to (if any)
+   pub summary_to: Option<Address>,
+   /// The CSV files additional recipients are read from
+   pub recipients_csv: Vec<String>,
+   /// What to do about recipients that appear more than once
+   pub on_duplicate: OnDuplicate,
+   /// How the body parts of the emails are encoded
+   pub transfer_encoding: TransferEncoding,
+   /// The files attached to every email
+   pub attachments: Vec<Arc<Attachment>>,
+   /// The command that generates a personalized attachment for every recipient
+   pub attachment_cmd: Option<String>,
+   /// The attachments generated by the *attachment_cmd*, by recipient email address
+   pub generated_attachments: HashMap<String, Arc<Attachment>>,
+   /// The images embedded in HTML emails
+   pub inline_images: Vec<Arc<Attachment>>,
+   /// Whether the CSS rules of HTML emails are inlined into the `style` attributes
+   pub inline_css: bool,
+   /// Whether scripts, frames etc. are stripped from HTML emails
+   pub sanitize_html: bool,
+   /// The preview text inboxes show next to the subject of HTML emails (templated)
+   pub preheader: Option<String>,
+   /// The maximum size of a message in bytes, 0 means no limit
+   pub max_message_size: u64,
+   /// What to do about messages that exceed the maximum size
+   pub on_oversize: OnOversize,
+   /// The syntax of the templates
+   pub engine: Engine,
+   /// How formal the `%_GREETING%` salutations are
+   pub greeting: Formality,
+   /// The address all emails are delivered to instead of the recipients, for reviewing a
+   /// campaign
+   pub redirect_to: Option<String>,
+   /// When to start sending the emails, `None` to send them right away
+   pub send_at: Option<DateTime<Utc>>,
+   /// The name of the tool
    pub tool_name: String,
    /// The version of the tool
    pub tool_version: String,
+   /// The settings of the SMTP server used to send the emails, only needed for actual sends
+   pub smtp: Option<Smtp>,
+   /// The mailbox copies of the emails sent are stored in (if any)
+   pub imap: Option<Imap>,
+   /// The named signatures recipients may pick with a `signature` datum
+   pub signatures: HashMap<String, Signature>,
+   /// The signature of the recipients that do not pick one, read from the *signature_file*
+   pub signature: Option<Signature>,
+   /// The values of the recipient data keys for the recipients that lack them
+   pub defaults: HashMap<String, String>,
+   /// The template variants of an A/B test, ordered by name
+   pub variants: Vec<Variant>,
+   /// The types of the recipient data keys declared in the *schema* section
+   pub schema: HashMap<String, DataType>,
+   /// The conventions for formatting the typed recipient data
+   pub locale: Locale,
+   /// The event the recipients are invited to, if any
+   pub event: Option<Event>,
    /// A list of recipients who should recaive the email
    pub recipients: Vec<Recipient>,
+   /// The reasons for skipping invalid recipients, only populated by the lenient parser
+   pub quarantine: Vec<String>,
+   /// Where the settings and recipients were read from, only populated by `instantiate` & co.
+   #[cfg_attr(feature = "serde", serde(skip, default))]
+   pub sources: Sources,
+   /// Whether the campaign was uploaded to `rmt serve`, its credentials are never looked up (see
+   /// `resolve_credentials`)
+   #[cfg_attr(feature = "serde", serde(skip, default))]
+   pub confined: bool,
 }
 
 impl PartialEq for Config {
@@ -61,20 +685,363 @@ impl PartialEq for Config {
    }
 }
 
-impl ToString for Config {
+impl fmt::Display for Config {
    /// Makes it possible to print instances of `Config`
-   fn to_string(&self) -> String {
-      let mut result = self.gdata.to_string();
-      result.push_str(format!(", recipients: {{{}}}", self.recipients[0].to_string()).as_ref());
-      for recipient in self.recipients.iter().skip(1) {
-         result.push_str(format!(", {{{}}}", recipient.to_string()).as_ref());
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "{}, recipients: ", self.gdata)?;
+      for (i, recipient) in self.recipients.iter().enumerate() {
+         if i > 0 {
+            write!(f, ", ")?;
+         }
+         write!(f, "{{{}}}", recipient)?;
+      }
+      Ok(())
+   }
+}
+
+impl Config {
+   /// Returns a builder for making a config in code instead of reading it from a file.
+   pub fn builder() -> ConfigBuilder {
+      ConfigBuilder::default()
+   }
+
+   /// Returns the *From* address of the campaign.
+   pub fn from(&self) -> &Address {
+      &self.gdata.from
+   }
+
+   /// Returns the (templated) subject of the campaign.
+   pub fn subject(&self) -> &str {
+      &self.gdata.subject
+   }
+
+   /// Fills in the credentials the *smtp* section and, with `with_imap` set, the *imap* section
+   /// do not give (see `Smtp::resolve_credentials` and `Imap::resolve_credentials`).
+   ///
+   /// The credentials of a confined campaign are never looked up: the environment, `~/.netrc` and
+   /// the keyring hold the secrets of the user running `rmt serve`, not those of the uploader.
+   pub fn resolve_credentials(&mut self, with_imap: bool) -> Result<(), String> {
+      if self.confined {
+         return match self.imap {
+            Some(ref imap) if with_imap && imap.password.is_none() => Err(format!(
+               "no password for {} on the imap server {}",
+               imap.username, imap.host
+            )),
+            _ => Ok(()),
+         };
+      }
+      if let Some(ref mut smtp) = self.smtp {
+         smtp.resolve_credentials()?;
+      }
+      match self.imap {
+         Some(ref mut imap) if with_imap => imap.resolve_credentials(),
+         _ => Ok(()),
+      }
+   }
+
+   /// Returns the number of recipients the campaign is sent to, the quarantined ones not counted.
+   pub fn recipient_count(&self) -> usize {
+      self.recipients.len()
+   }
+
+   /// Returns a multi-line, human-readable description of the campaign: the main settings, the
+   /// default values and the recipients with their data (sorted by key).
+   pub fn dump(&self) -> String {
+      let mut result = format!(
+         "From: {}\nSubject: {}\n",
+         self.gdata.from, self.gdata.subject
+      );
+      if !self.gdata.cc.is_empty() {
+         result.push_str(&format!("Cc: {}\n", join(&self.gdata.cc)));
+      }
+      if !self.gdata.replyto.is_empty() {
+         result.push_str(&format!("Reply-To: {}\n", join(&self.gdata.replyto)));
+      }
+      result.push_str(&format!("campaign id: {}\n", self.campaign_id));
+      result.push_str(&format!("message id domain: {}\n", self.msgid_domain));
+      let engine = match self.engine {
+         Engine::Rmt => "rmt",
+         Engine::Tera => "tera",
+      };
+      result.push_str(&format!("template engine: {}\n", engine));
+      if let Some(ref smtp) = self.smtp {
+         result.push_str(&format!("smtp server: {}:{}\n", smtp.host, smtp.port));
+      }
+      if let Some(ref send_at) = self.send_at {
+         result.push_str(&format!("send at: {}\n", send_at.to_rfc3339()));
+      }
+      if let Some(ref event) = self.event {
+         result.push_str(&format!(
+            "event: {} ({} - {})\n",
+            event.summary,
+            event.start.to_rfc3339(),
+            event.end.to_rfc3339()
+         ));
+      }
+      let sorted = |data: &HashMap<String, String>| {
+         let mut result: Vec<String> = data.iter().map(|(k, v)| format!("{} = {}", k, v)).collect();
+         result.sort();
+         result
+      };
+      if !self.defaults.is_empty() {
+         result.push_str("defaults:\n");
+         for line in sorted(&self.defaults) {
+            result.push_str(&format!("   {}\n", line));
+         }
+      }
+      result.push_str(&format!("recipients ({}):\n", self.recipients.len()));
+      for rcp in self.recipients.iter() {
+         result.push_str(&format!("   {}: {}\n", rcp.email, rcp.names.join(" ")));
+         for line in sorted(&rcp.data) {
+            result.push_str(&format!("      {}\n", line));
+         }
+      }
+      if !self.quarantine.is_empty() {
+         result.push_str(&format!("quarantined ({}):\n", self.quarantine.len()));
+         for reason in self.quarantine.iter() {
+            result.push_str(&format!("   {}\n", reason));
+         }
       }
       result
    }
+
+   /// Runs the *attachment_cmd* (if any) for every recipient, see `attachment::generate`.
+   pub fn generate_attachments(&mut self) -> Result<(), String> {
+      let command = match self.attachment_cmd {
+         Some(ref command) => command,
+         None => return Ok(()),
+      };
+      for rcp in self.recipients.iter() {
+         let attachment = attachment::generate(command, &template::values(self, rcp))
+            .map_err(|e| format!("cannot generate the attachment for {}: {}", rcp.email, e))?;
+         self
+            .generated_attachments
+            .insert(rcp.email.clone(), Arc::new(attachment));
+      }
+      Ok(())
+   }
+
+   /// Embeds the images the HTML template references with `%_CID:path%`, images with the same
+   /// file name as one of the *inline_images* refer to that.
+   pub fn embed_images(&mut self, html: &Template) -> Result<(), String> {
+      for path in html.cid_references() {
+         let image = attachment::load_inline(&path, &self.msgid_domain)?;
+         if !self.inline_images.iter().any(|i| i.name == image.name) {
+            self.inline_images.push(Arc::new(image));
+         }
+      }
+      Ok(())
+   }
+
+   /// Merges the other config into this one, e.g. to layer the settings of a campaign over
+   /// shared defaults.
+   ///
+   /// The settings of the other config win: optional settings that are set there (e.g. the
+   /// *smtp* section or the *preheader*) replace the ones here, the others (e.g. the *engine*
+   /// or the *locale*) do unless they have their default value; switches (e.g. *inline_css*) are
+   /// on if they are on in either config. The default values, signatures and types are merged
+   /// by key and the *variants* are replaced if the other config has any. The lists are combined
+   /// as per `lists`, recipients that appear in both configs are then handled as per the merged
+   /// *on_duplicate* policy, which fails the merge by default.
+   pub fn merge(&mut self, other: Config, lists: ListMerge) -> Result<(), String> {
+      self.confined |= other.confined;
+      self.gdata.from = other.gdata.from;
+      if !other.gdata.subject.is_empty() {
+         self.gdata.subject = other.gdata.subject;
+      }
+      lists.apply(&mut self.gdata.cc, other.gdata.cc);
+      lists.apply(&mut self.gdata.replyto, other.gdata.replyto);
+      self.campaign_id = other.campaign_id;
+      self.msgid_domain = other.msgid_domain;
+      self.threading.in_reply_to = other
+         .threading
+         .in_reply_to
+         .or(self.threading.in_reply_to.take());
+      lists.apply(&mut self.threading.references, other.threading.references);
+      self.threading.follow_up_of = other
+         .threading
+         .follow_up_of
+         .or(self.threading.follow_up_of.take());
+      self.transliterate |= other.transliterate;
+      self.tracking_pixel_url = other.tracking_pixel_url.or(self.tracking_pixel_url.take());
+      lists.apply(&mut self.tracking.utm, other.tracking.utm);
+      self.tracking.redirect_url = other
+         .tracking
+         .redirect_url
+         .or(self.tracking.redirect_url.take());
+      self.tracking.secret = other.tracking.secret.or(self.tracking.secret.take());
+      self.webhook_url = other.webhook_url.or(self.webhook_url.take());
+      self.audit_log = other.audit_log.or(self.audit_log.take());
+      self.disposition_notification_to = other
+         .disposition_notification_to
+         .or(self.disposition_notification_to.take());
+      self.summary_to = other.summary_to.or(self.summary_to.take());
+      lists.apply(&mut self.recipients_csv, other.recipients_csv);
+      if other.on_duplicate != OnDuplicate::default() {
+         self.on_duplicate = other.on_duplicate;
+      }
+      if other.transfer_encoding != TransferEncoding::default() {
+         self.transfer_encoding = other.transfer_encoding;
+      }
+      lists.apply(&mut self.attachments, other.attachments);
+      self.attachment_cmd = other.attachment_cmd.or(self.attachment_cmd.take());
+      self
+         .generated_attachments
+         .extend(other.generated_attachments);
+      lists.apply(&mut self.inline_images, other.inline_images);
+      self.inline_css |= other.inline_css;
+      self.sanitize_html |= other.sanitize_html;
+      self.preheader = other.preheader.or(self.preheader.take());
+      if other.max_message_size != DEFAULT_MAX_MESSAGE_SIZE {
+         self.max_message_size = other.max_message_size;
+      }
+      if other.on_oversize != OnOversize::default() {
+         self.on_oversize = other.on_oversize;
+      }
+      if other.engine != Engine::default() {
+         self.engine = other.engine;
+      }
+      if other.greeting != Formality::default() {
+         self.greeting = other.greeting;
+      }
+      self.redirect_to = other.redirect_to.or(self.redirect_to.take());
+      self.send_at = other.send_at.or(self.send_at.take());
+      self.smtp = other.smtp.or(self.smtp.take());
+      self.imap = other.imap.or(self.imap.take());
+      self.signatures.extend(other.signatures);
+      self.signature = other.signature.or(self.signature.take());
+      self.defaults.extend(other.defaults);
+      if !other.variants.is_empty() {
+         self.variants = other.variants;
+      }
+      self.schema.extend(other.schema);
+      if other.locale != Locale::default() {
+         self.locale = other.locale;
+      }
+      self.event = other.event.or(self.event.take());
+      let mut recipients = std::mem::take(&mut self.recipients);
+      lists.apply(&mut recipients, other.recipients);
+      let (recipients, duplicates) = dedup_recipients(recipients, self.on_duplicate);
+      self.recipients = recipients;
+      self.quarantine.extend(other.quarantine);
+      match duplicates.into_iter().next() {
+         Some(msg) => Err(msg),
+         None => Ok(()),
+      }
+   }
+
+   /// Returns an iterator over the recipients.
+   pub fn recipients(&self) -> std::slice::Iter<'_, Recipient> {
+      self.recipients.iter()
+   }
+
+   /// Returns an iterator over the recipients with an email address in the domain (compared
+   /// case-insensitively), subdomains do not count.
+   pub fn filter_by_domain<'a>(&'a self, domain: &str) -> impl Iterator<Item = &'a Recipient> {
+      let domain = domain.trim().to_lowercase();
+      self
+         .recipients
+         .iter()
+         .filter(move |rcp| email_domain(&rcp.email).as_ref() == Some(&domain))
+   }
+
+   /// Returns an iterator over the recipients with the tag (see `Recipient::tags`).
+   pub fn filter_by_tag<'a>(&'a self, tag: &str) -> impl Iterator<Item = &'a Recipient> {
+      let tag = tag.trim().to_string();
+      self
+         .recipients
+         .iter()
+         .filter(move |rcp| rcp.tags().contains(&tag))
+   }
+
+   /// Returns an iterator over the recipients in batches of (at most) `n`, e.g. for sending in
+   /// batches; a batch size of 0 counts as 1.
+   pub fn chunked(&self, n: usize) -> std::slice::Chunks<'_, Recipient> {
+      self.recipients.chunks(n.max(1))
+   }
+
+   /// Keeps only the recipients that match any of the `only` patterns (if there are any) and
+   /// none of the `skip` patterns, see `email_matches` for the pattern syntax.
+   pub fn filter_recipients(&mut self, only: &[&str], skip: &[&str]) {
+      self.recipients.retain(|rcp| {
+         (only.is_empty() || only.iter().any(|p| email_matches(p, &rcp.email)))
+            && !skip.iter().any(|p| email_matches(p, &rcp.email))
+      });
+   }
+
+   /// Keeps only the recipients in the segment i.e. the ones whose tags match the segment
+   /// expression.
+   pub fn segment_recipients(&mut self, segment: &Segment) {
+      self.recipients.retain(|rcp| segment.matches(&rcp.tags()));
+   }
+
+   /// Returns the template variant assigned to the recipient, `None` unless there are variants.
+   ///
+   /// The assignment is deterministic: a hash of the campaign identifier and the email address
+   /// picks the variant, each with a probability proportional to its weight.
+   pub fn variant(&self, email: &str) -> Option<&Variant> {
+      let total: u64 = self.variants.iter().map(|v| u64::from(v.weight)).sum();
+      if total == 0 {
+         return None;
+      }
+      let key = format!("{}\n{}", self.campaign_id, email.trim().to_lowercase());
+      let mut point = message::fnv1a(key.as_bytes()) % total;
+      for variant in self.variants.iter() {
+         if point < u64::from(variant.weight) {
+            return Some(variant);
+         }
+         point -= u64::from(variant.weight);
+      }
+      None
+   }
+
+   /// Keeps `n` recipients picked at random, the same `seed` always picks the same recipients.
+   ///
+   /// The recipients kept stay in their original order.
+   pub fn sample_recipients(&mut self, n: usize, seed: u64) {
+      if n >= self.recipients.len() {
+         return;
+      }
+      // a partial Fisher-Yates shuffle of the indices, the random numbers are generated with
+      // splitmix64 so that a seed picks the same sample with every version of rmt
+      let mut state = seed;
+      let mut indices: Vec<usize> = (0..self.recipients.len()).collect();
+      for i in 0..n {
+         let z = splitmix64(&mut state);
+         let j = i + (z % (indices.len() - i) as u64) as usize;
+         indices.swap(i, j);
+      }
+      let mut chosen = vec![false; self.recipients.len()];
+      for i in indices.into_iter().take(n) {
+         chosen[i] = true;
+      }
+      let mut chosen = chosen.into_iter();
+      self.recipients.retain(|_| chosen.next().unwrap_or(false));
+   }
 }
 
-#[derive(Debug)]
+/// Checks whether the email address matches the pattern, the comparison is case-insensitive.
+///
+/// Patterns are either email addresses or globs where `*` matches any number of characters
+/// and `?` matches a single character e.g. `*@example.com`.
+pub fn email_matches(pattern: &str, email: &str) -> bool {
+   let mut re = String::from("(?i)^");
+   for c in pattern.trim().chars() {
+      match c {
+         '*' => re.push_str(".*"),
+         '?' => re.push('.'),
+         _ => re.push_str(&regex::escape(&c.to_string())),
+      }
+   }
+   re.push('$');
+   Regex::new(&re)
+      .map(|re| re.is_match(email.trim()))
+      .unwrap_or(false)
+}
+
+#[derive(Debug, Clone)]
 /// The `Recipient` struct holds per-recipient data
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Recipient {
    /// This is the recipient's email address
    pub email: String,
@@ -92,15 +1059,116 @@ impl PartialEq for Recipient {
    }
 }
 
-impl ToString for Recipient {
+impl Recipient {
+   /// Returns a builder for making a recipient in code.
+   pub fn builder() -> RecipientBuilder {
+      RecipientBuilder::default()
+   }
+
+   /// Returns the addresses the recipient's email is copied to: the global *cc* addresses plus
+   /// the ones of a `cc:-+...` datum or, if the recipient has a `cc:-...` datum without the `+`
+   /// prefix, the ones of the datum instead.
+   pub fn effective_cc(&self, cfg: &Config) -> Vec<Address> {
+      let cc = match self.data.get("cc") {
+         Some(cc) => cc.trim(),
+         None => return cfg.gdata.cc.clone(),
+      };
+      let mut result = vec![];
+      let cc = match cc.strip_prefix('+') {
+         Some(extra) => {
+            result = cfg.gdata.cc.clone();
+            extra
+         }
+         None => cc,
+      };
+      add_addresses(&mut result, cc);
+      result
+   }
+
+   /// Returns the addresses the recipient's email is blind-copied to i.e. the ones of a
+   /// `bcc:-...` datum. There is no global *bcc* setting, the `+` prefix is optional.
+   pub fn effective_bcc(&self) -> Vec<Address> {
+      let mut result = vec![];
+      if let Some(bcc) = self.data.get("bcc") {
+         let bcc = bcc.trim();
+         add_addresses(&mut result, bcc.strip_prefix('+').unwrap_or(bcc));
+      }
+      result
+   }
+
+   /// Returns the tags of the recipient i.e. the ones of a `tags:-vip,emea` datum.
+   pub fn tags(&self) -> Vec<String> {
+      self
+         .data
+         .get("tags")
+         .map(|tags| {
+            tags
+               .split(',')
+               .map(|t| t.trim().to_string())
+               .filter(|t| !t.is_empty())
+               .collect()
+         })
+         .unwrap_or_default()
+   }
+
+   /// Returns the custom headers of the recipient's email i.e. the ones of the data with the
+   /// `h_` prefix (e.g. `h_X-Account-ID:-12345`), ordered by name.
+   pub fn custom_headers(&self) -> Vec<(String, String)> {
+      let mut result: Vec<(String, String)> = self
+         .data
+         .iter()
+         .filter_map(|(key, val)| {
+            key.strip_prefix("h_")
+               .map(|name| (name.to_string(), val.trim().to_string()))
+         })
+         .collect();
+      result.sort();
+      result
+   }
+}
+
+/// The headers `rmt` sets itself, the recipient data may not add them.
+const RESERVED_HEADERS: &[&str] = &[
+   "bcc",
+   "cc",
+   "content-transfer-encoding",
+   "content-type",
+   "date",
+   "from",
+   "in-reply-to",
+   "message-id",
+   "mime-version",
+   "references",
+   "reply-to",
+   "subject",
+   "to",
+   "x-original-to",
+];
+
+/// Adds the addresses in the comma separated list that are not in the list already (compared
+/// case-insensitively).
+fn add_addresses(addresses: &mut Vec<Address>, list: &str) {
+   for address in address::split_list(list)
+      .into_iter()
+      .filter_map(|a| Address::parse(a).ok())
+   {
+      let email = address.email().to_lowercase();
+      if !addresses.iter().any(|a| a.email().to_lowercase() == email) {
+         addresses.push(address);
+      }
+   }
+}
+
+impl fmt::Display for Recipient {
    /// Makes it possible to print instances of `Recipient`
-   fn to_string(&self) -> String {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
       let mut dv = Vec::new();
       for (key, val) in self.data.iter() {
          dv.push(format!("{} => {}", key, val));
       }
       dv.sort();
-      format!(
+      write!(
+         f,
          "email: {}, names: {}, data: {}",
          self.email,
          self.names.join(", "),
@@ -109,699 +1177,5184 @@ impl ToString for Recipient {
    }
 }
 
-pub fn instantiate(
-   config_path: &str,
-   tool_name: &str,
-   tool_version: &str,
-) -> Result<Config, String> {
-   let i = Ini::load_from_file(config_path).unwrap();
-   check(&i)?;
-   parse(&i, tool_name, tool_version)
+#[derive(Debug, Default)]
+/// The `ConfigBuilder` struct makes a `Config` in code, the settings that are not set have
+/// their defaults (as if they were left out of the config file).
+pub struct ConfigBuilder {
+   from: Option<String>,
+   subject: Option<String>,
+   cc: Option<String>,
+   replyto: Option<String>,
+   campaign_id: Option<String>,
+   engine: Engine,
+   locale: Locale,
+   smtp: Option<Smtp>,
+   defaults: HashMap<String, String>,
+   recipients: Vec<Recipient>,
 }
 
-/// Constructs a list of `String` from an array of string slices.
-pub fn sa(a: &[&str]) -> Vec<String> {
-   a.iter().map(|w| w.to_string()).collect()
-}
+impl ConfigBuilder {
+   /// Sets the *From* address e.g. `"Frodo Baggins" <fb@example.com>`.
+   pub fn from(mut self, from: &str) -> ConfigBuilder {
+      self.from = Some(from.to_string());
+      self
+   }
 
-/// Constructs a map of `String` from an array 2-tuples with string slices.
-pub fn sm(a: &[(&str, &str)]) -> HashMap<String, String> {
-   let mut result: HashMap<String, String> = HashMap::new();
-   for (k, v) in a.iter() {
-      result.insert(k.to_string(), v.to_string());
+   /// Sets the (templated) subject.
+   pub fn subject(mut self, subject: &str) -> ConfigBuilder {
+      self.subject = Some(subject.to_string());
+      self
    }
-   result
-}
 
-/// Top-level configuration parsing function.
-pub fn parse(cfg: &ini::Ini, tool_name: &str, tool_version: &str) -> Result<Config, String> {
-   let mut result = parse_general(cfg, tool_name, tool_version)?;
-   result.recipients = parse_recipients(cfg)?;
-   Ok(result)
-}
+   /// Sets the comma separated *Cc* addresses.
+   pub fn cc(mut self, cc: &str) -> ConfigBuilder {
+      self.cc = Some(cc.to_string());
+      self
+   }
 
-/// Takes a string with comma-delimited email addresses and checks their validity.
-///
-/// If they are all valid returns them as a list of strings. Returns various error messages in the
-/// opposite case. See the unit tests for details.
-fn check_emails(header: &str, emails: &str) -> Result<Vec<String>, String> {
-   let mut valid = Vec::new();
-   let mut invalid = Vec::new();
-   let data: Vec<String> = emails
-      .split(",")
-      .map(|w| w.trim())
-      .filter(|w| w.len() > 0)
-      .map(|w| w.to_string())
-      .collect();
-   if data.len() == 0 {
-      return Err(format!("no emails for *{}* header", header));
+   /// Sets the comma separated *Reply-To* addresses.
+   pub fn reply_to(mut self, replyto: &str) -> ConfigBuilder {
+      self.replyto = Some(replyto.to_string());
+      self
    }
-   for email in data {
-      if check_email(&email) {
-         valid.push(email)
-      } else {
-         invalid.push(email)
-      }
+
+   /// Sets the campaign identifier, a hash of the *From* address and the subject by default.
+   pub fn campaign_id(mut self, campaign_id: &str) -> ConfigBuilder {
+      self.campaign_id = Some(campaign_id.to_string());
+      self
    }
-   if invalid.len() > 0 {
-      invalid.sort();
-      return Err(format!(
-         "invalid *{}* email(s): {}",
-         header,
-         invalid.join(", ")
-      ));
+
+   /// Sets the syntax of the templates.
+   pub fn engine(mut self, engine: Engine) -> ConfigBuilder {
+      self.engine = engine;
+      self
+   }
+
+   /// Sets the conventions for formatting the typed recipient data.
+   pub fn locale(mut self, locale: Locale) -> ConfigBuilder {
+      self.locale = locale;
+      self
+   }
+
+   /// Sets the SMTP server the emails are sent through.
+   pub fn smtp(mut self, smtp: Smtp) -> ConfigBuilder {
+      self.smtp = Some(smtp);
+      self
+   }
+
+   /// Sets the value of a recipient data key for the recipients that lack it.
+   pub fn default_value(mut self, key: &str, val: &str) -> ConfigBuilder {
+      self.defaults.insert(key.to_string(), val.to_string());
+      self
+   }
+
+   /// Adds a recipient.
+   pub fn recipient(mut self, recipient: Recipient) -> ConfigBuilder {
+      self.recipients.push(recipient);
+      self
+   }
+
+   /// Makes the config, the settings and the recipients are checked the way `parse` checks
+   /// them.
+   pub fn build(self) -> Result<Config, String> {
+      let from = match self.from {
+         Some(from) => from,
+         None => return Err(String::from("No *From* header in the general section")),
+      };
+      let subject = match self.subject {
+         Some(subject) => subject,
+         None => return Err(String::from("No *Subject* in the general section")),
+      };
+      let gdata = GData {
+         from: Address::parse(&from)
+            .map_err(|e| format!("invalid *From* email: {} ({})", from, e))?,
+         cc: match self.cc {
+            Some(ref cc) => check_emails("cc", cc)?,
+            None => vec![],
+         },
+         replyto: match self.replyto {
+            Some(ref replyto) => check_emails("Reply-To", replyto)?,
+            None => vec![],
+         },
+         subject,
+      };
+      let campaign_id = match self.campaign_id {
+         Some(id) if !check_campaign_id(&id) => {
+            return Err(format!("invalid *campaign_id*: {}", id))
+         }
+         Some(id) => id,
+         None => format!(
+            "{:016x}",
+            message::fnv1a(format!("{}\n{}", from.trim(), gdata.subject).as_bytes())
+         ),
+      };
+      let mut result = Config {
+         msgid_domain: address::ascii_domain(&gdata.from.domain).to_lowercase(),
+         gdata,
+         campaign_id,
+         threading: Threading::default(),
+         transliterate: false,
+         tracking_pixel_url: None,
+         tracking: Tracking::default(),
+         webhook_url: None,
+         audit_log: None,
+         disposition_notification_to: None,
+         summary_to: None,
+         recipients_csv: vec![],
+         on_duplicate: OnDuplicate::default(),
+         transfer_encoding: TransferEncoding::default(),
+         attachments: vec![],
+         attachment_cmd: None,
+         generated_attachments: HashMap::new(),
+         inline_images: vec![],
+         inline_css: false,
+         sanitize_html: false,
+         preheader: None,
+         max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+         on_oversize: OnOversize::default(),
+         engine: self.engine,
+         greeting: Formality::default(),
+         redirect_to: None,
+         send_at: None,
+         tool_name: String::from("rmt"),
+         tool_version: String::from(env!("CARGO_PKG_VERSION")),
+         smtp: self.smtp,
+         imap: None,
+         signatures: HashMap::new(),
+         signature: None,
+         defaults: self.defaults,
+         schema: HashMap::new(),
+         locale: self.locale,
+         variants: vec![],
+         event: None,
+         recipients: vec![],
+         quarantine: vec![],
+         sources: Sources::default(),
+         confined: false,
+      };
+      let (recipients, duplicates) = dedup_recipients(self.recipients, result.on_duplicate);
+      if let Some(msg) = duplicates.into_iter().next() {
+         return Err(msg);
+      }
+      for rcp in recipients.iter() {
+         check_copies(rcp)?;
+         check_headers(rcp)?;
+         check_tags(rcp)?;
+         check_tz(rcp)?;
+      }
+      result.recipients = recipients;
+      Ok(result)
    }
-   valid.sort();
-   Ok(valid)
 }
 
-/// Parses the `[general]` config file section, returns a `Config` object that has everything but
-/// the recipient data if successfull.
-fn parse_general(cfg: &ini::Ini, tool_name: &str, tool_version: &str) -> Result<Config, String> {
-   let mut gdata = GData {
-      from: String::from(""),
-      replyto: vec![],
-      cc: vec![],
-      subject: String::from(""),
-   };
-   let section = cfg.section(Some(String::from("general"))).unwrap();
+#[derive(Debug, Default)]
+/// The `RecipientBuilder` struct makes a `Recipient` in code.
+pub struct RecipientBuilder {
+   email: Option<String>,
+   names: Vec<String>,
+   data: HashMap<String, String>,
+}
 
-   let keys: Vec<&String> = section.keys().collect();
+impl RecipientBuilder {
+   /// Sets the email address.
+   pub fn email(mut self, email: &str) -> RecipientBuilder {
+      self.email = Some(email.to_string());
+      self
+   }
 
-   for key in keys {
-      let val = section.get(key).unwrap();
-      match key.as_ref() {
-         "From" | "from" => {
-            if !check_email(val) {
-               return Err(format!("invalid *From* email: {}", val));
-            } else {
-               gdata.from = val.to_string();
-            }
+   /// Sets the names (separated by whitespace, the first name first) e.g. `John Doe Jr.`.
+   pub fn names(mut self, names: &str) -> RecipientBuilder {
+      self.names = names.split_whitespace().map(|n| n.to_string()).collect();
+      self
+   }
+
+   /// Sets a datum e.g. `ORG` or `cc`.
+   pub fn datum(mut self, key: &str, val: &str) -> RecipientBuilder {
+      self.data.insert(key.to_string(), val.to_string());
+      self
+   }
+
+   /// Makes the recipient, the email address must be valid and there must be a name.
+   pub fn build(self) -> Result<Recipient, String> {
+      let email = match self.email {
+         Some(email) => email,
+         None => return Err(String::from("no email address")),
+      };
+      match Address::parse(&email) {
+         Ok(address) if address.display_name.is_some() => {
+            return Err(format!("invalid email: {} (the names are separate)", email))
          }
-         "Reply-To" | "Reply-to" => gdata.replyto = check_emails(key, val)?,
-         "cc" | "Cc" | "CC" => gdata.cc = check_emails(key, val)?,
-         "Subject" | "subject" => gdata.subject = val.to_string(),
-         _ => return Err(format!("invalid configuration datum: *{}*", key)),
+         Ok(_) => (),
+         Err(e) => return Err(format!("invalid email: {} ({})", email, e)),
+      }
+      if self.names.is_empty() {
+         return Err(format!("invalid data for email: {}", email));
       }
+      Ok(Recipient {
+         email,
+         names: self.names,
+         data: self.data,
+      })
    }
-   let result = Config {
-      gdata: gdata,
-      tool_name: String::from(tool_name),
-      tool_version: String::from(tool_version),
-      recipients: vec![],
-   };
-   Ok(result)
 }
 
-/// Implements a crude, basic sanity check for email addresses. Yay, regular expressions :-P
-fn check_email(email: &str) -> bool {
-   let re_long = Regex::new(r#"^("\s*)?(\S+\s+)*(\S+)\s*"?\s+<\S+@\S+\.\S+>$"#).unwrap();
-   let re = Regex::new(r"^\S+@\S+\.\S+$").unwrap();
-   re_long.is_match(email.to_string().trim()) || re.is_match(email.to_string().trim())
+#[derive(Debug, Clone, PartialEq)]
+/// The `Origin` struct tells where a setting (or a section) of the config was read from.
+pub struct Origin {
+   /// The config file
+   pub file: String,
+   /// The line (1-based), if known
+   pub line: Option<usize>,
 }
 
-/// Parses the optional per-recipient data (delimited by `':-'`) if present.
-fn parse_recipient_data(rdata: &Vec<&str>) -> Result<HashMap<String, String>, String> {
-   let mut result: Vec<(&str, &str)> = Vec::new();
-   for rd in rdata.iter() {
-      // split the data, example: "cc:-+inc@gg.org"
-      let data = rd.split(":-").map(|w| w.trim()).collect::<Vec<&str>>();
-      match data.as_slice() {
-         [""] => continue,
-         [key, val] => {
-            if key.len() == 0 && val.len() == 0 {
+#[derive(Debug, Clone, Default)]
+/// The `Sources` struct records where the sections, settings and recipient entries of a config
+/// were read from. Like the settings themselves the origins of a file override the ones of the
+/// files it includes and of the files given before it.
+pub struct Sources {
+   /// The origins by section and key, the sections themselves have no key
+   origins: HashMap<(String, Option<String>), Origin>,
+   /// The config file the problems of the config as a whole are reported for, if there is one
+   main: Option<String>,
+   /// The origins of the recipient entries quarantined by the lenient parser, in order
+   quarantined: Vec<Option<Origin>>,
+}
+
+impl Sources {
+   /// Returns where the setting was read from, the section itself if `key` is `None`.
+   pub fn origin(&self, section: &str, key: Option<&str>) -> Option<&Origin> {
+      self
+         .origins
+         .get(&(section.to_string(), key.map(String::from)))
+   }
+
+   /// Returns where the entry of the recipient was read from, recipients read from CSV files
+   /// have none.
+   pub fn recipient(&self, email: &str) -> Option<&Origin> {
+      self.origin("recipients", Some(email))
+   }
+
+   /// Returns where the entry of the `i`th quarantined recipient (see `Config::quarantine`) was
+   /// read from, if known.
+   pub fn quarantined(&self, i: usize) -> Option<&Origin> {
+      self.quarantined.get(i).and_then(|o| o.as_ref())
+   }
+
+   /// Returns the error found while parsing the setting `at` points to, at the line of the
+   /// setting. Errors about a section point to its header, the ones about the config as a whole
+   /// to the config file.
+   fn error(&self, message: String, at: &At) -> ConfigError {
+      let origin = match (&at.section, &at.key) {
+         (Some(section), Some(key)) => self.origin(section, Some(key)).cloned(),
+         (Some(section), None) => self.origin(section, None).cloned(),
+         (None, _) => None,
+      };
+      let origin = match (&at.key, origin) {
+         (None, None) => self.main.as_ref().map(|file| Origin {
+            file: file.clone(),
+            line: None,
+         }),
+         (_, origin) => origin,
+      };
+      ConfigError { message, origin }
+   }
+
+   /// Records the lines of the sections and settings of the config file, the settings of the
+   /// file override the ones recorded before.
+   fn record(&mut self, path: &Path, text: &str, format: FileFormat) {
+      let file = path.display().to_string();
+      let mut section = String::new();
+      for (i, line) in text.lines().enumerate() {
+         let trimmed = line.trim();
+         if trimmed.is_empty() || trimmed.starts_with(['#', ';']) {
+            continue;
+         }
+         let origin = Origin {
+            file: file.clone(),
+            line: Some(i + 1),
+         };
+         let unquote = |key: &str| key.trim().trim_matches(['"', '\'']).to_string();
+         let entry = match format {
+            FileFormat::Yaml if !line.starts_with([' ', '\t']) => {
+               if let Some(name) = trimmed.strip_suffix(':') {
+                  section = unquote(name);
+                  self.origins.insert((section.clone(), None), origin);
+               }
                continue;
             }
-            if key.len() == 0 {
-               return Err(format!("no key for datum ({})", val));
-            }
-            if val.len() == 0 {
-               return Err(format!("empty value for key ({})", key));
+            FileFormat::Yaml => trimmed.split_once(':'),
+            _ if trimmed.starts_with('[') && trimmed.ends_with(']') => {
+               section = unquote(&trimmed[1..trimmed.len() - 1]);
+               self.origins.insert((section.clone(), None), origin);
+               continue;
             }
-            result.push((key, val));
+            _ => trimmed.split_once('='),
+         };
+         if let Some((key, _)) = entry {
+            self
+               .origins
+               .insert((section.clone(), Some(unquote(key))), origin);
          }
-         _ => return Err(format!("invalid recipient data ({})", rd)),
       }
    }
-   Ok(sm(&result))
+
+   /// Adds the origins of a later layer, which override the ones recorded before.
+   fn merge(&mut self, layer: Sources) {
+      self.origins.extend(layer.origins);
+   }
 }
 
-/// Parses the `[recipients]` config file section.
-fn parse_recipients(cfg: &ini::Ini) -> Result<Vec<Recipient>, String> {
-   let mut result: Vec<Recipient> = Vec::new();
-   let section = cfg.section(Some(String::from("recipients"))).unwrap();
+#[derive(Debug, Clone, PartialEq)]
+/// The `ConfigError` struct holds an error in the config files and where it was found, if known.
+pub struct ConfigError {
+   pub message: String,
+   pub origin: Option<Origin>,
+}
 
-   // we want a stable sort order of the recipient data
-   let mut keys: Vec<&String> = section.keys().collect();
-   keys.sort();
+impl ConfigError {
+   /// Creates an error found in the given line (if known) of the config file.
+   fn at(path: &Path, line: Option<usize>, message: String) -> ConfigError {
+      ConfigError {
+         message,
+         origin: Some(Origin {
+            file: path.display().to_string(),
+            line,
+         }),
+      }
+   }
+}
 
-   for key in keys {
-      let val = section.get(key).unwrap();
-      if !check_email(key) {
-         return Err(format!("invalid email: {}", key));
+/// Formats the error the way compilers do e.g. `campaign.ini:14: invalid *cc* email(s)`.
+impl fmt::Display for ConfigError {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self.origin {
+         Some(Origin {
+            ref file,
+            line: Some(line),
+         }) => write!(f, "{}:{}: {}", file, line, self.message),
+         Some(Origin { ref file, .. }) => write!(f, "{}: {}", file, self.message),
+         None => write!(f, "{}", self.message),
       }
-      // split recipient data, example:
-      // John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org
-      let mut data: Vec<&str> = val
-         .split("|")
-         .map(|w| w.trim())
-         .filter(|w| w.len() > 0)
-         .collect();
-      if data.len() == 0 {
-         return Err(format!("invalid data for email: {}", key));
-      }
-      // split the first entry in the recipient data i.e. the names
-      let names: Vec<String> = data
-         .remove(0)
-         .split_ascii_whitespace()
-         .filter(|w| w.len() > 0)
-         .map(|n| n.to_string())
-         .collect();
-      // parse the remainder of the recipient data
-      match parse_recipient_data(&data) {
-         Ok(rd) => result.push(Recipient {
-            email: key.to_string(),
-            names: names,
-            data: rd,
-         }),
-         Err(msg) => return Err(format!("invalid recipient data for {} ({})", key, msg)),
+   }
+}
+
+impl From<String> for ConfigError {
+   fn from(message: String) -> ConfigError {
+      ConfigError {
+         message,
+         origin: None,
       }
    }
-   return Ok(result);
 }
 
-/// Very basic sanity checks on the config.
+impl From<ConfigError> for String {
+   fn from(error: ConfigError) -> String {
+      error.to_string()
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `At` struct keeps track of the setting being parsed, so that an error can be traced back
+/// to the line it was read from (see `Sources`).
+struct At {
+   section: Option<String>,
+   key: Option<String>,
+   /// The recipient entries quarantined by the lenient parser, in order
+   quarantined: Vec<Option<String>>,
+}
+
+impl At {
+   /// Starts parsing the section, the errors that follow are about the section as a whole.
+   fn section(&mut self, name: &str) {
+      self.section = Some(name.to_string());
+      self.key = None;
+   }
+
+   /// Starts parsing the setting of the section.
+   fn key(&mut self, key: &str) {
+      self.key = Some(key.to_string());
+   }
+
+   /// Starts parsing the setting of the given section.
+   fn setting(&mut self, section: &str, key: &str) {
+      self.section(section);
+      self.key(key);
+   }
+}
+
+/// Loads a config file along with the files it includes.
 ///
-/// Does it have the general/recipients sections and does the former have a `From` and a `Subject`?
-pub fn check(cfg: &ini::Ini) -> Result<usize, String> {
-   let sections = sa(&["general", "recipients"]);
-   let mut num_recipients = 0;
+/// The *include* key in the general section names the base configs (comma separated, relative
+/// to the including file) that are read first; later files override the settings of earlier
+/// ones and the including file overrides them all.
+pub fn load(config_path: &Path) -> Result<Ini, String> {
+   Ok(load_layers(config_path, &mut vec![])?.0)
+}
+
+/// Loads the config files along with the files they include, the settings of later files
+/// override the ones of earlier files the way the settings of an including file override the ones
+/// of the files it includes.
+pub fn load_all(config_paths: &[&str]) -> Result<Ini, String> {
+   Ok(load_traced(config_paths)?.0)
+}
+
+/// Like `load_all` but records where the settings were read from.
+fn load_traced(config_paths: &[&str]) -> Result<(Ini, Sources), ConfigError> {
+   let (mut result, mut sources) = match config_paths.split_first() {
+      Some((first, _)) => load_layers(Path::new(first), &mut vec![])?,
+      None => return Err(ConfigError::from(String::from("no config file"))),
+   };
+   for path in config_paths.iter().skip(1) {
+      let (layer, origins) = load_layers(Path::new(path), &mut vec![])?;
+      merge(&mut result, layer);
+      sources.merge(origins);
+   }
+   if let [path] = config_paths {
+      sources.main = Some(path.to_string());
+   }
+   Ok((result, sources))
+}
+
+/// Loads a config file and its includes, `stack` holds the files being loaded to detect cycles.
+fn load_layers(path: &Path, stack: &mut Vec<PathBuf>) -> Result<(Ini, Sources), ConfigError> {
+   let canonical = path
+      .canonicalize()
+      .map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+   if stack.contains(&canonical) {
+      return Err(ConfigError::from(format!(
+         "{} includes itself",
+         path.display()
+      )));
+   }
+   let text = std::fs::read_to_string(path)
+      .map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+   let format = FileFormat::of(path);
+   let mut cfg = match format {
+      FileFormat::Toml => load_toml(path, &text)?,
+      FileFormat::Yaml => load_yaml(path, &text)?,
+      FileFormat::Ini => Ini::load_from_str(&text)
+         .map_err(|e| ConfigError::at(path, Some(e.line), e.msg.to_string()))?,
+   };
+   let mut sources = Sources::default();
+   sources.record(path, &text, format);
+   let includes = match cfg.delete_from(Some("general"), "include") {
+      Some(includes) => includes,
+      None => return Ok((cfg, sources)),
+   };
+   let dir = path.parent().unwrap_or_else(|| Path::new(""));
+   stack.push(canonical);
+   let (mut result, mut origins) = (Ini::new(), Sources::default());
+   for include in includes
+      .split(',')
+      .map(|p| p.trim())
+      .filter(|p| !p.is_empty())
+   {
+      let (layer, layer_origins) = load_layers(&dir.join(include), stack)?;
+      merge(&mut result, layer);
+      origins.merge(layer_origins);
+   }
+   stack.pop();
+   merge(&mut result, cfg);
+   origins.merge(sources);
+   Ok((result, origins))
+}
+
+/// Loads a config file in TOML format: the tables are the sections, arrays (e.g. of *cc*
+/// addresses) become comma separated lists.
+fn load_toml(path: &Path, text: &str) -> Result<Ini, ConfigError> {
+   let table: toml::Table = text.parse().map_err(|e: toml::de::Error| {
+      let line = e
+         .span()
+         .map_or(1, |s| text[..s.start].lines().count().max(1));
+      ConfigError::at(path, Some(line), e.message().to_string())
+   })?;
+   let mut result = Ini::new();
+   for (name, section) in table.iter() {
+      result.entry(Some(name.clone())).or_default();
+      let section = section.as_table().ok_or_else(|| {
+         ConfigError::at(
+            path,
+            None,
+            format!(
+               "{} is not a table, the settings go into tables like [general]",
+               name
+            ),
+         )
+      })?;
+      for (key, value) in section.iter() {
+         let text = match value {
+            toml::Value::Array(values) => values
+               .iter()
+               .map(user_config::text)
+               .collect::<Option<Vec<String>>>()
+               .map(|values| values.join(", ")),
+            value => user_config::text(value),
+         };
+         let text = text.ok_or_else(|| {
+            ConfigError::at(
+               path,
+               None,
+               format!("invalid value of *{}* in [{}]", key, name),
+            )
+         })?;
+         result
+            .with_section(Some(name.as_str()))
+            .set(key.as_str(), text);
+      }
+   }
+   Ok(result)
+}
+
+/// Loads a config file in YAML format: the top-level mappings are the sections, sequences (e.g.
+/// of *cc* addresses) become comma separated lists.
+fn load_yaml(path: &Path, text: &str) -> Result<Ini, ConfigError> {
+   let docs =
+      YamlLoader::load_from_str(text).map_err(|e| ConfigError::at(path, None, e.to_string()))?;
+   let mut result = Ini::new();
+   let sections = match docs.first() {
+      Some(Yaml::Hash(sections)) => sections,
+      None | Some(Yaml::Null) => return Ok(result),
+      Some(_) => {
+         return Err(ConfigError::at(
+            path,
+            None,
+            String::from("the settings go into sections like general:"),
+         ))
+      }
+   };
+   for (name, section) in sections.iter() {
+      let name = yaml_text(name).unwrap_or_default();
+      result.entry(Some(name.clone())).or_default();
+      let section = match section {
+         Yaml::Hash(section) => section,
+         // a section with all its settings commented out
+         Yaml::Null => continue,
+         _ => {
+            return Err(ConfigError::at(
+               path,
+               None,
+               format!(
+                  "{} is not a section, the settings go into sections like general:",
+                  name
+               ),
+            ))
+         }
+      };
+      for (key, value) in section.iter() {
+         let key = yaml_text(key).unwrap_or_default();
+         let text = match value {
+            Yaml::Array(values) => values
+               .iter()
+               .map(yaml_text)
+               .collect::<Option<Vec<String>>>()
+               .map(|values| values.join(", ")),
+            value => yaml_text(value),
+         };
+         let text = text.ok_or_else(|| {
+            ConfigError::at(
+               path,
+               None,
+               format!("invalid value of *{}* in {}", key, name),
+            )
+         })?;
+         result
+            .with_section(Some(name.as_str()))
+            .set(key.as_str(), text);
+      }
+   }
+   Ok(result)
+}
+
+/// Converts a YAML scalar to the text it would have in a campaign file.
+fn yaml_text(value: &Yaml) -> Option<String> {
+   match value {
+      Yaml::String(s) | Yaml::Real(s) => Some(s.clone()),
+      Yaml::Integer(i) => Some(i.to_string()),
+      Yaml::Boolean(b) => Some(b.to_string()),
+      Yaml::Null => Some(String::new()),
+      _ => None,
+   }
+}
+
+/// Merges the layer into the config, the settings of the layer win.
+fn merge(cfg: &mut Ini, layer: Ini) {
+   for (section, properties) in layer.iter() {
+      let target = cfg.entry(section.clone()).or_default();
+      for (key, val) in properties.iter() {
+         target.insert(key.clone(), val.clone());
+      }
+   }
+}
+
+/// Loads the config files and parses them, the errors are reported with the line of the
+/// setting they were found in (see `Sources`). The user-level configuration (the sending
+/// profiles and the defaults), if given, is applied to the settings of the config files.
+pub fn instantiate(
+   config_paths: &[&str],
+   user: Option<&UserConfig>,
+   tool_name: &str,
+   tool_version: &str,
+) -> Result<Config, ConfigError> {
+   instantiate_with(config_paths, user, |cfg, at| {
+      parse_traced(cfg, tool_name, tool_version, at)
+   })
+}
+
+/// Like `instantiate` but invalid recipients are quarantined instead of failing the parse.
+pub fn instantiate_lenient(
+   config_paths: &[&str],
+   user: Option<&UserConfig>,
+   tool_name: &str,
+   tool_version: &str,
+) -> Result<Config, ConfigError> {
+   instantiate_with(config_paths, user, |cfg, at| {
+      parse_lenient_traced(cfg, tool_name, tool_version, at)
+   })
+}
+
+/// Like `instantiate` but the *recipients_csv* files are not read, the config holds the recipients
+/// of the *recipients* section only. The campaign is sent to the recipients of the CSV files as
+/// they are read, see `source::open`.
+pub fn instantiate_streaming(
+   config_paths: &[&str],
+   user: Option<&UserConfig>,
+   tool_name: &str,
+   tool_version: &str,
+) -> Result<Config, ConfigError> {
+   instantiate_with(config_paths, user, |cfg, at| {
+      let mut result = parse_settings(cfg, tool_name, tool_version, at)?;
+      at.section("recipients");
+      let (recipients, duplicates) =
+         dedup_recipients(parse_recipients(cfg, at)?, result.on_duplicate);
+      if let Some(msg) = duplicates.into_iter().next() {
+         return Err(msg);
+      }
+      for rcp in recipients.iter() {
+         at.key(&rcp.email);
+         check_recipient(&result, rcp)?;
+      }
+      result.recipients = recipients;
+      Ok(result)
+   })
+}
+
+/// Loads the config files, applies the user-level configuration (if any) and the environment and
+/// parses the result with the given function, which keeps track of the setting it parses.
+fn instantiate_with<F>(
+   config_paths: &[&str],
+   user: Option<&UserConfig>,
+   parse: F,
+) -> Result<Config, ConfigError>
+where
+   F: FnOnce(&Ini, &mut At) -> Result<Config, String>,
+{
+   let (mut i, mut sources) = load_traced(config_paths)?;
+   let mut at = At::default();
+   let none = UserConfig::default();
+   let user = user.unwrap_or(&none);
+   at.setting("general", "profile");
+   apply_profile(&mut i, user).map_err(|e| sources.error(e, &at))?;
+   apply_defaults(&mut i, &user.defaults);
+   interpolate_env(&mut i, &mut at).map_err(|e| sources.error(e, &at))?;
+   check(&i).map_err(|e| sources.error(e, &At::default()))?;
+   let mut result = parse(&i, &mut at).map_err(|e| sources.error(e, &at))?;
+   sources.quarantined = at
+      .quarantined
+      .iter()
+      .map(|key| key.as_ref().and_then(|k| sources.recipient(k).cloned()))
+      .collect();
+   result.sources = sources;
+   Ok(result)
+}
+
+/// Expands the `${NAME}` references to environment variables in the value, `$${` stands for a
+/// literal `${`.
+pub fn expand_env(value: &str) -> Result<String, String> {
+   let re = Regex::new(r"\$(\$?)\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+   let mut result = String::with_capacity(value.len());
+   let mut last = 0;
+   for caps in re.captures_iter(value) {
+      let m = caps.get(0).unwrap();
+      result.push_str(&value[last..m.start()]);
+      if caps[1].is_empty() {
+         match std::env::var(&caps[2]) {
+            Ok(val) => result.push_str(&val),
+            Err(_) => return Err(format!("undefined environment variable: {}", &caps[2])),
+         }
+      } else {
+         result.push_str(&m.as_str()[1..]);
+      }
+      last = m.end();
+   }
+   result.push_str(&value[last..]);
+   Ok(result)
+}
+
+/// Expands the environment variable references in the config values, the recipient data is
+/// left alone.
+fn interpolate_env(cfg: &mut ini::Ini, at: &mut At) -> Result<(), String> {
+   for (section, properties) in cfg.iter_mut() {
+      let section = section.as_deref().unwrap_or_default();
+      if section == "recipients" {
+         continue;
+      }
+      let mut keys: Vec<String> = properties.keys().cloned().collect();
+      keys.sort();
+      for key in keys {
+         at.setting(section, &key);
+         let val = properties.get_mut(&key).unwrap();
+         if val.contains('$') {
+            *val = expand_env(val)
+               .map_err(|e| format!("*{}* in the {} section: {}", key, section, e))?;
+         }
+      }
+   }
+   Ok(())
+}
+
+/// Fills in the user's defaults for the settings the config does not have.
+pub fn apply_defaults(cfg: &mut ini::Ini, defaults: &Defaults) {
+   if let Some(ref rate) = defaults.rate {
+      if cfg.section(Some("smtp")).is_some() && cfg.get_from(Some("smtp"), "rate").is_none() {
+         cfg.set_to(Some("smtp"), String::from("rate"), rate.clone());
+      }
+   }
+}
+
+/// Merges the sending profile named by the *profile* key into the config, the settings in the
+/// config take precedence.
+///
+/// The profile provides the *From* address unless the general section has one and the *smtp*
+/// settings the config does not have. The credentials go together: if the *smtp* section has
+/// any of *username*, *password* or *keyring* the profile's credentials are not used.
+pub fn apply_profile(cfg: &mut ini::Ini, user: &UserConfig) -> Result<(), String> {
+   let name = match cfg.delete_from(Some("general"), "profile") {
+      Some(name) => name.trim().to_string(),
+      None => return Ok(()),
+   };
+   let profile = user
+      .profiles
+      .get(&name)
+      .ok_or_else(|| format!("unknown *profile*: {}", name))?;
+   if let Some(ref from) = profile.from {
+      if cfg.get_from(Some("general"), "From").is_none()
+         && cfg.get_from(Some("general"), "from").is_none()
+      {
+         cfg.set_to(Some("general"), String::from("From"), from.clone());
+      }
+   }
+   let credentials = ["username", "password", "keyring"];
+   let has_credentials = cfg
+      .section(Some("smtp"))
+      .map(|s| credentials.iter().any(|k| s.contains_key(*k)))
+      .unwrap_or(false);
+   for (key, val) in profile.smtp.iter() {
+      if has_credentials && credentials.contains(&key.as_ref()) {
+         continue;
+      }
+      if cfg.get_from(Some("smtp"), key).is_none() {
+         cfg.set_to(Some("smtp"), key.clone(), val.clone());
+      }
+   }
+   Ok(())
+}
+
+/// Constructs a list of `String` from an array of string slices.
+pub fn sa(a: &[&str]) -> Vec<String> {
+   a.iter().map(|w| w.to_string()).collect()
+}
+
+/// Constructs a map of `String` from an array 2-tuples with string slices.
+pub fn sm(a: &[(&str, &str)]) -> HashMap<String, String> {
+   let mut result: HashMap<String, String> = HashMap::new();
+   for (k, v) in a.iter() {
+      result.insert(k.to_string(), v.to_string());
+   }
+   result
+}
+
+/// Parses everything but the recipients.
+fn parse_settings(
+   cfg: &ini::Ini,
+   tool_name: &str,
+   tool_version: &str,
+   at: &mut At,
+) -> Result<Config, String> {
+   let mut result = parse_general(cfg, tool_name, tool_version, at)?;
+   result.smtp = parse_smtp(cfg, at)?;
+   result.imap = parse_imap(cfg, at)?;
+   result.signatures = parse_signatures(cfg, at)?;
+   result.variants = parse_variants(cfg, result.engine, at)?;
+   result.defaults = parse_defaults(cfg, at)?;
+   result.schema = parse_schema(cfg, &result.defaults, at)?;
+   result.tracking = parse_tracking(cfg, at)?;
+   // the tracking tokens are keyed with the secret
+   if result.tracking.secret.is_none() {
+      if result.tracking_pixel_url.is_some() {
+         at.setting("general", "tracking_pixel_url");
+         return Err(String::from(
+            "*tracking_pixel_url* needs a *secret* in the tracking section",
+         ));
+      }
+      if result.tracking.redirect_url.is_some() {
+         at.setting("tracking", "redirect_url");
+         return Err(String::from(
+            "*redirect_url* needs a *secret* in the tracking section",
+         ));
+      }
+   }
+   result.event = parse_event(cfg, &result.gdata.from, at)?;
+   Ok(result)
+}
+
+/// Top-level configuration parsing function.
+pub fn parse(cfg: &ini::Ini, tool_name: &str, tool_version: &str) -> Result<Config, String> {
+   parse_traced(cfg, tool_name, tool_version, &mut At::default())
+}
+
+/// Works like `parse` and keeps track of the setting parsed.
+fn parse_traced(
+   cfg: &ini::Ini,
+   tool_name: &str,
+   tool_version: &str,
+   at: &mut At,
+) -> Result<Config, String> {
+   let mut result = parse_settings(cfg, tool_name, tool_version, at)?;
+   result.recipients = parse_recipients(cfg, at)?;
+   at.setting("general", "recipients_csv");
+   result
+      .recipients
+      .extend(read_recipients_csv(&result.recipients_csv)?);
+   at.section("recipients");
+   let (recipients, duplicates) = dedup_recipients(result.recipients, result.on_duplicate);
+   if let Some(msg) = duplicates.into_iter().next() {
+      return Err(msg);
+   }
+   result.recipients = recipients;
+   for rcp in result.recipients.iter() {
+      at.key(&rcp.email);
+      check_recipient(&result, rcp)?;
+   }
+   Ok(result)
+}
+
+/// Reads the recipients from the given CSV files (see `listing::recipients_from_csv`).
+fn read_recipients_csv(paths: &[String]) -> Result<Vec<Recipient>, String> {
+   let mut result = vec![];
+   for path in paths.iter() {
+      let text = std::fs::read_to_string(path)
+         .map_err(|e| format!("cannot read *recipients_csv* {} ({})", path, e))?;
+      result.extend(listing::recipients_from_csv(&text).map_err(|e| format!("{}: {}", path, e))?);
+   }
+   Ok(result)
+}
+
+/// Handles recipients that appear more than once as per the policy, the recipients keep the
+/// position of their first entry.
+///
+/// With the `Error` policy the duplicates are dropped and reported in the second list.
+pub fn dedup_recipients(
+   recipients: Vec<Recipient>,
+   policy: OnDuplicate,
+) -> (Vec<Recipient>, Vec<String>) {
+   let mut result: Vec<Recipient> = vec![];
+   let mut duplicates = vec![];
+   let mut index: HashMap<String, usize> = HashMap::new();
+   for rcp in recipients {
+      let key = rcp.email.trim().to_lowercase();
+      let i = match index.get(&key) {
+         Some(i) => *i,
+         None => {
+            index.insert(key, result.len());
+            result.push(rcp);
+            continue;
+         }
+      };
+      match policy {
+         OnDuplicate::Error => duplicates.push(format!(
+            "duplicate recipient: {} (see *on_duplicate*)",
+            rcp.email
+         )),
+         OnDuplicate::KeepFirst => (),
+         OnDuplicate::KeepLast => result[i] = rcp,
+         OnDuplicate::Merge => result[i].data.extend(rcp.data),
+      }
+   }
+   (result, duplicates)
+}
+
+/// Lenient top-level configuration parsing function.
+///
+/// Invalid recipients are skipped, the reasons are collected in the `quarantine` of the config.
+/// Errors in the other sections still fail the parse.
+pub fn parse_lenient(
+   cfg: &ini::Ini,
+   tool_name: &str,
+   tool_version: &str,
+) -> Result<Config, String> {
+   parse_lenient_traced(cfg, tool_name, tool_version, &mut At::default())
+}
+
+/// Works like `parse_lenient` and keeps track of the setting parsed and of the recipient entries
+/// quarantined.
+fn parse_lenient_traced(
+   cfg: &ini::Ini,
+   tool_name: &str,
+   tool_version: &str,
+   at: &mut At,
+) -> Result<Config, String> {
+   let mut result = parse_settings(cfg, tool_name, tool_version, at)?;
+   at.section("recipients");
+   // we want a stable sort order of the recipient data
+   let mut entries: Vec<(&String, &String)> = section(cfg, "recipients")?.iter().collect();
+   entries.sort();
+
+   let mut recipients = vec![];
+   for (key, val) in entries {
+      match parse_recipient(key, val) {
+         Ok(rcp) => recipients.push(rcp),
+         Err(msg) => {
+            result.quarantine.push(msg);
+            at.quarantined.push(Some(key.clone()));
+         }
+      }
+   }
+   at.setting("general", "recipients_csv");
+   recipients.extend(read_recipients_csv(&result.recipients_csv)?);
+   let (recipients, duplicates) = dedup_recipients(recipients, result.on_duplicate);
+   at.quarantined.extend(duplicates.iter().map(|_| None));
+   result.quarantine.extend(duplicates);
+   for rcp in recipients {
+      match check_recipient(&result, &rcp) {
+         Ok(()) => result.recipients.push(rcp),
+         Err(msg) => {
+            result.quarantine.push(msg);
+            at.quarantined.push(Some(rcp.email));
+         }
+      }
+   }
+   Ok(result)
+}
+
+/// Checks the data of a parsed recipient against the settings of the campaign: the signature,
+/// the copies, the custom headers, the typed data, the tags and the time zone.
+pub fn check_recipient(cfg: &Config, rcp: &Recipient) -> Result<(), String> {
+   check_signature(cfg, rcp)?;
+   check_copies(rcp)?;
+   check_headers(rcp)?;
+   check_types(cfg, rcp)?;
+   check_tags(rcp)?;
+   check_tz(rcp)
+}
+
+/// Makes sure the signature picked by the recipient (if any) exists.
+fn check_signature(cfg: &Config, rcp: &Recipient) -> Result<(), String> {
+   match rcp.data.get("signature") {
+      Some(name) if !cfg.signatures.contains_key(name) => Err(format!(
+         "invalid recipient data for {} (unknown signature ({}))",
+         rcp.email, name
+      )),
+      _ => Ok(()),
+   }
+}
+
+/// Makes sure the addresses of the recipient's `cc` and `bcc` data (if any) are valid.
+fn check_copies(rcp: &Recipient) -> Result<(), String> {
+   for key in ["cc", "bcc"].iter() {
+      if let Some(val) = rcp.data.get(*key) {
+         let val = val.trim();
+         check_emails(key, val.strip_prefix('+').unwrap_or(val))
+            .map_err(|e| format!("invalid recipient data for {} ({})", rcp.email, e))?;
+      }
+   }
+   Ok(())
+}
+
+/// Makes sure the time zone of the recipient's `tz` datum (if any) is known.
+fn check_tz(rcp: &Recipient) -> Result<(), String> {
+   match rcp.data.get("tz") {
+      Some(name) => tz::lookup(name)
+         .map(|_| ())
+         .map_err(|e| format!("invalid recipient data for {} ({})", rcp.email, e)),
+      None => Ok(()),
+   }
+}
+
+/// Makes sure the tags of the recipient's `tags` datum (if any) are valid tag names.
+fn check_tags(rcp: &Recipient) -> Result<(), String> {
+   match rcp.tags().into_iter().find(|t| !segment::check_tag(t)) {
+      Some(tag) => Err(format!(
+         "invalid recipient data for {} (invalid tag ({}))",
+         rcp.email, tag
+      )),
+      None => Ok(()),
+   }
+}
+
+/// Makes sure the values of the recipient's typed data are valid for their types.
+fn check_types(cfg: &Config, rcp: &Recipient) -> Result<(), String> {
+   let mut keys: Vec<&String> = rcp.data.keys().collect();
+   keys.sort();
+   for key in keys {
+      if let Some(data_type) = cfg.schema.get(key) {
+         data_type
+            .check(&rcp.data[key])
+            .map_err(|e| format!("invalid recipient data for {} ({}: {})", rcp.email, key, e))?;
+      }
+   }
+   Ok(())
+}
+
+/// Makes sure the custom headers of the recipient's `h_` data (if any) are legal: the names
+/// consist of printable ASCII characters other than `:` and are not among the headers `rmt` sets
+/// itself, the values have no control characters.
+fn check_headers(rcp: &Recipient) -> Result<(), String> {
+   for (name, val) in rcp.custom_headers() {
+      let error = if name.is_empty() || !name.bytes().all(|b| b.is_ascii_graphic() && b != b':') {
+         "invalid header name"
+      } else if RESERVED_HEADERS.contains(&name.to_lowercase().as_ref()) {
+         "reserved header name"
+      } else if val.chars().any(char::is_control) {
+         "invalid header value"
+      } else {
+         continue;
+      };
+      return Err(format!(
+         "invalid recipient data for {} ({} ({}))",
+         rcp.email, error, name
+      ));
+   }
+   Ok(())
+}
+
+/// Takes a string with comma-delimited email addresses and checks their validity.
+///
+/// If they are all valid returns them as a list of addresses. Returns various error messages in
+/// the opposite case. See the unit tests for details.
+pub fn check_emails(header: &str, emails: &str) -> Result<Vec<Address>, String> {
+   let mut valid = Vec::new();
+   let mut invalid = Vec::new();
+   let data = address::split_list(emails);
+   if data.is_empty() {
+      return Err(format!("no emails for *{}* header", header));
+   }
+   for email in data {
+      match Address::parse(email) {
+         Ok(address) => valid.push(address),
+         Err(e) => invalid.push(format!("{} ({})", email, e)),
+      }
+   }
+   if !invalid.is_empty() {
+      invalid.sort();
+      return Err(format!(
+         "invalid *{}* email(s): {}",
+         header,
+         invalid.join(", ")
+      ));
+   }
+   valid.sort_by_key(|a| a.to_string());
+   Ok(valid)
+}
+
+/// Parses the `[general]` config file section, returns a `Config` object that has everything but
+/// the recipient data if successfull.
+fn parse_general(
+   cfg: &ini::Ini,
+   tool_name: &str,
+   tool_version: &str,
+   at: &mut At,
+) -> Result<Config, String> {
+   let mut from = None;
+   let mut gdata = GData {
+      from: Address {
+         display_name: None,
+         local: String::new(),
+         domain: String::new(),
+      },
+      replyto: vec![],
+      cc: vec![],
+      subject: String::from(""),
+   };
+   let mut campaign_id = String::new();
+   let mut msgid_domain = String::new();
+   let mut threading = Threading::default();
+   let mut transliterate = false;
+   let mut tracking_pixel_url = None;
+   let mut webhook_url = None;
+   let mut audit_log = None;
+   let mut disposition_notification_to = None;
+   let mut summary_to = None;
+   let mut recipients_csv = vec![];
+   let mut on_duplicate = OnDuplicate::default();
+   let mut transfer_encoding = TransferEncoding::default();
+   let mut attachments = vec![];
+   let mut attachment_cmd = None;
+   let mut inline_images = vec![];
+   let mut inline_css = false;
+   let mut sanitize_html = false;
+   let mut preheader = None;
+   let mut signature = None;
+   let mut max_message_size = DEFAULT_MAX_MESSAGE_SIZE;
+   let mut on_oversize = OnOversize::default();
+   let mut engine = Engine::default();
+   let mut greeting = Formality::default();
+   let mut locale = Locale::default();
+   let mut send_at = None;
+   at.section("general");
+   for (key, val) in section(cfg, "general")?.iter() {
+      at.key(key);
+      match key.as_ref() {
+         "From" | "from" => match Address::parse(val) {
+            Ok(address) => {
+               gdata.from = address;
+               from = Some(val.trim());
+            }
+            Err(e) => return Err(format!("invalid *From* email: {} ({})", val, e)),
+         },
+         "Reply-To" | "Reply-to" => gdata.replyto = check_emails(key, val)?,
+         "cc" | "Cc" | "CC" => gdata.cc = check_emails(key, val)?,
+         "Subject" | "subject" => gdata.subject = val.to_string(),
+         "campaign_id" => {
+            if !check_campaign_id(val) {
+               return Err(format!("invalid *campaign_id*: {}", val));
+            }
+            campaign_id = val.to_string();
+         }
+         "message_id_domain" => {
+            if !check_domain(val) {
+               return Err(format!("invalid *message_id_domain*: {}", val));
+            }
+            msgid_domain = val.to_string();
+         }
+         "in_reply_to" => {
+            if !check_message_id(val) {
+               return Err(format!("invalid *in_reply_to* message id: {}", val));
+            }
+            threading.in_reply_to = Some(val.trim().to_string());
+         }
+         "references" => threading.references = check_message_ids(key, val)?,
+         "follow_up_of" => {
+            if !check_campaign_id(val) {
+               return Err(format!("invalid *follow_up_of* campaign id: {}", val));
+            }
+            threading.follow_up_of = Some(val.to_string());
+         }
+         "transliterate" => transliterate = parse_bool(key, val)?,
+         "tracking_pixel_url" => {
+            if !check_url(val) {
+               return Err(format!("invalid *tracking_pixel_url*: {}", val));
+            }
+            tracking_pixel_url = Some(val.trim().to_string());
+         }
+         "webhook_url" => {
+            if !check_url(val) {
+               return Err(format!("invalid *webhook_url*: {}", val));
+            }
+            webhook_url = Some(val.trim().to_string());
+         }
+         "audit_log" => {
+            if val.trim().is_empty() {
+               return Err(String::from("empty *audit_log* path"));
+            }
+            audit_log = Some(val.trim().to_string());
+         }
+         "disposition_notification_to" => match Address::parse(val) {
+            Ok(address) => disposition_notification_to = Some(address),
+            Err(e) => {
+               return Err(format!(
+                  "invalid *disposition_notification_to* email: {} ({})",
+                  val, e
+               ))
+            }
+         },
+         "summary_to" => match Address::parse(val) {
+            Ok(address) => summary_to = Some(address),
+            Err(e) => return Err(format!("invalid *summary_to* email: {} ({})", val, e)),
+         },
+         "recipients_csv" => {
+            recipients_csv = val
+               .split(',')
+               .map(|p| p.trim().to_string())
+               .filter(|p| !p.is_empty())
+               .collect();
+            if recipients_csv.is_empty() {
+               return Err(String::from("empty *recipients_csv* path"));
+            }
+         }
+         "on_duplicate" => {
+            on_duplicate = match val.trim() {
+               "error" => OnDuplicate::Error,
+               "keep-first" => OnDuplicate::KeepFirst,
+               "keep-last" => OnDuplicate::KeepLast,
+               "merge" => OnDuplicate::Merge,
+               _ => return Err(format!("invalid *on_duplicate* policy: {}", val)),
+            }
+         }
+         "transfer_encoding" => {
+            transfer_encoding = match val.trim().to_lowercase().as_ref() {
+               "auto" => TransferEncoding::Auto,
+               "quoted-printable" => TransferEncoding::QuotedPrintable,
+               "base64" => TransferEncoding::Base64,
+               "8bit" => TransferEncoding::EightBit,
+               _ => return Err(format!("invalid *transfer_encoding*: {}", val)),
+            }
+         }
+         "attachments" => {
+            for path in val.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+               attachments.push(Arc::new(attachment::load(path)?));
+            }
+            if attachments.is_empty() {
+               return Err(String::from("empty *attachments* path"));
+            }
+         }
+         "attachment_cmd" => {
+            if val.trim().is_empty() {
+               return Err(String::from("empty *attachment_cmd*"));
+            }
+            attachment_cmd = Some(val.trim().to_string());
+         }
+         "inline_css" => inline_css = parse_bool(key, val)?,
+         "sanitize_html" => sanitize_html = parse_bool(key, val)?,
+         "preheader" => {
+            if val.trim().is_empty() {
+               return Err(String::from("empty *preheader*"));
+            }
+            preheader = Some(val.trim().to_string());
+         }
+         "signature_file" => {
+            let path = val.trim();
+            let text = std::fs::read_to_string(path)
+               .map_err(|e| format!("cannot read *signature_file* {} ({})", path, e))?;
+            if text.trim().is_empty() {
+               return Err(format!("empty *signature_file*: {}", path));
+            }
+            signature = Some(Signature {
+               text: text.trim_end().to_string(),
+               html: None,
+            });
+         }
+         "inline_images" => {
+            inline_images = val
+               .split(',')
+               .map(|p| p.trim())
+               .filter(|p| !p.is_empty())
+               .collect();
+            if inline_images.is_empty() {
+               return Err(String::from("empty *inline_images* path"));
+            }
+         }
+         "max_message_size" => {
+            max_message_size =
+               parse_size(val).map_err(|_| format!("invalid *max_message_size*: {}", val))?
+         }
+         "on_oversize" => {
+            on_oversize = match val.trim() {
+               "abort" => OnOversize::Abort,
+               "warn" => OnOversize::Warn,
+               _ => return Err(format!("invalid *on_oversize* policy: {}", val)),
+            }
+         }
+         "engine" => {
+            engine = match val.trim() {
+               "rmt" => Engine::Rmt,
+               "tera" => Engine::Tera,
+               _ => return Err(format!("invalid template *engine*: {}", val)),
+            }
+         }
+         "locale" => locale = Locale::parse(val)?,
+         "greeting" => greeting = Formality::parse(val)?,
+         "send_at" => {
+            send_at =
+               Some(parse_time(val).ok_or_else(|| format!("invalid *send_at* time: {}", val))?)
+         }
+         _ => return Err(format!("invalid configuration datum: *{}*", key)),
+      }
+   }
+   at.section("general");
+   // the campaign identifier defaults to a hash of the data that identifies a campaign so that
+   // re-running a campaign yields the same `Message-ID` headers; the *From* address is hashed
+   // as written so that the identifier does not depend on how addresses are normalized
+   if campaign_id.is_empty() {
+      campaign_id = format!(
+         "{:016x}",
+         message::fnv1a(format!("{}\n{}", from.unwrap_or_default(), gdata.subject).as_bytes())
+      );
+   }
+   // the `Message-ID` domain defaults to the domain of the *From* address
+   if msgid_domain.is_empty() {
+      msgid_domain = address::ascii_domain(&gdata.from.domain).to_lowercase();
+   }
+   // the content identifiers of the inline images use the `Message-ID` domain
+   at.key("inline_images");
+   let inline_images = inline_images
+      .into_iter()
+      .map(|path| attachment::load_inline(path, &msgid_domain).map(Arc::new))
+      .collect::<Result<Vec<Arc<Attachment>>, String>>()?;
+   let result = Config {
+      gdata,
+      campaign_id,
+      msgid_domain,
+      threading,
+      transliterate,
+      tracking_pixel_url,
+      tracking: Tracking::default(),
+      webhook_url,
+      audit_log,
+      disposition_notification_to,
+      summary_to,
+      recipients_csv,
+      on_duplicate,
+      transfer_encoding,
+      attachments,
+      attachment_cmd,
+      generated_attachments: HashMap::new(),
+      inline_images,
+      inline_css,
+      sanitize_html,
+      preheader,
+      max_message_size,
+      on_oversize,
+      engine,
+      greeting,
+      redirect_to: None,
+      send_at,
+      tool_name: String::from(tool_name),
+      tool_version: String::from(tool_version),
+      smtp: None,
+      imap: None,
+      signatures: HashMap::new(),
+      signature,
+      defaults: HashMap::new(),
+      schema: HashMap::new(),
+      locale,
+      variants: vec![],
+      event: None,
+      recipients: vec![],
+      quarantine: vec![],
+      sources: Sources::default(),
+      confined: false,
+   };
+   Ok(result)
+}
+
+/// Checks whether the email address (short or long form) is valid, see `Address::parse` for
+/// the reason why an address is not.
+pub fn check_email(email: &str) -> bool {
+   Address::parse(email).is_ok()
+}
+
+/// Returns the domain part of an email address (short or long form) if there is one.
+pub fn email_domain(email: &str) -> Option<String> {
+   let email = email.trim();
+   let address = match (email.rfind('<'), email.rfind('>')) {
+      (Some(start), Some(end)) if start < end => &email[start + 1..end],
+      _ => email,
+   };
+   match address.rsplit('@').next() {
+      Some(domain) if domain.len() < address.len() && !domain.is_empty() => {
+         Some(domain.to_lowercase())
+      }
+      _ => None,
+   }
+}
+
+/// Implements a basic sanity check for (http) URLs.
+fn check_url(url: &str) -> bool {
+   let re = Regex::new(r"^https?://[^\s/?#]+\.[^\s/?#]+(/\S*)?(\?\S*)?$").unwrap();
+   re.is_match(url.trim())
+}
+
+/// Parses a boolean config value.
+fn parse_bool(key: &str, val: &str) -> Result<bool, String> {
+   match val.trim().to_lowercase().as_ref() {
+      "true" | "yes" | "on" | "1" => Ok(true),
+      "false" | "no" | "off" | "0" => Ok(false),
+      _ => Err(format!("invalid boolean value for *{}*: {}", key, val)),
+   }
+}
+
+/// Implements a basic sanity check for `Message-ID` values e.g. `<abc.123@example.com>`.
+fn check_message_id(msgid: &str) -> bool {
+   let re = Regex::new(r"^<[^<>@\s]+@[^<>@\s]+>$").unwrap();
+   re.is_match(msgid.trim())
+}
+
+/// Takes a string with whitespace-delimited `Message-ID` values and checks their validity.
+fn check_message_ids(key: &str, msgids: &str) -> Result<Vec<String>, String> {
+   let result: Vec<String> = msgids.split_whitespace().map(|w| w.to_string()).collect();
+   if result.is_empty() {
+      return Err(format!("no message ids for *{}*", key));
+   }
+   let invalid: Vec<String> = result
+      .iter()
+      .filter(|m| !check_message_id(m))
+      .cloned()
+      .collect();
+   if !invalid.is_empty() {
+      return Err(format!(
+         "invalid *{}* message id(s): {}",
+         key,
+         invalid.join(", ")
+      ));
+   }
+   Ok(result)
+}
+
+/// Checks that a campaign identifier may be used as part of a `Message-ID` header.
+pub fn check_campaign_id(campaign_id: &str) -> bool {
+   let re = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+   re.is_match(campaign_id)
+}
+
+/// Implements a basic sanity check for domain names.
+fn check_domain(domain: &str) -> bool {
+   let re = Regex::new(r"^[A-Za-z0-9-]+(\.[A-Za-z0-9-]+)+$").unwrap();
+   re.is_match(domain)
+}
+
+/// Parses the optional per-recipient data (delimited by `':-'`) if present.
+fn parse_recipient_data(rdata: &Vec<&str>) -> Result<HashMap<String, String>, String> {
+   let mut result = HashMap::new();
+   for rd in rdata.iter() {
+      // split the data, example: "cc:-+inc@gg.org"
+      let (key, val) = match rd.split_once(":-") {
+         Some((key, val)) => (key.trim(), val.trim()),
+         None if rd.trim().is_empty() => continue,
+         None => return Err(format!("invalid recipient data ({})", rd)),
+      };
+      // only quoted values may contain the `:-` marker
+      let val = match unquote(val) {
+         Some(val) => val,
+         None if val.contains(":-") => return Err(format!("invalid recipient data ({})", rd)),
+         None => val.to_string(),
+      };
+      if key.is_empty() && val.is_empty() {
+         continue;
+      }
+      if key.is_empty() {
+         return Err(format!("no key for datum ({})", val));
+      }
+      if val.is_empty() {
+         return Err(format!("empty value for key ({})", key));
+      }
+      // the copies are made whatever the case of their keys e.g. `Cc:-..`
+      let lower = key.to_lowercase();
+      let key = if lower == "cc" || lower == "bcc" {
+         lower
+      } else {
+         key.to_string()
+      };
+      result.insert(key, val);
+   }
+   Ok(result)
+}
+
+/// Returns the text of a value enclosed in double quotes (with `""` standing for a literal `"`),
+/// `None` if the value is not quoted.
+fn unquote(val: &str) -> Option<String> {
+   let inner = val.strip_prefix('"')?.strip_suffix('"')?;
+   if inner.replace("\"\"", "").contains('"') {
+      return None;
+   }
+   Some(inner.replace("\"\"", "\""))
+}
+
+/// Quotes a recipient data value if it contains a `|` separator or the `:-` marker.
+fn quote(val: &str) -> String {
+   if val.contains('|') || val.contains(":-") || (val.starts_with('"') && val.ends_with('"')) {
+      format!("\"{}\"", val.replace('"', "\"\""))
+   } else {
+      val.to_string()
+   }
+}
+
+/// Splits the recipient data at the `|` separators, except for the ones in quoted values i.e.
+/// values that start with a double quote right after the `:-` marker.
+fn split_recipient_data(val: &str) -> Vec<&str> {
+   let mut result = vec![];
+   let mut start = 0;
+   let mut quoted = false;
+   // whether only white space was seen since the last `:-` marker
+   let mut after_marker = false;
+   let mut chars = val.char_indices().peekable();
+   while let Some((i, c)) = chars.next() {
+      if quoted {
+         if c == '"' {
+            // a doubled quote stands for a literal one
+            if chars.peek().map(|(_, n)| *n) == Some('"') {
+               chars.next();
+            } else {
+               quoted = false;
+            }
+         }
+         continue;
+      }
+      match c {
+         '|' => {
+            result.push(&val[start..i]);
+            start = i + 1;
+            after_marker = false;
+         }
+         ':' if chars.peek().map(|(_, n)| *n) == Some('-') => {
+            chars.next();
+            after_marker = true;
+         }
+         '"' if after_marker => quoted = true,
+         c if c.is_whitespace() => (),
+         _ => after_marker = false,
+      }
+   }
+   result.push(&val[start..]);
+   result
+}
+
+/// Parses the optional `[smtp]` config file section.
+fn parse_smtp(cfg: &ini::Ini, at: &mut At) -> Result<Option<Smtp>, String> {
+   let section = match cfg.section(Some(String::from("smtp"))) {
+      Some(section) => section,
+      None => return Ok(None),
+   };
+   at.section("smtp");
+   let mut host = None;
+   let mut port = None;
+   let mut security = Security::Tls;
+   let mut username = None;
+   let mut password = None;
+   let mut keyring = None;
+   let mut rate = None;
+   let mut domain_rates = HashMap::new();
+   let mut jitter = None;
+   let mut group_by_domain = false;
+   let mut domain_burst = None;
+   let mut connections = 1;
+   let mut dsn_notify = None;
+   let mut dsn_ret = None;
+   let mut smtputf8 = false;
+
+   for (key, val) in section.iter() {
+      at.key(key);
+      let val = val.trim();
+      match key.as_ref() {
+         "host" => host = Some(val.to_string()),
+         "port" => match val.parse::<u16>() {
+            Ok(p) if p > 0 => port = Some(p),
+            _ => return Err(format!("invalid *smtp* port: {}", val)),
+         },
+         "security" => {
+            security = match val.to_lowercase().as_ref() {
+               "tls" => Security::Tls,
+               "starttls" => Security::StartTls,
+               "none" => Security::None,
+               _ => return Err(format!("invalid *smtp* security: {}", val)),
+            }
+         }
+         "username" => username = Some(val.to_string()),
+         "password" => password = Some(val.to_string()),
+         "keyring" => {
+            if val.is_empty() {
+               return Err(String::from("empty *keyring* entry name"));
+            }
+            keyring = Some(val.to_string())
+         }
+         "rate" => rate = Some(parse_rate(val)?),
+         "jitter" => jitter = Some(parse_jitter(val)?),
+         "group_by_domain" => group_by_domain = parse_bool(key, val)?,
+         "domain_burst" => match val.parse::<usize>() {
+            Ok(n) if n > 0 => domain_burst = Some(n),
+            _ => return Err(format!("invalid *smtp* domain_burst: {}", val)),
+         },
+         "connections" => match val.parse::<usize>() {
+            Ok(n) if (1..=MAX_CONNECTIONS).contains(&n) => connections = n,
+            _ => return Err(format!("invalid *smtp* connections: {}", val)),
+         },
+         "dsn_notify" => dsn_notify = Some(parse_dsn_notify(val)?),
+         "dsn_ret" => {
+            dsn_ret = match val.to_lowercase().as_ref() {
+               "hdrs" | "full" => Some(val.to_uppercase()),
+               _ => return Err(format!("invalid *smtp* dsn_ret: {}", val)),
+            }
+         }
+         "smtputf8" => smtputf8 = parse_bool(key, val)?,
+         k if k.starts_with("rate.") => {
+            let domain = k["rate.".len()..].to_lowercase();
+            if domain != "default" && !check_domain(&domain) {
+               return Err(format!("invalid *smtp* rate domain: {}", domain));
+            }
+            domain_rates.insert(domain, parse_rate(val)?);
+         }
+         _ => return Err(format!("invalid *smtp* configuration datum: *{}*", key)),
+      }
+   }
+   at.section("smtp");
+   let host = match host {
+      Some(ref h) if !h.is_empty() => h.clone(),
+      _ => return Err(String::from("No *host* in the smtp section")),
+   };
+   if password.is_some() && keyring.is_some() {
+      return Err(String::from(
+         "the smtp section needs either a *password* or a *keyring* entry, not both",
+      ));
+   }
+   if username.is_some() != (password.is_some() || keyring.is_some()) {
+      return Err(String::from(
+         "the smtp section needs both a *username* and a *password* or neither",
+      ));
+   }
+   let port = port.unwrap_or(match security {
+      Security::Tls => 465,
+      Security::StartTls => 587,
+      Security::None => 25,
+   });
+   Ok(Some(Smtp {
+      host,
+      port,
+      security,
+      username,
+      password,
+      keyring,
+      rate,
+      domain_rates,
+      jitter,
+      group_by_domain,
+      domain_burst,
+      connections,
+      dsn_notify,
+      dsn_ret,
+      smtputf8,
+   }))
+}
+
+/// Parses the optional `[imap]` config file section.
+fn parse_imap(cfg: &ini::Ini, at: &mut At) -> Result<Option<Imap>, String> {
+   let section = match cfg.section(Some(String::from("imap"))) {
+      Some(section) => section,
+      None => return Ok(None),
+   };
+   at.section("imap");
+   let mut host = None;
+   let mut port = None;
+   let mut security = Security::Tls;
+   let mut username = None;
+   let mut password = None;
+   let mut keyring = None;
+   let mut folder = String::from("Sent");
+
+   for (key, val) in section.iter() {
+      at.key(key);
+      let val = val.trim();
+      match key.as_ref() {
+         "host" => host = Some(val.to_string()),
+         "port" => match val.parse::<u16>() {
+            Ok(p) if p > 0 => port = Some(p),
+            _ => return Err(format!("invalid *imap* port: {}", val)),
+         },
+         "security" => {
+            security = match val.to_lowercase().as_ref() {
+               "tls" => Security::Tls,
+               "starttls" => Security::StartTls,
+               "none" => Security::None,
+               _ => return Err(format!("invalid *imap* security: {}", val)),
+            }
+         }
+         "username" => username = Some(val.to_string()),
+         "password" => password = Some(val.to_string()),
+         "keyring" => {
+            if val.is_empty() {
+               return Err(String::from("empty *keyring* entry name"));
+            }
+            keyring = Some(val.to_string())
+         }
+         "folder" => {
+            // non-ASCII names would need the modified UTF-7 encoding of IMAP
+            if val.is_empty() || !val.chars().all(|c| c.is_ascii_graphic() || c == ' ') {
+               return Err(format!("invalid *imap* folder: {}", val));
+            }
+            folder = val.to_string();
+         }
+         _ => return Err(format!("invalid *imap* configuration datum: *{}*", key)),
+      }
+   }
+   at.section("imap");
+   let host = match host {
+      Some(ref h) if !h.is_empty() => h.clone(),
+      _ => return Err(String::from("No *host* in the imap section")),
+   };
+   let username = match username {
+      Some(ref u) if !u.is_empty() => u.clone(),
+      _ => return Err(String::from("No *username* in the imap section")),
+   };
+   if password.is_some() && keyring.is_some() {
+      return Err(String::from(
+         "the imap section needs either a *password* or a *keyring* entry, not both",
+      ));
+   }
+   let port = port.unwrap_or(match security {
+      Security::Tls => 993,
+      Security::StartTls | Security::None => 143,
+   });
+   Ok(Some(Imap {
+      host,
+      port,
+      security,
+      username,
+      password,
+      keyring,
+      folder,
+   }))
+}
+
+/// Parses the *dsn_notify* setting, `never` or a combination of `success`, `failure` and `delay`
+/// e.g. `success,failure`, returns the value of the `NOTIFY` parameter.
+fn parse_dsn_notify(val: &str) -> Result<String, String> {
+   let conditions: Vec<String> = val.split(',').map(|c| c.trim().to_uppercase()).collect();
+   let valid = conditions == ["NEVER"]
+      || conditions
+         .iter()
+         .all(|c| ["SUCCESS", "FAILURE", "DELAY"].contains(&c.as_str()));
+   if !valid {
+      return Err(format!("invalid *smtp* dsn_notify: {}", val));
+   }
+   Ok(conditions.join(","))
+}
+
+/// Parses the optional `[defaults]` config file section with the values of the recipient data
+/// keys for the recipients that lack them e.g. `ORG=our community`.
+fn parse_defaults(cfg: &ini::Ini, at: &mut At) -> Result<HashMap<String, String>, String> {
+   let mut result = HashMap::new();
+   let section = match cfg.section(Some(String::from("defaults"))) {
+      Some(section) => section,
+      None => return Ok(result),
+   };
+   let re = Regex::new(r"^[A-Za-z0-9]\w*$").unwrap();
+   at.section("defaults");
+   for (key, val) in section.iter() {
+      at.key(key);
+      if !re.is_match(key) {
+         return Err(format!("invalid *defaults* configuration datum: *{}*", key));
+      }
+      result.insert(key.clone(), val.trim().to_string());
+   }
+   Ok(result)
+}
+
+/// Parses the optional `[schema]` config file section with the types of the recipient data keys
+/// e.g. `DUE=date`, the values in the `[defaults]` section must be valid for their types.
+fn parse_schema(
+   cfg: &ini::Ini,
+   defaults: &HashMap<String, String>,
+   at: &mut At,
+) -> Result<HashMap<String, DataType>, String> {
+   let mut result = HashMap::new();
+   let section = match cfg.section(Some(String::from("schema"))) {
+      Some(section) => section,
+      None => return Ok(result),
+   };
+   let re = Regex::new(r"^[A-Za-z0-9]\w*$").unwrap();
+   at.section("schema");
+   for (key, val) in section.iter() {
+      at.key(key);
+      if !re.is_match(key) {
+         return Err(format!("invalid *schema* configuration datum: *{}*", key));
+      }
+      let data_type =
+         DataType::parse(val).map_err(|e| format!("invalid type of *{}* ({})", key, e))?;
+      if let Some(default) = defaults.get(key) {
+         data_type
+            .check(default)
+            .map_err(|e| format!("invalid default of *{}* ({})", key, e))?;
+      }
+      result.insert(key.clone(), data_type);
+   }
+   Ok(result)
+}
+
+/// Parses the optional `[variants]` config file section with the template variants of an A/B
+/// test.
+///
+/// A variant is defined by a `name=template path` entry and may have additional `name.html=path`
+/// (the HTML template) and `name.weight=number` (1 by default) entries.
+fn parse_variants(cfg: &ini::Ini, engine: Engine, at: &mut At) -> Result<Vec<Variant>, String> {
+   let section = match cfg.section(Some(String::from("variants"))) {
+      Some(section) => section,
+      None => return Ok(vec![]),
+   };
+   at.section("variants");
+   let re = Regex::new(r"^(\w+)(\.html|\.weight)?$").unwrap();
+   let load = |path: &str| {
+      template::instantiate(path.trim())
+         .map_err(|e| format!("cannot read template {} ({})", path.trim(), e))?
+         .with_engine(engine)
+         .map(Arc::new)
+         .map_err(|e| format!("{}: {}", path.trim(), e))
+   };
+   let mut names: Vec<&str> = vec![];
+   for key in section.keys() {
+      at.key(key);
+      let name = match re.captures(key) {
+         Some(caps) => caps.get(1).unwrap().as_str(),
+         None => return Err(format!("invalid *variants* configuration datum: *{}*", key)),
+      };
+      if !names.contains(&name) {
+         names.push(name);
+      }
+   }
+   names.sort_unstable();
+   let mut result = vec![];
+   for name in names {
+      at.section("variants");
+      let template = section
+         .get(name)
+         .ok_or_else(|| format!("no template for variant {}", name))?;
+      let key = format!("{}.weight", name);
+      at.key(&key);
+      let weight = match section.get(&key) {
+         Some(weight) => match weight.trim().parse::<u32>() {
+            Ok(weight) if weight > 0 => weight,
+            _ => return Err(format!("invalid weight of variant {}: {}", name, weight)),
+         },
+         None => 1,
+      };
+      let key = format!("{}.html", name);
+      at.key(&key);
+      let html = match section.get(&key) {
+         Some(path) => Some(load(path)?),
+         None => None,
+      };
+      at.key(name);
+      result.push(Variant {
+         name: name.to_string(),
+         weight,
+         template: load(template)?,
+         html,
+      });
+   }
+   Ok(result)
+}
+
+/// Parses the optional `[signatures]` config file section.
+///
+/// A signature is defined by a `name=text` entry and may have an additional `name.html=html`
+/// entry holding its HTML variant.
+fn parse_signatures(cfg: &ini::Ini, at: &mut At) -> Result<HashMap<String, Signature>, String> {
+   let mut result: HashMap<String, Signature> = HashMap::new();
+   let section = match cfg.section(Some(String::from("signatures"))) {
+      Some(section) => section,
+      None => return Ok(result),
+   };
+   let re = Regex::new(r"^\w+$").unwrap();
+
+   at.section("signatures");
+   for (key, val) in section.iter() {
+      at.key(key);
+      let (name, html) = match key.rfind(".html") {
+         Some(pos) if pos + 5 == key.len() => (&key[..pos], true),
+         _ => (key.as_ref(), false),
+      };
+      if !re.is_match(name) {
+         return Err(format!("invalid signature name: {}", key));
+      }
+      if val.trim().is_empty() {
+         return Err(format!("empty signature: {}", key));
+      }
+      let signature = result.entry(name.to_string()).or_insert(Signature {
+         text: String::new(),
+         html: None,
+      });
+      if html {
+         signature.html = Some(val.to_string());
+      } else {
+         signature.text = val.to_string();
+      }
+   }
+   at.section("signatures");
+   for (name, signature) in result.iter() {
+      if signature.text.is_empty() {
+         return Err(format!("no text variant for signature: {}", name));
+      }
+   }
+   Ok(result)
+}
+
+/// The UTM parameters supported in the `[tracking]` section, in the order they are appended to
+/// the links.
+const UTM_PARAMETERS: [&str; 5] = [
+   "utm_source",
+   "utm_medium",
+   "utm_campaign",
+   "utm_term",
+   "utm_content",
+];
+
+/// The minimum length of the tracking secret.
+const MIN_TRACKING_SECRET: usize = 16;
+
+/// Parses the optional `[tracking]` config file section.
+fn parse_tracking(cfg: &ini::Ini, at: &mut At) -> Result<Tracking, String> {
+   let mut result = Tracking::default();
+   let section = match cfg.section(Some(String::from("tracking"))) {
+      Some(section) => section,
+      None => return Ok(result),
+   };
+
+   at.section("tracking");
+   for (key, val) in section.iter() {
+      at.key(key);
+      let val = val.trim();
+      match key.as_ref() {
+         k if UTM_PARAMETERS.contains(&k) => {
+            if val.is_empty() {
+               return Err(format!("empty *{}* in the tracking section", key));
+            }
+         }
+         "redirect_url" => {
+            if !check_url(val) {
+               return Err(format!("invalid *redirect_url*: {}", val));
+            }
+            result.redirect_url = Some(val.to_string());
+         }
+         "secret" => {
+            if val.len() < MIN_TRACKING_SECRET {
+               return Err(format!(
+                  "the tracking *secret* must be at least {} characters long",
+                  MIN_TRACKING_SECRET
+               ));
+            }
+            result.secret = Some(val.to_string());
+         }
+         _ => return Err(format!("invalid *tracking* configuration datum: *{}*", key)),
+      }
+   }
+   // the properties of a section are not ordered, use the canonical order instead
+   for name in UTM_PARAMETERS.iter() {
+      if let Some(val) = section.get(*name) {
+         result.utm.push((name.to_string(), val.trim().to_string()));
+      }
+   }
+   Ok(result)
+}
+
+/// Parses a point in time in RFC 3339 format e.g. `2024-05-01T15:00:00+02:00`, the seconds may
+/// be omitted.
+pub fn parse_time(val: &str) -> Option<DateTime<Utc>> {
+   parse_local_time(val).map(|t| t.with_timezone(&Utc))
+}
+
+/// Like `parse_time` but the time keeps the UTC offset it was given with.
+pub fn parse_local_time(val: &str) -> Option<DateTime<FixedOffset>> {
+   let val = val.trim();
+   DateTime::parse_from_rfc3339(val)
+      .or_else(|_| DateTime::parse_from_str(val, "%Y-%m-%dT%H:%M%:z"))
+      .or_else(|_| DateTime::parse_from_str(&val.replace('Z', "+00:00"), "%Y-%m-%dT%H:%M%:z"))
+      .ok()
+}
+
+/// Parses the optional `[event]` config file section, the organizer defaults to the sender.
+fn parse_event(cfg: &ini::Ini, from: &Address, at: &mut At) -> Result<Option<Event>, String> {
+   let section = match cfg.section(Some(String::from("event"))) {
+      Some(section) => section,
+      None => return Ok(None),
+   };
+   at.section("event");
+   let mut summary = None;
+   let mut start = None;
+   let mut end = None;
+   let mut location = None;
+   let mut description = None;
+   let mut organizer = from.clone();
+
+   for (key, val) in section.iter() {
+      at.key(key);
+      let val = val.trim();
+      match key.as_ref() {
+         "summary" => summary = Some(val.to_string()),
+         "start" | "end" => {
+            let time = parse_time(val)
+               .ok_or_else(|| format!("invalid *{}* in the event section: {}", key, val))?;
+            if key == "start" {
+               start = Some(time);
+            } else {
+               end = Some(time);
+            }
+         }
+         "location" => location = Some(val.to_string()),
+         "description" => description = Some(val.to_string()),
+         "organizer" => {
+            organizer = Address::parse(val)
+               .map_err(|e| format!("invalid *organizer* in the event section: {} ({})", val, e))?
+         }
+         _ => return Err(format!("invalid *event* configuration datum: *{}*", key)),
+      }
+   }
+   at.section("event");
+   let missing = |key: &str| format!("No *{}* in the event section", key);
+   let result = Event {
+      summary: summary
+         .filter(|s| !s.is_empty())
+         .ok_or_else(|| missing("summary"))?,
+      start: start.ok_or_else(|| missing("start"))?,
+      end: end.ok_or_else(|| missing("end"))?,
+      location,
+      description,
+      organizer,
+   };
+   if result.end <= result.start {
+      return Err(String::from("the event ends before it starts"));
+   }
+   Ok(Some(result))
+}
+
+/// Parses the `[recipients]` config file section.
+fn parse_recipients(cfg: &ini::Ini, at: &mut At) -> Result<Vec<Recipient>, String> {
+   let mut result: Vec<Recipient> = Vec::new();
+
+   at.section("recipients");
+   // we want a stable sort order of the recipient data
+   let mut entries: Vec<(&String, &String)> = section(cfg, "recipients")?.iter().collect();
+   entries.sort();
+
+   for (key, val) in entries {
+      at.key(key);
+      result.push(parse_recipient(key, val)?);
+   }
+   Ok(result)
+}
+
+/// Returns the section of the config file, an error if there is no such section.
+fn section<'a>(cfg: &'a ini::Ini, name: &str) -> Result<&'a ini::ini::Properties, String> {
+   cfg.section(Some(name))
+      .ok_or_else(|| format!("No *{}* section in config file", name))
+}
+
+/// Parses a single entry of the `[recipients]` section.
+fn parse_recipient(key: &str, val: &str) -> Result<Recipient, String> {
+   match Address::parse(key) {
+      Ok(address) if address.display_name.is_some() => {
+         return Err(format!(
+            "invalid email: {} (the names go after the = sign)",
+            key
+         ))
+      }
+      Ok(_) => (),
+      Err(e) => return Err(format!("invalid email: {} ({})", key, e)),
+   }
+   // split recipient data, example:
+   // John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org
+   let mut data: Vec<&str> = split_recipient_data(val)
+      .into_iter()
+      .map(|w| w.trim())
+      .filter(|w| !w.is_empty())
+      .collect();
+   if data.is_empty() {
+      return Err(format!("invalid data for email: {}", key));
+   }
+   // split the first entry in the recipient data i.e. the names
+   let names: Vec<String> = data
+      .remove(0)
+      .split_ascii_whitespace()
+      .filter(|w| !w.is_empty())
+      .map(|n| n.to_string())
+      .collect();
+   // parse the remainder of the recipient data
+   match parse_recipient_data(&data) {
+      Ok(rd) => Ok(Recipient {
+         email: key.to_string(),
+         names,
+         data: rd,
+      }),
+      Err(msg) => Err(format!("invalid recipient data for {} ({})", key, msg)),
+   }
+}
+
+/// Very basic sanity checks on the config.
+///
+/// Does it have the general/recipients sections and does the former have a `From` and a `Subject`?
+pub fn check(cfg: &ini::Ini) -> Result<usize, String> {
+   let sections = sa(&["general", "recipients"]);
+   let mut num_recipients = 0;
+
+   for s in sections {
+      match cfg.section(Some(s.to_string())) {
+         Some(props) => {
+            if s == "general" {
+               if !props.contains_key("From") && !props.contains_key("from") {
+                  return Err(String::from("No *From* header in the general section"));
+               }
+               if !props.contains_key("Subject") && !props.contains_key("subject") {
+                  return Err(String::from("No *Subject* in the general section"));
+               }
+            }
+            if s == "recipients" {
+               num_recipients = props.len();
+               let csv = cfg
+                  .section(Some(String::from("general")))
+                  .map(|g| g.contains_key("recipients_csv"))
+                  .unwrap_or(false);
+               if num_recipients == 0 && !csv {
+                  return Err(String::from("No email recipients found in config file"));
+               }
+            }
+         }
+         None => {
+            return Err(format!("No *{}* section in config file", s));
+         }
+      }
+   }
+   Ok(num_recipients)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `FileFormat` enum lists the formats of config files.
+pub enum FileFormat {
+   #[default]
+   Ini,
+   Toml,
+   Yaml,
+}
+
+impl FileFormat {
+   /// Parses a format name: `ini`, `toml` or `yaml`.
+   pub fn parse(text: &str) -> Result<FileFormat, String> {
+      match text.trim().to_lowercase().as_str() {
+         "ini" => Ok(FileFormat::Ini),
+         "toml" => Ok(FileFormat::Toml),
+         "yaml" | "yml" => Ok(FileFormat::Yaml),
+         _ => Err(format!("invalid config file format: {}", text)),
+      }
+   }
+
+   /// Returns the format of the config file as per its extension, INI unless it is `.toml`,
+   /// `.yaml` or `.yml`.
+   pub fn of(path: &Path) -> FileFormat {
+      path
+         .extension()
+         .and_then(|e| FileFormat::parse(&e.to_string_lossy()).ok())
+         .unwrap_or_default()
+   }
+}
+
+/// Converts the text of an INI config file to the given format, keeping the comments. Settings
+/// that are commented out (`#key=value`) stay commented out in the new format.
+pub fn convert(text: &str, format: FileFormat) -> Result<String, String> {
+   if format == FileFormat::Ini {
+      return Ok(text.to_string());
+   }
+   let ini = Ini::load_from_str(text).map_err(|e| format!("line {}: {}", e.line, e.msg))?;
+   let setting = Regex::new(r#"^(#?)([^\s=#;'"\[]+)=(.*)$"#).unwrap();
+   let bare = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+   let quoted = |text: &str| serde_json::Value::from(text).to_string();
+   let mut result = String::new();
+   let mut section: Option<String> = None;
+   for line in text.lines() {
+      let trimmed = line.trim();
+      if trimmed.starts_with('[') && trimmed.ends_with(']') {
+         let name = trimmed[1..trimmed.len() - 1].trim().to_string();
+         match format {
+            FileFormat::Yaml => result.push_str(&format!("{}:\n", name)),
+            _ => result.push_str(&format!("{}\n", trimmed)),
+         }
+         section = Some(name);
+         continue;
+      }
+      let indent = match (format, &section) {
+         (FileFormat::Yaml, Some(_)) if !trimmed.is_empty() => "  ",
+         _ => "",
+      };
+      let caps = match setting.captures(trimmed) {
+         Some(caps) => caps,
+         None => {
+            result.push_str(&format!("{}{}\n", indent, trimmed));
+            continue;
+         }
+      };
+      let key = caps[2].trim();
+      let value = if caps[1].is_empty() {
+         ini.get_from(section.as_deref(), key)
+            .unwrap_or_default()
+            .to_string()
+      } else {
+         // read the setting that is commented out the way it would be read if it were not
+         Ini::load_from_str(&format!("{}={}", key, &caps[3]))
+            .ok()
+            .and_then(|i| i.get_from(None::<String>, key).map(|v| v.to_string()))
+            .unwrap_or_else(|| caps[3].trim().to_string())
+      };
+      let key = if bare.is_match(key) {
+         key.to_string()
+      } else {
+         quoted(key)
+      };
+      let separator = if format == FileFormat::Yaml {
+         ":"
+      } else {
+         " ="
+      };
+      result.push_str(&format!(
+         "{}{}{}{} {}\n",
+         indent,
+         &caps[1],
+         key,
+         separator,
+         quoted(&value)
+      ));
+   }
+   Ok(result)
+}
+
+/// Generates a configuration for a mailing campaign for a user to tweak as needed.
+pub fn gen_config(name: &str, version: &str) -> String {
+   format!(
+      r#"# {} version {}
+#
+# anything that follows a hash is a comment
+# email address is to the left of the '=' sign, first word after is
+# the first name, the rest is the surname
+[general]
+From="Frodo Baggins" <rts@example.com>
+#cc=weirdo@nsb.gov, cc@example.com
+#Reply-To="John Doe" <jd@mail.com>
+# ask for read receipts, mail clients may ignore the request or ask the reader
+#disposition_notification_to=rts@example.com
+subject=Hello %_FN%!
+# base configs (e.g. with the From address and the smtp section shared by several
+# campaigns) to read first, the settings here override theirs
+#include=base.ini
+# a sending profile from the user configuration provides the From address (if
+# there is none here) and the smtp settings (unless set in the smtp section)
+#profile=work
+# the generated Message-ID headers look like <campaign_id.hash@message_id_domain>
+# campaign_id defaults to a hash of From/subject, message_id_domain to the From domain
+#campaign_id=spring-newsletter
+#message_id_domain=mail.example.com
+# threading headers for a follow-up campaign: either reply to the message each
+# recipient got in a previous campaign (as per the campaign history) or to one
+# particular message
+#follow_up_of=winter-newsletter
+#in_reply_to=<abc.123@example.com>
+#references=<abc.122@example.com> <abc.123@example.com>
+# non-ASCII display names are RFC 2047 encoded, set this for relays that
+# mangle encoded words to transliterate them instead (Müller -> Mueller)
+#transliterate=yes
+# PRIVACY: setting tracking_pixel_url adds an invisible, per-recipient image to
+# HTML emails so that you can see who opened them. Recipients are not asked for
+# consent, this may be illegal in your jurisdiction (e.g. under the GDPR) and
+# many mail clients block remote images anyway. Off unless set, needs the
+# secret in the tracking section.
+#tracking_pixel_url=https://track.example.com/open.gif
+# the campaign events (campaign started/finished, message sent/failed) are
+# POSTed as JSON documents to the webhook URL, if set, in the background (a
+# webhook that cannot keep up misses events)
+#webhook_url=https://hooks.example.com/rmt
+# every delivery attempt is recorded in the (append-only) audit log, if set
+#audit_log=/var/log/rmt/audit.jsonl
+# a summary of every run (counts, failures, duration and the JSON report) is
+# emailed to this address over the same smtp server, if set
+#summary_to=ops@example.com
+# more recipients may be read from CSV files with an 'email' and a 'names'
+# column, every other column is a recipient data key
+#recipients_csv=recipients.csv, more-recipients.csv
+# what to do if an email address appears more than once: error (default),
+# keep-first, keep-last or merge (the data, later values win)
+#on_duplicate=merge
+# how the email body is encoded: auto (default, plain ASCII text is sent as
+# is, everything else quoted-printable or base64), quoted-printable, base64
+# or 8bit (only if the SMTP server supports 8BITMIME)
+#transfer_encoding=quoted-printable
+# files attached to every email (separated by commas)
+#attachments=/home/user/atmt1.ics, ../Documents/doc2.txt
+# a command that is run for every recipient to generate a personalized
+# attachment e.g. an invoice, it may use the template keys (which are passed
+# as RMT_* environment variables as well) and prints the path of the file
+#attachment_cmd=./make_invoice.sh %_EA% %INVOICE_NO%
+# images embedded in HTML emails, the HTML template refers to them with
+# %_CID:logo.png% (images referenced that way are embedded even if they are
+# not listed here)
+#inline_images=img/logo.png, img/banner.jpg
+# many mail clients ignore <style> blocks, set this to copy the CSS rules of
+# HTML emails into the style attributes of the elements
+#inline_css=yes
+# strip scripts, frames, embedded objects, event handlers and javascript: URLs
+# from HTML emails ('rmt validate' reports them either way)
+#sanitize_html=yes
+# the preview text inboxes show next to the subject, it is inserted hidden at
+# the top of HTML emails and may use the template keys
+#preheader=Our spring offers for %ORG%
+# the (templated) contents of this file are appended to every email unless
+# the recipient picks a named signature
+#signature_file=signature.txt
+# the maximum size of an email (default 10M, 0 means no limit), what to do
+# about larger ones: abort (default, nothing is sent) or warn
+#max_message_size=25M
+#on_oversize=warn
+# the syntax of the templates: rmt (default, %KEY% placeholders) or tera
+# (Jinja-like, {{{{ KEY }}}}, {{% if KEY %}} etc.)
+#engine=tera
+# when to start sending (RFC 3339), rmt waits until then after the confirmation
+#send_at=2024-06-01T09:00+02:00
+# how the typed recipient data (see the schema section) is formatted e.g.
+# en (default), en-GB, de, de-CH, es, fr, it, nl or pt
+#locale=de
+# the %_GREETING% key holds a salutation in the recipient's language (the
+# 'lang' datum, the locale by default): informal (default, "Hi John") or
+# formal (with the 'TITLE' datum, "Dear Dr. Doe")
+#greeting=formal
+# click tracking: the links in HTML emails get the utm_* parameters appended
+# (the values may use the template keys) and, if a redirect_url is set, are
+# routed through it as '<redirect_url>?t=<recipient token>&u=<original link>'
+# the recipient tokens (of the links and the tracking pixel) are keyed with the
+# secret (at least 16 characters), share it with the tracking service only
+#[tracking]
+#utm_source=newsletter
+#utm_medium=email
+#utm_campaign=spring-sale
+#utm_content=%ORG%
+#redirect_url=https://track.example.com/click
+#secret=${{TRACKING_SECRET}}
+# every recipient gets a personalized calendar invitation (invite.ics) to the
+# event, the summary and the description may use the template keys, times
+# are in RFC 3339 format, the organizer defaults to the From address
+# templates show the start in the recipients' time zones (the tz datum e.g.
+# tz:-Europe/Vienna) with %_EVENT_START|time% or %_EVENT_START|datetime%
+#[event]
+#summary=Spring sale preview for %_FN%
+#start=2024-05-01T15:00:00+02:00
+#end=2024-05-01T16:00:00+02:00
+#location=https://meet.example.com/spring
+#description=Join us for a preview of the spring collection.\nSee you there!
+#organizer="Frodo Baggins" <rts@example.com>
+# the SMTP server used to send the emails, security is one of tls (default,
+# port 465), starttls (port 587) or none (port 25)
+#[smtp]
+#host=smtp.example.com
+#security=tls
+#username=frodo
+# config values may refer to environment variables e.g. password=${{SMTP_PASSWORD}}
+#password=secret
+# instead of the password the name of a system keyring entry may be given,
+# store the password in the keyring with `rmt auth set smtp-example`
+#keyring=smtp-example
+# without any credentials here they are taken from the RMT_SMTP_USERNAME and
+# RMT_SMTP_PASSWORD variables (which may be set in a .env file) or from the
+# ~/.netrc entry for the host
+# optional rate limit, the maximum number of emails sent per s, min or h
+#rate=30/min
+# optional rate limits per recipient domain, 'default' applies to the domains
+# not listed, the domains are sent to in turns to keep the total rate up
+#rate.gmail.com=20/min
+#rate.default=60/min
+# send the emails grouped by recipient domain instead of in the order of the
+# recipients below, at most domain_burst emails to a domain in a row
+#group_by_domain=yes
+#domain_burst=50
+# the number of connections the emails are sent over at the same time
+#connections=4
+# optional random delay between two emails so that they are not sent at
+# perfectly uniform intervals, the units are ms, s (the default) and min
+#jitter=5-30s
+# internationalized domains (e.g. bücher.example) are converted to punycode
+# unless the server supports SMTPUTF8, check with 'rmt doctor'
+#smtputf8=yes
+# ask for delivery status notifications (if the server supports DSN): when
+# (never or any of success, failure and delay) and what they return of the
+# email (hdrs or full)
+#dsn_notify=success,failure
+#dsn_ret=hdrs
+# store a copy of every email sent in a mailbox folder, the password may also
+# come from a keyring entry or ~/.netrc
+#[imap]
+#host=imap.example.com
+#security=tls
+#username=frodo
+#password=${{IMAP_PASSWORD}}
+#folder=Sent
+# named signatures, picked per recipient with a 'signature' datum e.g.
+# 'jd@example.com=John Doe|signature:-anna', the optional '.html' entry
+# is the HTML variant of the signature; the recipients that do not pick one
+# get the signature_file of the general section (if any)
+#[signatures]
+#anna=Anna Smith\nYour account manager
+#anna.html=<b>Anna Smith</b><br>Your account manager
+# the values of the recipient data keys for the recipients that lack them
+#[defaults]
+#ORG=our community
+# A/B test: every recipient gets one of the template variants (the name=path
+# entries) with a probability proportional to its weight (1 by default),
+# 'rmt run' needs no --template then
+#[variants]
+#a=spring-a.eml
+#b=spring-b.eml
+#b.html=spring-b.html
+#b.weight=3
+# the types of recipient data keys: date (written as YYYY-MM-DD), number or
+# currency:CODE, the values are checked and formatted as per the locale
+#[schema]
+#DUE=date
+#AMOUNT=currency:EUR
+[recipients]
+# values containing a '|' or ':-' are enclosed in double quotes e.g.
+# TITLE:-"CEO | Founder", a literal '"' within them is doubled; a '#' or ';'
+# starts a comment, write \# or \; for the literal characters
+# data keys with the 'h_' prefix add headers to the recipient's email e.g.
+# h_X-Account-ID:-12345; a 'tags' datum e.g. tags:-vip,emea puts the recipient
+# in segments, select them with e.g. 'rmt run --segment "emea AND NOT vip"'
+# The 'cc' setting below *redefines* the global 'cc' value above
+jd@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org
+mm@gmail.com=Mickey Mouse|ORG:-Disney   # trailing comment!!
+# The 'cc' setting below *adds* to the global 'cc' value above, a 'bcc' setting
+# blind-copies the recipient's email e.g. bcc:-archive@example.com
+daisy@example.com=Daisy Lila|ORG:-NASA|TITLE:-Dr.|cc:-+inc@gg.org"#,
+      name, version
+   )
+}
+
+/// Escapes the characters the INI reader treats specially in values: backslashes and the `#`
+/// and `;` that would start a comment.
+fn escape_ini(text: &str) -> String {
+   text
+      .replace('\\', "\\\\")
+      .replace('#', "\\#")
+      .replace(';', "\\;")
+}
+
+/// Formats the recipient as an entry of the `[recipients]` section e.g.
+/// `jd@example.com=John Doe|ORG:-EFF`, the data keys are sorted.
+pub fn recipient_entry(rcp: &Recipient) -> String {
+   let mut result = format!("{}={}", rcp.email, escape_ini(&rcp.names.join(" ")));
+   let mut keys: Vec<&String> = rcp.data.keys().collect();
+   keys.sort();
+   for key in keys {
+      result.push_str(&format!("|{}:-{}", key, escape_ini(&quote(&rcp.data[key]))));
+   }
+   result
+}
+
+/// Generates the config for a new campaign from the answers given to `rmt init`.
+///
+/// Without recipients a placeholder entry is written. The SMTP credentials are commented out
+/// since the password is never written.
+pub fn gen_campaign(
+   name: &str,
+   version: &str,
+   from: &str,
+   subject: &str,
+   smtp: Option<&Smtp>,
+   recipients: &[Recipient],
+) -> String {
+   let mut result = format!(
+      "# {} version {}\n#\n# see `{} sample config` for all settings\n[general]\nFrom={}\nsubject={}\n",
+      name, version, name, from, subject
+   );
+   if let Some(smtp) = smtp {
+      result.push_str(&format!("[smtp]\nhost={}\n", smtp.host));
+      result.push_str(&format!("port={}\n", smtp.port));
+      result.push_str(match smtp.security {
+         Security::Tls => "security=tls\n",
+         Security::StartTls => "security=starttls\n",
+         Security::None => "security=none\n",
+      });
+      if let Some(ref username) = smtp.username {
+         // the parser wants both or neither, the user completes them
+         result.push_str(&format!(
+            "# uncomment and fill in the password (or the name of a keyring entry, see\n# `rmt auth set`) to authenticate\n#username={}\n#password=\n",
+            username
+         ));
+      }
+      result.push_str("#rate=30/min\n");
+   }
+   result.push_str("[recipients]\n");
+   if recipients.is_empty() {
+      result.push_str("# replace this with your recipients\njd@example.com=John Doe|ORG:-EFF\n");
+   }
+   for rcp in recipients.iter() {
+      result.push_str(&recipient_entry(rcp));
+      result.push('\n');
+   }
+   result
+}
+
+/// Generates the template for a new campaign, `keys` are the recipient data keys available.
+pub fn gen_campaign_template(name: &str, version: &str, from: &str, keys: &[&str]) -> String {
+   let mut result = String::from("Hello %_FN%,\n\nwrite your email here");
+   if !keys.is_empty() {
+      let keys: Vec<String> = keys.iter().map(|k| format!("%{}%", k)).collect();
+      result.push_str(&format!(
+         ", the recipient data is available as {}",
+         keys.join(", ")
+      ));
+   }
+   result.push_str(&format!(
+      ".\n\nBest regards\n{}\n\n\nSent with {} version {}, see https://301.mx/{} for details",
+      crate::encoding::split_address(from).0,
+      name,
+      version,
+      name
+   ));
+   result
+}
+
+/// Generates a template for a mailing campaign for a user to tweak as needed.
+pub fn gen_template(name: &str, version: &str) -> String {
+   format!(
+      r#"_FN / _LN / _EA = first name / last name / email address
+
+Hello %_FN% // %_LN%, how are things going at %ORG%?
+this is your email: %_EA% :)
+
+
+Sent with {} version {}, see https://301.mx/{} for details"#,
+      name, version, name
+   )
+}
+
+/// Generates an HTML template for a mailing campaign for a user to tweak as needed, the plain
+/// text template (see `gen_template`) is sent along with it.
+pub fn gen_html_template(name: &str, version: &str) -> String {
+   format!(
+      r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<style>
+  body {{ font-family: sans-serif; line-height: 1.5; }}
+  .footer {{ color: #888; font-size: small; }}
+</style>
+</head>
+<body>
+<p>Hello %_FN% %_LN%, how are things going at %ORG%?</p>
+<p>this is your email: <a href="mailto:%_EA%">%_EA%</a> :)</p>
+<p class="footer">Sent with {} version {}, see <a href="https://301.mx/{}">301.mx/{}</a> for details</p>
+</body>
+</html>"#,
+      name, version, name, name
+   )
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+   use ini::Ini;
+   use std::io::{Error, Write};
+   use tempfile::NamedTempFile;
+
+   fn prep_config(content: &str) -> Result<ini::Ini, Error> {
+      let mut tf = NamedTempFile::new()?;
+
+      // Write some test data to the first handle.
+      tf.write_all(content.as_bytes())?;
+      let cfg = Ini::load_from_file(tf.path()).unwrap();
+      Ok(cfg)
+   }
+
+   #[test]
+   fn check_with_empty_file() {
+      let cfg = prep_config("").expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No *general* section in config file")),
+         check(&cfg)
+      );
+   }
+
+   #[test]
+   fn parse_without_sections() {
+      let cfg = prep_config("").expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No *general* section in config file")),
+         parse(&cfg, "rmt", "0.1.2").map(|_| ())
+      );
+      let cfg = prep_config("[general]\nFrom=abc@def.com\nSubject=hello\n")
+         .expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No *recipients* section in config file")),
+         parse_lenient(&cfg, "rmt", "0.1.2").map(|_| ())
+      );
+   }
+
+   #[test]
+   fn load_invalid_files() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let path = dir.path().join("campaign.ini");
+      let err = instantiate(&[&path.display().to_string()], None, "rmt", "0.1.2")
+         .err()
+         .unwrap()
+         .to_string();
+      assert!(err.starts_with("cannot read "), "{}", err);
+      std::fs::write(&path, "[general]\nFrom=abc@def.com\n[recipients\n").unwrap();
+      let err = instantiate(&[&path.display().to_string()], None, "rmt", "0.1.2")
+         .err()
+         .unwrap()
+         .to_string();
+      assert!(
+         err.starts_with(&format!("{}:3: ", path.display())),
+         "{}",
+         err
+      );
+   }
+
+   #[test]
+   fn trace_errors_to_settings() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let path = |name: &str| dir.path().join(name).display().to_string();
+      let write = |name: &str, text: &str| std::fs::write(path(name), text).unwrap();
+      write(
+         "base.ini",
+         "[general]\nFrom=fb@example.com\nsubject=Hi\n[smtp]\nhost=localhost\nport=25\n",
+      );
+      write(
+         "c.ini",
+         "[general]\ninclude=base.ini\n[recipients]\njd@example.com=John Doe\n\
+          [imap]\nhost=localhost\nusername=fb\nport=0\n[smtp]\nport=x\n",
+      );
+      let at = |names: &[&str]| {
+         let paths: Vec<String> = names.iter().map(|n| path(n)).collect();
+         let paths: Vec<&str> = paths.iter().map(|p| p.as_str()).collect();
+         instantiate(&paths, None, "rmt", "0.1.2")
+            .expect_err("valid config")
+            .origin
+            .map(|o| (o.file, o.line))
+      };
+      // the *port* of the smtp section, not the one of the imap section or the base config
+      assert_eq!(Some((path("c.ini"), Some(10))), at(&["c.ini"]));
+      write(
+         "c.ini",
+         "[general]\ninclude=base.ini\n[recipients]\njd@example.com=John Doe\n\
+          [imap]\nhost=localhost\nusername=fb\nport=0\n",
+      );
+      assert_eq!(Some((path("c.ini"), Some(8))), at(&["c.ini"]));
+      write(
+         "c.ini",
+         "[general]\nFrom=fb@example.com\nsubject=Hi\ncc=info@example.com\n[recipients]\n\
+          jd@example.com=John Doe|cc:-bl@kf.io,info@\n",
+      );
+      assert_eq!(Some((path("c.ini"), Some(6))), at(&["c.ini"]));
+      // the settings of later files win
+      write("o.toml", "[general]\ncc = \"oh!no!\"\n");
+      assert_eq!(Some((path("o.toml"), Some(2))), at(&["c.ini", "o.toml"]));
+      write("o.yaml", "general:\n  subject: Hi\n  cc: oh!no!\n");
+      assert_eq!(Some((path("o.yaml"), Some(3))), at(&["c.ini", "o.yaml"]));
+      // errors about the config as a whole
+      write("c.ini", "[general]\nFrom=fb@example.com\nsubject=Hi\n");
+      assert_eq!(Some((path("c.ini"), None)), at(&["c.ini"]));
+
+      write(
+         "c.ini",
+         "[general]\nFrom=fb@example.com\nsubject=Hi\n[recipients]\nx=X\njd@example.com=J D\n",
+      );
+      let cfg =
+         instantiate_lenient(&[&path("c.ini")], None, "rmt", "0.1.2").expect("invalid config");
+      assert_eq!(1, cfg.quarantine.len());
+      assert_eq!(Some(5), cfg.sources.quarantined(0).and_then(|o| o.line));
+      assert_eq!(
+         Some(6),
+         cfg.sources.recipient("jd@example.com").and_then(|o| o.line)
+      );
+   }
+
+   #[test]
+   fn check_with_no_recipients_section() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello world!
+# this is a comment"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No *recipients* section in config file")),
+         check(&cfg)
+      );
+   }
+
+   #[test]
+   fn check_with_no_recipients() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello world!
+# this is a comment
+[recipients]"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No email recipients found in config file")),
+         check(&cfg)
+      );
+   }
+
+   #[test]
+   fn check_with_no_from_in_empty_general() {
+      let file = r#"
+[general]
+# this is a comment
+[recipients]"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No *From* header in the general section")),
+         check(&cfg)
+      );
+   }
+
+   #[test]
+   fn check_with_no_from_in_non_empty_general() {
+      let file = r#"
+[general]
+P1=a
+P2=b
+# this is a comment
+[recipients]"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No *From* header in the general section")),
+         check(&cfg)
+      );
+   }
+
+   #[test]
+   fn check_with_no_subject() {
+      let file = r#"
+[general]
+From=a
+# this is a comment
+[recipients]"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("No *Subject* in the general section")),
+         check(&cfg)
+      );
+   }
+
+   #[test]
+   fn check_with_happy_path() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello world!
+# this is a comment
+[recipients]
+a@b.com=A B
+c@d.com=C D"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(Ok(2), check(&cfg));
+   }
+
+   #[test]
+   fn parse_recipients_with_happy_path() {
+      let file = r#"
+[general]
+From=abc@def.com
+# this is a comment
+cc=weirdo@nsb.gov, cc@example.com
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+jd@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org
+mm@gmail.com=Mickey Mouse|ORG:-Disney   # trailing comment!!
+# The 'cc' setting below *adds* to the global 'cc' value above
+daisy@example.com=Daisy Lila|ORG:-NASA|TITLE:-Dr.|cc:-+inc@gg.org"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[
+               ("ORG", "EFF"),
+               ("TITLE", "PhD"),
+               ("cc", "bl@kf.io,info@ex.org"),
+            ]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[("ORG", "Disney")]),
+         },
+      ];
+      assert_eq!(
+         expected,
+         parse_recipients(&cfg, &mut At::default()).expect("This should not fail")
+      );
+   }
+
+   #[test]
+   fn recipients_to_string() {
+      let r = Recipient {
+         email: String::from("jd@example.com"),
+         names: sa(&["John", "Doe", "Jr."]),
+         data: sm(&[
+            ("ORG", "EFF"),
+            ("TITLE", "PhD"),
+            ("cc", "bl@kf.io,info@ex.org"),
+         ]),
+      };
+      assert_eq!("email: jd@example.com, names: John, Doe, Jr., data: ORG => EFF, TITLE => PhD, cc => bl@kf.io,info@ex.org", r.to_string());
+   }
+
+   #[test]
+   fn merge_configs() {
+      let mut base = testing::config("base@example.com", "hello");
+      base.inline_css = true;
+      base.smtp = Some(testing::smtp());
+      base.preheader = Some(String::from("base"));
+      base.defaults = sm(&[("ORG", "none"), ("CITY", "Vienna")]);
+      base.gdata.cc = testing::addresses("cc@example.com");
+      base.recipients = vec![testing::recipient("jd@example.com", "John Doe", &[])];
+      let mut campaign = testing::config("fb@example.com", "");
+      campaign.engine = Engine::Tera;
+      campaign.preheader = Some(String::from("campaign"));
+      campaign.defaults = sm(&[("ORG", "EFF")]);
+      campaign.gdata.cc = testing::addresses("cc2@example.com");
+      campaign.recipients = vec![testing::recipient("mm@gmail.com", "Mickey Mouse", &[])];
+      let mut cfg = base;
+      cfg.merge(
+         Config {
+            recipients: vec![testing::recipient("dd@example.com", "Donald Duck", &[])],
+            ..testing::config("fb@example.com", "")
+         },
+         ListMerge::Append,
+      )
+      .expect("Failed to merge");
+      assert_eq!("fb@example.com", cfg.from().to_string());
+      assert_eq!("hello", cfg.subject());
+      assert_eq!(2, cfg.recipient_count());
+      cfg.merge(campaign, ListMerge::Replace)
+         .expect("Failed to merge");
+      assert_eq!(Engine::Tera, cfg.engine);
+      assert!(cfg.inline_css);
+      assert!(cfg.smtp.is_some());
+      assert_eq!(Some(String::from("campaign")), cfg.preheader);
+      assert_eq!(sm(&[("ORG", "EFF"), ("CITY", "Vienna")]), cfg.defaults);
+      assert_eq!(testing::addresses("cc2@example.com"), cfg.gdata.cc);
+      assert_eq!(
+         vec![testing::recipient("mm@gmail.com", "Mickey Mouse", &[])],
+         cfg.recipients
+      );
+
+      let mut twice = testing::config("fb@example.com", "hello");
+      twice.recipients = vec![testing::recipient("jd@example.com", "John Doe", &[])];
+      let mut other = testing::config("fb@example.com", "hello");
+      other.recipients = vec![testing::recipient("JD@example.com", "John Doe", &[])];
+      assert_eq!(
+         Err(String::from(
+            "duplicate recipient: JD@example.com (see *on_duplicate*)"
+         )),
+         twice.merge(other, ListMerge::Append)
+      );
+   }
+
+   #[test]
+   fn iterate_recipients() {
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.recipients = vec![
+         testing::recipient("jd@Example.com", "John Doe", &[("tags", "vip, emea")]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[("tags", "emea")]),
+         testing::recipient("dd@mail.example.com", "Donald Duck", &[]),
+      ];
+      let emails = |rcps: Vec<&Recipient>| -> Vec<String> {
+         rcps.into_iter().map(|r| r.email.clone()).collect()
+      };
+      assert_eq!(3, cfg.recipients().count());
+      assert_eq!(
+         sa(&["jd@Example.com"]),
+         emails(cfg.filter_by_domain("EXAMPLE.com").collect())
+      );
+      assert_eq!(
+         sa(&["jd@Example.com", "mm@gmail.com"]),
+         emails(cfg.filter_by_tag("emea").collect())
+      );
+      assert_eq!(0, cfg.filter_by_tag("apac").count());
+      let sizes: Vec<usize> = cfg.chunked(2).map(|c| c.len()).collect();
+      assert_eq!(vec![2, 1], sizes);
+      assert_eq!(3, cfg.chunked(0).count());
+   }
+
+   #[test]
+   fn dump_config() {
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.defaults = sm(&[("ORG", "none")]);
+      cfg.recipients = vec![
+         testing::recipient(
+            "jd@example.com",
+            "John Doe",
+            &[("TITLE", "PhD"), ("ORG", "EFF")],
+         ),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[]),
+      ];
+      let expected = "From: fb@example.com
+Subject: hello
+campaign id: test-campaign
+message id domain: example.com
+template engine: rmt
+defaults:
+   ORG = none
+recipients (2):
+   jd@example.com: John Doe
+      ORG = EFF
+      TITLE = PhD
+   mm@gmail.com: Mickey Mouse
+";
+      assert_eq!(expected, cfg.dump());
+      assert_eq!(
+         "from: fb@example.com, subject: hello, recipients: {email: jd@example.com, names: John, Doe, data: ORG => EFF, TITLE => PhD}, {email: mm@gmail.com, names: Mickey, Mouse, data: }",
+         cfg.to_string()
+      );
+      cfg.recipients.clear();
+      assert_eq!(
+         "from: fb@example.com, subject: hello, recipients: ",
+         cfg.to_string()
+      );
+   }
+
+   #[test]
+   fn check_email_happy_case() {
+      assert!(check_email("abx@yajo.co.uk"));
+   }
+
+   #[test]
+   fn check_email_with_plus_char_happy_case() {
+      assert!(check_email("abx+alias@yajo.co.uk"));
+   }
+
+   #[test]
+   fn check_email_with_leading_trailing_whitespace_happy_case() {
+      assert!(check_email("      abx+alias@yajo.co.uk      "));
+   }
+
+   #[test]
+   fn check_email_with_failure() {
+      assert!(!check_email("@yajo.co.uk"));
+   }
+
+   #[test]
+   fn check_email_with_whitespace_failure() {
+      assert!(!check_email("    @yajo.co.uk"));
+      assert!(!check_email("hello@   .uk  "));
+      assert!(!check_email("hello@"));
+      assert!(!check_email("@"));
+      assert!(!check_email("hello@      "));
+   }
+
+   #[test]
+   fn check_email_with_long_form_and_quotes() {
+      assert!(check_email(r#""Frodo Baggins" <rts@example.com>"#));
+   }
+
+   #[test]
+   fn check_email_with_long_form_and_no_quotes() {
+      assert!(check_email(r#"Frodo Baggins <rts@example.com>"#));
+   }
+
+   #[test]
+   fn parse_recipients_with_invalid_email() {
+      let file = r#"
+[general]
+From=abc@def.com
+# this is a comment
+cc=weirdo@nsb.gov, cc@example.com
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid email: @example.com (empty local part)",
+      ));
+      assert_eq!(expected, parse_recipients(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_recipients_with_invalid_data() {
+      let file = r#"
+[general]
+From=abc@def.com
+# this is a comment
+cc=weirdo@nsb.gov, cc@example.com
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+a@example.com="#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("invalid data for email: a@example.com"));
+      assert_eq!(expected, parse_recipients(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_recipients_with_invalid_non_name_data_no_value() {
+      let file = r#"
+[general]
+From=abc@def.com
+# this is a comment
+cc=weirdo@nsb.gov, cc@example.com
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+a@example.com=A B C|ORG:-"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid recipient data for a@example.com (empty value for key (ORG))",
+      ));
+      assert_eq!(expected, parse_recipients(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_recipients_with_invalid_non_name_data_no_key() {
+      let file = r#"
+[general]
+From=abc@def.com
+# this is a comment
+cc=weirdo@nsb.gov, cc@example.com
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+a@example.com=A B C|:-Disney"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid recipient data for a@example.com (no key for datum (Disney))",
+      ));
+      assert_eq!(expected, parse_recipients(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_recipient_data_happy_case() {
+      let expected = sm(&[
+         ("ORG", "EFF"),
+         ("TITLE", "PhD"),
+         ("cc", "bl@kf.io,info@ex.org"),
+      ]);
+      let mut args: Vec<&str> =
+         "jd@example.com=John Doe Jr.|ORG:-EFF|   TITLE:-PhD|cc:-       bl@kf.io,info@ex.org"
+            .split("|")
+            .collect();
+
+      args.remove(0);
+      assert_eq!(Ok(expected), parse_recipient_data(&args));
+   }
+
+   #[test]
+   fn parse_recipient_data_happy_case_override_everything() {
+      let expected = sm(&[
+         ("From", r#""Carl Gauss" <cgauss@math.org>"#),
+         ("Subject", "Do you like math?"),
+         ("Reply-To", r#""Leonhard Euler" <leuler@math.org>"#),
+         ("cc", "briemann@math.org,gcantor@math.org"),
+      ]);
+      let mut args: Vec<&str> =
+         r#"likes2@override.everything=Big Overrider|From:-"Carl Gauss" <cgauss@math.org>|Subject:-Do you like math?|Reply-To:-"Leonhard Euler" <leuler@math.org>|Cc:-briemann@math.org,gcantor@math.org"#
+            .split("|")
+            .collect();
+
+      args.remove(0);
+      assert_eq!(Ok(expected), parse_recipient_data(&args));
+   }
+
+   #[test]
+   fn load_example_config() {
+      let path = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/config.ini");
+      let cfg = instantiate(&[path], None, "rmt", "0.1.2").expect("Failed to load config");
+      let rcp = cfg
+         .recipients
+         .iter()
+         .find(|r| r.email == "likes2@override.everything")
+         .expect("no recipient");
+      let cc: Vec<String> = rcp
+         .effective_cc(&cfg)
+         .iter()
+         .map(|a| a.to_string())
+         .collect();
+      assert_eq!(vec!["briemann@math.org", "gcantor@math.org"], cc);
+   }
+
+   #[test]
+   fn parse_recipient_data_happy_case_with_empty_key_and_value() {
+      let expected = sm(&[("ORG", "EFF"), ("cc", "bl@kf.io,info@ex.org")]);
+      let mut args: Vec<&str> =
+         "jd@example.com=John Doe Jr.|ORG:-EFF|:-|cc:-       bl@kf.io,info@ex.org"
+            .split("|")
+            .collect();
+
+      args.remove(0);
+      assert_eq!(Ok(expected), parse_recipient_data(&args));
+   }
+
+   #[test]
+   fn parse_recipient_data_failure_case_with_empty_key() {
+      let mut args: Vec<&str> =
+         "jd@example.com=John Doe Jr.|:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"
+            .split("|")
+            .collect();
+
+      args.remove(0);
+      assert_eq!(
+         Err(String::from("no key for datum (EFF)")),
+         parse_recipient_data(&args)
+      );
+   }
+
+   #[test]
+   fn parse_recipient_data_failure_case_with_empty_value() {
+      let mut args: Vec<&str> =
+         "jd@example.com=John Doe Jr.|ORG:-   |TITLE:-PhD|cc:-bl@kf.io,info@ex.org"
+            .split("|")
+            .collect();
+
+      args.remove(0);
+      assert_eq!(
+         Err(String::from("empty value for key (ORG)")),
+         parse_recipient_data(&args)
+      );
+   }
+
+   #[test]
+   fn parse_recipient_data_happy_case_with_empty_rdata() {
+      let expected = sm(&[("TITLE", "PhD")]);
+      let mut args: Vec<&str> = "jd@example.com=John Doe Jr.||TITLE:-PhD|"
+         .split("|")
+         .collect();
+
+      args.remove(0);
+      assert_eq!(Ok(expected), parse_recipient_data(&args));
+   }
+
+   #[test]
+   fn parse_recipient_data_failure_case_with_invalid_format() {
+      let mut args: Vec<&str> = "jd@example.com=John Doe Jr.|R:-E:-K:-T|cc:-bl@kf.io,info@ex.org"
+         .split("|")
+         .collect();
+
+      args.remove(0);
+      assert_eq!(
+         Err(String::from("invalid recipient data (R:-E:-K:-T)")),
+         parse_recipient_data(&args)
+      );
+   }
+
+   #[test]
+   fn parse_recipient_with_quoted_values() {
+      let rcp = parse_recipient(
+         "jd@example.com",
+         r#"John Doe|TITLE:-"CEO | Founder"|URL:- "https://example.com/?a:-b"|Q:-"say ""hi"""|From:-"Carl Gauss" <cg@math.org>"#,
+      )
+      .expect("cannot parse recipient");
+      assert_eq!(
+         sm(&[
+            ("TITLE", "CEO | Founder"),
+            ("URL", "https://example.com/?a:-b"),
+            ("Q", r#"say "hi""#),
+            ("From", r#""Carl Gauss" <cg@math.org>"#),
+         ]),
+         rcp.data
+      );
+      assert_eq!(
+         Err(String::from(
+            "invalid recipient data for jd@example.com (invalid recipient data (URL:-a:-b))"
+         )),
+         parse_recipient("jd@example.com", "John Doe|URL:-a:-b")
+      );
+      // the entries written for recipients round-trip
+      let entry = recipient_entry(&rcp);
+      let (email, val) = entry.split_once('=').unwrap();
+      assert_eq!(Ok(rcp), parse_recipient(email, val));
+   }
+
+   #[test]
+   fn parse_recipients_with_trailing_comments() {
+      let file = r#"
+[recipients]
+mm@gmail.com=Mickey Mouse|ORG:-Disney   # trailing comment!!
+jd@example.com=John Doe|ORG:-C\# Devs|URL:-https://example.com/\#top ; another comment
+"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let mut recipients =
+         parse_recipients(&cfg, &mut At::default()).expect("Failed to parse recipients");
+      assert_eq!(sm(&[("ORG", "Disney")]), recipients[1].data);
+      assert_eq!(
+         sm(&[("ORG", "C# Devs"), ("URL", "https://example.com/#top")]),
+         recipients[0].data
+      );
+      // the entries written for recipients are escaped accordingly
+      let file = format!("[recipients]\n{}", recipient_entry(&recipients[0]));
+      let cfg = prep_config(&file).expect("Failed to set up config");
+      assert_eq!(
+         Ok(vec![recipients.remove(0)]),
+         parse_recipients(&cfg, &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_general_with_invalid_from_email() {
+      let file = r#"
+[general]
+From=abc@defcom
+# this is a comment
+cc=weirdo@nsb.gov, cc@example.com
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid *From* email: abc@defcom (the domain has no top-level domain)",
+      ));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_general_with_invalid_reply_to_email() {
+      let file = r#"
+[general]
+From=abc@def.com
+Reply-To=no@one
+# this is a comment
+cc=weirdo@nsb.gov, cc@example.com
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid *Reply-To* email(s): no@one (the domain has no top-level domain)",
+      ));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_general_with_invalid_cc_email() {
+      let file = r#"
+[general]
+From=abc@def.com
+Reply-To=no@one.org
+# this is a comment
+cc=dd@examplecom, weirdo@nsb.gov, oh!no!
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid *cc* email(s): dd@examplecom (the domain has no top-level domain), \
+          oh!no! (no @ in the address)",
+      ));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_general_with_commas_in_display_names() {
+      let file = r#"
+[general]
+From=Frodo Baggins <fb@example.com>
+Subject=hello
+cc=mm@example.com, "Doe, John" <jd@example.com>
+[recipients]
+dd@example.com=Daisy Duck"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(
+         Some(String::from("Frodo Baggins")),
+         actual.gdata.from.display_name
+      );
+      assert_eq!(
+         testing::addresses(r#""Doe, John" <jd@example.com>, mm@example.com"#),
+         actual.gdata.cc
+      );
+      assert_eq!("example.com", actual.msgid_domain);
+   }
+
+   #[test]
+   fn effective_cc_and_bcc() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.gdata.cc = testing::addresses("boss@example.com");
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[]);
+      assert_eq!(
+         testing::addresses("boss@example.com"),
+         rcp.effective_cc(&cfg)
+      );
+      assert!(rcp.effective_bcc().is_empty());
+      let rcp = testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[
+            ("cc", "+inc@gg.org, Boss@example.com"),
+            ("bcc", "+archive@example.com"),
+         ],
+      );
+      assert_eq!(
+         testing::addresses("boss@example.com, inc@gg.org"),
+         rcp.effective_cc(&cfg)
+      );
+      assert_eq!(
+         testing::addresses("archive@example.com"),
+         rcp.effective_bcc()
+      );
+      // without the `+` prefix the global cc is replaced
+      let rcp = testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("cc", "bl@kf.io,info@ex.org")],
+      );
+      assert_eq!(
+         testing::addresses("bl@kf.io, info@ex.org"),
+         rcp.effective_cc(&cfg)
+      );
+   }
+
+   #[test]
+   fn parse_with_invalid_cc_data() {
+      let file = r#"
+[general]
+From=rts@example.com
+Subject=hello
+[recipients]
+jd@example.com=John Doe|cc:-bl@kf.io,info@
+mm@example.com=Mickey Mouse|bcc:-+archive@example.com"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid recipient data for jd@example.com (invalid *cc* email(s): info@ (empty domain))"
+         )),
+         parse(&cfg, "rmt", "0.1.0").map(|_| ())
+      );
+      let cfg = parse_lenient(&cfg, "rmt", "0.1.0").expect("Failed to parse config");
+      assert_eq!(1, cfg.recipients.len());
+      assert_eq!(1, cfg.quarantine.len());
+   }
+
+   #[test]
+   fn parse_with_custom_headers() {
+      let file = r#"
+[general]
+From=rts@example.com
+Subject=hello
+[recipients]
+jd@example.com=John Doe|h_X-Account-ID:-12345|h_X-Tier:- gold
+mm@example.com=Mickey Mouse|h_X Account:-1
+dd@example.com=Donald Duck|h_subject:-Spam
+gg@example.com=Goofy|h_X-Note:-a\nBcc: all@example.com"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid recipient data for dd@example.com (reserved header name (subject))"
+         )),
+         parse(&cfg, "rmt", "0.1.0").map(|_| ())
+      );
+      let cfg = parse_lenient(&cfg, "rmt", "0.1.0").expect("Failed to parse config");
+      assert_eq!(1, cfg.recipients.len());
+      assert_eq!(
+         vec![
+            (String::from("X-Account-ID"), String::from("12345")),
+            (String::from("X-Tier"), String::from("gold")),
+         ],
+         cfg.recipients[0].custom_headers()
+      );
+      let mut quarantine = cfg.quarantine.clone();
+      quarantine.sort();
+      assert_eq!(
+         vec![
+            String::from(
+               "invalid recipient data for dd@example.com (reserved header name (subject))"
+            ),
+            String::from(
+               "invalid recipient data for gg@example.com (invalid header value (X-Note))"
+            ),
+            String::from(
+               "invalid recipient data for mm@example.com (invalid header name (X Account))"
+            ),
+         ],
+         quarantine
+      );
+   }
+
+   #[test]
+   fn parse_general_with_empty_cc_email() {
+      let file = r#"
+[general]
+From=abc@def.com
+Reply-To=no@one.org
+# this is a comment
+Cc=
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("no emails for *Cc* header"));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_general_with_invalid_config_datum() {
+      let file = r#"
+[general]
+From=abc@def.com
+Reply-To=no@one.org
+# this is a comment
+blah=invalid
+[recipients]
+# The 'cc' setting below *redefines* the global 'cc' value above
+@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("invalid configuration datum: *blah*"));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_general_with_campaign_id_and_message_id_domain() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+campaign_id=spring-2019_news
+message_id_domain=mail.example.com
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!("spring-2019_news", actual.campaign_id);
+      assert_eq!("mail.example.com", actual.msgid_domain);
+   }
+
+   #[test]
+   fn parse_general_with_default_campaign_id_and_message_id_domain() {
+      let file = r#"
+[general]
+From="Frodo Baggins" <rts@Example.com>
+Subject=hello
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      let again =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(16, actual.campaign_id.len());
+      assert_eq!(again.campaign_id, actual.campaign_id);
+      assert_eq!("example.com", actual.msgid_domain);
+   }
+
+   #[test]
+   fn parse_general_with_invalid_campaign_id() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+campaign_id=spring <2019>
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("invalid *campaign_id*: spring <2019>"));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_general_with_invalid_message_id_domain() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+message_id_domain=localhost
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("invalid *message_id_domain*: localhost"));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
+
+   #[test]
+   fn email_domain_with_short_and_long_form() {
+      assert_eq!(Some(String::from("ex.org")), email_domain("jd@ex.org"));
+      assert_eq!(
+         Some(String::from("example.com")),
+         email_domain(r#""Frodo Baggins" <rts@EXAMPLE.com>"#)
+      );
+      assert_eq!(None, email_domain("no domain"));
+      assert_eq!(None, email_domain("jd@"));
+   }
+
+   #[test]
+   fn parse_signatures_happy_case() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[signatures]
+anna=Anna Smith\nAccount manager
+anna.html=<b>Anna Smith</b>
+bob=Bob
+[recipients]
+a@b.com=A B|signature:-anna"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual = parse(&cfg, "rmt", "0.1.2").expect("Failed to parse config");
+      assert_eq!(2, actual.signatures.len());
+      assert_eq!(
+         Some(&Signature {
+            text: String::from("Anna Smith\nAccount manager"),
+            html: Some(String::from("<b>Anna Smith</b>")),
+         }),
+         actual.signatures.get("anna")
+      );
+      assert_eq!(
+         Some(&Signature {
+            text: String::from("Bob"),
+            html: None,
+         }),
+         actual.signatures.get("bob")
+      );
+   }
+
+   #[test]
+   fn parse_signatures_with_html_variant_only() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[signatures]
+anna.html=<b>Anna Smith</b>
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("no text variant for signature: anna"));
+      assert_eq!(expected, parse_signatures(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_recipients_with_time_zones() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[recipients]
+a@b.com=A B|tz:-Mars/Olympus_Mons"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid recipient data for a@b.com (unknown time zone (Mars/Olympus_Mons))"
+         )),
+         parse(&cfg, "rmt", "0.1.2").map(|_| ())
+      );
+      let actual = parse_lenient(&cfg, "rmt", "0.1.2").expect("Failed to parse config");
+      assert_eq!(1, actual.quarantine.len());
+   }
+
+   #[cfg(feature = "serde")]
+   #[test]
+   fn serialize_config() {
+      let file = r#"
+[general]
+From="Frodo Baggins" <rts@example.com>
+Subject=hello %FN%
+locale=de_CH
+[event]
+summary=Webinar
+start=2024-05-01T15:00:00+02:00
+end=2024-05-01T16:00:00+02:00
+[smtp]
+host=smtp.example.com
+username=frodo
+password=secret
+[recipients]
+jd@example.com=John Doe|ORG:-Acme|tz:-Europe/Vienna"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = parse(&cfg, "rmt", "0.1.2").expect("Failed to parse config");
+      let json = serde_json::to_string(&expected).expect("Failed to serialize config");
+      assert!(json.contains(r#""locale":"de-ch""#));
+      assert!(!json.contains("secret"));
+      let actual: Config = serde_json::from_str(&json).expect("Failed to deserialize config");
+      assert_eq!(expected, actual);
+      assert_eq!(expected.locale, actual.locale);
+      assert_eq!(expected.event, actual.event);
+      assert_eq!(None, actual.smtp.as_ref().and_then(|s| s.password.clone()));
+   }
+
+   #[test]
+   fn build_config() {
+      let jd = Recipient::builder()
+         .email("jd@example.com")
+         .names("John  Doe")
+         .datum("ORG", "EFF")
+         .build()
+         .expect("Failed to build recipient");
+      assert_eq!(
+         testing::recipient("jd@example.com", "John Doe", &[("ORG", "EFF")]),
+         jd
+      );
+      let cfg = Config::builder()
+         .from(r#""Frodo Baggins" <fb@Example.com>"#)
+         .subject("hello %_FN%")
+         .cc("cc@example.com")
+         .default_value("ORG", "none")
+         .recipient(jd)
+         .build()
+         .expect("Failed to build config");
+      assert_eq!("example.com", cfg.msgid_domain);
+      assert_eq!(16, cfg.campaign_id.len());
+      assert_eq!(testing::addresses("cc@example.com"), cfg.gdata.cc);
+      assert_eq!(Some(&String::from("none")), cfg.defaults.get("ORG"));
+      assert_eq!(1, cfg.recipient_count());
+   }
+
+   #[test]
+   fn build_invalid_config() {
+      assert_eq!(
+         Err(String::from("No *Subject* in the general section")),
+         Config::builder().from("fb@example.com").build()
+      );
+      assert_eq!(
+         Err(String::from("invalid *campaign_id*: a b")),
+         Config::builder()
+            .from("fb@example.com")
+            .subject("hello")
+            .campaign_id("a b")
+            .build()
+      );
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[("tz", "Mars/Base")]);
+      assert_eq!(
+         Err(String::from(
+            "invalid recipient data for jd@example.com (unknown time zone (Mars/Base))"
+         )),
+         Config::builder()
+            .from("fb@example.com")
+            .subject("hello")
+            .recipient(rcp)
+            .build()
+      );
+      assert_eq!(
+         Err(String::from("invalid data for email: jd@example.com")),
+         Recipient::builder().email("jd@example.com").build()
+      );
+      assert!(Recipient::builder()
+         .email("not an email")
+         .names("John")
+         .build()
+         .is_err());
+   }
+
+   #[test]
+   fn accessors() {
+      let file = r#"
+[general]
+From="Frodo Baggins" <rts@example.com>
+Subject=hello %FN%
+[recipients]
+jd@example.com=John Doe
+mm@gmail.com=Mary Miller
+invalid=Nobody"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual = parse_lenient(&cfg, "rmt", "0.1.2").expect("Failed to parse config");
+      assert_eq!("Frodo Baggins <rts@example.com>", actual.from().to_string());
+      assert_eq!("hello %FN%", actual.subject());
+      assert_eq!(2, actual.recipient_count());
+   }
+
+   #[test]
+   fn parse_signatures_with_invalid_name() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[signatures]
+anna smith=Anna Smith
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("invalid signature name: anna smith"));
+      assert_eq!(expected, parse_signatures(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_with_unknown_signature() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[signatures]
+anna=Anna Smith
+[recipients]
+a@b.com=A B|signature:-bob"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid recipient data for a@b.com (unknown signature (bob))",
+      ));
+      assert_eq!(expected, parse(&cfg, "rmt", "0.1.2"));
+   }
+
+   #[test]
+   fn parse_tracking_happy_case() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[tracking]
+utm_source=newsletter
+utm_content=%ORG%
+redirect_url=https://t.example.com/click
+secret=0123456789abcdef
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Tracking {
+         utm: vec![
+            (String::from("utm_source"), String::from("newsletter")),
+            (String::from("utm_content"), String::from("%ORG%")),
+         ],
+         redirect_url: Some(String::from("https://t.example.com/click")),
+         secret: Some(String::from("0123456789abcdef")),
+      };
+      assert_eq!(Ok(expected), parse_tracking(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_tracking_with_invalid_datum() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[tracking]
+utm_foo=bar
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid *tracking* configuration datum: *utm_foo*",
+      ));
+      assert_eq!(expected, parse_tracking(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_tracking_with_invalid_redirect_url() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[tracking]
+redirect_url=ftp://t.example.com
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("invalid *redirect_url*: ftp://t.example.com"));
+      assert_eq!(expected, parse_tracking(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_tracking_without_secret() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+tracking_pixel_url=https://t.example.com/o.gif
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "*tracking_pixel_url* needs a *secret* in the tracking section"
+         )),
+         parse(&cfg, "rmt", "0.1.2").map(|_| ())
+      );
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[tracking]
+redirect_url=https://t.example.com/click
+secret=too short
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "the tracking *secret* must be at least 16 characters long"
+         )),
+         parse_tracking(&cfg, &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_lenient_quarantines_invalid_recipients() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+[signatures]
+anna=Anna Smith
+[recipients]
+a@b.com=A B|signature:-anna
+bad-email=C D
+c@d.com=C D|ORG
+e@f.com=E F|signature:-bob
+g@h.com=G H"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid email: bad-email (no @ in the address)"
+         )),
+         parse(&cfg, "rmt", "0.1.2").map(|_| ())
+      );
+      let actual = parse_lenient(&cfg, "rmt", "0.1.2").expect("Failed to parse config");
+      let emails: Vec<&str> = actual.recipients.iter().map(|r| r.email.as_ref()).collect();
+      assert_eq!(vec!["a@b.com", "g@h.com"], emails);
+      assert_eq!(
+         sa(&[
+            "invalid email: bad-email (no @ in the address)",
+            "invalid recipient data for c@d.com (invalid recipient data (ORG))",
+            "invalid recipient data for e@f.com (unknown signature (bob))",
+         ]),
+         actual.quarantine
+      );
+   }
+
+   #[test]
+   fn email_matches_patterns() {
+      assert!(email_matches("jd@example.com", "JD@Example.com"));
+      assert!(!email_matches("jd@example.com", "jd@example.org"));
+      assert!(email_matches("*@example.com", "jd@example.com"));
+      assert!(!email_matches("*@example.com", "jd@mail.example.com"));
+      assert!(email_matches("*example.com", "jd@mail.example.com"));
+      assert!(email_matches("?d@*", "jd@gmail.com"));
+      assert!(!email_matches("?d@*", "jjd@gmail.com"));
+      assert!(!email_matches("jd@example.com", "jd@examplexcom"));
+   }
+
+   #[test]
+   fn filter_recipients_with_only_and_skip() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[]),
+         testing::recipient("daisy@example.com", "Daisy Lila", &[]),
+      ];
+      cfg.filter_recipients(&["*@example.com", "mm@gmail.com"], &["daisy@*"]);
+      let emails: Vec<&str> = cfg.recipients.iter().map(|r| r.email.as_ref()).collect();
+      assert_eq!(vec!["jd@example.com", "mm@gmail.com"], emails);
+      cfg.filter_recipients(&[], &["*@gmail.com"]);
+      assert_eq!(1, cfg.recipients.len());
+   }
+
+   #[test]
+   fn segment_recipients_by_tags() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[("tags", "vip, emea")]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[("tags", "EMEA")]),
+         testing::recipient("daisy@example.com", "Daisy Lila", &[]),
+      ];
+      assert_eq!(sa(&["vip", "emea"]), cfg.recipients[0].tags());
+      assert!(cfg.recipients[2].tags().is_empty());
+      cfg.segment_recipients(&Segment::parse("emea AND NOT vip").unwrap());
+      let emails: Vec<&str> = cfg.recipients.iter().map(|r| r.email.as_ref()).collect();
+      assert_eq!(vec!["mm@gmail.com"], emails);
+      assert_eq!(
+         Err(String::from(
+            "invalid recipient data for jd@example.com (invalid tag (big spender))"
+         )),
+         check_tags(&testing::recipient(
+            "jd@example.com",
+            "John Doe",
+            &[("tags", "vip,big spender")]
+         ))
+      );
+   }
+
+   #[test]
+   fn variant_assignment_is_weighted_and_stable() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      assert_eq!(None, cfg.variant("jd@example.com"));
+      for (name, weight) in [("a", 1), ("b", 3)].iter() {
+         cfg.variants.push(Variant {
+            name: name.to_string(),
+            weight: *weight,
+            template: Arc::new(template::new(name)),
+            html: None,
+         });
+      }
+      let b = (0..1000)
+         .filter(|i| cfg.variant(&format!("user{}@example.com", i)).unwrap().name == "b")
+         .count();
+      assert!(b > 700 && b < 800, "{} of 1000 recipients got variant b", b);
+      assert_eq!(
+         cfg.variant("jd@example.com"),
+         cfg.variant(" JD@example.com")
+      );
+   }
+
+   #[test]
+   fn parse_variants_section() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      for name in ["a.eml", "b.eml", "b.html"].iter() {
+         std::fs::write(dir.path().join(name), format!("template {}", name)).unwrap();
+      }
+      let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
+      let file = format!(
+         "[variants]\nb={}\nb.html={}\nb.weight=3\na={}\n",
+         path("b.eml"),
+         path("b.html"),
+         path("a.eml")
+      );
+      let cfg = prep_config(&file).expect("Failed to set up config");
+      let variants =
+         parse_variants(&cfg, Engine::Rmt, &mut At::default()).expect("Failed to parse variants");
+      assert_eq!(2, variants.len());
+      assert_eq!(("a", 1), (variants[0].name.as_ref(), variants[0].weight));
+      assert_eq!(("b", 3), (variants[1].name.as_ref(), variants[1].weight));
+      assert_eq!("template b.html", variants[1].html.as_ref().unwrap().text());
+      assert!(variants[0].html.is_none());
+      let errors = [
+         ("a.html=x.html", "no template for variant a"),
+         ("a=a.eml\na.weight=0", "invalid weight of variant a: 0"),
+         (
+            "a.size=1",
+            "invalid *variants* configuration datum: *a.size*",
+         ),
+      ];
+      for (section, error) in errors.iter() {
+         let cfg =
+            prep_config(&format!("[variants]\n{}", section)).expect("Failed to set up config");
+         assert_eq!(
+            Some(error.to_string()),
+            parse_variants(&cfg, Engine::Rmt, &mut At::default()).err()
+         );
+      }
+      let cfg =
+         prep_config("[variants]\na=/no/such/template.eml").expect("Failed to set up config");
+      assert!(parse_variants(&cfg, Engine::Rmt, &mut At::default())
+         .err()
+         .unwrap()
+         .starts_with("cannot read template /no/such/template.eml"));
+   }
+
+   #[test]
+   fn sample_recipients_is_reproducible() {
+      let sample = |n, seed| {
+         let mut cfg = testing::config("rts@example.com", "hello");
+         cfg.recipients = (0..20)
+            .map(|i| testing::recipient(&format!("r{:02}@example.com", i), "R", &[]))
+            .collect();
+         cfg.sample_recipients(n, seed);
+         cfg.recipients
+            .into_iter()
+            .map(|r| r.email)
+            .collect::<Vec<String>>()
+      };
+      let first = sample(5, 42);
+      assert_eq!(5, first.len());
+      assert_eq!(first, sample(5, 42));
+      assert_ne!(first, sample(5, 43));
+      // the original order is kept
+      let mut sorted = first.clone();
+      sorted.sort();
+      assert_eq!(sorted, first);
+      assert_eq!(20, sample(30, 1).len());
+   }
+
+   #[test]
+   fn gen_campaign_parses() {
+      let smtp = Smtp {
+         host: String::from("smtp.example.com"),
+         port: 587,
+         security: Security::StartTls,
+         username: Some(String::from("frodo")),
+         password: Some(String::from("secret")),
+         keyring: None,
+         rate: None,
+         domain_rates: HashMap::new(),
+         jitter: None,
+         group_by_domain: false,
+         domain_burst: None,
+         connections: 1,
+         dsn_notify: None,
+         dsn_ret: None,
+         smtputf8: false,
+      };
+      let recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("ORG", "EFF"), ("TITLE", "PhD")],
+      )];
+      let content = gen_campaign(
+         "rmt",
+         "0.1.2",
+         "Frodo Baggins <fb@example.com>",
+         "Hello %FN%!",
+         Some(&smtp),
+         &recipients,
+      );
+      assert!(content.contains("jd@example.com=John Doe|ORG:-EFF|TITLE:-PhD\n"));
+      assert!(!content.contains("secret"));
+      let ini = prep_config(&content).expect("Failed to set up config");
+      let cfg = parse(&ini, "rmt", "0.1.2").expect("the generated config is invalid");
+      assert_eq!(recipients, cfg.recipients);
+      assert_eq!("Hello %FN%!", cfg.gdata.subject);
+      let parsed = cfg.smtp.expect("no smtp section");
+      assert_eq!(Security::StartTls, parsed.security);
+      assert!(content.contains("\n#username=frodo\n#password=\n"));
+      assert_eq!(None, parsed.password);
+      let placeholder = gen_campaign("rmt", "0.1.2", "fb@example.com", "Hi", None, &[]);
+      let ini = prep_config(&placeholder).expect("Failed to set up config");
+      assert_eq!(1, parse(&ini, "rmt", "0.1.2").unwrap().recipients.len());
+   }
+
+   #[test]
+   fn sample_files() {
+      let text = gen_config("rmt", "0.1.2");
+      let ini = prep_config(&text).expect("Failed to set up config");
+      let cfg = parse(&ini, "rmt", "0.1.2").expect("the sample config is invalid");
+      let tmpl = template::new(&gen_template("rmt", "0.1.2"));
+      assert_eq!(Ok(()), tmpl.check_recipents(&cfg.recipients, &cfg.defaults));
+      let html = template::new(&gen_html_template("rmt", "0.1.2"));
+      assert_eq!(Ok(()), html.check_recipents(&cfg.recipients, &cfg.defaults));
+
+      let settings = |ini: &Ini| -> Vec<(Option<String>, String, String)> {
+         let mut result: Vec<_> = ini
+            .iter()
+            .flat_map(|(s, p)| {
+               p.iter()
+                  .map(move |(k, v)| (s.clone(), k.clone(), v.clone()))
+            })
+            .collect();
+         result.sort();
+         result
+      };
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      for (format, name) in [(FileFormat::Toml, "c.toml"), (FileFormat::Yaml, "c.yaml")] {
+         let path = dir.path().join(name);
+         std::fs::write(&path, convert(&text, format).unwrap()).unwrap();
+         let converted = load(&path).expect("cannot load the converted config");
+         assert_eq!(settings(&ini), settings(&converted), "{:?}", format);
+      }
+      let toml = convert(&text, FileFormat::Toml).unwrap();
+      assert!(toml.contains("\nFrom = \"Frodo Baggins <rts@example.com>\"\n"));
+      assert!(toml.contains("\n#cc = \"weirdo@nsb.gov, cc@example.com\"\n"));
+      assert!(toml.contains("\n\"mm@gmail.com\" = \"Mickey Mouse|ORG:-Disney\"\n"));
+      let yaml = convert(&text, FileFormat::Yaml).unwrap();
+      assert!(yaml.contains("\ngeneral:\n  From: "));
+      assert_eq!(FileFormat::Yaml, FileFormat::of(Path::new("spring.yml")));
+      assert_eq!(FileFormat::Ini, FileFormat::of(Path::new("spring.cfg")));
+   }
+
+   #[test]
+   fn dedup_recipients_policies() {
+      let recipients = || {
+         vec![
+            testing::recipient("jd@example.com", "John Doe", &[("ORG", "EFF")]),
+            testing::recipient("mm@gmail.com", "Mickey Mouse", &[]),
+            testing::recipient(
+               "JD@example.com",
+               "Johnny",
+               &[("TITLE", "PhD"), ("ORG", "FSF")],
+            ),
+         ]
+      };
+      let (kept, duplicates) = dedup_recipients(recipients(), OnDuplicate::Error);
+      assert_eq!(2, kept.len());
+      assert_eq!(
+         vec![String::from(
+            "duplicate recipient: JD@example.com (see *on_duplicate*)"
+         )],
+         duplicates
+      );
+      let (kept, duplicates) = dedup_recipients(recipients(), OnDuplicate::KeepFirst);
+      assert!(duplicates.is_empty());
+      let mut expected = recipients();
+      expected.truncate(2);
+      assert_eq!(expected, kept);
+      let (kept, _) = dedup_recipients(recipients(), OnDuplicate::KeepLast);
+      let mut expected = recipients();
+      expected.swap(0, 2);
+      expected.truncate(2);
+      assert_eq!(expected, kept);
+      let (kept, _) = dedup_recipients(recipients(), OnDuplicate::Merge);
+      assert_eq!("jd@example.com", kept[0].email);
+      assert_eq!(sa(&["John", "Doe"]), kept[0].names);
+      assert_eq!(sm(&[("ORG", "FSF"), ("TITLE", "PhD")]), kept[0].data);
+   }
+
+   #[test]
+   fn parse_with_recipients_csv() {
+      let mut csv = NamedTempFile::new().expect("cannot create temp file");
+      csv.write_all(
+         b"email,names,ORG\nmm@gmail.com,Mickey Mouse,Disney\njd@example.com,John Doe,FSF\n",
+      )
+      .unwrap();
+      let file = |extra: &str| {
+         format!(
+            "[general]\nFrom=abc@def.com\nSubject=Hi\nrecipients_csv={}\n{}[recipients]\n\
+             jd@example.com=John Doe|ORG:-EFF\n",
+            csv.path().display(),
+            extra
+         )
+      };
+      let ini = prep_config(&file("")).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "duplicate recipient: jd@example.com (see *on_duplicate*)"
+         )),
+         parse(&ini, "rmt", "0.1.2").map(|c| c.recipients.len())
+      );
+      let lenient = parse_lenient(&ini, "rmt", "0.1.2").expect("lenient parse failed");
+      assert_eq!(2, lenient.recipients.len());
+      assert_eq!(1, lenient.quarantine.len());
+      let ini = prep_config(&file("on_duplicate=keep-last\n")).expect("Failed to set up config");
+      let cfg = parse(&ini, "rmt", "0.1.2").expect("parse failed");
+      assert_eq!(
+         vec!["jd@example.com", "mm@gmail.com"],
+         cfg.recipients
+            .iter()
+            .map(|r| r.email.as_ref())
+            .collect::<Vec<&str>>()
+      );
+      assert_eq!("FSF", cfg.recipients[0].data["ORG"]);
+   }
+
+   #[test]
+   fn parse_general_with_threading_settings() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+in_reply_to=<abc.123@example.com>
+references=<abc.122@example.com>   <abc.123@example.com>
+follow_up_of=winter-news
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      let expected = Threading {
+         in_reply_to: Some(String::from("<abc.123@example.com>")),
+         references: sa(&["<abc.122@example.com>", "<abc.123@example.com>"]),
+         follow_up_of: Some(String::from("winter-news")),
+         message_ids: HashMap::new(),
+      };
+      assert_eq!(expected, actual.threading);
+   }
 
-   for s in sections {
-      match cfg.section(Some(s.to_string())) {
-         Some(props) => {
-            if s == "general" {
-               if !props.contains_key("From") && !props.contains_key("from") {
-                  return Err(String::from("No *From* header in the general section"));
-               }
-               if !props.contains_key("Subject") && !props.contains_key("subject") {
-                  return Err(String::from("No *Subject* in the general section"));
-               }
-            }
-            if s == "recipients" {
-               num_recipients = props.len();
-               if num_recipients == 0 {
-                  return Err(String::from("No email recipients found in config file"));
-               }
-            }
-         }
-         None => {
-            return Err(format!("No *{}* section in config file", s));
-         }
-      }
+   #[test]
+   fn parse_general_with_invalid_in_reply_to() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+in_reply_to=abc.123@example.com
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid *in_reply_to* message id: abc.123@example.com",
+      ));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
    }
-   Ok(num_recipients)
-}
 
-/// Generates a configuration for a mailing campaign for a user to tweak as needed.
-pub fn gen_config(name: &str, version: &str) -> String {
-   format!(
-      r#"# {} version {}
-#
-# anything that follows a hash is a comment
-# email address is to the left of the '=' sign, first word after is
-# the first name, the rest is the surname
+   #[test]
+   fn parse_general_with_invalid_references() {
+      let file = r#"
 [general]
-From="Frodo Baggins" <rts@example.com>
-#cc=weirdo@nsb.gov, cc@example.com
-#Reply-To="John Doe" <jd@mail.com>
-subject=Hello %FN%!
-#attachments=/home/user/atmt1.ics, ../Documents/doc2.txt
+From=abc@def.com
+Subject=hello
+references=<abc.122@example.com> <nope> <a@b@c>
 [recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-jd@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org
-mm@gmail.com=Mickey Mouse|ORG:-Disney   # trailing comment!!
-# The 'cc' setting below *adds* to the global 'cc' value above
-daisy@example.com=Daisy Lila|ORG:-NASA|TITLE:-Dr.|cc:-+inc@gg.org"#,
-      name, version
-   )
-}
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid *references* message id(s): <nope>, <a@b@c>",
+      ));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
+   }
 
-/// Generates a template for a mailing campaign for a user to tweak as needed.
-pub fn gen_template(name: &str, version: &str) -> String {
-   format!(
-      r#"FN / LN / EA = first name / last name / email address
+   #[test]
+   fn parse_general_with_transfer_encoding() {
+      let file = |encoding: &str| {
+         format!(
+            "[general]\nFrom=rts@example.com\nSubject=hi\ntransfer_encoding={}\n[recipients]\njd@example.com=John Doe",
+            encoding
+         )
+      };
+      let cfg = prep_config(&file("Base64")).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(TransferEncoding::Base64, actual.transfer_encoding);
+      let cfg = prep_config(&file("7bit")).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("invalid *transfer_encoding*: 7bit")),
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).map(|_| ())
+      );
+   }
 
-Hello %FN% // %LN%, how are things going at %ORG%?
-this is your email: %EA% :)
+   #[test]
+   fn parse_sizes() {
+      assert_eq!(Ok(10 * 1024 * 1024), parse_size("10M"));
+      assert_eq!(Ok(512 * 1024), parse_size("512kb"));
+      assert_eq!(Ok(1000), parse_size(" 1000 "));
+      assert!(parse_size("ten MB").is_err());
+      assert!(parse_size("-1K").is_err());
+      assert_eq!("1.5 MB", format_size(1536 * 1024));
+      assert_eq!("200 bytes", format_size(200));
+   }
 
+   #[test]
+   fn parse_general_with_signature_file() {
+      let mut file = NamedTempFile::new().expect("cannot create file");
+      std::io::Write::write_all(&mut file, b"Anna Smith\n%ORG%\n\n").unwrap();
+      let path = file.path().to_str().unwrap();
+      let ini = format!(
+         "[general]\nFrom=rts@example.com\nSubject=hi\nsignature_file={}\n[recipients]\njd@example.com=John Doe",
+         path
+      );
+      let cfg = prep_config(&ini).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(
+         Some(Signature {
+            text: String::from("Anna Smith\n%ORG%"),
+            html: None,
+         }),
+         actual.signature
+      );
+      let ini = "[general]\nFrom=rts@example.com\nSubject=hi\nsignature_file=/no/such/sig.txt\n[recipients]\njd@example.com=John Doe";
+      let cfg = prep_config(ini).expect("Failed to set up config");
+      assert!(parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+         .unwrap_err()
+         .starts_with("cannot read *signature_file* /no/such/sig.txt"));
+   }
 
-Sent with {} version {}, see https://301.mx/{} for details"#,
-      name, version, name
-   )
-}
+   #[test]
+   fn parse_general_with_attachments() {
+      let mut file = tempfile::Builder::new()
+         .suffix(".pdf")
+         .tempfile()
+         .expect("cannot create file");
+      std::io::Write::write_all(&mut file, b"%PDF-1.4\n").unwrap();
+      let path = file.path().to_str().unwrap();
+      let ini = format!(
+         "[general]\nFrom=rts@example.com\nSubject=hi\nattachments={}\nmax_message_size=2M\non_oversize=warn\n[recipients]\njd@example.com=John Doe",
+         path
+      );
+      let cfg = prep_config(&ini).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(1, actual.attachments.len());
+      assert_eq!("application/pdf", actual.attachments[0].content_type);
+      assert_eq!(2 * 1024 * 1024, actual.max_message_size);
+      assert_eq!(OnOversize::Warn, actual.on_oversize);
+   }
 
-#[cfg(test)]
-mod tests {
-   use super::*;
-   use ini::Ini;
-   use std::io::{Error, Write};
-   use tempfile::NamedTempFile;
+   #[test]
+   fn parse_general_with_invalid_size_settings() {
+      let file = |setting: &str| {
+         format!(
+            "[general]\nFrom=rts@example.com\nSubject=hi\n{}\n[recipients]\njd@example.com=John Doe",
+            setting
+         )
+      };
+      let cfg = prep_config(&file("max_message_size=big")).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("invalid *max_message_size*: big")),
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).map(|_| ())
+      );
+      let cfg = prep_config(&file("on_oversize=ignore")).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("invalid *on_oversize* policy: ignore")),
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).map(|_| ())
+      );
+      let cfg =
+         prep_config(&file("attachments=/no/such/file.pdf")).expect("Failed to set up config");
+      assert!(parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).is_err());
+   }
 
-   fn prep_config(content: &str) -> Result<ini::Ini, Error> {
-      let mut tf = NamedTempFile::new()?;
+   #[test]
+   fn parse_general_with_inline_css() {
+      let file = "[general]\nFrom=rts@example.com\nSubject=hi\ninline_css=on\n[recipients]\njd@example.com=John Doe";
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert!(actual.inline_css);
+   }
 
-      // Write some test data to the first handle.
-      tf.write_all(content.as_bytes())?;
-      let cfg = Ini::load_from_file(tf.path()).unwrap();
-      Ok(cfg)
+   #[test]
+   fn parse_general_with_greeting() {
+      let file = "[general]\nFrom=rts@example.com\nSubject=hi\ngreeting=formal\n[recipients]\njd@example.com=John Doe";
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(Formality::Formal, actual.greeting);
+      let file = "[general]\nFrom=rts@example.com\nSubject=hi\ngreeting=casual\n[recipients]\njd@example.com=John Doe";
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("invalid *greeting*: casual")),
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).map(|_| ())
+      );
    }
 
    #[test]
-   fn check_with_empty_file() {
-      let cfg = prep_config("").expect("Failed to set up config");;
+   fn parse_general_with_preheader() {
+      let file = "[general]\nFrom=rts@example.com\nSubject=hi\npreheader= Deals for %ORG% \n[recipients]\njd@example.com=John Doe";
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(Some("Deals for %ORG%"), actual.preheader.as_deref());
+      let file = "[general]\nFrom=rts@example.com\nSubject=hi\npreheader=\n[recipients]\njd@example.com=John Doe";
+      let cfg = prep_config(file).expect("Failed to set up config");
       assert_eq!(
-         Err(String::from("No *general* section in config file")),
-         check(&cfg)
+         Err(String::from("empty *preheader*")),
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).map(|_| ())
       );
    }
 
    #[test]
-   fn check_with_no_recipients_section() {
+   fn parse_event_happy_case() {
       let file = r#"
 [general]
-From=abc@def.com
-Subject=hello world!
-# this is a comment"#;
-      let cfg = prep_config(file).expect("Failed to set up config");;
+From=Frodo Baggins <rts@example.com>
+Subject=hello
+[event]
+summary=Webinar for %_FN%
+start=2024-05-01T15:00:00+02:00
+end=2024-05-01T16:30:00+02:00
+location=Room 1
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual = parse(&cfg, "rmt", "0.1.2").expect("Failed to parse config");
+      let event = actual.event.expect("no event");
+      assert_eq!("Webinar for %_FN%", event.summary);
+      assert_eq!("2024-05-01T13:00:00+00:00", event.start.to_rfc3339());
+      assert_eq!(90, (event.end - event.start).num_minutes());
+      assert_eq!(Some(String::from("Room 1")), event.location);
+      assert_eq!(actual.gdata.from, event.organizer);
+   }
+
+   #[test]
+   fn parse_send_at() {
+      let file = |at: &str| {
+         format!(
+            "[general]\nFrom=rts@example.com\nSubject=hi\nsend_at={}\n[recipients]\njd@example.com=John Doe",
+            at
+         )
+      };
+      for at in [
+         "2024-06-01T09:00+02:00",
+         "2024-06-01T07:00:00Z",
+         "2024-06-01T07:00Z",
+      ]
+      .iter()
+      {
+         let cfg = prep_config(&file(at)).expect("Failed to set up config");
+         let cfg = parse(&cfg, "rmt", "0.1.0").expect("Failed to parse config");
+         assert_eq!(
+            Some(String::from("2024-06-01T07:00:00+00:00")),
+            cfg.send_at.map(|t| t.to_rfc3339())
+         );
+      }
+      let cfg = prep_config(&file("tomorrow")).expect("Failed to set up config");
       assert_eq!(
-         Err(String::from("No *recipients* section in config file")),
-         check(&cfg)
+         Err(String::from("invalid *send_at* time: tomorrow")),
+         parse(&cfg, "rmt", "0.1.0").map(|_| ())
       );
    }
 
    #[test]
-   fn check_with_no_recipients() {
+   fn parse_event_with_errors() {
+      let file = |event: &str| {
+         format!(
+            "[general]\nFrom=rts@example.com\nSubject=hi\n[event]\n{}\n[recipients]\njd@example.com=John Doe",
+            event
+         )
+      };
+      let cases = [
+         (
+            "summary=x\nstart=2024-05-01T15:00:00Z",
+            "No *end* in the event section",
+         ),
+         (
+            "summary=x\nstart=May 1st\nend=2024-05-01T15:00:00Z",
+            "invalid *start* in the event section: May 1st",
+         ),
+         (
+            "summary=x\nstart=2024-05-01T15:00:00Z\nend=2024-05-01T14:00:00Z",
+            "the event ends before it starts",
+         ),
+         (
+            "summary=x\nstart=2024-05-01T15:00:00Z\nend=2024-05-01T16:00:00Z\nduration=1h",
+            "invalid *event* configuration datum: *duration*",
+         ),
+      ];
+      for (event, error) in cases.iter() {
+         let cfg = prep_config(&file(event)).expect("Failed to set up config");
+         assert_eq!(
+            Err(error.to_string()),
+            parse(&cfg, "rmt", "0.1.2").map(|_| ())
+         );
+      }
+   }
+
+   #[test]
+   fn parse_general_with_transliterate() {
       let file = r#"
 [general]
 From=abc@def.com
-Subject=hello world!
-# this is a comment
-[recipients]"#;
-      let cfg = prep_config(file).expect("Failed to set up config");;
+Subject=hello
+transliterate=Yes
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert!(actual.transliterate);
+   }
+
+   #[test]
+   fn parse_general_with_invalid_transliterate() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+transliterate=maybe
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid boolean value for *transliterate*: maybe",
+      ));
       assert_eq!(
-         Err(String::from("No email recipients found in config file")),
-         check(&cfg)
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
       );
    }
 
    #[test]
-   fn check_with_no_from_in_empty_general() {
+   fn parse_general_with_tracking_pixel_url() {
       let file = r#"
 [general]
-# this is a comment
-[recipients]"#;
-      let cfg = prep_config(file).expect("Failed to set up config");;
+From=abc@def.com
+Subject=hello
+tracking_pixel_url=https://t.example.com/o.gif?c=1
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
       assert_eq!(
-         Err(String::from("No *From* header in the general section")),
-         check(&cfg)
+         Some(String::from("https://t.example.com/o.gif?c=1")),
+         actual.tracking_pixel_url
       );
    }
 
    #[test]
-   fn check_with_no_from_in_non_empty_general() {
+   fn parse_general_without_tracking_pixel_url() {
       let file = r#"
 [general]
-P1=a
-P2=b
-# this is a comment
-[recipients]"#;
-      let cfg = prep_config(file).expect("Failed to set up config");;
+From=abc@def.com
+Subject=hello
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(None, actual.tracking_pixel_url);
+   }
+
+   #[test]
+   fn parse_general_with_invalid_tracking_pixel_url() {
+      let file = r#"
+[general]
+From=abc@def.com
+Subject=hello
+tracking_pixel_url=ftp://t.example.com/o.gif
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from(
+         "invalid *tracking_pixel_url*: ftp://t.example.com/o.gif",
+      ));
       assert_eq!(
-         Err(String::from("No *From* header in the general section")),
-         check(&cfg)
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
       );
    }
 
    #[test]
-   fn check_with_no_subject() {
+   fn parse_general_with_webhook_url() {
       let file = r#"
 [general]
-From=a
-# this is a comment
-[recipients]"#;
-      let cfg = prep_config(file).expect("Failed to set up config");;
+From=abc@def.com
+Subject=hello
+webhook_url=https://hooks.example.com/rmt
+[recipients]
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
       assert_eq!(
-         Err(String::from("No *Subject* in the general section")),
-         check(&cfg)
+         Some(String::from("https://hooks.example.com/rmt")),
+         actual.webhook_url
       );
    }
 
    #[test]
-   fn check_with_happy_path() {
+   fn parse_general_with_invalid_webhook_url() {
       let file = r#"
 [general]
 From=abc@def.com
-Subject=hello world!
-# this is a comment
+Subject=hello
+webhook_url=hooks.example.com
 [recipients]
-a@b.com=A B
-c@d.com=C D"#;
-      let cfg = prep_config(file).expect("Failed to set up config");;
-      assert_eq!(Ok(2), check(&cfg));
+a@b.com=A B"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Err(String::from("invalid *webhook_url*: hooks.example.com"));
+      assert_eq!(
+         expected,
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+      );
    }
 
    #[test]
-   fn parse_recipients_with_happy_path() {
+   fn parse_general_with_audit_log() {
       let file = r#"
 [general]
 From=abc@def.com
-# this is a comment
-cc=weirdo@nsb.gov, cc@example.com
+Subject=hello
+audit_log= /var/log/rmt/audit.jsonl
 [recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-jd@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org
-mm@gmail.com=Mickey Mouse|ORG:-Disney   # trailing comment!!
-# The 'cc' setting below *adds* to the global 'cc' value above
-daisy@example.com=Daisy Lila|ORG:-NASA|TITLE:-Dr.|cc:-+inc@gg.org"#;
+a@b.com=A B"#;
       let cfg = prep_config(file).expect("Failed to set up config");
-      let mut expected = Vec::new();
-      expected.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
-      });
-      expected.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[
-            ("ORG", "EFF"),
-            ("TITLE", "PhD"),
-            ("cc", "bl@kf.io,info@ex.org"),
-         ]),
-      });
-      expected.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[("ORG", "Disney")]),
-      });
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
       assert_eq!(
-         expected,
-         parse_recipients(&cfg).expect("This should not fail")
+         Some(String::from("/var/log/rmt/audit.jsonl")),
+         actual.audit_log
       );
    }
 
    #[test]
-   fn recipients_to_string() {
-      let r = Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[
-            ("ORG", "EFF"),
-            ("TITLE", "PhD"),
-            ("cc", "bl@kf.io,info@ex.org"),
-         ]),
+   fn parse_general_with_summary_to() {
+      let file = "[general]\nFrom=abc@def.com\nSubject=hello\nsummary_to=ops@def.com";
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(
+         Some(String::from("ops@def.com")),
+         actual.summary_to.map(|a| a.to_string())
+      );
+      let cfg = prep_config("[general]\nFrom=abc@def.com\nsummary_to=ops")
+         .expect("Failed to set up config");
+      assert!(parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+         .err()
+         .unwrap()
+         .starts_with("invalid *summary_to* email: ops"));
+   }
+
+   #[test]
+   fn parse_general_with_disposition_notification_to() {
+      let file = "[general]\nFrom=abc@def.com\nSubject=hello\n\
+                  disposition_notification_to=\"A B\" <receipts@def.com>";
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let actual =
+         parse_general(&cfg, "rmt", "0.1.2", &mut At::default()).expect("Failed to parse config");
+      assert_eq!(
+         Some(String::from("A B <receipts@def.com>")),
+         actual.disposition_notification_to.map(|a| a.to_string())
+      );
+      let cfg = prep_config("[general]\nFrom=abc@def.com\ndisposition_notification_to=nobody")
+         .expect("Failed to set up config");
+      assert!(parse_general(&cfg, "rmt", "0.1.2", &mut At::default())
+         .err()
+         .unwrap()
+         .starts_with("invalid *disposition_notification_to* email: nobody"));
+   }
+
+   #[test]
+   fn parse_smtp_happy_case() {
+      let file = r#"
+[smtp]
+host=smtp.example.com
+security=STARTTLS
+username=frodo
+password=secret
+rate=30/min
+smtputf8=yes"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Smtp {
+         host: String::from("smtp.example.com"),
+         port: 587,
+         security: Security::StartTls,
+         username: Some(String::from("frodo")),
+         password: Some(String::from("secret")),
+         keyring: None,
+         rate: Some(Rate {
+            messages: 30,
+            per: Duration::from_secs(60),
+         }),
+         domain_rates: HashMap::new(),
+         jitter: None,
+         group_by_domain: false,
+         domain_burst: None,
+         connections: 1,
+         dsn_notify: None,
+         dsn_ret: None,
+         smtputf8: true,
       };
-      assert_eq!("email: jd@example.com, names: John, Doe, Jr., data: ORG => EFF, TITLE => PhD, cc => bl@kf.io,info@ex.org", r.to_string());
+      assert_eq!(Ok(Some(expected)), parse_smtp(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_smtp_with_defaults() {
+      let file = r#"
+[smtp]
+host=smtp.example.com"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let expected = Smtp {
+         host: String::from("smtp.example.com"),
+         port: 465,
+         security: Security::Tls,
+         username: None,
+         password: None,
+         keyring: None,
+         rate: None,
+         domain_rates: HashMap::new(),
+         jitter: None,
+         group_by_domain: false,
+         domain_burst: None,
+         connections: 1,
+         dsn_notify: None,
+         dsn_ret: None,
+         smtputf8: false,
+      };
+      assert_eq!(Ok(Some(expected)), parse_smtp(&cfg, &mut At::default()));
    }
 
    #[test]
-   fn check_email_happy_case() {
-      assert_eq!(true, check_email("abx@yajo.co.uk"));
+   fn parse_rate_happy_case() {
+      let rate = parse_rate(" 120 / min").expect("Failed to parse rate");
+      assert_eq!(Duration::from_millis(500), rate.interval());
+      assert_eq!(
+         Ok(Rate {
+            messages: 2,
+            per: Duration::from_secs(3600)
+         }),
+         parse_rate("2/h")
+      );
    }
 
    #[test]
-   fn check_email_with_plus_char_happy_case() {
-      assert_eq!(true, check_email("abx+alias@yajo.co.uk"));
+   fn parse_rate_with_invalid_values() {
+      for rate in ["0/min", "10", "10/day", "x/s", "-1/s"].iter() {
+         assert_eq!(
+            Err(format!("invalid rate limit: {}", rate)),
+            parse_rate(rate)
+         );
+      }
    }
 
    #[test]
-   fn check_email_with_leading_trailing_whitespace_happy_case() {
-      assert_eq!(true, check_email("      abx+alias@yajo.co.uk      "));
+   fn parse_jitter_happy_case() {
+      let jitter = parse_jitter("5-30s").expect("Failed to parse jitter");
+      assert_eq!(Duration::from_secs(5), jitter.min);
+      assert_eq!(Duration::from_secs(30), jitter.max);
+      assert_eq!(Duration::from_millis(17500), jitter.mean());
+      assert_eq!(
+         Ok(Jitter {
+            min: Duration::from_millis(200),
+            max: Duration::from_millis(200)
+         }),
+         parse_jitter(" 200 - 200ms")
+      );
+      assert_eq!(Duration::from_secs(60), parse_jitter("1-2min").unwrap().min);
+      assert_eq!(Duration::from_secs(5), jitter.pick(0));
+      assert_eq!(Duration::from_secs(30), jitter.pick(25_000));
    }
 
    #[test]
-   fn check_email_with_failure() {
-      assert_eq!(false, check_email("@yajo.co.uk"));
+   fn parse_jitter_with_invalid_values() {
+      for jitter in ["30-5s", "5s", "5-30h", "a-b", "-5-30"].iter() {
+         assert_eq!(
+            Err(format!("invalid jitter: {}", jitter)),
+            parse_jitter(jitter)
+         );
+      }
    }
 
    #[test]
-   fn check_email_with_whitespace_failure() {
-      assert_eq!(false, check_email("    @yajo.co.uk"));
-      assert_eq!(false, check_email("hello@   .uk  "));
-      assert_eq!(false, check_email("hello@"));
-      assert_eq!(false, check_email("@"));
-      assert_eq!(false, check_email("hello@      "));
+   fn smtp_interval() {
+      let mut smtp = parse_smtp(
+         &prep_config("[smtp]\nhost=localhost").unwrap(),
+         &mut At::default(),
+      )
+      .unwrap()
+      .unwrap();
+      assert_eq!(None, smtp.interval());
+      smtp.rate = parse_rate("60/min").ok();
+      assert_eq!(Some(Duration::from_secs(1)), smtp.interval());
+      smtp.jitter = parse_jitter("2-4s").ok();
+      assert_eq!(Some(Duration::from_secs(3)), smtp.interval());
    }
 
    #[test]
-   fn check_email_with_long_form_and_quotes() {
-      assert_eq!(true, check_email(r#""Frodo Baggins" <rts@example.com>"#));
+   fn parse_smtp_with_grouping() {
+      let cfg =
+         prep_config("[smtp]\nhost=localhost\ngroup_by_domain=yes\ndomain_burst=20").unwrap();
+      let smtp = parse_smtp(&cfg, &mut At::default()).unwrap().unwrap();
+      assert!(smtp.group_by_domain);
+      assert_eq!(Some(20), smtp.domain_burst);
+      assert_eq!(1, smtp.connections);
+      let cfg = prep_config("[smtp]\nhost=localhost\nconnections=4").unwrap();
+      assert_eq!(
+         4,
+         parse_smtp(&cfg, &mut At::default())
+            .unwrap()
+            .unwrap()
+            .connections
+      );
+      let cfg = prep_config("[smtp]\nhost=localhost\nconnections=33").unwrap();
+      assert_eq!(
+         Err(String::from("invalid *smtp* connections: 33")),
+         parse_smtp(&cfg, &mut At::default())
+      );
+      let cfg = prep_config("[smtp]\nhost=localhost\ndomain_burst=0").unwrap();
+      assert_eq!(
+         Err(String::from("invalid *smtp* domain_burst: 0")),
+         parse_smtp(&cfg, &mut At::default())
+      );
    }
 
    #[test]
-   fn check_email_with_long_form_and_no_quotes() {
-      assert_eq!(true, check_email(r#"Frodo Baggins <rts@example.com>"#));
+   fn parse_smtp_with_dsn() {
+      let cfg =
+         prep_config("[smtp]\nhost=localhost\ndsn_notify=success, Failure\ndsn_ret=hdrs").unwrap();
+      let smtp = parse_smtp(&cfg, &mut At::default()).unwrap().unwrap();
+      assert_eq!(Some(String::from("SUCCESS,FAILURE")), smtp.dsn_notify);
+      assert_eq!(Some(String::from("HDRS")), smtp.dsn_ret);
+      for (setting, error) in [
+         (
+            "dsn_notify=never,success",
+            "invalid *smtp* dsn_notify: never,success",
+         ),
+         ("dsn_notify=", "invalid *smtp* dsn_notify: "),
+         ("dsn_ret=body", "invalid *smtp* dsn_ret: body"),
+      ]
+      .iter()
+      {
+         let cfg = prep_config(&format!("[smtp]\nhost=localhost\n{}", setting)).unwrap();
+         assert_eq!(
+            Err(String::from(*error)),
+            parse_smtp(&cfg, &mut At::default())
+         );
+      }
    }
 
    #[test]
-   fn parse_recipients_with_invalid_email() {
-      let file = r#"
-[general]
-From=abc@def.com
-# this is a comment
-cc=weirdo@nsb.gov, cc@example.com
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
-      let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from("invalid email: @example.com"));
-      assert_eq!(expected, parse_recipients(&cfg));
+   fn parse_imap_section() {
+      let cfg = prep_config("[imap]\nhost=imap.example.com\nusername=frodo\nkeyring=imap").unwrap();
+      let expected = Imap {
+         host: String::from("imap.example.com"),
+         port: 993,
+         security: Security::Tls,
+         username: String::from("frodo"),
+         password: None,
+         keyring: Some(String::from("imap")),
+         folder: String::from("Sent"),
+      };
+      assert_eq!(Ok(Some(expected)), parse_imap(&cfg, &mut At::default()));
+      let cfg = prep_config(
+         "[imap]\nhost=localhost\nsecurity=starttls\nusername=frodo\nfolder=Sent Items",
+      )
+      .unwrap();
+      let imap = parse_imap(&cfg, &mut At::default()).unwrap().unwrap();
+      assert_eq!((143, "Sent Items"), (imap.port, imap.folder.as_ref()));
+      let cfg = prep_config("[imap]\nhost=localhost").unwrap();
+      assert_eq!(
+         Err(String::from("No *username* in the imap section")),
+         parse_imap(&cfg, &mut At::default())
+      );
+      let cfg = prep_config("[imap]\nhost=localhost\nusername=frodo\nfolder=Gesendet ü").unwrap();
+      assert_eq!(
+         Err(String::from("invalid *imap* folder: Gesendet ü")),
+         parse_imap(&cfg, &mut At::default())
+      );
+      assert_eq!(
+         Ok(None),
+         parse_imap(
+            &prep_config("[smtp]\nhost=localhost").unwrap(),
+            &mut At::default()
+         )
+      );
    }
 
    #[test]
-   fn parse_recipients_with_invalid_data() {
+   fn parse_smtp_with_domain_rates() {
+      let cfg = prep_config(
+         "[smtp]\nhost=localhost\nrate.Gmail.com=20/min\nrate.default=60/min\nrate=2/s",
+      )
+      .unwrap();
+      let smtp = parse_smtp(&cfg, &mut At::default()).unwrap().unwrap();
+      assert_eq!(2, smtp.domain_rates.len());
+      assert_eq!(parse_rate("20/min").ok(), smtp.domain_rate("gmail.com"));
+      assert_eq!(parse_rate("60/min").ok(), smtp.domain_rate("example.com"));
+      let recipients = vec![
+         testing::recipient("a@gmail.com", "Jane Doe", &[]),
+         testing::recipient("b@gmail.com", "John Doe", &[]),
+         testing::recipient("c@example.com", "Mickey Mouse", &[]),
+      ];
+      // the gmail.com recipients take longest
+      assert_eq!(Some(Duration::from_secs(6)), smtp.estimate(&recipients));
+      let cfg = prep_config("[smtp]\nhost=localhost\nrate.gmail=20/min").unwrap();
+      assert_eq!(
+         Err(String::from("invalid *smtp* rate domain: gmail")),
+         parse_smtp(&cfg, &mut At::default())
+      );
+   }
+
+   #[test]
+   fn parse_smtp_without_section() {
+      let cfg = prep_config("[general]").expect("Failed to set up config");
+      assert_eq!(Ok(None), parse_smtp(&cfg, &mut At::default()));
+   }
+
+   #[test]
+   fn parse_smtp_without_host() {
       let file = r#"
-[general]
-From=abc@def.com
-# this is a comment
-cc=weirdo@nsb.gov, cc@example.com
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-a@example.com="#;
+[smtp]
+port=2525"#;
       let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from("invalid data for email: a@example.com"));
-      assert_eq!(expected, parse_recipients(&cfg));
+      let expected = Err(String::from("No *host* in the smtp section"));
+      assert_eq!(expected, parse_smtp(&cfg, &mut At::default()));
    }
 
    #[test]
-   fn parse_recipients_with_invalid_non_name_data_no_value() {
+   fn parse_smtp_with_invalid_port() {
       let file = r#"
-[general]
-From=abc@def.com
-# this is a comment
-cc=weirdo@nsb.gov, cc@example.com
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-a@example.com=A B C|ORG:-"#;
+[smtp]
+host=smtp.example.com
+port=99999"#;
       let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from(
-         "invalid recipient data for a@example.com (empty value for key (ORG))",
-      ));
-      assert_eq!(expected, parse_recipients(&cfg));
+      let expected = Err(String::from("invalid *smtp* port: 99999"));
+      assert_eq!(expected, parse_smtp(&cfg, &mut At::default()));
    }
 
    #[test]
-   fn parse_recipients_with_invalid_non_name_data_no_key() {
+   fn parse_smtp_with_username_only() {
       let file = r#"
-[general]
-From=abc@def.com
-# this is a comment
-cc=weirdo@nsb.gov, cc@example.com
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-a@example.com=A B C|:-Disney"#;
+[smtp]
+host=smtp.example.com
+username=frodo"#;
       let cfg = prep_config(file).expect("Failed to set up config");
       let expected = Err(String::from(
-         "invalid recipient data for a@example.com (no key for datum (Disney))",
+         "the smtp section needs both a *username* and a *password* or neither",
       ));
-      assert_eq!(expected, parse_recipients(&cfg));
+      assert_eq!(expected, parse_smtp(&cfg, &mut At::default()));
    }
 
    #[test]
-   fn parse_recipient_data_happy_case() {
-      let expected = sm(&[
-         ("ORG", "EFF"),
-         ("TITLE", "PhD"),
-         ("cc", "bl@kf.io,info@ex.org"),
-      ]);
-      let mut args: Vec<&str> =
-         "jd@example.com=John Doe Jr.|ORG:-EFF|   TITLE:-PhD|cc:-       bl@kf.io,info@ex.org"
-            .split("|")
-            .collect();
-
-      args.remove(0);
-      assert_eq!(Ok(expected), parse_recipient_data(&args));
+   fn parse_smtp_with_keyring() {
+      let file = |smtp: &str| format!("[smtp]\nhost=smtp.example.com\nusername=frodo\n{}", smtp);
+      let cfg = prep_config(&file("keyring=smtp-example")).expect("Failed to set up config");
+      let mut smtp = parse_smtp(&cfg, &mut At::default())
+         .expect("Failed to parse config")
+         .unwrap();
+      assert_eq!(Some(String::from("smtp-example")), smtp.keyring);
+      assert_eq!(None, smtp.password);
+      // an explicit password is used as is, the keyring is not consulted
+      smtp.password = Some(String::from("secret"));
+      assert_eq!(Ok(()), smtp.resolve_credentials());
+      assert_eq!(Some(String::from("secret")), smtp.password);
+      let cfg = prep_config(&file("password=secret\nkeyring=smtp-example"))
+         .expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "the smtp section needs either a *password* or a *keyring* entry, not both"
+         )),
+         parse_smtp(&cfg, &mut At::default()).map(|_| ())
+      );
    }
 
    #[test]
-   fn parse_recipient_data_happy_case_override_everything() {
-      let expected = sm(&[
-         ("From", r#""Carl Gauss" <cgauss@math.org>"#),
-         ("Subject", "Do you like math?"),
-         ("Reply-To", r#""Leonhard Euler" <leuler@math.org>"#),
-         ("Cc", "briemann@math.org,gcantor@math.org"),
-      ]);
-      let mut args: Vec<&str> =
-         r#"likes2@override.everything=Big Overrider|From:-"Carl Gauss" <cgauss@math.org>|Subject:-Do you like math?|Reply-To:-"Leonhard Euler" <leuler@math.org>|Cc:-briemann@math.org,gcantor@math.org"#
-            .split("|")
-            .collect();
-
-      args.remove(0);
-      assert_eq!(Ok(expected), parse_recipient_data(&args));
+   fn parse_defaults_section() {
+      let file = "[general]\nFrom=rts@example.com\nsubject=hi\n[defaults]\nORG=our community\ntitle = friend\n[recipients]\njd@example.com=John Doe";
+      let cfg = prep_config(file).expect("Failed to set up config");
+      let cfg = parse(&cfg, "rmt", "0.1.0").expect("Failed to parse config");
+      assert_eq!(
+         sm(&[("ORG", "our community"), ("title", "friend")]),
+         cfg.defaults
+      );
+      // the automatic keys cannot be defaulted
+      let cfg = prep_config("[defaults]\n_FN=friend").expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid *defaults* configuration datum: *_FN*"
+         )),
+         parse_defaults(&cfg, &mut At::default())
+      );
    }
 
    #[test]
-   fn parse_recipient_data_happy_case_with_empty_key_and_value() {
-      let expected = sm(&[("ORG", "EFF"), ("cc", "bl@kf.io,info@ex.org")]);
-      let mut args: Vec<&str> =
-         "jd@example.com=John Doe Jr.|ORG:-EFF|:-|cc:-       bl@kf.io,info@ex.org"
-            .split("|")
-            .collect();
+   fn parse_schema_section() {
+      let file = r#"
+[general]
+From=rts@example.com
+subject=hi
+locale=de_DE
+[schema]
+DUE=date
+AMOUNT=currency:eur
+[defaults]
+AMOUNT=0
+[recipients]
+jd@example.com=John Doe|DUE:-2024-05-01|AMOUNT:-1234.5
+mm@example.com=Mickey Mouse|DUE:-tomorrow"#;
+      let cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid recipient data for mm@example.com (DUE: invalid date (tomorrow))"
+         )),
+         parse(&cfg, "rmt", "0.1.0").map(|_| ())
+      );
+      let cfg = parse_lenient(&cfg, "rmt", "0.1.0").expect("Failed to parse config");
+      assert_eq!(1, cfg.quarantine.len());
+      assert_eq!(Locale::parse("de").unwrap(), cfg.locale);
+      assert_eq!(
+         Some(&DataType::Currency(String::from("EUR"))),
+         cfg.schema.get("AMOUNT")
+      );
+      let values = template::values(&cfg, &cfg.recipients[0]);
+      assert_eq!("01.05.2024", values["DUE"]);
+      assert_eq!("1.234,50 €", values["AMOUNT"]);
+      // the raw data is kept
+      assert_eq!("2024-05-01", cfg.recipients[0].data["DUE"]);
 
-      args.remove(0);
-      assert_eq!(Ok(expected), parse_recipient_data(&args));
+      let cfg = prep_config("[schema]\nDUE=datetime").expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid type of *DUE* (unknown type: datetime)"
+         )),
+         parse_schema(&cfg, &HashMap::new(), &mut At::default())
+      );
+      let cfg = prep_config("[schema]\nDUE=date").expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "invalid default of *DUE* (invalid date (soon))"
+         )),
+         parse_schema(&cfg, &sm(&[("DUE", "soon")]), &mut At::default())
+      );
    }
 
    #[test]
-   fn parse_recipient_data_failure_case_with_empty_key() {
-      let mut args: Vec<&str> =
-         "jd@example.com=John Doe Jr.|:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"
-            .split("|")
-            .collect();
-
-      args.remove(0);
+   fn load_with_includes() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let write =
+         |name: &str, content: &str| std::fs::write(dir.path().join(name), content).unwrap();
+      write(
+         "base.ini",
+         "[general]\nFrom=rts@example.com\ncc=cc@example.com\nsubject=base\n[smtp]\nhost=smtp.example.com\n",
+      );
+      write(
+         "news.ini",
+         "[general]\nReply-To=news@example.com\nsubject=news\n",
+      );
+      write(
+         "campaign.ini",
+         "[general]\ninclude=base.ini, news.ini\nsubject=Hello %FN%\n[recipients]\njd@example.com=John Doe\n",
+      );
+      let cfg = load(&dir.path().join("campaign.ini")).expect("cannot load config");
       assert_eq!(
-         Err(String::from("no key for datum (EFF)")),
-         parse_recipient_data(&args)
+         Some("rts@example.com"),
+         cfg.get_from(Some("general"), "From")
       );
+      assert_eq!(
+         Some("news@example.com"),
+         cfg.get_from(Some("general"), "Reply-To")
+      );
+      assert_eq!(Some("Hello %FN%"), cfg.get_from(Some("general"), "subject"));
+      assert_eq!(None, cfg.get_from(Some("general"), "include"));
+      assert_eq!(Some("smtp.example.com"), cfg.get_from(Some("smtp"), "host"));
+      assert!(parse(&cfg, "rmt", "0.1.0").is_ok());
+
+      write("a.ini", "[general]\ninclude=b.ini\n");
+      write("b.ini", "[general]\ninclude=a.ini\n");
+      let err = load(&dir.path().join("a.ini")).err().unwrap();
+      assert!(err.ends_with("a.ini includes itself"), "{}", err);
+      write("c.ini", "[general]\ninclude=missing.ini\n");
+      let err = load(&dir.path().join("c.ini")).err().unwrap();
+      assert!(err.starts_with("cannot read"), "{}", err);
    }
 
    #[test]
-   fn parse_recipient_data_failure_case_with_empty_value() {
-      let mut args: Vec<&str> =
-         "jd@example.com=John Doe Jr.|ORG:-   |TITLE:-PhD|cc:-bl@kf.io,info@ex.org"
-            .split("|")
-            .collect();
-
-      args.remove(0);
+   fn load_several_files() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let path = |name: &str| dir.path().join(name).display().to_string();
+      std::fs::write(
+         path("base.ini"),
+         "[general]\nFrom=rts@example.com\nsubject=base\n[recipients]\njd@example.com=John Doe\n",
+      )
+      .unwrap();
+      std::fs::write(path("overrides.ini"), "[general]\nsubject=Hello %FN%\n").unwrap();
+      let (base, overrides) = (path("base.ini"), path("overrides.ini"));
+      let cfg = load_all(&[&base, &overrides]).expect("cannot load config");
+      assert_eq!(Some("Hello %FN%"), cfg.get_from(Some("general"), "subject"));
       assert_eq!(
-         Err(String::from("empty value for key (ORG)")),
-         parse_recipient_data(&args)
+         Some("John Doe"),
+         cfg.get_from(Some("recipients"), "jd@example.com")
+      );
+      assert_eq!(
+         Err(String::from("no config file")),
+         load_all(&[]).map(|_| ())
       );
    }
 
    #[test]
-   fn parse_recipient_data_happy_case_with_empty_rdata() {
-      let expected = sm(&[("TITLE", "PhD")]);
-      let mut args: Vec<&str> = "jd@example.com=John Doe Jr.||TITLE:-PhD|"
-         .split("|")
-         .collect();
-
-      args.remove(0);
-      assert_eq!(Ok(expected), parse_recipient_data(&args));
+   fn load_toml_file() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let path = dir.path().join("rmt.toml");
+      std::fs::write(
+         &path,
+         "[general]\nFrom = \"rts@example.com\"\ncc = [\"a@example.com\", \"b@example.com\"]\n\
+          [smtp]\nport = 587\n[recipients]\n\"jd@example.com\" = \"John Doe\"\n",
+      )
+      .unwrap();
+      let cfg = load(&path).expect("cannot load config");
+      assert_eq!(
+         Some("a@example.com, b@example.com"),
+         cfg.get_from(Some("general"), "cc")
+      );
+      assert_eq!(Some("587"), cfg.get_from(Some("smtp"), "port"));
+      assert_eq!(
+         Some("John Doe"),
+         cfg.get_from(Some("recipients"), "jd@example.com")
+      );
+      std::fs::write(&path, "[general]\nFrom = \"rts@example.com\"\nsubject = \n").unwrap();
+      let err = load(&path).err().unwrap();
+      assert!(
+         err.starts_with(&format!("{}:3: ", path.display())),
+         "{}",
+         err
+      );
+      std::fs::write(&path, "subject = \"hello\"\n").unwrap();
+      assert!(load(&path).is_err());
    }
 
    #[test]
-   fn parse_recipient_data_failure_case_with_invalid_format() {
-      let mut args: Vec<&str> = "jd@example.com=John Doe Jr.|R:-E:-K:-T|cc:-bl@kf.io,info@ex.org"
-         .split("|")
-         .collect();
-
-      args.remove(0);
+   fn expand_env_references() {
+      std::env::set_var("RMT_TEST_SMTP_PASSWORD", "s3cret");
+      std::env::remove_var("RMT_TEST_UNDEFINED");
       assert_eq!(
-         Err(String::from("invalid recipient data (R:-E:-K:-T)")),
-         parse_recipient_data(&args)
+         Ok(String::from(
+            "s3cret and s3cret, ${RMT_TEST_UNDEFINED} $5 ${"
+         )),
+         expand_env(
+            "${RMT_TEST_SMTP_PASSWORD} and ${RMT_TEST_SMTP_PASSWORD}, $${RMT_TEST_UNDEFINED} $5 ${"
+         )
+      );
+      assert_eq!(
+         Err(String::from(
+            "undefined environment variable: RMT_TEST_UNDEFINED"
+         )),
+         expand_env("x${RMT_TEST_UNDEFINED}")
+      );
+      let file = "[general]\nFrom=rts@example.com\nsubject=hi ${RMT_TEST_SMTP_PASSWORD}\n[smtp]\nhost=smtp.example.com\nusername=rts\npassword=${RMT_TEST_SMTP_PASSWORD}\n[recipients]\njd@example.com=John ${RMT_TEST_UNDEFINED}";
+      let mut cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(Ok(()), interpolate_env(&mut cfg, &mut At::default()));
+      assert_eq!(Some("s3cret"), cfg.get_from(Some("smtp"), "password"));
+      assert_eq!(
+         Some("John ${RMT_TEST_UNDEFINED}"),
+         cfg.get_from(Some("recipients"), "jd@example.com")
+      );
+      let mut cfg =
+         prep_config("[smtp]\npassword=${RMT_TEST_UNDEFINED}").expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from(
+            "*password* in the smtp section: undefined environment variable: RMT_TEST_UNDEFINED"
+         )),
+         interpolate_env(&mut cfg, &mut At::default())
       );
    }
 
    #[test]
-   fn parse_general_with_invalid_from_email() {
-      let file = r#"
-[general]
-From=abc@defcom
-# this is a comment
-cc=weirdo@nsb.gov, cc@example.com
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
-      let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from("invalid *From* email: abc@defcom"));
-      assert_eq!(expected, parse_general(&cfg, "rmt", "0.1.2"));
+   fn instantiate_with_user_config() {
+      let user =
+         user_config::parse("[profiles.work]\nfrom = \"Frodo Baggins <frodo@work.example.com>\"\n")
+            .unwrap();
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let path = dir.path().join("c.ini").display().to_string();
+      std::fs::write(
+         &path,
+         "[general]\nprofile=work\nsubject=hi\n[recipients]\njd@example.com=John Doe\n",
+      )
+      .unwrap();
+      let cfg = instantiate(&[&path], Some(&user), "rmt", "0.1.2").expect("invalid config");
+      assert_eq!("frodo@work.example.com", cfg.gdata.from.email());
+      // without the user config there are no profiles
+      let err = instantiate(&[&path], None, "rmt", "0.1.2").expect_err("valid config");
+      assert_eq!("unknown *profile*: work", err.message);
+      assert_eq!(Some(2), err.origin.and_then(|o| o.line));
    }
 
    #[test]
-   fn parse_general_with_invalid_reply_to_email() {
-      let file = r#"
-[general]
-From=abc@def.com
-Reply-To=no@one
-# this is a comment
-cc=weirdo@nsb.gov, cc@example.com
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
-      let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from("invalid *Reply-To* email(s): no@one"));
-      assert_eq!(expected, parse_general(&cfg, "rmt", "0.1.2"));
-   }
+   fn apply_user_config_happy_case() {
+      let user = user_config::parse(
+         r#"
+[profiles.work]
+from = "Frodo Baggins <frodo@work.example.com>"
+[profiles.work.smtp]
+host = "smtp.work.example.com"
+security = "starttls"
+username = "frodo"
+keyring = "smtp-work"
+"#,
+      )
+      .unwrap();
+      let file = "[general]\nprofile=work\nsubject=hi\n[smtp]\nsecurity=tls\n[recipients]\njd@example.com=John Doe";
+      let mut cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(Ok(()), apply_profile(&mut cfg, &user));
+      assert_eq!(Ok(1), check(&cfg));
+      let cfg = parse(&cfg, "rmt", "0.1.0").expect("Failed to parse config");
+      assert_eq!("frodo@work.example.com", cfg.gdata.from.email());
+      let smtp = cfg.smtp.unwrap();
+      assert_eq!("smtp.work.example.com", smtp.host);
+      // the settings in the config win
+      assert_eq!(Security::Tls, smtp.security);
+      assert_eq!(Some(String::from("smtp-work")), smtp.keyring);
 
-   #[test]
-   fn parse_general_with_invalid_cc_email() {
-      let file = r#"
-[general]
-From=abc@def.com
-Reply-To=no@one.org
-# this is a comment
-cc=dd@examplecom, weirdo@nsb.gov, oh!no!
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
-      let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from("invalid *cc* email(s): dd@examplecom, oh!no!"));
-      assert_eq!(expected, parse_general(&cfg, "rmt", "0.1.2"));
-   }
+      // the credentials in the config replace the profile's
+      let file =
+         "[general]\nFrom=rts@example.com\nprofile=work\n[smtp]\nusername=rts\npassword=secret";
+      let mut cfg = prep_config(file).expect("Failed to set up config");
+      assert_eq!(Ok(()), apply_profile(&mut cfg, &user));
+      let smtp = parse_smtp(&cfg, &mut At::default()).unwrap().unwrap();
+      assert_eq!(Some(String::from("rts")), smtp.username);
+      assert_eq!(None, smtp.keyring);
+      assert_eq!(
+         Some("rts@example.com"),
+         cfg.get_from(Some("general"), "From")
+      );
 
-   #[test]
-   fn parse_general_with_empty_cc_email() {
-      let file = r#"
-[general]
-From=abc@def.com
-Reply-To=no@one.org
-# this is a comment
-Cc=
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
-      let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from("no emails for *Cc* header"));
-      assert_eq!(expected, parse_general(&cfg, "rmt", "0.1.2"));
-   }
+      let defaults = Defaults {
+         rate: Some(String::from("10/s")),
+         ..Defaults::default()
+      };
+      apply_defaults(&mut cfg, &defaults);
+      assert_eq!(Some("10/s"), cfg.get_from(Some("smtp"), "rate"));
 
-   #[test]
-   fn parse_general_with_invalid_config_datum() {
-      let file = r#"
-[general]
-From=abc@def.com
-Reply-To=no@one.org
-# this is a comment
-blah=invalid
-[recipients]
-# The 'cc' setting below *redefines* the global 'cc' value above
-@example.com=John Doe Jr.|ORG:-EFF|TITLE:-PhD|cc:-bl@kf.io,info@ex.org"#;
-      let cfg = prep_config(file).expect("Failed to set up config");
-      let expected = Err(String::from("invalid configuration datum: *blah*"));
-      assert_eq!(expected, parse_general(&cfg, "rmt", "0.1.2"));
+      let mut cfg = prep_config("[general]\nprofile=home").expect("Failed to set up config");
+      assert_eq!(
+         Err(String::from("unknown *profile*: home")),
+         apply_profile(&mut cfg, &user)
+      );
    }
 
    #[test]
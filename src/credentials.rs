@@ -0,0 +1,227 @@
+/// The `credentials` module reads credentials the way other tools keep them: from `~/.netrc`
+/// entries and from `RMT_*` variables, which may be set in a local `.env` file.
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The environment variable holding the SMTP user name.
+pub const USERNAME_VAR: &str = "RMT_SMTP_USERNAME";
+/// The environment variable holding the SMTP password.
+pub const PASSWORD_VAR: &str = "RMT_SMTP_PASSWORD";
+
+/// Returns the path of the netrc file, `$NETRC` or `~/.netrc` by default.
+pub fn netrc_path() -> Option<PathBuf> {
+   match env::var_os("NETRC") {
+      Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+      _ => Some(PathBuf::from(env::var_os("HOME")?).join(".netrc")),
+   }
+}
+
+/// Looks up the login and the password for the given host in the netrc text.
+///
+/// The `default` entry is deliberately ignored, the credentials it holds are meant for other
+/// services. Macro definitions are skipped.
+pub fn netrc_lookup(text: &str, host: &str) -> Option<(String, String)> {
+   let mut machine: Option<String> = None;
+   let mut login = None;
+   let mut password = None;
+   let mut lines = text.lines();
+   while let Some(line) = lines.next() {
+      let mut tokens = line.split_whitespace();
+      while let Some(token) = tokens.next() {
+         match token {
+            "machine" | "default" => {
+               if machine.as_deref().map(|m| m.eq_ignore_ascii_case(host)) == Some(true) {
+                  break;
+               }
+               machine = if token == "machine" {
+                  tokens.next().map(|m| m.to_string())
+               } else {
+                  Some(String::new())
+               };
+               login = None;
+               password = None;
+            }
+            "login" => login = tokens.next().map(|l| l.to_string()),
+            "password" => password = tokens.next().map(|p| p.to_string()),
+            "account" => {
+               tokens.next();
+            }
+            // a macro definition extends to the next empty line
+            "macdef" => {
+               for line in lines.by_ref() {
+                  if line.trim().is_empty() {
+                     break;
+                  }
+               }
+               break;
+            }
+            t if t.starts_with('#') => break,
+            _ => (),
+         }
+      }
+      if machine.as_deref().map(|m| m.eq_ignore_ascii_case(host)) == Some(true)
+         && login.is_some()
+         && password.is_some()
+      {
+         break;
+      }
+   }
+   match machine {
+      Some(ref m) if m.eq_ignore_ascii_case(host) => login.zip(password),
+      _ => None,
+   }
+}
+
+/// Parses the `KEY=value` lines of a `.env` file.
+///
+/// Values may be single-quoted (taken literally) or double-quoted (with `\n` escapes), the
+/// `export` prefix and comments are allowed.
+pub fn parse_dotenv(text: &str) -> Result<Vec<(String, String)>, String> {
+   let mut result = vec![];
+   for (i, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+         continue;
+      }
+      let line = line.strip_prefix("export ").unwrap_or(line);
+      let (key, value) = line
+         .split_once('=')
+         .ok_or_else(|| format!("line {}: no = sign", i + 1))?;
+      let key = key.trim();
+      if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+         return Err(format!("line {}: invalid variable name: {}", i + 1, key));
+      }
+      let value = value.trim();
+      let value = if let Some(quoted) = value.strip_prefix('\'') {
+         quoted
+            .strip_suffix('\'')
+            .ok_or_else(|| format!("line {}: unterminated quote", i + 1))?
+            .to_string()
+      } else if let Some(quoted) = value.strip_prefix('"') {
+         quoted
+            .strip_suffix('"')
+            .ok_or_else(|| format!("line {}: unterminated quote", i + 1))?
+            .replace("\\n", "\n")
+            .replace("\\\"", "\"")
+      } else {
+         match value.find(" #") {
+            Some(pos) => value[..pos].trim_end().to_string(),
+            None => value.to_string(),
+         }
+      };
+      result.push((key.to_string(), value));
+   }
+   Ok(result)
+}
+
+/// Sets the `RMT_*` variables of the `.env` file (if there is one) that are not in the
+/// environment already, returns how many were set.
+pub fn load_dotenv(path: &Path) -> Result<usize, String> {
+   if !path.exists() {
+      return Ok(0);
+   }
+   let text =
+      fs::read_to_string(path).map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+   let variables = parse_dotenv(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+   let mut result = 0;
+   for (key, value) in variables {
+      if key.starts_with("RMT_") && env::var_os(&key).is_none() {
+         env::set_var(key, value);
+         result += 1;
+      }
+   }
+   Ok(result)
+}
+
+/// Returns the SMTP credentials from the `RMT_SMTP_USERNAME` and `RMT_SMTP_PASSWORD` variables
+/// if both are set.
+pub fn from_env() -> Option<(String, String)> {
+   let username = env::var(USERNAME_VAR).ok().filter(|u| !u.is_empty())?;
+   let password = env::var(PASSWORD_VAR).ok().filter(|p| !p.is_empty())?;
+   Some((username, password))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn netrc_lookup_happy_case() {
+      let netrc = "# my hosts
+machine ftp.example.com login anon password guest
+machine smtp.example.com
+   login frodo
+   password s3cret
+macdef init
+   machine smtp.example.com login mallory password stolen
+
+default login me password mine
+";
+      assert_eq!(
+         Some((String::from("frodo"), String::from("s3cret"))),
+         netrc_lookup(netrc, "SMTP.example.com")
+      );
+      assert_eq!(
+         Some((String::from("anon"), String::from("guest"))),
+         netrc_lookup(netrc, "ftp.example.com")
+      );
+      // the default entry is not used
+      assert_eq!(None, netrc_lookup(netrc, "mail.example.com"));
+      assert_eq!(
+         None,
+         netrc_lookup("machine smtp.example.com login frodo", "smtp.example.com")
+      );
+   }
+
+   #[test]
+   fn parse_dotenv_happy_case() {
+      let text = r#"
+# SMTP credentials
+export RMT_SMTP_USERNAME=frodo   # the user
+RMT_SMTP_PASSWORD='p#ss w"rd'
+RMT_GREETING="Hello\nthere"
+OTHER=
+"#;
+      assert_eq!(
+         Ok(vec![
+            (String::from("RMT_SMTP_USERNAME"), String::from("frodo")),
+            (
+               String::from("RMT_SMTP_PASSWORD"),
+               String::from("p#ss w\"rd")
+            ),
+            (String::from("RMT_GREETING"), String::from("Hello\nthere")),
+            (String::from("OTHER"), String::new()),
+         ]),
+         parse_dotenv(text)
+      );
+      assert_eq!(
+         Err(String::from("line 2: no = sign")),
+         parse_dotenv("A=1\nB\n")
+      );
+      assert_eq!(
+         Err(String::from("line 1: unterminated quote")),
+         parse_dotenv("A='1\n")
+      );
+   }
+
+   #[test]
+   fn load_dotenv_only_sets_rmt_variables() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let path = dir.path().join(".env");
+      fs::write(
+         &path,
+         "RMT_TEST_DOTENV=from file\nRMT_TEST_DOTENV_SET=from file\nTEST_DOTENV_OTHER=x\n",
+      )
+      .unwrap();
+      env::set_var("RMT_TEST_DOTENV_SET", "from env");
+      assert_eq!(Ok(1), load_dotenv(&path));
+      assert_eq!(Ok(String::from("from file")), env::var("RMT_TEST_DOTENV"));
+      assert_eq!(
+         Ok(String::from("from env")),
+         env::var("RMT_TEST_DOTENV_SET")
+      );
+      assert!(env::var("TEST_DOTENV_OTHER").is_err());
+      assert_eq!(Ok(0), load_dotenv(&dir.path().join("missing.env")));
+   }
+}
@@ -0,0 +1,262 @@
+/// The `css` module inlines the rules of the `<style>` blocks of HTML emails into the `style`
+/// attributes of the elements, many mail clients ignore style sheets.
+use regex::{Captures, Regex};
+
+#[derive(Debug)]
+/// The `Selector` struct holds a simple selector i.e. an optional element name, id and classes
+/// e.g. `p.note`.
+struct Selector {
+   /// The element name (lowercase), `None` matches any element
+   element: Option<String>,
+   /// The id the element must have
+   id: Option<String>,
+   /// The classes the element must have
+   classes: Vec<String>,
+}
+
+impl PartialEq for Selector {
+   /// Makes it possible to compare instances of `Selector`
+   fn eq(&self, other: &Self) -> bool {
+      self.element == other.element && self.id == other.id && self.classes == other.classes
+   }
+}
+
+impl Selector {
+   /// Parses a simple selector, returns `None` for anything else e.g. descendant selectors,
+   /// attribute selectors or pseudo-classes.
+   fn parse(text: &str) -> Option<Selector> {
+      let text = text.trim();
+      let re = Regex::new(r"^(\*|[a-zA-Z][\w-]*)?((?:[.#][\w-]+)*)$").unwrap();
+      let caps = re.captures(text).filter(|_| !text.is_empty())?;
+      let mut result = Selector {
+         element: caps
+            .get(1)
+            .map(|m| m.as_str().to_lowercase())
+            .filter(|e| e != "*"),
+         id: None,
+         classes: vec![],
+      };
+      let part = Regex::new(r"([.#])([\w-]+)").unwrap();
+      for p in part.captures_iter(&caps[2]) {
+         if &p[1] == "." {
+            result.classes.push(p[2].to_string());
+         } else if result.id.replace(p[2].to_string()).is_some() {
+            return None;
+         }
+      }
+      Some(result)
+   }
+
+   /// Returns the specificity of the selector, rules with a higher one take precedence.
+   fn specificity(&self) -> usize {
+      self.id.as_ref().map(|_| 100).unwrap_or(0)
+         + self.classes.len() * 10
+         + self.element.as_ref().map(|_| 1).unwrap_or(0)
+   }
+
+   /// Checks whether an element with the given name, id and classes matches the selector.
+   fn matches(&self, element: &str, id: Option<&str>, classes: &[&str]) -> bool {
+      self
+         .element
+         .as_ref()
+         .map(|e| e.eq_ignore_ascii_case(element))
+         .unwrap_or(true)
+         && self.id.as_deref().map(|i| Some(i) == id).unwrap_or(true)
+         && self.classes.iter().all(|c| classes.contains(&c.as_ref()))
+   }
+}
+
+/// Splits CSS declarations like `color: red; margin: 0` into (property, value) pairs.
+fn declarations(text: &str) -> Vec<(String, String)> {
+   text
+      .split(';')
+      .filter_map(|d| d.split_once(':'))
+      .map(|(p, v)| (p.trim().to_lowercase(), v.trim().to_string()))
+      .filter(|(p, v)| !p.is_empty() && !v.is_empty())
+      .collect()
+}
+
+/// Splits a style sheet into its top-level (prelude, block) pairs, the blocks of at-rules may be
+/// nested.
+fn blocks(css: &str) -> Vec<(&str, &str)> {
+   let mut result = vec![];
+   let (mut depth, mut start, mut open) = (0, 0, 0);
+   for (i, c) in css.char_indices() {
+      match c {
+         '{' => {
+            if depth == 0 {
+               open = i;
+            }
+            depth += 1;
+         }
+         '}' if depth > 0 => {
+            depth -= 1;
+            if depth == 0 {
+               result.push((css[start..open].trim(), &css[open + 1..i]));
+               start = i + 1;
+            }
+         }
+         _ => (),
+      }
+   }
+   result
+}
+
+/// Returns the value of the attribute with the given name in the attributes of a tag.
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+   let re = Regex::new(&format!(
+      r#"(?i)(?:^|\s){}\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#,
+      name
+   ))
+   .unwrap();
+   re.captures(attributes).map(|caps| {
+      caps
+         .get(1)
+         .or_else(|| caps.get(2))
+         .or_else(|| caps.get(3))
+         .unwrap()
+         .as_str()
+   })
+}
+
+/// The elements that are not rendered and hence don't need styles.
+const NOT_RENDERED: &[&str] = &["base", "head", "link", "meta", "script", "style", "title"];
+
+/// Inlines the CSS rules of the `<style>` blocks into the `style` attributes of the matching
+/// elements.
+///
+/// Only rules with simple selectors (element names, ids and classes) are inlined, in the order
+/// of their specificity. The `style` attributes the elements already have take precedence.
+/// Everything else e.g. media queries or `a:hover` rules stays in a `<style>` block.
+pub fn inline(html: &str) -> String {
+   let style_re = Regex::new(r"(?is)<style[^>]*>(.*?)</style\s*>").unwrap();
+   let comment_re = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+   let mut rules: Vec<(Selector, Vec<(String, String)>)> = vec![];
+   let mut leftovers = vec![];
+   for caps in style_re.captures_iter(html) {
+      let css = comment_re.replace_all(&caps[1], "");
+      for (prelude, block) in blocks(&css) {
+         if prelude.starts_with('@') {
+            leftovers.push(format!("{} {{{}}}", prelude, block));
+            continue;
+         }
+         for text in prelude.split(',') {
+            match Selector::parse(text) {
+               Some(selector) => rules.push((selector, declarations(block))),
+               None => leftovers.push(format!("{} {{{}}}", text.trim(), block.trim())),
+            }
+         }
+      }
+   }
+   if rules.is_empty() {
+      return html.to_string();
+   }
+   // a stable sort keeps the rules with the same specificity in the order of the style sheet
+   rules.sort_by_key(|(selector, _)| selector.specificity());
+
+   let mut first = true;
+   let html = style_re.replace_all(html, |_: &Captures| {
+      if !first || leftovers.is_empty() {
+         return String::new();
+      }
+      first = false;
+      format!("<style>\n{}\n</style>", leftovers.join("\n"))
+   });
+   let tag_re = Regex::new(r"<([a-zA-Z][\w-]*)(\s[^>]*?)?(/?)>").unwrap();
+   let style_attr_re =
+      Regex::new(r#"(?i)(^|\s)style\s*=\s*(?:"[^"]*"|'[^']*'|[^\s"'>]+)"#).unwrap();
+   tag_re
+      .replace_all(&html, |caps: &Captures| {
+         let element = &caps[1];
+         if NOT_RENDERED.iter().any(|e| e.eq_ignore_ascii_case(element)) {
+            return caps[0].to_string();
+         }
+         let attributes = caps.get(2).map(|m| m.as_str()).unwrap_or("");
+         let id = attribute(attributes, "id");
+         let classes: Vec<&str> = attribute(attributes, "class")
+            .map(|c| c.split_whitespace().collect())
+            .unwrap_or_default();
+         let mut style: Vec<(String, String)> = vec![];
+         let inline = attribute(attributes, "style").map(declarations);
+         let matching = rules
+            .iter()
+            .filter(|(s, _)| s.matches(element, id, &classes))
+            .map(|(_, d)| d);
+         for (property, value) in matching.chain(inline.as_ref()).flatten() {
+            style.retain(|(p, _)| p != property);
+            style.push((property.clone(), value.clone()));
+         }
+         if style.is_empty() {
+            return caps[0].to_string();
+         }
+         let style: Vec<String> = style
+            .iter()
+            .map(|(p, v)| format!("{}: {}", p, v.replace('"', "'")))
+            .collect();
+         format!(
+            "<{}{} style=\"{}\"{}>",
+            element,
+            style_attr_re.replace_all(attributes, "").trim_end(),
+            style.join("; "),
+            &caps[3]
+         )
+      })
+      .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn parse_selectors() {
+      assert_eq!(
+         Some(Selector {
+            element: Some(String::from("p")),
+            id: None,
+            classes: vec![String::from("note"), String::from("big")],
+         }),
+         Selector::parse(" P.note.big ")
+      );
+      assert_eq!(111, Selector::parse("td#total.sum").unwrap().specificity());
+      assert_eq!(0, Selector::parse("*").unwrap().specificity());
+      for complex in &[
+         "div p",
+         "a:hover",
+         "p > span",
+         "input[type=text]",
+         "#a#b",
+         "",
+      ] {
+         assert_eq!(None, Selector::parse(complex));
+      }
+   }
+
+   #[test]
+   fn inline_rules_by_specificity() {
+      let html = r#"<html><head><style>
+/* the basics */
+p { color: black; margin: 0 }
+.note { color: blue }
+p.note { font-weight: bold }
+#first { color: red }
+</style></head><body><p id="first" class="note">Hi</p><p class='note' style="color: green">there</p><br/></body></html>"#;
+      assert_eq!(
+         r#"<html><head></head><body><p id="first" class="note" style="margin: 0; font-weight: bold; color: red">Hi</p><p class='note' style="margin: 0; font-weight: bold; color: green">there</p><br/></body></html>"#,
+         inline(html)
+      );
+   }
+
+   #[test]
+   fn inline_keeps_what_cannot_be_inlined() {
+      let html = r#"<style>a, a:hover { color: red }
+@media (max-width: 600px) { td { display: block } }</style><a href="x">x</a><img src="y" />"#;
+      assert_eq!(
+         "<style>\na:hover {color: red}\n@media (max-width: 600px) { td { display: block } }\n</style><a href=\"x\" style=\"color: red\">x</a><img src=\"y\" />",
+         inline(html)
+      );
+      // nothing to inline
+      let html = "<p>no styles</p>";
+      assert_eq!(html, inline(html));
+   }
+}
@@ -0,0 +1,129 @@
+/// The `diagnostic` module renders the parse and validation errors along with their source
+/// locations (config file and line, see `config::Sources`) for humans or as JSON for editors and
+/// CI.
+use crate::config::{ConfigError, Origin};
+use serde_json::json;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The `Severity` enum lists the kinds of diagnostics.
+pub enum Severity {
+   Error,
+   Warning,
+}
+
+impl fmt::Display for Severity {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         Severity::Error => write!(f, "error"),
+         Severity::Warning => write!(f, "warning"),
+      }
+   }
+}
+
+#[derive(Debug, PartialEq)]
+/// The `Diagnostic` struct holds an error or warning and where it was found, if known.
+pub struct Diagnostic {
+   pub severity: Severity,
+   /// The config file the problem is in
+   pub file: Option<String>,
+   /// The line (1-based) the problem is in
+   pub line: Option<usize>,
+   pub message: String,
+}
+
+impl Diagnostic {
+   /// Creates an error without a location.
+   pub fn error(message: &str) -> Diagnostic {
+      Diagnostic {
+         severity: Severity::Error,
+         file: None,
+         line: None,
+         message: message.to_string(),
+      }
+   }
+
+   /// Creates a warning without a location.
+   pub fn warning(message: &str) -> Diagnostic {
+      Diagnostic {
+         severity: Severity::Warning,
+         ..Diagnostic::error(message)
+      }
+   }
+
+   /// Places the diagnostic at the given origin (see `config::Sources`), if known.
+   pub fn at(mut self, origin: Option<&Origin>) -> Diagnostic {
+      if let Some(origin) = origin {
+         self.file = Some(origin.file.clone());
+         self.line = origin.line;
+      }
+      self
+   }
+
+   /// Returns the diagnostic as a JSON object with the `severity`, `file`, `line` and `message`
+   /// fields (`file` and `line` are `null` if not known).
+   pub fn to_json(&self) -> serde_json::Value {
+      json!({
+         "severity": self.severity.to_string(),
+         "file": self.file,
+         "line": self.line,
+         "message": self.message,
+      })
+   }
+}
+
+/// Formats the diagnostic the way compilers do e.g. `campaign.ini:14: invalid *cc* email(s)`.
+impl fmt::Display for Diagnostic {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match (&self.file, self.line) {
+         (Some(file), Some(line)) => write!(f, "{}:{}: {}", file, line, self.message),
+         (Some(file), None) => write!(f, "{}: {}", file, self.message),
+         _ => write!(f, "{}", self.message),
+      }
+   }
+}
+
+/// Turns a config error into a diagnostic, at the place it was found in.
+impl From<&ConfigError> for Diagnostic {
+   fn from(error: &ConfigError) -> Diagnostic {
+      Diagnostic::error(&error.message).at(error.origin.as_ref())
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::config;
+   use std::fs;
+
+   #[test]
+   fn render_located_errors() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let path = dir.path().join("c.ini");
+      fs::write(
+         &path,
+         "[general]\nFrom=fb@example.com\nsubject=Hi\ncc=info@example.com\n[recipients]\n\
+          jd@example.com=John Doe|cc:-bl@kf.io,info@\n",
+      )
+      .unwrap();
+      let p = path.display().to_string();
+      let err = config::instantiate(&[&p], None, "rmt", "0.1.2").expect_err("valid config");
+      let d = Diagnostic::from(&err);
+      assert_eq!((Some(p.clone()), Some(6)), (d.file.clone(), d.line));
+      assert!(d.to_string().starts_with(&format!("{}:6: ", p)), "{}", d);
+      assert_eq!(
+         json!({"severity": "error", "file": p, "line": 6, "message": err.message}),
+         d.to_json()
+      );
+      let d = Diagnostic::warning("no *smtp* section, only dry runs are possible").at(None);
+      assert_eq!(
+         json!({"severity": "warning", "file": null, "line": null,
+                "message": "no *smtp* section, only dry runs are possible"}),
+         d.to_json()
+      );
+      assert_eq!(
+         "no *smtp* section, only dry runs are possible",
+         d.to_string()
+      );
+   }
+}
@@ -0,0 +1,286 @@
+/// The `dns` module verifies that the recipients' domains are able to receive email.
+///
+/// A domain is deliverable if it has MX records or, lacking those, A/AAAA records (RFC 5321,
+/// section 5.1). The MX records are looked up with the name servers in `/etc/resolv.conf`, the
+/// A/AAAA fallback uses the system resolver.
+use crate::address;
+use crate::config::{email_domain, Config};
+use dns_parser::rdata::RData;
+use dns_parser::{Builder, Packet, QueryClass, QueryType, ResponseCode};
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The `Verdict` enum holds the outcome of the verification of a domain.
+pub enum Verdict {
+   /// The domain accepts email
+   Deliverable,
+   /// The domain does not accept email, for the given reason
+   Undeliverable(String),
+}
+
+/// The outcome of an MX lookup.
+enum Mx {
+   /// The mail exchangers of the domain
+   Hosts(Vec<String>),
+   /// The domain exists but has no MX records
+   None,
+   /// The domain does not exist
+   NxDomain,
+}
+
+/// Returns the name servers configured in `/etc/resolv.conf`.
+pub fn nameservers() -> Vec<SocketAddr> {
+   let content = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+   parse_resolv_conf(&content)
+}
+
+fn parse_resolv_conf(content: &str) -> Vec<SocketAddr> {
+   content
+      .lines()
+      .filter_map(|l| {
+         let mut words = l.split_whitespace();
+         match (words.next(), words.next()) {
+            (Some("nameserver"), Some(ip)) => ip.parse().ok().map(|ip| SocketAddr::new(ip, 53)),
+            _ => None,
+         }
+      })
+      .collect()
+}
+
+/// Looks up the MX records of the domain with the first name server that answers.
+fn lookup_mx(domain: &str, servers: &[SocketAddr]) -> Result<Mx, String> {
+   let id = (std::process::id() as u16) ^ (crate::events::now() as u16);
+   let mut builder = Builder::new_query(id, true);
+   builder.add_question(domain, false, QueryType::MX, QueryClass::IN);
+   let query = builder
+      .build()
+      .map_err(|_| format!("invalid domain: {}", domain))?;
+   let mut last_error = String::from("no name servers configured");
+   for server in servers.iter() {
+      let bind = if server.is_ipv4() {
+         "0.0.0.0:0"
+      } else {
+         "[::]:0"
+      };
+      let result = UdpSocket::bind(bind)
+         .and_then(|s| s.set_read_timeout(Some(Duration::from_secs(3))).map(|_| s))
+         .and_then(|s| s.send_to(&query, server).map(|_| s))
+         .and_then(|s| {
+            let mut buf = [0; 4096];
+            loop {
+               let (n, from) = s.recv_from(&mut buf)?;
+               if from == *server {
+                  return Ok(buf[..n].to_vec());
+               }
+            }
+         });
+      let response = match result {
+         Ok(response) => response,
+         Err(e) => {
+            last_error = format!("no answer from {} ({})", server, e);
+            continue;
+         }
+      };
+      let packet = match Packet::parse(&response) {
+         Ok(packet) if packet.header.id == id => packet,
+         Ok(_) => {
+            last_error = format!("mismatched answer from {}", server);
+            continue;
+         }
+         Err(e) => {
+            last_error = format!("invalid answer from {} ({})", server, e);
+            continue;
+         }
+      };
+      match packet.header.response_code {
+         ResponseCode::NoError => (),
+         ResponseCode::NameError => return Ok(Mx::NxDomain),
+         code => {
+            last_error = format!("{} answered {:?}", server, code);
+            continue;
+         }
+      }
+      let hosts: Vec<String> = packet
+         .answers
+         .iter()
+         .filter_map(|rr| match rr.data {
+            RData::MX(ref mx) => Some(mx.exchange.to_string()),
+            _ => None,
+         })
+         .collect();
+      debug!("dns: MX {} -> {:?}", domain, hosts);
+      return Ok(if hosts.is_empty() {
+         Mx::None
+      } else {
+         Mx::Hosts(hosts)
+      });
+   }
+   Err(last_error)
+}
+
+/// Verifies that the domain accepts email.
+///
+/// Returns an error if the domain could not be looked up at all e.g. because the name servers
+/// cannot be reached.
+pub fn verify_domain(domain: &str, servers: &[SocketAddr]) -> Result<Verdict, String> {
+   match lookup_mx(domain, servers)? {
+      // a "null MX" (RFC 7505) says explicitly that the domain accepts no email
+      Mx::Hosts(ref hosts) if hosts.iter().all(|h| h.trim_end_matches('.').is_empty()) => Ok(
+         Verdict::Undeliverable(format!("{} accepts no email (null MX)", domain)),
+      ),
+      Mx::Hosts(_) => Ok(Verdict::Deliverable),
+      Mx::NxDomain => Ok(Verdict::Undeliverable(format!("{} does not exist", domain))),
+      Mx::None => match (domain, 25).to_socket_addrs().map(|a| a.count()) {
+         Ok(n) if n > 0 => Ok(Verdict::Deliverable),
+         _ => Ok(Verdict::Undeliverable(format!(
+            "{} has no MX or A/AAAA records",
+            domain
+         ))),
+      },
+   }
+}
+
+/// Verifies the domains of all recipients, every domain is looked up once.
+///
+/// Returns the email addresses of the recipients whose domain does not accept email along with
+/// the reason.
+pub fn verify_recipients(
+   cfg: &Config,
+   servers: &[SocketAddr],
+) -> Result<Vec<(String, String)>, String> {
+   let mut verdicts: HashMap<String, Verdict> = HashMap::new();
+   let mut result = vec![];
+   for rcp in cfg.recipients.iter() {
+      let domain = match email_domain(&rcp.email) {
+         Some(domain) => address::ascii_domain(&domain).to_lowercase(),
+         None => {
+            result.push((rcp.email.clone(), String::from("no domain")));
+            continue;
+         }
+      };
+      if !verdicts.contains_key(&domain) {
+         let verdict = verify_domain(&domain, servers)?;
+         verdicts.insert(domain.clone(), verdict);
+      }
+      if let Some(Verdict::Undeliverable(reason)) = verdicts.get(&domain) {
+         result.push((rcp.email.clone(), reason.clone()));
+      }
+   }
+   Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+   use std::thread;
+
+   /// Builds the answer to the query, with an MX record pointing to `exchange` if given.
+   fn answer(query: &[u8], rcode: u8, exchange: Option<&[u8]>) -> Vec<u8> {
+      let mut result = query.to_vec();
+      result[2] |= 0x80; // a response
+      result[3] = 0x80 | rcode; // recursion available
+      if let Some(exchange) = exchange {
+         result[7] = 1; // one answer
+         result.extend_from_slice(&[0xc0, 12]); // the name in the question
+         result.extend_from_slice(&[0, 15, 0, 1, 0, 0, 1, 0]); // MX, IN, TTL
+         result.extend_from_slice(&[0, (2 + exchange.len()) as u8, 0, 10]);
+         result.extend_from_slice(exchange);
+      }
+      result
+   }
+
+   /// Runs a name server that answers the given number of queries, MX queries for domains
+   /// starting with "mx" get an answer, "null" a null MX, "nx" NXDOMAIN.
+   fn server(queries: usize) -> SocketAddr {
+      let socket = UdpSocket::bind("127.0.0.1:0").expect("cannot bind");
+      let addr = socket.local_addr().unwrap();
+      thread::spawn(move || {
+         let mut buf = [0; 512];
+         for _ in 0..queries {
+            let (n, from) = socket.recv_from(&mut buf).unwrap();
+            let query = &buf[..n];
+            let response = match &query[13..15] {
+               b"mx" => answer(query, 0, Some(b"\x04mail\x07example\x00")),
+               b"nu" => answer(query, 0, Some(b"\x00")),
+               b"nx" => answer(query, 3, None),
+               _ => answer(query, 0, None),
+            };
+            socket.send_to(&response, from).unwrap();
+         }
+      });
+      addr
+   }
+
+   #[test]
+   fn verify_domain_verdicts() {
+      let servers = [server(4)];
+      assert_eq!(
+         Ok(Verdict::Deliverable),
+         verify_domain("mx.example.com", &servers)
+      );
+      assert_eq!(
+         Ok(Verdict::Undeliverable(String::from(
+            "null.example.com accepts no email (null MX)"
+         ))),
+         verify_domain("null.example.com", &servers)
+      );
+      assert_eq!(
+         Ok(Verdict::Undeliverable(String::from(
+            "nx.example.com does not exist"
+         ))),
+         verify_domain("nx.example.com", &servers)
+      );
+      // no MX records, the A/AAAA fallback finds localhost
+      assert_eq!(
+         Ok(Verdict::Deliverable),
+         verify_domain("localhost", &servers)
+      );
+   }
+
+   #[test]
+   fn verify_recipients_looks_up_domains_once() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.recipients = vec![
+         testing::recipient("jd@mx.example.com", "John Doe", &[]),
+         testing::recipient("mm@nx.example.com", "Mickey Mouse", &[]),
+         testing::recipient("dd@NX.example.com", "Daisy Duck", &[]),
+      ];
+      // the server only answers two queries
+      let result = verify_recipients(&cfg, &[server(2)]).expect("lookup failed");
+      assert_eq!(
+         vec![
+            (
+               String::from("mm@nx.example.com"),
+               String::from("nx.example.com does not exist")
+            ),
+            (
+               String::from("dd@NX.example.com"),
+               String::from("nx.example.com does not exist")
+            ),
+         ],
+         result
+      );
+   }
+
+   #[test]
+   fn parse_resolv_conf_nameservers() {
+      let content = "# comment\nnameserver 127.0.0.53\nsearch example.com\nnameserver ::1\n";
+      assert_eq!(
+         vec![
+            "127.0.0.53:53".parse::<SocketAddr>().unwrap(),
+            "[::1]:53".parse::<SocketAddr>().unwrap()
+         ],
+         parse_resolv_conf(content)
+      );
+   }
+
+   #[test]
+   fn unreachable_name_server() {
+      assert!(verify_domain("example.com", &[]).is_err());
+   }
+}
@@ -0,0 +1,330 @@
+/// The `doctor` module checks whether the configured SMTP server is usable without sending any
+/// email.
+///
+/// The checks mirror what happens when a campaign is sent: the host name is resolved, the
+/// connection is established and secured, the credentials are verified and the server is asked
+/// whether it accepts the *From* address. The transaction is reset before any recipient is named.
+use crate::config::{Security, Smtp};
+use crate::encoding;
+use crate::smtp::Connection;
+use crate::transport;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+#[derive(Debug)]
+/// The `Check` struct holds the outcome of a single diagnostic step.
+pub struct Check {
+   /// What was checked e.g. `connect`
+   pub name: String,
+   /// The details on success, the problem otherwise
+   pub result: Result<String, String>,
+   /// What to do about a failure (if anything comes to mind)
+   pub hint: Option<String>,
+}
+
+impl PartialEq for Check {
+   /// Makes it possible to compare instances of `Check`
+   fn eq(&self, other: &Self) -> bool {
+      self.name == other.name && self.result == other.result && self.hint == other.hint
+   }
+}
+
+impl Check {
+   fn ok(name: &str, details: String) -> Check {
+      Check {
+         name: name.to_string(),
+         result: Ok(details),
+         hint: None,
+      }
+   }
+
+   fn failed(name: &str, problem: String, hint: &str) -> Check {
+      Check {
+         name: name.to_string(),
+         result: Err(problem),
+         hint: Some(hint.to_string()),
+      }
+   }
+}
+
+/// Runs the checks against the SMTP server, `from` is the sender address of the campaign.
+///
+/// The checks stop at the first failure, the last check returned is the one that failed.
+pub fn diagnose(smtp: &Smtp, from: &str) -> Vec<Check> {
+   let mut result = vec![];
+   let addr: SocketAddr = match (smtp.host.as_ref(), smtp.port)
+      .to_socket_addrs()
+      .map(|mut addrs| addrs.next())
+   {
+      Ok(Some(addr)) => {
+         result.push(Check::ok("resolve", format!("{} is {}", smtp.host, addr)));
+         addr
+      }
+      Ok(None) => {
+         result.push(Check::failed(
+            "resolve",
+            format!("{} has no address", smtp.host),
+            "check the *host* setting in the *smtp* section",
+         ));
+         return result;
+      }
+      Err(e) => {
+         result.push(Check::failed(
+            "resolve",
+            format!("cannot resolve {} ({})", smtp.host, e),
+            "check the *host* setting in the *smtp* section and your DNS set up",
+         ));
+         return result;
+      }
+   };
+   transport::runtime().block_on(session(smtp, addr, from, &mut result));
+   result
+}
+
+/// Runs the checks of an SMTP session with the server at `addr`, until the first failure.
+async fn session(smtp: &Smtp, addr: SocketAddr, from: &str, result: &mut Vec<Check>) {
+   let mut conn = match Connection::connect(&smtp.host, addr, &smtp.security).await {
+      Ok((conn, greeting)) => {
+         result.push(Check::ok("connect", greeting.describe()));
+         conn
+      }
+      Err(e) => {
+         result.push(Check::failed(
+            "connect",
+            e,
+            "check the *port* and *security* settings (465: tls, 587: starttls) and whether a \
+             firewall blocks the connection",
+         ));
+         return;
+      }
+   };
+   if let Err(e) = conn.hello().await {
+      result.push(Check::failed(
+         "ehlo",
+         e,
+         "the server does not speak ESMTP, check the *port* setting",
+      ));
+      return;
+   }
+   if smtp.security == Security::StartTls {
+      if !conn.ehlo().offers("STARTTLS") {
+         result.push(Check::failed(
+            "tls",
+            String::from("the server does not offer STARTTLS"),
+            "use `security=tls` (usually port 465) if the server expects TLS from the start",
+         ));
+         return;
+      }
+      match conn.starttls(&smtp.host).await {
+         Ok(c) => conn = c,
+         Err(e) => {
+            result.push(Check::failed(
+               "tls",
+               format!("STARTTLS failed ({})", e),
+               "check that the *host* setting matches the name in the server certificate",
+            ));
+            return;
+         }
+      }
+   }
+   if conn.is_encrypted() {
+      result.push(Check::ok(
+         "tls",
+         String::from("the connection is encrypted"),
+      ));
+   }
+   if smtp.smtputf8 {
+      if !conn.ehlo().offers("SMTPUTF8") {
+         result.push(Check::failed(
+            "smtputf8",
+            String::from("the server does not offer SMTPUTF8"),
+            "remove the *smtputf8* setting, internationalized domains are then converted to \
+             punycode",
+         ));
+         return;
+      }
+      result.push(Check::ok(
+         "smtputf8",
+         String::from("the server offers SMTPUTF8"),
+      ));
+   }
+   if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+      if !conn.is_encrypted() {
+         result.push(Check::failed(
+            "auth",
+            String::from("credentials are never sent over an unencrypted connection"),
+            "use `security=tls` or `security=starttls`",
+         ));
+         return;
+      }
+      let mechanisms = conn.ehlo().params("AUTH");
+      if !mechanisms.iter().any(|m| m == "PLAIN" || m == "LOGIN") {
+         result.push(Check::failed(
+            "auth",
+            String::from("the server offers neither AUTH PLAIN nor AUTH LOGIN"),
+            "check whether the server expects authentication at all",
+         ));
+         return;
+      }
+      match conn.authenticate(username, password).await {
+         Ok(r) => result.push(Check::ok(
+            "auth",
+            format!("authenticated as {} ({})", username, r.describe()),
+         )),
+         Err(e) => {
+            result.push(Check::failed(
+               "auth",
+               format!("authentication as {} failed ({})", username, e),
+               "check the *username* and *password* settings in the *smtp* section",
+            ));
+            return;
+         }
+      }
+   }
+   let sender = encoding::split_address(from).1;
+   match conn.command(&format!("MAIL FROM:<{}>", sender), 250).await {
+      Ok(r) => result.push(Check::ok(
+         "sender",
+         format!("{} is accepted ({})", sender, r.describe()),
+      )),
+      Err(e) => result.push(Check::failed(
+         "sender",
+         format!("{} is not accepted ({})", sender, e),
+         "check that the *From* address may be used with the configured account",
+      )),
+   }
+   let _ = conn.command("RSET", 250).await;
+   conn.quit().await;
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::collections::HashMap;
+   use std::io::{BufRead, BufReader, Write};
+   use std::net::TcpListener;
+   use std::thread;
+
+   /// Runs a minimal SMTP server that accepts a single connection and answers `MAIL` with the
+   /// given reply, returns the port and the handle for the commands received.
+   fn server(mail_reply: &'static str) -> (u16, thread::JoinHandle<Vec<String>>) {
+      let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+      let port = listener.local_addr().unwrap().port();
+      let handle = thread::spawn(move || {
+         let (stream, _) = listener.accept().expect("no connection");
+         let mut writer = stream.try_clone().unwrap();
+         let mut commands = vec![];
+         writer.write_all(b"220 test ESMTP\r\n").unwrap();
+         for line in BufReader::new(stream).lines() {
+            let line = line.unwrap();
+            let reply = match line.split(' ').next().unwrap_or_default() {
+               "EHLO" => "250-test\r\n250 SIZE 1000000\r\n",
+               "MAIL" => mail_reply,
+               "QUIT" => "221 bye\r\n",
+               _ => "250 ok\r\n",
+            };
+            writer.write_all(reply.as_bytes()).unwrap();
+            commands.push(line);
+            if commands.last().map(|c| c.as_str()) == Some("QUIT") {
+               break;
+            }
+         }
+         commands
+      });
+      (port, handle)
+   }
+
+   fn smtp(port: u16, username: Option<&str>) -> Smtp {
+      Smtp {
+         host: String::from("127.0.0.1"),
+         port,
+         security: Security::None,
+         username: username.map(|u| u.to_string()),
+         password: username.map(|_| String::from("secret")),
+         keyring: None,
+         rate: None,
+         domain_rates: HashMap::new(),
+         jitter: None,
+         group_by_domain: false,
+         domain_burst: None,
+         connections: 1,
+         dsn_notify: None,
+         dsn_ret: None,
+         smtputf8: false,
+      }
+   }
+
+   #[test]
+   fn diagnose_happy_case() {
+      let (port, server) = server("250 2.1.0 ok\r\n");
+      let checks = diagnose(&smtp(port, None), "Frodo Baggins <fb@example.com>");
+      let names: Vec<&str> = checks.iter().map(|c| c.name.as_ref()).collect();
+      assert_eq!(vec!["resolve", "connect", "sender"], names);
+      assert!(checks.iter().all(|c| c.result.is_ok()));
+      assert_eq!(
+         Ok(String::from("fb@example.com is accepted (250 2.1.0 ok)")),
+         checks[2].result
+      );
+      let commands = server.join().unwrap();
+      assert_eq!("MAIL FROM:<fb@example.com>", commands[1]);
+      // the transaction is reset and no recipient is ever named
+      assert_eq!(vec!["RSET", "QUIT"], commands[2..].to_vec());
+   }
+
+   #[test]
+   fn diagnose_rejected_sender() {
+      let (port, server) = server("553 5.7.1 sender not allowed\r\n");
+      let checks = diagnose(&smtp(port, None), "fb@example.com");
+      let last = checks.last().unwrap();
+      assert_eq!("sender", last.name);
+      assert_eq!(
+         Err(String::from(
+            "fb@example.com is not accepted (MAIL failed (553 5.7.1 sender not allowed))"
+         )),
+         last.result
+      );
+      assert!(last.hint.is_some());
+      server.join().unwrap();
+   }
+
+   #[test]
+   fn diagnose_without_starttls() {
+      let (port, server) = server("250 ok\r\n");
+      let mut smtp = smtp(port, None);
+      smtp.security = Security::StartTls;
+      let checks = diagnose(&smtp, "fb@example.com");
+      let last = checks.last().unwrap();
+      assert_eq!("tls", last.name);
+      assert_eq!(
+         Err(String::from("the server does not offer STARTTLS")),
+         last.result
+      );
+      server.join().unwrap();
+   }
+
+   #[test]
+   fn diagnose_without_smtputf8() {
+      let (port, server) = server("250 ok\r\n");
+      let mut smtp = smtp(port, None);
+      smtp.smtputf8 = true;
+      let checks = diagnose(&smtp, "fb@example.com");
+      let last = checks.last().unwrap();
+      assert_eq!("smtputf8", last.name);
+      assert!(last.result.is_err());
+      server.join().unwrap();
+   }
+
+   #[test]
+   fn diagnose_refuses_plain_text_credentials() {
+      let (port, server) = server("250 ok\r\n");
+      let checks = diagnose(&smtp(port, Some("frodo")), "fb@example.com");
+      let last = checks.last().unwrap();
+      assert_eq!("auth", last.name);
+      assert_eq!(
+         Err(String::from(
+            "credentials are never sent over an unencrypted connection"
+         )),
+         last.result
+      );
+      server.join().unwrap();
+   }
+}
@@ -0,0 +1,396 @@
+/// The `encoding` module implements the encodings needed to put non-ASCII text into emails.
+use base64::Engine;
+use deunicode::deunicode;
+
+/// The maximum length of an RFC 2047 encoded word.
+const MAX_ENCODED_WORD_LEN: usize = 75;
+
+/// The maximum length of a line of quoted-printable or base64 encoded text (RFC 2045).
+const MAX_ENCODED_LINE_LEN: usize = 76;
+
+/// The maximum length of a line in an email, not counting the CRLF (RFC 5322, section 2.1.1).
+const MAX_LINE_LEN: usize = 998;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `TransferEncoding` enum defines how the body parts of an email are encoded for transport
+/// (the `Content-Transfer-Encoding`).
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransferEncoding {
+   /// ASCII text with reasonably short lines is sent as is (7bit), other text is encoded with
+   /// quoted-printable or, if it's mostly non-ASCII, base64 (the default)
+   #[default]
+   Auto,
+   /// Quoted-printable, readable for text that is mostly ASCII
+   QuotedPrintable,
+   /// Base64
+   Base64,
+   /// The text is sent as is, this requires a server that supports 8BITMIME
+   EightBit,
+}
+
+/// Encodes the text of a body part, returns the value of the `Content-Transfer-Encoding` header
+/// and the encoded text.
+pub fn encode_body(text: &str, encoding: TransferEncoding) -> (&'static str, String) {
+   let encoding = match encoding {
+      TransferEncoding::Auto => {
+         let non_ascii = text.chars().filter(|c| !c.is_ascii()).count();
+         if non_ascii == 0 && text.lines().all(|l| l.len() <= MAX_LINE_LEN) {
+            return ("7bit", text.to_string());
+         }
+         if non_ascii * 3 > text.chars().count() {
+            TransferEncoding::Base64
+         } else {
+            TransferEncoding::QuotedPrintable
+         }
+      }
+      e => e,
+   };
+   match encoding {
+      TransferEncoding::QuotedPrintable => ("quoted-printable", quoted_printable(text)),
+      // the line breaks of text are CRLF on the wire, they need to be encoded that way
+      TransferEncoding::Base64 => (
+         "base64",
+         base64(text.replace("\r\n", "\n").replace('\n', "\r\n").as_bytes()),
+      ),
+      _ => ("8bit", text.to_string()),
+   }
+}
+
+/// Encodes the text with the quoted-printable encoding (RFC 2045, section 6.7), the line breaks
+/// of the text are kept.
+pub fn quoted_printable(text: &str) -> String {
+   let mut lines = vec![];
+   for line in text.split('\n') {
+      let line = line.strip_suffix('\r').unwrap_or(line);
+      let bytes = line.as_bytes();
+      let mut encoded = String::new();
+      let mut len = 0;
+      for (i, b) in bytes.iter().enumerate() {
+         let last = i + 1 == bytes.len();
+         let chunk = match *b {
+            // trailing white space would be removed in transit
+            b' ' | b'\t' if !last => char::from(*b).to_string(),
+            33..=60 | 62..=126 => char::from(*b).to_string(),
+            _ => format!("={:02X}", b),
+         };
+         // a soft line break, the "=" counts towards the line length
+         if len + chunk.len() > MAX_ENCODED_LINE_LEN - 1
+            && !(last && len + chunk.len() <= MAX_ENCODED_LINE_LEN)
+         {
+            encoded.push_str("=\n");
+            len = 0;
+         }
+         encoded.push_str(&chunk);
+         len += chunk.len();
+      }
+      lines.push(encoded);
+   }
+   lines.join("\n")
+}
+
+/// Encodes the data with base64, in lines of 76 characters.
+pub fn base64(data: &[u8]) -> String {
+   let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+   encoded
+      .as_bytes()
+      .chunks(MAX_ENCODED_LINE_LEN)
+      .map(|c| String::from_utf8_lossy(c).into_owned())
+      .collect::<Vec<String>>()
+      .join("\n")
+}
+
+/// Encodes the given text as one or more RFC 2047 encoded words (using the "Q" encoding) if it
+/// contains non-ASCII characters. Pure ASCII text is returned as is.
+pub fn encode_word(text: &str) -> String {
+   if text.is_ascii() {
+      return text.to_string();
+   }
+   let prefix = "=?utf-8?Q?";
+   let suffix = "?=";
+   let max_len = MAX_ENCODED_WORD_LEN - prefix.len() - suffix.len();
+   let mut words = vec![];
+   let mut word = String::new();
+   let mut buf = [0; 4];
+   for c in text.chars() {
+      let encoded: String = match c {
+         ' ' => String::from("_"),
+         'A'..='Z' | 'a'..='z' | '0'..='9' | '!' | '*' | '+' | '-' | '/' => c.to_string(),
+         _ => c
+            .encode_utf8(&mut buf)
+            .bytes()
+            .map(|b| format!("={:02X}", b))
+            .collect(),
+      };
+      // never split the encoding of a character across encoded words
+      if word.len() + encoded.len() > max_len {
+         words.push(format!("{}{}{}", prefix, word, suffix));
+         word.clear();
+      }
+      word.push_str(&encoded);
+   }
+   if !word.is_empty() {
+      words.push(format!("{}{}{}", prefix, word, suffix));
+   }
+   words.join(" ")
+}
+
+/// Transliterates the given text to ASCII e.g. `Müller` becomes `Mueller`.
+///
+/// The german umlauts and the sharp s are spelled out the way it's done in german, everything
+/// else is transliterated by stripping diacritics and the like.
+pub fn transliterate(text: &str) -> String {
+   let mut result = String::with_capacity(text.len());
+   for c in text.chars() {
+      match c {
+         'ä' => result.push_str("ae"),
+         'ö' => result.push_str("oe"),
+         'ü' => result.push_str("ue"),
+         'Ä' => result.push_str("Ae"),
+         'Ö' => result.push_str("Oe"),
+         'Ü' => result.push_str("Ue"),
+         'ß' => result.push_str("ss"),
+         _ if c.is_ascii() => result.push(c),
+         _ => result.push_str(&deunicode(&c.to_string())),
+      }
+   }
+   result
+}
+
+/// Splits an email address into its (unquoted) display name and the actual address.
+///
+/// Addresses in short form have an empty display name.
+pub fn split_address(address: &str) -> (String, String) {
+   let address = address.trim();
+   match (address.rfind('<'), address.rfind('>')) {
+      (Some(start), Some(end)) if start < end => {
+         let name = address[..start].trim();
+         let name = if name.len() > 1 && name.starts_with('"') && name.ends_with('"') {
+            name[1..name.len() - 1]
+               .replace("\\\"", "\"")
+               .replace("\\\\", "\\")
+         } else {
+            name.to_string()
+         };
+         (name, address[start + 1..end].trim().to_string())
+      }
+      _ => (String::new(), address.to_string()),
+   }
+}
+
+/// Quotes a display name if it contains characters that are special in email headers.
+fn quote_name(name: &str) -> String {
+   let specials = "()<>[]:;@\\,.\"";
+   if name.chars().any(|c| specials.contains(c)) {
+      format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+   } else {
+      name.to_string()
+   }
+}
+
+/// Formats an email address for use in an address header (*From*, *To*, *Cc* etc.)
+///
+/// Display names with non-ASCII characters are RFC 2047 encoded or, if `transliterate` is set,
+/// transliterated to ASCII. Addresses with ASCII-only display names are left as they are.
+pub fn encode_address(address: &str, transliterate: bool) -> String {
+   let (name, email) = split_address(address);
+   if name.is_ascii() {
+      return address.trim().to_string();
+   }
+   if transliterate {
+      format!("{} <{}>", quote_name(&self::transliterate(&name)), email)
+   } else {
+      format!("{} <{}>", encode_word(&name), email)
+   }
+}
+
+/// Builds an address from a display name and an email address.
+pub fn mailbox(name: &str, email: &str) -> String {
+   if name.is_empty() {
+      email.to_string()
+   } else {
+      format!("{} <{}>", quote_name(name), email)
+   }
+}
+
+/// Percent-encodes the given text for use in a URL query, only the unreserved characters of
+/// RFC 3986 are left as they are.
+pub fn percent_encode(text: &str) -> String {
+   let mut result = String::with_capacity(text.len());
+   for b in text.bytes() {
+      match b {
+         b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+            result.push(char::from(b))
+         }
+         _ => result.push_str(&format!("%{:02X}", b)),
+      }
+   }
+   result
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn encode_word_with_ascii_text() {
+      assert_eq!("Frodo Baggins", encode_word("Frodo Baggins"));
+   }
+
+   #[test]
+   fn encode_word_with_non_ascii_text() {
+      assert_eq!(
+         "=?utf-8?Q?J=C3=BCrgen_M=C3=BCller?=",
+         encode_word("Jürgen Müller")
+      );
+   }
+
+   #[test]
+   fn encode_word_with_long_text() {
+      let encoded = encode_word(&"ü".repeat(30));
+      let words: Vec<&str> = encoded.split(' ').collect();
+      assert_eq!(3, words.len());
+      for word in words {
+         assert!(word.len() <= MAX_ENCODED_WORD_LEN);
+         assert!(word.starts_with("=?utf-8?Q?") && word.ends_with("?="));
+         // the encoding of a character is never split
+         assert_eq!(0, (word.len() - 12) % 6);
+      }
+   }
+
+   #[test]
+   fn encode_body_auto() {
+      assert_eq!(
+         ("7bit", String::from("Hi!\n")),
+         encode_body("Hi!\n", TransferEncoding::Auto)
+      );
+      let long = "a".repeat(1000);
+      assert_eq!(
+         "quoted-printable",
+         encode_body(&long, TransferEncoding::Auto).0
+      );
+      assert_eq!(
+         (
+            "quoted-printable",
+            String::from("Sch=C3=B6ne Gr=C3=BC=C3=9Fe")
+         ),
+         encode_body("Schöne Grüße", TransferEncoding::Auto)
+      );
+      assert_eq!("base64", encode_body("Привет", TransferEncoding::Auto).0);
+      assert_eq!(
+         ("8bit", String::from("Grüße")),
+         encode_body("Grüße", TransferEncoding::EightBit)
+      );
+   }
+
+   #[test]
+   fn quoted_printable_lines() {
+      assert_eq!(
+         "a=3Db \t=09\nline two=20",
+         quoted_printable("a=b \t\t\r\nline two ")
+      );
+      let encoded = quoted_printable(&format!("{}ü", "x".repeat(80)));
+      let lines: Vec<&str> = encoded.split('\n').collect();
+      assert_eq!(2, lines.len());
+      assert!(lines.iter().all(|l| l.len() <= MAX_ENCODED_LINE_LEN));
+      assert!(lines[0].ends_with('='));
+      assert_eq!(format!("{}=C3=BC", "x".repeat(5)), lines[1]);
+   }
+
+   #[test]
+   fn base64_lines() {
+      assert_eq!("SGk=", base64(b"Hi"));
+      let encoded = base64(&[0; 100]);
+      let lines: Vec<&str> = encoded.split('\n').collect();
+      assert_eq!(
+         vec![76, 60],
+         lines.iter().map(|l| l.len()).collect::<Vec<usize>>()
+      );
+      assert_eq!(
+         ("base64", String::from("YQ0KYg==")),
+         encode_body("a\nb", TransferEncoding::Base64)
+      );
+   }
+
+   #[test]
+   fn transliterate_german() {
+      assert_eq!("Mueller Strasse AeOeUe", transliterate("Müller Straße ÄÖÜ"));
+   }
+
+   #[test]
+   fn transliterate_other_diacritics() {
+      assert_eq!("Francois Dvorak", transliterate("François Dvořák"));
+   }
+
+   #[test]
+   fn split_address_forms() {
+      assert_eq!(
+         (
+            String::from("Frodo Baggins"),
+            String::from("fb@example.com")
+         ),
+         split_address(r#""Frodo Baggins" <fb@example.com>"#)
+      );
+      assert_eq!(
+         (
+            String::from("Frodo Baggins"),
+            String::from("fb@example.com")
+         ),
+         split_address("Frodo Baggins <fb@example.com>")
+      );
+      assert_eq!(
+         (String::new(), String::from("fb@example.com")),
+         split_address("  fb@example.com ")
+      );
+   }
+
+   #[test]
+   fn encode_address_with_ascii_name() {
+      assert_eq!(
+         r#""Frodo Baggins" <fb@example.com>"#,
+         encode_address(r#""Frodo Baggins" <fb@example.com>"#, false)
+      );
+   }
+
+   #[test]
+   fn encode_address_with_non_ascii_name() {
+      assert_eq!(
+         "=?utf-8?Q?J=C3=BCrgen_M=C3=BCller?= <jm@example.com>",
+         encode_address(r#""Jürgen Müller" <jm@example.com>"#, false)
+      );
+   }
+
+   #[test]
+   fn encode_address_with_transliteration() {
+      assert_eq!(
+         "Juergen Mueller <jm@example.com>",
+         encode_address("Jürgen Müller <jm@example.com>", true)
+      );
+      assert_eq!(
+         r#""Mueller, Juergen" <jm@example.com>"#,
+         encode_address(r#""Müller, Jürgen" <jm@example.com>"#, true)
+      );
+   }
+
+   #[test]
+   fn percent_encode_reserved_and_non_ascii() {
+      assert_eq!("a-b_c.d~e", percent_encode("a-b_c.d~e"));
+      assert_eq!(
+         "https%3A%2F%2Fex.org%2F%3Fa%3D1%26b%3D2",
+         percent_encode("https://ex.org/?a=1&b=2")
+      );
+      assert_eq!("M%C3%BCller%20%26%20Co", percent_encode("Müller & Co"));
+   }
+
+   #[test]
+   fn mailbox_quotes_specials() {
+      assert_eq!(
+         "John Doe <jd@example.com>",
+         mailbox("John Doe", "jd@example.com")
+      );
+      assert_eq!(
+         r#""John Doe Jr." <jd@example.com>"#,
+         mailbox("John Doe Jr.", "jd@example.com")
+      );
+      assert_eq!("jd@example.com", mailbox("", "jd@example.com"));
+   }
+}
@@ -0,0 +1,470 @@
+/// The `events` module defines the events emitted while a campaign is sent and the observers
+/// that are notified of them.
+use log::{debug, warn};
+use native_tls::TlsConnector;
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+/// The `Event` enum lists the events in the life cycle of a campaign send.
+pub enum Event {
+   /// Sending the campaign started
+   CampaignStarted {
+      campaign_id: String,
+      recipients: usize,
+   },
+   /// An email was sent successfully
+   MessageSent {
+      campaign_id: String,
+      recipient: String,
+      message_id: String,
+      subject: String,
+      variant: Option<String>,
+   },
+   /// Sending an email failed
+   MessageFailed {
+      campaign_id: String,
+      recipient: String,
+      message_id: String,
+      subject: String,
+      variant: Option<String>,
+      error: String,
+   },
+   /// Sending the campaign finished
+   CampaignFinished {
+      campaign_id: String,
+      sent: usize,
+      failed: usize,
+   },
+}
+
+impl Event {
+   /// Returns the name of the event as used in the JSON representation.
+   pub fn name(&self) -> &'static str {
+      match self {
+         Event::CampaignStarted { .. } => "campaign_started",
+         Event::MessageSent { .. } => "message_sent",
+         Event::MessageFailed { .. } => "message_failed",
+         Event::CampaignFinished { .. } => "campaign_finished",
+      }
+   }
+
+   /// Returns the JSON representation of the event, `timestamp` is in seconds since the epoch.
+   pub fn to_json(&self, timestamp: u64) -> Value {
+      let mut result = match self {
+         Event::CampaignStarted {
+            campaign_id,
+            recipients,
+         } => json!({"campaign_id": campaign_id, "recipients": recipients}),
+         Event::MessageSent {
+            campaign_id,
+            recipient,
+            message_id,
+            subject,
+            variant,
+         } => json!({
+            "campaign_id": campaign_id,
+            "recipient": recipient,
+            "message_id": message_id,
+            "subject": subject,
+            "variant": variant,
+         }),
+         Event::MessageFailed {
+            campaign_id,
+            recipient,
+            message_id,
+            subject,
+            variant,
+            error,
+         } => json!({
+            "campaign_id": campaign_id,
+            "recipient": recipient,
+            "message_id": message_id,
+            "subject": subject,
+            "variant": variant,
+            "error": error,
+         }),
+         Event::CampaignFinished {
+            campaign_id,
+            sent,
+            failed,
+         } => json!({"campaign_id": campaign_id, "sent": sent, "failed": failed}),
+      };
+      result["event"] = json!(self.name());
+      result["timestamp"] = json!(timestamp);
+      result
+   }
+}
+
+/// The `Observer` trait is implemented by everything that wants to be notified of the events
+/// of a campaign send.
+pub trait Observer {
+   /// Handles the event, observers must not abort the campaign.
+   fn notify(&mut self, event: &Event);
+}
+
+/// A list of observers notifies all of them in order.
+impl Observer for Vec<Box<dyn Observer>> {
+   fn notify(&mut self, event: &Event) {
+      for observer in self.iter_mut() {
+         observer.notify(event);
+      }
+   }
+}
+
+/// A list of events records all events it is notified of.
+impl Observer for Vec<Event> {
+   fn notify(&mut self, event: &Event) {
+      self.push(event.clone());
+   }
+}
+
+/// Returns the current time in seconds since the epoch.
+pub fn now() -> u64 {
+   SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_secs())
+      .unwrap_or(0)
+}
+
+/// The number of events queued for the webhook, more per-recipient events are dropped while the
+/// queue is full.
+const WEBHOOK_QUEUE: usize = 1024;
+
+/// How long the queued events may take to be posted once the campaign is done.
+const WEBHOOK_FLUSH: Duration = Duration::from_secs(10);
+
+/// The `Webhook` struct POSTs the events as JSON documents to a URL, see `Webhook::queue` for the
+/// observer.
+pub struct Webhook {
+   url: String,
+   agent: ureq::Agent,
+}
+
+impl Webhook {
+   /// Sets up the webhook for the given URL.
+   pub fn new(url: &str) -> Result<Webhook, String> {
+      let connector = TlsConnector::new().map_err(|e| format!("TLS set up failed ({})", e))?;
+      let agent = ureq::AgentBuilder::new()
+         .tls_connector(Arc::new(connector))
+         .timeout(Duration::from_secs(10))
+         .build();
+      Ok(Webhook {
+         url: url.to_string(),
+         agent,
+      })
+   }
+
+   /// POSTs the event to the webhook URL.
+   pub fn post(&self, event: &Event) -> Result<(), String> {
+      debug!("webhook: posting {} to {}", event.name(), self.url);
+      self
+         .agent
+         .post(&self.url)
+         .set("Content-Type", "application/json")
+         .send_string(&event.to_json(now()).to_string())
+         .map(|_| ())
+         .map_err(|e| e.to_string())
+   }
+
+   /// Returns the observer that POSTs the events from a thread of its own, see `WebhookQueue`.
+   pub fn queue(self) -> Result<WebhookQueue, String> {
+      self.queue_of(WEBHOOK_QUEUE)
+   }
+
+   /// Returns the observer queuing up to `capacity` events.
+   fn queue_of(self, capacity: usize) -> Result<WebhookQueue, String> {
+      let (sender, receiver) = mpsc::sync_channel::<Event>(capacity);
+      let (done, finished) = mpsc::channel();
+      thread::Builder::new()
+         .name(String::from("rmt-webhook"))
+         .spawn(move || {
+            for event in receiver {
+               if let Err(err) = self.post(&event) {
+                  warn!("webhook notification ({}) failed ({})", event.name(), err);
+               }
+            }
+            let _ = done.send(());
+         })
+         .map_err(|e| format!("cannot start the webhook thread ({})", e))?;
+      Ok(WebhookQueue {
+         sender: Some(sender),
+         finished,
+         dropped: 0,
+      })
+   }
+}
+
+/// The `WebhookQueue` observer hands the events to the thread that POSTs them to the webhook, a
+/// slow webhook does not hold up the campaign. The per-recipient events that do not fit into the
+/// (bounded) queue are dropped, the `campaign_started` and `campaign_finished` events wait for
+/// room instead. The queued events are posted when the observer is dropped, for a little while.
+pub struct WebhookQueue {
+   sender: Option<SyncSender<Event>>,
+   /// Notified once the thread has posted all events
+   finished: Receiver<()>,
+   /// The number of events dropped because the queue was full
+   dropped: usize,
+}
+
+impl Observer for WebhookQueue {
+   /// Queues the event, a failure is reported but does not affect the campaign.
+   fn notify(&mut self, event: &Event) {
+      let sender = self.sender.as_ref().unwrap();
+      if let Event::CampaignStarted { .. } | Event::CampaignFinished { .. } = event {
+         if sender.send(event.clone()).is_err() {
+            warn!(
+               "webhook notification ({}) failed (no webhook thread)",
+               event.name()
+            )
+         }
+         return;
+      }
+      match sender.try_send(event.clone()) {
+         Ok(()) => (),
+         Err(TrySendError::Full(event)) => {
+            if self.dropped == 0 {
+               warn!(
+                  "webhook notification ({}) dropped, the webhook does not keep up",
+                  event.name()
+               );
+            }
+            self.dropped += 1;
+         }
+         Err(TrySendError::Disconnected(event)) => {
+            warn!(
+               "webhook notification ({}) failed (no webhook thread)",
+               event.name()
+            )
+         }
+      }
+   }
+}
+
+impl Drop for WebhookQueue {
+   /// Waits (for a while) until the queued events are posted.
+   fn drop(&mut self) {
+      drop(self.sender.take());
+      if self.finished.recv_timeout(WEBHOOK_FLUSH).is_err() {
+         warn!("webhook: giving up on the events not posted yet");
+      }
+      if self.dropped > 0 {
+         warn!(
+            "webhook: {} notification(s) dropped, the webhook did not keep up",
+            self.dropped
+         );
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::cell::Cell;
+   use std::io::{Read, Write};
+   use std::net::TcpListener;
+   use std::rc::Rc;
+   use std::thread;
+
+   #[test]
+   fn event_to_json() {
+      let event = Event::MessageFailed {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("jd@example.com"),
+         message_id: String::from("<spring-sale.1@example.com>"),
+         subject: String::from("Hello John!"),
+         variant: None,
+         error: String::from("mailbox full"),
+      };
+      let expected = json!({
+         "event": "message_failed",
+         "campaign_id": "spring-sale",
+         "recipient": "jd@example.com",
+         "message_id": "<spring-sale.1@example.com>",
+         "subject": "Hello John!",
+         "variant": null,
+         "error": "mailbox full",
+         "timestamp": 1_600_000_000,
+      });
+      assert_eq!(expected, event.to_json(1_600_000_000));
+   }
+
+   #[test]
+   fn observer_list_notifies_all() {
+      struct Counter(Rc<Cell<usize>>);
+      impl Observer for Counter {
+         fn notify(&mut self, _event: &Event) {
+            self.0.set(self.0.get() + 1);
+         }
+      }
+      let count = Rc::new(Cell::new(0));
+      let mut observers: Vec<Box<dyn Observer>> = vec![
+         Box::new(Counter(count.clone())),
+         Box::new(Counter(count.clone())),
+      ];
+      observers.notify(&Event::CampaignStarted {
+         campaign_id: String::from("spring-sale"),
+         recipients: 3,
+      });
+      assert_eq!(2, count.get());
+   }
+
+   #[test]
+   fn webhook_posts_json() {
+      let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+      let url = format!("http://{}/hook", listener.local_addr().unwrap());
+      let server = thread::spawn(move || {
+         let (mut stream, _) = listener.accept().expect("no connection");
+         let mut request = vec![];
+         let mut buf = [0; 1024];
+         // read until the JSON body is complete
+         while !request.ends_with(b"}") {
+            let n = stream.read(&mut buf).expect("read failed");
+            if n == 0 {
+               break;
+            }
+            request.extend_from_slice(&buf[..n]);
+         }
+         stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+         String::from_utf8(request).unwrap()
+      });
+      let webhook = Webhook::new(&url).expect("webhook set up failed");
+      let event = Event::CampaignFinished {
+         campaign_id: String::from("spring-sale"),
+         sent: 2,
+         failed: 1,
+      };
+      assert_eq!(Ok(()), webhook.post(&event));
+      let request = server.join().unwrap();
+      assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+      assert!(request
+         .to_lowercase()
+         .contains("\r\ncontent-type: application/json\r\n"));
+      let body: Value =
+         serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).expect("invalid JSON");
+      assert_eq!("campaign_finished", body["event"]);
+      assert_eq!(2, body["sent"]);
+      assert_eq!(1, body["failed"]);
+   }
+
+   #[test]
+   fn webhook_queue_posts_in_background() {
+      let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+      let url = format!("http://{}/hook", listener.local_addr().unwrap());
+      let server = thread::spawn(move || {
+         let mut events = vec![];
+         for _ in 0..3 {
+            let (mut stream, _) = listener.accept().expect("no connection");
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"}") {
+               let n = stream.read(&mut buf).expect("read failed");
+               if n == 0 {
+                  break;
+               }
+               request.extend_from_slice(&buf[..n]);
+            }
+            // a slow webhook
+            thread::sleep(Duration::from_millis(100));
+            stream
+               .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+               .unwrap();
+            let request = String::from_utf8(request).unwrap();
+            let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap())
+               .expect("invalid JSON");
+            events.push(body["event"].as_str().unwrap().to_string());
+         }
+         events
+      });
+      let mut queue = Webhook::new(&url)
+         .and_then(|w| w.queue())
+         .expect("webhook set up failed");
+      let start = std::time::Instant::now();
+      queue.notify(&Event::CampaignStarted {
+         campaign_id: String::from("spring-sale"),
+         recipients: 1,
+      });
+      queue.notify(&Event::MessageSent {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("jd@example.com"),
+         message_id: String::from("<spring-sale.1@example.com>"),
+         subject: String::from("Hello John!"),
+         variant: None,
+      });
+      queue.notify(&Event::CampaignFinished {
+         campaign_id: String::from("spring-sale"),
+         sent: 1,
+         failed: 0,
+      });
+      assert!(start.elapsed() < Duration::from_millis(100));
+      // dropping the queue waits for the events to be posted
+      drop(queue);
+      assert_eq!(
+         vec!["campaign_started", "message_sent", "campaign_finished"],
+         server.join().unwrap()
+      );
+   }
+
+   #[test]
+   fn webhook_queue_keeps_lifecycle_events_when_full() {
+      let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+      let url = format!("http://{}/hook", listener.local_addr().unwrap());
+      let server = thread::spawn(move || {
+         let mut events: Vec<String> = vec![];
+         while events.last().map(|e| e.as_str()) != Some("campaign_finished") {
+            let (mut stream, _) = listener.accept().expect("no connection");
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"}") {
+               let n = stream.read(&mut buf).expect("read failed");
+               if n == 0 {
+                  break;
+               }
+               request.extend_from_slice(&buf[..n]);
+            }
+            // a slow webhook
+            thread::sleep(Duration::from_millis(50));
+            stream
+               .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+               .unwrap();
+            let request = String::from_utf8(request).unwrap();
+            let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap())
+               .expect("invalid JSON");
+            events.push(body["event"].as_str().unwrap().to_string());
+         }
+         events
+      });
+      let mut queue = Webhook::new(&url)
+         .and_then(|w| w.queue_of(1))
+         .expect("webhook set up failed");
+      queue.notify(&Event::CampaignStarted {
+         campaign_id: String::from("spring-sale"),
+         recipients: 5,
+      });
+      for i in 0..5 {
+         queue.notify(&Event::MessageSent {
+            campaign_id: String::from("spring-sale"),
+            recipient: format!("jd{}@example.com", i),
+            message_id: format!("<spring-sale.{}@example.com>", i),
+            subject: String::from("Hello John!"),
+            variant: None,
+         });
+      }
+      queue.notify(&Event::CampaignFinished {
+         campaign_id: String::from("spring-sale"),
+         sent: 5,
+         failed: 0,
+      });
+      assert!(queue.dropped > 0);
+      drop(queue);
+      let events = server.join().unwrap();
+      assert_eq!(Some("campaign_started"), events.first().map(|e| e.as_str()));
+      assert_eq!(Some("campaign_finished"), events.last().map(|e| e.as_str()));
+      assert!(events.len() < 7);
+   }
+}
@@ -0,0 +1,474 @@
+/// The `followup` module collects the responses to a past campaign from the mailbox: the replies
+/// per recipient and the bounces. The recipients who neither replied nor bounced are the
+/// audience of a follow-up campaign.
+///
+/// A reply is recognized by its `In-Reply-To` or `References` header pointing at the `Message-ID`
+/// of a campaign email, a bounce by the delivery status notification quoting one. Automatic
+/// replies (out of office), read receipts and successful delivery notifications do not count.
+use crate::config::Config;
+use crate::history;
+use crate::imap::{quote, Connection};
+use crate::listing;
+use crate::message;
+use chrono::DateTime;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+
+/// The header fields needed to tell replies from bounces.
+const HEADER_FIELDS: &str = "BODY.PEEK[HEADER.FIELDS (FROM DATE SUBJECT CONTENT-TYPE \
+                             AUTO-SUBMITTED IN-REPLY-TO REFERENCES)]";
+
+#[derive(Debug, PartialEq)]
+/// The `Kind` enum tells what a message referring to a campaign email is.
+enum Kind {
+   /// Written by the recipient
+   Reply,
+   /// A (possible) delivery failure notification
+   Bounce,
+   /// Anything else e.g. an out of office reply
+   Other,
+}
+
+#[derive(Debug, Clone)]
+/// The `Reply` struct holds a reply to a campaign email.
+pub struct Reply {
+   /// The sender of the reply
+   pub from: String,
+   /// The subject of the reply
+   pub subject: String,
+   /// When the reply was written (seconds since the epoch), if its date is valid
+   pub date: Option<u64>,
+}
+
+impl PartialEq for Reply {
+   /// Makes it possible to compare instances of `Reply`
+   fn eq(&self, other: &Self) -> bool {
+      self.from == other.from && self.subject == other.subject && self.date == other.date
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `Responses` struct holds the recipients who responded to a campaign.
+pub struct Responses {
+   /// The recipients who replied along with their replies
+   pub replied: BTreeMap<String, Vec<Reply>>,
+   /// The recipients the campaign email did not reach
+   pub bounced: BTreeSet<String>,
+}
+
+impl PartialEq for Responses {
+   /// Makes it possible to compare instances of `Responses`
+   fn eq(&self, other: &Self) -> bool {
+      self.replied == other.replied && self.bounced == other.bounced
+   }
+}
+
+/// Returns the `Message-ID`s of the emails sent in the given campaign run, mapped to their
+/// recipients. Records that lack the ids get them recomputed from the configuration.
+pub fn message_ids(record: &Value, cfg: &Config) -> HashMap<String, String> {
+   let campaign_id = record["campaign_id"].as_str().unwrap_or(&cfg.campaign_id);
+   let deliveries = record["deliveries"].as_array().cloned().unwrap_or_default();
+   deliveries
+      .iter()
+      .filter(|d| d["status"] == "sent")
+      .filter_map(|d| {
+         let recipient = d["recipient"].as_str()?;
+         let id = match d["message_id"].as_str() {
+            Some(id) => id.to_string(),
+            None => message::message_id(campaign_id, recipient, &cfg.msgid_domain),
+         };
+         Some((id, recipient.to_string()))
+      })
+      .collect()
+}
+
+/// Looks for the replies and bounces to the given campaign run in a folder of the mailbox.
+/// Deliveries that failed right away count as bounces too.
+pub fn scan(
+   conn: &mut Connection,
+   folder: &str,
+   record: &Value,
+   ids: &HashMap<String, String>,
+) -> Result<Responses, String> {
+   let mut result = Responses::default();
+   result
+      .bounced
+      .extend(crate::history::failed_recipients(record));
+   let campaign_id = record["campaign_id"]
+      .as_str()
+      .ok_or("the campaign record has no campaign id")?;
+   // all the message ids of the campaign start like this
+   let prefix = quote(&format!("<{}.", campaign_id))?;
+   conn.examine(folder)?;
+   let uids = conn.search(&format!(
+      "OR OR HEADER In-Reply-To {0} HEADER References {0} BODY {0}",
+      prefix
+   ))?;
+   let mut bounces = vec![];
+   for (uid, header) in conn.fetch(&uids, HEADER_FIELDS)? {
+      let header = String::from_utf8_lossy(&header);
+      match kind(&header) {
+         Kind::Reply => {
+            let reply = Reply {
+               from: field(&header, "From").unwrap_or_default(),
+               subject: field(&header, "Subject").unwrap_or_default(),
+               date: field(&header, "Date").and_then(|d| parse_date(&d)),
+            };
+            for recipient in referenced(&header, ids) {
+               result
+                  .replied
+                  .entry(recipient)
+                  .or_default()
+                  .push(reply.clone());
+            }
+         }
+         Kind::Bounce => bounces.push(uid),
+         Kind::Other => {}
+      }
+   }
+   for (_, text) in conn.fetch(&bounces, "BODY.PEEK[TEXT]")? {
+      let text = String::from_utf8_lossy(&text);
+      if failed(&text) {
+         result.bounced.extend(referenced(&text, ids));
+      }
+   }
+   Ok(result)
+}
+
+/// Returns the recipients the campaign email reached who neither replied nor bounced.
+pub fn non_responders(record: &Value, responses: &Responses) -> Vec<String> {
+   record["deliveries"]
+      .as_array()
+      .map(|deliveries| {
+         deliveries
+            .iter()
+            .filter(|d| d["status"] == "sent")
+            .filter_map(|d| d["recipient"].as_str())
+            .filter(|r| !responses.replied.contains_key(*r) && !responses.bounced.contains(*r))
+            .map(|r| r.to_string())
+            .collect()
+      })
+      .unwrap_or_default()
+}
+
+#[derive(Debug)]
+/// The `Tally` struct holds the responses of a recipient to a campaign.
+pub struct Tally {
+   /// The recipient's email address
+   pub recipient: String,
+   /// One of `replied`, `bounced`, `failed` (not delivered in the first place) or `none`
+   pub status: &'static str,
+   /// The number of replies
+   pub replies: usize,
+   /// When the first reply was written (seconds since the epoch)
+   pub first_reply_at: Option<u64>,
+   /// When the last reply was written (seconds since the epoch)
+   pub last_reply_at: Option<u64>,
+}
+
+impl PartialEq for Tally {
+   /// Makes it possible to compare instances of `Tally`
+   fn eq(&self, other: &Self) -> bool {
+      self.recipient == other.recipient
+         && self.status == other.status
+         && self.replies == other.replies
+         && self.first_reply_at == other.first_reply_at
+         && self.last_reply_at == other.last_reply_at
+   }
+}
+
+/// Tallies the responses per recipient of the campaign run, in the order of the deliveries. A
+/// reply counts more than a bounce, the email did reach the recipient after all.
+pub fn tally(record: &Value, responses: &Responses) -> Vec<Tally> {
+   let deliveries = record["deliveries"].as_array().cloned().unwrap_or_default();
+   deliveries
+      .iter()
+      .filter_map(|d| {
+         let recipient = d["recipient"].as_str()?.to_string();
+         let replies = responses.replied.get(&recipient);
+         let dates: Vec<u64> = replies
+            .map(|r| r.iter().filter_map(|r| r.date).collect())
+            .unwrap_or_default();
+         let status = if replies.is_some() {
+            "replied"
+         } else if d["status"] != "sent" {
+            "failed"
+         } else if responses.bounced.contains(&recipient) {
+            "bounced"
+         } else {
+            "none"
+         };
+         Some(Tally {
+            status,
+            replies: replies.map_or(0, |r| r.len()),
+            first_reply_at: dates.iter().min().copied(),
+            last_reply_at: dates.iter().max().copied(),
+            recipient,
+         })
+      })
+      .collect()
+}
+
+/// Formats the response report as CSV, one row per recipient.
+pub fn to_csv(tallies: &[Tally]) -> String {
+   let mut result = String::from("recipient,status,replies,first_reply_at,last_reply_at\r\n");
+   let date = |d: Option<u64>| d.map(history::format_timestamp).unwrap_or_default();
+   for t in tallies.iter() {
+      result.push_str(&format!(
+         "{},{},{},{},{}\r\n",
+         listing::csv_field(&t.recipient),
+         t.status,
+         t.replies,
+         date(t.first_reply_at),
+         date(t.last_reply_at)
+      ));
+   }
+   result
+}
+
+/// Formats the response report as a JSON document.
+pub fn to_json(campaign_id: &str, tallies: &[Tally]) -> Value {
+   let count = |status: &str| tallies.iter().filter(|t| t.status == status).count();
+   json!({
+      "campaign_id": campaign_id,
+      "replied": count("replied"),
+      "bounced": count("bounced"),
+      "failed": count("failed"),
+      "none": count("none"),
+      "recipients": tallies
+         .iter()
+         .map(|t| json!({
+            "recipient": t.recipient,
+            "status": t.status,
+            "replies": t.replies,
+            "first_reply_at": t.first_reply_at,
+            "last_reply_at": t.last_reply_at,
+         }))
+         .collect::<Vec<Value>>(),
+   })
+}
+
+/// Parses the `Date` of a message, a trailing comment like `(UTC)` is ignored.
+fn parse_date(date: &str) -> Option<u64> {
+   let date = match date.find('(') {
+      Some(start) => &date[..start],
+      None => date,
+   };
+   let date = DateTime::parse_from_rfc2822(date.trim()).ok()?;
+   u64::try_from(date.timestamp()).ok()
+}
+
+/// Returns the value of a header field, folded lines are joined.
+fn field(header: &str, name: &str) -> Option<String> {
+   let re = Regex::new(&format!(r"(?im)^{}:[ \t]*(.*(?:\r?\n[ \t].*)*)", name)).unwrap();
+   let value = re.captures(header)?[1].to_string();
+   Some(value.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Tells what a message is from its header.
+fn kind(header: &str) -> Kind {
+   let content_type = field(header, "Content-Type")
+      .unwrap_or_default()
+      .to_lowercase();
+   let from = field(header, "From").unwrap_or_default().to_lowercase();
+   if content_type.contains("disposition-notification") {
+      // a read receipt
+      Kind::Other
+   } else if content_type.contains("delivery-status")
+      || from.contains("mailer-daemon")
+      || from.contains("postmaster")
+   {
+      Kind::Bounce
+   } else if field(header, "Auto-Submitted").is_some_and(|v| !v.eq_ignore_ascii_case("no")) {
+      Kind::Other
+   } else if field(header, "In-Reply-To").is_some() || field(header, "References").is_some() {
+      Kind::Reply
+   } else {
+      Kind::Other
+   }
+}
+
+/// Whether a bounce reports a failed delivery. Delivery status notifications also report delays
+/// and successful deliveries, bounces without any `Action` are failures.
+fn failed(text: &str) -> bool {
+   let re = Regex::new(r"(?im)^Action:[ \t]*([a-z]+)").unwrap();
+   let mut actions = re.captures_iter(text).map(|c| c[1].to_lowercase());
+   match actions.next() {
+      Some(action) => action == "failed" || actions.any(|a| a == "failed"),
+      None => true,
+   }
+}
+
+/// Returns the recipients of the campaign emails the text refers to.
+fn referenced(text: &str, ids: &HashMap<String, String>) -> BTreeSet<String> {
+   let re = Regex::new(r"<[^<>\s]+>").unwrap();
+   re.find_iter(text)
+      .filter_map(|m| ids.get(m.as_str()))
+      .cloned()
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+   use serde_json::json;
+
+   fn record() -> Value {
+      json!({
+         "campaign_id": "c1",
+         "deliveries": [
+            {"recipient": "jd@example.com", "status": "sent", "message_id": "<c1.01@example.org>"},
+            {"recipient": "mm@gmail.com", "status": "sent", "message_id": "<c1.02@example.org>"},
+            {"recipient": "ab@example.com", "status": "sent", "message_id": "<c1.03@example.org>"},
+            {"recipient": "dd@gmail.com", "status": "failed", "message_id": "<c1.04@example.org>"},
+            {"recipient": "ee@gmail.com", "status": "sent"},
+         ],
+      })
+   }
+
+   #[test]
+   fn message_ids_of_run() {
+      let mut cfg = testing::config("Frodo <fb@example.com>", "hi");
+      cfg.msgid_domain = String::from("example.org");
+      let ids = message_ids(&record(), &cfg);
+      assert_eq!(4, ids.len());
+      assert_eq!(
+         Some("jd@example.com"),
+         ids.get("<c1.01@example.org>").map(|s| s.as_str())
+      );
+      let recomputed = message::message_id("c1", "ee@gmail.com", "example.org");
+      assert_eq!(
+         Some("ee@gmail.com"),
+         ids.get(&recomputed).map(|s| s.as_str())
+      );
+   }
+
+   #[test]
+   fn tell_replies_from_bounces() {
+      let reply = "From: Jane <jd@example.com>\r\nIn-Reply-To: <c1.01@example.org>\r\n\r\n";
+      assert_eq!(Kind::Reply, kind(reply));
+      let references = "From: jd@example.com\r\nReferences: <x@y>\r\n <c1.01@example.org>\r\n";
+      assert_eq!(Kind::Reply, kind(references));
+      let vacation = "From: jd@example.com\r\nAuto-Submitted: auto-replied\r\n\
+                      In-Reply-To: <c1.01@example.org>\r\n";
+      assert_eq!(Kind::Other, kind(vacation));
+      let dsn = "From: Mail Delivery System <MAILER-DAEMON@example.com>\r\n\
+                 Content-Type: multipart/report; report-type=delivery-status;\r\n \
+                 boundary=\"b1\"\r\n";
+      assert_eq!(Kind::Bounce, kind(dsn));
+      let mdn = "From: jd@example.com\r\nContent-Type: multipart/report;\r\n \
+                 report-type=disposition-notification\r\nReferences: <c1.01@example.org>\r\n";
+      assert_eq!(Kind::Other, kind(mdn));
+      assert_eq!(Kind::Other, kind("From: jd@example.com\r\n"));
+   }
+
+   #[test]
+   fn failed_deliveries() {
+      assert!(failed(
+         "Final-Recipient: rfc822; mm@gmail.com\r\nAction: failed\r\n"
+      ));
+      assert!(!failed(
+         "Final-Recipient: rfc822; mm@gmail.com\r\nAction: delayed\r\n"
+      ));
+      assert!(failed("Action: delivered\r\n\r\nAction: failed\r\n"));
+      assert!(failed("Your message could not be delivered.\r\n"));
+   }
+
+   #[test]
+   fn non_responders_of_run() {
+      let ids: HashMap<String, String> = [
+         ("<c1.01@example.org>", "jd@example.com"),
+         ("<c1.02@example.org>", "mm@gmail.com"),
+      ]
+      .iter()
+      .map(|(id, r)| (id.to_string(), r.to_string()))
+      .collect();
+      let mut responses = Responses {
+         replied: referenced("In-Reply-To: <c1.01@example.org> <other@x>", &ids)
+            .into_iter()
+            .map(|r| (r, vec![]))
+            .collect(),
+         bounced: referenced("Message-ID: <c1.02@example.org>", &ids),
+      };
+      responses.bounced.insert(String::from("dd@gmail.com"));
+      assert_eq!(
+         vec![String::from("ab@example.com"), String::from("ee@gmail.com")],
+         non_responders(&record(), &responses)
+      );
+   }
+
+   fn reply(date: u64) -> Reply {
+      Reply {
+         from: String::from("jd@example.com"),
+         subject: String::from("Re: hi"),
+         date: Some(date),
+      }
+   }
+
+   #[test]
+   fn tally_responses() {
+      let mut responses = Responses::default();
+      responses.replied.insert(
+         String::from("jd@example.com"),
+         vec![reply(1_600_000_200), reply(1_600_000_100)],
+      );
+      responses
+         .replied
+         .insert(String::from("ab@example.com"), vec![]);
+      responses.bounced.insert(String::from("ab@example.com"));
+      responses.bounced.insert(String::from("mm@gmail.com"));
+      responses.bounced.insert(String::from("dd@gmail.com"));
+      let tallies = tally(&record(), &responses);
+      let statuses: Vec<(&str, &str, usize)> = tallies
+         .iter()
+         .map(|t| (t.recipient.as_str(), t.status, t.replies))
+         .collect();
+      assert_eq!(
+         vec![
+            ("jd@example.com", "replied", 2),
+            ("mm@gmail.com", "bounced", 0),
+            ("ab@example.com", "replied", 0),
+            ("dd@gmail.com", "failed", 0),
+            ("ee@gmail.com", "none", 0),
+         ],
+         statuses
+      );
+      assert_eq!(Some(1_600_000_100), tallies[0].first_reply_at);
+      assert_eq!(Some(1_600_000_200), tallies[0].last_reply_at);
+      assert_eq!(None, tallies[2].first_reply_at);
+
+      let csv = to_csv(&tallies);
+      let lines: Vec<&str> = csv.lines().collect();
+      assert_eq!(6, lines.len());
+      assert_eq!(
+         "recipient,status,replies,first_reply_at,last_reply_at",
+         lines[0]
+      );
+      assert!(lines[1].starts_with("jd@example.com,replied,2,20"));
+      assert_eq!("ee@gmail.com,none,0,,", lines[5]);
+
+      let json = to_json("c1", &tallies);
+      assert_eq!(2, json["replied"]);
+      assert_eq!(1, json["bounced"]);
+      assert_eq!(1, json["failed"]);
+      assert_eq!(1, json["none"]);
+      assert_eq!(1_600_000_100, json["recipients"][0]["first_reply_at"]);
+      assert_eq!(Value::Null, json["recipients"][4]["last_reply_at"]);
+   }
+
+   #[test]
+   fn parse_dates() {
+      assert_eq!(
+         Some(1_600_000_000),
+         parse_date("Sun, 13 Sep 2020 12:26:40 +0000")
+      );
+      assert_eq!(
+         Some(1_600_000_000),
+         parse_date("Sun, 13 Sep 2020 14:26:40 +0200 (CEST)")
+      );
+      assert_eq!(None, parse_date("yesterday"));
+   }
+}
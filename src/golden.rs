@@ -0,0 +1,201 @@
+/// The `golden` module renders the emails of a campaign into snapshot files and compares them
+/// with previously approved snapshots, so that changes to the content show up in reviews.
+use crate::message::Message;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// The extension of the snapshot files.
+const EXTENSION: &str = "txt";
+
+#[derive(Debug, PartialEq)]
+/// The `Difference` enum lists the ways the rendered emails may differ from the approved ones.
+pub enum Difference {
+   /// The email differs, starting at the given line
+   Changed {
+      email: String,
+      line: usize,
+      approved: String,
+      rendered: String,
+   },
+   /// There is no approved snapshot for the recipient
+   New(String),
+   /// The approved snapshot belongs to a recipient the campaign no longer has
+   Gone(String),
+}
+
+impl fmt::Display for Difference {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self {
+         Difference::Changed {
+            email,
+            line,
+            approved,
+            rendered,
+         } => write!(
+            f,
+            "{}: changed in line {}\n   - {}\n   + {}",
+            email, line, approved, rendered
+         ),
+         Difference::New(email) => write!(f, "{}: no approved snapshot", email),
+         Difference::Gone(name) => write!(f, "{}: no such recipient", name),
+      }
+   }
+}
+
+/// Returns the snapshot of the email: the headers and the plain text body the way they would be
+/// sent, followed by the HTML body and the names of the attachments. Unlike the MIME message the
+/// snapshot is readable and has no encoded parts.
+pub fn snapshot(msg: &Message) -> String {
+   let mut result = String::new();
+   for (name, value) in msg.headers.iter() {
+      result.push_str(&format!("{}: {}\n", name, value));
+   }
+   result.push('\n');
+   result.push_str(&msg.body);
+   if let Some(ref html) = msg.html {
+      result.push_str("\n\n--- text/html ---\n");
+      result.push_str(html);
+   }
+   for attachment in msg.attachments.iter().chain(msg.inline_images.iter()) {
+      result.push_str(&format!(
+         "\n\n--- {} ({}) ---",
+         attachment.name, attachment.content_type
+      ));
+   }
+   if !result.ends_with('\n') {
+      result.push('\n');
+   }
+   result
+}
+
+/// Returns the name of the snapshot file of the recipient.
+pub fn file_name(email: &str) -> String {
+   format!(
+      "{}.{}",
+      email.trim().to_lowercase().replace(['/', '\\'], "_"),
+      EXTENSION
+   )
+}
+
+/// Writes the snapshots (by recipient email address) to the directory, which is created if
+/// needed. Snapshots of other recipients are removed.
+pub fn write(dir: &Path, snapshots: &[(String, String)]) -> Result<(), String> {
+   fs::create_dir_all(dir).map_err(|e| format!("cannot create {} ({})", dir.display(), e))?;
+   for name in stale(dir, snapshots)? {
+      let path = dir.join(&name);
+      fs::remove_file(&path).map_err(|e| format!("cannot remove {} ({})", path.display(), e))?;
+   }
+   for (email, text) in snapshots.iter() {
+      let path = dir.join(file_name(email));
+      fs::write(&path, text).map_err(|e| format!("cannot write {} ({})", path.display(), e))?;
+   }
+   Ok(())
+}
+
+/// Compares the snapshots (by recipient email address) with the approved ones in the directory.
+pub fn check(dir: &Path, snapshots: &[(String, String)]) -> Result<Vec<Difference>, String> {
+   if !dir.is_dir() {
+      return Err(format!("no such directory: {}", dir.display()));
+   }
+   let mut result = vec![];
+   for (email, text) in snapshots.iter() {
+      let path = dir.join(file_name(email));
+      let approved = match fs::read_to_string(&path) {
+         Ok(approved) => approved,
+         Err(_) if !path.exists() => {
+            result.push(Difference::New(email.clone()));
+            continue;
+         }
+         Err(e) => return Err(format!("cannot read {} ({})", path.display(), e)),
+      };
+      if approved == *text {
+         continue;
+      }
+      let mut approved_lines = approved.lines();
+      let mut rendered_lines = text.lines();
+      for line in 1.. {
+         let (a, r) = (approved_lines.next(), rendered_lines.next());
+         if a != r || (a.is_none() && r.is_none()) {
+            result.push(Difference::Changed {
+               email: email.clone(),
+               line,
+               approved: a.unwrap_or_default().to_string(),
+               rendered: r.unwrap_or_default().to_string(),
+            });
+            break;
+         }
+      }
+   }
+   result.extend(stale(dir, snapshots)?.into_iter().map(Difference::Gone));
+   Ok(result)
+}
+
+/// Returns the names of the snapshot files in the directory that belong to none of the
+/// recipients.
+fn stale(dir: &Path, snapshots: &[(String, String)]) -> Result<BTreeSet<String>, String> {
+   let names: BTreeSet<String> = snapshots.iter().map(|(e, _)| file_name(e)).collect();
+   let entries = fs::read_dir(dir).map_err(|e| format!("cannot read {} ({})", dir.display(), e))?;
+   Ok(entries
+      .filter_map(|e| e.ok())
+      .map(|e| e.file_name().to_string_lossy().into_owned())
+      .filter(|n| n.ends_with(&format!(".{}", EXTENSION)) && !names.contains(n))
+      .collect())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::message;
+   use crate::template;
+   use crate::testing;
+
+   #[test]
+   fn snapshot_of_html_email() {
+      let mut cfg = testing::config("fb@example.com", "Hi %_FN%");
+      cfg.recipients = vec![testing::recipient("jd@example.com", "John Doe", &[])];
+      let tmpl = template::new("Hello %_FN%!");
+      let html = template::new("<p>Hello %_FN%!</p>");
+      let msg = message::compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      let text = snapshot(&msg);
+      assert!(text.contains("\nSubject: Hi John\n"));
+      assert!(text.ends_with("\n\nHello John!\n\n--- text/html ---\n<p>Hello John!</p>\n"));
+   }
+
+   #[test]
+   fn write_and_check() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      let golden = dir.path().join("golden");
+      let snapshots = vec![
+         (String::from("jd@example.com"), String::from("a\nb\n")),
+         (String::from("mm@gmail.com"), String::from("c\n")),
+      ];
+      assert!(check(&golden, &snapshots).is_err());
+      fs::create_dir(&golden).unwrap();
+      fs::write(golden.join("old@example.com.txt"), "x").unwrap();
+      write(&golden, &snapshots).expect("cannot write the snapshots");
+      assert!(!golden.join("old@example.com.txt").exists());
+      assert_eq!(Ok(vec![]), check(&golden, &snapshots));
+
+      fs::write(golden.join("gone@example.com.txt"), "x").unwrap();
+      let changed = vec![
+         (String::from("jd@example.com"), String::from("a\nb\nc\n")),
+         (String::from("new@example.com"), String::from("d\n")),
+      ];
+      assert_eq!(
+         Ok(vec![
+            Difference::Changed {
+               email: String::from("jd@example.com"),
+               line: 3,
+               approved: String::new(),
+               rendered: String::from("c"),
+            },
+            Difference::New(String::from("new@example.com")),
+            Difference::Gone(String::from("gone@example.com.txt")),
+            Difference::Gone(String::from("mm@gmail.com.txt")),
+         ]),
+         check(&golden, &changed)
+      );
+   }
+}
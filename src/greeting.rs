@@ -0,0 +1,141 @@
+/// The `greeting` module builds the salutations the templates get with the `%_GREETING%` key
+/// e.g. "Dear Dr. Doe" or "Hallo John", in the language of the recipient.
+use crate::config::{Config, Recipient};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `Formality` enum lists the styles of the salutations.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Formality {
+   /// The first name e.g. "Hi John" (the default)
+   #[default]
+   Informal,
+   /// The title (if any) and the last name e.g. "Dear Dr. Doe"
+   Formal,
+}
+
+impl Formality {
+   /// Parses the *greeting* setting: `formal` or `informal`.
+   pub fn parse(text: &str) -> Result<Formality, String> {
+      match text.trim().to_lowercase().as_str() {
+         "informal" => Ok(Formality::Informal),
+         "formal" => Ok(Formality::Formal),
+         _ => Err(format!("invalid *greeting*: {}", text)),
+      }
+   }
+}
+
+/// The salutations by language: the informal one, the formal one and the one for recipients
+/// without a name. Unknown languages get the English ones.
+const SALUTATIONS: &[(&str, &str, &str, &str)] = &[
+   ("en", "Hi", "Dear", "Hello"),
+   ("de", "Hallo", "Guten Tag", "Guten Tag"),
+   ("es", "Hola", "Estimado/a", "Hola"),
+   ("fr", "Salut", "Bonjour", "Bonjour"),
+   ("it", "Ciao", "Gentile", "Buongiorno"),
+   ("nl", "Hoi", "Geachte", "Goedendag"),
+   ("pt", "Olá", "Caro/a", "Olá"),
+];
+
+/// Returns the salutation for the recipient as per the *greeting* setting of the campaign. The
+/// recipient's `lang` datum gives the language (the *locale* of the campaign by default), the
+/// `TITLE` datum (or its default value) the title.
+pub fn salutation(cfg: &Config, rcp: &Recipient) -> String {
+   let datum = |key: &str| rcp.data.get(key).or_else(|| cfg.defaults.get(key));
+   greet(
+      &rcp.names,
+      datum("TITLE").map(|t| t.as_str()),
+      datum("lang").map_or(cfg.locale.language, |l| l.as_str()),
+      cfg.greeting,
+   )
+}
+
+/// Returns the salutation for the recipient with the given names (first name first) and title
+/// (e.g. `Dr.`) in the language (e.g. `de` or `de-CH`). The formal salutation falls back to the
+/// first name for recipients with just one name.
+pub fn greet(
+   names: &[String],
+   title: Option<&str>,
+   language: &str,
+   formality: Formality,
+) -> String {
+   let language = language.trim().to_lowercase();
+   let language = language.split(['-', '_']).next().unwrap_or_default();
+   let (_, informal, formal, anonymous) = SALUTATIONS
+      .iter()
+      .find(|(l, _, _, _)| *l == language)
+      .unwrap_or(&SALUTATIONS[0]);
+   let name = match formality {
+      Formality::Informal => names.first().cloned(),
+      Formality::Formal if names.len() > 1 => Some(names[1..].join(" ")),
+      Formality::Formal => names.first().cloned(),
+   };
+   match name {
+      Some(name) if formality == Formality::Formal => {
+         match title.map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            Some(title) => format!("{} {} {}", formal, title, name),
+            None => format!("{} {}", formal, name),
+         }
+      }
+      Some(name) => format!("{} {}", informal, name),
+      None => anonymous.to_string(),
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::config;
+   use crate::testing;
+
+   fn names(text: &str) -> Vec<String> {
+      text.split_whitespace().map(|n| n.to_string()).collect()
+   }
+
+   #[test]
+   fn greetings() {
+      let jd = names("John Doe");
+      assert_eq!("Hi John", greet(&jd, None, "en", Formality::Informal));
+      assert_eq!(
+         "Dear Dr. Doe",
+         greet(&jd, Some("Dr."), "en", Formality::Formal)
+      );
+      assert_eq!(
+         "Hallo John",
+         greet(&jd, Some("Dr."), "de-CH", Formality::Informal)
+      );
+      assert_eq!(
+         "Guten Tag Prof. Doe",
+         greet(&jd, Some(" Prof. "), "de_DE", Formality::Formal)
+      );
+      assert_eq!(
+         "Geachte van Dijk",
+         greet(&names("Jan van Dijk"), Some(""), "NL", Formality::Formal)
+      );
+      assert_eq!(
+         "Dear Madonna",
+         greet(&names("Madonna"), None, "xx", Formality::Formal)
+      );
+      assert_eq!("Bonjour", greet(&[], None, "fr", Formality::Informal));
+   }
+
+   #[test]
+   fn salutations_of_recipients() {
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.greeting = Formality::Formal;
+      cfg.defaults = config::sm(&[("TITLE", "Dr.")]);
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[("lang", "de")]);
+      assert_eq!("Guten Tag Dr. Doe", salutation(&cfg, &rcp));
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[("TITLE", "")]);
+      assert_eq!("Dear Doe", salutation(&cfg, &rcp));
+   }
+
+   #[test]
+   fn parse_formality() {
+      assert_eq!(Ok(Formality::Formal), Formality::parse(" Formal"));
+      assert_eq!(Ok(Formality::Informal), Formality::parse("informal"));
+      assert_eq!(
+         Err(String::from("invalid *greeting*: casual")),
+         Formality::parse("casual")
+      );
+   }
+}
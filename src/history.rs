@@ -0,0 +1,428 @@
+/// The `history` module keeps a local record of the campaigns sent.
+///
+/// Every campaign run is stored as a JSON document (see `report::Run::to_json`) in the campaigns
+/// directory, `$XDG_DATA_HOME/rmt/campaigns/` or `~/.local/share/rmt/campaigns/` by default.
+use crate::config::Config;
+use chrono::{Local, TimeZone};
+use log::warn;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the directory `rmt` keeps its data in, `$XDG_DATA_HOME/rmt/` or
+/// `~/.local/share/rmt/` by default.
+pub fn data_dir() -> Option<PathBuf> {
+   let data_dir = match env::var_os("XDG_DATA_HOME") {
+      Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+      _ => PathBuf::from(env::var_os("HOME")?)
+         .join(".local")
+         .join("share"),
+   };
+   Some(data_dir.join("rmt"))
+}
+
+/// Returns the directory the campaign records are stored in.
+pub fn campaigns_dir() -> Option<PathBuf> {
+   Some(data_dir()?.join("campaigns"))
+}
+
+/// Stores the record of a campaign run in the given directory, it is created if needed.
+///
+/// The file is named after the campaign and the start of the run, runs of a campaign that
+/// started within the same second get a counter appended (`winter-1600000000-2.json`) rather
+/// than overwriting each other's records.
+///
+/// Returns the path of the file written.
+pub fn save(dir: &Path, record: &Value) -> Result<PathBuf, String> {
+   fs::create_dir_all(dir)
+      .map_err(|e| format!("cannot create directory {} ({})", dir.display(), e))?;
+   let stem = format!(
+      "{}-{}",
+      record["campaign_id"].as_str().unwrap_or("unknown"),
+      record["started_at"].as_u64().unwrap_or(0)
+   );
+   let mut n = 1;
+   loop {
+      let path = match n {
+         1 => dir.join(format!("{}.json", stem)),
+         _ => dir.join(format!("{}-{}.json", stem, n)),
+      };
+      match OpenOptions::new().write(true).create_new(true).open(&path) {
+         Ok(mut file) => {
+            file
+               .write_all(format!("{:#}\n", record).as_bytes())
+               .map_err(|e| format!("cannot write {} ({})", path.display(), e))?;
+            return Ok(path);
+         }
+         Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+         Err(e) => return Err(format!("cannot write {} ({})", path.display(), e)),
+      }
+   }
+}
+
+#[derive(Debug)]
+/// The `Entry` struct holds the summary of a past campaign run.
+pub struct Entry {
+   /// The path of the campaign record
+   pub path: PathBuf,
+   /// The campaign identifier
+   pub campaign_id: String,
+   /// The subject of the emails
+   pub subject: String,
+   /// When the run started (seconds since the epoch)
+   pub started_at: u64,
+   /// The number of recipients
+   pub recipients: usize,
+   /// The number of emails sent successfully
+   pub sent: usize,
+   /// The number of failed deliveries
+   pub failed: usize,
+}
+
+impl PartialEq for Entry {
+   /// Makes it possible to compare instances of `Entry`
+   fn eq(&self, other: &Self) -> bool {
+      self.path == other.path
+         && self.campaign_id == other.campaign_id
+         && self.subject == other.subject
+         && self.started_at == other.started_at
+         && self.recipients == other.recipients
+         && self.sent == other.sent
+         && self.failed == other.failed
+   }
+}
+
+impl Entry {
+   /// Builds the history entry from a campaign record.
+   pub fn from_record(path: &Path, record: &Value) -> Option<Entry> {
+      let count = |key: &str| record[key].as_u64().map(|n| n as usize);
+      Some(Entry {
+         path: path.to_path_buf(),
+         campaign_id: record["campaign_id"].as_str()?.to_string(),
+         subject: record["subject"].as_str().unwrap_or_default().to_string(),
+         started_at: record["started_at"].as_u64()?,
+         recipients: record["deliveries"].as_array()?.len(),
+         sent: count("sent")?,
+         failed: count("failed")?,
+      })
+   }
+}
+
+/// Reads the campaign record from the given file.
+pub fn read(path: &Path) -> Result<Value, String> {
+   let content =
+      fs::read_to_string(path).map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+   serde_json::from_str(&content).map_err(|e| format!("invalid record {} ({})", path.display(), e))
+}
+
+/// Loads the summaries of all campaign runs in the given directory, oldest first.
+///
+/// Files that are not campaign records are skipped with a warning.
+pub fn load(dir: &Path) -> Result<Vec<Entry>, String> {
+   let mut result = vec![];
+   if !dir.exists() {
+      return Ok(result);
+   }
+   let entries =
+      fs::read_dir(dir).map_err(|e| format!("cannot read directory {} ({})", dir.display(), e))?;
+   for entry in entries.flatten() {
+      let path = entry.path();
+      if path.extension().and_then(|e| e.to_str()) != Some("json") {
+         continue;
+      }
+      match read(&path).map(|r| Entry::from_record(&path, &r)) {
+         Ok(Some(entry)) => result.push(entry),
+         Ok(None) => warn!("skipping {} (not a campaign record)", path.display()),
+         Err(err) => warn!("skipping {}", err),
+      }
+   }
+   // the records of runs started within the same second are numbered (see `save`), the shorter
+   // name comes first
+   result.sort_by_key(|e| (e.started_at, e.path.as_os_str().len(), e.path.clone()));
+   Ok(result)
+}
+
+/// Returns the most recent run of the given campaign (if any).
+pub fn latest(dir: &Path, campaign_id: &str) -> Result<Option<Entry>, String> {
+   Ok(load(dir)?
+      .into_iter()
+      .rev()
+      .find(|e| e.campaign_id == campaign_id))
+}
+
+/// Returns the recipients whose delivery failed in the given campaign run.
+pub fn failed_recipients(record: &Value) -> Vec<String> {
+   record["deliveries"]
+      .as_array()
+      .map(|deliveries| {
+         deliveries
+            .iter()
+            .filter(|d| d["status"] == "failed")
+            .filter_map(|d| d["recipient"].as_str())
+            .map(|r| r.to_string())
+            .collect()
+      })
+      .unwrap_or_default()
+}
+
+/// Returns the recipients an interrupted run did not get to.
+pub fn remaining_recipients(record: &Value) -> Vec<String> {
+   record["remaining"]
+      .as_array()
+      .map(|remaining| {
+         remaining
+            .iter()
+            .filter_map(|r| r.as_str())
+            .map(|r| r.to_string())
+            .collect()
+      })
+      .unwrap_or_default()
+}
+
+/// Returns the `Message-ID`s of the emails sent in the given campaign run by the (lowercase)
+/// address of their recipient.
+pub fn sent_message_ids(record: &Value) -> HashMap<String, String> {
+   record["deliveries"]
+      .as_array()
+      .map(|deliveries| {
+         deliveries
+            .iter()
+            .filter(|d| d["status"] == "sent")
+            .filter_map(|d| {
+               let recipient = d["recipient"].as_str()?.trim().to_lowercase();
+               Some((recipient, d["message_id"].as_str()?.to_string()))
+            })
+            .collect()
+      })
+      .unwrap_or_default()
+}
+
+/// Makes the emails of a follow-up campaign reply to the ones sent in the campaign its
+/// *follow_up_of* names: their `Message-ID`s are read from the records of that campaign's runs in
+/// the given directory. Recipients the earlier campaign did not reach get no `In-Reply-To`.
+pub fn thread_under(dir: &Path, cfg: &mut Config) -> Result<(), String> {
+   let campaign_id = match cfg.threading.follow_up_of {
+      Some(ref campaign_id) => campaign_id.clone(),
+      None => return Ok(()),
+   };
+   if cfg.threading.in_reply_to.is_some() {
+      return Err(String::from(
+         "*follow_up_of* and *in_reply_to* cannot be used together",
+      ));
+   }
+   let runs: Vec<Entry> = load(dir)?
+      .into_iter()
+      .filter(|e| e.campaign_id == campaign_id)
+      .collect();
+   if runs.is_empty() {
+      return Err(format!(
+         "campaign {} is not in the history ({}), cannot thread under its emails",
+         campaign_id,
+         dir.display()
+      ));
+   }
+   // a recipient left out of a run (e.g. an interrupted one) may have been sent to in another
+   let mut message_ids = HashMap::new();
+   for run in runs {
+      message_ids.extend(sent_message_ids(&read(&run.path)?));
+   }
+   cfg.threading.message_ids = message_ids;
+   Ok(())
+}
+
+/// Formats a timestamp (seconds since the epoch) in local time.
+pub fn format_timestamp(timestamp: u64) -> String {
+   match Local.timestamp_opt(timestamp as i64, 0).single() {
+      Some(t) => t.format("%Y-%m-%d %H:%M").to_string(),
+      None => timestamp.to_string(),
+   }
+}
+
+/// Formats the history as a table, one campaign run per line.
+pub fn format(entries: &[Entry]) -> String {
+   let mut result = format!(
+      "{:<16}  {:<24}  {:>10}  {:>6}  {:>6}  SUBJECT\n",
+      "STARTED", "CAMPAIGN", "RECIPIENTS", "SENT", "FAILED"
+   );
+   for e in entries.iter() {
+      result.push_str(&format!(
+         "{:<16}  {:<24}  {:>10}  {:>6}  {:>6}  {}\n",
+         format_timestamp(e.started_at),
+         e.campaign_id,
+         e.recipients,
+         e.sent,
+         e.failed,
+         e.subject
+      ));
+   }
+   result
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+   use serde_json::json;
+   use tempfile::TempDir;
+
+   fn record(campaign_id: &str, started_at: u64) -> Value {
+      json!({
+         "campaign_id": campaign_id,
+         "subject": "Hello %_FN%!",
+         "dry_run": false,
+         "started_at": started_at,
+         "finished_at": started_at + 2,
+         "sent": 1,
+         "failed": 1,
+         "deliveries": [
+            {"recipient": "jd@example.com", "status": "sent"},
+            {"recipient": "mm@gmail.com", "status": "failed"},
+         ],
+      })
+   }
+
+   #[test]
+   fn save_and_load() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let campaigns = dir.path().join("campaigns");
+      let path = save(&campaigns, &record("winter", 1_600_000_100)).expect("save failed");
+      assert_eq!(campaigns.join("winter-1600000100.json"), path);
+      save(&campaigns, &record("autumn", 1_600_000_000)).expect("save failed");
+      fs::write(campaigns.join("notes.txt"), "not a record").unwrap();
+      fs::write(campaigns.join("broken.json"), "{").unwrap();
+      let entries = load(&campaigns).expect("load failed");
+      assert_eq!(2, entries.len());
+      assert_eq!(
+         Entry {
+            path: campaigns.join("autumn-1600000000.json"),
+            campaign_id: String::from("autumn"),
+            subject: String::from("Hello %_FN%!"),
+            started_at: 1_600_000_000,
+            recipients: 2,
+            sent: 1,
+            failed: 1,
+         },
+         entries[0]
+      );
+      assert_eq!("winter", entries[1].campaign_id);
+   }
+
+   #[test]
+   fn save_runs_started_in_the_same_second() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let paths: Vec<PathBuf> = (0..3)
+         .map(|_| save(dir.path(), &record("winter", 1_600_000_000)).expect("save failed"))
+         .collect();
+      assert_eq!(
+         vec![
+            dir.path().join("winter-1600000000.json"),
+            dir.path().join("winter-1600000000-2.json"),
+            dir.path().join("winter-1600000000-3.json"),
+         ],
+         paths
+      );
+      assert_eq!(3, load(dir.path()).expect("load failed").len());
+      let latest = latest(dir.path(), "winter").expect("load failed");
+      assert_eq!(Some(paths[2].clone()), latest.map(|e| e.path));
+   }
+
+   #[test]
+   fn latest_run_of_campaign() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      save(dir.path(), &record("winter", 1_600_000_100)).expect("save failed");
+      save(dir.path(), &record("winter", 1_600_000_000)).expect("save failed");
+      save(dir.path(), &record("autumn", 1_600_000_200)).expect("save failed");
+      let latest = latest(dir.path(), "winter").expect("load failed");
+      assert_eq!(Some(1_600_000_100), latest.map(|e| e.started_at));
+      assert_eq!(Ok(None), super::latest(dir.path(), "spring"));
+   }
+
+   #[test]
+   fn failed_recipients_of_run() {
+      assert_eq!(
+         vec![String::from("mm@gmail.com")],
+         failed_recipients(&record("winter", 1_600_000_000))
+      );
+      assert!(failed_recipients(&json!({})).is_empty());
+   }
+
+   #[test]
+   fn remaining_recipients_of_run() {
+      let mut record = record("winter", 1_600_000_000);
+      assert!(remaining_recipients(&record).is_empty());
+      record["remaining"] = json!(["daisy@example.com"]);
+      assert_eq!(
+         vec![String::from("daisy@example.com")],
+         remaining_recipients(&record)
+      );
+   }
+
+   #[test]
+   fn thread_under_earlier_campaign() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let mut first = record("winter", 1_600_000_000);
+      first["deliveries"] = json!([
+         {"recipient": "JD@example.com", "status": "sent", "message_id": "<w.1@example.com>"},
+         {"recipient": "mm@gmail.com", "status": "failed", "message_id": "<w.2@example.com>"},
+      ]);
+      save(dir.path(), &first).expect("save failed");
+      let mut resumed = record("winter", 1_600_000_100);
+      resumed["deliveries"] = json!([
+         {"recipient": "mm@gmail.com", "status": "sent", "message_id": "<w.3@example.com>"},
+      ]);
+      save(dir.path(), &resumed).expect("save failed");
+      let mut cfg = testing::config("fb@example.com", "Hello %_FN%!");
+      thread_under(dir.path(), &mut cfg).expect("no follow-up campaign");
+      assert!(cfg.threading.message_ids.is_empty());
+      cfg.threading.follow_up_of = Some(String::from("winter"));
+      thread_under(dir.path(), &mut cfg).expect("cannot read the history");
+      let expected: HashMap<String, String> = vec![
+         (
+            String::from("jd@example.com"),
+            String::from("<w.1@example.com>"),
+         ),
+         (
+            String::from("mm@gmail.com"),
+            String::from("<w.3@example.com>"),
+         ),
+      ]
+      .into_iter()
+      .collect();
+      assert_eq!(expected, cfg.threading.message_ids);
+      cfg.threading.follow_up_of = Some(String::from("spring"));
+      let err = thread_under(dir.path(), &mut cfg).expect_err("unknown campaign");
+      assert!(
+         err.starts_with("campaign spring is not in the history"),
+         "{}",
+         err
+      );
+      cfg.threading.follow_up_of = Some(String::from("winter"));
+      cfg.threading.in_reply_to = Some(String::from("<a@example.com>"));
+      assert_eq!(
+         Err(String::from(
+            "*follow_up_of* and *in_reply_to* cannot be used together"
+         )),
+         thread_under(dir.path(), &mut cfg)
+      );
+   }
+
+   #[test]
+   fn load_without_directory() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      assert_eq!(Ok(vec![]), load(&dir.path().join("campaigns")));
+   }
+
+   #[test]
+   fn format_history() {
+      let entry = Entry::from_record(Path::new("x.json"), &record("winter", 1_600_000_000))
+         .expect("invalid record");
+      let lines: Vec<String> = format(&[entry]).lines().map(|l| l.to_string()).collect();
+      assert_eq!(2, lines.len());
+      assert!(lines[0].starts_with("STARTED "));
+      assert!(lines[1].contains("  winter  "));
+      assert!(lines[1].ends_with("     2       1       1  Hello %_FN%!"));
+   }
+}
@@ -0,0 +1,417 @@
+/// The `imap` module stores copies of the emails sent in a mailbox folder.
+///
+/// Emails sent over SMTP do not show up in the sender's *Sent* folder, mail clients append them
+/// there themselves. This is a minimal IMAP4rev1 (RFC 3501) client doing the same: it secures
+/// the connection (TLS from the start or STARTTLS), logs in and `APPEND`s the messages to the
+/// configured folder, flagged as seen. It also searches a folder read-only e.g. for the replies
+/// to a campaign.
+use crate::config::{Imap, Security};
+use crate::smtp::{self, Stream};
+use log::{debug, trace};
+use regex::Regex;
+use std::io::{Read, Write};
+
+/// The `Untagged` struct holds a line of data the server sent in response to a command.
+struct Untagged {
+   /// The line e.g. `* SEARCH 1 2`, literals are replaced with `<literal>`
+   text: String,
+   /// The literals (`{n}` followed by n bytes) in the line, in order
+   literals: Vec<Vec<u8>>,
+}
+
+/// The `Connection` struct is a session with an IMAP server.
+pub struct Connection {
+   stream: Stream,
+   /// The data read from the server but not consumed yet
+   buffer: Vec<u8>,
+   /// The number of the last command tag used
+   tag: u32,
+   /// Whether the connection was lost or closed by the server
+   broken: bool,
+}
+
+impl Connection {
+   /// Connects to the server, secures the connection and logs in as per the settings. The
+   /// password must have been resolved. The credentials are never sent over an unencrypted
+   /// connection, only a server that greets with `PREAUTH` (e.g. one reached through a tunnel)
+   /// can be used without TLS.
+   pub fn open(imap: &Imap) -> Result<Connection, String> {
+      let (stream, addr) = smtp::connect("imap", &imap.host, imap.port, &imap.security)?;
+      let mut result = Connection {
+         stream,
+         buffer: vec![],
+         tag: 0,
+         broken: false,
+      };
+      let greeting = result.read_line()?;
+      trace!("imap: << {}", greeting);
+      if !greeting.starts_with("* OK") && !greeting.starts_with("* PREAUTH") {
+         return Err(format!("unexpected imap greeting: {}", greeting));
+      }
+      if greeting.starts_with("* PREAUTH") {
+         debug!("imap: {} pre-authenticated the connection", addr);
+         return Ok(result);
+      }
+      if imap.security == Security::StartTls {
+         result.command("STARTTLS")?;
+         result.stream = result.stream.secure(&imap.host)?;
+         result.buffer.clear();
+      }
+      if let Stream::Plain(_) = result.stream {
+         return Err(String::from(
+            "imap credentials are never sent over an unencrypted connection, use `security=tls` \
+             or `security=starttls`",
+         ));
+      }
+      let password = imap.password.as_deref().unwrap_or_default();
+      let login = format!("LOGIN {} {}", quote(&imap.username)?, quote(password)?);
+      let tag = result.send(&login, false)?;
+      result
+         .response(&tag)
+         .map_err(|e| format!("imap login as {} failed ({})", imap.username, e))?;
+      debug!("imap: logged in to {} as {}", addr, imap.username);
+      Ok(result)
+   }
+
+   /// Whether the connection was lost, a new one is needed for the next message then.
+   pub fn is_broken(&self) -> bool {
+      self.broken
+   }
+
+   /// Appends a message to the folder and flags it as seen, `data` is the message with CRLF line
+   /// endings.
+   pub fn append(&mut self, folder: &str, data: &[u8]) -> Result<(), String> {
+      let command = format!("APPEND {} (\\Seen) {{{}}}", quote(folder)?, data.len());
+      let tag = self.send(&command, true)?;
+      // the server asks for the message (or refuses it right away)
+      let line = self.read_line()?;
+      trace!("imap: << {}", line);
+      if !line.starts_with('+') {
+         return Err(format!("APPEND failed ({})", status(&line, &tag)));
+      }
+      trace!("imap: >> <{} bytes>", data.len());
+      self.write(data)?;
+      self.write(b"\r\n")?;
+      self
+         .response(&tag)
+         .map_err(|e| format!("APPEND to {} failed ({})", folder, e))?;
+      Ok(())
+   }
+
+   /// Opens a folder read-only, the messages stay unseen.
+   pub fn examine(&mut self, folder: &str) -> Result<(), String> {
+      self.command(&format!("EXAMINE {}", quote(folder)?))?;
+      Ok(())
+   }
+
+   /// Searches the open folder, returns the UIDs of the matching messages. The criteria are
+   /// passed on as they are e.g. `HEADER In-Reply-To "<x"`.
+   pub fn search(&mut self, criteria: &str) -> Result<Vec<u32>, String> {
+      let response = self.command(&format!("UID SEARCH {}", criteria))?;
+      Ok(response
+         .iter()
+         .filter_map(|l| l.text.strip_prefix("* SEARCH"))
+         .flat_map(|l| l.split_whitespace().filter_map(|n| n.parse().ok()))
+         .collect())
+   }
+
+   /// Fetches a part of the given messages e.g. `BODY.PEEK[HEADER]`, returns the UIDs and data
+   /// of the messages the server found.
+   pub fn fetch(&mut self, uids: &[u32], item: &str) -> Result<Vec<(u32, Vec<u8>)>, String> {
+      if uids.is_empty() {
+         return Ok(vec![]);
+      }
+      let uid = Regex::new(r"\bUID (\d+)").unwrap();
+      let set: Vec<String> = uids.iter().map(|u| u.to_string()).collect();
+      let response = self.command(&format!("UID FETCH {} ({})", set.join(","), item))?;
+      Ok(response
+         .into_iter()
+         .filter(|l| l.text.contains(" FETCH "))
+         .filter_map(|mut l| {
+            let uid = uid.captures(&l.text)?[1].parse().ok()?;
+            Some((uid, l.literals.pop().unwrap_or_default()))
+         })
+         .collect())
+   }
+
+   /// Ends the session.
+   pub fn logout(mut self) {
+      let _ = self.command("LOGOUT");
+   }
+
+   /// Sends a command and reads the response, which must be OK.
+   fn command(&mut self, command: &str) -> Result<Vec<Untagged>, String> {
+      let tag = self.send(command, true)?;
+      self.response(&tag).map_err(|e| {
+         // e.g. "UID SEARCH failed"
+         let words = if command.starts_with("UID ") { 2 } else { 1 };
+         let verb: Vec<&str> = command.split(' ').take(words).collect();
+         format!("{} failed ({})", verb.join(" "), e)
+      })
+   }
+
+   /// Sends a tagged command, returns the tag. Unlike other commands `LOGIN` is not logged.
+   fn send(&mut self, command: &str, log: bool) -> Result<String, String> {
+      self.tag += 1;
+      let tag = format!("a{}", self.tag);
+      if log {
+         trace!("imap: >> {} {}", tag, command);
+      } else {
+         trace!("imap: >> {} <credentials>", tag);
+      }
+      self.write(format!("{} {}\r\n", tag, command).as_bytes())?;
+      Ok(tag)
+   }
+
+   /// Reads the response to the command with the given tag. Returns the untagged data if the
+   /// command succeeded and the status otherwise.
+   fn response(&mut self, tag: &str) -> Result<Vec<Untagged>, String> {
+      let literal = Regex::new(r"\{(\d+)\}$").unwrap();
+      let mut result = vec![];
+      loop {
+         let mut line = self.read_line()?;
+         trace!("imap: << {}", line);
+         let mut literals = vec![];
+         // the line goes on after the data announced at its end
+         while let Some(size) = literal.captures(&line).and_then(|c| c[1].parse().ok()) {
+            literals.push(self.read_bytes(size)?);
+            trace!("imap: << <{} bytes>", size);
+            let rest = self.read_line()?;
+            line = format!("{}<literal>{}", literal.replace(&line, ""), rest);
+         }
+         if line.starts_with("* BYE") {
+            self.broken = true;
+         }
+         if let Some(rest) = line.strip_prefix(tag).and_then(|r| r.strip_prefix(' ')) {
+            return match rest.starts_with("OK") {
+               true => Ok(result),
+               false => Err(rest.to_string()),
+            };
+         }
+         result.push(Untagged {
+            text: line,
+            literals,
+         });
+      }
+   }
+
+   fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+      let result = self
+         .stream
+         .write_all(bytes)
+         .and_then(|_| self.stream.flush());
+      self.broken |= result.is_err();
+      result.map_err(|e| format!("cannot write to the imap server ({})", e))
+   }
+
+   /// Reads a line (without its line ending).
+   fn read_line(&mut self) -> Result<String, String> {
+      loop {
+         if let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+         }
+         self.fill()?;
+      }
+   }
+
+   /// Reads the given number of bytes.
+   fn read_bytes(&mut self, size: usize) -> Result<Vec<u8>, String> {
+      while self.buffer.len() < size {
+         self.fill()?;
+      }
+      Ok(self.buffer.drain(..size).collect())
+   }
+
+   /// Reads more data from the server into the buffer.
+   fn fill(&mut self) -> Result<(), String> {
+      let mut chunk = [0; 4096];
+      match self.stream.read(&mut chunk) {
+         Ok(0) => {
+            self.broken = true;
+            Err(String::from("the imap server closed the connection"))
+         }
+         Ok(n) => {
+            self.buffer.extend_from_slice(&chunk[..n]);
+            Ok(())
+         }
+         Err(e) => {
+            self.broken = true;
+            Err(format!("cannot read from the imap server ({})", e))
+         }
+      }
+   }
+}
+
+/// Returns the status of a tagged response e.g. `NO [TRYCREATE] no such folder`, or the line
+/// itself if it is not the response to the command with the given tag.
+fn status<'a>(line: &'a str, tag: &str) -> &'a str {
+   line
+      .strip_prefix(tag)
+      .map(|rest| rest.trim_start())
+      .unwrap_or(line)
+}
+
+/// Quotes a string for use in a command, strings with line breaks or non-ASCII characters cannot
+/// be quoted.
+pub fn quote(s: &str) -> Result<String, String> {
+   if !s.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+      return Err(String::from(
+         "imap user names, passwords, folders and search terms must be printable ASCII",
+      ));
+   }
+   Ok(format!(
+      "\"{}\"",
+      s.replace('\\', "\\\\").replace('"', "\\\"")
+   ))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::io::{BufRead, BufReader};
+   use std::net::TcpListener;
+   use std::thread;
+
+   /// Runs a minimal IMAP server that accepts a single connection, `APPEND` is refused for the
+   /// folder `Missing` and searches find the messages 3 and 7. Returns the port and the handle
+   /// for the lines received.
+   /// Runs a fake server that greets with the given line, the connection is not encrypted.
+   fn server(greeting: &'static str) -> (u16, thread::JoinHandle<Vec<String>>) {
+      let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+      let port = listener.local_addr().unwrap().port();
+      let handle = thread::spawn(move || {
+         let (stream, _) = listener.accept().expect("no connection");
+         let mut writer = stream.try_clone().unwrap();
+         let mut received = vec![];
+         let mut reader = BufReader::new(stream);
+         writer
+            .write_all(format!("{}\r\n", greeting).as_bytes())
+            .unwrap();
+         let mut line = String::new();
+         while reader.read_line(&mut line).unwrap() > 0 {
+            let command = line.trim_end().to_string();
+            line.clear();
+            received.push(command.clone());
+            let mut words = command.splitn(3, ' ');
+            let (tag, verb) = (words.next().unwrap(), words.next().unwrap_or_default());
+            let reply = match verb {
+               "APPEND" if command.contains("\"Missing\"") => {
+                  format!("{} NO [TRYCREATE] no such folder\r\n", tag)
+               }
+               "APPEND" => {
+                  // read the literal and the line break after it
+                  let size: usize = command
+                     .rsplit('{')
+                     .next()
+                     .and_then(|s| s.trim_end_matches('}').parse().ok())
+                     .unwrap();
+                  writer.write_all(b"+ go ahead\r\n").unwrap();
+                  let mut data = vec![0; size + 2];
+                  reader.read_exact(&mut data).unwrap();
+                  received.push(String::from_utf8_lossy(&data).to_string());
+                  format!("* 3 EXISTS\r\n{} OK [APPENDUID 1 3] done\r\n", tag)
+               }
+               "UID" if command.contains(" SEARCH ") => {
+                  format!("* SEARCH 3 7\r\n{} OK done\r\n", tag)
+               }
+               "UID" => format!(
+                  "* 1 FETCH (UID 3 BODY[TEXT] {{7}}\r\nhello\r\n)\r\n\
+                   * 2 FETCH (FLAGS (\\Seen))\r\n\
+                   * 2 FETCH (UID 7 BODY[TEXT] {{3}}\r\nbye FLAGS ())\r\n{} OK done\r\n",
+                  tag
+               ),
+               "LOGOUT" => format!("* BYE see you\r\n{} OK bye\r\n", tag),
+               _ => format!("{} OK done\r\n", tag),
+            };
+            writer.write_all(reply.as_bytes()).unwrap();
+            if verb == "LOGOUT" {
+               break;
+            }
+         }
+         received
+      });
+      (port, handle)
+   }
+
+   fn imap(port: u16) -> Imap {
+      Imap {
+         host: String::from("127.0.0.1"),
+         port,
+         security: Security::None,
+         username: String::from("frodo"),
+         password: Some(String::from("se\"cret")),
+         keyring: None,
+         folder: String::from("Sent"),
+      }
+   }
+
+   #[test]
+   fn append_messages() {
+      let (port, server) = server("* PREAUTH IMAP4rev1 ready");
+      let mut conn = Connection::open(&imap(port)).expect("cannot connect");
+      let data = b"Subject: hi\r\n\r\nhello\r\n";
+      assert_eq!(Ok(()), conn.append("Sent", data));
+      assert_eq!(
+         Err(String::from(
+            "APPEND failed (NO [TRYCREATE] no such folder)"
+         )),
+         conn.append("Missing", data)
+      );
+      assert!(!conn.is_broken());
+      conn.logout();
+      assert_eq!(
+         vec![
+            String::from("a1 APPEND \"Sent\" (\\Seen) {22}"),
+            String::from("Subject: hi\r\n\r\nhello\r\n\r\n"),
+            String::from("a2 APPEND \"Missing\" (\\Seen) {22}"),
+            String::from("a3 LOGOUT"),
+         ],
+         server.join().unwrap()
+      );
+   }
+
+   #[test]
+   fn search_and_fetch() {
+      let (port, server) = server("* PREAUTH IMAP4rev1 ready");
+      let mut conn = Connection::open(&imap(port)).expect("cannot connect");
+      assert_eq!(Ok(()), conn.examine("INBOX"));
+      assert_eq!(Ok(vec![3, 7]), conn.search("BODY \"<c1.\""));
+      assert_eq!(
+         Ok(vec![(3, b"hello\r\n".to_vec()), (7, b"bye".to_vec())]),
+         conn.fetch(&[3, 7], "BODY.PEEK[TEXT]")
+      );
+      assert_eq!(Ok(vec![]), conn.fetch(&[], "BODY.PEEK[TEXT]"));
+      conn.logout();
+      assert_eq!(
+         vec![
+            String::from("a1 EXAMINE \"INBOX\""),
+            String::from("a2 UID SEARCH BODY \"<c1.\""),
+            String::from("a3 UID FETCH 3,7 (BODY.PEEK[TEXT])"),
+            String::from("a4 LOGOUT"),
+         ],
+         server.join().unwrap()
+      );
+   }
+
+   #[test]
+   fn refuse_login_over_plain_connection() {
+      let (port, server) = server("* OK IMAP4rev1 ready");
+      assert_eq!(
+         Err(String::from(
+            "imap credentials are never sent over an unencrypted connection, use `security=tls` \
+             or `security=starttls`"
+         )),
+         Connection::open(&imap(port)).map(|_| ())
+      );
+      assert!(server.join().unwrap().is_empty());
+   }
+
+   #[test]
+   fn quote_strings() {
+      assert_eq!(Ok(String::from("\"a\\\\b\"")), quote("a\\b"));
+      assert!(quote("line\r\nbreak").is_err());
+      assert!(quote("Gesendete Objekte ü").is_err());
+   }
+}
@@ -0,0 +1,147 @@
+/// The `ledger` module keeps a record of all the emails ever delivered, across campaigns.
+///
+/// The ledger is a tab separated file, `ledger.tsv` in the data directory (see
+/// [`crate::history::data_dir`]), with a line per delivered email: the campaign identifier, the
+/// recipient, the `Message-ID` and when the email was sent (seconds since the epoch). Running a
+/// campaign skips the recipients the ledger has on record for it, so re-running an identical
+/// campaign file never sends an email twice.
+///
+/// Lines are only ever appended, existing content is never modified.
+use crate::events::{self, Event, Observer};
+use crate::history;
+use log::error;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the path of the ledger.
+pub fn path() -> Option<PathBuf> {
+   Some(history::data_dir()?.join("ledger.tsv"))
+}
+
+/// The `Ledger` struct holds the (campaign, recipient) pairs delivered so far, as an observer it
+/// records the emails sent.
+pub struct Ledger {
+   path: PathBuf,
+   delivered: HashSet<(String, String)>,
+   file: Option<File>,
+}
+
+impl Ledger {
+   /// Reads the ledger at the given path, a missing ledger is empty.
+   pub fn open(path: &Path) -> Result<Ledger, String> {
+      let content = match fs::read_to_string(path) {
+         Ok(content) => content,
+         Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+         Err(e) => return Err(format!("cannot read ledger {} ({})", path.display(), e)),
+      };
+      let delivered = content
+         .lines()
+         .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some((fields.next()?.to_string(), fields.next()?.to_lowercase()))
+         })
+         .collect();
+      Ok(Ledger {
+         path: path.to_path_buf(),
+         delivered,
+         file: None,
+      })
+   }
+
+   /// Whether the campaign was delivered to the recipient before.
+   pub fn contains(&self, campaign_id: &str, recipient: &str) -> bool {
+      self
+         .delivered
+         .contains(&(campaign_id.to_string(), recipient.to_lowercase()))
+   }
+
+   /// Records the delivery of an email, the ledger file is created if needed.
+   pub fn record(
+      &mut self,
+      campaign_id: &str,
+      recipient: &str,
+      message_id: &str,
+      timestamp: u64,
+   ) -> Result<(), String> {
+      if self.file.is_none() {
+         if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+               .map_err(|e| format!("cannot create directory {} ({})", dir.display(), e))?;
+         }
+         let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("cannot open ledger {} ({})", self.path.display(), e))?;
+         self.file = Some(file);
+      }
+      let line = format!(
+         "{}\t{}\t{}\t{}\n",
+         campaign_id, recipient, message_id, timestamp
+      );
+      // the line is written at once so that concurrent writers do not interleave
+      self
+         .file
+         .as_mut()
+         .unwrap()
+         .write_all(line.as_bytes())
+         .map_err(|e| format!("cannot write to ledger {} ({})", self.path.display(), e))?;
+      self
+         .delivered
+         .insert((campaign_id.to_string(), recipient.to_lowercase()));
+      Ok(())
+   }
+}
+
+impl Observer for Ledger {
+   fn notify(&mut self, event: &Event) {
+      if let Event::MessageSent {
+         campaign_id,
+         recipient,
+         message_id,
+         ..
+      } = event
+      {
+         if let Err(err) = self.record(campaign_id, recipient, message_id, events::now()) {
+            error!("{}, {} may be sent the campaign again", err, recipient);
+         }
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use tempfile::TempDir;
+
+   #[test]
+   fn ledger_records_sent_emails() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let path = dir.path().join("rmt").join("ledger.tsv");
+      let mut ledger = Ledger::open(&path).expect("open failed");
+      assert!(!ledger.contains("spring-sale", "jd@example.com"));
+      ledger.notify(&Event::MessageSent {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("jd@example.com"),
+         message_id: String::from("<spring-sale.1@example.com>"),
+         subject: String::from("Hello John"),
+         variant: None,
+      });
+      ledger.notify(&Event::MessageFailed {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("mm@gmail.com"),
+         message_id: String::from("<spring-sale.2@example.com>"),
+         subject: String::from("Hello Mickey"),
+         variant: None,
+         error: String::from("mailbox full"),
+      });
+      assert!(ledger.contains("spring-sale", "jd@example.com"));
+      // the ledger survives a round trip
+      let ledger = Ledger::open(&path).expect("open failed");
+      assert!(ledger.contains("spring-sale", "JD@example.com"));
+      assert!(!ledger.contains("spring-sale", "mm@gmail.com"));
+      assert!(!ledger.contains("autumn-sale", "jd@example.com"));
+   }
+}
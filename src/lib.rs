@@ -0,0 +1,52 @@
+//! `rmt` is a simple utility that allows the automated sending of emails using a configuration
+//! file and a template for the email body.
+//!
+//! The library crate makes the whole pipeline (config parsing, template rendering, composing and
+//! sending emails) available to other programs, `campaign::Campaign` ties it together.
+//!
+//! With the `serde` feature the parsed campaigns (`Config`, `Recipient`, `Template` and the types
+//! they hold) implement `Serialize` and `Deserialize`, e.g. for caching or transmitting them.
+//! Passwords are not serialized.
+pub mod address;
+pub mod attachment;
+pub mod audit;
+pub mod calendar;
+pub mod campaign;
+pub mod color;
+pub mod config;
+pub mod credentials;
+pub mod css;
+pub mod diagnostic;
+pub mod dns;
+pub mod doctor;
+pub mod encoding;
+pub mod events;
+pub mod followup;
+pub mod golden;
+pub mod greeting;
+pub mod history;
+pub mod imap;
+pub mod ledger;
+pub mod lint;
+pub mod listing;
+pub mod message;
+pub mod metrics;
+pub mod preview;
+pub mod progress;
+pub mod report;
+pub mod sanitizer;
+pub mod schema;
+pub mod secrets;
+pub mod segment;
+pub mod server;
+pub mod shutdown;
+pub mod smtp;
+pub mod source;
+pub mod spamcheck;
+pub mod spool;
+pub mod syslog;
+pub mod template;
+pub mod testing;
+pub mod transport;
+pub mod tz;
+pub mod user_config;
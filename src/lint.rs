@@ -0,0 +1,390 @@
+/// The `lint` module checks templates for the mistakes that render silently: placeholders
+/// misspelled or used with filters, loops that are not closed, stray percent signs and overly
+/// long lines.
+use crate::config::Config;
+use crate::template::{self, Engine, Template, AUTO_KEYS, ENDFOR, FILTERS, FOR, PLACEHOLDER};
+use regex::Regex;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// The recipient data keys rmt interprets itself, they need not appear in a template.
+const SPECIAL_KEYS: &[&str] = &["bcc", "cc", "lang", "signature", "tags", "tz"];
+
+#[derive(Debug, PartialEq)]
+/// The `Finding` struct holds a problem found in a template.
+pub struct Finding {
+   /// The line number, starting at 1
+   pub line: usize,
+   /// The column (in characters), starting at 1
+   pub column: usize,
+   /// What is wrong
+   pub message: String,
+}
+
+impl fmt::Display for Finding {
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      write!(f, "{}:{}: {}", self.line, self.column, self.message)
+   }
+}
+
+/// Returns the template keys the recipients of the campaign provide: their data keys, the keys
+/// with a default value and the automatic keys.
+pub fn provided_keys(cfg: &Config) -> HashSet<String> {
+   let mut result: HashSet<String> = AUTO_KEYS.iter().map(|k| k.to_string()).collect();
+   if cfg.event.is_none() {
+      result.remove("_EVENT_START");
+      result.remove("_EVENT_END");
+   }
+   result.extend(cfg.defaults.keys().cloned());
+   for rcp in cfg.recipients.iter() {
+      result.extend(rcp.data.keys().cloned());
+   }
+   result
+}
+
+/// Returns the keys the templated settings refer to: the subject, the preheader, the attachment
+/// command, the signatures, the calendar event and the link tracking parameters.
+pub fn setting_keys(cfg: &Config) -> HashSet<String> {
+   let mut texts: Vec<&str> = vec![&cfg.gdata.subject];
+   texts.extend(cfg.preheader.as_deref());
+   texts.extend(cfg.attachment_cmd.as_deref());
+   for signature in cfg.signatures.values().chain(cfg.signature.as_ref()) {
+      texts.push(&signature.text);
+      texts.extend(signature.html.as_deref());
+   }
+   if let Some(ref event) = cfg.event {
+      texts.push(&event.summary);
+      texts.extend(event.location.as_deref());
+      texts.extend(event.description.as_deref());
+   }
+   texts.extend(cfg.tracking.utm.iter().map(|(_, v)| v.as_str()));
+   let re = Regex::new(PLACEHOLDER).unwrap();
+   texts
+      .iter()
+      .flat_map(|t| {
+         re.captures_iter(t)
+            .filter_map(|c| Some(c.get(1)?.as_str().to_string()))
+      })
+      .collect()
+}
+
+/// Returns the recipient data keys that neither the templates nor the templated settings use,
+/// with the number of recipients that have them. Such keys are usually misspelled (`0RG` for
+/// `ORG`). The keys rmt interprets itself and the custom headers (`h_`) are left out.
+pub fn unused_keys(cfg: &Config, templates: &[&Template]) -> BTreeMap<String, usize> {
+   let used = setting_keys(cfg);
+   let mut result = BTreeMap::new();
+   for key in cfg.recipients.iter().flat_map(|r| r.data.keys()) {
+      if SPECIAL_KEYS.contains(&key.as_str())
+         || key.starts_with("h_")
+         || used.contains(key)
+         || templates.iter().any(|t| t.keys().contains(key))
+      {
+         continue;
+      }
+      *result.entry(key.clone()).or_insert(0) += 1;
+   }
+   result
+}
+
+/// Checks the template text, the placeholders are checked against the keys `provided` (if
+/// given) and lines longer than `max_line_length` characters are reported. Loops must be closed
+/// and cannot be nested.
+pub fn lint(
+   text: &str,
+   provided: Option<&HashSet<String>>,
+   max_line_length: usize,
+) -> Vec<Finding> {
+   let placeholder = Regex::new(r"^%(\w+)%").unwrap();
+   let cid = Regex::new(r"^%_CID:[^%]+%").unwrap();
+   let filtered = Regex::new(r"^%(\w+)\|(\w+)%").unwrap();
+   let for_tag = Regex::new(&format!("^{}", FOR)).unwrap();
+   let unknown = |key: &str, var: Option<&String>| {
+      var.map(|v| v.as_str()) != Some(key) && provided.is_some_and(|p| !p.contains(key))
+   };
+   let mut result = vec![];
+   // the variable and the position of the loop the text is in
+   let mut open: Option<(String, usize, usize)> = None;
+   for (i, line) in text.lines().enumerate() {
+      let finding = |offset: usize, message: String| Finding {
+         line: i + 1,
+         column: line[..offset].chars().count() + 1,
+         message,
+      };
+      let length = line.chars().count();
+      if length > max_line_length {
+         let offset = line
+            .char_indices()
+            .nth(max_line_length)
+            .map_or(line.len(), |(o, _)| o);
+         result.push(finding(
+            offset,
+            format!(
+               "the line is {} characters long, more than {}",
+               length, max_line_length
+            ),
+         ));
+      }
+      let mut offset = 0;
+      while let Some(start) = line[offset..].find('%').map(|o| o + offset) {
+         let rest = &line[start..];
+         let var = open.as_ref().map(|(v, _, _)| v);
+         if rest.starts_with("%%") {
+            offset = start + 2;
+         } else if let Some(caps) = for_tag.captures(rest) {
+            if open.is_some() {
+               result.push(finding(start, String::from("loops cannot be nested")));
+            } else {
+               let position = finding(start, String::new());
+               open = Some((caps[1].to_string(), position.line, position.column));
+            }
+            if unknown(&caps[2], None) {
+               result.push(finding(
+                  start,
+                  format!("no recipient provides the key {}", &caps[2]),
+               ));
+            }
+            offset = start + caps[0].len();
+         } else if rest.starts_with(ENDFOR) {
+            if open.take().is_none() {
+               result.push(finding(start, format!("{} without a loop", ENDFOR)));
+            }
+            offset = start + ENDFOR.len();
+         } else if let Some(caps) = placeholder.captures(rest) {
+            if unknown(&caps[1], var) {
+               result.push(finding(
+                  start,
+                  format!("no recipient provides the key {}", &caps[1]),
+               ));
+            }
+            offset = start + caps[0].len();
+         } else if let Some(m) = cid.find(rest) {
+            offset = start + m.end();
+         } else if let Some(caps) = filtered.captures(rest) {
+            if !FILTERS.contains(&&caps[2]) {
+               result.push(finding(
+                  start,
+                  format!("unknown filter {} in {}", &caps[2], &caps[0]),
+               ));
+            }
+            if unknown(&caps[1], var) {
+               result.push(finding(
+                  start,
+                  format!("no recipient provides the key {}", &caps[1]),
+               ));
+            }
+            offset = start + caps[0].len();
+         } else {
+            result.push(finding(
+               start,
+               String::from("bare % that is not part of a placeholder"),
+            ));
+            offset = start + 1;
+         }
+      }
+   }
+   if let Some((var, line, column)) = open {
+      result.push(Finding {
+         line,
+         column,
+         message: format!("the loop over {} lacks an {}", var, ENDFOR),
+      });
+   }
+   result
+}
+
+/// Checks a Tera template: a syntax error is reported at its position, the variables are
+/// checked against the keys `provided` (if given) and long lines are reported as by `lint`.
+pub fn lint_tera(
+   text: &str,
+   provided: Option<&HashSet<String>>,
+   max_line_length: usize,
+) -> Vec<Finding> {
+   let mut result: Vec<Finding> = lint(text, None, max_line_length)
+      .into_iter()
+      .filter(|f| f.message.starts_with("the line is "))
+      .collect();
+   match template::new(text).with_engine(Engine::Tera) {
+      Err(err) => {
+         // Tera points at the problem with "--> line:column" and explains it on a "= " line
+         let position = Regex::new(r"--> (\d+):(\d+)").unwrap();
+         let (line, column) = position.captures(&err).map_or((1, 1), |c| {
+            (c[1].parse().unwrap_or(1), c[2].parse().unwrap_or(1))
+         });
+         let message = err.strip_suffix(')').unwrap_or(&err);
+         let message = message
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| l.starts_with("= "))
+            .map_or(message, |l| &l[2..]);
+         result.push(Finding {
+            line,
+            column,
+            message: format!("invalid Tera template, {}", message),
+         });
+      }
+      Ok(tmpl) => {
+         let mut missing: Vec<&String> = tmpl
+            .keys()
+            .iter()
+            .filter(|k| provided.is_some_and(|p| !p.contains(*k)))
+            .collect();
+         missing.sort();
+         for key in missing {
+            let word = Regex::new(&format!(r"\b{}\b", regex::escape(key))).unwrap();
+            let (line, column) = text
+               .lines()
+               .enumerate()
+               .find_map(|(i, l)| {
+                  word
+                     .find(l)
+                     .map(|m| (i + 1, l[..m.start()].chars().count() + 1))
+               })
+               .unwrap_or((1, 1));
+            result.push(Finding {
+               line,
+               column,
+               message: format!("no recipient provides the key {}", key),
+            });
+         }
+      }
+   }
+   result.sort_by_key(|f| (f.line, f.column));
+   result
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::template;
+   use crate::testing;
+
+   #[test]
+   fn lint_clean_template() {
+      let text = "Hello %_FN%,\n\n100%% free at %ORG%?\n<img src=\"%_CID:logo.png%\">\n";
+      assert_eq!(Vec::<Finding>::new(), lint(text, None, 998));
+   }
+
+   #[test]
+   fn lint_positions() {
+      let text = "Hello %_FN%,\nsave 50% now at %ORG|upper%!\nübel %0RG%\n";
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("ORG", "EFF")],
+      )];
+      let provided = provided_keys(&cfg);
+      let findings: Vec<String> = lint(text, Some(&provided), 998)
+         .iter()
+         .map(|f| f.to_string())
+         .collect();
+      assert_eq!(
+         vec![
+            "2:8: bare % that is not part of a placeholder",
+            "2:17: unknown filter upper in %ORG|upper%",
+            "3:6: no recipient provides the key 0RG",
+         ],
+         findings
+      );
+   }
+
+   #[test]
+   fn unused_recipient_keys() {
+      let mut cfg = testing::config("fb@example.com", "News for %TEAM%");
+      cfg.recipients = vec![
+         testing::recipient(
+            "jd@example.com",
+            "John Doe",
+            &[
+               ("0RG", "EFF"),
+               ("TEAM", "a"),
+               ("cc", "bl@kf.io"),
+               ("h_X-Id", "1"),
+            ],
+         ),
+         testing::recipient(
+            "mm@gmail.com",
+            "Mickey Mouse",
+            &[("0RG", "Disney"), ("ROLE", "x")],
+         ),
+      ];
+      let tmpl = template::new("Hi %_FN% from %ORG%");
+      let html = template::new("<p>%ROLE%</p>");
+      let expected: BTreeMap<String, usize> = vec![(String::from("0RG"), 2)].into_iter().collect();
+      assert_eq!(expected, unused_keys(&cfg, &[&tmpl, &html]));
+      assert_eq!(2, unused_keys(&cfg, &[&tmpl]).len());
+   }
+
+   #[test]
+   fn lint_loops() {
+      let text = "%FOR t IN TICKETS%\n- %t%\n%ENDFOR%\n%ENDFOR%\n%FOR a IN X% %FOR b IN X%\n";
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("TICKETS", "a,b"), ("X", "")],
+      )];
+      let findings: Vec<String> = lint(text, Some(&provided_keys(&cfg)), 998)
+         .iter()
+         .map(|f| f.to_string())
+         .collect();
+      assert_eq!(
+         vec![
+            "4:1: %ENDFOR% without a loop",
+            "5:14: loops cannot be nested",
+            "5:1: the loop over a lacks an %ENDFOR%",
+         ],
+         findings
+      );
+   }
+
+   #[test]
+   fn lint_long_lines() {
+      let text = format!("short\n{}\n", "ä".repeat(12));
+      assert_eq!(
+         vec![Finding {
+            line: 2,
+            column: 11,
+            message: String::from("the line is 12 characters long, more than 10"),
+         }],
+         lint(&text, None, 10)
+      );
+   }
+
+   #[test]
+   fn lint_tera_templates() {
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("ORG", "EFF")],
+      )];
+      let provided = provided_keys(&cfg);
+      let text = "Hello {{ _FN }},\n{% if ORG %}50% off at {{ 0RG }}{% endif %}\n";
+      let findings: Vec<String> = lint_tera(text, Some(&provided), 998)
+         .iter()
+         .map(|f| f.to_string())
+         .collect();
+      assert_eq!(
+         vec![
+            "2:28: invalid Tera template, expected `or`, `and`, `not`, `<=`, `>=`, `<`, `>`, \
+             `==`, `!=`, `+`, `-`, `*`, `/`, `%`, a filter, or a variable end (`}}`)"
+         ],
+         findings
+      );
+      let text = "Hello {{ _FN }},\n{% if ORG %}50% off at {{ ROLE }}{% endif %}\n";
+      let findings: Vec<String> = lint_tera(text, Some(&provided), 10)
+         .iter()
+         .map(|f| f.to_string())
+         .collect();
+      assert_eq!(
+         vec![
+            "1:11: the line is 16 characters long, more than 10",
+            "2:11: the line is 44 characters long, more than 10",
+            "2:27: no recipient provides the key ROLE",
+         ],
+         findings
+      );
+   }
+}
@@ -0,0 +1,375 @@
+/// The `listing` module formats the parsed recipients for display and export and imports
+/// recipients from CSV files.
+use crate::address::Address;
+use crate::config::{Config, Recipient};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+
+/// Returns the addresses the email to the recipient is copied to (see `Recipient::effective_cc`).
+fn cc(cfg: &Config, rcp: &Recipient) -> Vec<String> {
+   rcp.effective_cc(cfg)
+      .iter()
+      .map(|a| a.to_string())
+      .collect()
+}
+
+/// Returns the data keys of all recipients, sorted.
+pub fn data_keys(cfg: &Config) -> Vec<&str> {
+   let keys: BTreeSet<&str> = cfg
+      .recipients
+      .iter()
+      .flat_map(|r| r.data.keys().map(|k| k.as_ref()))
+      .collect();
+   keys.into_iter().collect()
+}
+
+/// Formats the recipients as a table, one recipient per line.
+pub fn table(cfg: &Config) -> String {
+   let rows: Vec<[String; 4]> = cfg
+      .recipients
+      .iter()
+      .map(|rcp| {
+         let mut data: Vec<String> = rcp
+            .data
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+         data.sort();
+         [
+            rcp.email.clone(),
+            rcp.names.join(" "),
+            cc(cfg, rcp).join(", "),
+            data.join("; "),
+         ]
+      })
+      .collect();
+   let header = [
+      String::from("EMAIL"),
+      String::from("NAMES"),
+      String::from("CC"),
+      String::from("DATA"),
+   ];
+   let width = |i: usize| {
+      rows
+         .iter()
+         .chain(Some(&header))
+         .map(|r| r[i].chars().count())
+         .max()
+         .unwrap_or(0)
+   };
+   let widths = [width(0), width(1), width(2)];
+   let mut result = String::new();
+   for row in Some(&header).into_iter().chain(rows.iter()) {
+      let line = format!(
+         "{:<w0$}  {:<w1$}  {:<w2$}  {}",
+         row[0],
+         row[1],
+         row[2],
+         row[3],
+         w0 = widths[0],
+         w1 = widths[1],
+         w2 = widths[2]
+      );
+      result.push_str(line.trim_end());
+      result.push('\n');
+   }
+   result
+}
+
+/// Quotes a CSV field if needed (RFC 4180).
+pub fn csv_field(field: &str) -> String {
+   if field.contains([',', '"', '\n', '\r']) {
+      format!("\"{}\"", field.replace('"', "\"\""))
+   } else {
+      field.to_string()
+   }
+}
+
+/// Parses CSV (RFC 4180) text into records, empty lines are skipped.
+pub fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+   let mut records = vec![];
+   let mut record: Vec<String> = vec![];
+   let mut field = String::new();
+   let mut quoted = false;
+   let mut chars = text.chars().peekable();
+   let mut line = 1;
+   while let Some(c) = chars.next() {
+      match c {
+         '"' if quoted => {
+            if chars.peek() == Some(&'"') {
+               chars.next();
+               field.push('"');
+            } else {
+               quoted = false;
+            }
+         }
+         '"' if field.is_empty() => quoted = true,
+         ',' if !quoted => record.push(std::mem::take(&mut field)),
+         '\r' if !quoted && chars.peek() == Some(&'\n') => (),
+         '\n' if !quoted => {
+            line += 1;
+            record.push(std::mem::take(&mut field));
+            if record.len() > 1 || !record[0].is_empty() {
+               records.push(std::mem::take(&mut record));
+            } else {
+               record.clear();
+            }
+         }
+         _ => {
+            if c == '\n' {
+               line += 1;
+            }
+            field.push(c)
+         }
+      }
+   }
+   if quoted {
+      return Err(format!("unterminated quoted field in line {}", line));
+   }
+   if !field.is_empty() || !record.is_empty() {
+      record.push(field);
+      records.push(record);
+   }
+   Ok(records)
+}
+
+/// The `CsvHeader` struct holds the columns named by the first line of a recipients CSV file.
+pub struct CsvHeader {
+   columns: Vec<String>,
+   email: usize,
+   names: Option<usize>,
+}
+
+impl CsvHeader {
+   /// Reads the columns from the header record: `email` is required, `names` holds the
+   /// recipient's names and every other column is a data key.
+   pub fn parse(record: Vec<String>) -> Result<CsvHeader, String> {
+      let columns: Vec<String> = record.into_iter().map(|h| h.trim().to_string()).collect();
+      let column = |name: &str| columns.iter().position(|h| h.eq_ignore_ascii_case(name));
+      let email = column("email").ok_or("the CSV file has no *email* column")?;
+      let names = column("names");
+      Ok(CsvHeader {
+         columns,
+         email,
+         names,
+      })
+   }
+
+   /// Reads the recipient from a record, `n` is the number of the record (the header not
+   /// counted) for the error messages. Empty data values are skipped.
+   pub fn recipient(&self, record: &[String], n: usize) -> Result<Recipient, String> {
+      let field = |c: usize| record.get(c).map(|f| f.trim()).unwrap_or_default();
+      if let Err(e) = Address::parse(field(self.email)) {
+         return Err(format!(
+            "invalid email in CSV record {}: {} ({})",
+            n,
+            field(self.email),
+            e
+         ));
+      }
+      let mut data = HashMap::new();
+      for (c, key) in self.columns.iter().enumerate() {
+         if c != self.email && Some(c) != self.names && !key.is_empty() && !field(c).is_empty() {
+            data.insert(key.clone(), field(c).to_string());
+         }
+      }
+      let rcp = Recipient {
+         email: field(self.email).to_string(),
+         names: self
+            .names
+            .map(|c| field(c).split_whitespace().map(|n| n.to_string()).collect())
+            .unwrap_or_default(),
+         data,
+      };
+      if rcp.names.is_empty() {
+         return Err(format!("no names for {} in the CSV file", rcp.email));
+      }
+      Ok(rcp)
+   }
+}
+
+/// Reads recipients from CSV text.
+///
+/// The first line names the columns, see `CsvHeader`. Use `source::CsvSource` to read large
+/// files one recipient at a time.
+pub fn recipients_from_csv(text: &str) -> Result<Vec<Recipient>, String> {
+   let mut records = parse_csv(text)?.into_iter();
+   let header = CsvHeader::parse(records.next().ok_or("the CSV file is empty")?)?;
+   records
+      .enumerate()
+      .map(|(i, record)| header.recipient(&record, i + 1))
+      .collect()
+}
+
+/// Formats the recipients as CSV with a header line, every data key gets a column of its own.
+/// The resolved *cc* addresses are in the `effective_cc` column, a per-recipient *cc* datum keeps
+/// its `cc` column.
+pub fn csv(cfg: &Config) -> String {
+   to_csv(cfg, true)
+}
+
+/// Formats the recipients as CSV for editing in a spreadsheet, the result can be read with
+/// `recipients_from_csv`.
+///
+/// Unlike `csv` there is no column with the resolved *cc* addresses, a per-recipient *cc* datum
+/// is exported like any other data key.
+pub fn export_csv(cfg: &Config) -> String {
+   to_csv(cfg, false)
+}
+
+fn to_csv(cfg: &Config, with_cc: bool) -> String {
+   let keys = data_keys(cfg);
+   let line = |fields: Vec<String>| {
+      fields
+         .iter()
+         .map(|f| csv_field(f))
+         .collect::<Vec<String>>()
+         .join(",")
+         + "\r\n"
+   };
+   let mut header = vec![String::from("email"), String::from("names")];
+   if with_cc {
+      header.push(String::from("effective_cc"));
+   }
+   header.extend(keys.iter().map(|k| k.to_string()));
+   let mut result = line(header);
+   for rcp in cfg.recipients.iter() {
+      let mut fields = vec![rcp.email.clone(), rcp.names.join(" ")];
+      if with_cc {
+         fields.push(cc(cfg, rcp).join(", "));
+      }
+      fields.extend(
+         keys
+            .iter()
+            .map(|k| rcp.data.get(*k).cloned().unwrap_or_default()),
+      );
+      result.push_str(&line(fields));
+   }
+   result
+}
+
+/// Returns the JSON representation of the recipients.
+pub fn json(cfg: &Config) -> Value {
+   let recipients: Vec<Value> = cfg
+      .recipients
+      .iter()
+      .map(|rcp| {
+         json!({
+            "email": rcp.email,
+            "names": rcp.names,
+            "cc": cc(cfg, rcp),
+            "data": rcp.data,
+         })
+      })
+      .collect();
+   Value::Array(recipients)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+
+   fn prep_config() -> Config {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.gdata.cc = testing::addresses("boss@example.com");
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[("ORG", "EFF, Inc.")]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[("TITLE", "Dr.")]),
+      ];
+      cfg
+   }
+
+   #[test]
+   fn recipients_table() {
+      let expected = "\
+EMAIL           NAMES         CC                DATA
+jd@example.com  John Doe      boss@example.com  ORG=EFF, Inc.
+mm@gmail.com    Mickey Mouse  boss@example.com  TITLE=Dr.
+";
+      assert_eq!(expected, table(&prep_config()));
+   }
+
+   #[test]
+   fn recipients_csv() {
+      let expected = "email,names,effective_cc,ORG,TITLE\r\n\
+                      jd@example.com,John Doe,boss@example.com,\"EFF, Inc.\",\r\n\
+                      mm@gmail.com,Mickey Mouse,boss@example.com,,Dr.\r\n";
+      assert_eq!(expected, csv(&prep_config()));
+      assert_eq!("\"say \"\"hi\"\"\"", csv_field("say \"hi\""));
+   }
+
+   #[test]
+   fn recipients_with_their_own_cc() {
+      let mut cfg = prep_config();
+      cfg.recipients[0]
+         .data
+         .insert(String::from("cc"), String::from("+x@example.com"));
+      cfg.recipients[1].data.insert(
+         String::from("cc"),
+         String::from("a@example.com, b@example.com"),
+      );
+      let table = table(&cfg);
+      assert!(
+         table.contains("jd@example.com  John Doe      boss@example.com, x@example.com"),
+         "{}",
+         table
+      );
+      assert!(
+         table.contains("mm@gmail.com    Mickey Mouse  a@example.com, b@example.com"),
+         "{}",
+         table
+      );
+      assert_eq!(
+         json!(["a@example.com", "b@example.com"]),
+         json(&cfg)[1]["cc"]
+      );
+      let csv = csv(&cfg);
+      assert!(
+         csv.starts_with("email,names,effective_cc,ORG,TITLE,cc\r\n"),
+         "{}",
+         csv
+      );
+   }
+
+   #[test]
+   fn parse_csv_quoting() {
+      let text = "a,\"b, \"\"c\"\"\"\r\n\r\n\"multi\nline\",\nlast";
+      let expected = vec![
+         vec![String::from("a"), String::from("b, \"c\"")],
+         vec![String::from("multi\nline"), String::new()],
+         vec![String::from("last")],
+      ];
+      assert_eq!(Ok(expected), parse_csv(text));
+      assert!(parse_csv("\"open").is_err());
+   }
+
+   #[test]
+   fn recipients_csv_round_trip() {
+      let mut cfg = prep_config();
+      cfg.recipients[1]
+         .data
+         .insert(String::from("cc"), String::from("+x@example.com"));
+      let exported = export_csv(&cfg);
+      assert!(exported.starts_with("email,names,ORG,TITLE,cc\r\n"));
+      let recipients = recipients_from_csv(&exported).expect("invalid CSV");
+      assert_eq!(cfg.recipients, recipients);
+      assert_eq!(
+         Err(String::from("the CSV file has no *email* column")),
+         recipients_from_csv("names\nJohn Doe\n")
+      );
+      assert_eq!(
+         Err(String::from("no names for jd@example.com in the CSV file")),
+         recipients_from_csv("email,names\njd@example.com,\n")
+      );
+   }
+
+   #[test]
+   fn recipients_json() {
+      let json = json(&prep_config());
+      assert_eq!("mm@gmail.com", json[1]["email"]);
+      assert_eq!(json!(["Mickey", "Mouse"]), json[1]["names"]);
+      assert_eq!(json!(["boss@example.com"]), json[1]["cc"]);
+      assert_eq!(json!({"TITLE": "Dr."}), json[1]["data"]);
+   }
+}
@@ -1,22 +1,260 @@
 #[macro_use]
 extern crate clap;
-use clap::App;
-mod config;
-mod template;
+use chrono::Utc;
+use clap::{App, ArgMatches};
+use log::{debug, info, warn, LevelFilter};
+use rmt::address::Address;
+use rmt::config::Variant;
+use rmt::diagnostic::{Diagnostic, Severity};
+use rmt::events::Observer;
+use rmt::progress::Verbosity;
+use rmt::segment::Segment;
+use rmt::template::Template;
+use rmt::transport::Transport;
+use rmt::user_config::{Defaults, UserConfig};
+use rmt::{
+   audit, campaign, color, config, credentials, dns, doctor, events, followup, golden, history,
+   imap, ledger, lint, listing, message, metrics, preview, progress, report, secrets, server,
+   shutdown, source, spamcheck, spool, syslog, template, transport, user_config,
+};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Any other error, e.g. an unreadable report file or an unknown recipient.
+const EXIT_ERROR: i32 = 1;
+/// A check found problems: invalid recipient data, lint findings, snapshot differences etc.
+const EXIT_INVALID: i32 = 2;
+/// Some of the emails could not be delivered.
+const EXIT_PARTIAL: i32 = 3;
+/// The send was interrupted, the remaining recipients are left for a later run.
+const EXIT_INTERRUPTED: i32 = 4;
+/// The config file cannot be read or is invalid.
+const EXIT_CONFIG: i32 = 5;
+/// The template cannot be read or is invalid.
+const EXIT_TEMPLATE: i32 = 6;
+/// None of the emails could be sent, e.g. the SMTP server is unreachable or rejects the
+/// credentials.
+const EXIT_TRANSPORT: i32 = 7;
+/// The send was not confirmed: the user declined or there is no terminal to ask on.
+const EXIT_UNCONFIRMED: i32 = 8;
+
+/// The config files looked for in the current directory if none is given with `-c` (or
+/// `RMT_CONFIG`), the first one found is used.
+const DEFAULT_CONFIGS: &[&str] = &["rmt.ini", "rmt.toml"];
+/// The template looked for in the current directory if none is given with `-t` (or
+/// `RMT_TEMPLATE`).
+const DEFAULT_TEMPLATE: &str = "template.txt";
+
+/// The output level as per `--quiet` and `-v`, set once the arguments are parsed.
+static VERBOSITY: OnceLock<Verbosity> = OnceLock::new();
+
+/// Returns the output level of the command.
+fn verbosity() -> Verbosity {
+   VERBOSITY.get().copied().unwrap_or_default()
+}
+
+/// Whether the errors are reported as JSON (`--error-format json`), set once the arguments are
+/// parsed.
+static JSON_ERRORS: OnceLock<bool> = OnceLock::new();
+
+/// The user-level configuration, loaded once the arguments are parsed. It is applied to the
+/// campaigns loaded by `load_config`.
+static USER_CONFIG: OnceLock<UserConfig> = OnceLock::new();
+
+/// Returns whether the errors are reported as JSON, one object per line.
+fn json_errors() -> bool {
+   JSON_ERRORS.get().copied().unwrap_or_default()
+}
+
+/// Prints a status line or a warning, unless `--quiet` is given or the errors are reported as
+/// JSON.
+macro_rules! say {
+   ($($arg:tt)*) => {
+      if verbosity() > Verbosity::Quiet && !json_errors() {
+         println!($($arg)*);
+      }
+   };
+}
 
 macro_rules! ee {
    ($res:expr) => {
+      ee!($res, EXIT_ERROR)
+   };
+   ($res:expr, $status:expr) => {
       match $res {
          Ok(v) => v,
          Err(m) => {
-            println!("!! error: {}", m);
-            ::std::process::exit(1)
+            report(&Diagnostic::error(&m.to_string()));
+            ::std::process::exit($status)
+         }
+      }
+   };
+}
+
+/// Prints the error or warning, as JSON with `--error-format json`. Warnings are left out with
+/// `--quiet`.
+fn report(diagnostic: &Diagnostic) {
+   match diagnostic.severity {
+      _ if json_errors() => {
+         if diagnostic.severity == Severity::Error || verbosity() > Verbosity::Quiet {
+            println!("{}", diagnostic.to_json())
          }
       }
+      Severity::Error => println!("{}", color::error(&format!("!! error: {}", diagnostic))),
+      Severity::Warning => say!("{}", color::warning(&format!("!! warning: {}", diagnostic))),
+   }
+}
+
+/// Reports a list of errors or warnings under a headline, every one of them as a JSON object with
+/// `--error-format json` (the headline is left out then). Warnings are left out with `--quiet`.
+fn report_all(severity: Severity, headline: &str, diagnostics: &[Diagnostic]) {
+   if json_errors() {
+      diagnostics.iter().for_each(report);
+      return;
+   }
+   match severity {
+      Severity::Error => println!("{}", color::error(&format!("!! error: {}", headline))),
+      Severity::Warning => say!("{}", color::warning(&format!("!! warning: {}", headline))),
+   }
+   for diagnostic in diagnostics {
+      if severity == Severity::Error {
+         println!("   - {}", diagnostic)
+      } else {
+         say!("   - {}", diagnostic)
+      }
+   }
+}
+
+/// Returns the value of a global argument, which may be given before or after the subcommand.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+   matches
+      .value_of(name)
+      .or_else(|| matches.subcommand().1.and_then(|m| m.value_of(name)))
+}
+
+/// Loads the campaign from the config files, exits with status 5 if they cannot be read or are
+/// invalid. The error is reported along with its location in the config files, if known.
+fn load_config(config_paths: &[&str], lenient: bool) -> config::Config {
+   if lenient {
+      load_config_with(config_paths, config::instantiate_lenient)
+   } else {
+      load_config_with(config_paths, config::instantiate)
+   }
+}
+
+/// A way of parsing the config files e.g. `config::instantiate_streaming`.
+type Instantiate =
+   fn(&[&str], Option<&UserConfig>, &str, &str) -> Result<config::Config, config::ConfigError>;
+
+/// Works like `load_config` with the given way of parsing the config files e.g.
+/// `config::instantiate_streaming`.
+fn load_config_with(config_paths: &[&str], instantiate: Instantiate) -> config::Config {
+   if config_paths.is_empty() {
+      ee!(
+         Err(format!(
+            "no config file given (--config) and no {} in the current directory",
+            DEFAULT_CONFIGS.join(" or ")
+         )),
+         EXIT_CONFIG
+      )
+   }
+   let mut cfg = match instantiate(
+      config_paths,
+      USER_CONFIG.get(),
+      crate_name!(),
+      crate_version!(),
+   ) {
+      Ok(cfg) => cfg,
+      Err(err) => {
+         report(&Diagnostic::from(&err));
+         ::std::process::exit(EXIT_CONFIG)
+      }
    };
+   thread_under(&mut cfg);
+   cfg
+}
+
+/// Reads the `Message-ID`s a follow-up campaign replies to from the history (see
+/// `history::thread_under`), exits with status 5 if they cannot be read.
+fn thread_under(cfg: &mut config::Config) {
+   if cfg.threading.follow_up_of.is_some() {
+      let dir = ee!(
+         history::campaigns_dir().ok_or("cannot determine the data directory"),
+         EXIT_CONFIG
+      );
+      ee!(history::thread_under(&dir, cfg), EXIT_CONFIG);
+   }
+}
+
+/// Returns the log level for the number of `-v` flags: warnings and errors (or the `default`
+/// level) without any, `info`, `debug` and `trace` with one, two and three. With `--quiet` only
+/// errors are logged.
+fn log_level(verbosity: u64, quiet: bool, default: Option<LevelFilter>) -> LevelFilter {
+   match verbosity {
+      _ if quiet => LevelFilter::Error,
+      0 => default.unwrap_or(LevelFilter::Warn),
+      1 => LevelFilter::Info,
+      2 => LevelFilter::Debug,
+      _ => LevelFilter::Trace,
+   }
+}
+
+/// Sets up logging and the output level, the log level depends on the number of `-v` flags and
+/// defaults to the one in the user-level configuration. With `--quiet` only errors are logged.
+fn init_logging(matches: &ArgMatches, default: Option<LevelFilter>) -> Result<(), String> {
+   // the global args may be given before or after the subcommand
+   let sub = matches.subcommand().1;
+   let verbosity =
+      matches.occurrences_of("verbose") + sub.map(|m| m.occurrences_of("verbose")).unwrap_or(0);
+   let log_file = matches
+      .value_of("log_file")
+      .or_else(|| sub.and_then(|m| m.value_of("log_file")));
+   let target = matches
+      .value_of("log")
+      .or_else(|| sub.and_then(|m| m.value_of("log")))
+      .unwrap_or("stderr");
+   let quiet = matches.is_present("quiet") || sub.is_some_and(|m| m.is_present("quiet"));
+   if quiet && verbosity > 0 {
+      return Err(String::from("--quiet and --verbose cannot be combined"));
+   }
+   let _ = VERBOSITY.set(match verbosity {
+      _ if quiet => Verbosity::Quiet,
+      0 => Verbosity::Normal,
+      _ => Verbosity::Verbose,
+   });
+   let level = log_level(verbosity, quiet, default);
+   if let Some(target) = syslog::Target::parse(target)? {
+      if log_file.is_some() {
+         return Err(String::from("--log-file only works with --log stderr"));
+      }
+      return syslog::init(target, level);
+   }
+   let mut builder = env_logger::Builder::new();
+   builder.filter_level(level).format_timestamp_secs();
+   builder.parse_default_env();
+   if let Some(path) = log_file {
+      let file = OpenOptions::new()
+         .create(true)
+         .append(true)
+         .open(path)
+         .map_err(|e| format!("cannot open log file {} ({})", path, e))?;
+      builder.target(env_logger::Target::Pipe(Box::new(file)));
+   }
+   builder.try_init().map_err(|e| e.to_string())
 }
 
 fn main() {
+   // before parsing the arguments, RMT_CONFIG and RMT_TEMPLATE may be set in .env
+   let loaded = ee!(credentials::load_dotenv(Path::new(".env")));
    let yaml = load_yaml!("cli.yml");
    let app = App::from_yaml(yaml)
       .about(crate_description!())
@@ -24,40 +262,1634 @@ fn main() {
       .author(crate_authors!())
       .version(crate_version!());
    let matches = app.get_matches();
+   let _ = JSON_ERRORS.set(global_value(&matches, "error_format") == Some("json"));
+   let user = USER_CONFIG.get_or_init(|| ee!(user_config::load(), EXIT_CONFIG));
+   ee!(init_logging(&matches, user.defaults.log_level));
+   let choice = global_value(&matches, "color").unwrap_or("auto");
+   color::init(ee!(color::ColorChoice::parse(choice)));
+   if loaded > 0 {
+      debug!("{} RMT_* variables loaded from .env", loaded);
+   }
 
    if let Some(matches) = matches.subcommand_matches("sample") {
-      if let Some(_matches) = matches.subcommand_matches("config") {
-         println!("{}", config::gen_config(crate_name!(), crate_version!()));
+      if let Some(matches) = matches.subcommand_matches("config") {
+         let output = matches.value_of("output");
+         let format = match matches.value_of("format") {
+            Some(format) => ee!(config::FileFormat::parse(format)),
+            None => output
+               .map(|o| config::FileFormat::of(Path::new(o)))
+               .unwrap_or_default(),
+         };
+         let text = config::gen_config(crate_name!(), crate_version!());
+         ee!(write_sample(output, &ee!(config::convert(&text, format))));
+      }
+      if let Some(matches) = matches.subcommand_matches("template") {
+         let text = if matches.is_present("html") {
+            config::gen_html_template(crate_name!(), crate_version!())
+         } else {
+            config::gen_template(crate_name!(), crate_version!())
+         };
+         ee!(write_sample(matches.value_of("output"), &text));
+      }
+   } else if let Some(matches) = matches.subcommand_matches("init") {
+      ee!(init(matches.value_of("name").unwrap()));
+      if matches.is_present("edit") {
+         let name = matches.value_of("name").unwrap();
+         ee!(edit(
+            &[format!("{}.ini", name), format!("{}.eml", name)],
+            &user.defaults
+         ));
+      }
+   } else if let Some(matches) = matches.subcommand_matches("export") {
+      if let Some(matches) = matches.subcommand_matches("recipients") {
+         let cfg = load_config(&config_paths(matches), false);
+         let csv = listing::export_csv(&cfg);
+         match matches.value_of("out") {
+            Some(path) => {
+               ee!(fs::write(path, csv).map_err(|e| format!("cannot write {} ({})", path, e)));
+               say!(
+                  "* exported {} recipient(s) to {}",
+                  cfg.recipients.len(),
+                  path
+               );
+            }
+            None => print!("{}", csv),
+         }
       }
-      if let Some(_matches) = matches.subcommand_matches("template") {
-         println!("{}", config::gen_template(crate_name!(), crate_version!()));
+   } else if let Some(matches) = matches.subcommand_matches("validate") {
+      validate(matches, &user.defaults);
+   } else if let Some(matches) = matches.subcommand_matches("lint") {
+      if let Some(matches) = matches.subcommand_matches("template") {
+         lint_template(matches, &user.defaults);
+      }
+   } else if let Some(matches) = matches.subcommand_matches("spamcheck") {
+      spamcheck(matches, &user.defaults);
+   } else if let Some(matches) = matches.subcommand_matches("preview") {
+      preview(matches, &user.defaults);
+   } else if let Some(matches) = matches.subcommand_matches("render") {
+      render(matches, &user.defaults);
+   } else if let Some(matches) = matches.subcommand_matches("doctor") {
+      let mut cfg = load_config(&config_paths(matches), false);
+      let smtp = ee!(cfg.smtp.as_mut().ok_or("No *smtp* section in config file"));
+      ee!(smtp.resolve_credentials());
+      let checks = doctor::diagnose(smtp, &cfg.gdata.from.to_string());
+      for check in checks.iter() {
+         match check.result {
+            Ok(ref details) => say!("* {}: {}", check.name, details),
+            Err(ref problem) => {
+               println!(
+                  "{}",
+                  color::error(&format!("!! {}: {}", check.name, problem))
+               );
+               if let Some(ref hint) = check.hint {
+                  println!("   hint: {}", hint);
+               }
+            }
+         }
+      }
+      if checks.iter().any(|c| c.result.is_err()) {
+         ::std::process::exit(EXIT_INVALID)
+      }
+      say!("{}", color::success("* all checks passed"));
+   } else if let Some(matches) = matches.subcommand_matches("list") {
+      let cfg = load_config(&config_paths(matches), false);
+      match matches.value_of("format") {
+         Some("csv") => print!("{}", listing::csv(&cfg)),
+         Some("json") => println!("{:#}", listing::json(&cfg)),
+         Some("dump") => print!("{}", cfg.dump()),
+         _ => print!("{}", listing::table(&cfg)),
+      }
+   } else if let Some(matches) = matches.subcommand_matches("stats") {
+      let record = match matches.value_of("report") {
+         Some(path) => ee!(history::read(Path::new(path))),
+         None => {
+            let dir = ee!(history::campaigns_dir().ok_or("cannot determine the data directory"));
+            let entry = match matches.value_of("campaign") {
+               Some(campaign_id) => ee!(history::latest(&dir, campaign_id)),
+               None => ee!(history::load(&dir)).pop(),
+            };
+            match entry {
+               Some(entry) => ee!(history::read(&entry.path)),
+               None => ee!(Err("no campaign record found")),
+            }
+         }
+      };
+      let stats = ee!(report::Stats::from_record(&record));
+      match matches.value_of("format") {
+         Some("csv") => print!("{}", stats.to_csv()),
+         Some("html") => print!("{}", stats.to_html()),
+         _ => print!("{}", stats.to_text()),
+      }
+   } else if let Some(matches) = matches.subcommand_matches("followup") {
+      followup(matches);
+   } else if let Some(matches) = matches.subcommand_matches("replies") {
+      replies(matches);
+   } else if matches.subcommand_matches("history").is_some() {
+      let dir = ee!(history::campaigns_dir().ok_or("cannot determine the data directory"));
+      let entries = ee!(history::load(&dir));
+      if entries.is_empty() {
+         println!("no campaigns sent yet");
+      } else {
+         print!("{}", history::format(&entries));
+      }
+   } else if let Some(matches) = matches.subcommand_matches("auth") {
+      if let Some(matches) = matches.subcommand_matches("set") {
+         let name = matches.value_of("name").unwrap();
+         let secret = ee!(read_secret(&format!("secret for {}: ", name)));
+         ee!(secrets::set(name, &secret));
+         say!("* secret {} stored in the keyring", name);
+      } else if let Some(matches) = matches.subcommand_matches("get") {
+         println!("{}", ee!(secrets::get(matches.value_of("name").unwrap())));
+      } else if let Some(matches) = matches.subcommand_matches("delete") {
+         let name = matches.value_of("name").unwrap();
+         ee!(secrets::delete(name));
+         say!("* secret {} removed from the keyring", name);
       }
    } else if let Some(matches) = matches.subcommand_matches("run") {
-      println!("Run mailer tool");
-      if matches.is_present("dry_run") {
-         println!("* dry run, no action");
+      run(matches, &user.defaults, false);
+   } else if let Some(matches) = matches.subcommand_matches("resend") {
+      run(matches, &user.defaults, false);
+   } else if let Some(matches) = matches.subcommand_matches("resume") {
+      run(matches, &user.defaults, true);
+   } else if let Some(matches) = matches.subcommand_matches("queue") {
+      run(matches, &user.defaults, false);
+   } else if let Some(matches) = matches.subcommand_matches("flush") {
+      flush(matches);
+   } else if let Some(matches) = matches.subcommand_matches("daemon") {
+      daemon(matches);
+   } else if let Some(matches) = matches.subcommand_matches("serve") {
+      serve(matches);
+   }
+}
+
+/// Reads a secret from the terminal without echoing it or, if stdin is not a terminal, from the
+/// first line of stdin.
+fn read_secret(prompt: &str) -> Result<String, String> {
+   let secret = if io::stdin().is_terminal() {
+      rpassword::read_password_from_tty(Some(prompt)).map_err(|e| e.to_string())?
+   } else {
+      let mut line = String::new();
+      io::stdin()
+         .read_line(&mut line)
+         .map_err(|e| e.to_string())?;
+      line.trim_end_matches(['\r', '\n']).to_string()
+   };
+   if secret.is_empty() {
+      return Err(String::from("empty secret"));
+   }
+   Ok(secret)
+}
+
+/// Asks the user a question, returns the (trimmed) answer or the default if the answer is empty.
+fn ask(question: &str, default: Option<&str>) -> Result<String, String> {
+   match default {
+      Some(d) if !d.is_empty() => print!("{} [{}]: ", question, d),
+      _ => print!("{}: ", question),
+   }
+   io::stdout().flush().map_err(|e| e.to_string())?;
+   let mut answer = String::new();
+   let n = io::stdin()
+      .read_line(&mut answer)
+      .map_err(|e| format!("cannot read the answer ({})", e))?;
+   if n == 0 {
+      return Err(String::from("no answer, giving up"));
+   }
+   match answer.trim() {
+      "" => Ok(default.unwrap_or_default().to_string()),
+      a => Ok(a.to_string()),
+   }
+}
+
+/// Writes a sample file (see the `sample` subcommand) to the given path, which must not exist yet,
+/// or to stdout.
+fn write_sample(path: Option<&str>, text: &str) -> Result<(), String> {
+   let text = text.trim_end();
+   let path = match path {
+      Some(path) => path,
+      None => {
+         println!("{}", text);
+         return Ok(());
+      }
+   };
+   if Path::new(path).exists() {
+      return Err(format!("{} exists already, not overwriting it", path));
+   }
+   fs::write(path, format!("{}\n", text)).map_err(|e| format!("cannot write {} ({})", path, e))?;
+   say!("* wrote {}", path);
+   Ok(())
+}
+
+/// Asks the questions needed to set up a new campaign and writes its config and template, this
+/// implements the `init` subcommand.
+fn init(name: &str) -> Result<(), String> {
+   let config_path = format!("{}.ini", name);
+   let template_path = format!("{}.eml", name);
+   for path in [&config_path, &template_path].iter() {
+      if Path::new(path).exists() {
+         return Err(format!("{} exists already, not overwriting it", path));
+      }
+   }
+   let from = loop {
+      let from = ask("From (e.g. \"Frodo Baggins\" <fb@example.com>)", None)?;
+      if config::check_email(&from) {
+         break from;
+      }
+      println!(
+         "{}",
+         color::error(&format!("!! invalid email address: {}", from))
+      );
+   };
+   let subject = loop {
+      let subject = ask("Subject (e.g. Hello %_FN%!)", None)?;
+      if !subject.is_empty() {
+         break subject;
+      }
+   };
+   let host = ask("SMTP server (leave empty to set it up later)", None)?;
+   let smtp = if host.is_empty() {
+      None
+   } else {
+      let security = loop {
+         match ask("security (tls, starttls or none)", Some("tls"))?.as_ref() {
+            "tls" => break config::Security::Tls,
+            "starttls" => break config::Security::StartTls,
+            "none" => break config::Security::None,
+            other => println!(
+               "{}",
+               color::error(&format!("!! invalid security: {}", other))
+            ),
+         }
+      };
+      let default_port = match security {
+         config::Security::Tls => "465",
+         config::Security::StartTls => "587",
+         config::Security::None => "25",
+      };
+      let port = loop {
+         let port = ask("port", Some(default_port))?;
+         match port.parse::<u16>() {
+            Ok(p) if p > 0 => break p,
+            _ => println!("{}", color::error(&format!("!! invalid port: {}", port))),
+         }
+      };
+      let username = ask("user name (leave empty if none)", None)?;
+      Some(config::Smtp {
+         host,
+         port,
+         security,
+         username: if username.is_empty() {
+            None
+         } else {
+            Some(username)
+         },
+         password: None,
+         keyring: None,
+         rate: None,
+         domain_rates: HashMap::new(),
+         jitter: None,
+         group_by_domain: false,
+         domain_burst: None,
+         connections: 1,
+         dsn_notify: None,
+         dsn_ret: None,
+         smtputf8: false,
+      })
+   };
+   let recipients = loop {
+      let path = ask("recipients CSV file (leave empty for an example)", None)?;
+      if path.is_empty() {
+         break vec![];
+      }
+      match fs::read_to_string(&path)
+         .map_err(|e| format!("cannot read {} ({})", path, e))
+         .and_then(|text| listing::recipients_from_csv(&text))
+      {
+         Ok(recipients) => break recipients,
+         Err(err) => println!("{}", color::error(&format!("!! {}", err))),
+      }
+   };
+   let mut keys: Vec<&str> = recipients
+      .iter()
+      .flat_map(|r| r.data.keys().map(|k| k.as_ref()))
+      .collect();
+   keys.sort_unstable();
+   keys.dedup();
+   let content = config::gen_campaign(
+      crate_name!(),
+      crate_version!(),
+      &from,
+      &subject,
+      smtp.as_ref(),
+      &recipients,
+   );
+   fs::write(&config_path, content).map_err(|e| format!("cannot write {} ({})", config_path, e))?;
+   let content = config::gen_campaign_template(crate_name!(), crate_version!(), &from, &keys);
+   fs::write(&template_path, content)
+      .map_err(|e| format!("cannot write {} ({})", template_path, e))?;
+   println!("* wrote {} and {}", config_path, template_path);
+   println!(
+      "  edit them and check with: {} validate -c {} -t {}",
+      crate_name!(),
+      config_path,
+      template_path
+   );
+   Ok(())
+}
+
+/// Opens the files in the user's editor and waits for it to exit.
+fn edit(paths: &[String], defaults: &Defaults) -> Result<(), String> {
+   let editor = defaults
+      .editor()
+      .ok_or("no editor configured, set *editor* in the user configuration or $EDITOR")?;
+   let mut words = editor.split_whitespace();
+   let program = words.next().unwrap();
+   let status = Command::new(program)
+      .args(words)
+      .args(paths)
+      .status()
+      .map_err(|e| format!("cannot run {} ({})", program, e))?;
+   if !status.success() {
+      return Err(format!("{} failed ({})", program, status));
+   }
+   Ok(())
+}
+
+/// Returns the config files given with `--config` (or `RMT_CONFIG`), the settings of later ones
+/// override the ones of earlier ones. Without any, `rmt.ini` or `rmt.toml` in the current
+/// directory is used if there is one.
+fn config_paths<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+   match matches.values_of("config") {
+      Some(paths) => paths.collect(),
+      None => DEFAULT_CONFIGS
+         .iter()
+         .copied()
+         .filter(|p| Path::new(p).is_file())
+         .take(1)
+         .collect(),
+   }
+}
+
+/// Returns the template given with `-t` (or `RMT_TEMPLATE`), `template.txt` in the current
+/// directory if there is none.
+fn template_arg<'a>(matches: &'a ArgMatches) -> Option<&'a str> {
+   matches
+      .value_of("template")
+      .or_else(|| Some(DEFAULT_TEMPLATE).filter(|p| Path::new(p).is_file()))
+}
+
+/// Like `template_arg` but exits with status 6 if there is no template.
+fn required_template<'a>(matches: &'a ArgMatches) -> &'a str {
+   ee!(
+      template_arg(matches).ok_or(format!(
+         "no template given (--template) and no {} in the current directory",
+         DEFAULT_TEMPLATE
+      )),
+      EXIT_TEMPLATE
+   )
+}
+
+/// Has SpamAssassin score the email rendered for a recipient, this implements the `spamcheck`
+/// subcommand.
+///
+/// Exits with 2 if the email is considered spam.
+fn spamcheck(matches: &ArgMatches, defaults: &Defaults) {
+   let mut cfg = load_config(&config_paths(matches), false);
+   let tmpl = load_template(required_template(matches), defaults, cfg.engine);
+   let html = matches
+      .value_of("html")
+      .map(|html_path| load_template(html_path, defaults, cfg.engine));
+   ee!(campaign::embed_images(&mut cfg, html.as_ref()));
+   let rcp = match matches.value_of("recipient") {
+      Some(email) => ee!(find_recipient(&cfg, email)),
+      None => ee!(cfg.recipients.first().ok_or("no recipients")),
+   };
+   let msg = message::compose(&cfg, &tmpl, html.as_ref(), rcp);
+   let report = ee!(spamcheck::check(
+      &msg.to_bytes(),
+      matches
+         .value_of("command")
+         .unwrap_or(spamcheck::DEFAULT_COMMAND)
+   ));
+   say!(
+      "* spam score of the email to {}: {} (threshold {})",
+      rcp.email,
+      report.score,
+      report.threshold
+   );
+   for rule in report.rules.iter() {
+      println!(
+         "   {:>5.1} {}: {}",
+         rule.points, rule.name, rule.description
+      );
+   }
+   if report.is_spam() {
+      println!(
+         "{}",
+         color::error("!! the email looks like spam, fix the content before sending")
+      );
+      ::std::process::exit(EXIT_INVALID)
+   }
+}
+
+/// Renders the email for a recipient, this implements the `preview` subcommand.
+///
+/// The email is printed unless it is to be opened in the browser. With `--fake` the recipient is
+/// made up, the recipients of the config are ignored.
+fn preview(matches: &ArgMatches, defaults: &Defaults) {
+   let fake = matches.is_present("fake");
+   let config_paths = config_paths(matches);
+   let mut cfg = load_config(&config_paths, fake);
+   let tmpl = load_template(required_template(matches), defaults, cfg.engine);
+   let html = matches
+      .value_of("html")
+      .map(|html_path| load_template(html_path, defaults, cfg.engine));
+   ee!(campaign::embed_images(&mut cfg, html.as_ref()));
+   let mut seed = Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64;
+   let random = config::splitmix64(&mut seed);
+   if fake {
+      // the keys with a default value keep it
+      let mut keys = lint::setting_keys(&cfg);
+      keys.extend(tmpl.keys().iter().cloned());
+      keys.extend(html.iter().flat_map(|h| h.keys().iter().cloned()));
+      keys.retain(|k| !k.starts_with('_') && !cfg.defaults.contains_key(k));
+      cfg.recipients = vec![preview::fake_recipient(keys.iter(), &cfg.schema, random)];
+   }
+   let rcp = match matches.value_of("recipient") {
+      Some(email) => ee!(find_recipient(&cfg, email)),
+      None => {
+         if cfg.recipients.is_empty() {
+            ee!(Err("no recipients"))
+         }
+         &cfg.recipients[(random % cfg.recipients.len() as u64) as usize]
+      }
+   };
+   let msg = message::compose(&cfg, &tmpl, html.as_ref(), rcp);
+   if !matches.is_present("open") {
+      print!("{}", msg);
+      return;
+   }
+   let path = ee!(preview::write(&preview::page(&msg), &rcp.email));
+   ee!(preview::open(&path));
+   say!("* opened the email to {} ({})", rcp.email, path.display());
+}
+
+/// Renders the emails of all recipients into snapshot files or compares them with the approved
+/// snapshots, this implements the `render` subcommand.
+///
+/// Exits with 2 if the emails differ from the approved snapshots.
+fn render(matches: &ArgMatches, defaults: &Defaults) {
+   let mut cfg = load_config(&config_paths(matches), false);
+   let load = |path: &str| load_template(path, defaults, cfg.engine);
+   let (tmpl, html) = match matches.value_of("template").or_else(|| {
+      Some(DEFAULT_TEMPLATE).filter(|p| cfg.variants.is_empty() && Path::new(p).is_file())
+   }) {
+      Some(path) => (
+         Arc::new(load(path)),
+         matches.value_of("html").map(load).map(Arc::new),
+      ),
+      None => match cfg.variants.first() {
+         Some(variant) => (variant.template.clone(), variant.html.clone()),
+         None => ee!(Err(
+            "no template given (--template) and no *variants* section"
+         )),
+      },
+   };
+   let htmls: Vec<Arc<Template>> = html
+      .iter()
+      .chain(cfg.variants.iter().filter_map(|v| v.html.as_ref()))
+      .cloned()
+      .collect();
+   ee!(campaign::embed_images(
+      &mut cfg,
+      htmls.iter().map(|h| h.as_ref())
+   ));
+   let snapshots: Vec<(String, String)> = cfg
+      .recipients
+      .iter()
+      .map(|rcp| {
+         let msg = message::compose(&cfg, &tmpl, html.as_deref(), rcp);
+         (rcp.email.clone(), golden::snapshot(&msg))
+      })
+      .collect();
+   if let Some(dir) = matches.value_of("out") {
+      ee!(golden::write(Path::new(dir), &snapshots));
+      say!("* wrote {} snapshot(s) to {}", snapshots.len(), dir);
+      return;
+   }
+   let dir = matches.value_of("check").unwrap();
+   let differences = ee!(golden::check(Path::new(dir), &snapshots));
+   if differences.is_empty() {
+      say!(
+         "{}",
+         color::success(&format!(
+            "* the {} email(s) match the snapshots in {}",
+            snapshots.len(),
+            dir
+         ))
+      );
+      return;
+   }
+   for difference in differences.iter() {
+      println!("{}", difference);
+   }
+   println!(
+      "{}",
+      color::error(&format!(
+         "!! error: {} email(s) differ from the snapshots in {}, run with --out {} to approve the changes",
+         differences.len(),
+         dir,
+         dir
+      ))
+   );
+   ::std::process::exit(EXIT_INVALID)
+}
+
+/// Looks up the recipient with the given email address.
+fn find_recipient<'a>(
+   cfg: &'a config::Config,
+   email: &str,
+) -> Result<&'a config::Recipient, String> {
+   cfg.recipients
+      .iter()
+      .find(|r| r.email.eq_ignore_ascii_case(email.trim()))
+      .ok_or(format!("no such recipient: {}", email))
+}
+
+/// Checks the config, the template(s) and the recipient data, this implements the `validate`
+/// subcommand.
+///
+/// All problems found are listed, exits with 2 if there are any.
+fn validate(matches: &ArgMatches, defaults: &Defaults) {
+   let config_paths = config_paths(matches);
+   let mut cfg = load_config(&config_paths, true);
+   let tmpl = Arc::new(load_template(
+      required_template(matches),
+      defaults,
+      cfg.engine,
+   ));
+   let html = matches
+      .value_of("html")
+      .map(|html_path| Arc::new(load_template(html_path, defaults, cfg.engine)));
+   let preparation = campaign::Preparation {
+      nameservers: matches.is_present("verify_dns").then(dns::nameservers),
+      ..Default::default()
+   };
+   let campaign::Checks {
+      problems,
+      mut warnings,
+   } = ee!(campaign::prepare(
+      &mut cfg,
+      &tmpl,
+      html.as_ref(),
+      &preparation
+   ));
+   if cfg.smtp.is_none() {
+      warnings.push(Diagnostic::warning(
+         "no *smtp* section, only dry runs are possible",
+      ));
+   }
+   warnings.iter().for_each(report);
+   if problems.is_empty() {
+      say!(
+         "{}",
+         color::success(&format!(
+            "* {} is valid, {} recipient(s)",
+            config_paths.join(" + "),
+            cfg.recipients.len()
+         ))
+      );
+   } else {
+      report_all(
+         Severity::Error,
+         &format!("found {} problem(s)", problems.len()),
+         &problems,
+      );
+      ::std::process::exit(EXIT_INVALID)
+   }
+}
+
+/// Reports the likely mistakes in a template, this implements the `lint template` subcommand.
+///
+/// Exits with 2 if anything was found.
+fn lint_template(matches: &ArgMatches, defaults: &Defaults) {
+   let path = required_template(matches);
+   let (path, text) = if path == "-" {
+      let text = ee!(io::read_to_string(io::stdin().lock())
+         .map_err(|e| format!("cannot read the template from stdin ({})", e)));
+      (String::from("stdin"), text)
+   } else {
+      let path = defaults.template_path(path);
+      let text = ee!(
+         fs::read_to_string(&path).map_err(|e| format!("cannot read template {} ({})", path, e))
+      );
+      (path, text)
+   };
+   let max = matches.value_of("max_line_length").unwrap();
+   let max = ee!(max
+      .parse()
+      .map_err(|_| format!("invalid maximum line length: {}", max)));
+   let config_paths = config_paths(matches);
+   let cfg = config_paths
+      .first()
+      .map(|_| load_config(&config_paths, true));
+   let provided = cfg.as_ref().map(lint::provided_keys);
+   let findings = match cfg.map(|c| c.engine) {
+      Some(template::Engine::Tera) => lint::lint_tera(&text, provided.as_ref(), max),
+      _ => lint::lint(&text, provided.as_ref(), max),
+   };
+   if findings.is_empty() {
+      say!("{}", color::success(&format!("* {} looks good", path)));
+      return;
+   }
+   if json_errors() {
+      for finding in findings.iter() {
+         let mut diagnostic = Diagnostic::error(&finding.message);
+         diagnostic.file = Some(path.clone());
+         diagnostic.line = Some(finding.line);
+         report(&diagnostic);
+      }
+      ::std::process::exit(EXIT_INVALID)
+   }
+   for finding in findings.iter() {
+      println!("{}:{}", path, finding);
+   }
+   println!(
+      "{}",
+      color::error(&format!("!! error: found {} problem(s)", findings.len()))
+   );
+   ::std::process::exit(EXIT_INVALID)
+}
+
+/// Reads the template at the given path (which may be relative to the template directory) for
+/// the template engine of the campaign, `-` reads it from stdin (only once).
+fn load_template(path: &str, defaults: &Defaults, engine: template::Engine) -> Template {
+   static STDIN_READ: AtomicBool = AtomicBool::new(false);
+   let (path, tmpl) = if path == "-" {
+      if STDIN_READ.swap(true, Ordering::SeqCst) {
+         ee!(
+            Err("only one template can be read from stdin"),
+            EXIT_TEMPLATE
+         )
+      }
+      let tmpl = template::read(io::stdin().lock())
+         .map_err(|e| format!("cannot read the template from stdin ({})", e));
+      (String::from("stdin"), ee!(tmpl, EXIT_TEMPLATE))
+   } else {
+      let path = defaults.template_path(path);
+      let tmpl = template::instantiate(&path).map_err(|e| format!("cannot read {} ({})", path, e));
+      let tmpl = ee!(tmpl, EXIT_TEMPLATE);
+      (path, tmpl)
+   };
+   ee!(
+      tmpl
+         .with_engine(engine)
+         .map_err(|e| format!("{}: {}", path, e)),
+      EXIT_TEMPLATE
+   )
+}
+
+/// Shows the campaign summary and asks the user to confirm the send, the summary goes to stderr
+/// unless the output is `text`.
+///
+/// Fails unless the user answers "yes".
+fn confirm(summary: &str, text: bool) -> Result<(), String> {
+   if !io::stdin().is_terminal() {
+      return Err(String::from(
+         "cannot ask for confirmation without a terminal, use --yes to send anyway",
+      ));
+   }
+   let prompt = format!(
+      "{}\n\nsend the campaign? type \"yes\" to confirm: ",
+      summary
+   );
+   if text {
+      print!("{}", prompt);
+      io::stdout().flush().map_err(|e| e.to_string())?;
+   } else {
+      eprint!("{}", prompt);
+   }
+   let mut answer = String::new();
+   io::stdin()
+      .read_line(&mut answer)
+      .map_err(|e| format!("cannot read the answer ({})", e))?;
+   if !answer.trim().eq_ignore_ascii_case("yes") {
+      return Err(String::from("aborted, nothing was sent"));
+   }
+   Ok(())
+}
+
+/// Runs a campaign, this implements the `run`, `resend`, `resume` and `queue` subcommands.
+///
+/// With `resume` set the campaign is sent to the recipients an interrupted run left out and to
+/// those whose delivery failed.
+fn run(matches: &ArgMatches, defaults: &Defaults, resume: bool) {
+   // with JSON output stdout is reserved for the report
+   let text = matches.value_of("output") != Some("json");
+   if text {
+      say!("Run mailer tool");
+   }
+   if matches.is_present("dry_run") {
+      info!("dry run, no action");
+   } else {
+      info!("run the mailer");
+   }
+   let config_paths = config_paths(matches);
+   let template_path = matches
+      .value_of("template")
+      .map(|t| defaults.template_path(t));
+
+   let skip_invalid = matches.is_present("skip_invalid");
+   // streamed recipients are read while sending, the config holds those of its own section only
+   let stream = matches.is_present("stream");
+   let mut cfg = if stream {
+      load_config_with(&config_paths, config::instantiate_streaming)
+   } else {
+      load_config(&config_paths, skip_invalid)
+   };
+   info!(
+      "loaded {} with {} recipient(s), campaign {}",
+      config_paths.join(" + "),
+      cfg.recipients.len(),
+      cfg.campaign_id
+   );
+   if matches.is_present("failed") || resume {
+      let record = match matches.value_of("report") {
+         Some(path) => ee!(history::read(Path::new(path))),
+         None => {
+            let dir = ee!(history::campaigns_dir().ok_or("cannot determine the data directory"));
+            match ee!(history::latest(&dir, &cfg.campaign_id)) {
+               Some(entry) => ee!(history::read(&entry.path)),
+               None => ee!(Err(format!(
+                  "no record of campaign {} found",
+                  cfg.campaign_id
+               ))),
+            }
+         }
+      };
+      if record["campaign_id"] != cfg.campaign_id.as_str() {
+         warn!(
+            "the report is for campaign {}, not {}",
+            record["campaign_id"], cfg.campaign_id
+         );
+      }
+      let mut failed = history::failed_recipients(&record);
+      if resume {
+         failed.extend(history::remaining_recipients(&record));
+      }
+      for email in failed.iter() {
+         if !cfg.recipients.iter().any(|r| &r.email == email) {
+            warn!("{} is no longer a recipient, skipping it", email);
+         }
+      }
+      cfg.recipients.retain(|r| failed.contains(&r.email));
+      if cfg.recipients.is_empty() {
+         if resume {
+            say!("* nothing left to resume");
+         } else {
+            say!("* no failed deliveries to retry");
+         }
+         return;
+      }
+      info!("retrying {} recipient(s)", cfg.recipients.len());
+   }
+   // test runs go to everybody, they do not count as deliveries
+   let mut delivered = None;
+   if !matches.is_present("force") && !matches.is_present("redirect_to") {
+      if let Some(path) = ledger::path() {
+         let ledger = ee!(ledger::Ledger::open(&path));
+         let skipped = campaign::skip_delivered(&mut cfg, &ledger);
+         if cfg.recipients.is_empty() && !stream {
+            say!(
+               "* every recipient already received campaign {}",
+               cfg.campaign_id
+            );
+            return;
+         }
+         if skipped > 0 && text {
+            say!(
+               "* {} recipient(s) already received campaign {}, skipping them (--force sends \
+                anyway)",
+               skipped,
+               cfg.campaign_id
+            );
+         }
+         // the streamed recipients are looked up as they are read
+         delivered = Some(ledger);
+      }
+   }
+   if matches.is_present("only") || matches.is_present("skip") {
+      let patterns = |name| {
+         matches
+            .values_of(name)
+            .map(|v| v.collect())
+            .unwrap_or_default()
+      };
+      let (only, skip): (Vec<&str>, Vec<&str>) = (patterns("only"), patterns("skip"));
+      cfg.filter_recipients(&only, &skip);
+      if cfg.recipients.is_empty() {
+         ee!(Err("no recipients match the --only/--skip patterns"))
+      }
+      info!("{} recipient(s) left after filtering", cfg.recipients.len());
+   }
+   if let Some(segment) = matches.value_of("segment") {
+      cfg.segment_recipients(&ee!(Segment::parse(segment)));
+      if cfg.recipients.is_empty() {
+         ee!(Err(format!("no recipients in the segment: {}", segment)))
+      }
+      info!("{} recipient(s) in the segment", cfg.recipients.len());
+   }
+   if let Some(n) = matches.value_of("sample") {
+      let n: usize = ee!(n.parse().map_err(|_| format!("invalid sample size: {}", n)));
+      let seed = match matches.value_of("seed") {
+         Some(seed) => ee!(seed.parse().map_err(|_| format!("invalid seed: {}", seed))),
+         None => events::now() ^ u64::from(std::process::id()) << 32,
+      };
+      cfg.sample_recipients(n, seed);
+      let emails: Vec<&str> = cfg.recipients.iter().map(|r| r.email.as_ref()).collect();
+      info!("sampled {} recipient(s) with seed {}", emails.len(), seed);
+      if text {
+         say!(
+            "* sample of {} recipient(s) (--seed {}): {}",
+            emails.len(),
+            seed,
+            emails.join(", ")
+         );
+         say!(
+            "  exclude them from the full run with: --skip {}",
+            emails.join(",")
+         );
+      }
+   }
+   if let Some(campaign_id) = matches.value_of("follow_up_of") {
+      if !config::check_campaign_id(campaign_id) {
+         ee!(Err(format!("invalid campaign id: {}", campaign_id)))
+      }
+      cfg.threading.follow_up_of = Some(campaign_id.to_string());
+      thread_under(&mut cfg);
+   }
+   if let Some(address) = matches.value_of("redirect_to") {
+      if !config::check_email(address) {
+         ee!(Err(format!("invalid --redirect-to address: {}", address)))
+      }
+      info!("redirecting all emails to {}", address);
+      if text {
+         say!("* test mode, all emails go to {}", address);
+      }
+      cfg.redirect_to = Some(address.to_string());
+   }
+   // the header overrides apply to the validated config, the campaign id and the `Message-ID`
+   // domain stay the ones derived from the config file
+   if let Some(from) = matches.value_of("from") {
+      cfg.gdata.from =
+         ee!(Address::parse(from).map_err(|e| format!("invalid --from address: {} ({})", from, e)));
+   }
+   if let Some(subject) = matches.value_of("subject") {
+      if subject.trim().is_empty() {
+         ee!(Err("empty --subject"))
+      }
+      cfg.gdata.subject = subject.to_string();
+   }
+   if let Some(cc) = matches.value_of("cc") {
+      cfg.gdata.cc = ee!(config::check_emails("cc", cc));
+   }
+   if let Some(replyto) = matches.value_of("reply_to") {
+      cfg.gdata.replyto = ee!(config::check_emails("Reply-To", replyto));
+   }
+   if let Some(at) = matches.value_of("send_at") {
+      cfg.send_at = Some(ee!(
+         config::parse_time(at).ok_or_else(|| format!("invalid --send-at time: {}", at))
+      ));
+   }
+   if matches.is_present("group_by_domain") {
+      if let Some(ref mut smtp) = cfg.smtp {
+         smtp.group_by_domain = true;
+      }
+   }
+   if let Some(jitter) = matches.value_of("jitter") {
+      let jitter = ee!(config::parse_jitter(jitter));
+      if let Some(ref mut smtp) = cfg.smtp {
+         smtp.jitter = Some(jitter);
+      }
+   }
+   let load = |path: &str| Arc::new(load_template(path, defaults, cfg.engine));
+   // without a template on the command line the variants of the config file are sent
+   let template_path = template_path.or_else(|| {
+      Some(String::from(DEFAULT_TEMPLATE))
+         .filter(|p| cfg.variants.is_empty() && Path::new(p).is_file())
+   });
+   let (tmpl, html) = match template_path {
+      Some(ref path) => (load(path), matches.value_of("html").map(load)),
+      None => match cfg.variants.first() {
+         Some(variant) => (variant.template.clone(), variant.html.clone()),
+         None => ee!(Err(
+            "no template given (--template) and no *variants* section"
+         )),
+      },
+   };
+   if let Some(path) = matches.value_of("template_b") {
+      if !cfg.variants.is_empty() {
+         ee!(Err(
+            "--template-b cannot be combined with a *variants* section"
+         ))
+      }
+      cfg.variants = vec![
+         Variant {
+            name: String::from("a"),
+            weight: 1,
+            template: tmpl.clone(),
+            html: html.clone(),
+         },
+         Variant {
+            name: String::from("b"),
+            weight: 1,
+            template: load(path),
+            html: matches.value_of("html_b").map(load),
+         },
+      ];
+   }
+   if cfg.attachment_cmd.is_some() && stream {
+      ee!(Err("*attachment_cmd* cannot be combined with --stream"))
+   }
+   let preparation = campaign::Preparation {
+      nameservers: matches.is_present("verify_dns").then(dns::nameservers),
+      skip_invalid,
+      attachments: true,
+   };
+   let checks = ee!(campaign::prepare(
+      &mut cfg,
+      &tmpl,
+      html.as_ref(),
+      &preparation
+   ));
+   if text {
+      checks.warnings.iter().for_each(report);
+   }
+   if !checks.problems.is_empty() {
+      report_all(
+         Severity::Error,
+         &format!("found {} problem(s)", checks.problems.len()),
+         &checks.problems,
+      );
+      ::std::process::exit(EXIT_INVALID)
+   }
+   if skip_invalid && cfg.recipients.is_empty() && !stream {
+      ee!(Err("no valid recipients left"))
+   }
+   debug!("recipient data looks good");
+   if text {
+      say!("* recpient data looks good");
+   }
+   // the streamed recipients are checked against all templates (of all variants) as they are read
+   let (texts, htmls) = campaign::templates(&cfg, &tmpl, html.as_ref());
+   let templates: Vec<Arc<Template>> = texts.into_iter().chain(htmls).collect();
+   let used: Vec<&Template> = templates.iter().map(|t| t.as_ref()).collect();
+
+   if let Some(dir) = matches.value_of("spool") {
+      if config_paths.len() > 1 {
+         ee!(Err(
+            "the spool records a single config file, merge the config files first"
+         ));
+      }
+      let config_path = config_paths[0];
+      // the spool may be flushed from another directory
+      let absolute = fs::canonicalize(config_path)
+         .map(|p| p.display().to_string())
+         .unwrap_or_else(|_| config_path.to_string());
+      let spool = ee!(spool::Spool::create(
+         Path::new(dir),
+         &cfg.campaign_id,
+         &absolute,
+         events::now()
+      ));
+      for rcp in cfg.recipients.iter() {
+         let msg = message::compose(&cfg, &tmpl, html.as_deref(), rcp);
+         let variant = cfg.variant(&rcp.email).map(|v| v.name.as_str());
+         ee!(spool.add(&msg, variant));
+      }
+      say!(
+         "* {} email(s) queued in {}, send them with: rmt flush --spool {}",
+         cfg.recipients.len(),
+         dir,
+         dir
+      );
+      return;
+   }
+
+   let started_at = events::now();
+   let mut skipped = vec![];
+   let deliveries = if matches.is_present("dry_run") {
+      let mut deliveries = vec![];
+      for rcp in cfg.recipients.iter() {
+         let msg = message::compose(&cfg, &tmpl, html.as_deref(), rcp);
+         if text {
+            println!("\n{}", msg);
+         }
+         deliveries.push(transport::Delivery {
+            recipient: rcp.email.clone(),
+            message_id: msg.header("Message-ID").unwrap_or_default().to_string(),
+            result: Ok(()),
+            timestamp: events::now(),
+            variant: cfg.variant(&rcp.email).map(|v| v.name.clone()),
+         });
+      }
+      deliveries
+   } else {
+      let connections = cfg.smtp.as_ref().map_or(1, |s| s.connections);
+      // the connections are only established once the first email is sent
+      let mut transports = ee!(smtp_transports(&mut cfg, connections), EXIT_TRANSPORT);
+      let description = transports[0].describe();
+      // the CSV files are read once up front, for the confirmation and the progress display
+      let expected = if stream {
+         ee!(source::count(&cfg))
+      } else {
+         cfg.recipients.len()
+      };
+      if !matches.is_present("yes") {
+         let mut summary = report::summary(&cfg, &description);
+         if stream {
+            summary.push_str(&format!(
+               "\nstreamed:   {} recipient(s) from {}",
+               expected - cfg.recipients.len(),
+               cfg.recipients_csv.join(", ")
+            ));
+         }
+         ee!(confirm(&summary, text), EXIT_UNCONFIRMED);
+      }
+      if let Some(at) = cfg.send_at {
+         if at > Utc::now() {
+            if text {
+               say!("* waiting until {} to start sending", at.to_rfc3339());
+            }
+            progress::wait_until(at, text && io::stdout().is_terminal());
+         } else {
+            warn!(
+               "the scheduled time {} has passed, sending now",
+               at.to_rfc3339()
+            );
+         }
+      }
+      let stop = ee!(shutdown::install());
+      let mut observers = observers(&cfg, &description, text);
+      let transports = transports
+         .iter_mut()
+         .map(|t| t as &mut dyn Transport)
+         .collect();
+      if stream {
+         let campaign_id = cfg.campaign_id.clone();
+         let recipients = ee!(source::open(&cfg)).filter(move |rcp| match (rcp, &delivered) {
+            (Ok(rcp), Some(ledger)) => !ledger.contains(&campaign_id, &rcp.email),
+            _ => true,
+         });
+         let (deliveries, invalid) = transport::deliver_stream(
+            &cfg,
+            &tmpl,
+            html.as_deref(),
+            source::Checked::new(recipients, &cfg, &used),
+            expected,
+            transports,
+            &mut observers,
+            stop,
+         );
+         skipped = invalid;
+         deliveries
       } else {
-         println!("* run the mailer");
+         transport::deliver_concurrently(
+            &cfg,
+            &tmpl,
+            html.as_deref(),
+            transports,
+            &mut observers,
+            stop,
+         )
       }
-      let config_path = matches.value_of("config").unwrap();
-      let template_path = matches.value_of("template").unwrap();
+   };
+   if !skipped.is_empty() && text {
+      let reasons: Vec<Diagnostic> = skipped.iter().map(|r| Diagnostic::warning(r)).collect();
+      report_all(
+         Severity::Warning,
+         &format!("{} invalid recipient(s) skipped", reasons.len()),
+         &reasons,
+      );
+   }
+   cfg.quarantine.extend(skipped);
+   // the recipients an interrupted run did not get to
+   let delivered: HashSet<&str> = deliveries.iter().map(|d| d.recipient.as_str()).collect();
+   let remaining: Vec<String> = cfg
+      .recipients
+      .iter()
+      .filter(|r| !delivered.contains(r.email.as_str()))
+      .map(|r| r.email.clone())
+      .collect();
+   let run = report::Run {
+      campaign_id: &cfg.campaign_id,
+      dry_run: matches.is_present("dry_run"),
+      started_at,
+      finished_at: events::now(),
+      deliveries: &deliveries,
+      quarantined: &cfg.quarantine,
+      remaining: &remaining,
+   };
+   // redirected runs are tests, they must not be mistaken for the actual campaign
+   let record = cfg.redirect_to.is_none();
+   finish(&run, matches, &cfg, &config_paths, record, text);
+}
 
-      let cfg = ee!(config::instantiate(
-         config_path,
-         crate_name!(),
-         crate_version!()
+/// Sets up the given number of transports for the *smtp* server, they store copies of the emails
+/// sent in the *imap* mailbox (if any) unless the emails are redirected for testing. The
+/// credentials of both servers are resolved first (see `Config::resolve_credentials`).
+fn smtp_transports(
+   cfg: &mut config::Config,
+   count: usize,
+) -> Result<Vec<transport::SmtpTransport>, String> {
+   if cfg.smtp.is_none() {
+      return Err(String::from("No *smtp* section in config file"));
+   }
+   let copies = cfg.redirect_to.is_none();
+   cfg.resolve_credentials(copies)?;
+   let smtp = cfg.smtp.as_ref().unwrap();
+   let imap = cfg.imap.as_ref().filter(|_| copies);
+   let mut result = vec![];
+   for _ in 0..count {
+      let transport = transport::SmtpTransport::new(smtp)?;
+      result.push(match imap {
+         Some(imap) => transport.copy_to(imap),
+         None => transport,
+      });
+   }
+   Ok(result)
+}
+
+/// Sets up the observers of a send: the progress display, the audit log, the webhook and the
+/// ledger.
+fn observers(cfg: &config::Config, transport: &str, text: bool) -> Vec<Box<dyn Observer>> {
+   let mut result: Vec<Box<dyn Observer>> = vec![];
+   if text {
+      let interval = cfg.smtp.as_ref().and_then(|s| s.interval());
+      result.push(Box::new(
+         progress::Progress::new(interval).verbosity(verbosity()),
       ));
-      let tmpl = ee!(template::instantiate(template_path));
+   }
+   if let Some(ref path) = cfg.audit_log {
+      result.push(Box::new(ee!(audit::Audit::open(path, transport))));
+   }
+   if let Some(ref url) = cfg.webhook_url {
+      result.push(Box::new(ee!(
+         events::Webhook::new(url).and_then(|w| w.queue())
+      )));
+   }
+   if let Some(logger) = syslog::EventLogger::for_log() {
+      result.push(Box::new(ee!(logger)));
+   }
+   // emails redirected for testing are not recorded
+   match ledger::path() {
+      Some(_) if cfg.redirect_to.is_some() => (),
+      Some(path) => result.push(Box::new(ee!(ledger::Ledger::open(&path)))),
+      None => warn!("cannot determine the data directory, the deliveries are not recorded"),
+   }
+   result
+}
+
+/// Reports the outcome of a run, records it in the campaign history if `record` is set, emails
+/// the summary to the operator if configured and shows a desktop notification if asked to.
+/// Exits with status 4 if the run was interrupted, with status 7 if every delivery failed and with
+/// status 3 if some did.
+fn finish(
+   run: &report::Run,
+   matches: &ArgMatches,
+   cfg: &config::Config,
+   config_paths: &[&str],
+   record: bool,
+   text: bool,
+) {
+   if !text {
+      println!("{:#}", run.to_json());
+   }
+   if text {
+      for (variant, count) in run.variant_counts() {
+         say!("* variant {}: {} email(s)", variant, count);
+      }
+   }
+   if let Some(path) = matches.value_of("report_file") {
+      ee!(fs::write(path, format!("{:#}\n", run.to_json()))
+         .map_err(|e| format!("cannot write report file {} ({})", path, e)));
+   }
+   let mut document = run.to_json();
+   document["subject"] = cfg.gdata.subject.as_str().into();
+   document["config"] = config_paths.join(", ").into();
+   if !run.dry_run && record {
+      match history::campaigns_dir()
+         .ok_or_else(|| String::from("cannot determine the data directory"))
+         .and_then(|dir| history::save(&dir, &document))
+      {
+         Ok(path) => info!("campaign record saved to {}", path.display()),
+         Err(err) => warn!("campaign record not saved ({})", err),
+      }
+   }
+   if let (false, Some(ref to)) = (run.dry_run, &cfg.summary_to) {
+      send_summary(cfg, &document, to, text);
+   }
+   if matches.is_present("notify") {
+      let (title, body) = run.notification();
+      if let Err(err) = notify_rust::Notification::new()
+         .appname("rmt")
+         .summary(&title)
+         .body(&body)
+         .show()
+      {
+         warn!("cannot show the desktop notification ({})", err);
+      }
+   }
+   if matches.is_present("stream") && shutdown::requested() {
+      println!(
+         "{}",
+         color::warning(&format!(
+            "!! interrupted: {} email(s) sent, {} failed, the rest of the list was not read",
+            run.deliveries.len() - run.failed(),
+            run.failed()
+         ))
+      );
+      println!("   continue with the same command, the ledger skips the recipients sent to");
+      ::std::process::exit(EXIT_INTERRUPTED)
+   }
+   if !run.remaining.is_empty() {
+      println!(
+         "{}",
+         color::warning(&format!(
+            "!! interrupted: {} email(s) sent, {} failed, {} not sent",
+            run.deliveries.len() - run.failed(),
+            run.failed(),
+            run.remaining.len()
+         ))
+      );
+      match matches.value_of("spool") {
+         Some(dir) => println!("   continue with: rmt flush --spool {}", dir),
+         None => println!(
+            "   continue with: rmt resume -c {}",
+            config_paths.join(" -c ")
+         ),
+      }
+      ::std::process::exit(EXIT_INTERRUPTED)
+   }
+   if run.failed() > 0 && run.failed() == run.deliveries.len() {
+      ::std::process::exit(EXIT_TRANSPORT)
+   }
+   if run.failed() > 0 {
+      ::std::process::exit(EXIT_PARTIAL)
+   }
+}
+
+/// Emails the summary of a campaign run to the operator over the campaign's smtp server. The
+/// campaign is done at this point, a summary that cannot be sent is only worth a warning.
+fn send_summary(cfg: &config::Config, record: &serde_json::Value, to: &Address, text: bool) {
+   let result = report::summary_email(cfg, record, to).and_then(|msg| {
+      let smtp = cfg
+         .smtp
+         .as_ref()
+         .ok_or("No *smtp* section in config file")?;
+      transport::SmtpTransport::new(smtp)?.send(&msg)
+   });
+   match result {
+      Ok(()) if text => say!("* summary sent to {}", to),
+      Ok(()) => info!("summary sent to {}", to),
+      Err(err) => warn!("the summary could not be sent to {} ({})", to, err),
+   }
+}
+
+/// Lists the recipients of a past campaign run who neither replied nor bounced, this implements
+/// the `followup` subcommand.
+///
+/// They are written as recipients CSV, ready for the *recipients_csv* key of a follow-up campaign.
+fn followup(matches: &ArgMatches) {
+   let mut cfg = load_config(&config_paths(matches), false);
+   let (record, responses) = scan_mailbox(matches, &mut cfg);
+   let left = followup::non_responders(&record, &responses);
+   for email in left.iter() {
+      if !cfg.recipients.iter().any(|r| &r.email == email) {
+         warn!("{} is no longer a recipient, skipping it", email);
+      }
+   }
+   cfg.recipients.retain(|r| left.contains(&r.email));
+   let csv = listing::export_csv(&cfg);
+   let summary = format!(
+      "{} replied, {} bounced, {} recipient(s) left to follow up with",
+      responses.replied.len(),
+      responses.bounced.len(),
+      cfg.recipients.len()
+   );
+   match matches.value_of("out") {
+      Some(path) => {
+         ee!(fs::write(path, csv).map_err(|e| format!("cannot write {} ({})", path, e)));
+         say!("* {}, written to {}", summary, path);
+      }
+      None => {
+         info!("{}", summary);
+         print!("{}", csv)
+      }
+   }
+}
 
-      match tmpl.check_recipents(&cfg.recipients) {
-         Ok(()) => println!("* recpient data looks good"),
-         Err(errors) => {
-            println!("!! error: recipients are missing data needed by the template");
-            for err in errors {
-               println!("   - {}", err)
+/// Reports the responses to a past campaign run per recipient, this implements the `replies`
+/// subcommand.
+fn replies(matches: &ArgMatches) {
+   let mut cfg = load_config(&config_paths(matches), false);
+   let (record, responses) = scan_mailbox(matches, &mut cfg);
+   let tallies = followup::tally(&record, &responses);
+   let campaign_id = record["campaign_id"].as_str().unwrap_or(&cfg.campaign_id);
+   let report = match matches.value_of("format") {
+      Some("json") => format!("{:#}\n", followup::to_json(campaign_id, &tallies)),
+      _ => followup::to_csv(&tallies),
+   };
+   let summary = format!(
+      "{} of {} recipient(s) replied ({} replies), {} bounced",
+      tallies.iter().filter(|t| t.status == "replied").count(),
+      tallies.len(),
+      tallies.iter().map(|t| t.replies).sum::<usize>(),
+      tallies.iter().filter(|t| t.status == "bounced").count()
+   );
+   match matches.value_of("out") {
+      Some(path) => {
+         ee!(fs::write(path, report).map_err(|e| format!("cannot write {} ({})", path, e)));
+         say!("* {}, written to {}", summary, path);
+      }
+      None => {
+         info!("{}", summary);
+         print!("{}", report)
+      }
+   }
+}
+
+/// Looks through the mailbox in the *imap* section for the responses to the campaign run, which
+/// is taken from the campaign history unless a report is given.
+fn scan_mailbox(
+   matches: &ArgMatches,
+   cfg: &mut config::Config,
+) -> (serde_json::Value, followup::Responses) {
+   let record = match matches.value_of("report") {
+      Some(path) => ee!(history::read(Path::new(path))),
+      None => {
+         let dir = ee!(history::campaigns_dir().ok_or("cannot determine the data directory"));
+         match ee!(history::latest(&dir, &cfg.campaign_id)) {
+            Some(entry) => ee!(history::read(&entry.path)),
+            None => ee!(Err(format!(
+               "no record of campaign {} found",
+               cfg.campaign_id
+            ))),
+         }
+      }
+   };
+   let ids = followup::message_ids(&record, cfg);
+   let imap = ee!(cfg.imap.as_mut().ok_or("No *imap* section in config file"));
+   ee!(imap.resolve_credentials());
+   let mut conn = ee!(imap::Connection::open(imap));
+   let folder = matches.value_of("folder").unwrap();
+   let responses = ee!(followup::scan(&mut conn, folder, &record, &ids));
+   conn.logout();
+   (record, responses)
+}
+
+/// Sends the emails held in a spool directory, this implements the `flush` subcommand.
+///
+/// The smtp settings (and the audit log and webhook) are taken from the config file the emails
+/// were composed from unless another one is given.
+fn flush(matches: &ArgMatches) {
+   let text = matches.value_of("output") != Some("json");
+   let dir = matches.value_of("spool").unwrap();
+   let spool = ee!(spool::Spool::open(Path::new(dir)));
+   let config_path = matches
+      .value_of("config")
+      .unwrap_or(&spool.config)
+      .to_string();
+   let mut cfg = load_config(&[&config_path], false);
+   if cfg.campaign_id != spool.campaign_id {
+      warn!(
+         "the spool holds campaign {}, not {}",
+         spool.campaign_id, cfg.campaign_id
+      );
+   }
+   let entries = ee!(spool.entries());
+   let pending = entries.len();
+   if pending == 0 {
+      say!("* the spool {} is empty, nothing to send", dir);
+      return;
+   }
+   let mut transport = ee!(smtp_transports(&mut cfg, 1), EXIT_TRANSPORT).remove(0);
+   let smtp = cfg.smtp.as_ref().unwrap();
+   let description = transport.describe();
+   if !matches.is_present("yes") {
+      ee!(
+         confirm(&report::spool_summary(&spool, pending, &description), text),
+         EXIT_UNCONFIRMED
+      );
+   }
+   let started_at = events::now();
+   let mut sender = transport::SpoolSender::new().stop_on(ee!(shutdown::install()));
+   let mut observers = observers(&cfg, &description, text);
+   let deliveries = sender.deliver(
+      &spool,
+      Some(smtp),
+      &entries,
+      &mut transport,
+      &mut observers,
+      started_at,
+   );
+   // the webhook gets to post the queued events before the process exits
+   drop(observers);
+   let remaining: Vec<String> = entries
+      .iter()
+      .skip(deliveries.len())
+      .map(|e| e.recipient.clone())
+      .collect();
+   let run = report::Run {
+      campaign_id: &spool.campaign_id,
+      dry_run: false,
+      started_at,
+      finished_at: events::now(),
+      deliveries: &deliveries,
+      quarantined: &[],
+      remaining: &remaining,
+   };
+   finish(&run, matches, &cfg, &[&config_path], true, text);
+}
+
+/// Watches a spool and delivers the emails that are due until interrupted, the failed ones are
+/// retried with a backoff. The state is written to the `status.json` file of the spool.
+///
+/// Problems with the spool or the config file are logged and retried at the next poll rather
+/// than ending the daemon.
+fn daemon(matches: &ArgMatches) {
+   let dir = Path::new(matches.value_of("spool").unwrap());
+   let poll = matches.value_of("poll").unwrap();
+   let poll: u64 = ee!(poll
+      .parse()
+      .ok()
+      .filter(|p| *p > 0)
+      .ok_or(format!("invalid poll interval: {}", poll)));
+   let mut sender = transport::SpoolSender::new().stop_on(ee!(shutdown::install()));
+   let (mut sent, mut failed) = (0, 0);
+   let mut last_error: Option<String> = None;
+   let shared = metrics::Shared::default();
+   if let Some(address) = matches.value_of("metrics_listen") {
+      let address = ee!(metrics::serve(address, shared.clone()));
+      say!("* serving metrics at http://{}/metrics", address);
+   }
+   say!("* watching {} every {}s", dir.display(), poll);
+   while !shutdown::requested() {
+      let now = events::now();
+      let status = spool::Spool::open(dir).and_then(|spool| {
+         let entries = spool.entries()?;
+         let due: Vec<spool::Entry> = entries.iter().filter(|e| e.is_due(now)).cloned().collect();
+         if !due.is_empty() {
+            let given_up = spool.given_up()?;
+            let start = Instant::now();
+            let deliveries = daemon_batch(matches, &spool, &due, &mut sender, &shared)?;
+            if let Ok(mut metrics) = shared.lock() {
+               let gave_up = spool.given_up()?.saturating_sub(given_up);
+               metrics.record_batch(&due, &deliveries, gave_up, start.elapsed());
+            }
+            let errors = deliveries.iter().filter(|d| d.result.is_err()).count();
+            say!(
+               "* {} {} email(s) sent, {} failed",
+               spool.campaign_id,
+               deliveries.len() - errors,
+               errors
+            );
+            sent += deliveries.len() - errors;
+            failed += errors;
+            if let Some(Err(err)) = deliveries
+               .iter()
+               .rev()
+               .map(|d| &d.result)
+               .find(|r| r.is_err())
+            {
+               last_error = Some(err.clone());
+            }
+         }
+         let entries = spool.entries()?;
+         let status = json!({
+            "pid": std::process::id(),
+            "updated_at": events::now(),
+            "campaign_id": spool.campaign_id,
+            "pending": entries.len(),
+            "deferred": entries.iter().filter(|e| e.attempts > 0).count(),
+            "given_up": spool.given_up()?,
+            "next_retry_at": entries.iter().filter(|e| e.attempts > 0).map(|e| e.retry_at).min(),
+            "sent": sent,
+            "failed": failed,
+            "last_error": last_error,
+         });
+         spool.write_status(&status)?;
+         if let Ok(mut metrics) = shared.lock() {
+            metrics.pending = entries.len() as u64;
+            metrics.given_up = spool.given_up()? as u64;
+            if let Some(path) = matches.value_of("metrics_file") {
+               metrics::write_file(Path::new(path), &metrics)?;
             }
-            ::std::process::exit(2)
          }
+         Ok(())
+      });
+      if let Err(err) = status {
+         // e.g. the spool is not set up yet or the config file is being edited, the same
+         // problem is only reported once
+         if last_error.as_ref() != Some(&err) {
+            warn!("{}", err);
+         }
+         last_error = Some(err);
       }
+      for _ in 0..poll {
+         if shutdown::requested() {
+            break;
+         }
+         thread::sleep(Duration::from_secs(1));
+      }
+   }
+   say!("* stopped, {} email(s) sent, {} failed", sent, failed);
+}
+
+/// Runs the REST API until interrupted, this implements the `serve` subcommand.
+fn serve(matches: &ArgMatches) {
+   let dir = match matches.value_of("dir") {
+      Some(dir) => Path::new(dir).to_path_buf(),
+      None => ee!(history::data_dir().ok_or("cannot determine the data directory")).join("serve"),
+   };
+   let connect = |cfg: &mut config::Config| {
+      let connections = cfg.smtp.as_ref().map_or(1, |s| s.connections);
+      Ok(smtp_transports(cfg, connections)?
+         .into_iter()
+         .map(|t| Box::new(t) as Box<dyn Transport>)
+         .collect())
+   };
+   let token = matches
+      .value_of("token")
+      .map(String::from)
+      .or_else(|| env::var("RMT_API_TOKEN").ok())
+      .unwrap_or_default();
+   let mut server = ee!(server::Server::new(&dir, &token, Box::new(connect))
+      .map_err(|e| format!("{} (see --token)", e)));
+   for host in matches.values_of("allow_host").into_iter().flatten() {
+      server = server.allow_host(host);
+   }
+   let address = ee!(server::serve(matches.value_of("listen").unwrap(), server));
+   say!(
+      "* serving the API at http://{}/campaigns, the campaigns are kept in {}",
+      address,
+      dir.display()
+   );
+   ee!(shutdown::install());
+   while !shutdown::requested() {
+      thread::sleep(Duration::from_millis(200));
+   }
+}
+
+/// Delivers a batch of due emails for the daemon, the config file is read anew for each batch.
+fn daemon_batch(
+   matches: &ArgMatches,
+   spool: &spool::Spool,
+   due: &[spool::Entry],
+   sender: &mut transport::SpoolSender,
+   shared: &metrics::Shared,
+) -> Result<Vec<transport::Delivery>, String> {
+   let config_path = matches.value_of("config").unwrap_or(&spool.config);
+   let mut cfg = config::instantiate(
+      &[config_path],
+      USER_CONFIG.get(),
+      crate_name!(),
+      crate_version!(),
+   )?;
+   let mut transport = smtp_transports(&mut cfg, 1)?.remove(0);
+   let smtp = cfg.smtp.as_ref().unwrap();
+   let mut observers = observers(&cfg, &transport.describe(), false);
+   let mut transport = metrics::TimedTransport::new(&mut transport, shared.clone());
+   Ok(sender.deliver(
+      spool,
+      Some(smtp),
+      due,
+      &mut transport,
+      &mut observers,
+      events::now(),
+   ))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn log_levels() {
+      assert_eq!(LevelFilter::Warn, log_level(0, false, None));
+      assert_eq!(
+         LevelFilter::Info,
+         log_level(0, false, Some(LevelFilter::Info))
+      );
+      assert_eq!(LevelFilter::Info, log_level(1, false, None));
+      assert_eq!(
+         LevelFilter::Debug,
+         log_level(2, false, Some(LevelFilter::Error))
+      );
+      assert_eq!(LevelFilter::Trace, log_level(3, false, None));
+      assert_eq!(LevelFilter::Trace, log_level(5, false, None));
+      assert_eq!(
+         LevelFilter::Error,
+         log_level(0, true, Some(LevelFilter::Debug))
+      );
    }
 }
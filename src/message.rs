@@ -0,0 +1,1250 @@
+/// The `message` module composes the emails that are sent to the recipients.
+use crate::address::{self, Address};
+use crate::attachment::Attachment;
+use crate::calendar;
+use crate::config::{Config, Recipient, Signature, Tracking};
+use crate::css;
+use crate::encoding::{self, TransferEncoding};
+use crate::sanitizer;
+use crate::template::{self, Template};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use regex::{Captures, Regex};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+/// The `Message` struct holds a fully rendered email for a single recipient.
+pub struct Message {
+   /// The (envelope) email address of the sender
+   pub sender: String,
+   /// The email address of the recipient
+   pub recipient: String,
+   /// The email addresses the message is copied (or blind-copied) to
+   pub cc: Vec<String>,
+   /// The email headers in the order in which they are to be emitted
+   pub headers: Vec<(String, String)>,
+   /// The rendered (plain text) email body
+   pub body: String,
+   /// The rendered HTML email body, if any
+   pub html: Option<String>,
+   /// How the body parts are encoded for transport
+   pub transfer_encoding: TransferEncoding,
+   /// The attached files
+   pub attachments: Vec<Arc<Attachment>>,
+   /// The images embedded in the HTML body
+   pub inline_images: Vec<Arc<Attachment>>,
+}
+
+impl PartialEq for Message {
+   /// Makes it possible to compare instances of `Message`
+   fn eq(&self, other: &Self) -> bool {
+      self.sender == other.sender
+         && self.recipient == other.recipient
+         && self.cc == other.cc
+         && self.headers == other.headers
+         && self.body == other.body
+         && self.html == other.html
+         && self.transfer_encoding == other.transfer_encoding
+         && self.attachments == other.attachments
+         && self.inline_images == other.inline_images
+   }
+}
+
+impl fmt::Display for Message {
+   /// Formats the message the way it would be sent (headers, empty line, body)
+   ///
+   /// Messages with an HTML body are formatted as `multipart/alternative` MIME messages,
+   /// messages with attachments as `multipart/mixed` ones. An HTML body with inline images is a
+   /// `multipart/related` part.
+   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      for (name, value) in self.headers.iter() {
+         writeln!(f, "{}: {}", name, value)?;
+      }
+      writeln!(f, "MIME-Version: 1.0")?;
+      if self.attachments.is_empty() {
+         return self.fmt_body(f);
+      }
+      let names: Vec<&str> = self.attachments.iter().map(|a| a.name.as_ref()).collect();
+      let boundary = format!(
+         "rmt-mixed-{:016x}",
+         fnv1a(format!("{}{}", self.body, names.join("/")).as_bytes())
+      );
+      writeln!(
+         f,
+         "Content-Type: multipart/mixed; boundary=\"{}\"",
+         boundary
+      )?;
+      writeln!(f)?;
+      writeln!(f, "--{}", boundary)?;
+      self.fmt_body(f)?;
+      writeln!(f)?;
+      for attachment in self.attachments.iter() {
+         writeln!(f, "--{}", boundary)?;
+         fmt_attachment(f, attachment)?;
+      }
+      write!(f, "--{}--", boundary)
+   }
+}
+
+/// Counts the bytes of a message as transmitted i.e. with CRLF line endings.
+struct ByteCounter {
+   bytes: usize,
+   cr: bool,
+}
+
+impl fmt::Write for ByteCounter {
+   fn write_str(&mut self, s: &str) -> fmt::Result {
+      for b in s.bytes() {
+         self.bytes += if b == b'\n' && !self.cr { 2 } else { 1 };
+         self.cr = b == b'\r';
+      }
+      Ok(())
+   }
+}
+
+impl Message {
+   /// Formats the body i.e. the plain text and the HTML part along with their headers.
+   fn fmt_body(&self, f: &mut fmt::Formatter) -> fmt::Result {
+      match self.html {
+         None => self.fmt_text(f, "text/plain", &self.body),
+         Some(ref html) => {
+            let boundary = format!(
+               "rmt-{:016x}",
+               fnv1a(format!("{}{}", self.body, html).as_bytes())
+            );
+            writeln!(
+               f,
+               "Content-Type: multipart/alternative; boundary=\"{}\"",
+               boundary
+            )?;
+            writeln!(f)?;
+            writeln!(f, "--{}", boundary)?;
+            self.fmt_text(f, "text/plain", &self.body)?;
+            writeln!(f)?;
+            writeln!(f, "--{}", boundary)?;
+            if self.inline_images.is_empty() {
+               self.fmt_text(f, "text/html", html)?;
+            } else {
+               self.fmt_related(f, html)?;
+            }
+            writeln!(f)?;
+            write!(f, "--{}--", boundary)
+         }
+      }
+   }
+
+   /// Formats a text part along with its headers.
+   fn fmt_text(&self, f: &mut fmt::Formatter, ctype: &str, text: &str) -> fmt::Result {
+      let (cte, content) = encoding::encode_body(text, self.transfer_encoding);
+      writeln!(f, "Content-Type: {}; charset=utf-8", ctype)?;
+      writeln!(f, "Content-Transfer-Encoding: {}", cte)?;
+      writeln!(f)?;
+      write!(f, "{}", content)
+   }
+
+   /// Formats the HTML body and the inline images as a `multipart/related` part.
+   fn fmt_related(&self, f: &mut fmt::Formatter, html: &str) -> fmt::Result {
+      let names: Vec<&str> = self.inline_images.iter().map(|i| i.name.as_ref()).collect();
+      let boundary = format!(
+         "rmt-related-{:016x}",
+         fnv1a(format!("{}{}", html, names.join("/")).as_bytes())
+      );
+      writeln!(
+         f,
+         "Content-Type: multipart/related; type=\"text/html\"; boundary=\"{}\"",
+         boundary
+      )?;
+      writeln!(f)?;
+      writeln!(f, "--{}", boundary)?;
+      self.fmt_text(f, "text/html", html)?;
+      writeln!(f)?;
+      for image in self.inline_images.iter() {
+         writeln!(f, "--{}", boundary)?;
+         fmt_attachment(f, image)?;
+      }
+      write!(f, "--{}--", boundary)
+   }
+
+   /// Returns the value of the first header with the given (case-insensitive) name.
+   pub fn header(&self, name: &str) -> Option<&str> {
+      self
+         .headers
+         .iter()
+         .find(|(n, _)| n.eq_ignore_ascii_case(name))
+         .map(|(_, v)| v.as_ref())
+   }
+
+   /// Delivers the message to the given address instead of the recipient, the original
+   /// recipient is noted in an `X-Original-To` header.
+   ///
+   /// The headers are left alone so that the message looks the way the recipient would see it.
+   /// Nobody is copied on a redirected message.
+   pub fn redirect(&mut self, address: &str) {
+      self
+         .headers
+         .push((String::from("X-Original-To"), self.recipient.clone()));
+      self.recipient = address::ascii_email(&encoding::split_address(address).1);
+      self.cc = vec![];
+   }
+
+   /// Returns the size of the message as transmitted, without building it.
+   pub fn size(&self) -> usize {
+      let mut counter = ByteCounter {
+         bytes: 0,
+         cr: false,
+      };
+      let _ = fmt::write(&mut counter, format_args!("{}", self));
+      counter.bytes
+   }
+
+   /// Returns the message the way it is transmitted i.e. with CRLF line endings.
+   pub fn to_bytes(&self) -> Vec<u8> {
+      self
+         .to_string()
+         .replace("\r\n", "\n")
+         .replace('\n', "\r\n")
+         .into_bytes()
+   }
+}
+
+/// Formats an attachment (or inline image) part along with its headers.
+fn fmt_attachment(f: &mut fmt::Formatter, attachment: &Attachment) -> fmt::Result {
+   for (name, value) in attachment.headers() {
+      writeln!(f, "{}: {}", name, value)?;
+   }
+   writeln!(f)?;
+   writeln!(f, "{}", encoding::base64(&attachment.data))
+}
+
+/// Replaces the `%_CID:path%` references in the HTML body with the `cid:` URLs of the inline
+/// images with the same file name, references to unknown images are left untouched.
+fn link_images(html: &str, images: &[Arc<Attachment>]) -> String {
+   let re = Regex::new(r"%_CID:([^%]+)%").unwrap();
+   re.replace_all(html, |caps: &Captures| {
+      let path = caps[1].trim();
+      let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+      match images
+         .iter()
+         .find(|i| i.name == name)
+         .and_then(|i| i.content_id.as_ref())
+      {
+         Some(cid) => format!("cid:{}", cid),
+         None => caps[0].to_string(),
+      }
+   })
+   .into_owned()
+}
+
+/// Computes the 64-bit FNV-1a hash of the given data.
+///
+/// Unlike the hashers in the standard library the result is stable across rust releases and
+/// platforms which is what we need for identifiers that end up in emails.
+pub fn fnv1a(data: &[u8]) -> u64 {
+   let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+   for byte in data {
+      hash ^= u64::from(*byte);
+      hash = hash.wrapping_mul(0x0100_0000_01b3);
+   }
+   hash
+}
+
+/// Generates a deterministic `Message-ID` of the form `<campaign-id.recipient-hash@domain>`.
+pub fn message_id(campaign_id: &str, email: &str, domain: &str) -> String {
+   let hash = fnv1a(email.trim().to_lowercase().as_bytes());
+   format!("<{}.{:016x}@{}>", campaign_id, hash, domain)
+}
+
+/// Computes the `In-Reply-To` and `References` headers that make the email thread under a
+/// previous message. In a follow-up campaign that is the email the recipient got in the earlier
+/// one (if any).
+fn threading_headers(cfg: &Config, rcp: &Recipient) -> Vec<(String, String)> {
+   let threading = &cfg.threading;
+   let in_reply_to = match threading.follow_up_of {
+      Some(_) => threading
+         .message_ids
+         .get(&rcp.email.trim().to_lowercase())
+         .cloned(),
+      None => threading.in_reply_to.clone(),
+   };
+   let mut references = threading.references.clone();
+   let mut result = vec![];
+   if let Some(msgid) = in_reply_to {
+      if !references.contains(&msgid) {
+         references.push(msgid.clone());
+      }
+      result.push((String::from("In-Reply-To"), msgid));
+   }
+   if !references.is_empty() {
+      result.push((String::from("References"), references.join(" ")));
+   }
+   result
+}
+
+/// Appends the recipient's signature (if any) to the plain text body.
+fn sign_text(body: &mut String, signature: &Signature, values: &HashMap<String, String>) {
+   body.truncate(body.trim_end().len());
+   body.push_str("\n\n-- \n");
+   body.push_str(&template::expand(&signature.text, values));
+}
+
+/// Inserts the recipient's signature into the HTML body, right before the closing `</body>` tag
+/// if there is one. Signatures without an HTML variant are converted from their text variant.
+fn sign_html(html: &mut String, signature: &Signature, values: &HashMap<String, String>) {
+   let signature = match signature.html {
+      Some(ref shtml) => template::expand_html(shtml, values),
+      None => {
+         template::escape_html(&template::expand(&signature.text, values)).replace('\n', "<br>\n")
+      }
+   };
+   insert_html(
+      html,
+      &format!("<div class=\"signature\">{}</div>\n", signature),
+   );
+}
+
+/// Inserts the HTML snippet right before the closing `</body>` tag if there is one, appends it
+/// otherwise.
+fn insert_html(html: &mut String, snippet: &str) {
+   match html.rfind("</body>") {
+      Some(pos) => html.insert_str(pos, snippet),
+      None => {
+         html.push('\n');
+         html.push_str(snippet);
+      }
+   }
+}
+
+/// Inserts the preview text as a hidden element right after the opening `<body>` tag if there
+/// is one, at the very top otherwise. Mail clients show the first text of the body next to the
+/// subject, hiding it keeps it out of the email itself.
+fn insert_preheader(html: &mut String, text: &str) {
+   let snippet = format!(
+      "<div style=\"display:none;max-height:0;overflow:hidden;mso-hide:all\">{}</div>\n",
+      template::escape_html(text)
+   );
+   let body = Regex::new(r"(?i)<body[^>]*>\n?").unwrap();
+   let pos = body.find(html).map_or(0, |m| m.end());
+   html.insert_str(pos, &snippet);
+}
+
+/// Generates the per-recipient token that is used to track opened emails and clicked links: the
+/// first 128 bits of the HMAC-SHA256 of the campaign and the recipient keyed with the tracking
+/// secret, so that only the tracking service can tell the recipients apart or forge a token.
+pub fn tracking_token(secret: &str, campaign_id: &str, email: &str) -> String {
+   let mut mac =
+      Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+   mac.update(campaign_id.as_bytes());
+   mac.update(b"\n");
+   mac.update(email.trim().to_lowercase().as_bytes());
+   mac.finalize().into_bytes()[..16]
+      .iter()
+      .map(|b| format!("{:02x}", b))
+      .collect()
+}
+
+/// Returns the recipient's tracking token, parsing the config makes sure that there is a secret
+/// if the emails are tracked.
+fn recipient_token(cfg: &Config, rcp: &Recipient) -> String {
+   let secret = cfg.tracking.secret.as_deref().unwrap_or_default();
+   tracking_token(secret, &cfg.campaign_id, &rcp.email)
+}
+
+/// Generates the 1x1 image tag that tracks opened emails for the recipient the token belongs to.
+fn tracking_pixel(url: &str, token: &str) -> String {
+   let separator = if url.contains('?') { '&' } else { '?' };
+   let url = format!("{}{}t={}", url, separator, token);
+   format!(
+      "<img src=\"{}\" width=\"1\" height=\"1\" alt=\"\" style=\"border:0\">\n",
+      template::escape_html(&url)
+   )
+}
+
+/// Appends the UTM parameters to the link, in front of the fragment if there is one.
+///
+/// Parameters the link already has are left alone.
+fn add_utm(link: &str, utm: &[(String, String)], values: &HashMap<String, String>) -> String {
+   let (base, fragment) = match link.find('#') {
+      Some(pos) => link.split_at(pos),
+      None => (link, ""),
+   };
+   let query = base.split_once('?').map(|(_, q)| q).unwrap_or("");
+   let mut result = base.to_string();
+   for (name, value) in utm.iter() {
+      if query.split('&').any(|p| p.split('=').next() == Some(name)) {
+         continue;
+      }
+      result.push(if result.contains('?') { '&' } else { '?' });
+      result.push_str(&format!(
+         "{}={}",
+         name,
+         encoding::percent_encode(&template::expand(value, values))
+      ));
+   }
+   result.push_str(fragment);
+   result
+}
+
+/// Rewrites the (http) links of all anchors in the HTML body for click tracking.
+///
+/// The UTM parameters are appended to the links and, if there is a redirect URL, the links are
+/// routed through it along with the recipient's tracking token.
+fn rewrite_links(
+   html: &str,
+   tracking: &Tracking,
+   token: &str,
+   values: &HashMap<String, String>,
+) -> String {
+   let re = Regex::new(r#"(?i)(<a\s[^>]*?\bhref\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap();
+   re.replace_all(html, |caps: &Captures| {
+      let link = caps.get(2).or_else(|| caps.get(3)).unwrap().as_str();
+      let unescaped = link.replace("&amp;", "&");
+      if !unescaped.starts_with("http://") && !unescaped.starts_with("https://") {
+         return caps[0].to_string();
+      }
+      let mut link = add_utm(&unescaped, &tracking.utm, values);
+      if let Some(ref url) = tracking.redirect_url {
+         link = format!(
+            "{}{}t={}&u={}",
+            url,
+            if url.contains('?') { '&' } else { '?' },
+            token,
+            encoding::percent_encode(&link)
+         );
+      }
+      format!("{}\"{}\"", &caps[1], template::escape_html(&link))
+   })
+   .into_owned()
+}
+
+/// Composes the email for the given recipient.
+///
+/// The `html` template is optional, messages without it only have a plain text body. If the
+/// config has template variants the ones of the variant assigned to the recipient are used
+/// instead.
+pub fn compose(cfg: &Config, tmpl: &Template, html: Option<&Template>, rcp: &Recipient) -> Message {
+   let (tmpl, html) = match cfg.variant(&rcp.email) {
+      Some(variant) => (variant.template.as_ref(), variant.html.as_deref()),
+      None => (tmpl, html),
+   };
+   let values = template::values(cfg, rcp);
+   // internationalized domains may only appear in the headers if the server supports SMTPUTF8
+   let smtputf8 = cfg.smtp.as_ref().map(|s| s.smtputf8).unwrap_or(false);
+   let encode = |addresses: &[Address]| -> String {
+      addresses
+         .iter()
+         .map(|a| {
+            let a = if smtputf8 { a.clone() } else { a.to_ascii() };
+            encoding::encode_address(&a.to_string(), cfg.transliterate)
+         })
+         .collect::<Vec<String>>()
+         .join(", ")
+   };
+   let to = encoding::mailbox(
+      &rcp.names.join(" "),
+      &if smtputf8 {
+         rcp.email.clone()
+      } else {
+         address::ascii_email(&rcp.email)
+      },
+   );
+   let mut headers = vec![
+      (
+         String::from("From"),
+         encode(std::slice::from_ref(&cfg.gdata.from)),
+      ),
+      (
+         String::from("To"),
+         encoding::encode_address(&to, cfg.transliterate),
+      ),
+   ];
+   let cc = rcp.effective_cc(cfg);
+   if !cc.is_empty() {
+      headers.push((String::from("Cc"), encode(&cc)));
+   }
+   if !cfg.gdata.replyto.is_empty() {
+      headers.push((String::from("Reply-To"), encode(&cfg.gdata.replyto)));
+   }
+   if let Some(ref address) = cfg.disposition_notification_to {
+      headers.push((
+         String::from("Disposition-Notification-To"),
+         encode(std::slice::from_ref(address)),
+      ));
+   }
+   headers.push((
+      String::from("Subject"),
+      encoding::encode_word(&template::expand(&cfg.gdata.subject, &values)),
+   ));
+   headers.push((
+      String::from("Message-ID"),
+      message_id(&cfg.campaign_id, &rcp.email, &cfg.msgid_domain),
+   ));
+   headers.extend(threading_headers(cfg, rcp));
+   headers.extend(
+      rcp.custom_headers()
+         .into_iter()
+         .map(|(name, val)| (name, encoding::encode_word(&val))),
+   );
+   let mut body = tmpl.render(cfg, rcp);
+   let mut html = html.map(|t| t.render_html(cfg, rcp));
+   if let Some(signature) = rcp
+      .data
+      .get("signature")
+      .and_then(|n| cfg.signatures.get(n))
+      .or(cfg.signature.as_ref())
+   {
+      sign_text(&mut body, signature, &values);
+      if let Some(ref mut html) = html {
+         sign_html(html, signature, &values);
+      }
+   }
+   if let Some(ref mut html) = html {
+      if cfg.sanitize_html {
+         *html = sanitizer::sanitize(html);
+      }
+      if cfg.inline_css {
+         *html = css::inline(html);
+      }
+      if let Some(ref preheader) = cfg.preheader {
+         insert_preheader(html, &template::expand(preheader, &values));
+      }
+      if cfg.tracking.is_active() {
+         *html = rewrite_links(html, &cfg.tracking, &recipient_token(cfg, rcp), &values);
+      }
+   }
+   if let (Some(ref mut html), Some(ref url)) = (&mut html, &cfg.tracking_pixel_url) {
+      insert_html(html, &tracking_pixel(url, &recipient_token(cfg, rcp)));
+   }
+   // the inline images are only needed by HTML emails
+   let inline_images = match html {
+      Some(ref mut html) => {
+         *html = link_images(html, &cfg.inline_images);
+         cfg.inline_images.clone()
+      }
+      None => vec![],
+   };
+   let mut attachments = cfg.attachments.clone();
+   if let Some(attachment) = cfg.generated_attachments.get(&rcp.email) {
+      attachments.push(attachment.clone());
+   }
+   if let Some(ref event) = cfg.event {
+      attachments.push(Arc::new(calendar::invite(cfg, event, rcp, Utc::now())));
+   }
+   let mut result = Message {
+      sender: cfg.gdata.from.to_ascii().email(),
+      recipient: address::ascii_email(&rcp.email),
+      cc: cc
+         .iter()
+         .chain(rcp.effective_bcc().iter())
+         .map(|a| a.to_ascii().email())
+         .collect(),
+      headers,
+      body,
+      html,
+      transfer_encoding: cfg.transfer_encoding,
+      attachments,
+      inline_images,
+   };
+   if let Some(ref address) = cfg.redirect_to {
+      result.redirect(address);
+   }
+   result
+}
+
+/// Returns the recipients whose email exceeds the maximum message size, along with the size.
+pub fn oversized(cfg: &Config, tmpl: &Template, html: Option<&Template>) -> Vec<(String, usize)> {
+   if cfg.max_message_size == 0 {
+      return vec![];
+   }
+   cfg.recipients
+      .iter()
+      .map(|rcp| (rcp.email.clone(), compose(cfg, tmpl, html, rcp).size()))
+      .filter(|(_, size)| *size as u64 > cfg.max_message_size)
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::config::{sa, sm};
+   use crate::testing;
+
+   fn prep_config() -> Config {
+      let mut cfg = testing::config(r#""Frodo Baggins" <rts@example.com>"#, "Hello %_FN%!");
+      cfg.gdata.cc = testing::addresses("cc@example.com");
+      cfg.campaign_id = String::from("spring-sale");
+      cfg.msgid_domain = String::from("mail.example.com");
+      cfg.tool_version = String::from("0.1.2");
+      cfg.signatures = vec![
+         (
+            String::from("anna"),
+            Signature {
+               text: String::from("Anna Smith\nAccount manager for %ORG%"),
+               html: Some(String::from("<b>Anna Smith</b>, %ORG%")),
+            },
+         ),
+         (
+            String::from("bob"),
+            Signature {
+               text: String::from("Bob & Co\nSales"),
+               html: None,
+            },
+         ),
+      ]
+      .into_iter()
+      .collect();
+      cfg.recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe Jr.",
+         &[("ORG", "EFF")],
+      )];
+      cfg
+   }
+
+   #[test]
+   fn fnv1a_known_values() {
+      assert_eq!(0xcbf2_9ce4_8422_2325, fnv1a(b""));
+      assert_eq!(0xaf63_dc4c_8601_ec8c, fnv1a(b"a"));
+      assert_eq!(0x8594_4171_f739_67e8, fnv1a(b"foobar"));
+   }
+
+   #[test]
+   fn message_id_is_deterministic() {
+      assert_eq!(
+         message_id("c1", "jd@example.com", "example.org"),
+         message_id("c1", "jd@example.com", "example.org")
+      );
+   }
+
+   #[test]
+   fn message_id_ignores_case_and_whitespace_of_email() {
+      assert_eq!(
+         message_id("c1", "jd@example.com", "example.org"),
+         message_id("c1", " JD@Example.com ", "example.org")
+      );
+   }
+
+   #[test]
+   fn message_id_format() {
+      let expected = format!("<c1.{:016x}@example.org>", fnv1a(b"jd@example.com"));
+      assert_eq!(expected, message_id("c1", "jd@example.com", "example.org"));
+   }
+
+   #[test]
+   fn message_id_differs_per_recipient() {
+      assert_ne!(
+         message_id("c1", "jd@example.com", "example.org"),
+         message_id("c1", "mm@gmail.com", "example.org")
+      );
+   }
+
+   #[test]
+   fn compose_happy_case() {
+      let cfg = prep_config();
+      let tmpl = template::new("Hi %_FN% %_LN% from %ORG%!\n\n-- %_TN% %_TV%");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      let expected = Message {
+         sender: String::from("rts@example.com"),
+         recipient: String::from("jd@example.com"),
+         cc: sa(&["cc@example.com"]),
+         headers: vec![
+            (
+               String::from("From"),
+               String::from("Frodo Baggins <rts@example.com>"),
+            ),
+            (
+               String::from("To"),
+               String::from(r#""John Doe Jr." <jd@example.com>"#),
+            ),
+            (String::from("Cc"), String::from("cc@example.com")),
+            (String::from("Subject"), String::from("Hello John!")),
+            (
+               String::from("Message-ID"),
+               message_id("spring-sale", "jd@example.com", "mail.example.com"),
+            ),
+         ],
+         body: String::from("Hi John Doe Jr. from EFF!\n\n-- rmt 0.1.2"),
+         html: None,
+         transfer_encoding: TransferEncoding::Auto,
+         attachments: vec![],
+         inline_images: vec![],
+      };
+      assert_eq!(expected, msg);
+   }
+
+   #[test]
+   fn compose_with_per_recipient_cc_and_bcc() {
+      let cfg = prep_config();
+      let tmpl = template::new("Hi %_FN%!");
+      let rcp = testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("cc", "+inc@gg.org"), ("bcc", "archive@example.com")],
+      );
+      let msg = compose(&cfg, &tmpl, None, &rcp);
+      assert_eq!(
+         sa(&["cc@example.com", "inc@gg.org", "archive@example.com"]),
+         msg.cc
+      );
+      assert_eq!(Some("cc@example.com, inc@gg.org"), msg.header("Cc"));
+      // blind copies are not mentioned in the headers
+      assert!(!msg.to_string().contains("archive@example.com"));
+   }
+
+   #[test]
+   fn compose_with_custom_headers() {
+      let cfg = prep_config();
+      let tmpl = template::new("Hi %_FN%!");
+      let rcp = testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("h_X-Account-ID", "12345"), ("h_X-Region", "Zürich")],
+      );
+      let msg = compose(&cfg, &tmpl, None, &rcp);
+      assert_eq!(Some("12345"), msg.header("X-Account-ID"));
+      assert_eq!(Some("=?utf-8?Q?Z=C3=BCrich?="), msg.header("X-Region"));
+      assert_eq!("X-Account-ID", msg.headers[msg.headers.len() - 2].0);
+   }
+
+   #[test]
+   fn compose_with_variants() {
+      let mut cfg = prep_config();
+      let tmpl = template::new("Hi %_FN%!");
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[]);
+      cfg.variants.push(crate::config::Variant {
+         name: String::from("b"),
+         weight: 1,
+         template: Arc::new(template::new("Hello %_FN%!")),
+         html: Some(Arc::new(template::new("<p>Hello %_FN%!</p>"))),
+      });
+      // the recipient's variant replaces the templates passed in
+      let msg = compose(&cfg, &tmpl, None, &rcp);
+      assert_eq!("Hello John!", msg.body);
+      assert_eq!(Some(String::from("<p>Hello John!</p>")), msg.html);
+   }
+
+   #[test]
+   fn compose_with_text_signature() {
+      let mut cfg = prep_config();
+      cfg.recipients[0].data = sm(&[("ORG", "EFF"), ("signature", "anna")]);
+      let tmpl = template::new("Hi %_FN%!");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(
+         "Hi John!\n\n-- \nAnna Smith\nAccount manager for EFF",
+         msg.body
+      );
+      assert_eq!(None, msg.html);
+   }
+
+   #[test]
+   fn compose_with_html_signature() {
+      let mut cfg = prep_config();
+      cfg.recipients[0].data = sm(&[("ORG", "R&D"), ("signature", "anna")]);
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<html><body><p>Hi %_FN%!</p></body></html>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(
+         Some(String::from(
+            "<html><body><p>Hi John!</p><div class=\"signature\"><b>Anna Smith</b>, R&amp;D</div>\n</body></html>"
+         )),
+         msg.html
+      );
+   }
+
+   #[test]
+   fn compose_with_html_signature_from_text_variant() {
+      let mut cfg = prep_config();
+      cfg.recipients[0].data = sm(&[("signature", "bob")]);
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<p>Hi %_FN%!</p>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(
+         Some(String::from(
+            "<p>Hi John!</p>\n<div class=\"signature\">Bob &amp; Co<br>\nSales</div>\n"
+         )),
+         msg.html
+      );
+   }
+
+   #[test]
+   fn compose_with_signature_file() {
+      let mut cfg = prep_config();
+      cfg.signature = Some(Signature {
+         text: String::from("The %ORG% team"),
+         html: None,
+      });
+      cfg.tracking_pixel_url = Some(String::from("https://t.example.com/o.gif"));
+      let tmpl = template::new("Hi %_FN%!\n");
+      let html = template::new("<body><p>Hi %_FN%!</p></body>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!("Hi John!\n\n-- \nThe EFF team", msg.body);
+      let html = msg.html.unwrap();
+      assert!(
+         html.starts_with("<body><p>Hi John!</p><div class=\"signature\">The EFF team</div>\n<img")
+      );
+      // a named signature takes precedence
+      cfg.recipients[0].data = sm(&[("ORG", "EFF"), ("signature", "bob")]);
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!("Hi John!\n\n-- \nBob & Co\nSales", msg.body);
+   }
+
+   #[test]
+   fn format_with_transfer_encoding() {
+      let mut cfg = prep_config();
+      let tmpl = template::new("Grüße, %_FN%!");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert!(msg
+         .to_string()
+         .ends_with("Content-Transfer-Encoding: quoted-printable\n\nGr=C3=BC=C3=9Fe, John!"));
+      cfg.transfer_encoding = TransferEncoding::Base64;
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert!(msg
+         .to_string()
+         .ends_with("Content-Transfer-Encoding: base64\n\nR3LDvMOfZSwgSm9obiE="));
+   }
+
+   #[test]
+   fn format_multipart_message() {
+      let cfg = prep_config();
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<p>Hi %_FN%!</p>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      let text = msg.to_string();
+      let boundary = format!("rmt-{:016x}", fnv1a(b"Hi John!<p>Hi John!</p>"));
+      assert!(text.contains(&format!(
+         "MIME-Version: 1.0\nContent-Type: multipart/alternative; boundary=\"{}\"\n\n--{}\nContent-Type: text/plain; charset=utf-8\n",
+         boundary, boundary
+      )));
+      assert!(text.contains("Content-Type: text/html; charset=utf-8\nContent-Transfer-Encoding: 7bit\n\n<p>Hi John!</p>\n"));
+      assert!(text.ends_with(&format!("--{}--", boundary)));
+   }
+
+   #[test]
+   fn format_message_with_attachment() {
+      let mut cfg = prep_config();
+      cfg.attachments = vec![Arc::new(Attachment {
+         name: String::from("invite.ics"),
+         content_type: String::from("text/calendar; charset=utf-8"),
+         data: b"BEGIN:VCALENDAR".to_vec(),
+         content_id: None,
+      })];
+      let tmpl = template::new("Hi %_FN%!");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      let text = msg.to_string();
+      assert!(
+         text.contains("MIME-Version: 1.0\nContent-Type: multipart/mixed; boundary=\"rmt-mixed-")
+      );
+      assert!(text.contains(
+         "Content-Type: text/plain; charset=utf-8\nContent-Transfer-Encoding: 7bit\n\nHi John!\n"
+      ));
+      assert!(text.contains("Content-Type: text/calendar; charset=utf-8; name=\"invite.ics\"\nContent-Disposition: attachment; filename=\"invite.ics\"\nContent-Transfer-Encoding: base64\n\nQkVHSU46VkNBTEVOREFS\n"));
+      assert_eq!(msg.to_bytes().len(), msg.size());
+   }
+
+   #[test]
+   fn format_message_with_inline_image() {
+      let mut cfg = prep_config();
+      cfg.inline_images = vec![Arc::new(Attachment {
+         name: String::from("logo.png"),
+         content_type: String::from("image/png"),
+         data: b"\x89PNG".to_vec(),
+         content_id: Some(String::from("logo.png@mail.example.com")),
+      })];
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<img src=\"%_CID:img/logo.png%\"><img src=\"%_CID:x.gif%\">");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      let text = msg.to_string();
+      assert!(text
+         .contains("Content-Type: multipart/related; type=\"text/html\"; boundary=\"rmt-related-"));
+      assert!(
+         text.contains("<img src=\"cid:logo.png@mail.example.com\"><img src=\"%_CID:x.gif%\">\n")
+      );
+      assert!(text.contains("Content-Disposition: inline; filename=\"logo.png\"\nContent-ID: <logo.png@mail.example.com>\nContent-Transfer-Encoding: base64\n\niVBORw==\n"));
+      // plain text emails don't need the images
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert!(msg.inline_images.is_empty());
+   }
+
+   #[test]
+   fn compose_with_sanitize_html() {
+      let mut cfg = prep_config();
+      cfg.sanitize_html = true;
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<p onclick=\"go()\">Hi %_FN%!<script>go()</script></p>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(Some("<p>Hi John!</p>"), msg.html.as_deref());
+   }
+
+   #[test]
+   fn compose_with_preheader() {
+      let mut cfg = prep_config();
+      cfg.preheader = Some(String::from("Offers for %_FN% & co"));
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<html>\n<body class=\"x\">\n<p>Hi %_FN%!</p></body></html>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(
+         Some(
+            "<html>\n<body class=\"x\">\n<div style=\"display:none;max-height:0;overflow:hidden;\
+             mso-hide:all\">Offers for John &amp; co</div>\n<p>Hi John!</p></body></html>"
+         ),
+         msg.html.as_deref()
+      );
+      assert_eq!("Hi John!", msg.body);
+      let html = template::new("<p>Hi!</p>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert!(msg
+         .html
+         .unwrap()
+         .starts_with("<div style=\"display:none;max-height:0;overflow:hidden;mso-hide:all\">"));
+   }
+
+   #[test]
+   fn compose_with_inline_css() {
+      let mut cfg = prep_config();
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<style>p { color: red }</style><p>Hi %_FN%!</p>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(
+         Some("<style>p { color: red }</style><p>Hi John!</p>"),
+         msg.html.as_deref()
+      );
+      cfg.inline_css = true;
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(
+         Some("<p style=\"color: red\">Hi John!</p>"),
+         msg.html.as_deref()
+      );
+   }
+
+   #[test]
+   fn compose_with_generated_attachment() {
+      let mut cfg = prep_config();
+      let invoice = Arc::new(Attachment {
+         name: String::from("invoice.pdf"),
+         content_type: String::from("application/pdf"),
+         data: b"%PDF-1.4".to_vec(),
+         content_id: None,
+      });
+      cfg.generated_attachments
+         .insert(String::from("jd@example.com"), invoice.clone());
+      let tmpl = template::new("Hi %_FN%!");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(vec![invoice], msg.attachments);
+      let rcp = testing::recipient("mm@example.com", "Mickey Mouse", &[]);
+      assert!(compose(&cfg, &tmpl, None, &rcp).attachments.is_empty());
+   }
+
+   #[test]
+   fn compose_with_event() {
+      let mut cfg = prep_config();
+      cfg.event = Some(crate::config::Event {
+         summary: String::from("Webinar"),
+         start: Utc::now(),
+         end: Utc::now() + chrono::Duration::hours(1),
+         location: None,
+         description: None,
+         organizer: cfg.gdata.from.clone(),
+      });
+      let tmpl = template::new("Hi %_FN%!");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(1, msg.attachments.len());
+      let invite = String::from_utf8(msg.attachments[0].data.clone()).unwrap();
+      // the lines are folded
+      assert!(invite
+         .replace("\r\n ", "")
+         .contains("RSVP=TRUE:mailto:jd@example.com\r\n"));
+      let rcp = testing::recipient("mm@example.com", "Mickey Mouse", &[]);
+      let msg = compose(&cfg, &tmpl, None, &rcp);
+      assert!(!String::from_utf8(msg.attachments[0].data.clone())
+         .unwrap()
+         .contains("mailto:jd@example.com"));
+   }
+
+   #[test]
+   fn find_oversized_messages() {
+      let mut cfg = prep_config();
+      let tmpl = template::new("Hi %_FN%!");
+      assert!(oversized(&cfg, &tmpl, None).is_empty());
+      cfg.max_message_size = 100;
+      let actual = oversized(&cfg, &tmpl, None);
+      assert_eq!(cfg.recipients.len(), actual.len());
+      assert_eq!("jd@example.com", actual[0].0);
+      assert!(actual[0].1 > 100);
+      cfg.max_message_size = 0;
+      assert!(oversized(&cfg, &tmpl, None).is_empty());
+   }
+
+   #[test]
+   fn compose_with_follow_up_of() {
+      let mut cfg = prep_config();
+      cfg.threading.follow_up_of = Some(String::from("winter-sale"));
+      cfg.threading.references = sa(&["<intro@example.com>"]);
+      let original = String::from("<winter-sale.1@other.example.com>");
+      cfg.threading.message_ids =
+         HashMap::from([(String::from("jd@example.com"), original.clone())]);
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(
+         &(String::from("In-Reply-To"), original.clone()),
+         &msg.headers[5]
+      );
+      assert_eq!(
+         &(
+            String::from("References"),
+            format!("<intro@example.com> {}", original)
+         ),
+         &msg.headers[6]
+      ); // the recipient did not get an email in the earlier campaign
+      let rcp = testing::recipient("mm@gmail.com", "Mickey Mouse", &[]);
+      let msg = compose(&cfg, &tmpl, None, &rcp);
+      assert!(!msg.headers.iter().any(|(name, _)| name == "In-Reply-To"));
+      assert!(msg.headers.contains(&(
+         String::from("References"),
+         String::from("<intro@example.com>")
+      )));
+   }
+
+   #[test]
+   fn compose_with_in_reply_to_already_referenced() {
+      let mut cfg = prep_config();
+      cfg.threading.in_reply_to = Some(String::from("<b@example.com>"));
+      cfg.threading.references = sa(&["<a@example.com>", "<b@example.com>"]);
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(
+         vec![
+            (String::from("In-Reply-To"), String::from("<b@example.com>")),
+            (
+               String::from("References"),
+               String::from("<a@example.com> <b@example.com>")
+            ),
+         ],
+         msg.headers[5..].to_vec()
+      );
+   }
+
+   #[test]
+   fn compose_with_redirect() {
+      let mut cfg = prep_config();
+      cfg.gdata.cc = testing::addresses("boss@example.com");
+      cfg.redirect_to = Some(String::from("reviewer@example.com"));
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!("reviewer@example.com", msg.recipient);
+      assert!(msg.cc.is_empty());
+      assert_eq!(Some("\"John Doe Jr.\" <jd@example.com>"), msg.header("To"));
+      assert_eq!(Some("jd@example.com"), msg.header("X-Original-To"));
+   }
+
+   #[test]
+   fn compose_without_threading() {
+      let cfg = prep_config();
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(5, msg.headers.len());
+   }
+
+   #[test]
+   fn compose_with_internationalized_domains() {
+      let mut cfg = prep_config();
+      cfg.gdata.from = testing::addresses("info@bücher.example").remove(0);
+      cfg.recipients[0].email = String::from("jd@münchen.example");
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      // the envelope is always ASCII
+      assert_eq!("info@xn--bcher-kva.example", msg.sender);
+      assert_eq!("jd@xn--mnchen-3ya.example", msg.recipient);
+      assert_eq!(Some("info@xn--bcher-kva.example"), msg.header("From"));
+      assert_eq!(
+         Some("\"John Doe Jr.\" <jd@xn--mnchen-3ya.example>"),
+         msg.header("To")
+      );
+      // the Unicode form is kept in the headers if the server supports SMTPUTF8
+      cfg.smtp = Some(crate::config::Smtp {
+         host: String::from("smtp.example.com"),
+         port: 465,
+         security: crate::config::Security::Tls,
+         username: None,
+         password: None,
+         keyring: None,
+         rate: None,
+         domain_rates: HashMap::new(),
+         jitter: None,
+         group_by_domain: false,
+         domain_burst: None,
+         connections: 1,
+         dsn_notify: None,
+         dsn_ret: None,
+         smtputf8: true,
+      });
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!("info@xn--bcher-kva.example", msg.sender);
+      assert_eq!(Some("info@bücher.example"), msg.header("From"));
+      assert_eq!(
+         Some("\"John Doe Jr.\" <jd@münchen.example>"),
+         msg.header("To")
+      );
+   }
+
+   #[test]
+   fn compose_with_non_ascii_names() {
+      let mut cfg = prep_config();
+      cfg.gdata.from = testing::addresses(r#""Jürgen Müller" <jm@example.com>"#).remove(0);
+      cfg.gdata.subject = String::from("Grüße");
+      cfg.recipients[0].names = sa(&["Zoë"]);
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(
+         "=?utf-8?Q?J=C3=BCrgen_M=C3=BCller?= <jm@example.com>",
+         msg.headers[0].1
+      );
+      assert_eq!("=?utf-8?Q?Zo=C3=AB?= <jd@example.com>", msg.headers[1].1);
+      assert_eq!("=?utf-8?Q?Gr=C3=BC=C3=9Fe?=", msg.headers[3].1);
+   }
+
+   #[test]
+   fn compose_with_transliteration() {
+      let mut cfg = prep_config();
+      cfg.transliterate = true;
+      cfg.gdata.from = testing::addresses(r#""Jürgen Müller" <jm@example.com>"#).remove(0);
+      cfg.recipients[0].names = sa(&["Zoë"]);
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!("Juergen Mueller <jm@example.com>", msg.headers[0].1);
+      assert_eq!("Zoe <jd@example.com>", msg.headers[1].1);
+   }
+
+   #[test]
+   fn tracking_token_is_per_campaign_and_recipient() {
+      let token = tracking_token("s3cr3t", "c1", "jd@example.com");
+      assert_eq!(32, token.len());
+      assert_eq!(token, tracking_token("s3cr3t", "c1", "JD@example.com"));
+      assert_ne!(token, tracking_token("s3cr3t", "c2", "jd@example.com"));
+      assert_ne!(token, tracking_token("s3cr3t", "c1", "mm@gmail.com"));
+      assert_ne!(token, tracking_token("other", "c1", "jd@example.com"));
+   }
+
+   #[test]
+   fn compose_with_tracking_pixel() {
+      let mut cfg = prep_config();
+      cfg.tracking_pixel_url = Some(String::from("https://t.example.com/o.gif?c=spring"));
+      cfg.tracking.secret = Some(String::from("s3cr3t"));
+      cfg.recipients[0].data = sm(&[("signature", "bob")]);
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<body><p>Hi %_FN%!</p></body>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      let expected = format!(
+         "<body><p>Hi John!</p><div class=\"signature\">Bob &amp; Co<br>\nSales</div>\n<img src=\"https://t.example.com/o.gif?c=spring&amp;t={}\" width=\"1\" height=\"1\" alt=\"\" style=\"border:0\">\n</body>",
+         tracking_token("s3cr3t", "spring-sale", "jd@example.com")
+      );
+      assert_eq!(Some(expected), msg.html);
+      assert_eq!("Hi John!\n\n-- \nBob & Co\nSales", msg.body);
+   }
+
+   #[test]
+   fn compose_without_tracking_pixel() {
+      let cfg = prep_config();
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<p>Hi %_FN%!</p>");
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(Some(String::from("<p>Hi John!</p>")), msg.html);
+   }
+
+   #[test]
+   fn add_utm_keeps_existing_parameters_and_fragment() {
+      let utm = vec![
+         (String::from("utm_source"), String::from("news")),
+         (String::from("utm_content"), String::from("%ORG%")),
+      ];
+      let values = sm(&[("ORG", "Tom & Jerry")]);
+      assert_eq!(
+         "https://ex.org/a?utm_source=news&utm_content=Tom%20%26%20Jerry#top",
+         add_utm("https://ex.org/a#top", &utm, &values)
+      );
+      assert_eq!(
+         "https://ex.org/?utm_source=mine&x=1&utm_content=Tom%20%26%20Jerry",
+         add_utm("https://ex.org/?utm_source=mine&x=1", &utm, &values)
+      );
+   }
+
+   #[test]
+   fn rewrite_links_with_utm_parameters() {
+      let tracking = Tracking {
+         utm: vec![(String::from("utm_medium"), String::from("email"))],
+         redirect_url: None,
+         secret: None,
+      };
+      let html = r##"<a href="https://ex.org/?a=1&amp;b=2">x</a> <A class="c" HREF='http://ex.org'>y</A> <a href="mailto:jd@ex.org">z</a> <a href="#top">t</a>"##;
+      let expected = r##"<a href="https://ex.org/?a=1&amp;b=2&amp;utm_medium=email">x</a> <A class="c" HREF="http://ex.org?utm_medium=email">y</A> <a href="mailto:jd@ex.org">z</a> <a href="#top">t</a>"##;
+      assert_eq!(
+         expected,
+         rewrite_links(html, &tracking, "abc", &HashMap::new())
+      );
+   }
+
+   #[test]
+   fn rewrite_links_with_redirect_url() {
+      let tracking = Tracking {
+         utm: vec![(String::from("utm_source"), String::from("news"))],
+         redirect_url: Some(String::from("https://t.example.com/click")),
+         secret: Some(String::from("s3cr3t")),
+      };
+      let html = r#"<p><a href="https://ex.org/offer">offer</a></p>"#;
+      let expected = r#"<p><a href="https://t.example.com/click?t=abc&amp;u=https%3A%2F%2Fex.org%2Foffer%3Futm_source%3Dnews">offer</a></p>"#;
+      assert_eq!(
+         expected,
+         rewrite_links(html, &tracking, "abc", &HashMap::new())
+      );
+   }
+
+   #[test]
+   fn compose_with_click_tracking() {
+      let mut cfg = prep_config();
+      cfg.tracking.utm = vec![(String::from("utm_content"), String::from("%ORG%"))];
+      let tmpl = template::new("Hi %_FN%, see https://ex.org/");
+      let html = template::new(r#"<p>Hi %_FN%, see <a href="https://ex.org/">this</a></p>"#);
+      let msg = compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(
+         Some(String::from(
+            r#"<p>Hi John, see <a href="https://ex.org/?utm_content=EFF">this</a></p>"#
+         )),
+         msg.html
+      );
+      // the plain text body is left alone
+      assert_eq!("Hi John, see https://ex.org/", msg.body);
+   }
+
+   #[test]
+   fn compose_with_read_receipt() {
+      let mut cfg = prep_config();
+      cfg.disposition_notification_to = Address::parse("Frodo <rts@example.com>").ok();
+      let msg = compose(&cfg, &template::new("hi"), None, &cfg.recipients[0]);
+      assert_eq!(
+         Some("Frodo <rts@example.com>"),
+         msg.header("Disposition-Notification-To")
+      );
+   }
+
+   #[test]
+   fn header_lookup_is_case_insensitive() {
+      let cfg = prep_config();
+      let tmpl = template::new("");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      assert_eq!(Some("Hello John!"), msg.header("subject"));
+      assert_eq!(None, msg.header("Reply-To"));
+      assert_eq!(None, msg.header("Disposition-Notification-To"));
+   }
+
+   #[test]
+   fn to_bytes_uses_crlf_line_endings() {
+      let cfg = prep_config();
+      let tmpl = template::new("line 1\nline 2\r\nline 3");
+      let msg = compose(&cfg, &tmpl, None, &cfg.recipients[0]);
+      let bytes = String::from_utf8(msg.to_bytes()).expect("invalid utf-8");
+      assert!(bytes.starts_with("From: Frodo Baggins <rts@example.com>\r\nTo: "));
+      assert!(bytes.ends_with("\r\n\r\nline 1\r\nline 2\r\nline 3"));
+      assert!(!bytes.replace("\r\n", "").contains('\n'));
+   }
+}
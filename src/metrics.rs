@@ -0,0 +1,420 @@
+/// The `metrics` module keeps the counters and timings of the daemon for monitoring with
+/// Prometheus.
+///
+/// The metrics are rendered in the Prometheus text exposition format, they are either served over
+/// HTTP (`--metrics-listen`) or written to a file for the node exporter's textfile collector
+/// (`--metrics-file`).
+use crate::message::Message;
+use crate::spool::Entry;
+use crate::transport::{Delivery, Sending, Transport};
+use log::debug;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The upper bounds (in seconds) of the buckets of the timing histograms.
+const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Default)]
+/// The `Histogram` struct holds the distribution of durations.
+pub struct Histogram {
+   /// The number of observations per bucket (not cumulated)
+   counts: [u64; BUCKETS.len()],
+   /// The number of observations greater than the largest bucket
+   overflow: u64,
+   /// The sum of all observations in seconds
+   sum: f64,
+}
+
+impl Histogram {
+   /// Records a duration.
+   pub fn observe(&mut self, duration: Duration) {
+      let secs = duration.as_secs_f64();
+      match BUCKETS.iter().position(|b| secs <= *b) {
+         Some(i) => self.counts[i] += 1,
+         None => self.overflow += 1,
+      }
+      self.sum += secs;
+   }
+
+   /// Returns the number of observations.
+   pub fn count(&self) -> u64 {
+      self.counts.iter().sum::<u64>() + self.overflow
+   }
+
+   /// Renders the histogram with the given metric name.
+   fn render(&self, name: &str, help: &str) -> String {
+      let mut result = format!("# HELP {} {}\n# TYPE {} histogram\n", name, help, name);
+      let mut cumulated = 0;
+      for (bound, count) in BUCKETS.iter().zip(self.counts.iter()) {
+         cumulated += count;
+         result.push_str(&format!(
+            "{}_bucket{{le=\"{}\"}} {}\n",
+            name, bound, cumulated
+         ));
+      }
+      result.push_str(&format!(
+         "{}_bucket{{le=\"+Inf\"}} {}\n{}_sum {}\n{}_count {}\n",
+         name,
+         self.count(),
+         name,
+         self.sum,
+         name,
+         self.count()
+      ));
+      result
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `Metrics` struct holds the counters and timings of the daemon since it started.
+pub struct Metrics {
+   /// The number of emails sent
+   pub sent: u64,
+   /// The number of failed delivery attempts
+   pub failed: u64,
+   /// The number of delivery attempts that were retries of deferred emails
+   pub retried: u64,
+   /// The number of failed emails deferred for another attempt
+   pub deferred: u64,
+   /// The number of emails waiting in the spool
+   pub pending: u64,
+   /// The number of emails given up on
+   pub given_up: u64,
+   /// How long it took to hand the emails to the SMTP server
+   pub send_duration: Histogram,
+   /// How long the batches of due emails took
+   pub batch_duration: Histogram,
+}
+
+impl Metrics {
+   /// Counts the outcome of a batch of spooled emails. `due` are the entries the batch was
+   /// about, `gave_up` tells how many of the failed emails were given up on.
+   pub fn record_batch(
+      &mut self,
+      due: &[Entry],
+      deliveries: &[Delivery],
+      gave_up: usize,
+      duration: Duration,
+   ) {
+      let failed = deliveries.iter().filter(|d| d.result.is_err()).count() as u64;
+      self.sent += deliveries.len() as u64 - failed;
+      self.failed += failed;
+      self.deferred += failed.saturating_sub(gave_up as u64);
+      self.retried += deliveries
+         .iter()
+         .filter(|d| {
+            due.iter()
+               .any(|e| e.message_id == d.message_id && e.attempts > 0)
+         })
+         .count() as u64;
+      self.batch_duration.observe(duration);
+   }
+
+   /// Renders the metrics in the Prometheus text exposition format.
+   pub fn render(&self) -> String {
+      let mut result = String::new();
+      let counters = [
+         ("rmt_emails_sent_total", "Emails sent.", self.sent),
+         (
+            "rmt_emails_failed_total",
+            "Failed delivery attempts.",
+            self.failed,
+         ),
+         (
+            "rmt_emails_retried_total",
+            "Delivery attempts of deferred emails.",
+            self.retried,
+         ),
+         (
+            "rmt_emails_deferred_total",
+            "Failed emails deferred for another attempt.",
+            self.deferred,
+         ),
+      ];
+      for (name, help, value) in counters.iter() {
+         result.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+            name, help, name, name, value
+         ));
+      }
+      let gauges = [
+         (
+            "rmt_spool_pending",
+            "Emails waiting in the spool.",
+            self.pending,
+         ),
+         ("rmt_spool_given_up", "Emails given up on.", self.given_up),
+      ];
+      for (name, help, value) in gauges.iter() {
+         result.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} gauge\n{} {}\n",
+            name, help, name, name, value
+         ));
+      }
+      result.push_str(&self.send_duration.render(
+         "rmt_send_duration_seconds",
+         "Time taken to hand an email to the SMTP server.",
+      ));
+      result.push_str(&self.batch_duration.render(
+         "rmt_batch_duration_seconds",
+         "Time taken to deliver a batch of due emails.",
+      ));
+      result
+   }
+}
+
+/// The metrics shared by the daemon and the HTTP endpoint.
+pub type Shared = Arc<Mutex<Metrics>>;
+
+/// The `TimedTransport` struct measures how long the emails take to send over another
+/// transport.
+pub struct TimedTransport<'a> {
+   inner: &'a mut dyn Transport,
+   metrics: Shared,
+}
+
+impl<'a> TimedTransport<'a> {
+   /// Wraps the given transport, the send durations go to `metrics`.
+   pub fn new(inner: &'a mut dyn Transport, metrics: Shared) -> TimedTransport<'a> {
+      TimedTransport { inner, metrics }
+   }
+
+   /// Records the time elapsed since `start`.
+   fn observe(&self, start: Instant) {
+      if let Ok(mut metrics) = self.metrics.lock() {
+         metrics.send_duration.observe(start.elapsed());
+      }
+   }
+}
+
+impl<'a> Transport for TimedTransport<'a> {
+   fn send(&mut self, msg: &Message) -> Result<(), String> {
+      let start = Instant::now();
+      let result = self.inner.send(msg);
+      self.observe(start);
+      result
+   }
+
+   fn send_raw(&mut self, sender: &str, envelope: &[String], data: &[u8]) -> Result<(), String> {
+      let start = Instant::now();
+      let result = self.inner.send_raw(sender, envelope, data);
+      self.observe(start);
+      result
+   }
+
+   fn send_async<'b>(&'b mut self, msg: &'b Message) -> Sending<'b> {
+      Box::pin(async move {
+         let start = Instant::now();
+         let result = self.inner.send_async(msg).await;
+         self.observe(start);
+         result
+      })
+   }
+
+   fn describe(&self) -> String {
+      self.inner.describe()
+   }
+}
+
+/// Serves the metrics at `http://ADDRESS/metrics` on a thread of its own, returns the address
+/// listened on.
+pub fn serve(address: &str, metrics: Shared) -> Result<SocketAddr, String> {
+   let listener =
+      TcpListener::bind(address).map_err(|e| format!("cannot listen on {} ({})", address, e))?;
+   let result = listener
+      .local_addr()
+      .map_err(|e| format!("cannot listen on {} ({})", address, e))?;
+   thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+         if let Err(err) = respond(stream, &metrics) {
+            debug!("metrics: {}", err);
+         }
+      }
+   });
+   Ok(result)
+}
+
+/// Answers an HTTP request for the metrics.
+fn respond(stream: TcpStream, metrics: &Shared) -> Result<(), String> {
+   stream
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .map_err(|e| e.to_string())?;
+   let mut reader = BufReader::new(&stream);
+   let mut request = String::new();
+   reader
+      .read_line(&mut request)
+      .map_err(|e| format!("cannot read the request ({})", e))?;
+   // the headers are of no interest
+   let mut line = String::new();
+   while reader.read_line(&mut line).map_err(|e| e.to_string())? > 2 {
+      line.clear();
+   }
+   let path = request.split_whitespace().nth(1).unwrap_or_default();
+   let (status, body) = match path {
+      "/metrics" => match metrics.lock() {
+         Ok(metrics) => ("200 OK", metrics.render()),
+         Err(_) => ("500 Internal Server Error", String::new()),
+      },
+      _ => (
+         "404 Not Found",
+         String::from("the metrics are at /metrics\n"),
+      ),
+   };
+   let response = format!(
+      "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+       Connection: close\r\n\r\n{}",
+      status,
+      body.len(),
+      body
+   );
+   (&stream)
+      .write_all(response.as_bytes())
+      .map_err(|e| format!("cannot send the response ({})", e))
+}
+
+/// Writes the metrics to the given file for the textfile collector. The file is replaced
+/// atomically so that the collector never reads a partial file.
+pub fn write_file(path: &Path, metrics: &Metrics) -> Result<(), String> {
+   let temporary = path.with_extension("prom.tmp");
+   fs::write(&temporary, metrics.render())
+      .and_then(|_| fs::rename(&temporary, path))
+      .map_err(|e| format!("cannot write metrics file {} ({})", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing::MockTransport;
+   use std::io::Read;
+   use tempfile::TempDir;
+
+   fn entry(message_id: &str, attempts: u32) -> Entry {
+      Entry {
+         id: String::from("000001"),
+         sender: String::from("fb@example.com"),
+         recipient: String::from("jd@example.com"),
+         envelope: vec![String::from("jd@example.com")],
+         message_id: message_id.to_string(),
+         subject: String::from("hi"),
+         variant: None,
+         attempts,
+         retry_at: 0,
+         last_error: None,
+      }
+   }
+
+   fn delivery(message_id: &str, result: Result<(), String>) -> Delivery {
+      Delivery {
+         recipient: String::from("jd@example.com"),
+         message_id: message_id.to_string(),
+         result,
+         timestamp: 1_600_000_000,
+         variant: None,
+      }
+   }
+
+   #[test]
+   fn record_batches() {
+      let mut metrics = Metrics::default();
+      let due = vec![entry("<1>", 0), entry("<2>", 1), entry("<3>", 2)];
+      let deliveries = vec![
+         delivery("<1>", Ok(())),
+         delivery("<2>", Err(String::from("421 try later"))),
+         delivery("<3>", Err(String::from("550 no such user"))),
+      ];
+      metrics.record_batch(&due, &deliveries, 1, Duration::from_millis(300));
+      assert_eq!(
+         (1, 2, 2, 1),
+         (
+            metrics.sent,
+            metrics.failed,
+            metrics.retried,
+            metrics.deferred
+         )
+      );
+      assert_eq!(1, metrics.batch_duration.count());
+   }
+
+   #[test]
+   fn render_metrics() {
+      let mut metrics = Metrics {
+         sent: 3,
+         pending: 2,
+         ..Default::default()
+      };
+      metrics.send_duration.observe(Duration::from_millis(70));
+      metrics.send_duration.observe(Duration::from_millis(200));
+      metrics.send_duration.observe(Duration::from_secs(90));
+      let text = metrics.render();
+      assert!(text.contains(
+         "# HELP rmt_emails_sent_total Emails sent.\n# TYPE rmt_emails_sent_total counter\n\
+          rmt_emails_sent_total 3\n"
+      ));
+      assert!(text.contains("# TYPE rmt_spool_pending gauge\nrmt_spool_pending 2\n"));
+      assert!(text.contains(
+         "rmt_send_duration_seconds_bucket{le=\"0.05\"} 0\n\
+          rmt_send_duration_seconds_bucket{le=\"0.1\"} 1\n\
+          rmt_send_duration_seconds_bucket{le=\"0.25\"} 2\n"
+      ));
+      assert!(text.contains(
+         "rmt_send_duration_seconds_bucket{le=\"60\"} 2\n\
+          rmt_send_duration_seconds_bucket{le=\"+Inf\"} 3\n\
+          rmt_send_duration_seconds_sum 90.27\n\
+          rmt_send_duration_seconds_count 3\n"
+      ));
+      assert!(text.ends_with("rmt_batch_duration_seconds_count 0\n"));
+   }
+
+   #[test]
+   fn time_the_sends() {
+      let metrics = Shared::default();
+      let mut mock = MockTransport::new().fail_for("mm@gmail.com");
+      let mut timed = TimedTransport::new(&mut mock, metrics.clone());
+      let envelope = [String::from("jd@example.com")];
+      assert_eq!(Ok(()), timed.send_raw("fb@example.com", &envelope, b"hi"));
+      let envelope = [String::from("mm@gmail.com")];
+      assert!(timed.send_raw("fb@example.com", &envelope, b"hi").is_err());
+      assert_eq!("mock", timed.describe());
+      assert_eq!(2, metrics.lock().unwrap().send_duration.count());
+      assert_eq!(1, mock.raw.len());
+   }
+
+   #[test]
+   fn serve_metrics() {
+      let metrics = Shared::default();
+      metrics.lock().unwrap().sent = 7;
+      let address = serve("127.0.0.1:0", metrics).expect("cannot serve");
+      let get = |path: &str| {
+         let mut stream = TcpStream::connect(address).expect("cannot connect");
+         write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+         let mut response = String::new();
+         stream.read_to_string(&mut response).unwrap();
+         response
+      };
+      let response = get("/metrics");
+      assert!(
+         response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n")
+      );
+      assert!(response.contains("\nrmt_emails_sent_total 7\n"));
+      assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+   }
+
+   #[test]
+   fn write_metrics_file() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let path = dir.path().join("rmt.prom");
+      let metrics = Metrics {
+         failed: 2,
+         ..Default::default()
+      };
+      assert_eq!(Ok(()), write_file(&path, &metrics));
+      let text = fs::read_to_string(&path).unwrap();
+      assert!(text.contains("\nrmt_emails_failed_total 2\n"));
+      assert!(!dir.path().join("rmt.prom.tmp").exists());
+   }
+}
@@ -0,0 +1,202 @@
+/// The `preview` module turns a rendered email into a web page and opens it in the browser, for
+/// iterating on the look of a campaign without sending test emails. It also makes up recipients
+/// for templates whose recipients are not known yet.
+use crate::config::Recipient;
+use crate::message::{self, Message};
+use crate::schema::DataType;
+use crate::template;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The first names of the fake recipients.
+const FIRST_NAMES: &[&str] = &[
+   "Ada", "Alan", "Barbara", "Dennis", "Grace", "Ken", "Linus", "Margaret",
+];
+
+/// The last names of the fake recipients.
+const LAST_NAMES: &[&str] = &[
+   "Hamilton", "Hopper", "Liskov", "Lovelace", "Ritchie", "Thompson", "Torvalds", "Turing",
+];
+
+/// The organizations of the fake recipients.
+const ORGS: &[&str] = &["Acme Corp", "Globex", "Hooli", "Initech", "Umbrella Labs"];
+
+/// Makes up a recipient with a plausible value for every one of the `keys`, `random` picks the
+/// name and the organization. Typed keys get a value of their type, the values of the other keys
+/// are guessed from their names (`ORG`, `CITY`, `DUE_DATE`, `PRICE` etc.).
+pub fn fake_recipient<'a, I>(keys: I, schema: &HashMap<String, DataType>, random: u64) -> Recipient
+where
+   I: IntoIterator<Item = &'a String>,
+{
+   let first = FIRST_NAMES[(random % FIRST_NAMES.len() as u64) as usize];
+   let last = LAST_NAMES[(random / 8 % LAST_NAMES.len() as u64) as usize];
+   let org = ORGS[(random / 64 % ORGS.len() as u64) as usize];
+   let email = format!("{}.{}@example.com", first, last).to_lowercase();
+   let date = (Utc::now() + Duration::days(14))
+      .format("%Y-%m-%d")
+      .to_string();
+   let mut data = HashMap::new();
+   for key in keys {
+      // short words like ID must be a part of the key on their own, not of PAID
+      let upper = key.to_uppercase();
+      let has = |words: &[&str]| {
+         upper.split('_').any(|p| {
+            words
+               .iter()
+               .any(|w| p == *w || (w.len() > 2 && p.contains(w)))
+         })
+      };
+      let value = match schema.get(key) {
+         Some(DataType::Date) => date.clone(),
+         Some(DataType::Number) => String::from("42"),
+         Some(DataType::Currency(_)) => String::from("1234.50"),
+         None if has(&["EMAIL", "MAIL"]) => email.clone(),
+         None if has(&["ORG", "COMPANY"]) => org.to_string(),
+         None if has(&["FIRST", "FN"]) => first.to_string(),
+         None if has(&["LAST", "SURNAME", "LN"]) => last.to_string(),
+         None if has(&["NAME"]) => format!("{} {}", first, last),
+         None if has(&["CITY", "TOWN"]) => String::from("Springfield"),
+         None if has(&["TITLE", "ROLE", "POSITION"]) => String::from("Head of Engineering"),
+         None if has(&["URL", "LINK"]) => format!("https://example.com/{}", key.to_lowercase()),
+         None if has(&["DATE", "DUE", "DAY"]) => date.clone(),
+         None if has(&["AMOUNT", "PRICE", "TOTAL", "BALANCE"]) => String::from("99.00"),
+         None if has(&["PHONE", "MOBILE"]) => String::from("+1 555 0100"),
+         None if has(&["ID", "CODE", "NO", "NUMBER"]) => String::from("A-1024"),
+         None => format!("sample {}", key.to_lowercase().replace('_', " ")),
+      };
+      data.insert(key.clone(), value);
+   }
+   Recipient {
+      email,
+      names: vec![first.to_string(), last.to_string()],
+      data,
+   }
+}
+
+/// Returns the web page showing the email: its HTML body with the inline images (`cid:` URLs)
+/// replaced by `data:` URLs, or its plain text body if there is no HTML body.
+pub fn page(msg: &Message) -> String {
+   match msg.html {
+      Some(ref html) => {
+         let mut result = html.clone();
+         for image in msg.inline_images.iter() {
+            if let Some(ref cid) = image.content_id {
+               let url = format!(
+                  "data:{};base64,{}",
+                  image.content_type,
+                  base64::engine::general_purpose::STANDARD.encode(&image.data)
+               );
+               result = result.replace(&format!("cid:{}", cid), &url);
+            }
+         }
+         result
+      }
+      None => format!(
+         "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"></head><body><pre>{}</pre></body></html>\n",
+         template::escape_html(&msg.body)
+      ),
+   }
+}
+
+/// Writes the page to a file in the temporary directory and returns its path, the file is named
+/// after the recipient so that previewing the same recipient again overwrites it.
+pub fn write(page: &str, email: &str) -> Result<PathBuf, String> {
+   let path = std::env::temp_dir().join(format!(
+      "rmt-preview-{:016x}.html",
+      message::fnv1a(email.to_lowercase().as_bytes())
+   ));
+   fs::write(&path, page).map_err(|e| format!("cannot write {} ({})", path.display(), e))?;
+   Ok(path)
+}
+
+/// Opens the file with the default application for its type, i.e. the browser for web pages.
+pub fn open(path: &Path) -> Result<(), String> {
+   let (program, args): (&str, &[&str]) = if cfg!(target_os = "macos") {
+      ("open", &[])
+   } else if cfg!(windows) {
+      ("cmd", &["/C", "start", ""])
+   } else {
+      ("xdg-open", &[])
+   };
+   let status = Command::new(program)
+      .args(args)
+      .arg(path)
+      .status()
+      .map_err(|e| format!("cannot run {} ({})", program, e))?;
+   if !status.success() {
+      return Err(format!("{} failed ({})", program, status));
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::attachment::Attachment;
+   use crate::template;
+   use crate::testing;
+   use std::sync::Arc;
+
+   #[test]
+   fn page_with_inline_images() {
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.recipients = vec![testing::recipient("jd@example.com", "John Doe", &[])];
+      cfg.inline_images = vec![Arc::new(Attachment {
+         name: String::from("logo.png"),
+         content_type: String::from("image/png"),
+         data: b"PNG".to_vec(),
+         content_id: Some(String::from("logo.png@example.com")),
+      })];
+      let tmpl = template::new("Hi %_FN%!");
+      let html = template::new("<p>Hi %_FN%!<img src=\"%_CID:logo.png%\"></p>");
+      let msg = message::compose(&cfg, &tmpl, Some(&html), &cfg.recipients[0]);
+      assert_eq!(
+         "<p>Hi John!<img src=\"data:image/png;base64,UE5H\"></p>",
+         page(&msg)
+      );
+   }
+
+   #[test]
+   fn fake_recipients() {
+      let keys: Vec<String> = [
+         "ORG",
+         "DUE",
+         "SEATS",
+         "MOTTO",
+         "CUSTOMER_NAME",
+         "NOTE",
+         "ORDER_NO",
+      ]
+      .iter()
+      .map(|k| k.to_string())
+      .collect();
+      let schema: HashMap<String, DataType> = vec![
+         (String::from("DUE"), DataType::Date),
+         (String::from("SEATS"), DataType::Number),
+      ]
+      .into_iter()
+      .collect();
+      let rcp = fake_recipient(keys.iter(), &schema, 9);
+      assert_eq!("alan.hopper@example.com", rcp.email);
+      assert_eq!(vec!["Alan", "Hopper"], rcp.names);
+      assert_eq!("Acme Corp", rcp.data["ORG"]);
+      assert!(DataType::Date.check(&rcp.data["DUE"]).is_ok());
+      assert_eq!("42", rcp.data["SEATS"]);
+      assert_eq!("sample motto", rcp.data["MOTTO"]);
+      assert_eq!("Alan Hopper", rcp.data["CUSTOMER_NAME"]);
+      assert_eq!("sample note", rcp.data["NOTE"]);
+      assert_eq!("A-1024", rcp.data["ORDER_NO"]);
+   }
+
+   #[test]
+   fn page_without_html() {
+      let mut cfg = testing::config("fb@example.com", "hello");
+      cfg.recipients = vec![testing::recipient("jd@example.com", "John Doe", &[])];
+      let msg = message::compose(&cfg, &template::new("Hi <%_FN%>"), None, &cfg.recipients[0]);
+      assert!(page(&msg).contains("<pre>Hi &lt;John&gt;</pre>"));
+   }
+}
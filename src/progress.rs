@@ -0,0 +1,267 @@
+/// The `progress` module reports the progress of a campaign send to the user.
+use crate::color;
+use crate::events::{Event, Observer};
+use chrono::{DateTime, Utc};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+/// The `Verbosity` enum lists the output levels of the commands (`--quiet`, the default and
+/// `-v`).
+pub enum Verbosity {
+   /// Nothing but the errors e.g. for cron jobs
+   Quiet,
+   /// The status lines and warnings (the default)
+   #[default]
+   Normal,
+   /// A line per recipient even while the progress bar is shown
+   Verbose,
+}
+
+/// The `Progress` observer shows a progress bar with the sent/failed/remaining counts and an ETA
+/// if stdout is a terminal. Otherwise it prints a line per email. With `Verbosity::Quiet` only the
+/// failed deliveries are printed.
+pub struct Progress {
+   /// The progress bar, `None` for line output
+   bar: Option<ProgressBar>,
+   /// Whether a progress bar is to be shown at all
+   tty: bool,
+   /// How much is printed
+   verbosity: Verbosity,
+   /// The minimum time between two emails as per the rate limit (if any)
+   interval: Option<Duration>,
+   total: usize,
+   sent: usize,
+   failed: usize,
+}
+
+impl Progress {
+   /// Creates a progress observer, `interval` is the minimum time between two emails as per the
+   /// configured rate limit.
+   pub fn new(interval: Option<Duration>) -> Progress {
+      Progress::with_tty(interval, std::io::stdout().is_terminal())
+   }
+
+   /// Creates a progress observer that shows a progress bar if `tty` is set.
+   pub fn with_tty(interval: Option<Duration>, tty: bool) -> Progress {
+      Progress {
+         bar: None,
+         tty,
+         verbosity: Verbosity::Normal,
+         interval,
+         total: 0,
+         sent: 0,
+         failed: 0,
+      }
+   }
+
+   /// Sets the output level, the progress bar is not shown with `Verbosity::Quiet`.
+   pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+      self.verbosity = verbosity;
+      self
+   }
+
+   /// Returns the number of emails yet to be sent.
+   pub fn remaining(&self) -> usize {
+      self.total.saturating_sub(self.sent + self.failed)
+   }
+
+   /// Returns the estimated time needed for the remaining emails, this is only known if the
+   /// emails are rate limited.
+   pub fn eta(&self) -> Option<Duration> {
+      self.interval.map(|i| i * self.remaining() as u32)
+   }
+
+   /// Returns the current counts (and the ETA if known) for display.
+   pub fn status(&self) -> String {
+      let mut result = format!(
+         "sent: {}, failed: {}, remaining: {}",
+         self.sent,
+         self.failed,
+         self.remaining()
+      );
+      if let Some(eta) = self.eta() {
+         result.push_str(&format!(", ETA {}", format_duration(eta)));
+      }
+      result
+   }
+
+   /// Prints a line above the progress bar or to stdout if there is none.
+   fn println(&self, line: &str) {
+      match self.bar {
+         Some(ref bar) => bar.println(line),
+         None => println!("{}", line),
+      }
+   }
+
+   /// Advances the progress bar (if any).
+   fn advance(&self) {
+      if let Some(ref bar) = self.bar {
+         bar.inc(1);
+         bar.set_message(self.status());
+      }
+   }
+}
+
+/// Formats a duration for display e.g. `1h 02m 03s`.
+pub fn format_duration(duration: Duration) -> String {
+   let secs = duration.as_secs();
+   match (secs / 3600, secs % 3600 / 60, secs % 60) {
+      (0, 0, s) => format!("{}s", s),
+      (0, m, s) => format!("{}m {:02}s", m, s),
+      (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+   }
+}
+
+/// Formats the countdown shown while waiting for the scheduled start of a campaign.
+pub fn countdown(remaining: Duration) -> String {
+   format!("sending starts in {}", format_duration(remaining))
+}
+
+/// Waits until the given time, with a countdown on the terminal if `tty` is set.
+pub fn wait_until(at: DateTime<Utc>, tty: bool) {
+   let bar = if tty {
+      Some(ProgressBar::new_spinner())
+   } else {
+      None
+   };
+   while let Ok(remaining) = (at - Utc::now()).to_std() {
+      if let Some(ref bar) = bar {
+         bar.set_message(countdown(remaining));
+         bar.tick();
+      }
+      thread::sleep(remaining.min(Duration::from_secs(1)));
+   }
+   if let Some(bar) = bar {
+      bar.finish_and_clear();
+   }
+}
+
+impl Observer for Progress {
+   fn notify(&mut self, event: &Event) {
+      match event {
+         Event::CampaignStarted { recipients, .. } => {
+            self.total = *recipients;
+            if self.tty && self.verbosity > Verbosity::Quiet {
+               // without a rate limit let the progress bar estimate the remaining time
+               let template = match self.interval {
+                  Some(_) => "{wide_bar} {pos}/{len} {msg}",
+                  None => "{wide_bar} {pos}/{len} {msg}, ETA {eta}",
+               };
+               let bar = ProgressBar::new(*recipients as u64);
+               if let Ok(style) = ProgressStyle::with_template(template) {
+                  bar.set_style(style);
+               }
+               bar.set_message(self.status());
+               self.bar = Some(bar);
+            }
+         }
+         Event::MessageSent { recipient, .. } => {
+            self.sent += 1;
+            if (self.bar.is_none() && self.verbosity > Verbosity::Quiet)
+               || self.verbosity == Verbosity::Verbose
+            {
+               self.println(&format!("* sent to {}", recipient));
+            }
+            self.advance();
+         }
+         Event::MessageFailed {
+            recipient, error, ..
+         } => {
+            self.failed += 1;
+            self.println(&color::error(&format!(
+               "!! error: sending to {} failed ({})",
+               recipient, error
+            )));
+            self.advance();
+         }
+         Event::CampaignFinished { .. } => {
+            if let Some(bar) = self.bar.take() {
+               bar.finish_and_clear();
+            }
+            if self.verbosity > Verbosity::Quiet {
+               let line = format!("* done, sent: {}, failed: {}", self.sent, self.failed);
+               if self.failed == 0 {
+                  println!("{}", color::success(&line));
+               } else {
+                  println!("{}", color::warning(&line));
+               }
+            }
+         }
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn format_duration_happy_case() {
+      assert_eq!("7s", format_duration(Duration::from_secs(7)));
+      assert_eq!("2m 05s", format_duration(Duration::from_secs(125)));
+      assert_eq!("1h 02m 03s", format_duration(Duration::from_secs(3723)));
+   }
+
+   #[test]
+   fn countdown_and_wait() {
+      assert_eq!(
+         "sending starts in 1h 00m 00s",
+         countdown(Duration::from_secs(3600))
+      );
+      // a time in the past does not block
+      wait_until(Utc::now() - chrono::Duration::seconds(5), false);
+   }
+
+   #[test]
+   fn status_with_rate_limit() {
+      let mut progress = Progress::with_tty(Some(Duration::from_secs(2)), false);
+      progress.notify(&Event::CampaignStarted {
+         campaign_id: String::from("spring-sale"),
+         recipients: 100,
+      });
+      progress.notify(&Event::MessageSent {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("jd@example.com"),
+         message_id: String::from("<spring-sale.1@example.com>"),
+         subject: String::from("Hello John!"),
+         variant: None,
+      });
+      progress.notify(&Event::MessageFailed {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("mm@gmail.com"),
+         message_id: String::from("<spring-sale.2@example.com>"),
+         subject: String::from("Hello Mickey!"),
+         variant: None,
+         error: String::from("mailbox full"),
+      });
+      assert_eq!(
+         "sent: 1, failed: 1, remaining: 98, ETA 3m 16s",
+         progress.status()
+      );
+   }
+
+   #[test]
+   fn status_without_rate_limit() {
+      let mut progress = Progress::with_tty(None, false);
+      progress.notify(&Event::CampaignStarted {
+         campaign_id: String::from("spring-sale"),
+         recipients: 2,
+      });
+      assert_eq!(None, progress.eta());
+      assert_eq!("sent: 0, failed: 0, remaining: 2", progress.status());
+   }
+
+   #[test]
+   fn quiet_without_progress_bar() {
+      let mut progress = Progress::with_tty(None, true).verbosity(Verbosity::Quiet);
+      progress.notify(&Event::CampaignStarted {
+         campaign_id: String::from("spring-sale"),
+         recipients: 2,
+      });
+      assert!(progress.bar.is_none());
+      assert_eq!("sent: 0, failed: 0, remaining: 2", progress.status());
+   }
+}
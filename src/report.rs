@@ -0,0 +1,745 @@
+/// The `report` module generates the campaign summary shown before sending, machine-readable
+/// reports of campaign runs, the statistics of past runs and the summary emailed to the operator.
+use crate::address::Address;
+use crate::attachment::Attachment;
+use crate::config::{self, Config};
+use crate::message::Message;
+use crate::spool::Spool;
+use crate::template::escape_html;
+use crate::transport::Delivery;
+use crate::{encoding, history, listing, progress};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Summarizes what is about to be sent, `transport` describes how the emails are sent.
+pub fn summary(cfg: &Config, transport: &str) -> String {
+   let mut lines = vec![
+      format!("campaign:   {}", cfg.campaign_id),
+      format!("from:       {}", cfg.gdata.from),
+      format!("subject:    {}", cfg.gdata.subject),
+      format!("transport:  {}", transport),
+      format!("recipients: {}", cfg.recipients.len()),
+   ];
+   if !cfg.gdata.cc.is_empty() {
+      lines.push(format!("cc:         {}", config::join(&cfg.gdata.cc)));
+   }
+   if !cfg.attachments.is_empty() {
+      let sizes: Vec<String> = cfg
+         .attachments
+         .iter()
+         .map(|a| format!("{} ({})", a.name, config::format_size(a.data.len() as u64)))
+         .collect();
+      let total = cfg.attachments.iter().map(|a| a.data.len() as u64).sum();
+      lines.push(format!(
+         "attachments: {}, total {}",
+         sizes.join(", "),
+         config::format_size(total)
+      ));
+   }
+   if !cfg.generated_attachments.is_empty() {
+      let total: u64 = cfg
+         .generated_attachments
+         .values()
+         .map(|a| a.data.len() as u64)
+         .sum();
+      lines.push(format!(
+         "generated:  {} attachment(s), total {}",
+         cfg.generated_attachments.len(),
+         config::format_size(total)
+      ));
+   }
+   if let Some(ref address) = cfg.redirect_to {
+      lines.push(format!("redirected: all emails go to {}", address));
+   }
+   if let Some(ref at) = cfg.send_at {
+      lines.push(format!("scheduled:  {}", at.to_rfc3339()));
+   }
+   let estimate = cfg.smtp.as_ref().and_then(|s| s.estimate(&cfg.recipients));
+   lines.push(match estimate {
+      Some(estimate) => format!(
+         "duration:   about {} (rate limited)",
+         progress::format_duration(estimate)
+      ),
+      None => String::from("duration:   unknown (no rate limit)"),
+   });
+   lines.join("\n")
+}
+
+/// Summarizes the spooled emails about to be sent, `transport` describes how they are sent.
+pub fn spool_summary(spool: &Spool, pending: usize, transport: &str) -> String {
+   [
+      format!("campaign:   {}", spool.campaign_id),
+      format!(
+         "spooled:    {}",
+         history::format_timestamp(spool.created_at)
+      ),
+      format!("transport:  {}", transport),
+      format!("emails:     {}", pending),
+   ]
+   .join("\n")
+}
+
+/// The `Run` struct holds the outcome of a campaign run.
+pub struct Run<'a> {
+   /// The campaign identifier
+   pub campaign_id: &'a str,
+   /// Whether the emails were only composed and not sent
+   pub dry_run: bool,
+   /// When the run started (seconds since the epoch)
+   pub started_at: u64,
+   /// When the run finished (seconds since the epoch)
+   pub finished_at: u64,
+   /// The outcome for every recipient
+   pub deliveries: &'a [Delivery],
+   /// The reasons for skipping invalid recipients
+   pub quarantined: &'a [String],
+   /// The recipients not sent to because the run was interrupted
+   pub remaining: &'a [String],
+}
+
+impl<'a> Run<'a> {
+   /// Returns the number of failed deliveries.
+   pub fn failed(&self) -> usize {
+      self.deliveries.iter().filter(|d| d.result.is_err()).count()
+   }
+
+   /// Returns the number of deliveries per template variant, empty unless the campaign is an
+   /// A/B test.
+   pub fn variant_counts(&self) -> BTreeMap<String, usize> {
+      let mut result = BTreeMap::new();
+      for variant in self.deliveries.iter().filter_map(|d| d.variant.as_ref()) {
+         *result.entry(variant.clone()).or_insert(0) += 1;
+      }
+      result
+   }
+
+   /// Returns the title and the text of the desktop notification telling how the run went.
+   pub fn notification(&self) -> (String, String) {
+      let failed = self.failed();
+      let title = if !self.remaining.is_empty() {
+         format!("Campaign {} interrupted", self.campaign_id)
+      } else if failed > 0 {
+         format!("Campaign {} finished with failures", self.campaign_id)
+      } else {
+         format!("Campaign {} finished", self.campaign_id)
+      };
+      let mut text = format!(
+         "{} {}, {} failed",
+         self.deliveries.len() - failed,
+         if self.dry_run { "composed" } else { "sent" },
+         failed
+      );
+      if !self.remaining.is_empty() {
+         text.push_str(&format!(", {} not sent", self.remaining.len()));
+      }
+      (title, text)
+   }
+
+   /// Returns the JSON representation of the run.
+   ///
+   /// The status of a delivery is one of `sent`, `failed` or, for dry runs, `composed`. The
+   /// `error` is `null` unless the delivery failed, the `variant` is `null` unless the campaign
+   /// is an A/B test.
+   pub fn to_json(&self) -> Value {
+      let deliveries: Vec<Value> = self
+         .deliveries
+         .iter()
+         .map(|d| {
+            let (status, error) = match d.result {
+               Ok(()) if self.dry_run => ("composed", None),
+               Ok(()) => ("sent", None),
+               Err(ref err) => ("failed", Some(err)),
+            };
+            json!({
+               "recipient": d.recipient,
+               "status": status,
+               "message_id": d.message_id,
+               "timestamp": d.timestamp,
+               "variant": d.variant,
+               "error": error,
+            })
+         })
+         .collect();
+      let failed = self.failed();
+      json!({
+         "campaign_id": self.campaign_id,
+         "dry_run": self.dry_run,
+         "started_at": self.started_at,
+         "finished_at": self.finished_at,
+         "sent": if self.dry_run { 0 } else { self.deliveries.len() - failed },
+         "failed": failed,
+         "deliveries": deliveries,
+         "quarantined": self.quarantined,
+         "remaining": self.remaining,
+      })
+   }
+}
+
+#[derive(Debug)]
+/// The `Stats` struct holds the statistics of a campaign run.
+pub struct Stats {
+   /// The campaign identifier
+   pub campaign_id: String,
+   /// The subject of the emails
+   pub subject: String,
+   /// When the run started (seconds since the epoch)
+   pub started_at: u64,
+   /// How long the run took
+   pub duration: Duration,
+   /// The number of deliveries per status (`sent`, `failed`, ..)
+   pub counts: BTreeMap<String, usize>,
+   /// The failure reasons with the number of deliveries that failed for them, most frequent
+   /// first
+   pub errors: Vec<(String, usize)>,
+   /// The number of deliveries per status for every template variant of an A/B test
+   pub variants: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+impl PartialEq for Stats {
+   /// Makes it possible to compare instances of `Stats`
+   fn eq(&self, other: &Self) -> bool {
+      self.campaign_id == other.campaign_id
+         && self.subject == other.subject
+         && self.started_at == other.started_at
+         && self.duration == other.duration
+         && self.counts == other.counts
+         && self.errors == other.errors
+         && self.variants == other.variants
+   }
+}
+
+/// Replaces the email addresses in an error message so that the same failure for different
+/// recipients is grouped together.
+fn error_group(error: &str) -> String {
+   let re = Regex::new(r"<?[^\s<>]+@[^\s<>]+>?").unwrap();
+   re.replace_all(error.trim(), "<address>").to_string()
+}
+
+impl Stats {
+   /// Computes the statistics of the campaign run recorded in the given document (see
+   /// `Run::to_json`).
+   pub fn from_record(record: &Value) -> Result<Stats, String> {
+      let deliveries = record["deliveries"]
+         .as_array()
+         .ok_or("not a campaign record (no deliveries)")?;
+      let started_at = record["started_at"].as_u64().unwrap_or(0);
+      let finished_at = record["finished_at"].as_u64().unwrap_or(started_at);
+      let mut counts = BTreeMap::new();
+      let mut errors: BTreeMap<String, usize> = BTreeMap::new();
+      let mut variants: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+      for d in deliveries.iter() {
+         let status = d["status"].as_str().unwrap_or("unknown").to_string();
+         if let Some(variant) = d["variant"].as_str() {
+            *variants
+               .entry(variant.to_string())
+               .or_default()
+               .entry(status.clone())
+               .or_insert(0) += 1;
+         }
+         *counts.entry(status).or_insert(0) += 1;
+         if let Some(error) = d["error"].as_str() {
+            *errors.entry(error_group(error)).or_insert(0) += 1;
+         }
+      }
+      let mut errors: Vec<(String, usize)> = errors.into_iter().collect();
+      errors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+      Ok(Stats {
+         campaign_id: record["campaign_id"]
+            .as_str()
+            .unwrap_or("unknown")
+            .to_string(),
+         subject: record["subject"].as_str().unwrap_or_default().to_string(),
+         started_at,
+         duration: Duration::from_secs(finished_at.saturating_sub(started_at)),
+         counts,
+         errors,
+         variants,
+      })
+   }
+
+   /// Returns the number of deliveries attempted.
+   pub fn total(&self) -> usize {
+      self.counts.values().sum()
+   }
+
+   /// Returns the number of emails handled per minute.
+   pub fn throughput(&self) -> f64 {
+      let secs = self.duration.as_secs().max(1) as f64;
+      self.total() as f64 * 60.0 / secs
+   }
+
+   /// Returns the general figures as (name, value) pairs.
+   fn figures(&self) -> Vec<(String, String)> {
+      let mut result = vec![
+         (String::from("campaign"), self.campaign_id.clone()),
+         (String::from("subject"), self.subject.clone()),
+         (
+            String::from("started"),
+            history::format_timestamp(self.started_at),
+         ),
+         (
+            String::from("duration"),
+            progress::format_duration(self.duration),
+         ),
+         (
+            String::from("throughput"),
+            format!("{:.1} emails/min", self.throughput()),
+         ),
+         (String::from("total"), self.total().to_string()),
+      ];
+      result.extend(self.counts.iter().map(|(k, v)| (k.clone(), v.to_string())));
+      for (variant, counts) in self.variants.iter() {
+         let counts: Vec<String> = counts.iter().map(|(k, v)| format!("{} {}", k, v)).collect();
+         result.push((format!("variant {}", variant), counts.join(", ")));
+      }
+      result
+   }
+
+   /// Formats the statistics for display.
+   pub fn to_text(&self) -> String {
+      let mut result = String::new();
+      for (name, value) in self.figures() {
+         result.push_str(&format!("{:<12}{}\n", format!("{}:", name), value));
+      }
+      if !self.errors.is_empty() {
+         result.push_str("\nfailures by error:\n");
+         for (error, count) in self.errors.iter() {
+            result.push_str(&format!("{:>6}  {}\n", count, error));
+         }
+      }
+      result
+   }
+
+   /// Formats the statistics as CSV, the failures are listed with an `error: ` prefix.
+   pub fn to_csv(&self) -> String {
+      let mut result = String::from("metric,value\r\n");
+      let errors = self
+         .errors
+         .iter()
+         .map(|(e, c)| (format!("error: {}", e), c.to_string()));
+      for (name, value) in self.figures().into_iter().chain(errors) {
+         result.push_str(&format!(
+            "{},{}\r\n",
+            listing::csv_field(&name),
+            listing::csv_field(&value)
+         ));
+      }
+      result
+   }
+
+   /// Formats the statistics as a standalone HTML document.
+   pub fn to_html(&self) -> String {
+      let row = |name: &str, value: &str| {
+         format!(
+            "<tr><th>{}</th><td>{}</td></tr>\n",
+            escape_html(name),
+            escape_html(value)
+         )
+      };
+      let mut result = format!(
+         "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+          </head>\n<body>\n<h1>Campaign {}</h1>\n<table>\n",
+         escape_html(&self.campaign_id),
+         escape_html(&self.campaign_id)
+      );
+      for (name, value) in self.figures() {
+         result.push_str(&row(&name, &value));
+      }
+      result.push_str("</table>\n");
+      if !self.errors.is_empty() {
+         result.push_str("<h2>Failures by error</h2>\n<table>\n");
+         for (error, count) in self.errors.iter() {
+            result.push_str(&row(&count.to_string(), error));
+         }
+         result.push_str("</table>\n");
+      }
+      result.push_str("</body>\n</html>\n");
+      result
+   }
+}
+
+/// Composes the email telling the operator how a campaign run went: the statistics, the failed
+/// recipients and the campaign record (see `Run::to_json`) as a JSON attachment.
+pub fn summary_email(cfg: &Config, record: &Value, to: &Address) -> Result<Message, String> {
+   let stats = Stats::from_record(record)?;
+   let count = |key: &str| record[key].as_u64().unwrap_or(0);
+   let remaining = record["remaining"].as_array().map_or(0, |r| r.len());
+   let mut subject = format!(
+      "[rmt] campaign {}: {} sent, {} failed",
+      stats.campaign_id,
+      count("sent"),
+      count("failed")
+   );
+   let mut body = match remaining {
+      0 => format!("The campaign {} finished.\n\n", stats.campaign_id),
+      n => {
+         subject.push_str(&format!(", {} not sent", n));
+         format!(
+            "The campaign {} was interrupted, {} email(s) were not sent.\n\n",
+            stats.campaign_id, n
+         )
+      }
+   };
+   body.push_str(&stats.to_text());
+   let failed: Vec<String> = record["deliveries"]
+      .as_array()
+      .map(|deliveries| {
+         deliveries
+            .iter()
+            .filter(|d| d["status"] == "failed")
+            .map(|d| {
+               format!(
+                  "   - {} ({})",
+                  d["recipient"].as_str().unwrap_or_default(),
+                  d["error"].as_str().unwrap_or_default()
+               )
+            })
+            .collect()
+      })
+      .unwrap_or_default();
+   if !failed.is_empty() {
+      body.push_str(&format!("\nfailed recipients:\n{}\n", failed.join("\n")));
+   }
+   let report = Attachment {
+      name: format!("{}-{}.json", stats.campaign_id, stats.started_at),
+      content_type: String::from("application/json"),
+      data: format!("{:#}\n", record).into_bytes(),
+      content_id: None,
+   };
+   let from = cfg.gdata.from.to_ascii();
+   let to = to.to_ascii();
+   Ok(Message {
+      sender: from.email(),
+      recipient: to.email(),
+      cc: vec![],
+      headers: vec![
+         (
+            String::from("From"),
+            encoding::encode_address(&from.to_string(), cfg.transliterate),
+         ),
+         (
+            String::from("To"),
+            encoding::encode_address(&to.to_string(), cfg.transliterate),
+         ),
+         (String::from("Subject"), encoding::encode_word(&subject)),
+         (
+            String::from("Message-ID"),
+            format!(
+               "<{}.summary.{}@{}>",
+               stats.campaign_id, stats.started_at, cfg.msgid_domain
+            ),
+         ),
+      ],
+      body,
+      html: None,
+      transfer_encoding: cfg.transfer_encoding,
+      attachments: vec![Arc::new(report)],
+      inline_images: vec![],
+   })
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   use crate::config::{parse_rate, Security, Smtp};
+   use crate::testing;
+   use std::collections::HashMap;
+
+   #[test]
+   fn summary_with_rate_limit() {
+      let mut cfg = testing::config("rts@example.com", "Hello %_FN%!");
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[]),
+      ];
+      cfg.smtp = Some(Smtp {
+         host: String::from("smtp.example.com"),
+         port: 465,
+         security: Security::Tls,
+         username: None,
+         password: None,
+         keyring: None,
+         rate: Some(parse_rate("1/min").unwrap()),
+         domain_rates: HashMap::new(),
+         jitter: None,
+         group_by_domain: false,
+         domain_burst: None,
+         connections: 1,
+         dsn_notify: None,
+         dsn_ret: None,
+         smtputf8: false,
+      });
+      let expected = "campaign:   test-campaign
+from:       rts@example.com
+subject:    Hello %_FN%!
+transport:  smtp://smtp.example.com:465
+recipients: 2
+duration:   about 2m 00s (rate limited)";
+      assert_eq!(expected, summary(&cfg, "smtp://smtp.example.com:465"));
+      cfg.smtp = None;
+      cfg.redirect_to = Some(String::from("reviewer@example.com"));
+      assert!(summary(&cfg, "mock").ends_with(
+         "redirected: all emails go to reviewer@example.com\nduration:   unknown (no rate limit)"
+      ));
+   }
+
+   #[test]
+   fn summary_with_attachments() {
+      let mut cfg = testing::config("rts@example.com", "Hello");
+      let attachment = |name: &str, size: usize| {
+         Arc::new(Attachment {
+            name: name.to_string(),
+            content_type: String::from("application/pdf"),
+            data: vec![0; size],
+            content_id: None,
+         })
+      };
+      cfg.attachments = vec![
+         attachment("terms.pdf", 1536 * 1024),
+         attachment("a.txt", 512),
+      ];
+      cfg.generated_attachments = vec![
+         (String::from("jd@example.com"), attachment("jd.pdf", 2048)),
+         (String::from("mm@gmail.com"), attachment("mm.pdf", 1024)),
+      ]
+      .into_iter()
+      .collect();
+      let summary = summary(&cfg, "mock");
+      assert!(
+         summary.contains(
+            "\nattachments: terms.pdf (1.5 MB), a.txt (512 bytes), total 1.5 MB\n\
+             generated:  2 attachment(s), total 3.0 KB\n"
+         ),
+         "{}",
+         summary
+      );
+   }
+
+   fn stats_record() -> Value {
+      json!({
+         "campaign_id": "spring-sale",
+         "subject": "Hello %_FN%!",
+         "started_at": 1_600_000_000,
+         "finished_at": 1_600_000_120,
+         "deliveries": [
+            {"recipient": "jd@example.com", "status": "sent", "error": null},
+            {"recipient": "mm@gmail.com", "status": "failed",
+             "error": "550 5.1.1 <mm@gmail.com>: user unknown"},
+            {"recipient": "dd@gmail.com", "status": "failed",
+             "error": "550 5.1.1 <dd@gmail.com>: user unknown"},
+            {"recipient": "ab@example.com", "status": "failed", "error": "mailbox full"},
+         ],
+      })
+   }
+
+   #[test]
+   fn stats_from_record() {
+      let stats = Stats::from_record(&stats_record()).expect("invalid record");
+      assert_eq!(4, stats.total());
+      assert_eq!(Some(&3), stats.counts.get("failed"));
+      assert_eq!(Some(&1), stats.counts.get("sent"));
+      assert_eq!(
+         vec![
+            (String::from("550 5.1.1 <address>: user unknown"), 2),
+            (String::from("mailbox full"), 1),
+         ],
+         stats.errors
+      );
+      assert_eq!(Duration::from_secs(120), stats.duration);
+      assert!((stats.throughput() - 2.0).abs() < 1e-9);
+      assert!(Stats::from_record(&json!({})).is_err());
+   }
+
+   #[test]
+   fn stats_formats() {
+      let stats = Stats::from_record(&stats_record()).expect("invalid record");
+      let text = stats.to_text();
+      assert!(text.starts_with("campaign:   spring-sale\nsubject:    Hello %_FN%!\n"));
+      assert!(text.contains("duration:   2m 00s\nthroughput: 2.0 emails/min\ntotal:      4\n"));
+      assert!(text.ends_with(
+         "failures by error:\n     2  550 5.1.1 <address>: user unknown\n     1  mailbox full\n"
+      ));
+      let csv = stats.to_csv();
+      assert!(csv.starts_with("metric,value\r\ncampaign,spring-sale\r\n"));
+      assert!(csv.ends_with("error: mailbox full,1\r\n"));
+      let html = stats.to_html();
+      assert!(html.contains("<tr><th>2</th><td>550 5.1.1 &lt;address&gt;: user unknown</td></tr>"));
+   }
+
+   fn delivery(recipient: &str, result: Result<(), String>) -> Delivery {
+      Delivery {
+         recipient: recipient.to_string(),
+         message_id: format!("<spring-sale.{}@example.com>", recipient.len()),
+         result,
+         timestamp: 1_600_000_001,
+         variant: None,
+      }
+   }
+
+   #[test]
+   fn run_to_json() {
+      let mut deliveries = vec![
+         delivery("jd@example.com", Ok(())),
+         delivery("mm@gmail.com", Err(String::from("mailbox full"))),
+      ];
+      deliveries[0].variant = Some(String::from("a"));
+      let run = Run {
+         campaign_id: "spring-sale",
+         dry_run: false,
+         started_at: 1_600_000_000,
+         finished_at: 1_600_000_002,
+         deliveries: &deliveries,
+         quarantined: &[],
+         remaining: &[],
+      };
+      let expected = json!({
+         "campaign_id": "spring-sale",
+         "dry_run": false,
+         "started_at": 1_600_000_000,
+         "finished_at": 1_600_000_002,
+         "sent": 1,
+         "failed": 1,
+         "deliveries": [
+            {
+               "recipient": "jd@example.com",
+               "status": "sent",
+               "message_id": "<spring-sale.14@example.com>",
+               "timestamp": 1_600_000_001,
+               "variant": "a",
+               "error": null,
+            },
+            {
+               "recipient": "mm@gmail.com",
+               "status": "failed",
+               "message_id": "<spring-sale.12@example.com>",
+               "timestamp": 1_600_000_001,
+               "variant": null,
+               "error": "mailbox full",
+            },
+         ],
+         "quarantined": [],
+         "remaining": [],
+      });
+      assert_eq!(expected, run.to_json());
+      assert_eq!(1, run.variant_counts()["a"]);
+   }
+
+   #[test]
+   fn stats_with_variants() {
+      let mut record = stats_record();
+      for (i, variant) in ["a", "b", "a", "b"].iter().enumerate() {
+         record["deliveries"][i]["variant"] = json!(variant);
+      }
+      let stats = Stats::from_record(&record).expect("invalid record");
+      assert_eq!(2, stats.variants.len());
+      assert_eq!(Some(&1), stats.variants["a"].get("sent"));
+      assert_eq!(Some(&2), stats.variants["b"].get("failed"));
+      assert!(stats
+         .to_text()
+         .contains("variant a:  failed 1, sent 1\nvariant b:  failed 2\n"));
+   }
+
+   #[test]
+   fn dry_run_to_json() {
+      let deliveries = vec![delivery("jd@example.com", Ok(()))];
+      let run = Run {
+         campaign_id: "spring-sale",
+         dry_run: true,
+         started_at: 1_600_000_000,
+         finished_at: 1_600_000_000,
+         deliveries: &deliveries,
+         quarantined: &[],
+         remaining: &[],
+      };
+      let json = run.to_json();
+      assert_eq!(0, json["sent"]);
+      assert_eq!("composed", json["deliveries"][0]["status"]);
+   }
+
+   #[test]
+   fn operator_summary_email() {
+      let mut cfg = testing::config("Frodo Baggins <fb@example.com>", "Hello %_FN%!");
+      cfg.msgid_domain = String::from("example.com");
+      let mut record = stats_record();
+      record["sent"] = json!(1);
+      record["failed"] = json!(3);
+      let to = Address::parse("ops@example.com").unwrap();
+      let msg = summary_email(&cfg, &record, &to).expect("cannot compose summary");
+      assert_eq!("fb@example.com", msg.sender);
+      assert_eq!("ops@example.com", msg.recipient);
+      assert_eq!(
+         Some("[rmt] campaign spring-sale: 1 sent, 3 failed"),
+         msg.header("Subject")
+      );
+      assert_eq!(
+         Some("<spring-sale.summary.1600000000@example.com>"),
+         msg.header("Message-ID")
+      );
+      assert!(msg
+         .body
+         .starts_with("The campaign spring-sale finished.\n\ncampaign:   spring-sale\n"));
+      assert!(msg
+         .body
+         .ends_with("failed recipients:\n   - mm@gmail.com (550 5.1.1 <mm@gmail.com>: user unknown)\n   - dd@gmail.com (550 5.1.1 <dd@gmail.com>: user unknown)\n   - ab@example.com (mailbox full)\n"));
+      assert_eq!("spring-sale-1600000000.json", msg.attachments[0].name);
+      assert_eq!("application/json", msg.attachments[0].content_type);
+      let attached: Value = serde_json::from_slice(&msg.attachments[0].data).unwrap();
+      assert_eq!(record, attached);
+
+      record["remaining"] = json!(["ee@gmail.com"]);
+      let msg = summary_email(&cfg, &record, &to).expect("cannot compose summary");
+      assert_eq!(
+         Some("[rmt] campaign spring-sale: 1 sent, 3 failed, 1 not sent"),
+         msg.header("Subject")
+      );
+      assert!(msg
+         .body
+         .starts_with("The campaign spring-sale was interrupted, 1 email(s) were not sent.\n"));
+      assert!(summary_email(&cfg, &json!({}), &to).is_err());
+   }
+
+   #[test]
+   fn run_notification() {
+      let deliveries = vec![
+         delivery("jd@example.com", Ok(())),
+         delivery("mm@gmail.com", Err(String::from("mailbox full"))),
+      ];
+      let remaining = vec![String::from("ab@example.com")];
+      let mut run = Run {
+         campaign_id: "spring-sale",
+         dry_run: false,
+         started_at: 1_600_000_000,
+         finished_at: 1_600_000_010,
+         deliveries: &deliveries[..1],
+         quarantined: &[],
+         remaining: &[],
+      };
+      assert_eq!(
+         (
+            String::from("Campaign spring-sale finished"),
+            String::from("1 sent, 0 failed")
+         ),
+         run.notification()
+      );
+      run.deliveries = &deliveries;
+      assert_eq!(
+         (
+            String::from("Campaign spring-sale finished with failures"),
+            String::from("1 sent, 1 failed")
+         ),
+         run.notification()
+      );
+      run.remaining = &remaining;
+      assert_eq!(
+         (
+            String::from("Campaign spring-sale interrupted"),
+            String::from("1 sent, 1 failed, 1 not sent")
+         ),
+         run.notification()
+      );
+   }
+}
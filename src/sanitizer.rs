@@ -0,0 +1,207 @@
+/// The `sanitizer` module checks HTML templates for problems (unclosed tags, constructs that mail
+/// clients strip or spam filters penalize) and strips the dangerous constructs.
+use regex::{Captures, Regex};
+
+/// The elements that have no closing tag.
+const VOID: &[&str] = &[
+   "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+   "track", "wbr",
+];
+
+/// The elements whose closing tag may be omitted.
+const OPTIONAL_CLOSE: &[&str] = &[
+   "body", "colgroup", "dd", "dt", "head", "html", "li", "option", "p", "tbody", "td", "tfoot",
+   "th", "thead", "tr",
+];
+
+/// The elements that are removed by `sanitize` along with their content, and why they are a
+/// problem.
+const STRIPPED: &[(&str, &str)] = &[
+   (
+      "script",
+      "mail clients strip scripts and spam filters penalize them",
+   ),
+   ("iframe", "mail clients do not show frames"),
+   ("frame", "mail clients do not show frames"),
+   ("frameset", "mail clients do not show frames"),
+   ("object", "mail clients do not show embedded objects"),
+   ("embed", "mail clients do not show embedded objects"),
+   ("applet", "mail clients do not show embedded objects"),
+];
+
+/// Returns the line number of the given byte offset.
+fn line(text: &str, offset: usize) -> usize {
+   text[..offset].matches('\n').count() + 1
+}
+
+/// Replaces the comments in the HTML text with blanks, keeping the offsets intact.
+fn blank_comments(html: &str) -> String {
+   let re = Regex::new(r"(?s)<!--.*?-->").unwrap();
+   re.replace_all(html, |caps: &Captures| {
+      caps[0]
+         .chars()
+         .map(|c| if c == '\n' { '\n' } else { ' ' })
+         .collect::<String>()
+   })
+   .into_owned()
+}
+
+/// Checks the HTML text for unclosed or stray tags and for constructs that are hostile to mail
+/// clients, returns the problems found.
+pub fn check(html: &str) -> Vec<String> {
+   let text = blank_comments(html);
+   let tag_re = Regex::new(r#"<(/?)([a-zA-Z][\w-]*)((?:[^>'"]|'[^']*'|"[^"]*")*)>"#).unwrap();
+   let handler_re = Regex::new(r"(?i)(?:^|\s)(on[a-z]+)\s*=").unwrap();
+   let js_re = Regex::new(r#"(?i)(?:^|\s)(?:href|src|action)\s*=\s*["']?\s*javascript:"#).unwrap();
+   let mut problems = vec![];
+   let mut stack: Vec<(String, usize)> = vec![];
+   let mut raw_text_end = None;
+   for caps in tag_re.captures_iter(&text) {
+      let start = caps.get(0).unwrap().start();
+      let element = caps[2].to_lowercase();
+      let attributes = &caps[3];
+      let closing = !caps[1].is_empty();
+      let lineno = line(&text, start);
+      // the content of scripts and style sheets is not HTML
+      if let Some(ref end) = raw_text_end {
+         if !(closing && &element == end) {
+            continue;
+         }
+         raw_text_end = None;
+      }
+      if closing {
+         match stack.iter().rposition(|(e, _)| e == &element) {
+            Some(pos) => {
+               for (e, l) in stack.drain(pos..).skip(1) {
+                  if !OPTIONAL_CLOSE.contains(&e.as_ref()) {
+                     problems.push(format!("unclosed tag <{}> (line {})", e, l));
+                  }
+               }
+            }
+            None => problems.push(format!(
+               "stray closing tag </{}> (line {})",
+               element, lineno
+            )),
+         }
+         continue;
+      }
+      if let Some((_, reason)) = STRIPPED.iter().find(|(e, _)| *e == element) {
+         problems.push(format!("<{}> tag (line {}), {}", element, lineno, reason));
+      }
+      match element.as_ref() {
+         "form" => problems.push(format!(
+            "<form> tag (line {}), most mail clients do not support forms",
+            lineno
+         )),
+         "base" => problems.push(format!(
+            "<base> tag (line {}), mail clients ignore it or resolve the links differently",
+            lineno
+         )),
+         "link" if attributes.to_lowercase().contains("stylesheet") => problems.push(format!(
+            "external style sheet (line {}), most mail clients do not load them",
+            lineno
+         )),
+         "meta" if attributes.to_lowercase().contains("refresh") => problems.push(format!(
+            "<meta> refresh (line {}), spam filters penalize it",
+            lineno
+         )),
+         _ => (),
+      }
+      if let Some(handler) = handler_re.captures(attributes) {
+         problems.push(format!(
+            "event handler {} (line {}), mail clients strip scripts",
+            &handler[1], lineno
+         ));
+      }
+      if js_re.is_match(attributes) {
+         problems.push(format!(
+            "javascript: URL (line {}), mail clients strip scripts",
+            lineno
+         ));
+      }
+      if element == "script" || element == "style" {
+         raw_text_end = Some(element.clone());
+      }
+      if !VOID.contains(&element.as_ref()) && !attributes.trim_end().ends_with('/') {
+         stack.push((element, lineno));
+      }
+   }
+   for (e, l) in stack {
+      if !OPTIONAL_CLOSE.contains(&e.as_ref()) {
+         problems.push(format!("unclosed tag <{}> (line {})", e, l));
+      }
+   }
+   problems
+}
+
+/// Strips scripts, frames and embedded objects as well as event handlers and `javascript:` URLs
+/// from the HTML text.
+pub fn sanitize(html: &str) -> String {
+   let mut result = html.to_string();
+   for (element, _) in STRIPPED.iter() {
+      let paired = Regex::new(&format!(r"(?is)<{0}\b[^>]*>.*?</{0}\s*>", element)).unwrap();
+      let single = Regex::new(&format!(r"(?i)</?{}\b[^>]*>", element)).unwrap();
+      result = paired.replace_all(&result, "").into_owned();
+      result = single.replace_all(&result, "").into_owned();
+   }
+   let handler_re = Regex::new(r#"(?i)\s+on[a-z]+\s*=\s*(?:"[^"]*"|'[^']*'|[^\s"'>]+)"#).unwrap();
+   let js_re =
+      Regex::new(r#"(?i)(\s(?:href|src|action)\s*=\s*)(?:"\s*javascript:[^"]*"|'\s*javascript:[^']*'|javascript:[^\s>]*)"#)
+         .unwrap();
+   let tag_re = Regex::new(r"<[a-zA-Z][^>]*>").unwrap();
+   tag_re
+      .replace_all(&result, |caps: &Captures| {
+         let tag = handler_re.replace_all(&caps[0], "");
+         js_re.replace_all(&tag, "${1}\"#\"").into_owned()
+      })
+      .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn check_happy_case() {
+      let html = "<html><body>\n<!-- <div> -->\n<p>Hi<br>there<img src=\"a.png\"/>\n<ul><li>one<li>two</ul>\n<style>p > a { color: red }</style></body></html>";
+      assert!(check(html).is_empty());
+   }
+
+   #[test]
+   fn check_unclosed_and_stray_tags() {
+      let html = "<div>\n<table><tr><td><span>x</td></tr></table>\n</b>";
+      assert_eq!(
+         vec![
+            "unclosed tag <span> (line 2)",
+            "stray closing tag </b> (line 3)",
+            "unclosed tag <div> (line 1)",
+         ],
+         check(html)
+      );
+   }
+
+   #[test]
+   fn check_hostile_constructs() {
+      let html = r#"<script>if (a < b) { alert("<div>") }</script>
+<a href="javascript:void(0)" onclick="go()">x</a>
+<form action="/x"></form><iframe src="x"></iframe>"#;
+      assert_eq!(
+         vec![
+            "<script> tag (line 1), mail clients strip scripts and spam filters penalize them",
+            "event handler onclick (line 2), mail clients strip scripts",
+            "javascript: URL (line 2), mail clients strip scripts",
+            "<form> tag (line 3), most mail clients do not support forms",
+            "<iframe> tag (line 3), mail clients do not show frames",
+         ],
+         check(html)
+      );
+   }
+
+   #[test]
+   fn sanitize_strips_dangerous_constructs() {
+      let html = r#"<p onload='x()'>Hi<script>alert(1)</script></p><a href="javascript:go()" title="x">a</a><embed src="y">"#;
+      let actual = sanitize(html);
+      assert_eq!(r##"<p>Hi</p><a href="#" title="x">a</a>"##, actual);
+      assert!(check(&actual).is_empty());
+   }
+}
@@ -0,0 +1,329 @@
+/// The `schema` module handles typed recipient data.
+///
+/// The `[schema]` section of a config file declares the types of recipient data keys, e.g.
+///
+/// ```ini
+/// [schema]
+/// DUE=date
+/// COUNT=number
+/// AMOUNT=currency:EUR
+/// ```
+///
+/// The values of typed keys are checked when the config is parsed (dates are written as
+/// `YYYY-MM-DD`, numbers and amounts as e.g. `-1234.5`) and formatted as per the campaign's
+/// *locale* when the templates are rendered.
+use chrono::NaiveDate;
+use regex::Regex;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The `DataType` enum lists the types a recipient data key may have.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DataType {
+   /// A calendar date
+   Date,
+   /// A decimal number
+   Number,
+   /// An amount of money in the currency with the given (ISO 4217) code
+   Currency(String),
+}
+
+impl DataType {
+   /// Parses a type declaration e.g. `date` or `currency:EUR`.
+   pub fn parse(text: &str) -> Result<DataType, String> {
+      let text = text.trim();
+      match text.split_once(':') {
+         Some(("currency", code)) => {
+            let code = code.trim().to_uppercase();
+            if code.len() != 3 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+               return Err(format!("invalid currency code: {}", code));
+            }
+            Ok(DataType::Currency(code))
+         }
+         None if text == "date" => Ok(DataType::Date),
+         None if text == "number" => Ok(DataType::Number),
+         _ => Err(format!("unknown type: {}", text)),
+      }
+   }
+
+   /// Makes sure the value is valid for the type.
+   pub fn check(&self, val: &str) -> Result<(), String> {
+      let valid = match self {
+         DataType::Date => NaiveDate::parse_from_str(val.trim(), "%Y-%m-%d").is_ok(),
+         DataType::Number | DataType::Currency(_) => split_number(val).is_some(),
+      };
+      if valid {
+         Ok(())
+      } else {
+         Err(format!("invalid {} ({})", self.name(), val))
+      }
+   }
+
+   /// Formats the value as per the locale, invalid values are returned as they are.
+   pub fn format(&self, val: &str, locale: &Locale) -> String {
+      let result = match self {
+         DataType::Date => NaiveDate::parse_from_str(val.trim(), "%Y-%m-%d")
+            .ok()
+            .map(|d| d.format(locale.date_format).to_string()),
+         DataType::Number => split_number(val)
+            .map(|(negative, int, frac)| format_number(locale, negative, &int, &frac)),
+         DataType::Currency(code) => split_number(val)
+            .map(|(negative, int, frac)| format_amount(locale, code, negative, &int, &frac)),
+      };
+      result.unwrap_or_else(|| val.to_string())
+   }
+
+   /// Returns the name of the type as used in error messages.
+   fn name(&self) -> &'static str {
+      match self {
+         DataType::Date => "date",
+         DataType::Number => "number",
+         DataType::Currency(_) => "amount",
+      }
+   }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The `Locale` struct holds the conventions for formatting typed recipient data.
+pub struct Locale {
+   /// The language e.g. `de`
+   pub language: &'static str,
+   /// The decimal separator
+   pub decimal: char,
+   /// The separator between groups of thousands
+   pub grouping: char,
+   /// The `strftime` format of dates
+   pub date_format: &'static str,
+   /// Whether the currency symbol precedes the amount
+   pub symbol_first: bool,
+}
+
+impl Default for Locale {
+   fn default() -> Self {
+      LOCALES[0].1
+   }
+}
+
+/// The supported locales, a locale that is not listed falls back to its language.
+const LOCALES: &[(&str, Locale)] = &[
+   ("en", locale("en", '.', ',', "%B %-d, %Y", true)),
+   ("en-gb", locale("en", '.', ',', "%-d %B %Y", true)),
+   ("de", locale("de", ',', '.', "%d.%m.%Y", false)),
+   ("de-ch", locale("de", '.', '\'', "%d.%m.%Y", true)),
+   ("es", locale("es", ',', '.', "%d/%m/%Y", false)),
+   ("fr", locale("fr", ',', '\u{202f}', "%d/%m/%Y", false)),
+   ("it", locale("it", ',', '.', "%d/%m/%Y", false)),
+   ("nl", locale("nl", ',', '.', "%d-%m-%Y", true)),
+   ("pt", locale("pt", ',', '.', "%d/%m/%Y", false)),
+];
+
+/// Makes the locale table readable.
+const fn locale(
+   language: &'static str,
+   decimal: char,
+   grouping: char,
+   date_format: &'static str,
+   symbol_first: bool,
+) -> Locale {
+   Locale {
+      language,
+      decimal,
+      grouping,
+      date_format,
+      symbol_first,
+   }
+}
+
+impl Locale {
+   /// Looks up a locale by its name e.g. `de`, `en-GB` or `fr_FR`.
+   pub fn parse(name: &str) -> Result<Locale, String> {
+      let name = name.trim().replace('_', "-").to_lowercase();
+      let language = name.split('-').next().unwrap_or_default();
+      LOCALES
+         .iter()
+         .find(|(n, _)| *n == name)
+         .or_else(|| LOCALES.iter().find(|(n, _)| *n == language))
+         .map(|(_, l)| *l)
+         .ok_or_else(|| format!("unknown locale: {}", name))
+   }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Locale {
+   /// Serializes a locale as its name e.g. `de-ch`.
+   fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+      let name = LOCALES
+         .iter()
+         .find(|(_, l)| l == self)
+         .map_or(self.language, |(n, _)| *n);
+      serializer.serialize_str(name)
+   }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Locale {
+   /// Deserializes a locale from its name.
+   fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Locale, D::Error> {
+      let name = <String as serde::Deserialize>::deserialize(deserializer)?;
+      Locale::parse(&name).map_err(serde::de::Error::custom)
+   }
+}
+
+/// Splits a number e.g. `-1234.5` into its sign, integral and fractional digits.
+fn split_number(val: &str) -> Option<(bool, String, String)> {
+   let re = Regex::new(r"^([-+]?)(\d+)(?:\.(\d+))?$").unwrap();
+   let caps = re.captures(val.trim())?;
+   let frac = caps.get(3).map(|m| m.as_str()).unwrap_or_default();
+   Some((&caps[1] == "-", caps[2].to_string(), frac.to_string()))
+}
+
+/// Formats a number with the separators of the locale, the fractional digits are kept as given.
+fn format_number(locale: &Locale, negative: bool, int: &str, frac: &str) -> String {
+   let int = int.trim_start_matches('0');
+   let int = if int.is_empty() { "0" } else { int };
+   let mut result = String::new();
+   if negative {
+      result.push('-');
+   }
+   for (i, c) in int.chars().enumerate() {
+      if i > 0 && (int.len() - i) % 3 == 0 {
+         result.push(locale.grouping);
+      }
+      result.push(c);
+   }
+   if !frac.is_empty() {
+      result.push(locale.decimal);
+      result.push_str(frac);
+   }
+   result
+}
+
+/// Formats an amount of money, rounded to the minor unit of the currency and with its symbol.
+fn format_amount(locale: &Locale, code: &str, negative: bool, int: &str, frac: &str) -> String {
+   let (symbol, digits) = match code {
+      "EUR" => ("€", 2),
+      "GBP" => ("£", 2),
+      "JPY" => ("¥", 0),
+      "USD" => ("$", 2),
+      _ => (code, 2),
+   };
+   let (int, frac) = round(int, frac, digits);
+   let number = format_number(locale, false, &int, &frac);
+   // codes are set apart from the number, symbols only if they follow it
+   let is_code = symbol.chars().all(|c| c.is_ascii_alphabetic());
+   let amount = match (locale.symbol_first, is_code) {
+      (true, false) => format!("{}{}", symbol, number),
+      (true, true) => format!("{} {}", symbol, number),
+      (false, _) => format!("{} {}", number, symbol),
+   };
+   if negative {
+      format!("-{}", amount)
+   } else {
+      amount
+   }
+}
+
+/// Rounds a number (given as its integral and fractional digits) to the given number of
+/// fractional digits, half away from zero.
+fn round(int: &str, frac: &str, digits: usize) -> (String, String) {
+   let mut padded: Vec<u8> = int.bytes().chain(frac.bytes()).collect();
+   padded.extend(std::iter::repeat_n(
+      b'0',
+      (digits + 1).saturating_sub(frac.len()),
+   ));
+   let len = int.len() + digits;
+   let round_up = padded[len] >= b'5';
+   let mut kept: Vec<u8> = padded[..len].to_vec();
+   if round_up {
+      let mut i = kept.len();
+      loop {
+         if i == 0 {
+            kept.insert(0, b'1');
+            break;
+         }
+         i -= 1;
+         if kept[i] == b'9' {
+            kept[i] = b'0';
+         } else {
+            kept[i] += 1;
+            break;
+         }
+      }
+   }
+   let split = kept.len() - digits;
+   let text = String::from_utf8(kept).unwrap();
+   (text[..split].to_string(), text[split..].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn parse_types() {
+      assert_eq!(Ok(DataType::Date), DataType::parse("date"));
+      assert_eq!(Ok(DataType::Number), DataType::parse(" number "));
+      assert_eq!(
+         Ok(DataType::Currency(String::from("EUR"))),
+         DataType::parse("currency:eur")
+      );
+      assert_eq!(
+         Err(String::from("invalid currency code: EURO")),
+         DataType::parse("currency:EURO")
+      );
+      assert_eq!(
+         Err(String::from("unknown type: datetime")),
+         DataType::parse("datetime")
+      );
+   }
+
+   #[test]
+   fn check_values() {
+      assert_eq!(Ok(()), DataType::Date.check("2024-02-29"));
+      assert_eq!(
+         Err(String::from("invalid date (2023-02-29)")),
+         DataType::Date.check("2023-02-29")
+      );
+      assert_eq!(Ok(()), DataType::Number.check("-1234.5"));
+      assert_eq!(
+         Err(String::from("invalid number (1,5)")),
+         DataType::Number.check("1,5")
+      );
+      assert_eq!(
+         Err(String::from("invalid amount (12 EUR)")),
+         DataType::Currency(String::from("EUR")).check("12 EUR")
+      );
+   }
+
+   #[test]
+   fn parse_locales() {
+      assert_eq!(Ok(Locale::default()), Locale::parse("en-US"));
+      assert_eq!(Locale::parse("de"), Locale::parse("de_AT"));
+      assert_eq!(',', Locale::parse("fr-FR").unwrap().decimal);
+      assert_eq!(Err(String::from("unknown locale: xx")), Locale::parse("xx"));
+   }
+
+   #[test]
+   fn format_values() {
+      let en = Locale::default();
+      let de = Locale::parse("de").unwrap();
+      let ch = Locale::parse("de-CH").unwrap();
+      assert_eq!("May 1, 2024", DataType::Date.format("2024-05-01", &en));
+      assert_eq!("01.05.2024", DataType::Date.format("2024-05-01", &de));
+      assert_eq!(
+         "-1,234,567.891",
+         DataType::Number.format("-1234567.891", &en)
+      );
+      assert_eq!("1.234.567,891", DataType::Number.format("1234567.891", &de));
+      assert_eq!("0", DataType::Number.format("000", &de));
+      let eur = DataType::Currency(String::from("EUR"));
+      assert_eq!("€1,234.50", eur.format("1234.5", &en));
+      assert_eq!("1.234,50 €", eur.format("1234.5", &de));
+      assert_eq!("-€1,000.00", eur.format("-999.995", &en));
+      let chf = DataType::Currency(String::from("CHF"));
+      assert_eq!("CHF 1'234.57", chf.format("1234.567", &ch));
+      let jpy = DataType::Currency(String::from("JPY"));
+      assert_eq!("¥1,235", jpy.format("1234.5", &en));
+      // invalid values are left alone
+      assert_eq!("soon", DataType::Date.format("soon", &en));
+   }
+}
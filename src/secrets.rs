@@ -0,0 +1,42 @@
+/// The `secrets` module keeps secrets like SMTP passwords in the system keyring (the Secret
+/// Service on Linux, the Keychain on macOS, the Credential Manager on Windows) so that they need
+/// not appear in campaign files.
+use keyring::{Entry, Error};
+
+/// The service the secrets are stored under.
+const SERVICE: &str = "rmt";
+
+/// Returns the keyring entry with the given name.
+fn entry(name: &str) -> Result<Entry, String> {
+   if name.trim().is_empty() {
+      return Err(String::from("empty keyring entry name"));
+   }
+   Entry::new(SERVICE, name.trim())
+      .map_err(|e| format!("cannot access the keyring entry {} ({})", name, e))
+}
+
+/// Stores the secret in the keyring entry with the given name, replacing the previous one.
+pub fn set(name: &str, secret: &str) -> Result<(), String> {
+   entry(name)?
+      .set_password(secret)
+      .map_err(|e| format!("cannot store the keyring entry {} ({})", name, e))
+}
+
+/// Reads the secret from the keyring entry with the given name.
+pub fn get(name: &str) -> Result<String, String> {
+   entry(name)?.get_password().map_err(|e| match e {
+      Error::NoEntry => format!(
+         "no keyring entry named {0}, store the secret with `rmt auth set {0}`",
+         name
+      ),
+      e => format!("cannot read the keyring entry {} ({})", name, e),
+   })
+}
+
+/// Removes the keyring entry with the given name.
+pub fn delete(name: &str) -> Result<(), String> {
+   entry(name)?.delete_credential().map_err(|e| match e {
+      Error::NoEntry => format!("no keyring entry named {}", name),
+      e => format!("cannot remove the keyring entry {} ({})", name, e),
+   })
+}
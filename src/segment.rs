@@ -0,0 +1,195 @@
+/// The `segment` module selects recipients by their tags.
+///
+/// Recipients are tagged with a `tags` datum e.g. `jd@example.com=John Doe|tags:-vip,emea` and
+/// a segment is an expression over the tags e.g. `vip`, `emea AND NOT vip` or
+/// `(emea OR apac) AND vip`. `NOT` binds tighter than `AND` which binds tighter than `OR`, the
+/// operators and tags are case-insensitive.
+use regex::Regex;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The `Segment` enum is a parsed segment expression.
+pub enum Segment {
+   /// The recipients with the given tag
+   Tag(String),
+   /// The recipients not in the segment
+   Not(Box<Segment>),
+   /// The recipients in both segments
+   And(Box<Segment>, Box<Segment>),
+   /// The recipients in either segment
+   Or(Box<Segment>, Box<Segment>),
+}
+
+/// Checks whether the text is a valid tag name: letters, digits, `_` and `-`.
+pub fn check_tag(tag: &str) -> bool {
+   let re = Regex::new(r"^[\w-]+$").unwrap();
+   re.is_match(tag)
+}
+
+impl Segment {
+   /// Parses a segment expression.
+   pub fn parse(text: &str) -> Result<Segment, String> {
+      let re = Regex::new(r"\(|\)|[^\s()]+").unwrap();
+      let tokens: Vec<&str> = re.find_iter(text).map(|m| m.as_str()).collect();
+      let mut parser = Parser { tokens, pos: 0 };
+      parser
+         .or()
+         .and_then(|result| match parser.next() {
+            None => Ok(result),
+            Some(token) => Err(format!("unexpected {}", token)),
+         })
+         .map_err(|e| format!("invalid segment: {} ({})", text.trim(), e))
+   }
+
+   /// Checks whether a recipient with the given tags is in the segment.
+   pub fn matches(&self, tags: &[String]) -> bool {
+      match self {
+         Segment::Tag(tag) => tags.iter().any(|t| t.eq_ignore_ascii_case(tag)),
+         Segment::Not(s) => !s.matches(tags),
+         Segment::And(a, b) => a.matches(tags) && b.matches(tags),
+         Segment::Or(a, b) => a.matches(tags) || b.matches(tags),
+      }
+   }
+}
+
+/// A recursive descent parser for segment expressions.
+struct Parser<'a> {
+   tokens: Vec<&'a str>,
+   pos: usize,
+}
+
+impl<'a> Parser<'a> {
+   /// Returns the next token, if any, and moves past it.
+   fn next(&mut self) -> Option<&'a str> {
+      let result = self.tokens.get(self.pos).copied();
+      self.pos += 1;
+      result
+   }
+
+   /// Moves past the next token if it is the given operator.
+   fn accept(&mut self, operator: &str) -> bool {
+      match self.tokens.get(self.pos) {
+         Some(token) if token.eq_ignore_ascii_case(operator) => {
+            self.pos += 1;
+            true
+         }
+         _ => false,
+      }
+   }
+
+   /// Parses an `OR` expression.
+   fn or(&mut self) -> Result<Segment, String> {
+      let mut result = self.and()?;
+      while self.accept("OR") {
+         result = Segment::Or(Box::new(result), Box::new(self.and()?));
+      }
+      Ok(result)
+   }
+
+   /// Parses an `AND` expression.
+   fn and(&mut self) -> Result<Segment, String> {
+      let mut result = self.not()?;
+      while self.accept("AND") {
+         result = Segment::And(Box::new(result), Box::new(self.not()?));
+      }
+      Ok(result)
+   }
+
+   /// Parses a `NOT` expression, a tag or an expression in parentheses.
+   fn not(&mut self) -> Result<Segment, String> {
+      if self.accept("NOT") {
+         return Ok(Segment::Not(Box::new(self.not()?)));
+      }
+      match self.next() {
+         Some("(") => {
+            let result = self.or()?;
+            match self.next() {
+               Some(")") => Ok(result),
+               _ => Err(String::from("missing ')'")),
+            }
+         }
+         Some(token)
+            if check_tag(token)
+               && !["and", "or", "not"].contains(&token.to_lowercase().as_ref()) =>
+         {
+            Ok(Segment::Tag(token.to_string()))
+         }
+         Some(token) => Err(format!("unexpected {}", token)),
+         None => Err(String::from("unexpected end")),
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn tag(name: &str) -> Box<Segment> {
+      Box::new(Segment::Tag(String::from(name)))
+   }
+
+   fn tags(list: &[&str]) -> Vec<String> {
+      list.iter().map(|t| t.to_string()).collect()
+   }
+
+   #[test]
+   fn parse_happy_case() {
+      assert_eq!(Ok(*tag("vip")), Segment::parse("vip"));
+      assert_eq!(
+         Ok(Segment::And(
+            tag("emea"),
+            Box::new(Segment::Not(tag("vip")))
+         )),
+         Segment::parse("emea AND NOT vip")
+      );
+      // AND binds tighter than OR
+      assert_eq!(
+         Ok(Segment::Or(
+            tag("a"),
+            Box::new(Segment::And(tag("b"), tag("c")))
+         )),
+         Segment::parse("a or b and c")
+      );
+      assert_eq!(
+         Ok(Segment::And(
+            Box::new(Segment::Or(tag("a"), tag("b"))),
+            tag("c")
+         )),
+         Segment::parse("(a OR b)AND c")
+      );
+   }
+
+   #[test]
+   fn parse_with_errors() {
+      assert_eq!(
+         Err(String::from("invalid segment:  (unexpected end)")),
+         Segment::parse(" ")
+      );
+      assert_eq!(
+         Err(String::from("invalid segment: vip AND (unexpected end)")),
+         Segment::parse("vip AND")
+      );
+      assert_eq!(
+         Err(String::from("invalid segment: vip emea (unexpected emea)")),
+         Segment::parse("vip emea")
+      );
+      assert_eq!(
+         Err(String::from("invalid segment: (vip (missing ')')")),
+         Segment::parse("(vip")
+      );
+      assert_eq!(
+         Err(String::from("invalid segment: v!p (unexpected v!p)")),
+         Segment::parse("v!p")
+      );
+   }
+
+   #[test]
+   fn matches() {
+      let segment = Segment::parse("emea AND NOT vip").unwrap();
+      assert!(segment.matches(&tags(&["EMEA"])));
+      assert!(!segment.matches(&tags(&["emea", "vip"])));
+      assert!(!segment.matches(&tags(&[])));
+      let segment = Segment::parse("NOT (emea OR apac)").unwrap();
+      assert!(segment.matches(&tags(&["vip"])));
+      assert!(!segment.matches(&tags(&["apac"])));
+   }
+}
@@ -0,0 +1,1068 @@
+/// The `server` module implements `rmt serve`, a small REST API that lets other tools upload
+/// campaigns, validate and send them, follow the progress of a send and fetch the reports.
+///
+/// The API speaks JSON over HTTP/1.1:
+///
+/// - `POST /campaigns` uploads a campaign: `{"config": "..", "template": "..", "html": "..",
+///   "files": {"name": "base64 data"}}`
+/// - `GET /campaigns` lists the campaigns uploaded
+/// - `GET /campaigns/{id}` tells the state of a campaign and how far its send got
+/// - `POST /campaigns/{id}/validate` checks the campaign and lists the problems found
+/// - `POST /campaigns/{id}/send` starts sending the campaign, `{"dry_run": true}` only composes
+///   the emails
+/// - `GET /campaigns/{id}/report` returns the report of the last send
+///
+/// The campaigns are kept in a directory, one subdirectory per upload.
+///
+/// Every request must carry the bearer token and a `Host` header naming the server, requests with
+/// a body must be `application/json`. This keeps web pages the operator visits from driving the
+/// API. The uploaded campaigns cannot run commands or read files outside their directory, see
+/// `confine`.
+use crate::campaign::{self, Preparation};
+use crate::config::{self, Config};
+use crate::events::{self, Event, Observer};
+use crate::template::{self, Engine, Template};
+use crate::transport::{self, Delivery, Transport};
+use crate::{audit, history, ledger, message, report, syslog};
+use base64::Engine as _;
+use log::{debug, info, warn};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The largest request body accepted.
+const MAX_BODY: usize = 32 * 1024 * 1024;
+
+/// The files every campaign directory holds, the uploaded files must be named differently.
+const CAMPAIGN_FILES: &[&str] = &[
+   "campaign.ini",
+   "template.eml",
+   "template.html",
+   "report.json",
+];
+
+/// The settings of the *general* section that name files.
+const PATH_KEYS: &[&str] = &[
+   "attachments",
+   "audit_log",
+   "inline_images",
+   "recipients_csv",
+   "signature_file",
+];
+
+/// The settings uploaded campaigns must not have: they run commands or use the configuration and
+/// the secrets of the user running the server.
+const FORBIDDEN_KEYS: &[&str] = &["attachment_cmd", "include", "keyring", "profile"];
+
+/// The `Request` struct holds the parts of an HTTP request the API looks at.
+#[derive(Debug, Default)]
+pub struct Request<'a> {
+   pub method: &'a str,
+   pub path: &'a str,
+   /// The value of the `Authorization` header, if any
+   pub authorization: Option<&'a str>,
+   /// The value of the `Content-Type` header, if any
+   pub content_type: Option<&'a str>,
+   /// The value of the `Host` header, if any
+   pub host: Option<&'a str>,
+   pub body: &'a [u8],
+}
+
+/// Sets up the transports for sending a campaign.
+pub type Connect = dyn Fn(&mut Config) -> Result<Vec<Box<dyn Transport>>, String> + Send + Sync;
+
+#[derive(Debug, Clone, Default)]
+/// The `Job` struct holds the progress of a campaign send.
+struct Job {
+   /// One of `sending`, `finished` or `failed`
+   state: &'static str,
+   /// The number of recipients to send to
+   total: usize,
+   sent: usize,
+   failed: usize,
+   /// Why the send could not be carried out
+   error: Option<String>,
+}
+
+/// The `Server` holds the campaigns uploaded and the sends in progress.
+pub struct Server {
+   /// The directory the campaigns are kept in
+   dir: PathBuf,
+   /// The bearer token the clients must present
+   token: String,
+   /// The values of the `Host` header accepted e.g. `localhost:8025`
+   hosts: Vec<String>,
+   connect: Box<Connect>,
+   /// Whether the sends are recorded in the ledger and the campaign history
+   record: bool,
+   jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl Server {
+   /// Creates a server keeping the campaigns in the given directory (it is created if needed),
+   /// the clients must present the bearer `token`. `connect` sets up the transports for a send.
+   pub fn new(dir: &Path, token: &str, connect: Box<Connect>) -> Result<Server, String> {
+      if token.trim().is_empty() {
+         return Err(String::from(
+            "an API token is required, the API would be open to everybody who can connect",
+         ));
+      }
+      fs::create_dir_all(dir)
+         .map_err(|e| format!("cannot create directory {} ({})", dir.display(), e))?;
+      Ok(Server {
+         dir: dir.to_path_buf(),
+         token: token.to_string(),
+         hosts: vec![],
+         connect,
+         record: true,
+         jobs: Mutex::new(HashMap::new()),
+      })
+   }
+
+   /// Accepts requests with the given `Host` header (e.g. `mail.example.com:8025`), `serve` adds
+   /// the address listened on and its loopback names.
+   pub fn allow_host(mut self, host: &str) -> Server {
+      self.hosts.push(host.trim().to_lowercase());
+      self
+   }
+
+   /// Keeps the sends out of the ledger and the campaign history.
+   pub fn unrecorded(mut self) -> Server {
+      self.record = false;
+      self
+   }
+
+   /// Answers a request, returns the HTTP status and the JSON body of the response.
+   pub fn handle(self: &Arc<Self>, request: &Request) -> (&'static str, Value) {
+      let Request {
+         method, path, body, ..
+      } = *request;
+      let host = request.host.map(|h| h.trim().to_lowercase());
+      if !host.is_some_and(|h| self.hosts.contains(&h)) {
+         return error("403 Forbidden", "missing or unknown Host header");
+      }
+      let token = request
+         .authorization
+         .and_then(|a| a.strip_prefix("Bearer "));
+      if !token.is_some_and(|t| same_secret(t.as_bytes(), self.token.as_bytes())) {
+         return error("401 Unauthorized", "missing or invalid bearer token");
+      }
+      let json = request
+         .content_type
+         .and_then(|t| t.split(';').next())
+         .is_some_and(|t| t.trim().eq_ignore_ascii_case("application/json"));
+      if method == "POST" && !json {
+         return error(
+            "415 Unsupported Media Type",
+            "the request body must be application/json",
+         );
+      }
+      let parts: Vec<&str> = path.trim_end_matches('/').split('/').skip(1).collect();
+      let id = match parts.get(1) {
+         Some(id) if !self.dir.join(id).is_dir() || !is_id(id) => {
+            return error("404 Not Found", &format!("no campaign {}", id))
+         }
+         Some(id) => *id,
+         None => "",
+      };
+      match (method, parts.as_slice()) {
+         ("POST", ["campaigns"]) => match self.upload(body) {
+            Ok(result) => ("201 Created", result),
+            Err(err) => error("400 Bad Request", &err),
+         },
+         ("GET", ["campaigns"]) => ("200 OK", self.list()),
+         ("GET", ["campaigns", _]) => ("200 OK", self.status(id)),
+         ("POST", ["campaigns", _, "validate"]) => match self.validate(id) {
+            Ok(result) => ("200 OK", result),
+            Err(err) => error("400 Bad Request", &err),
+         },
+         ("POST", ["campaigns", _, "send"]) => match parse_body(body) {
+            Ok(options) => self.start(id, options["dry_run"].as_bool().unwrap_or(false)),
+            Err(err) => error("400 Bad Request", &err),
+         },
+         ("GET", ["campaigns", _, "report"]) => {
+            match history::read(&self.dir.join(id).join("report.json")) {
+               Ok(report) => ("200 OK", report),
+               Err(_) => error(
+                  "404 Not Found",
+                  &format!("campaign {} was not sent yet", id),
+               ),
+            }
+         }
+         (_, ["campaigns"]) | (_, ["campaigns", _]) | (_, ["campaigns", _, _]) => {
+            error("405 Method Not Allowed", "method not allowed")
+         }
+         _ => error("404 Not Found", "not found"),
+      }
+   }
+
+   /// Stores an uploaded campaign, the config must be valid.
+   fn upload(&self, body: &[u8]) -> Result<Value, String> {
+      let upload = parse_body(body)?;
+      let text = |key: &str| match upload[key] {
+         Value::String(ref s) => Ok(Some(s.clone())),
+         Value::Null => Ok(None),
+         _ => Err(format!("*{}* must be a string", key)),
+      };
+      let (config, template, html) = (text("config")?, text("template")?, text("html")?);
+      let (config, template) = match (config, template) {
+         (Some(c), Some(t)) => (c, t),
+         _ => return Err(String::from("the *config* and the *template* are required")),
+      };
+      let mut files = vec![];
+      match upload["files"] {
+         Value::Object(ref map) => {
+            for (name, data) in map.iter() {
+               if !is_file_name(name) || CAMPAIGN_FILES.contains(&name.as_str()) {
+                  return Err(format!("invalid file name: {}", name));
+               }
+               let data = data
+                  .as_str()
+                  .and_then(|d| base64::engine::general_purpose::STANDARD.decode(d).ok())
+                  .ok_or_else(|| format!("the file {} must be base64 encoded", name))?;
+               files.push((name.clone(), data));
+            }
+         }
+         Value::Null => (),
+         _ => return Err(String::from("*files* must be an object")),
+      }
+      // the lock keeps concurrent uploads from picking the same id
+      let _jobs = self
+         .jobs
+         .lock()
+         .map_err(|_| "the server state is poisoned")?;
+      let next = fs::read_dir(&self.dir)
+         .map_err(|e| format!("cannot read directory {} ({})", self.dir.display(), e))?
+         .filter_map(|e| e.ok()?.file_name().to_str()?.parse::<u64>().ok())
+         .max()
+         .unwrap_or(0)
+         + 1;
+      let id = next.to_string();
+      let dir = self.dir.join(&id);
+      let write = |name: &str, content: &[u8]| {
+         fs::write(dir.join(name), content)
+            .map_err(|e| format!("cannot write {} ({})", dir.join(name).display(), e))
+      };
+      let stored = fs::create_dir(&dir)
+         .map_err(|e| format!("cannot create directory {} ({})", dir.display(), e))
+         .and_then(|_| confine(&dir, &config, html.as_deref()))
+         .and_then(|(config, html)| {
+            write("campaign.ini", config.as_bytes())?;
+            write("template.eml", template.as_bytes())?;
+            html.map_or(Ok(()), |html| write("template.html", html.as_bytes()))
+         })
+         .and_then(|_| files.iter().try_for_each(|(name, data)| write(name, data)))
+         .and_then(|_| load_config(&dir, true));
+      match stored {
+         Ok(cfg) => {
+            info!("campaign {} uploaded as {}", cfg.campaign_id, id);
+            Ok(json!({
+               "id": id,
+               "campaign_id": cfg.campaign_id,
+               "recipients": cfg.recipients.len() + cfg.quarantine.len(),
+            }))
+         }
+         Err(err) => {
+            let _ = fs::remove_dir_all(&dir);
+            Err(err)
+         }
+      }
+   }
+
+   /// Lists the campaigns uploaded with their states.
+   fn list(&self) -> Value {
+      let mut ids: Vec<u64> = fs::read_dir(&self.dir)
+         .map(|entries| {
+            entries
+               .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+               .collect()
+         })
+         .unwrap_or_default();
+      ids.sort_unstable();
+      Value::Array(ids.iter().map(|id| self.status(&id.to_string())).collect())
+   }
+
+   /// Returns the state of a campaign: `uploaded`, `sending`, `finished` or `failed`.
+   fn status(&self, id: &str) -> Value {
+      if let Some(job) = self.jobs.lock().ok().and_then(|jobs| jobs.get(id).cloned()) {
+         return json!({
+            "id": id,
+            "state": job.state,
+            "total": job.total,
+            "sent": job.sent,
+            "failed": job.failed,
+            "error": job.error,
+         });
+      }
+      // the sends of an earlier server run left their reports
+      match history::read(&self.dir.join(id).join("report.json")) {
+         Ok(report) => json!({
+            "id": id,
+            "state": "finished",
+            "total": report["deliveries"].as_array().map_or(0, |d| d.len()),
+            "sent": report["sent"],
+            "failed": report["failed"],
+            "error": null,
+         }),
+         Err(_) => json!({"id": id, "state": "uploaded", "error": null}),
+      }
+   }
+
+   /// Checks the config, the templates and the recipient data of a campaign.
+   fn validate(&self, id: &str) -> Result<Value, String> {
+      let dir = self.dir.join(id);
+      let mut cfg = load_config(&dir, true)?;
+      let (tmpl, html) = load_templates(&dir, cfg.engine)?;
+      let checks = campaign::prepare(&mut cfg, &tmpl, html.as_ref(), &Preparation::default())?;
+      let problems: Vec<String> = checks.problems.iter().map(|p| p.to_string()).collect();
+      let mut warnings: Vec<String> = checks.warnings.iter().map(|w| w.to_string()).collect();
+      if cfg.smtp.is_none() {
+         warnings.push(String::from(
+            "no *smtp* section, only dry runs are possible",
+         ));
+      }
+      Ok(json!({
+         "valid": problems.is_empty(),
+         "recipients": cfg.recipient_count(),
+         "problems": problems,
+         "warnings": warnings,
+      }))
+   }
+
+   /// Starts sending a campaign in the background unless a send is in progress already.
+   fn start(self: &Arc<Self>, id: &str, dry_run: bool) -> (&'static str, Value) {
+      match self.jobs.lock() {
+         Ok(mut jobs) => {
+            if jobs.get(id).is_some_and(|j| j.state == "sending") {
+               return error("409 Conflict", &format!("campaign {} is being sent", id));
+            }
+            jobs.insert(
+               id.to_string(),
+               Job {
+                  state: "sending",
+                  ..Default::default()
+               },
+            );
+         }
+         Err(_) => return error("500 Internal Server Error", "the server state is poisoned"),
+      }
+      let (server, campaign) = (self.clone(), id.to_string());
+      thread::spawn(move || {
+         let result = server.send(&campaign, dry_run);
+         server.update(&campaign, |job| match result {
+            Ok(()) => job.state = "finished",
+            Err(err) => {
+               warn!("sending campaign {} failed ({})", campaign, err);
+               job.state = "failed";
+               job.error = Some(err);
+            }
+         });
+      });
+      ("202 Accepted", self.status(id))
+   }
+
+   /// Sends a campaign and stores the report.
+   fn send(self: &Arc<Self>, id: &str, dry_run: bool) -> Result<(), String> {
+      let dir = self.dir.join(id);
+      let mut cfg = load_config(&dir, false)?;
+      let (tmpl, html) = load_templates(&dir, cfg.engine)?;
+      if let (true, false, Some(path)) = (self.record, dry_run, ledger::path()) {
+         campaign::skip_delivered(&mut cfg, &ledger::Ledger::open(&path)?);
+      }
+      let preparation = Preparation {
+         attachments: true,
+         ..Default::default()
+      };
+      campaign::prepare(&mut cfg, &tmpl, html.as_ref(), &preparation)?
+         .result()
+         .map_err(|problems| problems.join(", "))?;
+      let total = cfg.recipient_count();
+      self.update(id, |job| job.total = total);
+      let started_at = events::now();
+      let deliveries = if dry_run {
+         let deliveries: Vec<Delivery> = cfg
+            .recipients
+            .iter()
+            .map(|rcp| {
+               let msg = message::compose(&cfg, &tmpl, html.as_deref(), rcp);
+               Delivery {
+                  recipient: rcp.email.clone(),
+                  message_id: msg.header("Message-ID").unwrap_or_default().to_string(),
+                  result: Ok(()),
+                  timestamp: events::now(),
+                  variant: cfg.variant(&rcp.email).map(|v| v.name.clone()),
+               }
+            })
+            .collect();
+         self.update(id, |job| job.sent = deliveries.len());
+         deliveries
+      } else {
+         let mut transports = (self.connect)(&mut cfg)?;
+         let description = transports
+            .first()
+            .map(|t| t.describe())
+            .ok_or("no transport to send with")?;
+         let mut observers = self.observers(&cfg, &description)?;
+         observers.push(Box::new(Tracker {
+            server: self.clone(),
+            id: id.to_string(),
+         }));
+         transport::deliver_concurrently(
+            &cfg,
+            &tmpl,
+            html.as_deref(),
+            transports
+               .iter_mut()
+               .map(|t| t.as_mut() as &mut dyn Transport)
+               .collect(),
+            &mut observers,
+            &AtomicBool::new(false),
+         )
+      };
+      let run = report::Run {
+         campaign_id: &cfg.campaign_id,
+         dry_run,
+         started_at,
+         finished_at: events::now(),
+         deliveries: &deliveries,
+         quarantined: &cfg.quarantine,
+         remaining: &[],
+      };
+      let mut document = run.to_json();
+      document["subject"] = cfg.gdata.subject.as_str().into();
+      document["config"] = dir.join("campaign.ini").display().to_string().into();
+      let path = dir.join("report.json");
+      fs::write(&path, format!("{:#}\n", document))
+         .map_err(|e| format!("cannot write report file {} ({})", path.display(), e))?;
+      if !dry_run && self.record {
+         match history::campaigns_dir()
+            .ok_or_else(|| String::from("cannot determine the data directory"))
+            .and_then(|dir| history::save(&dir, &document))
+         {
+            Ok(path) => info!("campaign record saved to {}", path.display()),
+            Err(err) => warn!("campaign record not saved ({})", err),
+         }
+      }
+      Ok(())
+   }
+
+   /// Sets up the observers of a send: the audit log, the webhook, the system log and the
+   /// ledger.
+   fn observers(&self, cfg: &Config, transport: &str) -> Result<Vec<Box<dyn Observer>>, String> {
+      let mut result: Vec<Box<dyn Observer>> = vec![];
+      if let Some(ref path) = cfg.audit_log {
+         result.push(Box::new(audit::Audit::open(path, transport)?));
+      }
+      if let Some(ref url) = cfg.webhook_url {
+         result.push(Box::new(events::Webhook::new(url)?.queue()?));
+      }
+      if let Some(logger) = syslog::EventLogger::for_log() {
+         result.push(Box::new(logger?));
+      }
+      if let (true, Some(path)) = (self.record, ledger::path()) {
+         result.push(Box::new(ledger::Ledger::open(&path)?));
+      }
+      Ok(result)
+   }
+
+   /// Changes the progress of the send of a campaign.
+   fn update<F: FnOnce(&mut Job)>(&self, id: &str, change: F) {
+      if let Ok(mut jobs) = self.jobs.lock() {
+         if let Some(job) = jobs.get_mut(id) {
+            change(job);
+         }
+      }
+   }
+}
+
+/// The `Tracker` observer counts the deliveries of a send for the progress queries.
+struct Tracker {
+   server: Arc<Server>,
+   id: String,
+}
+
+impl Observer for Tracker {
+   fn notify(&mut self, event: &Event) {
+      match event {
+         Event::MessageSent { .. } => self.server.update(&self.id, |job| job.sent += 1),
+         Event::MessageFailed { .. } => self.server.update(&self.id, |job| job.failed += 1),
+         _ => (),
+      }
+   }
+}
+
+/// Compares two secrets in constant time (for secrets of the same length), so that the time a
+/// comparison takes does not tell how much of a guessed token is right.
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+   a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Uploaded files must have plain names, they are kept in the campaign directory.
+fn is_file_name(name: &str) -> bool {
+   !name.is_empty()
+      && !name.starts_with('.')
+      && name
+         .chars()
+         .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+}
+
+/// Keeps an uploaded campaign to its directory: the settings that run commands or use the
+/// user's configuration and secrets (see `FORBIDDEN_KEYS`) and references to environment
+/// variables are refused, the files named by the config (attachments, CSV files, variant
+/// templates etc.) and the images of the HTML template must be plain file names (i.e. files
+/// uploaded with the campaign). Returns the config and the HTML template with the file names
+/// turned into paths in the campaign directory.
+///
+/// The credentials the *smtp* and *imap* sections lack are not looked up either, the loaded
+/// config is confined (see `Config::resolve_credentials`).
+fn confine(
+   dir: &Path,
+   config: &str,
+   html: Option<&str>,
+) -> Result<(String, Option<String>), String> {
+   let dir = dir
+      .canonicalize()
+      .map_err(|e| format!("cannot read directory {} ({})", dir.display(), e))?;
+   let path = |name: &str| -> Result<String, String> {
+      if is_file_name(name) {
+         Ok(dir.join(name).display().to_string())
+      } else {
+         Err(format!(
+            "{}: only the files uploaded with the campaign can be used",
+            name
+         ))
+      }
+   };
+   let mut result = vec![];
+   let mut section = String::new();
+   for line in config.lines() {
+      let trimmed = line.trim();
+      if trimmed.starts_with('[') && trimmed.ends_with(']') {
+         section = trimmed[1..trimmed.len() - 1].trim().to_lowercase();
+      }
+      let (key, value) = match trimmed.split_once('=') {
+         Some((key, value)) if !trimmed.starts_with(['#', ';']) => {
+            (key.trim().to_lowercase(), value.trim())
+         }
+         _ => {
+            result.push(line.to_string());
+            continue;
+         }
+      };
+      if section == "recipients" {
+         result.push(line.to_string());
+         continue;
+      }
+      if FORBIDDEN_KEYS.contains(&key.as_str()) {
+         return Err(format!("*{}* is not allowed in uploaded campaigns", key));
+      }
+      if value.contains("${") {
+         return Err(format!(
+            "*{}*: environment variables are not allowed in uploaded campaigns",
+            key
+         ));
+      }
+      let paths = (section == "general" && PATH_KEYS.contains(&key.as_str()))
+         || (section == "variants" && !key.ends_with(".weight"));
+      if paths {
+         let names: Vec<&str> = value
+            .split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .collect();
+         let paths = names
+            .into_iter()
+            .map(path)
+            .collect::<Result<Vec<String>, String>>()?;
+         result.push(format!("{}={}", key, paths.join(", ")));
+      } else {
+         result.push(line.to_string());
+      }
+   }
+   let html = match html {
+      Some(html) => {
+         let re = Regex::new(r"%_CID:([^%]+)%").expect("internal error, invalid regex");
+         let mut error = None;
+         let text = re.replace_all(html, |caps: &regex::Captures| match path(caps[1].trim()) {
+            Ok(path) => format!("%_CID:{}%", path),
+            Err(e) => {
+               error.get_or_insert(e);
+               String::new()
+            }
+         });
+         if let Some(error) = error {
+            return Err(error);
+         }
+         Some(text.into_owned())
+      }
+      None => None,
+   };
+   Ok((result.join("\n") + "\n", html))
+}
+
+/// Campaign ids are the numbers the uploads are given.
+fn is_id(id: &str) -> bool {
+   !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Parses the JSON body of a request, an empty body stands for an empty object.
+fn parse_body(body: &[u8]) -> Result<Value, String> {
+   if body.iter().all(|b| b.is_ascii_whitespace()) {
+      return Ok(json!({}));
+   }
+   match serde_json::from_slice(body) {
+      Ok(value @ Value::Object(_)) => Ok(value),
+      Ok(_) => Err(String::from("the request body must be a JSON object")),
+      Err(e) => Err(format!("invalid JSON in the request body ({})", e)),
+   }
+}
+
+/// Loads the config of a stored campaign, invalid recipients are quarantined if `lenient` is
+/// set.
+fn load_config(dir: &Path, lenient: bool) -> Result<Config, String> {
+   let path = dir.join("campaign.ini").display().to_string();
+   let (name, version) = (env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+   let mut cfg = if lenient {
+      config::instantiate_lenient(&[&path], None, name, version)?
+   } else {
+      config::instantiate(&[&path], None, name, version)?
+   };
+   cfg.confined = true;
+   if cfg.threading.follow_up_of.is_some() {
+      let dir = history::campaigns_dir().ok_or("cannot determine the data directory")?;
+      history::thread_under(&dir, &mut cfg)?;
+   }
+   Ok(cfg)
+}
+
+/// Loads the templates of a stored campaign, the HTML template is optional.
+fn load_templates(
+   dir: &Path,
+   engine: Engine,
+) -> Result<(Arc<Template>, Option<Arc<Template>>), String> {
+   let load = |name: &str| {
+      let path = dir.join(name);
+      template::instantiate(&path.display().to_string())
+         .map_err(|e| format!("cannot read template {} ({})", path.display(), e))?
+         .with_engine(engine)
+         .map(Arc::new)
+         .map_err(|e| format!("{}: {}", name, e))
+   };
+   let html = if dir.join("template.html").exists() {
+      Some(load("template.html")?)
+   } else {
+      None
+   };
+   Ok((load("template.eml")?, html))
+}
+
+/// Returns the error response with the given status.
+fn error(status: &'static str, message: &str) -> (&'static str, Value) {
+   (status, json!({ "error": message }))
+}
+
+/// Answers the requests on the given address in the background, returns the address listened
+/// on.
+pub fn serve(address: &str, mut server: Server) -> Result<SocketAddr, String> {
+   let listener =
+      TcpListener::bind(address).map_err(|e| format!("cannot listen on {} ({})", address, e))?;
+   let result = listener
+      .local_addr()
+      .map_err(|e| format!("cannot listen on {} ({})", address, e))?;
+   let port = result.port();
+   for host in [
+      address.to_string(),
+      result.to_string(),
+      format!("localhost:{}", port),
+      format!("127.0.0.1:{}", port),
+      format!("[::1]:{}", port),
+   ] {
+      server = server.allow_host(&host);
+   }
+   let server = Arc::new(server);
+   thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+         let server = server.clone();
+         thread::spawn(move || {
+            if let Err(err) = respond(stream, &server) {
+               debug!("serve: {}", err);
+            }
+         });
+      }
+   });
+   Ok(result)
+}
+
+/// Reads an HTTP request and sends the response.
+fn respond(stream: TcpStream, server: &Arc<Server>) -> Result<(), String> {
+   stream
+      .set_read_timeout(Some(Duration::from_secs(30)))
+      .map_err(|e| e.to_string())?;
+   let mut reader = BufReader::new(&stream);
+   let mut request = String::new();
+   reader
+      .read_line(&mut request)
+      .map_err(|e| format!("cannot read the request ({})", e))?;
+   let (mut length, mut authorization, mut content_type, mut host) = (0, None, None, None);
+   let mut line = String::new();
+   while reader.read_line(&mut line).map_err(|e| e.to_string())? > 2 {
+      if let Some((name, value)) = line.split_once(':') {
+         let value = value.trim();
+         match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+               length = value
+                  .parse()
+                  .map_err(|_| format!("invalid Content-Length: {}", value))?
+            }
+            "authorization" => authorization = Some(value.to_string()),
+            "content-type" => content_type = Some(value.to_string()),
+            "host" => host = Some(value.to_string()),
+            _ => (),
+         }
+      }
+      line.clear();
+   }
+   let mut fields = request.split_whitespace();
+   let (method, path) = (
+      fields.next().unwrap_or_default(),
+      fields.next().unwrap_or_default(),
+   );
+   let (status, body) = if length > MAX_BODY {
+      error("413 Payload Too Large", "the request body is too large")
+   } else {
+      let mut body = vec![0; length];
+      reader
+         .read_exact(&mut body)
+         .map_err(|e| format!("cannot read the request body ({})", e))?;
+      server.handle(&Request {
+         method,
+         path,
+         authorization: authorization.as_deref(),
+         content_type: content_type.as_deref(),
+         host: host.as_deref(),
+         body: &body,
+      })
+   };
+   let body = format!("{:#}\n", body);
+   let response = format!(
+      "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+       Connection: close\r\n\r\n{}",
+      status,
+      body.len(),
+      body
+   );
+   (&stream)
+      .write_all(response.as_bytes())
+      .map_err(|e| format!("cannot send the response ({})", e))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::credentials;
+   use crate::testing::MockTransport;
+   use tempfile::TempDir;
+
+   const CONFIG: &str = "[general]\n\
+      from = Frodo Baggins <fb@example.com>\n\
+      subject = Hello %_FN%\n\
+      [recipients]\n\
+      jd@example.com = John Doe\n\
+      mm@example.com = Mickey Mouse\n";
+
+   fn server(dir: &TempDir) -> Arc<Server> {
+      let connect = |_: &mut Config| {
+         Ok(vec![
+            Box::new(MockTransport::new().fail_for("mm@example.com")) as Box<dyn Transport>,
+         ])
+      };
+      Arc::new(
+         Server::new(dir.path(), "s3cr3t", Box::new(connect))
+            .expect("cannot create server")
+            .allow_host("localhost:8025")
+            .unrecorded(),
+      )
+   }
+
+   /// Sends an authorized request the way a client of the API does.
+   fn call(server: &Arc<Server>, method: &str, path: &str, body: &[u8]) -> (&'static str, Value) {
+      server.handle(&Request {
+         method,
+         path,
+         authorization: Some("Bearer s3cr3t"),
+         content_type: Some("application/json"),
+         host: Some("localhost:8025"),
+         body,
+      })
+   }
+
+   fn upload(server: &Arc<Server>, template: &str) -> String {
+      let body = json!({"config": CONFIG, "template": template}).to_string();
+      let (status, result) = call(server, "POST", "/campaigns", body.as_bytes());
+      assert_eq!("201 Created", status, "{}", result);
+      assert_eq!(2, result["recipients"]);
+      result["id"].as_str().unwrap().to_string()
+   }
+
+   /// Waits for the send of a campaign to end, returns its status.
+   fn wait(server: &Arc<Server>, id: &str) -> Value {
+      for _ in 0..100 {
+         let (_, status) = call(server, "GET", &format!("/campaigns/{}", id), b"");
+         if status["state"] != "sending" {
+            return status;
+         }
+         thread::sleep(Duration::from_millis(20));
+      }
+      panic!("the send did not end")
+   }
+
+   #[test]
+   fn upload_validate_send_report() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let server = server(&dir);
+      let id = upload(&server, "Hi %_FN%, greetings from %ORG%\n");
+      assert_eq!("1", id);
+      let (status, result) = call(&server, "POST", "/campaigns/1/validate", b"");
+      assert_eq!("200 OK", status);
+      assert_eq!(false, result["valid"]);
+      assert_eq!(2, result["problems"].as_array().unwrap().len());
+
+      let id = upload(&server, "Hi %_FN%\n");
+      assert_eq!("2", id);
+      let (_, result) = call(&server, "POST", "/campaigns/2/validate", b"");
+      assert_eq!(true, result["valid"], "{}", result);
+      let (status, _) = call(&server, "GET", "/campaigns/2/report", b"");
+      assert_eq!("404 Not Found", status);
+      let (status, result) = call(&server, "POST", "/campaigns/2/send", b"");
+      assert_eq!("202 Accepted", status, "{}", result);
+      let result = wait(&server, "2");
+      assert_eq!("finished", result["state"]);
+      assert_eq!(
+         json!([2, 1, 1]),
+         json!([result["total"], result["sent"], result["failed"]])
+      );
+      let (status, report) = call(&server, "GET", "/campaigns/2/report", b"");
+      assert_eq!("200 OK", status);
+      assert_eq!(false, report["dry_run"]);
+      assert_eq!("failed", report["deliveries"][1]["status"]);
+
+      let (_, list) = call(&server, "GET", "/campaigns", b"");
+      let states: Vec<&str> = list
+         .as_array()
+         .unwrap()
+         .iter()
+         .map(|s| s["state"].as_str().unwrap())
+         .collect();
+      assert_eq!(vec!["uploaded", "finished"], states);
+   }
+
+   #[test]
+   fn dry_run_and_failures() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let server = server(&dir);
+      let id = upload(&server, "Hi %_FN%, greetings from %ORG%\n");
+      call(
+         &server,
+         "POST",
+         "/campaigns/1/send",
+         br#"{"dry_run": true}"#,
+      );
+      let result = wait(&server, &id);
+      assert_eq!("failed", result["state"]);
+      assert!(result["error"].as_str().unwrap().contains("ORG"));
+
+      upload(&server, "Hi %_FN%\n");
+      call(
+         &server,
+         "POST",
+         "/campaigns/2/send",
+         br#"{"dry_run": true}"#,
+      );
+      assert_eq!("finished", wait(&server, "2")["state"]);
+      let (_, report) = call(&server, "GET", "/campaigns/2/report", b"");
+      assert_eq!(true, report["dry_run"]);
+      assert_eq!("composed", report["deliveries"][1]["status"]);
+   }
+
+   #[test]
+   fn bad_requests() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let server = server(&dir);
+      let post = |path: &str, body: &str| call(&server, "POST", path, body.as_bytes()).0;
+      assert_eq!("400 Bad Request", post("/campaigns", "{"));
+      assert_eq!("400 Bad Request", post("/campaigns", r#"{"config": "x"}"#));
+      assert_eq!(
+         "400 Bad Request",
+         post(
+            "/campaigns",
+            r#"{"config": "[general]\n", "template": "x"}"#
+         )
+      );
+      // the invalid upload was not kept
+      assert_eq!(json!([]), call(&server, "GET", "/campaigns", b"").1);
+      assert_eq!("404 Not Found", post("/campaigns/1/send", ""));
+      assert_eq!("404 Not Found", post("/campaigns/../send", ""));
+      assert_eq!("404 Not Found", post("/other", ""));
+      assert_eq!(
+         "405 Method Not Allowed",
+         call(&server, "DELETE", "/campaigns", b"").0
+      );
+   }
+
+   #[test]
+   fn confined_uploads() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let server = server(&dir);
+      let upload = |config: &str, html: Option<&str>| {
+         let mut body = json!({"config": config, "template": "Hi %_FN%\n",
+            "files": {"terms.txt": "VGVybXM=", "logo.png": "iVBO"}});
+         if let Some(html) = html {
+            body["html"] = json!(html);
+         }
+         call(&server, "POST", "/campaigns", body.to_string().as_bytes())
+      };
+      for setting in [
+         "attachment_cmd = cat /etc/passwd > %_EMAIL%.txt",
+         "include = /home/fb/.config/rmt/defaults.ini",
+         "attachments = /etc/passwd",
+         "attachments = terms.txt, ../../secret.pdf",
+         "signature_file = ~/.signature",
+         "recipients_csv = /var/lib/customers.csv",
+         "reply_to = ${HOME}@example.com",
+      ] {
+         let config = CONFIG.replacen("[recipients]", &format!("{}\n[recipients]", setting), 1);
+         let (status, result) = upload(&config, None);
+         assert_eq!("400 Bad Request", status, "{}: {}", setting, result);
+      }
+      let (status, result) = upload(CONFIG, Some("<img src=\"%_CID:/etc/passwd%\">"));
+      assert_eq!("400 Bad Request", status, "{}", result);
+      let body = json!({"config": CONFIG, "template": "Hi\n", "files": {"campaign.ini": ""}});
+      let (status, _) = call(&server, "POST", "/campaigns", body.to_string().as_bytes());
+      assert_eq!("400 Bad Request", status);
+      assert_eq!(json!([]), call(&server, "GET", "/campaigns", b"").1);
+
+      let config = CONFIG.replacen("[recipients]", "attachments = terms.txt\n[recipients]", 1);
+      let (status, result) = upload(&config, Some("<img src=\"%_CID:logo.png%\">"));
+      assert_eq!("201 Created", status, "{}", result);
+      let stored = dir.path().join("1");
+      let config = fs::read_to_string(stored.join("campaign.ini")).unwrap();
+      let terms = stored.canonicalize().unwrap().join("terms.txt");
+      assert!(
+         config.contains(&format!("attachments={}", terms.display())),
+         "{}",
+         config
+      );
+      assert_eq!("Terms", fs::read_to_string(terms).unwrap());
+   }
+
+   #[test]
+   fn uploads_do_not_use_the_server_credentials() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      // the credentials of the user running the server, e.g. from a .env file
+      std::env::set_var(credentials::USERNAME_VAR, "operator");
+      std::env::set_var(credentials::PASSWORD_VAR, "s3cr3t");
+      let used = Arc::new(Mutex::new(None));
+      let seen = used.clone();
+      let connect = move |cfg: &mut Config| {
+         cfg.resolve_credentials(true)?;
+         *seen.lock().unwrap() = cfg.smtp.as_ref().map(|s| s.username.clone());
+         Ok(vec![Box::new(MockTransport::new()) as Box<dyn Transport>])
+      };
+      let server = Arc::new(
+         Server::new(dir.path(), "s3cr3t", Box::new(connect))
+            .expect("cannot create server")
+            .allow_host("localhost:8025")
+            .unrecorded(),
+      );
+      let config = CONFIG.replacen(
+         "[recipients]",
+         "[smtp]\nhost = smtp.attacker.example\n[recipients]",
+         1,
+      );
+      let body = json!({"config": config, "template": "Hi %_FN%\n"}).to_string();
+      let (status, result) = call(&server, "POST", "/campaigns", body.as_bytes());
+      assert_eq!("201 Created", status, "{}", result);
+      call(&server, "POST", "/campaigns/1/send", b"");
+      let result = wait(&server, "1");
+      std::env::remove_var(credentials::USERNAME_VAR);
+      std::env::remove_var(credentials::PASSWORD_VAR);
+      assert_eq!("finished", result["state"], "{}", result);
+      assert_eq!(Some(None), *used.lock().unwrap());
+   }
+
+   #[test]
+   fn reject_foreign_requests() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      assert!(Server::new(dir.path(), " ", Box::new(|_: &mut Config| Ok(vec![]))).is_err());
+      let server = server(&dir);
+      let body = json!({"config": CONFIG, "template": "Hi\n"}).to_string();
+      let request = Request {
+         method: "POST",
+         path: "/campaigns",
+         authorization: Some("Bearer s3cr3t"),
+         content_type: Some("application/json; charset=utf-8"),
+         host: Some("localhost:8025"),
+         body: body.as_bytes(),
+      };
+      // a form or text/plain POST of a web page
+      let plain = Request {
+         content_type: Some("text/plain"),
+         ..request
+      };
+      assert_eq!("415 Unsupported Media Type", server.handle(&plain).0);
+      // DNS rebinding
+      let rebound = Request {
+         host: Some("evil.example.com:8025"),
+         ..request
+      };
+      assert_eq!("403 Forbidden", server.handle(&rebound).0);
+      let guessed = Request {
+         authorization: Some("Bearer s3cr3u"),
+         ..request
+      };
+      assert_eq!("401 Unauthorized", server.handle(&guessed).0);
+      assert_eq!("201 Created", server.handle(&request).0);
+   }
+
+   #[test]
+   fn serve_with_token() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let server =
+         Server::new(dir.path(), "s3cr3t", Box::new(|_: &mut Config| Ok(vec![]))).unwrap();
+      let address = serve("127.0.0.1:0", server).expect("cannot serve");
+      let request = |headers: &str, body: &str| {
+         let mut stream = TcpStream::connect(address).expect("cannot connect");
+         write!(
+            stream,
+            "POST /campaigns HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}",
+            headers,
+            body.len(),
+            body
+         )
+         .unwrap();
+         let mut response = String::new();
+         stream.read_to_string(&mut response).unwrap();
+         response
+      };
+      let body = json!({"config": CONFIG, "template": "Hi\n"}).to_string();
+      let host = format!(
+         "Host: localhost:{}\r\nContent-Type: application/json\r\n",
+         address.port()
+      );
+      let response = request(&host, &body);
+      assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+      let response = request("Host: localhost\r\nAuthorization: Bearer s3cr3t\r\n", &body);
+      assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+      let response = request(&format!("{}Authorization: Bearer s3cr3t\r\n", host), &body);
+      assert!(response.starts_with("HTTP/1.1 201 Created\r\nContent-Type: application/json\r\n"));
+      assert!(response.contains("\"id\": \"1\""));
+   }
+}
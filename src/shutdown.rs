@@ -0,0 +1,28 @@
+/// The `shutdown` module turns SIGINT/SIGTERM (Ctrl-C) into a request to stop sending.
+///
+/// The first signal sets a flag that the senders check between emails: the emails in flight are
+/// finished, the run is reported and recorded and `rmt resume` continues with the recipients left
+/// out. A second signal ends the process right away.
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once a signal was received.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs the signal handler, returns the flag that is set when a stop is requested.
+pub fn install() -> Result<&'static AtomicBool, String> {
+   ctrlc::set_handler(|| {
+      if REQUESTED.swap(true, Ordering::SeqCst) {
+         eprintln!("\n!! interrupted again, exiting immediately");
+         process::exit(130);
+      }
+      eprintln!("\n* stopping after the emails in flight, interrupt again to exit immediately");
+   })
+   .map_err(|e| format!("cannot install the signal handler ({})", e))?;
+   Ok(&REQUESTED)
+}
+
+/// Whether a stop was requested.
+pub fn requested() -> bool {
+   REQUESTED.load(Ordering::SeqCst)
+}
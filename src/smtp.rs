@@ -0,0 +1,839 @@
+/// The `smtp` module is a minimal SMTP client for sending the campaign emails.
+///
+/// It speaks just enough ESMTP to deliver messages over a reused connection: STARTTLS or TLS
+/// from the start, `AUTH PLAIN`/`AUTH LOGIN` and the `SMTPUTF8` and `8BITMIME` parameters. If
+/// the server advertises PIPELINING (RFC 2920) the `MAIL FROM`, `RCPT TO` and `DATA` commands of
+/// a message are sent in one batch, which saves two or more round trips per message.
+///
+/// The client is asynchronous (on tokio) so that a campaign can keep many connections busy
+/// without a thread for each of them. The blocking `Stream` and `connect` serve the IMAP client.
+use crate::config::{Security, Smtp};
+use base64::Engine;
+use lettre::smtp::extension::ClientId;
+use log::{debug, trace, warn};
+use native_tls::TlsConnector;
+use regex::Regex;
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
+use tokio_native_tls::TlsStream;
+
+/// How long to wait for the server before giving up.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The `Stream` enum is the connection to the server, plain or secured with TLS.
+pub(crate) enum Stream {
+   Plain(net::TcpStream),
+   Tls(Box<native_tls::TlsStream<net::TcpStream>>),
+}
+
+impl Read for Stream {
+   fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+      match self {
+         Stream::Plain(s) => s.read(buf),
+         Stream::Tls(s) => s.read(buf),
+      }
+   }
+}
+
+impl Write for Stream {
+   fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      match self {
+         Stream::Plain(s) => s.write(buf),
+         Stream::Tls(s) => s.write(buf),
+      }
+   }
+
+   fn flush(&mut self) -> std::io::Result<()> {
+      match self {
+         Stream::Plain(s) => s.flush(),
+         Stream::Tls(s) => s.flush(),
+      }
+   }
+}
+
+/// The `Link` enum is the connection to the smtp server, plain or secured with TLS. Unlike a
+/// `Stream` it does not block: many connections are served by the threads of the runtime (see
+/// `transport::runtime`).
+enum Link {
+   Plain(TcpStream),
+   Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Link {
+   /// Connects to the server, the connection is secured right away with `security=tls`. Returns
+   /// the link and the address connected to.
+   async fn open(host: &str, port: u16, security: &Security) -> Result<(Link, SocketAddr), String> {
+      let addr = lookup_host((host, port))
+         .await
+         .map_err(|e| format!("cannot resolve smtp host {} ({})", host, e))?
+         .next()
+         .ok_or_else(|| format!("smtp host {} has no address", host))?;
+      Ok((Link::connect(host, addr, security).await?, addr))
+   }
+
+   /// Connects to the (resolved) address of the server `host`.
+   async fn connect(host: &str, addr: SocketAddr, security: &Security) -> Result<Link, String> {
+      let tcp = timeout(TIMEOUT, TcpStream::connect(addr))
+         .await
+         .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+         .map_err(|e| format!("cannot connect to {} ({})", addr, e))?;
+      let link = match security {
+         Security::Tls => Link::Tls(Box::new(tls_async(host, tcp).await?)),
+         _ => Link::Plain(tcp),
+      };
+      Ok(link)
+   }
+
+   /// Secures a plain connection with TLS e.g. after STARTTLS.
+   async fn secure(self, host: &str) -> Result<Link, String> {
+      match self {
+         Link::Plain(tcp) => Ok(Link::Tls(Box::new(tls_async(host, tcp).await?))),
+         tls => Ok(tls),
+      }
+   }
+}
+
+impl AsyncRead for Link {
+   fn poll_read(
+      self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &mut ReadBuf<'_>,
+   ) -> Poll<io::Result<()>> {
+      match self.get_mut() {
+         Link::Plain(s) => Pin::new(s).poll_read(cx, buf),
+         Link::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+      }
+   }
+}
+
+impl AsyncWrite for Link {
+   fn poll_write(
+      self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &[u8],
+   ) -> Poll<io::Result<usize>> {
+      match self.get_mut() {
+         Link::Plain(s) => Pin::new(s).poll_write(cx, buf),
+         Link::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+      }
+   }
+
+   fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      match self.get_mut() {
+         Link::Plain(s) => Pin::new(s).poll_flush(cx),
+         Link::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+      }
+   }
+
+   fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      match self.get_mut() {
+         Link::Plain(s) => Pin::new(s).poll_shutdown(cx),
+         Link::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+      }
+   }
+}
+
+#[derive(Debug)]
+/// The `Reply` struct holds a server reply.
+pub struct Reply {
+   /// The reply code e.g. 250
+   pub code: u16,
+   /// The text lines of the reply (without the codes)
+   pub lines: Vec<String>,
+}
+
+impl PartialEq for Reply {
+   /// Makes it possible to compare instances of `Reply`
+   fn eq(&self, other: &Self) -> bool {
+      self.code == other.code && self.lines == other.lines
+   }
+}
+
+impl Reply {
+   /// Whether the reply signals success (2xx) or an intermediate step (3xx).
+   fn is_positive(&self) -> bool {
+      (200..400).contains(&self.code)
+   }
+
+   /// Formats the reply for error messages.
+   pub(crate) fn describe(&self) -> String {
+      format!("{} {}", self.code, self.lines.join(" / "))
+   }
+
+   /// Whether the server advertised the given extension in its EHLO reply.
+   pub(crate) fn offers(&self, extension: &str) -> bool {
+      self.lines.iter().skip(1).any(|l| {
+         l.split_whitespace()
+            .next()
+            .map(|k| k.eq_ignore_ascii_case(extension))
+            .unwrap_or(false)
+      })
+   }
+
+   /// Returns the parameters of the given extension in the EHLO reply e.g. the AUTH mechanisms.
+   pub(crate) fn params(&self, extension: &str) -> Vec<String> {
+      self
+         .lines
+         .iter()
+         .skip(1)
+         .map(|l| l.split_whitespace().collect::<Vec<&str>>())
+         .find(|words| words.first().map(|k| k.eq_ignore_ascii_case(extension)) == Some(true))
+         .map(|words| words[1..].iter().map(|w| w.to_uppercase()).collect())
+         .unwrap_or_default()
+   }
+}
+
+/// The `Connection` struct is an established (and authenticated) connection to the server.
+pub struct Connection {
+   stream: Link,
+   /// The data read from the server but not consumed yet
+   buffer: Vec<u8>,
+   /// The reply to the (last) EHLO command, listing the extensions offered
+   ehlo: Reply,
+   /// Whether the connection was lost or closed by the server
+   broken: bool,
+   /// The `NOTIFY` parameter of the recipients, if DSNs are requested and offered
+   dsn_notify: Option<String>,
+   /// The `RET` parameter of the messages, if DSNs are requested and offered
+   dsn_ret: Option<String>,
+}
+
+impl Connection {
+   /// Connects to the server, secures the connection and authenticates as per the settings.
+   pub async fn open(smtp: &Smtp) -> Result<Connection, String> {
+      let (stream, addr) = Link::open(&smtp.host, smtp.port, &smtp.security).await?;
+      let (mut result, _) = Connection::greet(stream).await?;
+      result.hello().await?;
+      if smtp.security == Security::StartTls {
+         result = result.starttls(&smtp.host).await?;
+      }
+      if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+         result.authenticate(username, password).await?;
+      }
+      if smtp.dsn_notify.is_some() || smtp.dsn_ret.is_some() {
+         if result.ehlo.offers("DSN") {
+            result.dsn_notify = smtp.dsn_notify.clone();
+            result.dsn_ret = smtp.dsn_ret.clone();
+         } else {
+            warn!(
+               "the smtp server does not offer DSN, no delivery status notifications are requested"
+            );
+         }
+      }
+      debug!(
+         "smtp: connected to {} ({})",
+         addr,
+         if result.pipelining() {
+            "pipelining"
+         } else {
+            "no pipelining"
+         }
+      );
+      Ok(result)
+   }
+
+   /// Connects to the (resolved) address of the server `host` without saying hello, the
+   /// connection is secured right away with `security=tls`. Returns the connection and the
+   /// greeting of the server. The steps of `open` are taken one at a time by `doctor`.
+   pub(crate) async fn connect(
+      host: &str,
+      addr: SocketAddr,
+      security: &Security,
+   ) -> Result<(Connection, Reply), String> {
+      Connection::greet(Link::connect(host, addr, security).await?).await
+   }
+
+   /// Reads the greeting of the server.
+   async fn greet(stream: Link) -> Result<(Connection, Reply), String> {
+      let mut result = Connection {
+         stream,
+         buffer: vec![],
+         ehlo: Reply {
+            code: 0,
+            lines: vec![],
+         },
+         broken: false,
+         dsn_notify: None,
+         dsn_ret: None,
+      };
+      let greeting = result.expect(220, "greeting").await?;
+      Ok((result, greeting))
+   }
+
+   /// Returns the reply to the (last) EHLO command, listing the extensions offered.
+   pub(crate) fn ehlo(&self) -> &Reply {
+      &self.ehlo
+   }
+
+   /// Whether the connection is encrypted.
+   pub(crate) fn is_encrypted(&self) -> bool {
+      matches!(self.stream, Link::Tls(_))
+   }
+
+   /// Whether the connection was lost, a new one is needed for the next message then.
+   pub fn is_broken(&self) -> bool {
+      self.broken
+   }
+
+   /// Whether the server supports PIPELINING.
+   pub fn pipelining(&self) -> bool {
+      self.ehlo.offers("PIPELINING")
+   }
+
+   /// Sends the EHLO command and keeps the extensions offered.
+   pub(crate) async fn hello(&mut self) -> Result<(), String> {
+      self.ehlo = self
+         .command(&format!("EHLO {}", ClientId::hostname()), 250)
+         .await?;
+      Ok(())
+   }
+
+   /// Secures the connection with STARTTLS and says hello again.
+   pub(crate) async fn starttls(mut self, host: &str) -> Result<Connection, String> {
+      if !self.ehlo.offers("STARTTLS") {
+         return Err(String::from("the server does not offer STARTTLS"));
+      }
+      self.command("STARTTLS", 220).await?;
+      self.stream = self.stream.secure(host).await?;
+      self.buffer.clear();
+      self.hello().await?;
+      Ok(self)
+   }
+
+   /// Authenticates with `AUTH PLAIN` or else `AUTH LOGIN`, returns the final reply of the
+   /// server. The credentials are never sent over an unencrypted connection.
+   pub(crate) async fn authenticate(
+      &mut self,
+      username: &str,
+      password: &str,
+   ) -> Result<Reply, String> {
+      if let Link::Plain(_) = self.stream {
+         return Err(String::from(
+            "credentials are never sent over an unencrypted connection, use `security=tls` or \
+             `security=starttls`",
+         ));
+      }
+      let b64 = |s: &str| base64::engine::general_purpose::STANDARD.encode(s);
+      let mechanisms = self.ehlo.params("AUTH");
+      if mechanisms.iter().any(|m| m == "PLAIN") {
+         let token = b64(&format!("\0{}\0{}", username, password));
+         self
+            .credentials(&format!("AUTH PLAIN {}", token), 235)
+            .await
+      } else if mechanisms.iter().any(|m| m == "LOGIN") {
+         self.command("AUTH LOGIN", 334).await?;
+         self.credentials(&b64(username), 334).await?;
+         self.credentials(&b64(password), 235).await
+      } else {
+         Err(String::from(
+            "the server offers neither AUTH PLAIN nor AUTH LOGIN",
+         ))
+      }
+   }
+
+   /// Sends a message, `data` is the message with CRLF line endings.
+   ///
+   /// A rejected recipient fails the whole message, the transaction is reset then so that the
+   /// connection can be used for the next message. With PIPELINING the server may accept `DATA`
+   /// after rejecting a recipient: ending the data would deliver an empty message to the others,
+   /// so the connection is dropped instead (it is broken then).
+   pub async fn send(&mut self, from: &str, to: &[String], data: &[u8]) -> Result<Reply, String> {
+      let mut params = String::new();
+      if !data.is_ascii() && self.ehlo.offers("8BITMIME") {
+         params.push_str(" BODY=8BITMIME");
+      }
+      if self.ehlo.offers("SMTPUTF8") {
+         params.push_str(" SMTPUTF8");
+      }
+      if let Some(ref ret) = self.dsn_ret {
+         params.push_str(&format!(" RET={}", ret));
+      }
+      let notify = match self.dsn_notify {
+         Some(ref notify) => format!(" NOTIFY={}", notify),
+         None => String::new(),
+      };
+      let mut commands = vec![format!("MAIL FROM:<{}>{}", from, params)];
+      commands.extend(to.iter().map(|a| format!("RCPT TO:<{}>{}", a, notify)));
+      commands.push(String::from("DATA"));
+      let replies = if self.pipelining() {
+         // all commands at once, then all the replies
+         let batch: String = commands.iter().map(|c| format!("{}\r\n", c)).collect();
+         trace!("smtp: >> {}", commands.join(" | "));
+         self.write(batch.as_bytes()).await?;
+         let mut replies = vec![];
+         for _ in commands.iter() {
+            replies.push(self.read().await?);
+         }
+         replies
+      } else {
+         // one command at a time, stopping at the first failure
+         let mut replies = vec![];
+         for c in commands.iter() {
+            trace!("smtp: >> {}", c);
+            self.write(format!("{}\r\n", c).as_bytes()).await?;
+            let reply = self.read().await?;
+            let positive = reply.is_positive();
+            replies.push(reply);
+            if !positive {
+               break;
+            }
+         }
+         replies
+      };
+      let data_accepted =
+         replies.len() == commands.len() && replies[commands.len() - 1].code == 354;
+      let failure = replies
+         .iter()
+         .zip(commands.iter())
+         .find(|(r, _)| !r.is_positive())
+         .map(|(r, c)| format!("{} failed ({})", verb(c), r.describe()));
+      if let Some(failure) = failure {
+         if data_accepted {
+            // the server wants the message although a recipient was rejected, the transaction
+            // is aborted by closing the connection
+            self.broken = true;
+            return Err(failure);
+         }
+         self.write(b"RSET\r\n").await?;
+         self.read().await?;
+         return Err(failure);
+      }
+      self.write(&dot_stuff(data)).await?;
+      let reply = self.read().await?;
+      if reply.code != 250 {
+         return Err(format!("DATA failed ({})", reply.describe()));
+      }
+      Ok(reply)
+   }
+
+   /// Ends the session, a broken connection is just closed.
+   pub async fn quit(mut self) {
+      if !self.broken && self.write(b"QUIT\r\n").await.is_ok() {
+         let _ = self.read().await;
+      }
+   }
+
+   /// Sends a command and reads the reply, which must have the given code.
+   pub(crate) async fn command(&mut self, command: &str, code: u16) -> Result<Reply, String> {
+      trace!("smtp: >> {}", command);
+      self.write(format!("{}\r\n", command).as_bytes()).await?;
+      self.expect(code, verb(command)).await
+   }
+
+   /// Sends credentials and reads the reply, which must have the given code. Unlike commands
+   /// the credentials are not logged.
+   async fn credentials(&mut self, credentials: &str, code: u16) -> Result<Reply, String> {
+      trace!("smtp: >> <credentials>");
+      self
+         .write(format!("{}\r\n", credentials).as_bytes())
+         .await?;
+      self.expect(code, "AUTH").await
+   }
+
+   /// Reads a reply, which must have the given code.
+   async fn expect(&mut self, code: u16, what: &str) -> Result<Reply, String> {
+      let reply = self.read().await?;
+      if reply.code != code {
+         return Err(format!("{} failed ({})", what, reply.describe()));
+      }
+      Ok(reply)
+   }
+
+   async fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+      let stream = &mut self.stream;
+      let result = timeout(TIMEOUT, async {
+         stream.write_all(bytes).await?;
+         stream.flush().await
+      })
+      .await
+      .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+      self.broken |= result.is_err();
+      result.map_err(|e| format!("cannot write to the smtp server ({})", e))
+   }
+
+   /// Reads a (possibly multi-line) reply.
+   async fn read(&mut self) -> Result<Reply, String> {
+      let mut lines = vec![];
+      loop {
+         let line = self.read_line().await?;
+         trace!("smtp: << {}", line);
+         let code = line
+            .get(..3)
+            .and_then(|c| c.parse::<u16>().ok())
+            .ok_or_else(|| format!("invalid smtp reply: {}", line))?;
+         lines.push(line.get(4..).unwrap_or_default().to_string());
+         if line.as_bytes().get(3) != Some(&b'-') {
+            // the server is shutting down the connection
+            self.broken |= code == 421;
+            return Ok(Reply { code, lines });
+         }
+      }
+   }
+
+   /// Reads a line (without its line ending).
+   async fn read_line(&mut self) -> Result<String, String> {
+      loop {
+         if let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+         }
+         let mut chunk = [0; 4096];
+         let read = timeout(TIMEOUT, self.stream.read(&mut chunk))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+         match read {
+            Ok(0) => {
+               self.broken = true;
+               return Err(String::from("the smtp server closed the connection"));
+            }
+            Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+            Err(e) => {
+               self.broken = true;
+               return Err(format!("cannot read from the smtp server ({})", e));
+            }
+         }
+      }
+   }
+}
+
+/// Returns the command verb for error messages e.g. `RCPT`.
+fn verb(command: &str) -> &str {
+   command.split([' ', ':']).next().unwrap_or(command)
+}
+
+/// Connects to the server (`what` is the kind of server for error messages e.g. `smtp`), the
+/// connection is secured right away with `security=tls`. Returns the stream and the address
+/// connected to.
+pub(crate) fn connect(
+   what: &str,
+   host: &str,
+   port: u16,
+   security: &Security,
+) -> Result<(Stream, SocketAddr), String> {
+   let addr = (host, port)
+      .to_socket_addrs()
+      .map_err(|e| format!("cannot resolve {} host {} ({})", what, host, e))?
+      .next()
+      .ok_or_else(|| format!("{} host {} has no address", what, host))?;
+   let tcp = net::TcpStream::connect_timeout(&addr, TIMEOUT)
+      .map_err(|e| format!("cannot connect to {} ({})", addr, e))?;
+   tcp.set_read_timeout(Some(TIMEOUT))
+      .and_then(|_| tcp.set_write_timeout(Some(TIMEOUT)))
+      .map_err(|e| e.to_string())?;
+   let stream = match security {
+      Security::Tls => Stream::Tls(Box::new(tls(host, tcp)?)),
+      _ => Stream::Plain(tcp),
+   };
+   Ok((stream, addr))
+}
+
+impl Stream {
+   /// Secures a plain connection with TLS e.g. after STARTTLS.
+   pub(crate) fn secure(self, host: &str) -> Result<Stream, String> {
+      match self {
+         Stream::Plain(tcp) => Ok(Stream::Tls(Box::new(tls(host, tcp)?))),
+         tls => Ok(tls),
+      }
+   }
+}
+
+/// Secures the connection with TLS.
+fn tls(host: &str, tcp: net::TcpStream) -> Result<native_tls::TlsStream<net::TcpStream>, String> {
+   let connector = TlsConnector::new().map_err(|e| format!("TLS set up failed ({})", e))?;
+   connector
+      .connect(host, tcp)
+      .map_err(|e| format!("TLS handshake with {} failed ({})", host, e))
+}
+
+/// Secures the connection with TLS without blocking.
+async fn tls_async(host: &str, tcp: TcpStream) -> Result<TlsStream<TcpStream>, String> {
+   let connector = TlsConnector::new().map_err(|e| format!("TLS set up failed ({})", e))?;
+   tokio_native_tls::TlsConnector::from(connector)
+      .connect(host, tcp)
+      .await
+      .map_err(|e| format!("TLS handshake with {} failed ({})", host, e))
+}
+
+/// Whether a delivery error is permanent i.e. the server rejected the message with a 5xx reply,
+/// trying again will not help.
+pub fn is_permanent(error: &str) -> bool {
+   Regex::new(r"\(5\d\d\b").unwrap().is_match(error)
+}
+
+/// Escapes the lines of the message starting with a dot and adds the end of data marker.
+fn dot_stuff(data: &[u8]) -> Vec<u8> {
+   let mut result = Vec::with_capacity(data.len() + 5);
+   let mut line_start = true;
+   for b in data.iter() {
+      if line_start && *b == b'.' {
+         result.push(b'.');
+      }
+      result.push(*b);
+      line_start = *b == b'\n';
+   }
+   if !result.ends_with(b"\r\n") {
+      result.extend_from_slice(b"\r\n");
+   }
+   result.extend_from_slice(b".\r\n");
+   result
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::testing;
+   use std::io::{BufRead, BufReader};
+   use std::net::TcpListener;
+   use std::thread;
+
+   /// Runs a minimal SMTP server that accepts a single connection, `extensions` are advertised
+   /// in the EHLO reply, `RCPT` is rejected for addresses starting with `bad` and `DATA` when no
+   /// recipient was accepted. Returns the port and the handle for the lines received.
+   fn server(extensions: &'static [&'static str]) -> (u16, thread::JoinHandle<Vec<String>>) {
+      let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+      let port = listener.local_addr().unwrap().port();
+      let handle = thread::spawn(move || {
+         let (stream, _) = listener.accept().expect("no connection");
+         let mut writer = stream.try_clone().unwrap();
+         let mut received = vec![];
+         let (mut in_data, mut recipients) = (false, 0);
+         writer.write_all(b"220 test ESMTP\r\n").unwrap();
+         for line in BufReader::new(stream).lines() {
+            let line = line.unwrap();
+            received.push(line.clone());
+            if in_data {
+               if line == "." {
+                  in_data = false;
+                  writer.write_all(b"250 queued\r\n").unwrap();
+               }
+               continue;
+            }
+            let reply = match line.split([' ', ':']).next().unwrap_or_default() {
+               "EHLO" => {
+                  let mut reply = String::from("250-test\r\n");
+                  for e in extensions.iter() {
+                     reply.push_str(&format!("250-{}\r\n", e));
+                  }
+                  reply + "250 SIZE 1000000\r\n"
+               }
+               "RCPT" if line.contains("<bad") => String::from("550 no such user\r\n"),
+               "RCPT" => {
+                  recipients += 1;
+                  String::from("250 ok\r\n")
+               }
+               "DATA" if recipients == 0 => String::from("554 no valid recipients\r\n"),
+               "DATA" => {
+                  (in_data, recipients) = (true, 0);
+                  String::from("354 go ahead\r\n")
+               }
+               "RSET" => {
+                  recipients = 0;
+                  String::from("250 ok\r\n")
+               }
+               "QUIT" => String::from("221 bye\r\n"),
+               _ => String::from("250 ok\r\n"),
+            };
+            writer.write_all(reply.as_bytes()).unwrap();
+            if line == "QUIT" {
+               break;
+            }
+         }
+         received
+      });
+      (port, handle)
+   }
+
+   fn smtp(port: u16) -> Smtp {
+      let mut result = testing::smtp();
+      result.host = String::from("127.0.0.1");
+      result.port = port;
+      result
+   }
+
+   fn to(list: &[&str]) -> Vec<String> {
+      list.iter().map(|a| a.to_string()).collect()
+   }
+
+   #[tokio::test]
+   async fn send_with_pipelining() {
+      let (port, server) = server(&["PIPELINING", "8BITMIME"]);
+      let mut conn = Connection::open(&smtp(port)).await.expect("cannot connect");
+      assert!(conn.pipelining());
+      let reply = conn
+         .send(
+            "fb@example.com",
+            &to(&["jd@example.com"]),
+            b"Subject: hi\r\n\r\n.x\r\n",
+         )
+         .await;
+      assert_eq!(Ok(250), reply.map(|r| r.code));
+      let err = conn
+         .send(
+            "fb@example.com",
+            &to(&["bad@example.com"]),
+            b"Subject: hi\r\n\r\nhi",
+         )
+         .await;
+      assert_eq!(Err(String::from("RCPT failed (550 no such user)")), err);
+      conn.quit().await;
+      let received = server.join().unwrap();
+      assert_eq!(
+         to(&[
+            "MAIL FROM:<fb@example.com>",
+            "RCPT TO:<jd@example.com>",
+            "DATA",
+            "Subject: hi",
+            "",
+            "..x",
+            ".",
+            "MAIL FROM:<fb@example.com>",
+            "RCPT TO:<bad@example.com>",
+            "DATA",
+            "RSET",
+            "QUIT"
+         ]),
+         received[1..].to_vec()
+      );
+   }
+
+   #[tokio::test]
+   async fn partially_rejected_envelope() {
+      let (port, server) = server(&["PIPELINING"]);
+      let mut conn = Connection::open(&smtp(port)).await.expect("cannot connect");
+      let err = conn
+         .send(
+            "fb@example.com",
+            &to(&["jd@example.com", "bad@example.com"]),
+            b"Subject: hi\r\n\r\nhi",
+         )
+         .await;
+      assert_eq!(Err(String::from("RCPT failed (550 no such user)")), err);
+      // the DATA accepted for jd@example.com is not ended, the connection is dropped
+      assert!(conn.is_broken());
+      conn.quit().await;
+      let received = server.join().unwrap();
+      assert_eq!(
+         to(&[
+            "MAIL FROM:<fb@example.com>",
+            "RCPT TO:<jd@example.com>",
+            "RCPT TO:<bad@example.com>",
+            "DATA"
+         ]),
+         received[1..].to_vec()
+      );
+   }
+
+   #[tokio::test]
+   async fn no_credentials_over_plain_connections() {
+      let (port, server) = server(&["AUTH PLAIN LOGIN"]);
+      let mut settings = smtp(port);
+      settings.username = Some(String::from("fb"));
+      settings.password = Some(String::from("s3cr3t"));
+      let err = Connection::open(&settings)
+         .await
+         .err()
+         .expect("credentials sent");
+      assert!(err.starts_with("credentials are never sent"), "{}", err);
+      let received = server.join().unwrap();
+      assert!(
+         received.iter().all(|l| !l.starts_with("AUTH")),
+         "{:?}",
+         received
+      );
+   }
+
+   #[tokio::test]
+   async fn send_with_dsn() {
+      let mut settings = smtp(0);
+      settings.dsn_notify = Some(String::from("SUCCESS,FAILURE"));
+      settings.dsn_ret = Some(String::from("HDRS"));
+      for (extensions, expected) in [
+         (
+            &["DSN"][..],
+            [
+               "MAIL FROM:<fb@example.com> RET=HDRS",
+               "RCPT TO:<jd@example.com> NOTIFY=SUCCESS,FAILURE",
+            ],
+         ),
+         // servers without DSN would reject the parameters
+         (
+            &[][..],
+            ["MAIL FROM:<fb@example.com>", "RCPT TO:<jd@example.com>"],
+         ),
+      ]
+      .iter()
+      {
+         let (port, server) = server(extensions);
+         settings.port = port;
+         let mut conn = Connection::open(&settings).await.expect("cannot connect");
+         let reply = conn
+            .send("fb@example.com", &to(&["jd@example.com"]), b"hi")
+            .await;
+         assert_eq!(Ok(250), reply.map(|r| r.code));
+         conn.quit().await;
+         assert_eq!(to(expected), server.join().unwrap()[1..3].to_vec());
+      }
+   }
+
+   #[tokio::test]
+   async fn send_without_pipelining() {
+      let (port, server) = server(&[]);
+      let mut conn = Connection::open(&smtp(port)).await.expect("cannot connect");
+      assert!(!conn.pipelining());
+      let err = conn
+         .send(
+            "fb@example.com",
+            &to(&["bad@example.com", "jd@example.com"]),
+            b"hi",
+         )
+         .await;
+      assert_eq!(Err(String::from("RCPT failed (550 no such user)")), err);
+      conn.quit().await;
+      let received = server.join().unwrap();
+      // the commands stop at the first failure
+      assert_eq!(
+         to(&[
+            "MAIL FROM:<fb@example.com>",
+            "RCPT TO:<bad@example.com>",
+            "RSET",
+            "QUIT"
+         ]),
+         received[1..].to_vec()
+      );
+   }
+
+   #[test]
+   fn ehlo_extensions() {
+      let reply = Reply {
+         code: 250,
+         lines: to(&["test", "pipelining", "AUTH LOGIN plain", "SIZE 100"]),
+      };
+      assert!(reply.offers("PIPELINING"));
+      assert!(!reply.offers("test"));
+      assert_eq!(to(&["LOGIN", "PLAIN"]), reply.params("AUTH"));
+      assert!(reply.params("STARTTLS").is_empty());
+   }
+
+   #[test]
+   fn permanent_errors() {
+      assert!(is_permanent("RCPT failed (550 no such user)"));
+      assert!(is_permanent("DATA failed (554 5.7.1 spam)"));
+      assert!(!is_permanent("MAIL failed (421 try again later)"));
+      assert!(!is_permanent(
+         "cannot connect to 127.0.0.1:25 (connection refused)"
+      ));
+   }
+
+   #[test]
+   fn dot_stuff_lines() {
+      assert_eq!(b"a\r\n..b\r\n.\r\n".to_vec(), dot_stuff(b"a\r\n.b"));
+      assert_eq!(b"..\r\n.\r\n".to_vec(), dot_stuff(b".\r\n"));
+   }
+}
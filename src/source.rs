@@ -0,0 +1,275 @@
+/// The `source` module reads the recipients of a campaign one at a time so that very large lists
+/// are checked, composed and sent in a pipeline without holding the whole list in memory.
+use crate::config::{self, Config, OnDuplicate, Recipient};
+use crate::listing::{self, CsvHeader};
+use crate::message;
+use crate::template::Template;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// The `RecipientSource` trait is implemented by everything the recipients of a campaign can be
+/// read from one at a time: iterators over the recipients (or the errors of invalid entries) that
+/// may be moved to the thread composing the emails. A database cursor becomes a source by mapping
+/// its rows to `Recipient`s.
+pub trait RecipientSource: Iterator<Item = Result<Recipient, String>> + Send {}
+
+impl<I> RecipientSource for I where I: Iterator<Item = Result<Recipient, String>> + Send {}
+
+/// The `CsvSource` struct reads the recipients from a CSV file (see `listing::CsvHeader`) one
+/// record at a time.
+pub struct CsvSource<R: BufRead> {
+   reader: R,
+   /// The name of the file (e.g. its path) the errors refer to
+   name: String,
+   header: CsvHeader,
+   /// The number of lines read so far
+   line: usize,
+   /// The number of records read so far, the header not counted
+   records: usize,
+   done: bool,
+}
+
+impl CsvSource<BufReader<File>> {
+   /// Opens the CSV file and reads its header.
+   pub fn open(path: &str) -> Result<CsvSource<BufReader<File>>, String> {
+      let file =
+         File::open(path).map_err(|e| format!("cannot read *recipients_csv* {} ({})", path, e))?;
+      CsvSource::new(BufReader::new(file), path)
+   }
+}
+
+impl<R: BufRead> CsvSource<R> {
+   /// Reads the header from the reader, `name` (e.g. the path) prefixes the error messages.
+   pub fn new(mut reader: R, name: &str) -> Result<CsvSource<R>, String> {
+      let mut line = 0;
+      let header = match read_record(&mut reader, &mut line) {
+         Ok(Some((start, record))) => {
+            CsvHeader::parse(record).map_err(|e| format!("{}:{}: {}", name, start, e))?
+         }
+         Ok(None) => return Err(format!("{}: the CSV file is empty", name)),
+         Err((start, e)) => return Err(format!("{}:{}: {}", name, start, e)),
+      };
+      Ok(CsvSource {
+         reader,
+         name: name.to_string(),
+         header,
+         line,
+         records: 0,
+         done: false,
+      })
+   }
+}
+
+/// Yields the recipients in the order of the file, an invalid record yields an error with its
+/// location (`path:line: ..`). The iteration ends after an error reading the file.
+impl<R: BufRead> Iterator for CsvSource<R> {
+   type Item = Result<Recipient, String>;
+
+   fn next(&mut self) -> Option<Result<Recipient, String>> {
+      if self.done {
+         return None;
+      }
+      match read_record(&mut self.reader, &mut self.line) {
+         Ok(Some((start, record))) => {
+            self.records += 1;
+            Some(
+               self
+                  .header
+                  .recipient(&record, self.records)
+                  .map_err(|e| format!("{}:{}: {}", self.name, start, e)),
+            )
+         }
+         Ok(None) => {
+            self.done = true;
+            None
+         }
+         Err((start, e)) => {
+            self.done = true;
+            Some(Err(format!("{}:{}: {}", self.name, start, e)))
+         }
+      }
+   }
+}
+
+/// Reads the next record and the line it starts in, the lines of quoted fields with line breaks
+/// are joined. Empty lines are skipped, `None` is returned at the end of the input. `line` counts
+/// the lines read.
+#[allow(clippy::type_complexity)]
+fn read_record<R: BufRead>(
+   reader: &mut R,
+   line: &mut usize,
+) -> Result<Option<(usize, Vec<String>)>, (usize, String)> {
+   let mut text = String::new();
+   let mut start = *line + 1;
+   loop {
+      let n = reader
+         .read_line(&mut text)
+         .map_err(|e| (*line + 1, e.to_string()))?;
+      if n == 0 && text.is_empty() {
+         return Ok(None);
+      }
+      if n > 0 {
+         *line += 1;
+      }
+      match listing::parse_csv(&text) {
+         Ok(mut records) => match records.pop() {
+            Some(record) => return Ok(Some((start, record))),
+            None => {
+               text.clear();
+               start = *line + 1;
+            }
+         },
+         // the line break is part of a quoted field
+         Err(_) if n > 0 => (),
+         Err(e) => return Err((start, e)),
+      }
+   }
+}
+
+/// Returns the recipients of the campaign: the ones of the config followed by the ones of the
+/// *recipients_csv* files (see `config::instantiate_streaming`), which are read as they are taken.
+/// The CSV files are opened (and their headers read) right away.
+pub fn open(cfg: &Config) -> Result<Box<dyn RecipientSource>, String> {
+   let files = cfg
+      .recipients_csv
+      .iter()
+      .map(|path| CsvSource::open(path))
+      .collect::<Result<Vec<_>, String>>()?;
+   Ok(Box::new(
+      cfg.recipients
+         .clone()
+         .into_iter()
+         .map(Ok)
+         .chain(files.into_iter().flatten()),
+   ))
+}
+
+/// Counts the recipients of the campaign without keeping them e.g. for the progress display,
+/// invalid entries are counted too.
+pub fn count(cfg: &Config) -> Result<usize, String> {
+   Ok(open(cfg)?.count())
+}
+
+/// The `Checked` struct checks the recipients of a source as they are read, the way a config and
+/// the templates are checked before a campaign is sent: the recipients must be valid for the
+/// settings of the campaign (see `config::check_recipient`) and have the data the templates need.
+///
+/// The email to a recipient is sent before the rest of the list is read, so the first entry of a
+/// recipient that appears more than once is used (*on_duplicate* `error` turns the later ones into
+/// errors). The addresses are remembered by their hash, 8 bytes per recipient.
+pub struct Checked<'a, S> {
+   source: S,
+   cfg: &'a Config,
+   templates: Vec<&'a Template>,
+   seen: HashSet<u64>,
+}
+
+impl<'a, S: RecipientSource> Checked<'a, S> {
+   /// Checks the recipients of the source for the campaign and the templates (of all variants).
+   pub fn new(source: S, cfg: &'a Config, templates: &[&'a Template]) -> Checked<'a, S> {
+      Checked {
+         source,
+         cfg,
+         templates: templates.to_vec(),
+         seen: HashSet::new(),
+      }
+   }
+}
+
+impl<S: RecipientSource> Iterator for Checked<'_, S> {
+   type Item = Result<Recipient, String>;
+
+   fn next(&mut self) -> Option<Result<Recipient, String>> {
+      loop {
+         let rcp = match self.source.next()? {
+            Ok(rcp) => rcp,
+            Err(err) => return Some(Err(err)),
+         };
+         let key = message::fnv1a(rcp.email.trim().to_lowercase().as_bytes());
+         if !self.seen.insert(key) {
+            if self.cfg.on_duplicate == OnDuplicate::Error {
+               return Some(Err(format!(
+                  "duplicate recipient: {} (see *on_duplicate*)",
+                  rcp.email
+               )));
+            }
+            continue;
+         }
+         let result = config::check_recipient(self.cfg, &rcp).and_then(|_| {
+            self
+               .templates
+               .iter()
+               .try_for_each(|t| t.check_recipient(&rcp, &self.cfg.defaults))
+         });
+         return Some(result.map(|_| rcp));
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::template;
+   use crate::testing;
+   use std::io::Cursor;
+
+   const CSV: &str = "email,names,ORG\n\
+                      jd@example.com,John Doe,EFF\n\
+                      \n\
+                      mm@gmail.com,\"Mickey\nMouse\",\"Disney, Inc.\"\n\
+                      oh!no!,Jane Doe,ACME\n\
+                      JD@example.com,John Doe,\n";
+
+   #[test]
+   fn read_csv_records() {
+      let source = CsvSource::new(Cursor::new(CSV), "big.csv").expect("no CSV header");
+      let recipients: Vec<Result<Recipient, String>> = source.collect();
+      assert_eq!(4, recipients.len());
+      let mm = recipients[1].as_ref().expect("invalid recipient");
+      assert_eq!(vec!["Mickey", "Mouse"], mm.names);
+      assert_eq!(Some("Disney, Inc."), mm.data.get("ORG").map(|o| o.as_str()));
+      let err = recipients[2].as_ref().expect_err("valid recipient");
+      assert!(
+         err.starts_with("big.csv:6: invalid email in CSV record 3"),
+         "{}",
+         err
+      );
+      let unterminated = CsvSource::new(Cursor::new("email,names\na@b.com,\"A B\n"), "x.csv")
+         .expect("no CSV header")
+         .collect::<Vec<_>>();
+      assert_eq!(
+         vec![Err(String::from(
+            "x.csv:2: unterminated quoted field in line 2"
+         ))],
+         unterminated
+      );
+      assert_eq!(
+         Err(String::from("y.csv:1: the CSV file has no *email* column")),
+         CsvSource::new(Cursor::new("names\n"), "y.csv").map(|_| ())
+      );
+   }
+
+   #[test]
+   fn check_streamed_recipients() {
+      let cfg = testing::config("fb@example.com", "Hello %_FN%");
+      let tmpl = template::new("Hi %_FN% at %ORG%");
+      let source = CsvSource::new(Cursor::new(CSV), "big.csv").expect("no CSV header");
+      let checked: Vec<Result<Recipient, String>> = Checked::new(source, &cfg, &[&tmpl]).collect();
+      assert_eq!(4, checked.len());
+      assert!(checked[0].is_ok() && checked[1].is_ok());
+      assert!(checked[2].is_err());
+      assert_eq!(
+         Err(String::from(
+            "duplicate recipient: JD@example.com (see *on_duplicate*)"
+         )),
+         checked[3]
+      );
+      let source = vec![Ok(testing::recipient("a@b.com", "A B", &[]))].into_iter();
+      let checked: Vec<Result<Recipient, String>> = Checked::new(source, &cfg, &[&tmpl]).collect();
+      assert!(checked[0]
+         .as_ref()
+         .expect_err("valid recipient")
+         .contains("ORG"));
+   }
+}
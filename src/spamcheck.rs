@@ -0,0 +1,196 @@
+/// The `spamcheck` module has a rendered email scored by SpamAssassin so that spammy content can
+/// be fixed before a campaign is sent.
+///
+/// The email is piped to a local `spamc -R` (or `spamassassin -t`) and the report it prints is
+/// parsed for the score and the rules that were triggered.
+use regex::Regex;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// The command the emails are piped to by default.
+pub const DEFAULT_COMMAND: &str = "spamc -R";
+
+#[derive(Debug)]
+/// The `Rule` struct holds a SpamAssassin rule that was triggered by the email.
+pub struct Rule {
+   /// The rule name e.g. `HTML_IMAGE_ONLY_24`
+   pub name: String,
+   /// The points the rule contributes to the score
+   pub points: f64,
+   /// What the rule is about
+   pub description: String,
+}
+
+impl PartialEq for Rule {
+   /// Makes it possible to compare instances of `Rule`
+   fn eq(&self, other: &Self) -> bool {
+      self.name == other.name
+         && self.points == other.points
+         && self.description == other.description
+   }
+}
+
+#[derive(Debug)]
+/// The `Report` struct holds the outcome of a spam check.
+pub struct Report {
+   /// The spam score of the email
+   pub score: f64,
+   /// The score from which on emails are considered spam
+   pub threshold: f64,
+   /// The rules that were triggered, the ones with the most points first
+   pub rules: Vec<Rule>,
+}
+
+impl PartialEq for Report {
+   /// Makes it possible to compare instances of `Report`
+   fn eq(&self, other: &Self) -> bool {
+      self.score == other.score && self.threshold == other.threshold && self.rules == other.rules
+   }
+}
+
+impl Report {
+   /// Whether SpamAssassin considers the email spam.
+   pub fn is_spam(&self) -> bool {
+      self.score >= self.threshold
+   }
+}
+
+/// Parses the report printed by `spamc -R` or `spamassassin -t`.
+pub fn parse(output: &str) -> Result<Report, String> {
+   let first_line = Regex::new(r"^\s*(-?\d+(?:\.\d+)?)/(\d+(?:\.\d+)?)\s*$").unwrap();
+   let details = Regex::new(
+      r"Content analysis details:\s+\((-?\d+(?:\.\d+)?) points?, (\d+(?:\.\d+)?) required\)",
+   )
+   .unwrap();
+   let caps = output
+      .lines()
+      .next()
+      .and_then(|l| first_line.captures(l))
+      .or_else(|| details.captures(output))
+      .ok_or("no spam score found in the SpamAssassin output")?;
+   let mut result = Report {
+      score: caps[1].parse().unwrap_or_default(),
+      threshold: caps[2].parse().unwrap_or_default(),
+      rules: vec![],
+   };
+   let rule = Regex::new(r"^\s*(-?\d+(?:\.\d+)?)\s+([A-Z0-9_]+)\s+(.*?)\s*$").unwrap();
+   let mut in_table = false;
+   for line in output.lines() {
+      if line.starts_with("---- ") {
+         in_table = true;
+      } else if !in_table {
+         continue;
+      } else if let Some(caps) = rule.captures(line) {
+         result.rules.push(Rule {
+            name: caps[2].to_string(),
+            points: caps[1].parse().unwrap_or_default(),
+            description: caps[3].to_string(),
+         });
+      } else if line.starts_with(' ') && !line.trim().is_empty() && !result.rules.is_empty() {
+         // the descriptions may be wrapped
+         let description = &mut result.rules.last_mut().unwrap().description;
+         description.push(' ');
+         description.push_str(line.trim());
+      } else {
+         in_table = false;
+      }
+   }
+   result.rules.sort_by(|a, b| {
+      b.points
+         .partial_cmp(&a.points)
+         .unwrap_or(std::cmp::Ordering::Equal)
+   });
+   Ok(result)
+}
+
+/// Pipes the email (as transmitted) to the given command and parses its report.
+pub fn check(message: &[u8], command: &str) -> Result<Report, String> {
+   let mut words = command.split_whitespace();
+   let program = words.next().ok_or("empty spam check command")?;
+   let mut child = Command::new(program)
+      .args(words)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .map_err(|e| format!("cannot run {} ({})", program, e))?;
+   child
+      .stdin
+      .take()
+      .unwrap()
+      .write_all(message)
+      .map_err(|e| format!("cannot pipe the email to {} ({})", program, e))?;
+   let output = child
+      .wait_with_output()
+      .map_err(|e| format!("{} failed ({})", program, e))?;
+   let stdout = String::from_utf8_lossy(&output.stdout);
+   parse(&stdout).map_err(|e| {
+      let stderr = String::from_utf8_lossy(&output.stderr);
+      match stderr.trim() {
+         "" => e,
+         details => format!("{} ({})", e, details),
+      }
+   })
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const SPAMC_OUTPUT: &str = "5.7/5.0
+Spam detection software, running on the system \"mail.example.com\",
+has identified this incoming email as possible spam.
+
+Content analysis details:   (5.7 points, 5.0 required)
+
+ pts rule name              description
+---- ---------------------- --------------------------------------------------
+ 0.5 MISSING_DATE           Missing Date: header
+ 2.5 HTML_IMAGE_ONLY_24     BODY: HTML: images with 2000-2400 bytes of
+                            words
+-0.1 DKIM_VALID             Message has at least one valid DKIM or DK
+                            signature
+ 2.8 FREEMAIL_FORGED_REPLYTO Freemail in Reply-To, but not From
+
+";
+
+   #[test]
+   fn parse_spamc_report() {
+      let report = parse(SPAMC_OUTPUT).expect("cannot parse report");
+      assert_eq!(5.7, report.score);
+      assert_eq!(5.0, report.threshold);
+      assert!(report.is_spam());
+      let names: Vec<&str> = report.rules.iter().map(|r| r.name.as_ref()).collect();
+      assert_eq!(
+         vec![
+            "FREEMAIL_FORGED_REPLYTO",
+            "HTML_IMAGE_ONLY_24",
+            "MISSING_DATE",
+            "DKIM_VALID"
+         ],
+         names
+      );
+      assert_eq!(
+         "BODY: HTML: images with 2000-2400 bytes of words",
+         report.rules[1].description
+      );
+   }
+
+   #[test]
+   fn parse_spamassassin_report() {
+      // `spamassassin -t` prints the email with the report, without the score line
+      let output = SPAMC_OUTPUT.replacen("5.7/5.0\n", "Subject: hi\n\nHi there!\n", 1);
+      let report = parse(&output).expect("cannot parse report");
+      assert_eq!(5.7, report.score);
+      assert_eq!(4, report.rules.len());
+      assert!(parse("0/0\n").map(|r| r.rules.is_empty()).unwrap());
+      assert!(parse("command not found").is_err());
+   }
+
+   #[test]
+   fn check_with_missing_command() {
+      assert!(check(b"Subject: hi\n\nhi", "no-such-spamc -R")
+         .unwrap_err()
+         .starts_with("cannot run no-such-spamc"));
+   }
+}
@@ -0,0 +1,369 @@
+/// The `spool` module keeps rendered emails in a directory until they are delivered.
+///
+/// `rmt queue` composes the emails of a campaign into a spool directory, where they can be
+/// reviewed, and `rmt flush` delivers them later (possibly run by somebody else or by cron). The
+/// directory holds a `spool.json` file describing the campaign and for every email the message
+/// (`NNNNNN.eml`) and its metadata (`NNNNNN.json`). Delivered emails are removed from the spool,
+/// the failed ones stay so that the next flush retries them. Emails that keep failing (or are
+/// rejected for good) are given up on and kept as `NNNNNN.failed.eml`/`NNNNNN.failed.json`.
+use crate::message::Message;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The name of the file describing the spooled campaign.
+const SPOOL_FILE: &str = "spool.json";
+
+/// The name of the status file written by `rmt daemon`.
+const STATUS_FILE: &str = "status.json";
+
+/// The number of attempts to deliver an email before giving up on it.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Returns how long to wait (in seconds) before the next attempt to deliver an email that failed
+/// the given number of times: 1, 4, 16 and 64 minutes.
+pub fn backoff(attempts: u32) -> u64 {
+   60 * 4u64.pow(attempts.clamp(1, MAX_ATTEMPTS) - 1)
+}
+
+#[derive(Debug, Clone)]
+/// The `Entry` struct holds the metadata of a spooled email.
+pub struct Entry {
+   /// The (file) name of the entry e.g. `000001`
+   pub id: String,
+   /// The envelope sender
+   pub sender: String,
+   /// The email address of the recipient
+   pub recipient: String,
+   /// The envelope recipients i.e. the recipient and everybody the email is copied to
+   pub envelope: Vec<String>,
+   /// The `Message-ID` of the email
+   pub message_id: String,
+   /// The subject of the email
+   pub subject: String,
+   /// The template variant the recipient was assigned, if the campaign is an A/B test
+   pub variant: Option<String>,
+   /// The number of failed attempts to deliver the email
+   pub attempts: u32,
+   /// When to try again (seconds since the epoch), 0 if the email was not deferred
+   pub retry_at: u64,
+   /// The reason the last attempt failed (if any)
+   pub last_error: Option<String>,
+}
+
+impl PartialEq for Entry {
+   /// Makes it possible to compare instances of `Entry`
+   fn eq(&self, other: &Self) -> bool {
+      self.id == other.id
+         && self.sender == other.sender
+         && self.recipient == other.recipient
+         && self.envelope == other.envelope
+         && self.message_id == other.message_id
+         && self.subject == other.subject
+         && self.variant == other.variant
+         && self.attempts == other.attempts
+         && self.retry_at == other.retry_at
+         && self.last_error == other.last_error
+   }
+}
+
+impl Entry {
+   /// Whether the email is due to be delivered at the given time (seconds since the epoch).
+   pub fn is_due(&self, now: u64) -> bool {
+      self.retry_at <= now
+   }
+
+   fn to_json(&self) -> Value {
+      json!({
+         "sender": self.sender,
+         "recipient": self.recipient,
+         "envelope": self.envelope,
+         "message_id": self.message_id,
+         "subject": self.subject,
+         "variant": self.variant,
+         "attempts": self.attempts,
+         "retry_at": self.retry_at,
+         "last_error": self.last_error,
+      })
+   }
+
+   fn from_json(id: &str, value: &Value) -> Option<Entry> {
+      let text = |key: &str| value[key].as_str().map(|s| s.to_string());
+      Some(Entry {
+         id: id.to_string(),
+         sender: text("sender")?,
+         recipient: text("recipient")?,
+         envelope: value["envelope"]
+            .as_array()?
+            .iter()
+            .filter_map(|a| a.as_str().map(|s| s.to_string()))
+            .collect(),
+         message_id: text("message_id").unwrap_or_default(),
+         subject: text("subject").unwrap_or_default(),
+         variant: text("variant"),
+         attempts: value["attempts"].as_u64().unwrap_or_default() as u32,
+         retry_at: value["retry_at"].as_u64().unwrap_or_default(),
+         last_error: text("last_error"),
+      })
+   }
+}
+
+/// The `Spool` struct is a spool directory.
+pub struct Spool {
+   dir: PathBuf,
+   /// The identifier of the spooled campaign
+   pub campaign_id: String,
+   /// The path of the config file the campaign was spooled from
+   pub config: String,
+   /// When the emails were spooled (seconds since the epoch)
+   pub created_at: u64,
+}
+
+impl Spool {
+   /// Creates a spool for the campaign, the directory is created if needed but must not hold a
+   /// spool with emails that were not delivered yet.
+   pub fn create(dir: &Path, campaign_id: &str, config: &str, now: u64) -> Result<Spool, String> {
+      fs::create_dir_all(dir)
+         .map_err(|e| format!("cannot create directory {} ({})", dir.display(), e))?;
+      if dir.join(SPOOL_FILE).exists() {
+         let spool = Spool::open(dir)?;
+         if !spool.entries()?.is_empty() {
+            return Err(format!(
+               "the spool {} still holds emails of campaign {}, flush it first",
+               dir.display(),
+               spool.campaign_id
+            ));
+         }
+      }
+      let result = Spool {
+         dir: dir.to_path_buf(),
+         campaign_id: campaign_id.to_string(),
+         config: config.to_string(),
+         created_at: now,
+      };
+      let description = json!({
+         "campaign_id": campaign_id,
+         "config": config,
+         "created_at": now,
+      });
+      write(
+         &dir.join(SPOOL_FILE),
+         format!("{:#}\n", description).as_bytes(),
+      )?;
+      Ok(result)
+   }
+
+   /// Opens an existing spool.
+   pub fn open(dir: &Path) -> Result<Spool, String> {
+      let path = dir.join(SPOOL_FILE);
+      let text = fs::read_to_string(&path)
+         .map_err(|e| format!("{} is not a spool ({})", dir.display(), e))?;
+      let value: Value = serde_json::from_str(&text)
+         .map_err(|e| format!("invalid spool file {} ({})", path.display(), e))?;
+      Ok(Spool {
+         dir: dir.to_path_buf(),
+         campaign_id: value["campaign_id"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+         config: value["config"].as_str().unwrap_or_default().to_string(),
+         created_at: value["created_at"].as_u64().unwrap_or_default(),
+      })
+   }
+
+   /// Adds an email to the spool.
+   pub fn add(&self, msg: &Message, variant: Option<&str>) -> Result<Entry, String> {
+      // the emails given up on keep their numbers
+      let last = self
+         .ids(".json")?
+         .into_iter()
+         .chain(self.ids(".failed.json")?)
+         .max();
+      let id = format!("{:06}", last.map(|n| n + 1).unwrap_or(1));
+      let mut envelope = vec![msg.recipient.clone()];
+      envelope.extend(msg.cc.iter().cloned());
+      let entry = Entry {
+         id,
+         sender: msg.sender.clone(),
+         recipient: msg.recipient.clone(),
+         envelope,
+         message_id: msg.header("Message-ID").unwrap_or_default().to_string(),
+         subject: msg.header("Subject").unwrap_or_default().to_string(),
+         variant: variant.map(|v| v.to_string()),
+         attempts: 0,
+         retry_at: 0,
+         last_error: None,
+      };
+      // the metadata is written last, an entry without it is incomplete and ignored
+      write(&self.message_path(&entry), &msg.to_bytes())?;
+      self.save(&entry)?;
+      Ok(entry)
+   }
+
+   /// Records a failed attempt to deliver an email, it is retried at `retry_at`.
+   pub fn defer(&self, entry: &mut Entry, error: &str, retry_at: u64) -> Result<(), String> {
+      entry.attempts += 1;
+      entry.retry_at = retry_at;
+      entry.last_error = Some(error.to_string());
+      self.save(entry)
+   }
+
+   /// Gives up on delivering an email, it is kept in the spool as `NNNNNN.failed.*`.
+   pub fn give_up(&self, entry: &mut Entry, error: &str) -> Result<(), String> {
+      entry.attempts += 1;
+      entry.last_error = Some(error.to_string());
+      self.save(entry)?;
+      for ext in ["eml", "json"].iter() {
+         let from = self.dir.join(format!("{}.{}", entry.id, ext));
+         let to = self.dir.join(format!("{}.failed.{}", entry.id, ext));
+         fs::rename(&from, &to).map_err(|e| format!("cannot rename {} ({})", from.display(), e))?;
+      }
+      Ok(())
+   }
+
+   /// Returns the number of emails given up on.
+   pub fn given_up(&self) -> Result<usize, String> {
+      Ok(self.ids(".failed.json")?.len())
+   }
+
+   /// Writes the status file of the spool e.g. for monitoring.
+   pub fn write_status(&self, status: &Value) -> Result<(), String> {
+      write(
+         &self.dir.join(STATUS_FILE),
+         format!("{:#}\n", status).as_bytes(),
+      )
+   }
+
+   fn save(&self, entry: &Entry) -> Result<(), String> {
+      write(
+         &self.dir.join(format!("{}.json", entry.id)),
+         format!("{:#}\n", entry.to_json()).as_bytes(),
+      )
+   }
+
+   /// Returns the spooled emails in the order they were added.
+   pub fn entries(&self) -> Result<Vec<Entry>, String> {
+      let mut result = vec![];
+      for n in self.ids(".json")? {
+         let id = format!("{:06}", n);
+         let path = self.dir.join(format!("{}.json", id));
+         let entry = fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+            .and_then(|value| Entry::from_json(&id, &value))
+            .ok_or_else(|| format!("invalid spool entry {}", path.display()))?;
+         result.push(entry);
+      }
+      Ok(result)
+   }
+
+   /// Reads the message of a spooled email.
+   pub fn message(&self, entry: &Entry) -> Result<Vec<u8>, String> {
+      let path = self.message_path(entry);
+      fs::read(&path).map_err(|e| format!("cannot read {} ({})", path.display(), e))
+   }
+
+   /// Removes a (delivered) email from the spool.
+   pub fn remove(&self, entry: &Entry) -> Result<(), String> {
+      for path in [
+         self.dir.join(format!("{}.json", entry.id)),
+         self.message_path(entry),
+      ]
+      .iter()
+      {
+         fs::remove_file(path).map_err(|e| format!("cannot remove {} ({})", path.display(), e))?;
+      }
+      Ok(())
+   }
+
+   fn message_path(&self, entry: &Entry) -> PathBuf {
+      self.dir.join(format!("{}.eml", entry.id))
+   }
+
+   /// Returns the sequence numbers of the entries whose metadata file has the given suffix
+   /// (`.json` for the pending emails), sorted.
+   fn ids(&self, suffix: &str) -> Result<Vec<u32>, String> {
+      let entries = fs::read_dir(&self.dir)
+         .map_err(|e| format!("cannot read directory {} ({})", self.dir.display(), e))?;
+      let mut result: Vec<u32> = entries
+         .filter_map(|e| e.ok())
+         .filter_map(|e| {
+            let name = e.file_name().to_string_lossy().to_string();
+            name.strip_suffix(suffix)?.parse::<u32>().ok()
+         })
+         .collect();
+      result.sort_unstable();
+      Ok(result)
+   }
+}
+
+fn write(path: &Path, contents: &[u8]) -> Result<(), String> {
+   fs::write(path, contents).map_err(|e| format!("cannot write {} ({})", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::message;
+   use crate::template;
+   use crate::testing;
+   use tempfile::TempDir;
+
+   #[test]
+   fn add_and_remove_entries() {
+      let dir = TempDir::new().unwrap();
+      let spool = Spool::create(dir.path(), "spring-sale", "sale.ini", 1000).unwrap();
+      let cfg = testing::config("fb@example.com", "hello %_FN%");
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[]);
+      let msg = message::compose(&cfg, &template::new("hi"), None, &rcp);
+      let first = spool.add(&msg, None).unwrap();
+      let second = spool.add(&msg, Some("b")).unwrap();
+      assert_eq!("000001", first.id);
+      assert_eq!(vec![String::from("jd@example.com")], first.envelope);
+      assert_eq!("hello John", first.subject);
+      assert_eq!(msg.to_bytes(), spool.message(&first).unwrap());
+      // the metadata survives a round trip
+      let spool = Spool::open(dir.path()).unwrap();
+      assert_eq!("spring-sale", spool.campaign_id);
+      assert_eq!("sale.ini", spool.config);
+      assert_eq!(vec![first, second], spool.entries().unwrap());
+      // a spool with pending emails is not overwritten
+      assert!(Spool::create(dir.path(), "other", "other.ini", 2000).is_err());
+      let entries = spool.entries().unwrap();
+      spool.remove(&entries[0]).unwrap();
+      assert_eq!("000002", spool.entries().unwrap()[0].id);
+      assert_eq!("000003", spool.add(&msg, None).unwrap().id);
+   }
+
+   #[test]
+   fn defer_and_give_up() {
+      let dir = TempDir::new().unwrap();
+      let spool = Spool::create(dir.path(), "spring-sale", "sale.ini", 1000).unwrap();
+      let cfg = testing::config("fb@example.com", "hello");
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[]);
+      let msg = message::compose(&cfg, &template::new("hi"), None, &rcp);
+      let mut entry = spool.add(&msg, None).unwrap();
+      assert!(entry.is_due(0));
+      spool.defer(&mut entry, "421 try later", 2000).unwrap();
+      let entries = spool.entries().unwrap();
+      assert_eq!(vec![entry], entries);
+      assert!(!entries[0].is_due(1999));
+      assert!(entries[0].is_due(2000));
+      let mut entry = spool.entries().unwrap().remove(0);
+      spool.give_up(&mut entry, "550 no such user").unwrap();
+      assert!(spool.entries().unwrap().is_empty());
+      assert_eq!(1, spool.given_up().unwrap());
+      // the numbers of the emails given up on are not reused
+      assert_eq!("000002", spool.add(&msg, None).unwrap().id);
+      assert_eq!(
+         vec![60, 240, 960],
+         (1..4).map(backoff).collect::<Vec<u64>>()
+      );
+   }
+
+   #[test]
+   fn open_without_spool() {
+      let dir = TempDir::new().unwrap();
+      assert!(Spool::open(dir.path()).is_err());
+   }
+}
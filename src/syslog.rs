@@ -0,0 +1,381 @@
+/// The `syslog` module sends the log to the local syslog daemon or to the systemd journal, which
+/// suits `rmt` running as a daemon on a server.
+///
+/// Syslog messages are formatted as per RFC 5424 and sent to `/dev/log`, journal entries use the
+/// native protocol of `systemd-journald`. The delivery events are logged along with structured
+/// fields: the campaign id, the recipient and the status of the delivery.
+use crate::events::{Event, Observer};
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// The socket of the syslog daemon.
+pub const SYSLOG_SOCKET: &str = "/dev/log";
+/// The socket of the systemd journal.
+pub const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+/// The facility of the syslog messages (mail system).
+const FACILITY: u8 = 2;
+/// The structured data id of the fields, 32473 is the private enterprise number reserved for
+/// documentation (RFC 5612).
+const SD_ID: &str = "rmt@32473";
+
+/// The target the log was sent to, set once the logger is installed.
+static TARGET: OnceLock<Target> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The `Target` enum lists where the log may be sent.
+pub enum Target {
+   /// The syslog daemon
+   Syslog,
+   /// The systemd journal
+   Journal,
+}
+
+impl Target {
+   /// Parses the value of the `--log` option, `stderr` (the default) is `None`.
+   pub fn parse(value: &str) -> Result<Option<Target>, String> {
+      match value {
+         "stderr" => Ok(None),
+         "syslog" => Ok(Some(Target::Syslog)),
+         "journald" => Ok(Some(Target::Journal)),
+         _ => Err(format!("invalid log target: {}", value)),
+      }
+   }
+
+   /// Returns the name of the target as given to the `--log` option.
+   fn name(&self) -> &'static str {
+      match self {
+         Target::Syslog => "syslog",
+         Target::Journal => "journald",
+      }
+   }
+
+   /// Returns the path of the socket the messages are sent to.
+   fn socket(&self) -> &'static str {
+      match self {
+         Target::Syslog => SYSLOG_SOCKET,
+         Target::Journal => JOURNAL_SOCKET,
+      }
+   }
+}
+
+/// The `Sink` struct sends messages to the syslog daemon or the journal.
+pub struct Sink {
+   target: Target,
+   path: PathBuf,
+   #[cfg(unix)]
+   socket: UnixDatagram,
+}
+
+impl Sink {
+   /// Sets up the sending of messages to the socket at the given path.
+   #[cfg(unix)]
+   pub fn open(target: Target, path: &Path) -> Result<Sink, String> {
+      let socket = UnixDatagram::unbound().map_err(|e| format!("cannot create socket ({})", e))?;
+      if !path.exists() {
+         return Err(format!("{} not found", path.display()));
+      }
+      Ok(Sink {
+         target,
+         path: path.to_path_buf(),
+         socket,
+      })
+   }
+
+   /// Sets up the sending of messages to the socket at the given path.
+   #[cfg(not(unix))]
+   pub fn open(_target: Target, _path: &Path) -> Result<Sink, String> {
+      Err(String::from(
+         "syslog and journald are only supported on unix",
+      ))
+   }
+
+   /// Sends a message along with the given fields (uppercase names).
+   pub fn send(&self, level: Level, message: &str, fields: &[(&str, &str)]) -> Result<(), String> {
+      let data = match self.target {
+         Target::Syslog => format_syslog(level, message, fields, std::process::id()).into_bytes(),
+         Target::Journal => format_journal(level, message, fields),
+      };
+      self.write(&data)
+   }
+
+   #[cfg(unix)]
+   fn write(&self, data: &[u8]) -> Result<(), String> {
+      self
+         .socket
+         .send_to(data, &self.path)
+         .map(|_| ())
+         .map_err(|e| format!("cannot write to {} ({})", self.path.display(), e))
+   }
+
+   #[cfg(not(unix))]
+   fn write(&self, _data: &[u8]) -> Result<(), String> {
+      Err(String::from(
+         "syslog and journald are only supported on unix",
+      ))
+   }
+}
+
+/// The `Logger` struct implements the `log` facade on top of a sink.
+pub struct Logger {
+   sink: Sink,
+   level: LevelFilter,
+}
+
+impl Log for Logger {
+   fn enabled(&self, metadata: &Metadata) -> bool {
+      metadata.level() <= self.level
+   }
+
+   fn log(&self, record: &Record) {
+      if self.enabled(record.metadata()) {
+         let message = record.args().to_string();
+         // there is nowhere else to report the problem
+         let _ = self
+            .sink
+            .send(record.level(), &message, &[("MODULE", record.target())]);
+      }
+   }
+
+   fn flush(&self) {}
+}
+
+/// Installs the logger for the given target.
+pub fn init(target: Target, level: LevelFilter) -> Result<(), String> {
+   let sink = Sink::open(target, Path::new(target.socket()))
+      .map_err(|e| format!("cannot log to {} ({})", target.name(), e))?;
+   let logger = Logger { sink, level };
+   log::set_boxed_logger(Box::new(logger)).map_err(|e| e.to_string())?;
+   log::set_max_level(level);
+   let _ = TARGET.set(target);
+   Ok(())
+}
+
+/// The `EventLogger` observer logs the delivery events with structured fields.
+pub struct EventLogger {
+   sink: Sink,
+}
+
+impl EventLogger {
+   /// Logs the events to the given sink.
+   pub fn new(sink: Sink) -> EventLogger {
+      EventLogger { sink }
+   }
+
+   /// Logs the events to the target the log goes to, if that is syslog or the journal.
+   pub fn for_log() -> Option<Result<EventLogger, String>> {
+      let target = TARGET.get()?;
+      Some(Sink::open(*target, Path::new(target.socket())).map(EventLogger::new))
+   }
+}
+
+impl Observer for EventLogger {
+   fn notify(&mut self, event: &Event) {
+      let (level, message, fields) = describe(event);
+      let fields: Vec<(&str, &str)> = fields.iter().map(|(k, v)| (*k, v.as_str())).collect();
+      let _ = self.sink.send(level, &message, &fields);
+   }
+}
+
+/// Returns the level, the message and the structured fields an event is logged with.
+fn describe(event: &Event) -> (Level, String, Vec<(&'static str, String)>) {
+   match event {
+      Event::CampaignStarted {
+         campaign_id,
+         recipients,
+      } => (
+         Level::Info,
+         format!(
+            "campaign {} started, {} recipient(s)",
+            campaign_id, recipients
+         ),
+         vec![
+            ("CAMPAIGN_ID", campaign_id.clone()),
+            ("STATUS", String::from("started")),
+         ],
+      ),
+      Event::MessageSent {
+         campaign_id,
+         recipient,
+         message_id,
+         ..
+      } => (
+         Level::Info,
+         format!("sent to {}", recipient),
+         vec![
+            ("CAMPAIGN_ID", campaign_id.clone()),
+            ("RECIPIENT", recipient.clone()),
+            ("STATUS", String::from("sent")),
+            ("MESSAGE_ID", message_id.clone()),
+         ],
+      ),
+      Event::MessageFailed {
+         campaign_id,
+         recipient,
+         message_id,
+         error,
+         ..
+      } => (
+         Level::Warn,
+         format!("sending to {} failed ({})", recipient, error),
+         vec![
+            ("CAMPAIGN_ID", campaign_id.clone()),
+            ("RECIPIENT", recipient.clone()),
+            ("STATUS", String::from("failed")),
+            ("MESSAGE_ID", message_id.clone()),
+            ("ERROR", error.clone()),
+         ],
+      ),
+      Event::CampaignFinished {
+         campaign_id,
+         sent,
+         failed,
+      } => (
+         Level::Info,
+         format!(
+            "campaign {} finished, sent: {}, failed: {}",
+            campaign_id, sent, failed
+         ),
+         vec![
+            ("CAMPAIGN_ID", campaign_id.clone()),
+            ("STATUS", String::from("finished")),
+         ],
+      ),
+   }
+}
+
+/// Returns the syslog severity of a log level.
+fn severity(level: Level) -> u8 {
+   match level {
+      Level::Error => 3,
+      Level::Warn => 4,
+      Level::Info => 6,
+      Level::Debug | Level::Trace => 7,
+   }
+}
+
+/// Formats a syslog message as per RFC 5424, the fields become structured data.
+pub fn format_syslog(level: Level, message: &str, fields: &[(&str, &str)], pid: u32) -> String {
+   let data = if fields.is_empty() {
+      String::from("-")
+   } else {
+      let params: Vec<String> = fields
+         .iter()
+         .map(|(name, value)| {
+            let value = value
+               .replace('\\', "\\\\")
+               .replace('"', "\\\"")
+               .replace(']', "\\]");
+            format!("{}=\"{}\"", name.to_lowercase(), value)
+         })
+         .collect();
+      format!("[{} {}]", SD_ID, params.join(" "))
+   };
+   format!(
+      "<{}>1 {} - rmt {} - {} {}",
+      FACILITY * 8 + severity(level),
+      Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+      pid,
+      data,
+      message
+   )
+}
+
+/// Formats a journal entry in the native protocol of `systemd-journald`, the fields are
+/// prefixed with `RMT_` so as not to clash with the journal's own.
+pub fn format_journal(level: Level, message: &str, fields: &[(&str, &str)]) -> Vec<u8> {
+   let priority = severity(level).to_string();
+   let mut entries = vec![
+      (String::from("MESSAGE"), message),
+      (String::from("PRIORITY"), priority.as_str()),
+      (String::from("SYSLOG_IDENTIFIER"), "rmt"),
+   ];
+   for (name, value) in fields.iter() {
+      entries.push((format!("RMT_{}", name), value));
+   }
+   let mut result = vec![];
+   for (name, value) in entries {
+      result.extend_from_slice(name.as_bytes());
+      if value.contains('\n') {
+         // multi-line values are preceded by their length
+         result.push(b'\n');
+         result.extend_from_slice(&(value.len() as u64).to_le_bytes());
+      } else {
+         result.push(b'=');
+      }
+      result.extend_from_slice(value.as_bytes());
+      result.push(b'\n');
+   }
+   result
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use tempfile::TempDir;
+
+   #[test]
+   fn parse_targets() {
+      assert_eq!(Ok(None), Target::parse("stderr"));
+      assert_eq!(Ok(Some(Target::Syslog)), Target::parse("syslog"));
+      assert_eq!(Ok(Some(Target::Journal)), Target::parse("journald"));
+      assert!(Target::parse("kafka").is_err());
+   }
+
+   #[test]
+   fn syslog_format() {
+      let fields = [("CAMPAIGN_ID", "spring-sale"), ("ERROR", "550 [x] \"no\"")];
+      let message = format_syslog(Level::Warn, "sending failed", &fields, 42);
+      assert!(message.starts_with("<20>1 20"));
+      assert!(message.ends_with(
+         "Z - rmt 42 - [rmt@32473 campaign_id=\"spring-sale\" error=\"550 [x\\] \\\"no\\\"\"] \
+          sending failed"
+      ));
+      assert!(format_syslog(Level::Debug, "hi", &[], 42).ends_with(" - rmt 42 - - hi"));
+   }
+
+   #[test]
+   fn journal_format() {
+      let entry = format_journal(
+         Level::Info,
+         "line 1\nline 2",
+         &[("RECIPIENT", "jd@example.com")],
+      );
+      let mut expected = b"MESSAGE\n".to_vec();
+      expected.extend_from_slice(&13u64.to_le_bytes());
+      expected.extend_from_slice(
+         b"line 1\nline 2\nPRIORITY=6\nSYSLOG_IDENTIFIER=rmt\nRMT_RECIPIENT=jd@example.com\n",
+      );
+      assert_eq!(expected, entry);
+   }
+
+   #[cfg(unix)]
+   #[test]
+   fn log_events() {
+      let dir = TempDir::new().expect("cannot create temp dir");
+      let path = dir.path().join("journal.sock");
+      let server = UnixDatagram::bind(&path).expect("cannot bind");
+      let sink = Sink::open(Target::Journal, &path).expect("cannot open sink");
+      let mut logger = EventLogger::new(sink);
+      logger.notify(&Event::MessageFailed {
+         campaign_id: String::from("spring-sale"),
+         recipient: String::from("jd@example.com"),
+         message_id: String::from("<spring-sale.1@example.com>"),
+         subject: String::from("Hello John!"),
+         variant: None,
+         error: String::from("mailbox full"),
+      });
+      let mut buffer = [0; 1024];
+      let size = server.recv(&mut buffer).expect("nothing received");
+      assert_eq!(
+         "MESSAGE=sending to jd@example.com failed (mailbox full)\nPRIORITY=4\n\
+          SYSLOG_IDENTIFIER=rmt\nRMT_CAMPAIGN_ID=spring-sale\nRMT_RECIPIENT=jd@example.com\n\
+          RMT_STATUS=failed\nRMT_MESSAGE_ID=<spring-sale.1@example.com>\nRMT_ERROR=mailbox full\n",
+         String::from_utf8_lossy(&buffer[..size])
+      );
+      assert!(Sink::open(Target::Syslog, &dir.path().join("missing")).is_err());
+   }
+}
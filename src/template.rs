@@ -1,77 +1,551 @@
-use crate::config::Recipient;
-use regex::Regex;
-use std::collections::HashSet;
+use crate::config::{self, Config, Recipient};
+use crate::greeting;
+use crate::tz;
+use chrono::Utc;
+use log::warn;
+use regex::{Captures, Regex};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 
+/// Matches the placeholders (`%KEY%` or `%KEY|filter%`) and the escaped percent signs (`%%`) in a
+/// template.
+pub const PLACEHOLDER: &str = r"%%|%(\w+)(?:\|(\w+))?%";
+
+/// The filters a placeholder may apply to its value (`%KEY|filter%`).
+pub const FILTERS: &[&str] = &["datetime", "time"];
+
+/// Matches the opening tag of a loop over the items of a list-valued key (`%FOR item IN items%`).
+pub const FOR: &str = r"%FOR (\w+) IN (\w+)%";
+
+/// The closing tag of a loop.
+pub const ENDFOR: &str = "%ENDFOR%";
+
+/// The keys rmt provides for every recipient.
+pub const AUTO_KEYS: &[&str] = &[
+   "_EA",
+   "_EVENT_END",
+   "_EVENT_START",
+   "_FN",
+   "_GREETING",
+   "_LN",
+   "_TN",
+   "_TV",
+];
+
+/// The words of the Tera syntax that are not variables.
+const TERA_KEYWORDS: &[&str] = &[
+   "and",
+   "as",
+   "block",
+   "break",
+   "continue",
+   "elif",
+   "else",
+   "endblock",
+   "endfilter",
+   "endfor",
+   "endif",
+   "endmacro",
+   "endraw",
+   "endset",
+   "extends",
+   "false",
+   "filter",
+   "for",
+   "if",
+   "import",
+   "in",
+   "include",
+   "is",
+   "loop",
+   "macro",
+   "none",
+   "not",
+   "or",
+   "raw",
+   "self",
+   "set",
+   "set_global",
+   "super",
+   "true",
+   "False",
+   "None",
+   "True",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+/// The `Engine` enum defines the syntax of the (body) templates.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Engine {
+   /// `%KEY%` placeholders and `%FOR%` loops (the default)
+   #[default]
+   Rmt,
+   /// The Jinja-like syntax of Tera (`{{ KEY }}`, `{% if %}` etc.)
+   Tera,
+}
+
 #[derive(Debug)]
 /// The `Template` struct holds the template data.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Template {
    /// This is the recipient's email address
    text: String,
    /// This is a map with miscellaneous optional metadata that was defined for the recipient in
    /// question
    keys: HashSet<String>,
+   /// How the template is rendered
+   engine: Engine,
 }
 
 impl PartialEq for Template {
    /// Makes it possible to compare instances of `Template`
    fn eq(&self, other: &Self) -> bool {
-      self.text == other.text && self.keys == other.keys
+      self.text == other.text && self.keys == other.keys && self.engine == other.engine
    }
 }
 
 pub fn instantiate(template_path: &str) -> Result<Template, io::Error> {
-   let contents = fs::read_to_string(template_path)?;
-   return Ok(new(&contents));
+   read(fs::File::open(template_path)?)
+}
+
+/// Reads the template from the given reader e.g. stdin, the text must be UTF-8.
+pub fn read<R: io::Read>(mut reader: R) -> Result<Template, io::Error> {
+   let mut contents = String::new();
+   reader.read_to_string(&mut contents)?;
+   Ok(new(&contents))
 }
 
 pub fn new(template: &str) -> Template {
    let mut result = Template {
       text: template.to_string(),
       keys: HashSet::new(),
+      engine: Engine::Rmt,
+   };
+   let re = Regex::new(PLACEHOLDER).expect("internal error, invalid regex");
+   let keys = |text: &str| -> Vec<String> {
+      re.captures_iter(text)
+         .filter_map(|cap| Some(cap.get(1)?.as_str().to_string()))
+         .collect()
    };
-   let re = Regex::new(r"%(\w+)%").expect("internal error, invalid regex");
-   for cap in re.captures_iter(template) {
-      result.keys.insert(cap[1].to_string());
+   for part in parts(template) {
+      match part {
+         Part::Text(text) => result.keys.extend(keys(text)),
+         Part::Loop {
+            var, list, body, ..
+         } => {
+            result.keys.insert(list.to_string());
+            result
+               .keys
+               .extend(keys(body).into_iter().filter(|k| k != var));
+         }
+      }
    }
    result
 }
 
-impl Template {
-   pub fn check_recipents(&self, recipients: &Vec<Recipient>) -> Result<(), Vec<String>> {
-      let auto_keys: HashSet<String> = ["_EA", "_FN", "_LN", "_TN", "_TV"]
-         .iter()
-         .map(|w| w.to_string())
-         .collect();
-      let user_defined_keys: HashSet<String> = self
-         .keys
+/// The `Part` enum holds a piece of a template.
+enum Part<'a> {
+   /// Text with placeholders
+   Text(&'a str),
+   /// A loop, the `body` is rendered for every item of the `list` key with the item as `var`
+   Loop {
+      var: &'a str,
+      list: &'a str,
+      body: &'a str,
+      /// The whole loop including the tags
+      source: &'a str,
+   },
+}
+
+/// Splits the template text into text and loops. The loop tags that stand alone on a line take
+/// the line break with them, tags without a counterpart are left in the text.
+fn parts(text: &str) -> Vec<Part<'_>> {
+   let re = Regex::new(FOR).expect("internal error, invalid regex");
+   // the length of the line break following the tag, if it stands alone on its line
+   let alone = |start: usize, end: usize| {
+      if start > 0 && !text[..start].ends_with('\n') {
+         0
+      } else if text[end..].starts_with("\r\n") {
+         2
+      } else if text[end..].starts_with('\n') {
+         1
+      } else {
+         0
+      }
+   };
+   let mut result = vec![];
+   let mut offset = 0;
+   while let Some(caps) = re.captures(&text[offset..]) {
+      let open = caps.get(0).unwrap();
+      let (start, open_end) = (offset + open.start(), offset + open.end());
+      let close = match text[open_end..].find(ENDFOR) {
+         Some(pos) => open_end + pos,
+         None => break,
+      };
+      let end = close + ENDFOR.len();
+      let end = end + alone(close, end);
+      result.push(Part::Text(&text[offset..start]));
+      result.push(Part::Loop {
+         var: caps.get(1).unwrap().as_str(),
+         list: caps.get(2).unwrap().as_str(),
+         body: &text[open_end + alone(start, open_end)..close],
+         source: &text[start..end],
+      });
+      offset = end;
+   }
+   result.push(Part::Text(&text[offset..]));
+   result
+}
+
+/// Returns the items of a list value, they are separated by commas (a semicolon would start a
+/// comment in the config file).
+fn items(value: &str) -> Vec<&str> {
+   value
+      .split(',')
+      .map(|i| i.trim())
+      .filter(|i| !i.is_empty())
+      .collect()
+}
+
+/// Returns the values that may be referenced in a template for the given recipient i.e. the
+/// recipient's data (falling back to the config's defaults, typed data formatted as per the
+/// locale) plus the "automatic" data items generated by rmt.
+pub fn values(cfg: &Config, rcp: &Recipient) -> HashMap<String, String> {
+   let mut result = cfg.defaults.clone();
+   result.extend(rcp.data.iter().map(|(k, v)| (k.clone(), v.clone())));
+   // the typed data is formatted as per the locale
+   for (key, data_type) in cfg.schema.iter() {
+      if let Some(val) = result.get_mut(key) {
+         *val = data_type.format(val, &cfg.locale);
+      }
+   }
+   result.insert(String::from("_EA"), rcp.email.clone());
+   result.insert(
+      String::from("_FN"),
+      rcp.names.first().cloned().unwrap_or_default(),
+   );
+   result.insert(
+      String::from("_LN"),
+      rcp.names
          .iter()
+         .skip(1)
          .cloned()
-         .filter(|k| !auto_keys.contains(k))
+         .collect::<Vec<String>>()
+         .join(" "),
+   );
+   result.insert(String::from("_GREETING"), greeting::salutation(cfg, rcp));
+   if let Some(ref event) = cfg.event {
+      result.insert(String::from("_EVENT_START"), event.start.to_rfc3339());
+      result.insert(String::from("_EVENT_END"), event.end.to_rfc3339());
+   }
+   result.insert(String::from("_TN"), cfg.tool_name.clone());
+   result.insert(String::from("_TV"), cfg.tool_version.clone());
+   result
+}
+
+/// Replaces the `%KEY%` placeholders in `text` with the corresponding values and `%%` with a
+/// literal `%`, the loops are rendered once per item. Placeholders for which there is no value
+/// (and loops over keys without a value) are left untouched.
+pub fn expand(text: &str, values: &HashMap<String, String>) -> String {
+   substitute(text, values, false)
+}
+
+/// Like `expand` but the substituted values are HTML-escaped.
+pub fn expand_html(text: &str, values: &HashMap<String, String>) -> String {
+   substitute(text, values, true)
+}
+
+/// Implements `expand` and `expand_html`.
+fn substitute(text: &str, values: &HashMap<String, String>, html: bool) -> String {
+   let re = Regex::new(PLACEHOLDER).expect("internal error, invalid regex");
+   let replace = |text: &str, values: &HashMap<String, String>| {
+      re.replace_all(text, |caps: &Captures| {
+         let val = match (caps.get(1), caps.get(2)) {
+            (None, _) => return String::from("%"),
+            (Some(key), None) => values.get(key.as_str()).cloned(),
+            (Some(key), Some(f)) => values
+               .get(key.as_str())
+               .and_then(|v| filter(v, f.as_str(), values)),
+         };
+         match val {
+            Some(val) if html => escape_html(&val),
+            Some(val) => val,
+            None => caps[0].to_string(),
+         }
+      })
+      .into_owned()
+   };
+   let mut result = String::with_capacity(text.len());
+   for part in parts(text) {
+      match part {
+         Part::Text(text) => result.push_str(&replace(text, values)),
+         Part::Loop {
+            var,
+            list,
+            body,
+            source,
+         } => match values.get(list) {
+            Some(value) => {
+               let mut values = values.clone();
+               for item in items(value) {
+                  values.insert(var.to_string(), item.to_string());
+                  result.push_str(&replace(body, &values));
+               }
+            }
+            None => result.push_str(source),
+         },
+      }
+   }
+   result
+}
+
+/// Applies the filter to the value, `None` if that is not possible. The `time` and `datetime`
+/// filters show a time (e.g. `2024-05-01T16:00:00Z`) in the time zone of the recipient's `tz`
+/// datum, or in the one it was given in if there is none.
+fn filter(val: &str, filter: &str, values: &HashMap<String, String>) -> Option<String> {
+   let time = config::parse_local_time(val)?;
+   let time = match values.get("tz") {
+      Some(name) => tz::local(tz::lookup(name).ok()?, time.with_timezone(&Utc)),
+      None => time,
+   };
+   match filter {
+      "time" => Some(time.format("%H:%M").to_string()),
+      "datetime" => Some(time.format("%Y-%m-%d %H:%M").to_string()),
+      _ => None,
+   }
+}
+
+/// Renders the text with Tera, the values are HTML-escaped if `html` is set.
+fn render_tera(text: &str, values: &HashMap<String, String>, html: bool) -> Result<String, String> {
+   let mut context = tera::Context::new();
+   for (key, value) in values.iter() {
+      context.insert(key.as_str(), value);
+   }
+   tera::Tera::one_off(text, &context, html).map_err(|e| tera_error(&e))
+}
+
+/// Returns the message of a Tera error followed by the messages of its causes.
+fn tera_error(error: &tera::Error) -> String {
+   let mut result = error.to_string();
+   let mut source = std::error::Error::source(error);
+   while let Some(cause) = source {
+      result.push_str(&format!(": {}", cause));
+      source = cause.source();
+   }
+   result
+}
+
+/// Returns the variables a Tera template refers to. The ones it defines itself (loop variables,
+/// `set`), the optional ones (tested with `is defined` or given a `default` anywhere in the
+/// template), attributes, filters, tests and functions are left out.
+fn tera_keys(text: &str) -> HashSet<String> {
+   let comment = Regex::new(r"(?s)\{#.*?#\}").unwrap();
+   let block = Regex::new(r"(?s)\{\{(.*?)\}\}|\{%(.*?)%\}").unwrap();
+   let token =
+      Regex::new(r#""(?:[^"\\]|\\.)*"|'[^']*'|`[^`]*`|[A-Za-z_]\w*|\d+(?:\.\d+)?|==|!=|<=|>=|\S"#)
+         .unwrap();
+   let is_ident = |t: &str| t.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+   let text = comment.replace_all(text, "");
+   let mut used = HashSet::new();
+   let mut defined = HashSet::new();
+   let mut optional = HashSet::new();
+   for caps in block.captures_iter(&text) {
+      let inner = caps.get(1).or_else(|| caps.get(2)).unwrap().as_str();
+      let tokens: Vec<&str> = token
+         .find_iter(inner.trim_matches('-'))
+         .map(|m| m.as_str())
          .collect();
-      let mut errors = vec![];
-      for rcp in recipients {
-         let rcp_keys: HashSet<String> = rcp.data.keys().cloned().collect();
-         if !user_defined_keys.is_subset(&rcp_keys) {
-            let mut missing_keys: Vec<String> = user_defined_keys
+      let at = |i: usize| tokens.get(i).copied().unwrap_or_default();
+      match at(0) {
+         "for" => defined.extend(
+            tokens[1..]
                .iter()
-               .cloned()
-               .filter(|k| !rcp_keys.contains(k))
-               .collect();
-            missing_keys.sort();
-            errors.push(format!(
-               "{} is missing the following key(s): {}",
-               rcp.email,
-               missing_keys.as_slice().join(", ")
-            ));
+               .take_while(|t| **t != "in")
+               .filter(|t| is_ident(t)),
+         ),
+         "set" | "set_global" => {
+            defined.insert(at(1));
+         }
+         "block" | "extends" | "import" | "include" | "macro" => continue,
+         _ => (),
+      }
+      for (i, t) in tokens.iter().enumerate() {
+         if !is_ident(t) || TERA_KEYWORDS.contains(t) {
+            continue;
+         }
+         let prev = if i > 0 { at(i - 1) } else { "" };
+         let tested = prev == "is" || (prev == "not" && i > 1 && at(i - 2) == "is");
+         if prev == "." || prev == "|" || tested || ["(", "="].contains(&at(i + 1)) {
+            continue;
+         }
+         if (at(i + 1) == "is"
+            && (["defined", "undefined"].contains(&at(i + 2))
+               || (at(i + 2) == "not" && at(i + 3) == "defined")))
+            || (at(i + 1) == "|" && at(i + 2) == "default")
+         {
+            optional.insert(t.to_string());
+         }
+         used.insert(t.to_string());
+      }
+   }
+   used
+      .into_iter()
+      .filter(|k| !defined.contains(k.as_str()) && !optional.contains(k))
+      .collect()
+}
+
+/// Escapes the characters that have a special meaning in HTML.
+pub fn escape_html(text: &str) -> String {
+   let mut result = String::with_capacity(text.len());
+   for c in text.chars() {
+      match c {
+         '&' => result.push_str("&amp;"),
+         '<' => result.push_str("&lt;"),
+         '>' => result.push_str("&gt;"),
+         '"' => result.push_str("&quot;"),
+         '\'' => result.push_str("&#39;"),
+         _ => result.push(c),
+      }
+   }
+   result
+}
+
+impl Template {
+   /// Returns the template text.
+   pub fn text(&self) -> &str {
+      &self.text
+   }
+
+   /// Makes the template use the given engine, fails if the template is not valid for it.
+   pub fn with_engine(self, engine: Engine) -> Result<Template, String> {
+      match engine {
+         Engine::Rmt => Ok(new(&self.text)),
+         Engine::Tera => {
+            tera::Tera::default()
+               .add_raw_template("template", &self.text)
+               .map_err(|e| format!("invalid Tera template ({})", tera_error(&e)))?;
+            Ok(Template {
+               keys: tera_keys(&self.text),
+               text: self.text,
+               engine,
+            })
          }
       }
-      if errors.len() > 0 {
-         return Err(errors);
+   }
+
+   /// Renders the template for the given recipient.
+   pub fn render(&self, cfg: &Config, rcp: &Recipient) -> String {
+      self.render_values(&values(cfg, rcp), false)
+   }
+
+   /// Renders the (HTML) template for the given recipient, the substituted values are HTML-escaped.
+   pub fn render_html(&self, cfg: &Config, rcp: &Recipient) -> String {
+      self.render_values(&values(cfg, rcp), true)
+   }
+
+   /// Renders the template with the given values. Tera templates that fail to render (which
+   /// `check_recipient` reports beforehand) are returned as they are.
+   fn render_values(&self, values: &HashMap<String, String>, html: bool) -> String {
+      match self.engine {
+         Engine::Rmt if html => expand_html(&self.text, values),
+         Engine::Rmt => expand(&self.text, values),
+         Engine::Tera => render_tera(&self.text, values, html).unwrap_or_else(|err| {
+            warn!("cannot render the template ({})", err);
+            self.text.clone()
+         }),
+      }
+   }
+
+   /// Returns the keys of the placeholders in the template.
+   pub fn keys(&self) -> &HashSet<String> {
+      &self.keys
+   }
+
+   /// Returns the paths of the images referenced with `%_CID:path%` in the (HTML) template, in
+   /// the order of their first appearance.
+   pub fn cid_references(&self) -> Vec<String> {
+      let re = Regex::new(r"%_CID:([^%]+)%").expect("internal error, invalid regex");
+      let mut result: Vec<String> = vec![];
+      for cap in re.captures_iter(&self.text) {
+         let path = cap[1].trim().to_string();
+         if !result.contains(&path) {
+            result.push(path);
+         }
+      }
+      result
+   }
+
+   /// Checks whether the recipients have all the data needed by the template, keys with a default
+   /// value are satisfied anyway.
+   pub fn check_recipents(
+      &self,
+      recipients: &[Recipient],
+      defaults: &HashMap<String, String>,
+   ) -> Result<(), Vec<String>> {
+      let errors: Vec<String> = recipients
+         .iter()
+         .filter_map(|rcp| self.check_recipient(rcp, defaults).err())
+         .collect();
+      if !errors.is_empty() {
+         Err(errors)
       } else {
+         Ok(())
+      }
+   }
+
+   /// Checks whether the recipient has all the data needed by the template.
+   pub fn check_recipient(
+      &self,
+      rcp: &Recipient,
+      defaults: &HashMap<String, String>,
+   ) -> Result<(), String> {
+      let mut missing_keys: Vec<&str> = self
+         .keys
+         .iter()
+         .filter(|k| {
+            !AUTO_KEYS.contains(&k.as_ref())
+               && !rcp.data.contains_key(*k)
+               && !defaults.contains_key(*k)
+         })
+         .map(|k| k.as_ref())
+         .collect();
+      if missing_keys.is_empty() {
+         if self.engine == Engine::Tera {
+            // the values of the automatic keys do not matter for finding errors
+            let mut values = defaults.clone();
+            values.extend(AUTO_KEYS.iter().map(|k| (k.to_string(), String::new())));
+            values.extend(rcp.data.iter().map(|(k, v)| (k.clone(), v.clone())));
+            render_tera(&self.text, &values, false).map_err(|err| {
+               format!(
+                  "the template cannot be rendered for {} ({})",
+                  rcp.email, err
+               )
+            })?;
+         }
          return Ok(());
       }
+      missing_keys.sort();
+      Err(format!(
+         "{} is missing the following key(s): {}",
+         rcp.email,
+         missing_keys.join(", ")
+      ))
+   }
+
+   /// Moves the recipients that lack data needed by the template to the config's quarantine.
+   pub fn quarantine(&self, cfg: &mut Config) {
+      let defaults = &cfg.defaults;
+      let (valid, invalid): (Vec<Recipient>, Vec<Recipient>) = cfg
+         .recipients
+         .drain(..)
+         .partition(|rcp| self.check_recipient(rcp, defaults).is_ok());
+      cfg.recipients = valid;
+      cfg.quarantine.extend(
+         invalid
+            .iter()
+            .filter_map(|rcp| self.check_recipient(rcp, defaults).err()),
+      );
    }
 }
 
@@ -80,6 +554,8 @@ mod tests {
    use super::*;
    use crate::config::sa;
    use crate::config::sm;
+   use crate::config::Event;
+   use crate::testing;
 
    /// Constructs a set of `String` from an array of string slices.
    fn ss(a: &[&str]) -> HashSet<String> {
@@ -91,6 +567,7 @@ mod tests {
       let expected = Template {
          text: String::from(""),
          keys: HashSet::new(),
+         engine: Engine::Rmt,
       };
       assert_eq!(expected, new(""));
    }
@@ -101,6 +578,7 @@ mod tests {
       let expected = Template {
          text: String::from(template),
          keys: HashSet::new(),
+         engine: Engine::Rmt,
       };
       assert_eq!(expected, new(template));
    }
@@ -118,16 +596,19 @@ Sent with rmt version 0.1.2, see https://301.mx/rmt for details"#;
       let expected = Template {
          text: String::from(template),
          keys: ss(&["EA", "FN", "LN", "ORG"]),
+         engine: Engine::Rmt,
       };
       assert_eq!(expected, new(template));
    }
 
    #[test]
    fn new_with_invalid_keys() {
+      // `%%` is an escaped percent sign, `%%HM%` renders as `%HM%`
       let template = "Hello Sir %FN%! How about %FN or EA% / %%HM%??";
       let expected = Template {
          text: String::from(template),
-         keys: ss(&["FN", "HM"]),
+         keys: ss(&["FN"]),
+         engine: Engine::Rmt,
       };
       assert_eq!(expected, new(template));
    }
@@ -138,16 +619,18 @@ Sent with rmt version 0.1.2, see https://301.mx/rmt for details"#;
       let expected = Template {
          text: String::from(template),
          keys: ss(&["FN", "HM"]),
+         engine: Engine::Rmt,
       };
       assert_eq!(expected, new(template));
    }
 
    #[test]
    fn new_with_keys_containing_digits() {
-      let template = "Hello Sir %FN%! How about %FN or EA% / %%H3%??";
+      let template = "Hello Sir %FN%! How about %FN or EA% / % %H3%??";
       let expected = Template {
          text: String::from(template),
          keys: ss(&["FN", "H3"]),
+         engine: Engine::Rmt,
       };
       assert_eq!(expected, new(template));
    }
@@ -158,181 +641,442 @@ Sent with rmt version 0.1.2, see https://301.mx/rmt for details"#;
       let expected = Template {
          text: String::from(template),
          keys: ss(&["FN", "H3"]),
+         engine: Engine::Rmt,
       };
       assert_eq!(expected, new(template));
    }
 
    #[test]
    fn check_recipents_with_1_missing_key() {
-      let mut recipients = Vec::new();
-      recipients.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[
-            ("ORG", "EFF"),
-            ("TITLE", "PhD"),
-            ("cc", "bl@kf.io,info@ex.org"),
-         ]),
-      });
-      recipients.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[("ORG", "Disney")]),
-      });
+      let recipients = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[
+               ("ORG", "EFF"),
+               ("TITLE", "PhD"),
+               ("cc", "bl@kf.io,info@ex.org"),
+            ]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[("ORG", "Disney")]),
+         },
+      ];
       let template = new("Missing key: %MK%");
       let expected: Vec<String> = sa(&[
          "daisy@example.com is missing the following key(s): MK",
          "jd@example.com is missing the following key(s): MK",
          "mm@gmail.com is missing the following key(s): MK",
       ]);
-      assert_eq!(Err(expected), template.check_recipents(&recipients));
+      assert_eq!(
+         Err(expected),
+         template.check_recipents(&recipients, &HashMap::new())
+      );
    }
 
    #[test]
    fn check_recipents_with_multiple_missing_key() {
-      let mut recipients = Vec::new();
-      recipients.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[("MK", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[
-            ("ORG", "EFF"),
-            ("TITLE", "PhD"),
-            ("M2", "bl@kf.io,info@ex.org"),
-         ]),
-      });
-      recipients.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[("ORG", "Disney")]),
-      });
+      let recipients = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[("MK", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[
+               ("ORG", "EFF"),
+               ("TITLE", "PhD"),
+               ("M2", "bl@kf.io,info@ex.org"),
+            ]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[("ORG", "Disney")]),
+         },
+      ];
       let template = new("Missing key: %MK% %M2% %m3%");
       let expected: Vec<String> = sa(&[
          "daisy@example.com is missing the following key(s): M2, m3",
          "jd@example.com is missing the following key(s): MK, m3",
          "mm@gmail.com is missing the following key(s): M2, MK, m3",
       ]);
-      assert_eq!(Err(expected), template.check_recipents(&recipients));
+      assert_eq!(
+         Err(expected),
+         template.check_recipents(&recipients, &HashMap::new())
+      );
    }
 
    #[test]
    fn check_recipents_happy_case() {
-      let mut recipients = Vec::new();
-      recipients.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[("ORG", "EFF"), ("TITLE", "PhD")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[("ORG", "Disney")]),
-      });
+      let recipients = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[("ORG", "EFF"), ("TITLE", "PhD")]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[("ORG", "Disney")]),
+         },
+      ];
       let template = new("only key: %ORG%");
-      assert_eq!(Ok(()), template.check_recipents(&recipients));
+      assert_eq!(
+         Ok(()),
+         template.check_recipents(&recipients, &HashMap::new())
+      );
    }
 
    #[test]
    fn check_recipents_happy_case_no_keys_in_template() {
-      let mut recipients = Vec::new();
-      recipients.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[("ORG", "EFF"), ("TITLE", "PhD")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[("ORG", "Disney")]),
-      });
+      let recipients = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[("ORG", "NASA"), ("TITLE", "Dr."), ("cc", "+inc@gg.org")]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[("ORG", "EFF"), ("TITLE", "PhD")]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[("ORG", "Disney")]),
+         },
+      ];
       let template = new("no keys in template");
-      assert_eq!(Ok(()), template.check_recipents(&recipients));
+      assert_eq!(
+         Ok(()),
+         template.check_recipents(&recipients, &HashMap::new())
+      );
    }
 
    #[test]
    fn check_recipents_happy_case_with_auto_keys_only() {
-      let mut recipients = Vec::new();
-      recipients.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[]),
-      });
-      recipients.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[]),
-      });
-      recipients.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[]),
-      });
+      let recipients = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[]),
+         },
+      ];
       let template = new("auto keys only: %_FN%, %_LN%, %_EA% !!");
-      assert_eq!(Ok(()), template.check_recipents(&recipients));
+      assert_eq!(
+         Ok(()),
+         template.check_recipents(&recipients, &HashMap::new())
+      );
    }
 
    #[test]
    fn check_recipents_happy_case_with_mixed_keys() {
-      let mut recipients = Vec::new();
-      recipients.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[("_USER_DEFINED", "dec")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[("_USER_DEFINED", "jec")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[("_USER_DEFINED", "mgc")]),
-      });
+      let recipients = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[("_USER_DEFINED", "dec")]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[("_USER_DEFINED", "jec")]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[("_USER_DEFINED", "mgc")]),
+         },
+      ];
       let template = new("auto keys only: %_FN%, %_USER_DEFINED%!!");
-      assert_eq!(Ok(()), template.check_recipents(&recipients));
+      assert_eq!(
+         Ok(()),
+         template.check_recipents(&recipients, &HashMap::new())
+      );
    }
 
    #[test]
    fn check_recipents_failure_with_mixed_keys() {
-      let mut recipients = Vec::new();
-      recipients.push(Recipient {
-         email: String::from("daisy@example.com"),
-         names: sa(&["Daisy", "Lila"]),
-         data: sm(&[("USER_DEFINED", "dec")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("jd@example.com"),
-         names: sa(&["John", "Doe", "Jr."]),
-         data: sm(&[("_USER_DEFINED", "jec")]),
-      });
-      recipients.push(Recipient {
-         email: String::from("mm@gmail.com"),
-         names: sa(&["Mickey", "Mouse"]),
-         data: sm(&[("_USER_DEFINED", "mgc")]),
-      });
+      let recipients = vec![
+         Recipient {
+            email: String::from("daisy@example.com"),
+            names: sa(&["Daisy", "Lila"]),
+            data: sm(&[("USER_DEFINED", "dec")]),
+         },
+         Recipient {
+            email: String::from("jd@example.com"),
+            names: sa(&["John", "Doe", "Jr."]),
+            data: sm(&[("_USER_DEFINED", "jec")]),
+         },
+         Recipient {
+            email: String::from("mm@gmail.com"),
+            names: sa(&["Mickey", "Mouse"]),
+            data: sm(&[("_USER_DEFINED", "mgc")]),
+         },
+      ];
       let template = new("auto keys only: %_FN%, %_USER_DEFINED%!!");
       let expected: Vec<String> =
          sa(&["daisy@example.com is missing the following key(s): _USER_DEFINED"]);
-      assert_eq!(Err(expected), template.check_recipents(&recipients));
+      assert_eq!(
+         Err(expected),
+         template.check_recipents(&recipients, &HashMap::new())
+      );
+   }
+
+   #[test]
+   fn quarantine_recipients_with_missing_keys() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[("ORG", "EFF")]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[]),
+      ];
+      new("Hi %_FN% at %ORG%").quarantine(&mut cfg);
+      assert_eq!(1, cfg.recipients.len());
+      assert_eq!("jd@example.com", cfg.recipients[0].email);
+      assert_eq!(
+         sa(&["mm@gmail.com is missing the following key(s): ORG"]),
+         cfg.quarantine
+      );
+   }
+
+   #[test]
+   fn defaults_satisfy_missing_keys() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.defaults = sm(&[("ORG", "our community")]);
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[("ORG", "EFF")]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[]),
+      ];
+      let template = new("Hi %_FN% at %ORG%");
+      assert_eq!(
+         Ok(()),
+         template.check_recipents(&cfg.recipients, &cfg.defaults)
+      );
+      assert_eq!("Hi John at EFF", template.render(&cfg, &cfg.recipients[0]));
+      assert_eq!(
+         "Hi Mickey at our community",
+         template.render(&cfg, &cfg.recipients[1])
+      );
+   }
+
+   #[test]
+   fn cid_references_in_order() {
+      let template = new(
+         "<img src=\"%_CID:img/logo.png%\"> %_FN% <img src=\"%_CID:sig.gif%\"> %_CID:img/logo.png%",
+      );
+      assert_eq!(sa(&["img/logo.png", "sig.gif"]), template.cid_references());
+      assert!(new("Hi %_FN%").cid_references().is_empty());
+   }
+
+   #[test]
+   fn escape_html_happy_case() {
+      assert_eq!(
+         "Tom &amp; Jerry &lt;b&gt; &quot;x&quot; &#39;y&#39;",
+         escape_html(r#"Tom & Jerry <b> "x" 'y'"#)
+      );
+   }
+
+   #[test]
+   fn render_html_escapes_values() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("ORG", "AT&T")],
+      )];
+      let template = new("<p>%_FN% @ %ORG%</p>");
+      assert_eq!(
+         "<p>John @ AT&amp;T</p>",
+         template.render_html(&cfg, &cfg.recipients[0])
+      );
+      assert_eq!(
+         "<p>John @ AT&T</p>",
+         template.render(&cfg, &cfg.recipients[0])
+      );
+   }
+
+   #[test]
+   fn read_from_reader() {
+      let tmpl = read("Hi %_FN% from %ORG%\n".as_bytes()).expect("cannot read the template");
+      assert_eq!(new("Hi %_FN% from %ORG%\n"), tmpl);
+      assert!(read(&[0xffu8, 0xfe][..]).is_err());
+   }
+
+   #[test]
+   fn escaped_percent_signs() {
+      let template = new("100%% free for %ORG%, %%ORG%% is literal");
+      assert_eq!(ss(&["ORG"]), template.keys);
+      let values: HashMap<String, String> = vec![(String::from("ORG"), String::from("EFF"))]
+         .into_iter()
+         .collect();
+      assert_eq!(
+         "100% free for EFF, %ORG% is literal",
+         expand(template.text(), &values)
+      );
+   }
+
+   #[test]
+   fn time_filters() {
+      let template = new("Starts at %START|time% (%START|datetime%), %ORG|time% %START|upper%");
+      assert_eq!(ss(&["START", "ORG"]), template.keys);
+      let mut values: HashMap<String, String> = vec![
+         (
+            String::from("START"),
+            String::from("2024-06-01T16:00:00+02:00"),
+         ),
+         (String::from("ORG"), String::from("EFF")),
+      ]
+      .into_iter()
+      .collect();
+      assert_eq!(
+         "Starts at 16:00 (2024-06-01 16:00), %ORG|time% %START|upper%",
+         expand(template.text(), &values)
+      );
+      values.insert(String::from("tz"), String::from("No/Such_Zone"));
+      assert_eq!(
+         "Starts at %START|time%",
+         expand("Starts at %START|time%", &values)
+      );
+      values.insert(String::from("tz"), String::from("America/New_York"));
+      assert_eq!(
+         "Starts at 10:00 (2024-06-01 10:00)",
+         expand("Starts at %START|time% (%START|datetime%)", &values)
+      );
+   }
+
+   #[test]
+   fn event_keys() {
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.event = Some(Event {
+         summary: String::from("Webinar"),
+         start: config::parse_time("2024-06-01T16:00:00Z").unwrap(),
+         end: config::parse_time("2024-06-01T17:00:00Z").unwrap(),
+         location: None,
+         description: None,
+         organizer: cfg.gdata.from.clone(),
+      });
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[]);
+      let tmpl = new("%_EVENT_START|time%-%_EVENT_END|time% UTC");
+      assert_eq!(Ok(()), tmpl.check_recipient(&rcp, &cfg.defaults));
+      assert_eq!("16:00-17:00 UTC", tmpl.render(&cfg, &rcp));
+   }
+
+   #[test]
+   fn loops_over_list_values() {
+      let text = "Your tickets:\n%FOR t IN TICKETS%\n- %t% (%_FN%)\n%ENDFOR%\nSeats: %FOR s IN SEATS%[%s%]%ENDFOR%.\n";
+      let template = new(text);
+      assert_eq!(ss(&["TICKETS", "SEATS", "_FN"]), template.keys);
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[("TICKETS", "A-1, B&2 ,"), ("SEATS", "")],
+      )];
+      assert_eq!(
+         "Your tickets:\n- A-1 (John)\n- B&2 (John)\nSeats: .\n",
+         template.render(&cfg, &cfg.recipients[0])
+      );
+      assert!(template
+         .render_html(&cfg, &cfg.recipients[0])
+         .contains("- B&amp;2 (John)\n"));
+   }
+
+   #[test]
+   fn loops_over_list_values_from_config_file() {
+      let dir = tempfile::TempDir::new().expect("cannot create temp dir");
+      let path = dir.path().join("tickets.ini");
+      fs::write(
+         &path,
+         "[general]\nFrom=rts@example.com\nSubject=hi\n[recipients]\n\
+          jd@example.com=John Doe|TICKETS:-a,b , c\n",
+      )
+      .unwrap();
+      let cfg = config::instantiate(&[path.to_str().unwrap()], None, "rmt", "0.1.2")
+         .expect("Failed to load config");
+      let template = new("%FOR t IN TICKETS%\n- %t%\n%ENDFOR%");
+      assert_eq!("- a\n- b\n- c\n", template.render(&cfg, &cfg.recipients[0]));
+   }
+
+   #[test]
+   fn loops_without_values_or_end() {
+      let values = HashMap::new();
+      let text = "%FOR t IN TICKETS%- %t%%ENDFOR% %FOR x IN Y%";
+      assert_eq!(text, expand(text, &values));
+      assert_eq!(ss(&["TICKETS"]), new(text).keys);
+   }
+
+   #[test]
+   fn tera_templates() {
+      let text =
+         "{# greeting #}Dear {% if TITLE is defined %}{{ TITLE }} {% endif %}{{ _LN | upper }},\n\
+         {% for t in TICKETS | split(pat=\";\") %}- {{ t | trim }} ({{ loop.index }})\n{% endfor %}\
+         {% set org = ORG | default(value=\"you\") %}{{ org }} {{ NOTE | truncate(length=5) }}";
+      let template = new(text)
+         .with_engine(Engine::Tera)
+         .expect("invalid template");
+      assert_eq!(ss(&["_LN", "TICKETS", "NOTE"]), template.keys);
+      let mut cfg = testing::config("rts@example.com", "hello");
+      cfg.recipients = vec![testing::recipient(
+         "jd@example.com",
+         "John Doe",
+         &[
+            ("TITLE", "Dr."),
+            ("TICKETS", "A-1; B&2"),
+            ("NOTE", "long note"),
+         ],
+      )];
+      assert_eq!(
+         "Dear Dr. DOE,\n- A-1 (1)\n- B&2 (2)\nyou long …",
+         template.render(&cfg, &cfg.recipients[0])
+      );
+      assert!(template
+         .render_html(&cfg, &cfg.recipients[0])
+         .contains("- B&amp;2 (2)"));
+      let rcp = testing::recipient("mm@gmail.com", "Mickey Mouse", &[("TICKETS", "")]);
+      assert_eq!(
+         Err(String::from(
+            "mm@gmail.com is missing the following key(s): NOTE"
+         )),
+         template.check_recipient(&rcp, &HashMap::new())
+      );
+   }
+
+   #[test]
+   fn tera_template_errors() {
+      let error = new("Hi {{ _FN").with_engine(Engine::Tera).unwrap_err();
+      assert!(error.starts_with("invalid Tera template ("), "{}", error);
+      let template = new("{{ N / 0 }}").with_engine(Engine::Tera).unwrap();
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[("N", "x")]);
+      let error = template.check_recipient(&rcp, &HashMap::new()).unwrap_err();
+      assert!(
+         error.starts_with("the template cannot be rendered for jd@example.com ("),
+         "{}",
+         error
+      );
    }
 }
@@ -0,0 +1,193 @@
+/// The `testing` module is an in-memory test harness for rmt and the users of the library.
+///
+/// It provides a mock transport that records the composed emails instead of sending them and
+/// helpers to build configs and recipients in code, so that full message output can be asserted
+/// on without network access or temporary config files.
+use crate::address::{self, Address};
+use crate::config::{self, Config, Recipient, Security, Smtp};
+use crate::events::Event;
+use crate::message::Message;
+use crate::template;
+use crate::transport::{self, Transport};
+use std::collections::HashMap;
+
+/// Parses a comma-separated list of addresses e.g. for the *Cc* header, panics if one of them is
+/// invalid.
+pub fn addresses(list: &str) -> Vec<Address> {
+   address::split_list(list)
+      .into_iter()
+      .map(|a| Address::parse(a).expect("invalid address"))
+      .collect()
+}
+
+/// Builds a config with the given *From* address and *Subject*, no recipients and defaults for
+/// everything else.
+pub fn config(from: &str, subject: &str) -> Config {
+   Config::builder()
+      .from(from)
+      .subject(subject)
+      .campaign_id("test-campaign")
+      .build()
+      .expect("invalid config")
+}
+
+/// Builds a recipient, the `names` are separated by whitespace.
+pub fn recipient(email: &str, names: &str, data: &[(&str, &str)]) -> Recipient {
+   Recipient {
+      email: email.to_string(),
+      names: names.split_whitespace().map(|n| n.to_string()).collect(),
+      data: config::sm(data),
+   }
+}
+
+/// Builds the *smtp* settings for an unauthenticated server on localhost without any limits.
+pub fn smtp() -> Smtp {
+   Smtp {
+      host: String::from("localhost"),
+      port: 25,
+      security: Security::None,
+      username: None,
+      password: None,
+      keyring: None,
+      rate: None,
+      domain_rates: HashMap::new(),
+      jitter: None,
+      group_by_domain: false,
+      domain_burst: None,
+      connections: 1,
+      dsn_notify: None,
+      dsn_ret: None,
+      smtputf8: false,
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `MockTransport` records the emails it is asked to send.
+pub struct MockTransport {
+   /// The emails that were "sent" successfully, in order
+   pub messages: Vec<Message>,
+   /// The rendered emails that were "sent" successfully with their envelopes, in order
+   pub raw: Vec<(Vec<String>, Vec<u8>)>,
+   /// The recipients for which sending fails
+   failures: Vec<String>,
+}
+
+impl MockTransport {
+   /// Creates a mock transport that "sends" all emails successfully.
+   pub fn new() -> MockTransport {
+      MockTransport::default()
+   }
+
+   /// Makes sending the email to the given recipient fail.
+   pub fn fail_for(mut self, email: &str) -> MockTransport {
+      self.failures.push(email.to_string());
+      self
+   }
+
+   /// Returns the email that was sent to the given recipient, if any.
+   pub fn sent_to(&self, email: &str) -> Option<&Message> {
+      self.messages.iter().find(|m| m.recipient == email)
+   }
+}
+
+impl Transport for MockTransport {
+   fn describe(&self) -> String {
+      String::from("mock")
+   }
+
+   fn send(&mut self, msg: &Message) -> Result<(), String> {
+      if self.failures.contains(&msg.recipient) {
+         return Err(format!("mock failure for {}", msg.recipient));
+      }
+      self.messages.push(msg.clone());
+      Ok(())
+   }
+
+   fn send_raw(&mut self, _sender: &str, envelope: &[String], data: &[u8]) -> Result<(), String> {
+      if let Some(failure) = envelope.iter().find(|a| self.failures.contains(a)) {
+         return Err(format!("mock failure for {}", failure));
+      }
+      self.raw.push((envelope.to_vec(), data.to_vec()));
+      Ok(())
+   }
+}
+
+/// Runs a campaign with the given (plain text and optional HTML) templates in memory.
+///
+/// The templates are checked against the recipients first, the errors are returned if that
+/// fails. Otherwise the returned mock transport holds the emails sent.
+pub fn run(cfg: &Config, text: &str, html: Option<&str>) -> Result<MockTransport, Vec<String>> {
+   let tmpl = template::new(text);
+   let html = html.map(template::new);
+   tmpl.check_recipents(&cfg.recipients, &cfg.defaults)?;
+   if let Some(ref html) = html {
+      html.check_recipents(&cfg.recipients, &cfg.defaults)?;
+   }
+   let mut transport = MockTransport::new();
+   let mut events: Vec<Event> = vec![];
+   transport::deliver(cfg, &tmpl, html.as_ref(), &mut transport, &mut events);
+   Ok(transport)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn recipient_happy_case() {
+      let expected = Recipient {
+         email: String::from("jd@example.com"),
+         names: config::sa(&["John", "Doe", "Jr."]),
+         data: config::sm(&[("ORG", "EFF")]),
+      };
+      assert_eq!(
+         expected,
+         recipient("jd@example.com", "John  Doe Jr.", &[("ORG", "EFF")])
+      );
+   }
+
+   #[test]
+   fn config_defaults() {
+      let cfg = config(r#""Frodo Baggins" <fb@example.com>"#, "hello");
+      assert_eq!("example.com", cfg.msgid_domain);
+      assert!(cfg.recipients.is_empty());
+   }
+
+   #[test]
+   fn run_happy_case() {
+      let mut cfg = config("fb@example.com", "Hello %_FN%");
+      cfg.recipients = vec![
+         recipient("jd@example.com", "John Doe", &[("ORG", "EFF")]),
+         recipient("mm@gmail.com", "Mickey Mouse", &[("ORG", "Disney")]),
+      ];
+      let transport = run(&cfg, "Hi %_FN% at %ORG%", None).expect("run failed");
+      assert_eq!(2, transport.messages.len());
+      let msg = transport.sent_to("mm@gmail.com").expect("no email sent");
+      assert_eq!(Some("Hello Mickey"), msg.header("Subject"));
+      assert_eq!("Hi Mickey at Disney", msg.body);
+      assert_eq!(None, transport.sent_to("daisy@example.com"));
+   }
+
+   #[test]
+   fn run_with_missing_data() {
+      let mut cfg = config("fb@example.com", "Hello");
+      cfg.recipients = vec![recipient("jd@example.com", "John Doe", &[])];
+      let expected = vec![String::from(
+         "jd@example.com is missing the following key(s): ORG",
+      )];
+      assert_eq!(Some(expected), run(&cfg, "Hi", Some("<p>%ORG%</p>")).err());
+   }
+
+   #[test]
+   fn mock_transport_failures() {
+      let cfg = config("fb@example.com", "Hello");
+      let rcp = recipient("jd@example.com", "John Doe", &[]);
+      let msg = crate::message::compose(&cfg, &template::new(""), None, &rcp);
+      let mut transport = MockTransport::new().fail_for("jd@example.com");
+      assert_eq!(
+         Err(String::from("mock failure for jd@example.com")),
+         transport.send(&msg)
+      );
+      assert!(transport.messages.is_empty());
+   }
+}
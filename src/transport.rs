@@ -0,0 +1,1146 @@
+/// The `transport` module implements the delivery of the composed emails.
+use crate::config::{self, Config, Imap, Jitter, Recipient, Smtp};
+use crate::events::{self, Event, Observer};
+use crate::imap;
+use crate::message::{self, Message};
+use crate::smtp;
+use crate::source::RecipientSource;
+use crate::spool::{self, Entry, Spool};
+use crate::template::Template;
+use futures_util::future::join_all;
+use lettre::{EmailAddress, Envelope};
+use log::{debug, info, warn};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::task;
+
+/// The `Transport` trait is implemented by everything that is able to deliver emails.
+///
+/// Transports are `Send` so that they can be handed to other threads e.g. by the REST API
+/// server. A campaign sends over several of them at the same time, see
+/// [`deliver_concurrently`].
+pub trait Transport: Send {
+   /// Delivers the message to its recipient and everybody it is copied to.
+   fn send(&mut self, msg: &Message) -> Result<(), String>;
+
+   /// Delivers an already rendered message (with CRLF line endings) to the `envelope` addresses.
+   fn send_raw(&mut self, sender: &str, envelope: &[String], data: &[u8]) -> Result<(), String>;
+
+   /// Delivers the message like `send` but without blocking the thread, so that the emails sent
+   /// over several transports are in flight at the same time. By default the message is sent
+   /// right away, holding up the other transports until it is done.
+   fn send_async<'a>(&'a mut self, msg: &'a Message) -> Sending<'a> {
+      Box::pin(future::ready(self.send(msg)))
+   }
+
+   /// Describes the transport e.g. for the audit log.
+   fn describe(&self) -> String;
+}
+
+/// The outcome of [`Transport::send_async`], to be awaited.
+pub type Sending<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + 'a>>;
+
+/// The number of threads that serve the connections of all transports.
+const IO_THREADS: usize = 2;
+
+/// Returns the (tokio) runtime the connections to the servers are served by, it is started when
+/// first needed. The campaigns are sent on it, see [`deliver_concurrently`].
+pub fn runtime() -> &'static Runtime {
+   static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+   RUNTIME.get_or_init(|| {
+      tokio::runtime::Builder::new_multi_thread()
+         .worker_threads(IO_THREADS)
+         .thread_name("rmt-io")
+         .enable_all()
+         .build()
+         .expect("cannot start the I/O runtime")
+   })
+}
+
+#[derive(Debug)]
+/// The `Delivery` struct holds the outcome of the attempt to deliver an email to a recipient.
+pub struct Delivery {
+   /// The email address of the recipient
+   pub recipient: String,
+   /// The `Message-ID` of the email
+   pub message_id: String,
+   /// The delivery result, holds the error message in case of failure
+   pub result: Result<(), String>,
+   /// When the delivery was attempted (seconds since the epoch)
+   pub timestamp: u64,
+   /// The template variant the recipient was assigned, if the campaign is an A/B test
+   pub variant: Option<String>,
+}
+
+impl PartialEq for Delivery {
+   /// Makes it possible to compare instances of `Delivery`
+   fn eq(&self, other: &Self) -> bool {
+      self.recipient == other.recipient
+         && self.message_id == other.message_id
+         && self.result == other.result
+         && self.timestamp == other.timestamp
+         && self.variant == other.variant
+   }
+}
+
+/// The `Throttle` enforces a rate limit by waiting between consecutive emails, the time waited
+/// varies at random if a jitter range is set. Per-domain rate limits are enforced on top.
+struct Throttle {
+   interval: Option<Duration>,
+   jitter: Option<Jitter>,
+   /// The state of the random number generator
+   state: u64,
+   last: Option<Instant>,
+   /// When an email was last sent to a domain
+   domains: HashMap<String, Instant>,
+}
+
+impl Throttle {
+   /// Creates a throttle, the random numbers are seeded from the current time.
+   fn new(interval: Option<Duration>, jitter: Option<Jitter>) -> Throttle {
+      let state = std::time::SystemTime::now()
+         .duration_since(std::time::UNIX_EPOCH)
+         .map(|d| d.as_nanos() as u64)
+         .unwrap_or_default();
+      Throttle {
+         interval,
+         jitter,
+         state,
+         last: None,
+         domains: HashMap::new(),
+      }
+   }
+
+   /// Returns the time to leave between the previous and the next email.
+   fn delay(&mut self) -> Option<Duration> {
+      let jitter = match self.jitter {
+         Some(j) => Some(j.pick(config::splitmix64(&mut self.state))),
+         None => None,
+      };
+      self.interval.max(jitter)
+   }
+
+   /// Reserves the next time an email may be sent to the given domain and returns how long to
+   /// wait for it, `interval` is the minimum time between two emails to the domain as per its
+   /// rate limit (if any). The waits of concurrent senders add up as if they sent in turns.
+   fn reserve(&mut self, domain: &str, interval: Option<Duration>) -> Duration {
+      let mut until = match (self.last, self.delay()) {
+         (Some(last), Some(delay)) => Some(last + delay),
+         _ => None,
+      };
+      if let (Some(last), Some(interval)) = (self.domains.get(domain), interval) {
+         until = until.max(Some(*last + interval));
+      }
+      let now = Instant::now();
+      let at = until.map_or(now, |until| until.max(now));
+      self.last = Some(at);
+      self.domains.insert(domain.to_string(), at);
+      at - now
+   }
+
+   /// Waits until the next email may be sent to the given domain, see `reserve`.
+   fn wait(&mut self, domain: &str, interval: Option<Duration>) {
+      let delay = self.reserve(domain, interval);
+      if !delay.is_zero() {
+         thread::sleep(delay);
+      }
+   }
+}
+
+/// Returns the recipients (as indices) grouped by their domains if the *smtp* settings ask for
+/// it, in the order of the config file otherwise.
+///
+/// The domains are taken in alphabetical order, with a *domain_burst* they take turns sending
+/// that many emails each until all recipients are covered.
+fn group(cfg: &Config) -> Vec<usize> {
+   let burst = match cfg.smtp {
+      Some(ref smtp) if smtp.group_by_domain => smtp.domain_burst.unwrap_or(usize::MAX),
+      _ => return (0..cfg.recipients.len()).collect(),
+   };
+   let mut domains: BTreeMap<String, VecDeque<usize>> = BTreeMap::new();
+   for (i, rcp) in cfg.recipients.iter().enumerate() {
+      let domain = config::email_domain(&rcp.email).unwrap_or_default();
+      domains.entry(domain).or_default().push_back(i);
+   }
+   let mut result = vec![];
+   while result.len() < cfg.recipients.len() {
+      for pending in domains.values_mut() {
+         let n = burst.min(pending.len());
+         result.extend(pending.drain(..n));
+      }
+   }
+   result
+}
+
+/// Returns the order in which the recipients are sent to (as indices into the recipients).
+///
+/// Without per-domain rate limits this is the (possibly grouped) order of [`group`]. Otherwise
+/// the domains are interleaved: the next recipient is always one of the domain that may be sent
+/// to soonest, so that a strictly limited domain does not hold up the others.
+fn schedule(cfg: &Config) -> Vec<usize> {
+   let order = group(cfg);
+   let smtp = match cfg.smtp {
+      Some(ref smtp) if !smtp.domain_rates.is_empty() => smtp,
+      _ => return order,
+   };
+   // the pending recipients of every domain, as positions in the order
+   let mut domains: Vec<(String, VecDeque<usize>)> = vec![];
+   for (pos, i) in order.iter().enumerate() {
+      let domain = config::email_domain(&cfg.recipients[*i].email).unwrap_or_default();
+      match domains.iter_mut().find(|(d, _)| *d == domain) {
+         Some((_, pending)) => pending.push_back(pos),
+         None => domains.push((domain, VecDeque::from(vec![pos]))),
+      }
+   }
+   // a simulated send, tracking the time (since the start) each domain may be sent to next
+   let interval = smtp.rate.map(|r| r.interval()).unwrap_or_default();
+   let mut ready = vec![Duration::ZERO; domains.len()];
+   let mut clock = Duration::ZERO;
+   let mut result = vec![];
+   while let Some(next) = (0..domains.len())
+      .filter(|&d| !domains[d].1.is_empty())
+      .min_by_key(|&d| (ready[d].max(clock), domains[d].1[0]))
+   {
+      let now = ready[next].max(clock);
+      let (ref domain, ref mut pending) = domains[next];
+      result.extend(pending.pop_front().map(|pos| order[pos]));
+      let rate = smtp.domain_rate(domain).map(|r| r.interval());
+      ready[next] = now + rate.unwrap_or_default();
+      clock = now + interval;
+   }
+   result
+}
+
+/// The number of composed emails that may wait for a connection, composing stops once the
+/// senders fall this far behind.
+const PIPELINE_DEPTH: usize = 16;
+
+/// Composes the emails for all recipients and hands them to the transport.
+///
+/// A failed delivery does not stop the remaining ones, the outcome of every attempt is returned.
+/// The observer is notified of the progress of the campaign. The emails are sent no faster than
+/// the rate limits in the *smtp* settings permit, the domains with a rate limit of their own are
+/// sent to in turns.
+pub fn deliver(
+   cfg: &Config,
+   tmpl: &Template,
+   html: Option<&Template>,
+   transport: &mut dyn Transport,
+   observer: &mut dyn Observer,
+) -> Vec<Delivery> {
+   let stop = AtomicBool::new(false);
+   deliver_concurrently(cfg, tmpl, html, vec![transport], observer, &stop)
+}
+
+/// Works like [`deliver`] but sends the emails over several transports (i.e. connections) at the
+/// same time.
+///
+/// The campaign is sent on the [`runtime`] without a thread per connection: the emails are
+/// composed on a thread of their own and handed to the senders (one per transport) through a
+/// bounded queue, so composing never runs far ahead of sending, and the sends over the
+/// transports overlap while they wait for their servers (see [`Transport::send_async`]). The
+/// rate limits apply to all transports together. The outcomes are returned in the order in
+/// which the sends finished.
+///
+/// This must not be called from within an asynchronous task, it blocks until the campaign is
+/// sent.
+///
+/// Once `stop` is set (see [`crate::shutdown`]) the emails being sent are finished but no others
+/// are started, the recipients without an outcome are left for a later run.
+pub fn deliver_concurrently(
+   cfg: &Config,
+   tmpl: &Template,
+   html: Option<&Template>,
+   transports: Vec<&mut dyn Transport>,
+   observer: &mut dyn Observer,
+   stop: &AtomicBool,
+) -> Vec<Delivery> {
+   let recipients = schedule(cfg)
+      .into_iter()
+      .map(|i| Ok::<_, String>(&cfg.recipients[i]));
+   let expected = cfg.recipients.len();
+   pipeline(
+      cfg, tmpl, html, recipients, expected, transports, observer, stop,
+   )
+   .0
+}
+
+/// Works like [`deliver_concurrently`] but sends the emails to the recipients of a source as they
+/// are read (e.g. a `source::Checked` over `source::open`), so that the list is never held in
+/// memory. `expected` is the number of recipients shown as the total of the campaign (0 if not
+/// known).
+///
+/// The recipients are sent to in the order of the source, the domains are not grouped. Errors of
+/// the source (i.e. invalid recipients) are skipped, they are returned along with the outcomes.
+#[allow(clippy::too_many_arguments)]
+pub fn deliver_stream<S: RecipientSource>(
+   cfg: &Config,
+   tmpl: &Template,
+   html: Option<&Template>,
+   source: S,
+   expected: usize,
+   transports: Vec<&mut dyn Transport>,
+   observer: &mut dyn Observer,
+   stop: &AtomicBool,
+) -> (Vec<Delivery>, Vec<String>) {
+   pipeline(
+      cfg, tmpl, html, source, expected, transports, observer, stop,
+   )
+}
+
+/// Composes the emails for the recipients and sends them over the transports, see
+/// [`deliver_concurrently`]. Returns the outcomes and the errors of the recipients that were
+/// skipped.
+#[allow(clippy::too_many_arguments)]
+fn pipeline<R, I>(
+   cfg: &Config,
+   tmpl: &Template,
+   html: Option<&Template>,
+   recipients: I,
+   expected: usize,
+   transports: Vec<&mut dyn Transport>,
+   observer: &mut dyn Observer,
+   stop: &AtomicBool,
+) -> (Vec<Delivery>, Vec<String>)
+where
+   R: Borrow<Recipient>,
+   I: Iterator<Item = Result<R, String>> + Send,
+{
+   let mut result: Vec<Delivery> = vec![];
+   let smtp = cfg.smtp.as_ref();
+   let throttle = Mutex::new(Throttle::new(
+      smtp.and_then(|s| s.rate).map(|r| r.interval()),
+      smtp.and_then(|s| s.jitter),
+   ));
+   observer.notify(&Event::CampaignStarted {
+      campaign_id: cfg.campaign_id.clone(),
+      recipients: expected,
+   });
+   let (messages, queue) = mpsc::channel::<Message>(PIPELINE_DEPTH);
+   let queue = AsyncMutex::new(queue);
+   let compose = move || {
+      let mut skipped = vec![];
+      for rcp in recipients {
+         if stop.load(Ordering::SeqCst) {
+            break;
+         }
+         let rcp = match rcp {
+            Ok(rcp) => rcp,
+            Err(err) => {
+               warn!("skipping recipient: {}", err);
+               skipped.push(err);
+               continue;
+            }
+         };
+         // waits while the queue is full
+         if messages
+            .blocking_send(message::compose(cfg, tmpl, html, rcp.borrow()))
+            .is_err()
+         {
+            break;
+         }
+      }
+      skipped
+   };
+   let (outcomes, mut done) = mpsc::unbounded_channel::<(Delivery, String)>();
+   let senders = join_all(transports.into_iter().map(|transport| {
+      let (queue, throttle, outcomes) = (&queue, &throttle, outcomes.clone());
+      async move {
+         loop {
+            // the lock is released as soon as the next email was taken off the queue
+            let msg = match queue.lock().await.recv().await {
+               Some(msg) => msg,
+               None => break,
+            };
+            let domain = config::email_domain(&msg.recipient).unwrap_or_default();
+            let interval = smtp
+               .and_then(|s| s.domain_rate(&domain))
+               .map(|r| r.interval());
+            if !stop.load(Ordering::SeqCst) {
+               let delay = throttle
+                  .lock()
+                  .map(|mut t| t.reserve(&domain, interval))
+                  .unwrap_or_default();
+               tokio::time::sleep(delay).await;
+            }
+            // the emails still queued are dropped
+            if stop.load(Ordering::SeqCst) {
+               queue.lock().await.close();
+               break;
+            }
+            let delivery = Delivery {
+               recipient: msg.recipient.clone(),
+               message_id: msg.header("Message-ID").unwrap_or_default().to_string(),
+               result: transport.send_async(&msg).await,
+               timestamp: events::now(),
+               variant: cfg.variant(&msg.recipient).map(|v| v.name.clone()),
+            };
+            let subject = msg.header("Subject").unwrap_or_default().to_string();
+            if outcomes.send((delivery, subject)).is_err() {
+               break;
+            }
+            // the observer gets its turn between emails
+            task::yield_now().await;
+         }
+      }
+   }));
+   drop(outcomes);
+   let reporter = async {
+      while let Some((delivery, subject)) = done.recv().await {
+         match delivery.result {
+            Ok(()) => info!("sent {} to {}", delivery.message_id, delivery.recipient),
+            Err(ref err) => debug!("sending to {} failed ({})", delivery.recipient, err),
+         }
+         observer.notify(&match delivery.result {
+            Ok(()) => Event::MessageSent {
+               campaign_id: cfg.campaign_id.clone(),
+               recipient: delivery.recipient.clone(),
+               message_id: delivery.message_id.clone(),
+               subject,
+               variant: delivery.variant.clone(),
+            },
+            Err(ref err) => Event::MessageFailed {
+               campaign_id: cfg.campaign_id.clone(),
+               recipient: delivery.recipient.clone(),
+               message_id: delivery.message_id.clone(),
+               subject,
+               variant: delivery.variant.clone(),
+               error: err.clone(),
+            },
+         });
+         result.push(delivery);
+      }
+   };
+   let skipped = thread::scope(|scope| {
+      // composing takes time of its own, the emails are composed on a thread while the earlier
+      // ones are sent
+      let composer = scope.spawn(compose);
+      runtime().block_on(async { tokio::join!(senders, reporter) });
+      composer.join().unwrap_or_default()
+   });
+   let failed = result.iter().filter(|d| d.result.is_err()).count();
+   observer.notify(&Event::CampaignFinished {
+      campaign_id: cfg.campaign_id.clone(),
+      sent: result.len() - failed,
+      failed,
+   });
+   (result, skipped)
+}
+
+/// Delivers the emails held in the spool and removes the delivered ones from it.
+///
+/// This works like [`deliver`] for emails that were composed before, the failed ones stay in the
+/// spool for the next attempt unless the server rejected them for good.
+pub fn deliver_spool(
+   spool: &Spool,
+   smtp: Option<&Smtp>,
+   transport: &mut dyn Transport,
+   observer: &mut dyn Observer,
+) -> Result<Vec<Delivery>, String> {
+   let entries = spool.entries()?;
+   let now = events::now();
+   Ok(SpoolSender::new().deliver(spool, smtp, &entries, transport, observer, now))
+}
+
+/// The `SpoolSender` delivers the emails of a spool in batches, the rate limits are honoured
+/// across batches.
+pub struct SpoolSender {
+   throttle: Throttle,
+   /// Once set, no further emails are sent
+   stop: Option<&'static AtomicBool>,
+}
+
+impl Default for SpoolSender {
+   fn default() -> Self {
+      SpoolSender::new()
+   }
+}
+
+impl SpoolSender {
+   pub fn new() -> SpoolSender {
+      SpoolSender {
+         throttle: Throttle::new(None, None),
+         stop: None,
+      }
+   }
+
+   /// Makes the sender stop before the next email once the flag is set, the emails not sent
+   /// stay in the spool.
+   pub fn stop_on(mut self, stop: &'static AtomicBool) -> SpoolSender {
+      self.stop = Some(stop);
+      self
+   }
+
+   fn stopped(&self) -> bool {
+      self.stop.is_some_and(|s| s.load(Ordering::SeqCst))
+   }
+
+   /// Delivers the given spool entries, `now` is the time in seconds since the epoch.
+   ///
+   /// The delivered emails are removed from the spool. The failed ones are deferred with an
+   /// exponential backoff, or given up on if the error is permanent or they failed
+   /// [`spool::MAX_ATTEMPTS`] times.
+   pub fn deliver(
+      &mut self,
+      spool: &Spool,
+      smtp: Option<&Smtp>,
+      entries: &[Entry],
+      transport: &mut dyn Transport,
+      observer: &mut dyn Observer,
+      now: u64,
+   ) -> Vec<Delivery> {
+      // the settings may have changed since the previous batch
+      self.throttle.interval = smtp.and_then(|s| s.rate).map(|r| r.interval());
+      self.throttle.jitter = smtp.and_then(|s| s.jitter);
+      let mut result: Vec<Delivery> = vec![];
+      let campaign_id = spool.campaign_id.clone();
+      observer.notify(&Event::CampaignStarted {
+         campaign_id: campaign_id.clone(),
+         recipients: entries.len(),
+      });
+      for entry in entries.iter() {
+         if self.stopped() {
+            break;
+         }
+         let domain = config::email_domain(&entry.recipient).unwrap_or_default();
+         self.throttle.wait(
+            &domain,
+            smtp
+               .and_then(|s| s.domain_rate(&domain))
+               .map(|r| r.interval()),
+         );
+         if self.stopped() {
+            break;
+         }
+         let sent = spool
+            .message(entry)
+            .and_then(|data| transport.send_raw(&entry.sender, &entry.envelope, &data));
+         let kept = match sent {
+            Ok(()) => spool.remove(entry),
+            Err(ref err) => {
+               let mut entry = entry.clone();
+               if smtp::is_permanent(err) || entry.attempts + 1 >= spool::MAX_ATTEMPTS {
+                  spool.give_up(&mut entry, err)
+               } else {
+                  let retry_at = now + spool::backoff(entry.attempts + 1);
+                  spool.defer(&mut entry, err, retry_at)
+               }
+            }
+         };
+         if let Err(err) = kept {
+            warn!("{}, the spool may be out of date", err);
+         }
+         let delivery = Delivery {
+            recipient: entry.recipient.clone(),
+            message_id: entry.message_id.clone(),
+            result: sent,
+            timestamp: events::now(),
+            variant: entry.variant.clone(),
+         };
+         observer.notify(&match delivery.result {
+            Ok(()) => Event::MessageSent {
+               campaign_id: campaign_id.clone(),
+               recipient: delivery.recipient.clone(),
+               message_id: delivery.message_id.clone(),
+               subject: entry.subject.clone(),
+               variant: delivery.variant.clone(),
+            },
+            Err(ref err) => Event::MessageFailed {
+               campaign_id: campaign_id.clone(),
+               recipient: delivery.recipient.clone(),
+               message_id: delivery.message_id.clone(),
+               subject: entry.subject.clone(),
+               variant: delivery.variant.clone(),
+               error: err.clone(),
+            },
+         });
+         result.push(delivery);
+      }
+      let failed = result.iter().filter(|d| d.result.is_err()).count();
+      observer.notify(&Event::CampaignFinished {
+         campaign_id,
+         sent: result.len() - failed,
+         failed,
+      });
+      result
+   }
+}
+
+/// The `SmtpTransport` delivers emails via an SMTP server.
+pub struct SmtpTransport {
+   smtp: Smtp,
+   /// The connection, established when the first email is sent
+   connection: Option<smtp::Connection>,
+   description: String,
+   /// The mailbox copies of the emails sent are stored in (if any)
+   imap: Option<Imap>,
+   /// The connection to the mailbox, established when the first copy is stored
+   mailbox: Option<imap::Connection>,
+}
+
+impl SmtpTransport {
+   /// Sets up the transport for the given SMTP server, the connection is only established once
+   /// the first email is sent and reused for all subsequent ones.
+   pub fn new(smtp: &Smtp) -> Result<SmtpTransport, String> {
+      debug!(
+         "smtp: using {}:{} ({:?}, {})",
+         smtp.host,
+         smtp.port,
+         smtp.security,
+         match smtp.username {
+            Some(ref u) => format!("authenticating as {}", u),
+            None => String::from("no authentication"),
+         }
+      );
+      Ok(SmtpTransport {
+         smtp: smtp.clone(),
+         connection: None,
+         description: format!("smtp://{}:{}", smtp.host, smtp.port),
+         imap: None,
+         mailbox: None,
+      })
+   }
+
+   /// Stores a copy of every email sent in the given mailbox folder, the password must have been
+   /// resolved.
+   pub fn copy_to(mut self, imap: &Imap) -> SmtpTransport {
+      self.imap = Some(imap.clone());
+      self
+   }
+
+   /// Sends an already rendered message over the connection, which is established first if
+   /// needed, and stores the copy.
+   async fn deliver(
+      &mut self,
+      sender: &str,
+      envelope: &[String],
+      data: &[u8],
+   ) -> Result<(), String> {
+      debug!(
+         "smtp: MAIL FROM:<{}> RCPT TO:{} ({} bytes)",
+         sender,
+         envelope
+            .iter()
+            .map(|a| format!("<{}>", a))
+            .collect::<Vec<String>>()
+            .join(","),
+         data.len()
+      );
+      let connection = match self.connection {
+         Some(ref mut connection) => connection,
+         None => self
+            .connection
+            .insert(smtp::Connection::open(&self.smtp).await?),
+      };
+      let result = connection.send(sender, envelope, data).await;
+      if connection.is_broken() {
+         // start afresh with the next email
+         self.connection = None;
+      }
+      match result {
+         Ok(reply) => {
+            debug!("smtp: {} {}", reply.code, reply.lines.join(" / "));
+            self.store_copy(data).await;
+            Ok(())
+         }
+         Err(e) => {
+            debug!("smtp: {}", e);
+            Err(e)
+         }
+      }
+   }
+
+   /// Stores a copy of an email sent. The IMAP client blocks, the copy is stored on a thread of
+   /// the runtime's blocking pool.
+   async fn store_copy(&mut self, data: &[u8]) {
+      let imap = match self.imap {
+         Some(ref imap) => imap.clone(),
+         None => return,
+      };
+      let (mailbox, data) = (self.mailbox.take(), data.to_vec());
+      self.mailbox = task::spawn_blocking(move || store(&imap, mailbox, &data))
+         .await
+         .unwrap_or(None);
+   }
+}
+
+/// Stores a copy of an email in the *imap* folder over the given connection or a new one, a
+/// failure is only logged since the email was delivered. Returns the connection unless it
+/// broke.
+fn store(imap: &Imap, mailbox: Option<imap::Connection>, data: &[u8]) -> Option<imap::Connection> {
+   let mut mailbox = match mailbox {
+      Some(mailbox) => mailbox,
+      None => match imap::Connection::open(imap) {
+         Ok(mailbox) => mailbox,
+         Err(err) => {
+            warn!("the copy of the email was not stored ({})", err);
+            return None;
+         }
+      },
+   };
+   if let Err(err) = mailbox.append(&imap.folder, data) {
+      warn!("the copy of the email was not stored ({})", err);
+   }
+   // start afresh with the next copy
+   Some(mailbox).filter(|m| !m.is_broken())
+}
+
+impl Drop for SmtpTransport {
+   fn drop(&mut self) {
+      if let Some(connection) = self.connection.take() {
+         // a transport dropped by an asynchronous task cannot wait for the server
+         match Handle::try_current() {
+            Ok(handle) => drop(handle.spawn(connection.quit())),
+            Err(_) => runtime().block_on(connection.quit()),
+         }
+      }
+      if let Some(mailbox) = self.mailbox.take() {
+         mailbox.logout();
+      }
+   }
+}
+
+/// Builds the SMTP envelope for the message.
+fn envelope(msg: &Message) -> Result<Envelope, String> {
+   let address = |a: &str| {
+      EmailAddress::new(a.to_string()).map_err(|e| format!("invalid email address {} ({})", a, e))
+   };
+   let mut to = vec![address(&msg.recipient)?];
+   for cc in msg.cc.iter() {
+      to.push(address(cc)?);
+   }
+   Envelope::new(Some(address(&msg.sender)?), to).map_err(|e| format!("invalid envelope ({})", e))
+}
+
+impl Transport for SmtpTransport {
+   fn describe(&self) -> String {
+      self.description.clone()
+   }
+
+   fn send(&mut self, msg: &Message) -> Result<(), String> {
+      runtime().block_on(self.send_async(msg))
+   }
+
+   fn send_raw(&mut self, sender: &str, envelope: &[String], data: &[u8]) -> Result<(), String> {
+      runtime().block_on(self.deliver(sender, envelope, data))
+   }
+
+   fn send_async<'a>(&'a mut self, msg: &'a Message) -> Sending<'a> {
+      Box::pin(async move {
+         let to: Vec<String> = envelope(msg)?.to().iter().map(|a| a.to_string()).collect();
+         self.deliver(&msg.sender, &to, &msg.to_bytes()).await
+      })
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use crate::config::sa;
+   use crate::template;
+   use crate::testing::{self, MockTransport};
+
+   #[test]
+   fn envelope_happy_case() {
+      let cfg = testing::config(r#""Frodo Baggins" <fb@example.com>"#, "hello");
+      let rcp = testing::recipient("jd@example.com", "John Doe", &[]);
+      let mut msg = message::compose(&cfg, &template::new(""), None, &rcp);
+      msg.cc = sa(&["cc@example.com"]);
+      let envelope = envelope(&msg).expect("invalid envelope");
+      assert_eq!(Some("fb@example.com"), envelope.from().map(|a| a.as_ref()));
+      let to: Vec<&str> = envelope.to().iter().map(|a| a.as_ref()).collect();
+      assert_eq!(vec!["jd@example.com", "cc@example.com"], to);
+   }
+
+   #[test]
+   fn throttle_waits_between_emails() {
+      let mut throttle = Throttle::new(Some(Duration::from_millis(50)), None);
+      let start = Instant::now();
+      throttle.wait("example.com", None);
+      assert!(start.elapsed() < Duration::from_millis(50));
+      throttle.wait("example.com", None);
+      throttle.wait("example.com", None);
+      assert!(start.elapsed() >= Duration::from_millis(100));
+   }
+
+   #[test]
+   fn throttle_waits_per_domain() {
+      let mut throttle = Throttle::new(None, None);
+      let start = Instant::now();
+      let interval = Some(Duration::from_millis(50));
+      throttle.wait("gmail.com", interval);
+      throttle.wait("example.com", None);
+      assert!(start.elapsed() < Duration::from_millis(50));
+      throttle.wait("gmail.com", interval);
+      assert!(start.elapsed() >= Duration::from_millis(50));
+   }
+
+   #[test]
+   fn schedule_interleaves_domains() {
+      let mut cfg = testing::config("fb@example.com", "hello %_FN%");
+      cfg.recipients = [
+         "a@gmail.com",
+         "b@gmail.com",
+         "c@gmail.com",
+         "d@x.org",
+         "e@y.org",
+      ]
+      .iter()
+      .map(|e| testing::recipient(e, "Jane Doe", &[]))
+      .collect();
+      // no per-domain limits: the order of the config file
+      assert_eq!(vec![0, 1, 2, 3, 4], schedule(&cfg));
+      let rate = |r: &str| config::parse_rate(r).unwrap();
+      let mut smtp = testing::smtp();
+      smtp
+         .domain_rates
+         .insert(String::from("gmail.com"), rate("1/min"));
+      cfg.smtp = Some(smtp);
+      assert_eq!(vec![0, 3, 4, 1, 2], schedule(&cfg));
+      // the default applies to the other domains, the overall limit to all of them
+      let smtp = cfg.smtp.as_mut().unwrap();
+      smtp
+         .domain_rates
+         .insert(String::from("default"), rate("2/min"));
+      smtp.rate = Some(rate("4/min"));
+      assert_eq!(vec![0, 3, 4, 1, 2], schedule(&cfg));
+   }
+
+   #[test]
+   fn group_by_domain() {
+      let mut cfg = testing::config("fb@example.com", "hello %_FN%");
+      cfg.recipients = [
+         "a@y.org",
+         "b@gmail.com",
+         "c@y.org",
+         "d@gmail.com",
+         "e@gmail.com",
+         "f@x.org",
+      ]
+      .iter()
+      .map(|e| testing::recipient(e, "Jane Doe", &[]))
+      .collect();
+      let mut smtp = testing::smtp();
+      smtp.group_by_domain = true;
+      cfg.smtp = Some(smtp);
+      assert_eq!(vec![1, 3, 4, 5, 0, 2], schedule(&cfg));
+      // at most two emails to a domain in a row
+      cfg.smtp.as_mut().unwrap().domain_burst = Some(2);
+      assert_eq!(vec![1, 3, 5, 0, 2, 4], schedule(&cfg));
+   }
+
+   #[test]
+   fn throttle_with_jitter() {
+      let jitter = Jitter {
+         min: Duration::from_millis(10),
+         max: Duration::from_millis(30),
+      };
+      let mut throttle = Throttle::new(None, Some(jitter));
+      let delays: Vec<Duration> = (0..50).filter_map(|_| throttle.delay()).collect();
+      assert_eq!(50, delays.len());
+      assert!(delays.iter().all(|d| jitter.min <= *d && *d <= jitter.max));
+      assert!(delays.iter().any(|d| *d != delays[0]));
+      // the rate limit is the lower bound
+      let mut throttle = Throttle::new(Some(Duration::from_millis(20)), Some(jitter));
+      assert!((0..50).all(|_| throttle.delay() >= Some(Duration::from_millis(20))));
+   }
+
+   #[test]
+   fn deliver_over_several_transports() {
+      let mut cfg = testing::config("fb@example.com", "hello %_FN%");
+      cfg.recipients = (0..50)
+         .map(|i| testing::recipient(&format!("r{}@example.com", i), "Jane Doe", &[]))
+         .collect();
+      let mut transports = [
+         MockTransport::new(),
+         MockTransport::new(),
+         MockTransport::new().fail_for("r7@example.com"),
+      ];
+      let mut events: Vec<Event> = vec![];
+      let deliveries = deliver_concurrently(
+         &cfg,
+         &template::new("hi"),
+         None,
+         transports
+            .iter_mut()
+            .map(|t| t as &mut dyn Transport)
+            .collect(),
+         &mut events,
+         &AtomicBool::new(false),
+      );
+      assert_eq!(50, deliveries.len());
+      let mut sent: Vec<&str> = transports
+         .iter()
+         .flat_map(|t| t.messages.iter().map(|m| m.recipient.as_ref()))
+         .collect();
+      sent.sort_unstable();
+      // every email was sent exactly once, unless it failed
+      assert!(sent.windows(2).all(|w| w[0] != w[1]), "{:?}", sent);
+      let failed = deliveries.iter().filter(|d| d.result.is_err()).count();
+      assert_eq!(50, sent.len() + failed);
+      assert_eq!(52, events.len());
+   }
+
+   /// The `SlowTransport` takes its time for every email without blocking the thread, the way
+   /// a remote server would. It records the threads it sent on.
+   #[derive(Default)]
+   struct SlowTransport {
+      threads: Vec<thread::ThreadId>,
+   }
+
+   impl Transport for SlowTransport {
+      fn send(&mut self, _msg: &Message) -> Result<(), String> {
+         thread::sleep(Duration::from_millis(500));
+         Ok(())
+      }
+
+      fn send_raw(&mut self, _sender: &str, _to: &[String], _data: &[u8]) -> Result<(), String> {
+         Err(String::from("not supported"))
+      }
+
+      fn send_async<'a>(&'a mut self, _msg: &'a Message) -> Sending<'a> {
+         Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            self.threads.push(thread::current().id());
+            Ok(())
+         })
+      }
+
+      fn describe(&self) -> String {
+         String::from("slow")
+      }
+   }
+
+   #[test]
+   fn deliver_without_a_thread_per_connection() {
+      let mut cfg = testing::config("fb@example.com", "hello %_FN%");
+      cfg.recipients = (0..10)
+         .map(|i| testing::recipient(&format!("r{}@example.com", i), "Jane Doe", &[]))
+         .collect();
+      let mut transports: Vec<SlowTransport> = (0..10).map(|_| SlowTransport::default()).collect();
+      let start = Instant::now();
+      let deliveries = deliver_concurrently(
+         &cfg,
+         &template::new("hi"),
+         None,
+         transports
+            .iter_mut()
+            .map(|t| t as &mut dyn Transport)
+            .collect(),
+         &mut Vec::<Event>::new(),
+         &AtomicBool::new(false),
+      );
+      assert_eq!(10, deliveries.len());
+      // the sends overlap, sending one after the other would take 5 seconds
+      assert!(
+         start.elapsed() < Duration::from_secs(3),
+         "{:?}",
+         start.elapsed()
+      );
+      // and the calling thread served all connections
+      let threads: Vec<&thread::ThreadId> = transports.iter().flat_map(|t| &t.threads).collect();
+      assert_eq!(10, threads.len());
+      assert!(threads.iter().all(|id| **id == thread::current().id()));
+   }
+
+   #[test]
+   fn deliver_spool_keeps_failures() {
+      let dir = tempfile::TempDir::new().unwrap();
+      let spool = Spool::create(dir.path(), "spring-sale", "sale.ini", 1000).unwrap();
+      let cfg = testing::config("fb@example.com", "hello %_FN%");
+      for email in ["jd@example.com", "mm@gmail.com"].iter() {
+         let rcp = testing::recipient(email, "Jane Doe", &[]);
+         let msg = message::compose(&cfg, &template::new("hi"), None, &rcp);
+         spool.add(&msg, None).unwrap();
+      }
+      let mut transport = MockTransport::new().fail_for("mm@gmail.com");
+      let mut events: Vec<Event> = vec![];
+      let deliveries = deliver_spool(&spool, None, &mut transport, &mut events).unwrap();
+      let results: Vec<(&str, bool)> = deliveries
+         .iter()
+         .map(|d| (d.recipient.as_ref(), d.result.is_ok()))
+         .collect();
+      assert_eq!(
+         vec![("jd@example.com", true), ("mm@gmail.com", false)],
+         results
+      );
+      assert_eq!(vec![String::from("jd@example.com")], transport.raw[0].0);
+      assert_eq!(4, events.len());
+      // only the failed email is left for the next flush
+      let left: Vec<String> = spool
+         .entries()
+         .unwrap()
+         .into_iter()
+         .map(|e| e.recipient)
+         .collect();
+      assert_eq!(vec![String::from("mm@gmail.com")], left);
+   }
+
+   #[test]
+   fn spool_sender_defers_and_gives_up() {
+      let dir = tempfile::TempDir::new().unwrap();
+      let spool = Spool::create(dir.path(), "spring-sale", "sale.ini", 1000).unwrap();
+      let cfg = testing::config("fb@example.com", "hello %_FN%");
+      let rcp = testing::recipient("mm@gmail.com", "Mickey Mouse", &[]);
+      spool
+         .add(
+            &message::compose(&cfg, &template::new("hi"), None, &rcp),
+            None,
+         )
+         .unwrap();
+      let mut transport = MockTransport::new().fail_for("mm@gmail.com");
+      let mut sender = SpoolSender::new();
+      let mut events: Vec<Event> = vec![];
+      for attempt in 1..spool::MAX_ATTEMPTS {
+         let entries = spool.entries().unwrap();
+         sender.deliver(&spool, None, &entries, &mut transport, &mut events, 5000);
+         let entry = &spool.entries().unwrap()[0];
+         assert_eq!(attempt, entry.attempts);
+         assert_eq!(5000 + spool::backoff(attempt), entry.retry_at);
+         assert_eq!(
+            Some(String::from("mock failure for mm@gmail.com")),
+            entry.last_error
+         );
+      }
+      // the last attempt fails as well
+      let entries = spool.entries().unwrap();
+      sender.deliver(&spool, None, &entries, &mut transport, &mut events, 5000);
+      assert!(spool.entries().unwrap().is_empty());
+      assert_eq!(1, spool.given_up().unwrap());
+   }
+
+   #[test]
+   fn deliver_streamed_recipients() {
+      let cfg = testing::config("fb@example.com", "hello %_FN%");
+      // the recipients are made as they are taken, every 10th one is invalid
+      let source = (0..50).map(|i| match i % 10 {
+         9 => Err(format!("invalid recipient {}", i)),
+         _ => Ok(testing::recipient(
+            &format!("r{}@example.com", i),
+            "Jane Doe",
+            &[],
+         )),
+      });
+      let mut transports = [MockTransport::new(), MockTransport::new()];
+      let mut events: Vec<Event> = vec![];
+      let (deliveries, skipped) = deliver_stream(
+         &cfg,
+         &template::new("hi"),
+         None,
+         source,
+         50,
+         transports
+            .iter_mut()
+            .map(|t| t as &mut dyn Transport)
+            .collect(),
+         &mut events,
+         &AtomicBool::new(false),
+      );
+      assert_eq!(45, deliveries.len());
+      assert_eq!(5, skipped.len());
+      assert_eq!("invalid recipient 9", skipped[0]);
+      assert_eq!(
+         45,
+         transports.iter().map(|t| t.messages.len()).sum::<usize>()
+      );
+      assert!(matches!(
+         events[0],
+         Event::CampaignStarted { recipients: 50, .. }
+      ));
+   }
+
+   /// Requests a stop once the first email was sent.
+   struct StopAfterFirst<'a>(&'a AtomicBool);
+
+   impl Observer for StopAfterFirst<'_> {
+      fn notify(&mut self, event: &Event) {
+         if let Event::MessageSent { .. } = event {
+            self.0.store(true, Ordering::SeqCst);
+         }
+      }
+   }
+
+   #[test]
+   fn deliver_stops_when_asked() {
+      let mut cfg = testing::config("fb@example.com", "hello %_FN%");
+      cfg.recipients = (0..50)
+         .map(|i| testing::recipient(&format!("r{}@example.com", i), "Jane Doe", &[]))
+         .collect();
+      let mut transport = MockTransport::new();
+      let stop = AtomicBool::new(false);
+      let deliveries = deliver_concurrently(
+         &cfg,
+         &template::new("hi"),
+         None,
+         vec![&mut transport],
+         &mut StopAfterFirst(&stop),
+         &stop,
+      );
+      // the email in flight is finished, the queued ones are not sent
+      assert!(!deliveries.is_empty() && deliveries.len() < 50);
+      assert_eq!(deliveries.len(), transport.messages.len());
+   }
+
+   #[test]
+   fn deliver_continues_after_failures() {
+      let mut cfg = testing::config("fb@example.com", "hello %_FN%");
+      cfg.recipients = vec![
+         testing::recipient("jd@example.com", "John Doe", &[]),
+         testing::recipient("mm@gmail.com", "Mickey Mouse", &[]),
+         testing::recipient("daisy@example.com", "Daisy Lila", &[]),
+      ];
+      let mut transport = MockTransport::new().fail_for("mm@gmail.com");
+      let mut events: Vec<Event> = vec![];
+      let deliveries = deliver(
+         &cfg,
+         &template::new("hi"),
+         None,
+         &mut transport,
+         &mut events,
+      );
+      let results: Vec<(&str, bool)> = deliveries
+         .iter()
+         .map(|d| (d.recipient.as_ref(), d.result.is_ok()))
+         .collect();
+      assert_eq!(
+         vec![
+            ("jd@example.com", true),
+            ("mm@gmail.com", false),
+            ("daisy@example.com", true)
+         ],
+         results
+      );
+      assert_eq!(
+         message::message_id(&cfg.campaign_id, "mm@gmail.com", &cfg.msgid_domain),
+         deliveries[1].message_id
+      );
+      assert_eq!(2, transport.messages.len());
+      let names: Vec<&str> = events.iter().map(|e| e.name()).collect();
+      assert_eq!(
+         vec![
+            "campaign_started",
+            "message_sent",
+            "message_failed",
+            "message_sent",
+            "campaign_finished"
+         ],
+         names
+      );
+      assert_eq!(
+         Event::CampaignFinished {
+            campaign_id: cfg.campaign_id.clone(),
+            sent: 2,
+            failed: 1
+         },
+         events[4]
+      );
+   }
+}
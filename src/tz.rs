@@ -0,0 +1,57 @@
+/// The `tz` module converts times to the local time of a time zone e.g. `Europe/Vienna`, with
+/// the IANA time zone database that is compiled in (see the `chrono-tz` crate).
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
+
+/// Looks up the time zone by its name e.g. `Europe/Vienna`.
+pub fn lookup(name: &str) -> Result<Tz, String> {
+   let name = name.trim();
+   name
+      .parse()
+      .map_err(|_| format!("unknown time zone ({})", name))
+}
+
+/// Converts the time to the local time of the time zone.
+pub fn local(zone: Tz, t: DateTime<Utc>) -> DateTime<FixedOffset> {
+   t.with_timezone(&zone).fixed_offset()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use chrono::Offset;
+
+   fn utc(text: &str) -> DateTime<Utc> {
+      DateTime::parse_from_rfc3339(text)
+         .unwrap()
+         .with_timezone(&Utc)
+   }
+
+   #[test]
+   fn lookup_zones() {
+      assert_eq!(
+         Err(String::from("unknown time zone (../etc/passwd)")),
+         lookup("../etc/passwd")
+      );
+      assert!(lookup("No/Such_Zone").is_err());
+      let vienna = lookup(" Europe/Vienna ").expect("unknown time zone");
+      let offset = |text: &str| local(vienna, utc(text)).offset().fix().local_minus_utc();
+      assert_eq!(7200, offset("2024-06-01T12:00:00Z"));
+      assert_eq!(3600, offset("2024-01-15T12:00:00Z"));
+      // the last Sunday of March 2024 is the 31st, the switch happens at 01:00 UTC
+      assert_eq!(3600, offset("2024-03-31T00:59:59Z"));
+      assert_eq!(7200, offset("2024-03-31T01:00:00Z"));
+      assert_eq!(3600, offset("2091-01-15T12:00:00Z"));
+      assert_eq!(7200, offset("2091-07-15T12:00:00Z"));
+      let new_york = lookup("America/New_York").expect("unknown time zone");
+      assert_eq!(
+         "2024-06-01T12:00:00-04:00",
+         local(new_york, utc("2024-06-01T16:00:00Z")).to_rfc3339()
+      );
+      let kolkata = lookup("Asia/Kolkata").expect("unknown time zone");
+      assert_eq!(
+         "1970-01-01T05:30:00+05:30",
+         local(kolkata, utc("1970-01-01T00:00:00Z")).to_rfc3339()
+      );
+   }
+}
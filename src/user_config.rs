@@ -0,0 +1,316 @@
+/// The `user_config` module reads the user-level configuration in
+/// `$XDG_CONFIG_HOME/rmt/config.toml` (`~/.config/rmt/config.toml` by default).
+///
+/// It holds defaults for all campaigns and named sending profiles, each with the settings of an
+/// *smtp* section and a default *From* address, so that campaigns can say `profile=work` instead
+/// of repeating them:
+///
+/// ```toml
+/// [defaults]
+/// rate = "30/min"
+/// log_level = "info"
+/// template_dir = "/home/frodo/templates"
+/// editor = "vim"
+///
+/// [profiles.work]
+/// from = "Frodo Baggins <frodo@work.example.com>"
+///
+/// [profiles.work.smtp]
+/// host = "smtp.work.example.com"
+/// security = "starttls"
+/// username = "frodo"
+/// keyring = "smtp-work"
+/// ```
+use crate::config;
+use log::LevelFilter;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+#[derive(Debug, Default)]
+/// The `Defaults` struct holds the settings that apply unless a campaign file or a command line
+/// flag says otherwise.
+pub struct Defaults {
+   /// The maximum rate at which emails are sent, for *smtp* sections without a rate
+   pub rate: Option<String>,
+   /// The log level if no `-v` flag is given
+   pub log_level: Option<LevelFilter>,
+   /// The directory relative template paths are looked up in if they are not found as given
+   pub template_dir: Option<PathBuf>,
+   /// The editor new campaign files are opened in
+   pub editor: Option<String>,
+}
+
+impl PartialEq for Defaults {
+   /// Makes it possible to compare instances of `Defaults`
+   fn eq(&self, other: &Self) -> bool {
+      self.rate == other.rate
+         && self.log_level == other.log_level
+         && self.template_dir == other.template_dir
+         && self.editor == other.editor
+   }
+}
+
+impl Defaults {
+   /// Returns the path of the template file: the given one unless it is relative, does not exist
+   /// and there is a file with that name in the default template directory.
+   pub fn template_path(&self, path: &str) -> String {
+      if Path::new(path).is_relative() && !Path::new(path).exists() {
+         if let Some(ref dir) = self.template_dir {
+            let candidate = dir.join(path);
+            if candidate.exists() {
+               return candidate.to_string_lossy().into_owned();
+            }
+         }
+      }
+      path.to_string()
+   }
+
+   /// Returns the editor to open files in: the configured one, else `$VISUAL` or `$EDITOR`.
+   pub fn editor(&self) -> Option<String> {
+      self
+         .editor
+         .clone()
+         .or_else(|| env::var("VISUAL").ok())
+         .or_else(|| env::var("EDITOR").ok())
+         .filter(|e| !e.trim().is_empty())
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `Profile` struct holds the settings of a named sending profile.
+pub struct Profile {
+   /// The default *From* address
+   pub from: Option<String>,
+   /// The settings of the *smtp* section, as key/value pairs
+   pub smtp: Vec<(String, String)>,
+}
+
+impl PartialEq for Profile {
+   /// Makes it possible to compare instances of `Profile`
+   fn eq(&self, other: &Self) -> bool {
+      self.from == other.from && self.smtp == other.smtp
+   }
+}
+
+#[derive(Debug, Default)]
+/// The `UserConfig` struct holds the contents of the user-level configuration file.
+pub struct UserConfig {
+   /// The defaults for all campaigns
+   pub defaults: Defaults,
+   /// The sending profiles by name
+   pub profiles: HashMap<String, Profile>,
+}
+
+impl PartialEq for UserConfig {
+   /// Makes it possible to compare instances of `UserConfig`
+   fn eq(&self, other: &Self) -> bool {
+      self.defaults == other.defaults && self.profiles == other.profiles
+   }
+}
+
+/// Returns the path of the user-level configuration file, in `$XDG_CONFIG_HOME` or else in
+/// `~/.config`.
+pub fn path() -> Option<PathBuf> {
+   if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+      // relative paths are invalid according to the XDG base directory specification
+      if Path::new(&dir).is_absolute() {
+         return Some(PathBuf::from(dir).join("rmt").join("config.toml"));
+      }
+   }
+   let home = env::var_os("HOME")?;
+   Some(
+      PathBuf::from(home)
+         .join(".config")
+         .join("rmt")
+         .join("config.toml"),
+   )
+}
+
+/// Converts a TOML value to the text it would have in a campaign file.
+pub fn text(value: &Value) -> Option<String> {
+   match value {
+      Value::String(s) => Some(s.clone()),
+      Value::Integer(i) => Some(i.to_string()),
+      Value::Boolean(b) => Some(b.to_string()),
+      _ => None,
+   }
+}
+
+/// Parses a profile table.
+fn parse_profile(name: &str, table: &Table) -> Result<Profile, String> {
+   let mut result = Profile::default();
+   for (key, value) in table.iter() {
+      match (key.as_ref(), value) {
+         ("from", Value::String(from)) => result.from = Some(from.clone()),
+         ("smtp", Value::Table(smtp)) => {
+            for (key, value) in smtp.iter() {
+               let value = text(value).ok_or_else(|| {
+                  format!("invalid *{}* in the smtp settings of profile {}", key, name)
+               })?;
+               result.smtp.push((key.clone(), value));
+            }
+            result.smtp.sort();
+         }
+         _ => return Err(format!("invalid *{}* in profile {}", key, name)),
+      }
+   }
+   Ok(result)
+}
+
+/// Parses the `[defaults]` table.
+fn parse_defaults(table: &Table) -> Result<Defaults, String> {
+   let mut result = Defaults::default();
+   for (key, value) in table.iter() {
+      let val = value
+         .as_str()
+         .ok_or_else(|| format!("invalid *{}* in the defaults section", key))?;
+      match key.as_ref() {
+         "rate" => {
+            config::parse_rate(val)?;
+            result.rate = Some(val.to_string());
+         }
+         "log_level" => {
+            result.log_level = Some(
+               val.parse()
+                  .map_err(|_| format!("invalid *log_level*: {}", val))?,
+            )
+         }
+         "template_dir" => result.template_dir = Some(PathBuf::from(val)),
+         "editor" => result.editor = Some(val.to_string()),
+         _ => return Err(format!("invalid defaults configuration datum: *{}*", key)),
+      }
+   }
+   Ok(result)
+}
+
+/// Parses the text of a user-level configuration file.
+pub fn parse(text: &str) -> Result<UserConfig, String> {
+   let table: Table = text.parse().map_err(|e| format!("{}", e))?;
+   let mut result = UserConfig::default();
+   for (key, value) in table.iter() {
+      match (key.as_ref(), value) {
+         ("defaults", Value::Table(defaults)) => result.defaults = parse_defaults(defaults)?,
+         ("profiles", Value::Table(profiles)) => {
+            for (name, profile) in profiles.iter() {
+               let profile = profile
+                  .as_table()
+                  .ok_or_else(|| format!("invalid profile: {}", name))?;
+               result
+                  .profiles
+                  .insert(name.clone(), parse_profile(name, profile)?);
+            }
+         }
+         _ => return Err(format!("invalid configuration datum: *{}*", key)),
+      }
+   }
+   Ok(result)
+}
+
+/// Loads the user-level configuration, a missing file is the same as an empty one.
+pub fn load() -> Result<UserConfig, String> {
+   let path = match path() {
+      Some(path) if path.exists() => path,
+      _ => return Ok(UserConfig::default()),
+   };
+   let text =
+      fs::read_to_string(&path).map_err(|e| format!("cannot read {} ({})", path.display(), e))?;
+   parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn template_path_lookup() {
+      let dir = tempfile::tempdir().expect("cannot create directory");
+      fs::write(dir.path().join("newsletter.eml"), "Hi").unwrap();
+      let defaults = Defaults {
+         template_dir: Some(dir.path().to_path_buf()),
+         ..Defaults::default()
+      };
+      assert_eq!(
+         dir.path().join("newsletter.eml").to_string_lossy(),
+         defaults.template_path("newsletter.eml")
+      );
+      assert_eq!("missing.eml", defaults.template_path("missing.eml"));
+      assert_eq!("Cargo.toml", defaults.template_path("Cargo.toml"));
+   }
+
+   #[test]
+   fn parse_happy_case() {
+      let text = r#"
+[defaults]
+rate = "30/min"
+log_level = "debug"
+template_dir = "/srv/templates"
+
+[profiles.work]
+from = "Frodo Baggins <frodo@work.example.com>"
+
+[profiles.work.smtp]
+host = "smtp.work.example.com"
+port = 587
+security = "starttls"
+smtputf8 = true
+
+[profiles.personal.smtp]
+host = "smtp.example.com"
+"#;
+      let cfg = parse(text).expect("cannot parse user config");
+      assert_eq!(2, cfg.profiles.len());
+      assert_eq!(
+         Profile {
+            from: Some(String::from("Frodo Baggins <frodo@work.example.com>")),
+            smtp: vec![
+               (String::from("host"), String::from("smtp.work.example.com")),
+               (String::from("port"), String::from("587")),
+               (String::from("security"), String::from("starttls")),
+               (String::from("smtputf8"), String::from("true")),
+            ],
+         },
+         cfg.profiles["work"]
+      );
+      assert_eq!(None, cfg.profiles["personal"].from);
+      assert_eq!(
+         Defaults {
+            rate: Some(String::from("30/min")),
+            log_level: Some(LevelFilter::Debug),
+            template_dir: Some(PathBuf::from("/srv/templates")),
+            editor: None,
+         },
+         cfg.defaults
+      );
+   }
+
+   #[test]
+   fn parse_with_errors() {
+      assert_eq!(
+         Err(String::from("invalid configuration datum: *editor*")),
+         parse("editor = \"vi\"")
+      );
+      assert_eq!(
+         Err(String::from("invalid *signature* in profile work")),
+         parse("[profiles.work]\nsignature = \"x\"")
+      );
+      assert_eq!(
+         Err(String::from(
+            "invalid *port* in the smtp settings of profile work"
+         )),
+         parse("[profiles.work.smtp]\nport = [1]")
+      );
+      assert_eq!(
+         Err(String::from("invalid *log_level*: loud")),
+         parse("[defaults]\nlog_level = \"loud\"")
+      );
+      assert_eq!(
+         Err(String::from("invalid *rate* in the defaults section")),
+         parse("[defaults]\nrate = 30")
+      );
+      assert!(parse("[defaults]\nrate = \"30/week\"").is_err());
+      assert!(parse("[profiles").is_err());
+   }
+}
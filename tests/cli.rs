@@ -0,0 +1,241 @@
+//! Tests that run the `rmt` binary and check its exit status and output.
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const TEMPLATE: &str = "Hi %_FN%,\nhow is %ORG%?\n";
+
+/// Returns the command running `rmt` in the given directory, isolated from the user's
+/// configuration, data and environment.
+fn rmt(dir: &Path) -> Command {
+   let mut cmd = Command::new(env!("CARGO_BIN_EXE_rmt"));
+   cmd.current_dir(dir)
+      .env("HOME", dir)
+      .env("XDG_CONFIG_HOME", dir.join("config"))
+      .env("XDG_DATA_HOME", dir.join("data"))
+      .env_remove("RMT_CONFIG")
+      .env_remove("RMT_TEMPLATE")
+      .env_remove("RUST_LOG")
+      .stdin(Stdio::null());
+   cmd
+}
+
+fn run(cmd: &mut Command) -> Output {
+   cmd.output().expect("cannot run rmt")
+}
+
+/// Returns what the command printed, the errors included.
+fn stdout(output: &Output) -> String {
+   String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// Creates a campaign directory with the given config (`rmt.ini`) and `template.txt`.
+fn campaign(config: &str) -> TempDir {
+   let dir = TempDir::new().expect("cannot create temp dir");
+   fs::write(dir.path().join("rmt.ini"), config).unwrap();
+   fs::write(dir.path().join("template.txt"), TEMPLATE).unwrap();
+   dir
+}
+
+/// The config of a campaign sent to the SMTP server on the given port.
+fn config(port: u16, recipients: &[&str]) -> String {
+   let mut result = format!(
+      "[general]\nFrom=fb@example.com\nSubject=Hello %_FN%!\ncampaign_id=cli-test\n\
+       [smtp]\nhost=127.0.0.1\nport={}\nsecurity=none\n[recipients]\n",
+      port
+   );
+   for email in recipients {
+      result.push_str(&format!("{}=Jane Doe|ORG:-ACME\n", email));
+   }
+   result
+}
+
+/// Runs a fake SMTP server that rejects the recipients whose address starts with `rejected`.
+/// With `hold` set the reply to the first email is held back: the server reports it on the
+/// first channel and replies once the second one says so.
+fn smtp_server(
+   rejected: &'static str,
+   hold: Option<(mpsc::Sender<()>, mpsc::Receiver<()>)>,
+) -> u16 {
+   let listener = TcpListener::bind("127.0.0.1:0").expect("cannot bind");
+   let port = listener.local_addr().unwrap().port();
+   thread::spawn(move || {
+      let mut hold = hold;
+      for stream in listener.incoming() {
+         let stream = stream.expect("no connection");
+         let mut writer = stream.try_clone().unwrap();
+         let mut reader = BufReader::new(stream);
+         writer.write_all(b"220 localhost ESMTP\r\n").unwrap();
+         let mut line = String::new();
+         while reader.read_line(&mut line).unwrap_or(0) > 0 {
+            let command = line.trim_end().to_uppercase();
+            line.clear();
+            let reply = if command.starts_with("EHLO") {
+               "250-localhost\r\n250 8BITMIME\r\n"
+            } else if command.starts_with(&format!("RCPT TO:<{}", rejected.to_uppercase())) {
+               "550 no such user\r\n"
+            } else if command == "DATA" {
+               writer.write_all(b"354 go ahead\r\n").unwrap();
+               while reader.read_line(&mut line).unwrap_or(0) > 0 && line != ".\r\n" {
+                  line.clear();
+               }
+               line.clear();
+               if let Some((held, resume)) = hold.take() {
+                  held.send(()).unwrap();
+                  resume.recv().unwrap();
+               }
+               "250 queued\r\n"
+            } else if command == "QUIT" {
+               writer.write_all(b"221 bye\r\n").unwrap();
+               break;
+            } else {
+               "250 ok\r\n"
+            };
+            writer.write_all(reply.as_bytes()).unwrap();
+         }
+      }
+   });
+   port
+}
+
+#[test]
+fn config_error_exits_with_5() {
+   let dir =
+      campaign("[general]\nFrom=not an address\nSubject=hi\n[recipients]\njd@example.com=J D\n");
+   let output = run(rmt(dir.path()).arg("validate"));
+   assert_eq!(Some(5), output.status.code());
+   assert!(stdout(&output).contains("rmt.ini"));
+}
+
+#[test]
+fn template_error_exits_with_6() {
+   let dir = campaign(&config(25, &["jd@example.com"]));
+   let output = run(rmt(dir.path()).args(["validate", "-t", "missing.txt"]));
+   assert_eq!(Some(6), output.status.code());
+   assert!(stdout(&output).contains("cannot read missing.txt"));
+}
+
+#[test]
+fn validation_error_exits_with_2() {
+   let dir = campaign(&config(25, &["jd@example.com"]));
+   fs::write(dir.path().join("template.txt"), "Hi %_FN% from %TEAM%").unwrap();
+   let output = run(rmt(dir.path()).arg("validate"));
+   assert_eq!(Some(2), output.status.code());
+   assert!(stdout(&output).contains("found 1 problem(s)"));
+}
+
+#[test]
+fn partial_delivery_exits_with_3() {
+   let port = smtp_server("mm@", None);
+   let dir = campaign(&config(port, &["jd@example.com", "mm@gmail.com"]));
+   let output = run(rmt(dir.path()).args(["run", "--yes"]));
+   assert_eq!(
+      Some(3),
+      output.status.code(),
+      "{}",
+      String::from_utf8_lossy(&output.stderr)
+   );
+   assert!(
+      stdout(&output).contains("* done, sent: 1, failed: 1"),
+      "{}",
+      stdout(&output)
+   );
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_run_exits_with_4() {
+   let (held, on_hold) = mpsc::channel();
+   let (resume, resumed) = mpsc::channel();
+   let port = smtp_server("nobody@", Some((held, resumed)));
+   let recipients = ["a@example.com", "b@example.com", "c@example.com"];
+   let dir = campaign(&config(port, &recipients));
+   let child = rmt(dir.path())
+      .args(["run", "--yes"])
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .expect("cannot run rmt");
+   // interrupt the run while the first email is in flight
+   on_hold
+      .recv_timeout(Duration::from_secs(30))
+      .expect("no email sent");
+   let status = Command::new("kill")
+      .args(["-INT", &child.id().to_string()])
+      .status()
+      .expect("cannot run kill");
+   assert!(status.success());
+   thread::sleep(Duration::from_millis(200));
+   resume.send(()).unwrap();
+   let output = child.wait_with_output().expect("rmt did not finish");
+   assert_eq!(
+      Some(4),
+      output.status.code(),
+      "{}",
+      String::from_utf8_lossy(&output.stderr)
+   );
+   assert!(
+      stdout(&output).contains("!! interrupted: 1 email(s) sent, 0 failed, 2 not sent"),
+      "{}",
+      stdout(&output)
+   );
+}
+
+#[test]
+fn quiet_prints_nothing_but_errors() {
+   let dir = campaign(&config(25, &["jd@example.com"]));
+   let output = run(rmt(dir.path()).args(["--quiet", "validate"]));
+   assert_eq!(Some(0), output.status.code());
+   assert!(output.stdout.is_empty());
+   assert!(output.stderr.is_empty());
+   let output = run(rmt(dir.path()).args(["--quiet", "validate", "-t", "missing.txt"]));
+   assert_eq!(Some(6), output.status.code());
+   assert!(stdout(&output).starts_with("!! error: cannot read missing.txt"));
+}
+
+#[test]
+fn discover_config_and_template() {
+   let dir = campaign(&config(25, &["jd@example.com"]));
+   let output = run(rmt(dir.path()).arg("validate"));
+   assert_eq!(Some(0), output.status.code());
+   assert!(stdout(&output).contains("* rmt.ini is valid, 1 recipient(s)"));
+   // the environment variables take precedence over the files in the current directory
+   let other = dir.path().join("other");
+   fs::create_dir(&other).unwrap();
+   fs::write(
+      other.join("other.ini"),
+      config(25, &["a@example.com", "b@example.com"]),
+   )
+   .unwrap();
+   fs::write(other.join("other.txt"), "Hi %_FN%").unwrap();
+   fs::remove_file(dir.path().join("template.txt")).unwrap();
+   let output = run(
+      rmt(dir.path())
+         .arg("validate")
+         .env("RMT_CONFIG", other.join("other.ini"))
+         .env("RMT_TEMPLATE", other.join("other.txt")),
+   );
+   assert_eq!(Some(0), output.status.code());
+   assert!(stdout(&output).contains("other.ini is valid, 2 recipient(s)"));
+   // without a config file
+   let empty = TempDir::new().expect("cannot create temp dir");
+   let output = run(rmt(empty.path()).arg("validate"));
+   assert_eq!(Some(5), output.status.code());
+   assert!(stdout(&output).contains(
+      "no config file given (--config) and no rmt.ini or rmt.toml in the current directory"
+   ));
+}
+
+#[test]
+fn unconfirmed_run_exits_with_8() {
+   let dir = campaign(&config(25, &["jd@example.com"]));
+   let output = run(rmt(dir.path()).arg("run"));
+   assert_eq!(Some(8), output.status.code());
+   assert!(stdout(&output).contains("use --yes to send anyway"));
+}
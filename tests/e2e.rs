@@ -0,0 +1,39 @@
+//! End-to-end tests that drive the whole pipeline through the public `rmt::testing` harness.
+use rmt::testing;
+
+#[test]
+fn full_message_output() {
+   let mut cfg = testing::config(r#""Frodo Baggins" <fb@example.com>"#, "Hello %_FN%!");
+   cfg.recipients = vec![testing::recipient(
+      "jd@example.com",
+      "John Doe",
+      &[("ORG", "EFF")],
+   )];
+   let transport = testing::run(&cfg, "Dear %_FN%,\nhow is %ORG%?", None).expect("run failed");
+   let msg = transport.sent_to("jd@example.com").expect("no email sent");
+   let expected = format!(
+      r#"From: Frodo Baggins <fb@example.com>
+To: John Doe <jd@example.com>
+Subject: Hello John!
+Message-ID: {}
+MIME-Version: 1.0
+Content-Type: text/plain; charset=utf-8
+Content-Transfer-Encoding: 7bit
+
+Dear John,
+how is EFF?"#,
+      rmt::message::message_id("test-campaign", "jd@example.com", "example.com")
+   );
+   assert_eq!(expected, msg.to_string());
+}
+
+#[test]
+fn html_message_output() {
+   let mut cfg = testing::config("fb@example.com", "Hello");
+   cfg.recipients = vec![testing::recipient("jd@example.com", "John Doe", &[])];
+   let transport = testing::run(&cfg, "Hi %_FN%", Some("<p>Hi %_FN%</p>")).expect("run failed");
+   let text = transport.messages[0].to_string();
+   assert!(text.contains("Content-Type: multipart/alternative; boundary="));
+   assert!(text.contains("\n\nHi John\n"));
+   assert!(text.contains("\n\n<p>Hi John</p>\n"));
+}